@@ -1,15 +1,73 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use cpal::{ChannelCount, SampleRate};
+use tokio::sync::{broadcast, watch};
 
-use self::{capture::AudioCapture, device::list_devices, playback::AudioPlayback};
+use self::{
+    analysis::{analyze, recording_duration, test_signal, Recording},
+    scope::Scope,
+};
 pub use self::{
-    capture::AudioSink,
+    analysis::{AnalysisReport, AnalysisThresholds},
+    announce::{AnnounceOptions, AnnounceTarget, Announcer, CallEvent, Messages},
+    beep::{beeps, chime},
+    bus::{BusInput, BusMixer, RoutingMatrix},
+    capture::{AudioSink, NO_INPUT_DEVICE},
+    clip::Clip,
     device::{AudioConfig, Devices},
-    playback::AudioSource,
+    drift::{stretch, Correction, DriftEstimator},
+    dtmf::is_dtmf_digit,
+    greeting::{Greeting, GreetingCue, GreetingGuard, GreetingReader},
+    hooks::FrameProcessor,
+    inputs::ExtraInput,
+    level::watch_levels,
+    mix::{Gain, SessionMix},
+    mode::AudioMode,
+    others::OtherApps,
+    pan::PanMode,
+    pipe::PIPE_PREFIX,
+    playback::{AudioSource, NO_OUTPUT_DEVICE},
+    power::Pacing,
+    routes::VOICE_TRACK,
+    watchdog::MicStatus,
+};
+use self::{
+    capture::AudioCapture,
+    conduit::Conduit,
+    device::{hands_free_input, list_devices, select_input_device},
+    dtmf::{DtmfDetector, DtmfSender},
+    duck::{Ducker, PriorityDuck, VoiceActivity, VoiceDetector},
+    feed::StreamFeed,
+    level::LevelSink,
+    overlay::OverlayEvents,
+    playback::AudioPlayback,
+    routes::Outputs,
+    surround::{surround_track, SurroundOptions},
+    watchdog::CaptureWatchdog,
+};
+#[cfg(feature = "transcribe")]
+use crate::transcribe::Transcriber;
+use crate::{
+    codec::{
+        budget::ErrorBudget,
+        multistream::ChannelLayout,
+        opus::{MediaTrackOpusDecoder, OpusChannels},
+        BitrateTarget, Codec,
+    },
+    media::{self, Injection, MediaSender, MediaTrack, OverflowPolicy, PauseState, TrackKind},
+    rtp::{RtpInput, RtpOutput},
+    stats::Stats,
 };
-use crate::media::MediaTrack;
 
 #[cfg(feature = "audio-processing")]
 mod processor;
@@ -20,9 +78,55 @@ pub use processor::WebrtcAudioProcessor;
 #[derive(Debug, Clone)]
 pub struct WebrtcAudioProcessor;
 
+/// A processor that leaves audio as it is, for streams away from the call's
+/// microphone and speakers.
+fn unprocessed(low_power: bool) -> Result<WebrtcAudioProcessor> {
+    #[cfg(feature = "audio-processing")]
+    return WebrtcAudioProcessor::new(false, low_power);
+    #[cfg(not(feature = "audio-processing"))]
+    {
+        let _ = low_power;
+        Ok(WebrtcAudioProcessor)
+    }
+}
+
+mod analysis;
+mod announce;
+mod beep;
+mod bus;
 mod capture;
+mod clip;
+mod conduit;
 mod device;
+mod drift;
+mod dtmf;
+mod duck;
+mod feed;
+mod gap;
+mod greeting;
+mod hooks;
+mod inputs;
+mod level;
+mod mix;
+mod mode;
+mod others;
+mod overlay;
+mod pan;
+mod pipe;
 mod playback;
+mod png;
+mod power;
+mod routes;
+mod scope;
+mod surround;
+#[cfg(feature = "tts")]
+mod tts;
+#[cfg(feature = "virtual-audio")]
+mod virtual_device;
+#[cfg(target_os = "macos")]
+mod vpio;
+mod watchdog;
+mod wav;
 
 pub const SAMPLE_RATE: SampleRate = SampleRate(48_000);
 pub const ENGINE_FORMAT: AudioFormat = AudioFormat::new(SAMPLE_RATE, 2);
@@ -30,10 +134,79 @@ pub const ENGINE_FORMAT: AudioFormat = AudioFormat::new(SAMPLE_RATE, 2);
 const DURATION_10MS: Duration = Duration::from_millis(10);
 const DURATION_20MS: Duration = Duration::from_millis(20);
 
+/// Audio buffered before a remote track starts playing.
+pub const DEFAULT_PLAYOUT_DELAY: Duration = Duration::from_millis(40);
+/// How much later than it has to the remote may play before it skips to live.
+pub const DEFAULT_LATENCY_BUDGET: Duration = Duration::from_millis(500);
+/// How much priority tracks duck the others by without `--duck`.
+const DEFAULT_PRIORITY_DUCK_DB: f32 = 12.;
+/// Extra time to record the loopback test signal for, as the capture sink
+/// may start a tick late.
+const RECORDING_SLACK: Duration = Duration::from_millis(100);
+/// Detected DTMF digits buffered for slow event consumers.
+const DTMF_EVENT_CAPACITY: usize = 64;
+const MARK_CAPACITY: usize = 16;
+/// What captured audio is encoded as, lossless renditions aside.
+const CAPTURE_CODEC: Codec = Codec::Opus {
+    channels: OpusChannels::Stereo,
+};
+
 #[derive(Debug, Clone)]
 pub struct AudioContext {
     playback: AudioPlayback,
+    /// Where remote tracks play: `playback`, or the devices they are routed to.
+    outputs: Outputs,
     capture: AudioCapture,
+    /// Further inputs, published as tracks of their own.
+    inputs: Vec<(ExtraInput, AudioCapture)>,
+    mode: AudioMode,
+    inject_capture: Injection,
+    inject_playback: Injection,
+    /// For surround capture, which opens the input device again.
+    input_device: Option<String>,
+    capture_overflow: OverflowPolicy,
+    playback_overflow: OverflowPolicy,
+    playout_delay: Duration,
+    latency_budget: Option<Duration>,
+    /// Codec errors each track survives per minute.
+    codec_error_budget: u32,
+    /// How remote tracks are mixed.
+    mix: SessionMix,
+    stats: Stats,
+    bitrate: BitrateTarget,
+    paused: PauseState,
+    announcer: Option<Announcer>,
+    dtmf: DtmfSender,
+    /// Beeps and other alerts for the local user.
+    alerts: Clip,
+    /// Set if recent audio is kept for debug pictures.
+    scope: Option<Scope>,
+    /// Set if the microphone is watched for silence and stalls.
+    mic_status: Option<watch::Receiver<MicStatus>>,
+    /// Set if remote audio is checked for DTMF digits.
+    dtmf_events: Option<broadcast::Sender<char>>,
+    /// Set if remote tracks are ducked while others play.
+    priority: Option<PriorityDuck>,
+    /// Moments the user marked, shared by every session on the devices.
+    marks: broadcast::Sender<String>,
+    /// Whether the user agreed to be recorded, in this session's current
+    /// call.
+    recording_consent: Arc<AtomicBool>,
+    /// When this session sends its greeting instead of the microphone.
+    greeting: GreetingCue,
+    /// Set if remote audio is also played into a source for streaming.
+    feed: Option<StreamFeed>,
+    /// Set if overlays are told who joins and leaves.
+    overlay: Option<Arc<OverlayEvents>>,
+    /// Set if other applications reach the call through system devices,
+    /// which go away with the last clone.
+    _conduit: Option<Arc<Conduit>>,
+    /// Set if the call is transcribed.
+    #[cfg(feature = "transcribe")]
+    transcriber: Option<Transcriber>,
+    /// Whether remote audio is transcribed too.
+    #[cfg(feature = "transcribe")]
+    transcribe_remote: bool,
 }
 
 impl AudioContext {
@@ -42,23 +215,550 @@ impl AudioContext {
     }
 
     /// Create a new [`AudioContext`].
-    pub async fn new(config: AudioConfig) -> Result<Self> {
+    pub async fn new(mut config: AudioConfig) -> Result<Self> {
         let host = cpal::default_host();
+        let conduit = match config.system_devices.clone() {
+            Some(name) => {
+                let conduit = tokio::task::spawn_blocking(move || Conduit::create(&name)).await??;
+                config.input_device = Some(conduit.input_device());
+                config.output_device = Some(conduit.output_device());
+                Some(Arc::new(conduit))
+            }
+            None => None,
+        };
+        #[cfg(target_os = "macos")]
+        if config.voice_processing {
+            config.input_device = Some(vpio::VOICE_PROCESSING_DEVICE.to_string());
+            config.output_device = Some(vpio::VOICE_PROCESSING_DEVICE.to_string());
+            // the unit cancels the echo itself.
+            config.processing_enabled = false;
+        }
+        if config.input_device.is_none() {
+            config.input_device = select_input_device().await.map_err(|err| {
+                err.context(crate::error::NeetError::AudioBackend(
+                    "failed to list input devices".to_string(),
+                ))
+            })?;
+        }
+        // a bluetooth headset's microphone drops its playback to call quality.
+        let devices = [
+            config.input_device.as_deref(),
+            config.output_device.as_deref(),
+        ];
+        let real = |name: &str| {
+            name != NO_INPUT_DEVICE && name != NO_OUTPUT_DEVICE && !name.starts_with(PIPE_PREFIX)
+        };
+        let headset = devices
+            .into_iter()
+            .flatten()
+            .all(real)
+            .then(|| hands_free_input(&host, devices[0], devices[1]))
+            .flatten();
+        if let Some(headset) = headset {
+            match headset.alternative {
+                Some(other) if config.keep_a2dp => {
+                    tracing::info!(input = %headset.input, "recording from `{other}` to keep A2DP");
+                    config.input_device = Some(other);
+                }
+                _ => tracing::warn!("{headset}"),
+            }
+        }
 
+        let processing = config.processing_enabled && config.mode.processing();
         #[cfg(feature = "audio-processing")]
-        let processor = WebrtcAudioProcessor::new(config.processing_enabled)?;
+        let processor = WebrtcAudioProcessor::new(processing, config.low_power)?;
         #[cfg(not(feature = "audio-processing"))]
-        let processor = WebrtcAudioProcessor;
+        let processor = {
+            if processing {
+                tracing::debug!("built without audio processing");
+            }
+            WebrtcAudioProcessor
+        };
+
+        let stats = Stats::default();
+        let bitrate = BitrateTarget::default();
+        let paused = PauseState::default();
+        let pacing = Pacing::new(config.low_power);
+        let capture = AudioCapture::build(
+            &host,
+            config.input_device.as_deref(),
+            processor.clone(),
+            config.capture_overflow,
+            config.mode,
+            pacing,
+            stats.pipeline_busy_us.clone(),
+        )
+        .await?;
+        capture
+            .add_sink(LevelSink(stats.capture_level.clone()))
+            .await?;
+        let priority = match config.duck_for.is_empty() {
+            true => None,
+            false => {
+                let amount_db = match config.duck_db {
+                    0. => DEFAULT_PRIORITY_DUCK_DB,
+                    db => db,
+                };
+                Some(PriorityDuck::new(config.duck_for.clone(), amount_db))
+            }
+        };
+        let ducker = if config.duck_db > 0. {
+            let activity = VoiceActivity::default();
+            capture
+                .add_sink(VoiceDetector::new(activity.clone()))
+                .await?;
+            Some(Ducker::new(activity, config.duck_db))
+        } else {
+            None
+        };
+        #[cfg(feature = "transcribe")]
+        let (transcriber, transcribe_remote) = match config.transcribe {
+            Some(options) => {
+                let sources = options.sources;
+                let transcriber = Transcriber::start(options)?;
+                if sources.local() {
+                    capture.add_sink(transcriber.tap("local")).await?;
+                }
+                (Some(transcriber), sources.remote())
+            }
+            None => (None, false),
+        };
+        #[cfg(target_os = "macos")]
+        let voice_unit = capture
+            .voice_unit()
+            .filter(|_| config.output_device.as_deref() == Some(vpio::VOICE_PROCESSING_DEVICE));
+        // only macOS has the unit.
+        #[cfg(not(target_os = "macos"))]
+        let voice_unit: Option<std::convert::Infallible> = None;
+        let playback = match voice_unit {
+            // the unit plays as well as captures.
+            #[cfg(target_os = "macos")]
+            Some(unit) => {
+                AudioPlayback::build_voice_processing(
+                    unit,
+                    processor.clone(),
+                    stats.playback_xruns.clone(),
+                    pacing,
+                    stats.pipeline_busy_us.clone(),
+                )
+                .await?
+            }
+            _ => {
+                AudioPlayback::build(
+                    &host,
+                    config.output_device.as_deref(),
+                    processor.clone(),
+                    stats.playback_xruns.clone(),
+                    pacing,
+                    stats.pipeline_busy_us.clone(),
+                )
+                .await?
+            }
+        };
+        if let Some(ducker) = ducker {
+            playback.add_processor(ducker).await?;
+        }
+        let scope = config.scope.map(Scope::new);
+        if let Some(scope) = &scope {
+            capture.add_sink(scope.capture.clone()).await?;
+            playback.add_tap(scope.playback.clone()).await?;
+        }
+        let mic_status = match config.mic_watchdog {
+            Some(after) => {
+                let (status, receiver) = watch::channel(MicStatus::Ok);
+                capture
+                    .add_sink(CaptureWatchdog::new(after, status))
+                    .await?;
+                let supervise = watchdog::supervise(
+                    receiver.clone(),
+                    capture.clone(),
+                    after,
+                    config.mic_failover,
+                );
+                tokio::spawn(supervise);
+                Some(receiver)
+            }
+            None => None,
+        };
+        let alerts = Clip::default();
+        playback.add_source(alerts.clone()).await?;
+        let dtmf = DtmfSender::default();
+        capture.add_insert(dtmf.clip()).await?;
+        let dtmf_events = config
+            .detect_dtmf
+            .then(|| broadcast::channel(DTMF_EVENT_CAPACITY).0);
+        let announcer = match config.announce {
+            Some(options) => Some(Announcer::start(options, &playback, &capture).await?),
+            None => None,
+        };
+        let mut inputs = Vec::new();
+        for input in config.extra_inputs {
+            // an instrument is no voice to clean up, and has no echo to cancel.
+            let capture = AudioCapture::build(
+                &host,
+                Some(&input.device),
+                unprocessed(config.low_power)?,
+                config.capture_overflow,
+                config.mode,
+                pacing,
+                stats.pipeline_busy_us.clone(),
+            )
+            .await
+            .with_context(|| format!("failed to open input `{}`", input.name))?;
+            inputs.push((input, capture));
+        }
+        let feed = match config.stream_feed {
+            Some(name) => Some(
+                StreamFeed::start(
+                    name,
+                    config.low_power,
+                    pacing,
+                    stats.pipeline_busy_us.clone(),
+                )
+                .await
+                .context("failed to add the stream feed")?,
+            ),
+            None => None,
+        };
+        let overlay = match config.stream_events {
+            Some(addr) => Some(Arc::new(OverlayEvents::bind(addr).await?)),
+            None => None,
+        };
+        let outputs = Outputs::new(
+            playback.clone(),
+            config.routes,
+            config.low_power,
+            pacing,
+            stats.playback_xruns.clone(),
+            stats.pipeline_busy_us.clone(),
+        );
+        Ok(Self {
+            playback,
+            outputs,
+            capture,
+            inputs,
+            mode: config.mode,
+            input_device: config.input_device,
+            capture_overflow: config.capture_overflow,
+            playback_overflow: config.playback_overflow,
+            playout_delay: config.playout_delay,
+            latency_budget: config.latency_budget,
+            inject_capture: config.inject_capture,
+            inject_playback: config.inject_playback,
+            codec_error_budget: config.codec_error_budget,
+            mix: SessionMix::new("", config.pan),
+            stats,
+            bitrate,
+            paused,
+            announcer,
+            dtmf,
+            alerts,
+            scope,
+            mic_status,
+            feed,
+            overlay,
+            _conduit: conduit,
+            dtmf_events,
+            priority,
+            marks: broadcast::channel(MARK_CAPACITY).0,
+            recording_consent: Arc::default(),
+            greeting: GreetingCue::default(),
+            #[cfg(feature = "transcribe")]
+            transcriber,
+            #[cfg(feature = "transcribe")]
+            transcribe_remote,
+        })
+    }
+
+    /// Audio for another session on the same devices. It shares the devices,
+    /// announcer, transcriber, DTMF dialing, microphone level, output xrun
+    /// counts and pipeline CPU time, but has its own statistics, bitrate, pause state,
+    /// recording consent and greeting, and its remote tracks are mixed
+    /// according to `mix`.
+    pub fn session(&self, mix: SessionMix) -> Self {
+        let stats = Stats {
+            capture_level: self.stats.capture_level.clone(),
+            playback_xruns: self.stats.playback_xruns.clone(),
+            pipeline_busy_us: self.stats.pipeline_busy_us.clone(),
+            ..Stats::default()
+        };
+        Self {
+            mix,
+            stats,
+            bitrate: BitrateTarget::default(),
+            paused: PauseState::default(),
+            recording_consent: Arc::default(),
+            greeting: GreetingCue::default(),
+            dtmf_events: self
+                .dtmf_events
+                .as_ref()
+                .map(|_| broadcast::channel(DTMF_EVENT_CAPACITY).0),
+            ..self.clone()
+        }
+    }
+
+    /// Playback volume of this session's remote audio.
+    pub fn gain(&self) -> &Gain {
+        &self.mix.gain
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn mode(&self) -> AudioMode {
+        self.mode
+    }
+
+    /// Bitrate requested for captured audio, shared by all capture encoders.
+    pub fn bitrate(&self) -> &BitrateTarget {
+        &self.bitrate
+    }
+
+    /// Stop (or restart) encoding captured audio. Tracks and broadcasts stay
+    /// open while paused.
+    pub fn set_paused(&self, paused: bool) -> bool {
+        self.paused.set(paused)
+    }
 
-        let capture =
-            AudioCapture::build(&host, config.input_device.as_deref(), processor.clone()).await?;
-        let playback =
-            AudioPlayback::build(&host, config.output_device.as_deref(), processor.clone()).await?;
-        Ok(Self { playback, capture })
+    pub fn pause_state(&self) -> &PauseState {
+        &self.paused
+    }
+
+    /// Speaks announcements, if enabled.
+    pub fn announcer(&self) -> Option<&Announcer> {
+        self.announcer.as_ref()
+    }
+
+    /// Tell the local user, and overlays, that the participant `name` did
+    /// `event`.
+    pub fn call_event(&self, event: CallEvent, name: &str) {
+        if let Some(announcer) = &self.announcer {
+            announcer.event(event, name);
+        }
+        if let Some(overlay) = &self.overlay {
+            overlay.send(event, name);
+        }
+    }
+
+    /// Mix the tones for `digits` into the captured audio.
+    pub fn send_dtmf(&self, digits: &str) -> Result<()> {
+        self.dtmf.send(digits)
+    }
+
+    /// Play `count` beeps to the local user.
+    pub fn beep(&self, count: usize) {
+        self.alerts.push(&beeps(count));
+    }
+
+    /// Play the chime that announces a caller to the local user.
+    pub fn chime(&self) {
+        self.alerts.push(&chime());
+    }
+
+    /// What the microphone watchdog makes of the input, if it is enabled.
+    pub fn mic_status(&self) -> Option<watch::Receiver<MicStatus>> {
+        self.mic_status.clone()
+    }
+
+    /// Mark this moment as `label`: in the transcript, if the call is
+    /// transcribed, and for the remote, whose recording may take it as a
+    /// chapter.
+    pub fn mark(&self, label: &str) {
+        #[cfg(feature = "transcribe")]
+        if let Some(transcriber) = &self.transcriber {
+            transcriber.mark(label);
+        }
+        // nobody listening is fine; no call is running.
+        let _ = self.marks.send(label.to_string());
+    }
+
+    /// Moments marked from now on.
+    pub fn marks(&self) -> broadcast::Receiver<String> {
+        self.marks.subscribe()
+    }
+
+    /// Agree to be recorded, for the rest of the call.
+    pub fn consent_to_recording(&self) {
+        self.recording_consent.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget the consent once the call is over; the next one asks again.
+    pub fn reset_recording_consent(&self) {
+        self.recording_consent.store(false, Ordering::Relaxed);
+    }
+
+    pub fn consents_to_recording(&self) -> bool {
+        self.recording_consent.load(Ordering::Relaxed)
+    }
+
+    /// Digits detected in remote audio, if detection is enabled.
+    pub fn dtmf_events(&self) -> Option<broadcast::Receiver<char>> {
+        self.dtmf_events.as_ref().map(|events| events.subscribe())
+    }
+
+    /// A fresh error budget for one track's codec.
+    fn error_budget(&self) -> ErrorBudget {
+        ErrorBudget::new(self.codec_error_budget, self.stats.codec_errors.clone())
     }
 
     pub async fn capture_track(&self) -> Result<MediaTrack> {
-        self.capture.create_opus_track().await
+        let track = self
+            .capture
+            .create_track(
+                CAPTURE_CODEC,
+                self.bitrate.clone(),
+                self.paused.clone(),
+                self.stats.capture_dropped.clone(),
+                self.error_budget(),
+                Some(&self.greeting),
+            )
+            .await?;
+        Ok(self.injected(track))
+    }
+
+    /// A capture track encoded at a fixed bitrate, e.g. for a simulcast layer.
+    pub async fn capture_track_at(&self, bits_per_second: u32) -> Result<MediaTrack> {
+        let bitrate = BitrateTarget::default();
+        bitrate.set(bits_per_second);
+        let track = self
+            .capture
+            .create_track(
+                CAPTURE_CODEC,
+                bitrate,
+                self.paused.clone(),
+                self.stats.capture_dropped.clone(),
+                self.error_budget(),
+                Some(&self.greeting),
+            )
+            .await?;
+        Ok(self.injected(track))
+    }
+
+    /// A track for each further input, by name. Those without a bitrate of
+    /// their own follow the microphone's.
+    pub async fn input_tracks(&self) -> Result<Vec<(String, MediaTrack)>> {
+        let mut tracks = Vec::new();
+        for (input, capture) in &self.inputs {
+            let bitrate = match input.bitrate {
+                Some(bits_per_second) => {
+                    let bitrate = BitrateTarget::default();
+                    bitrate.set(bits_per_second);
+                    bitrate
+                }
+                None => self.bitrate.clone(),
+            };
+            let track = capture
+                .create_track(
+                    input.codec,
+                    bitrate,
+                    self.paused.clone(),
+                    self.stats.capture_dropped.clone(),
+                    self.error_budget(),
+                    // an instrument is no place for a greeting.
+                    None,
+                )
+                .await?;
+            tracks.push((input.name.clone(), self.injected(track)));
+        }
+        Ok(tracks)
+    }
+
+    /// A lossless capture track, e.g. to offer next to the Opus one.
+    pub async fn flac_track(&self) -> Result<MediaTrack> {
+        let track = self
+            .capture
+            .create_track(
+                Codec::Flac,
+                BitrateTarget::default(),
+                self.paused.clone(),
+                self.stats.capture_dropped.clone(),
+                self.error_budget(),
+                Some(&self.greeting),
+            )
+            .await?;
+        Ok(self.injected(track))
+    }
+
+    /// A capture track downmixed to mono, processed like the stereo one.
+    pub async fn mono_track(&self) -> Result<MediaTrack> {
+        let track = self
+            .capture
+            .create_track(
+                Codec::Opus {
+                    channels: OpusChannels::Mono,
+                },
+                self.bitrate.clone(),
+                self.paused.clone(),
+                self.stats.capture_dropped.clone(),
+                self.error_budget(),
+                Some(&self.greeting),
+            )
+            .await?;
+        Ok(self.injected(track))
+    }
+
+    /// A capture track of `layout`, wider than stereo, from a multi-channel
+    /// stream of its own on the input device. It bypasses echo cancellation and the other
+    /// processing of the stereo capture.
+    pub async fn surround_track(&self, layout: ChannelLayout) -> Result<MediaTrack> {
+        let track = surround_track(SurroundOptions {
+            device: self.input_device.clone(),
+            layout,
+            bitrate: self.bitrate.clone(),
+            paused: self.paused.clone(),
+            overflow: self.capture_overflow,
+            dropped: self.stats.capture_dropped.clone(),
+        })
+        .await?;
+        Ok(self.injected(track))
+    }
+
+    /// `track` of captured audio, held back by any `--inject-delay-ms` and
+    /// `--inject-jitter-ms` on the capture path.
+    fn injected(&self, track: MediaTrack) -> MediaTrack {
+        self.inject_capture
+            .apply(track, self.stats.capture_dropped.clone())
+    }
+
+    /// A track of the Opus packets an RTP sender streams to `addr`, in
+    /// place of captured audio. The bitrate is whatever the sender encodes.
+    pub async fn rtp_track(&self, addr: SocketAddr) -> Result<MediaTrack> {
+        let input = RtpInput::bind(addr).await?;
+        let (sender, receiver) = media::channel(
+            self.mode.queue_frames(),
+            self.capture_overflow,
+            self.stats.capture_dropped.clone(),
+        );
+        let paused = self.paused.clone();
+        tokio::spawn(async move {
+            if let Err(err) = input.run(sender, paused).await {
+                tracing::warn!("RTP input failed: {err:#}");
+            }
+        });
+        Ok(MediaTrack::new(receiver, CAPTURE_CODEC, TrackKind::Audio))
+    }
+
+    /// Send remote Opus frames on to `addr` over RTP instead of playing
+    /// them, and return the sender that received frames should be pushed
+    /// into.
+    pub async fn rtp_output(&self, addr: SocketAddr, codec: Codec) -> Result<MediaSender> {
+        if !matches!(codec, Codec::Opus { .. }) {
+            bail!("the remote sends {codec:?}; only plain Opus goes out over RTP");
+        }
+        let output = RtpOutput::connect(addr).await?;
+        let (sender, receiver) = media::channel(
+            self.mode.queue_frames(),
+            self.playback_overflow,
+            self.stats.playback_dropped.clone(),
+        );
+        let track = MediaTrack::new(receiver, codec, TrackKind::Audio);
+        tokio::spawn(async move {
+            if let Err(err) = output.run(track).await {
+                tracing::warn!("RTP output failed: {err:#}");
+            }
+        });
+        Ok(sender)
     }
 
     pub async fn play_track(&self, track: MediaTrack) -> Result<()> {
@@ -66,6 +766,125 @@ impl AudioContext {
         Ok(())
     }
 
+    /// Add a decoder for the remote track `name`, [`VOICE_TRACK`] or an
+    /// extra input's, to the mixer of the output it is routed to, and return
+    /// the sender that received frames should be pushed into.
+    pub async fn play_remote_track(&self, name: &str, codec: Codec) -> Result<MediaSender> {
+        let (sender, receiver) = media::channel(
+            self.mode.queue_frames(),
+            self.playback_overflow,
+            self.stats.playback_dropped.clone(),
+        );
+        let track = self.inject_playback.apply(
+            MediaTrack::new(receiver, codec, TrackKind::Audio),
+            self.stats.playback_dropped.clone(),
+        );
+        let decoder = MediaTrackOpusDecoder::new(track)?
+            .with_buffer_gauge(self.stats.playback_buffer_us.clone())
+            .with_playout_delay(self.playout_delay)
+            .with_stall_counter(self.stats.playback_xruns.resets.clone())
+            .with_level(self.stats.playback_level.clone())
+            .with_error_budget(self.error_budget());
+        let decoder = match self.latency_budget {
+            Some(limit) => decoder.with_latency_budget(
+                limit,
+                self.stats.one_way_delay_us.clone(),
+                self.stats.resyncs.clone(),
+                self.stats.resync_skipped_us.clone(),
+            ),
+            None => decoder.without_latency_budget(),
+        };
+        let decoder = match &self.dtmf_events {
+            Some(events) => decoder.with_tap(DtmfDetector::new(events.clone())),
+            None => decoder,
+        };
+        #[cfg(feature = "transcribe")]
+        let decoder = match &self.transcriber {
+            Some(transcriber) if self.transcribe_remote => {
+                decoder.with_tap(transcriber.tap("remote"))
+            }
+            _ => decoder,
+        };
+        let decoder = match &self.feed {
+            Some(feed) => decoder.with_tap(feed.tap().await?),
+            None => decoder,
+        };
+        let mix = self.mix.clone();
+        match &self.priority {
+            Some(priority) if priority.is_priority(name) => {
+                let decoder = decoder.with_tap(priority.detector());
+                self.outputs.play(name, decoder, mix).await?
+            }
+            Some(priority) => self.outputs.play(name, priority.duck(decoder), mix).await?,
+            None => self.outputs.play(name, decoder, mix).await?,
+        }
+        Ok(sender)
+    }
+
+    /// Play the remote's `track` on the output `device` from now on, or on
+    /// the call's own output again if `None`. Shared by every session on
+    /// the devices.
+    pub fn set_route(&self, track: &str, device: Option<&str>) {
+        self.outputs.set_route(track, device);
+    }
+
+    /// The output device of each routed track.
+    pub fn routes(&self) -> BTreeMap<String, String> {
+        self.outputs.routes()
+    }
+
+    /// Capture from `device`, named as for `--input-device`, from now on.
+    /// Returns the device's name.
+    pub async fn switch_input(&self, device: &str) -> Result<String> {
+        self.capture.switch_input(device).await
+    }
+
+    /// Feed the microphone's audio, as sent, to `sink` for as long as the
+    /// capture runs.
+    pub async fn add_capture_sink(&self, sink: impl AudioSink) -> Result<()> {
+        self.capture.add_sink(sink).await
+    }
+
+    /// Send `greeting` instead of the microphone in this session until it
+    /// ends, or until the returned guard is dropped.
+    pub fn play_greeting(&self, greeting: &Greeting) -> Result<GreetingGuard> {
+        self.greeting.play(greeting)
+    }
+
+    /// Draw the recent capture and playback audio into a PNG at `path`, or a
+    /// timestamped file, and return where it went.
+    pub async fn dump_scope(&self, path: Option<PathBuf>) -> Result<PathBuf> {
+        match &self.scope {
+            Some(scope) => scope.dump(path).await,
+            None => bail!("no audio kept; start with --scope <seconds>"),
+        }
+    }
+
+    /// Play a test signal and measure what the input device records of it.
+    /// Echo cancellation would remove the signal, so processing should be
+    /// off.
+    pub async fn analyze_loopback(&self) -> Result<AnalysisReport> {
+        let recording = Recording::new(recording_duration());
+        self.add_capture_sink(recording.clone()).await?;
+        let queued = Instant::now();
+        self.alerts.push(&test_signal());
+        tokio::time::sleep(recording_duration() + RECORDING_SLACK).await;
+        let Some(started) = recording.started() else {
+            bail!("the input device delivered no audio");
+        };
+        let offset = match started.checked_duration_since(queued) {
+            Some(after) => after.as_secs_f32(),
+            None => -queued.duration_since(started).as_secs_f32(),
+        };
+        let samples = recording.samples();
+        tokio::task::spawn_blocking(move || analyze(&samples, offset)).await?
+    }
+
+    /// Wait until the remote tracks of this session have played out.
+    pub async fn playback_drained(&self) {
+        self.mix.sources.drained().await
+    }
+
     pub async fn feedback_encoded(&self) -> Result<()> {
         let track = self.capture_track().await?;
         self.play_track(track).await?;
@@ -0,0 +1,335 @@
+//! `loopback --analyze`: a scripted check of the audio path.
+//!
+//! A chirp and a steady tone are played on the output device and recorded
+//! from the input, over a cable or through the air. The chirp is found in
+//! the recording by cross-correlation, which gives the latency from queuing
+//! the signal to capturing it. The tone then gives the SNR (noise and
+//! distortion against a fitted sine) and dropouts (10 ms windows far below
+//! its usual level).
+
+use std::{
+    f32::consts::PI,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use super::{AudioSink, ENGINE_FORMAT, SAMPLE_RATE};
+use crate::error::NeetError;
+
+const LEAD_IN: Duration = Duration::from_millis(300);
+const CHIRP: Duration = Duration::from_millis(500);
+const CHIRP_FROM_HZ: f32 = 200.;
+const CHIRP_TO_HZ: f32 = 4_000.;
+const GAP: Duration = Duration::from_millis(200);
+const TONE: Duration = Duration::from_secs(2);
+const TONE_HZ: f32 = 1_000.;
+const AMPLITUDE: f32 = 0.5;
+const FADE: Duration = Duration::from_millis(10);
+/// How long after the signal to keep recording, and so the most latency
+/// that can be measured.
+pub const MAX_LATENCY: Duration = Duration::from_secs(1);
+/// Correlation is searched at a quarter of the sample rate, then refined.
+const DECIMATION: usize = 4;
+/// Normalized correlation below which the chirp counts as not found.
+const MIN_CORRELATION: f32 = 0.3;
+/// Tone ends skipped, for fades and a slightly wrong latency.
+const TONE_MARGIN: Duration = Duration::from_millis(50);
+/// Blocks the tone is fitted over; a whole number of periods.
+const FIT_BLOCK: Duration = Duration::from_millis(100);
+const DROPOUT_WINDOW: Duration = Duration::from_millis(10);
+/// A window below this share of the median tone level is a dropout.
+const DROPOUT_LEVEL: f32 = 0.25;
+
+/// What the analysis fails on.
+#[derive(Debug, Clone, Copy)]
+pub struct AnalysisThresholds {
+    pub min_snr_db: f32,
+    pub max_latency: Duration,
+    pub max_dropouts: usize,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AnalysisReport {
+    pub latency_ms: f32,
+    pub snr_db: f32,
+    pub dropouts: usize,
+    pub dropout_ms: f32,
+    /// Level of the recorded tone; a quiet one means low SNR, whatever the
+    /// path is like.
+    pub tone_dbfs: f32,
+    /// How well the recorded chirp matches the one played, from 0 to 1.
+    pub correlation: f32,
+}
+
+impl AnalysisReport {
+    /// Fail with [`NeetError::QualityCheck`] if a measurement is beyond its
+    /// threshold.
+    pub fn check(&self, thresholds: &AnalysisThresholds) -> Result<()> {
+        let mut failures = Vec::new();
+        if self.latency_ms > thresholds.max_latency.as_secs_f32() * 1000. {
+            failures.push(format!("latency {:.0} ms", self.latency_ms));
+        }
+        if self.snr_db < thresholds.min_snr_db {
+            failures.push(format!("SNR {:.1} dB", self.snr_db));
+        }
+        if self.dropouts > thresholds.max_dropouts {
+            failures.push(format!("{} dropouts", self.dropouts));
+        }
+        if failures.is_empty() {
+            return Ok(());
+        }
+        Err(
+            NeetError::QualityCheck(format!("loopback analysis failed: {}", failures.join(", ")))
+                .into(),
+        )
+    }
+}
+
+/// The test signal in [`ENGINE_FORMAT`]: silence, the chirp, silence and the
+/// tone.
+pub fn test_signal() -> Vec<f32> {
+    let mono = mono_signal();
+    mono.iter().flat_map(|&s| [s, s]).collect()
+}
+
+/// How long to record for after queuing the test signal.
+pub fn recording_duration() -> Duration {
+    LEAD_IN + CHIRP + GAP + TONE + MAX_LATENCY
+}
+
+fn mono_signal() -> Vec<f32> {
+    let mut out = vec![0.; blocks(LEAD_IN)];
+    out.extend(chirp());
+    out.extend(vec![0.; blocks(GAP)]);
+    let rate = SAMPLE_RATE.0 as f32;
+    let tone = (0..blocks(TONE)).map(|i| (2. * PI * TONE_HZ * i as f32 / rate).sin());
+    out.extend(faded(tone.collect()));
+    out
+}
+
+/// Linear sweep from [`CHIRP_FROM_HZ`] to [`CHIRP_TO_HZ`].
+fn chirp() -> Vec<f32> {
+    let rate = SAMPLE_RATE.0 as f32;
+    let length = CHIRP.as_secs_f32();
+    let sweep = (CHIRP_TO_HZ - CHIRP_FROM_HZ) / length;
+    let chirp = (0..blocks(CHIRP)).map(|i| {
+        let t = i as f32 / rate;
+        (2. * PI * (CHIRP_FROM_HZ * t + sweep * t * t / 2.)).sin()
+    });
+    faded(chirp.collect())
+}
+
+fn faded(mut samples: Vec<f32>) -> Vec<f32> {
+    let fade = blocks(FADE) as f32;
+    let len = samples.len();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let envelope = (i.min(len - 1 - i) as f32 / fade).min(1.);
+        *sample *= AMPLITUDE * envelope;
+    }
+    samples
+}
+
+fn blocks(duration: Duration) -> usize {
+    ENGINE_FORMAT.block_count(duration)
+}
+
+/// Capture sink that records up to a length, downmixed to mono, then lets
+/// go.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    samples: Arc<Mutex<Vec<f32>>>,
+    started: Arc<Mutex<Option<Instant>>>,
+    len: usize,
+}
+
+impl Recording {
+    pub fn new(duration: Duration) -> Self {
+        let len = blocks(duration);
+        Self {
+            samples: Arc::new(Mutex::new(Vec::with_capacity(len))),
+            started: Default::default(),
+            len,
+        }
+    }
+
+    /// When the first samples came in.
+    pub fn started(&self) -> Option<Instant> {
+        *self.started.lock().unwrap()
+    }
+
+    pub fn samples(&self) -> Vec<f32> {
+        self.samples.lock().unwrap().clone()
+    }
+}
+
+impl AudioSink for Recording {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        self.started
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
+        let channels = ENGINE_FORMAT.channel_count as usize;
+        let mut samples = self.samples.lock().unwrap();
+        for frame in buf.chunks_exact(channels) {
+            if samples.len() == self.len {
+                return Ok(ControlFlow::Break(()));
+            }
+            samples.push(frame.iter().sum::<f32>() / channels as f32);
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Analyze a mono `recording` that started `offset` seconds after the test
+/// signal was queued (negative if before).
+pub fn analyze(recording: &[f32], offset: f32) -> Result<AnalysisReport> {
+    let reference = chirp();
+    let (lag, correlation) = find(&reference, recording, blocks(LEAD_IN + MAX_LATENCY));
+    if correlation < MIN_CORRELATION {
+        bail!(NeetError::QualityCheck(format!(
+            "test signal not found in the recording (correlation {correlation:.2}); is the input hearing the output?"
+        )));
+    }
+    let rate = SAMPLE_RATE.0 as f32;
+    let latency = offset + (lag as f32 - blocks(LEAD_IN) as f32) / rate;
+
+    let tone_start = lag + blocks(CHIRP + GAP + TONE_MARGIN);
+    let tone_end = (lag + blocks(CHIRP + GAP + TONE) - blocks(TONE_MARGIN)).min(recording.len());
+    let tone = recording.get(tone_start..tone_end).unwrap_or_default();
+    let (snr_db, tone_dbfs) = snr(tone);
+    let dropped = dropouts(tone);
+    Ok(AnalysisReport {
+        latency_ms: latency * 1000.,
+        snr_db,
+        dropouts: dropped.0,
+        dropout_ms: dropped.1 as f32 * DROPOUT_WINDOW.as_secs_f32() * 1000.,
+        tone_dbfs,
+        correlation,
+    })
+}
+
+/// Where `reference` starts in `signal`, up to `max_lag`, and the normalized
+/// correlation there.
+fn find(reference: &[f32], signal: &[f32], max_lag: usize) -> (usize, f32) {
+    // coarse search at a quarter of the rate, where the chirp still fits.
+    let decimate = |samples: &[f32]| -> Vec<f32> {
+        samples
+            .chunks_exact(DECIMATION)
+            .map(|chunk| chunk.iter().sum::<f32>() / DECIMATION as f32)
+            .collect()
+    };
+    let coarse = best_lag(
+        &decimate(reference),
+        &decimate(signal),
+        0..max_lag / DECIMATION,
+    );
+    let around = coarse.0 * DECIMATION;
+    let from = around.saturating_sub(DECIMATION * 2);
+    best_lag(reference, signal, from..around + DECIMATION * 2)
+}
+
+fn best_lag(reference: &[f32], signal: &[f32], lags: std::ops::Range<usize>) -> (usize, f32) {
+    let energy = |samples: &[f32]| samples.iter().map(|s| s * s).sum::<f32>();
+    let reference_energy = energy(reference);
+    let mut best = (0, 0.);
+    for lag in lags {
+        let Some(window) = signal.get(lag..lag + reference.len()) else {
+            break;
+        };
+        let dot: f32 = reference.iter().zip(window).map(|(a, b)| a * b).sum();
+        let norm = (reference_energy * energy(window)).sqrt();
+        let correlation = if norm > 0. { dot / norm } else { 0. };
+        if correlation > best.1 {
+            best = (lag, correlation);
+        }
+    }
+    best
+}
+
+/// SNR of `tone` against the sine fitted to it block by block, which
+/// follows slow phase drift between the devices, and its level; both in dB.
+fn snr(tone: &[f32]) -> (f32, f32) {
+    let rate = SAMPLE_RATE.0 as f32;
+    let (mut signal, mut total) = (0f32, 0f32);
+    for block in tone.chunks_exact(blocks(FIT_BLOCK)) {
+        let (mut sin, mut cos) = (0f32, 0f32);
+        for (i, s) in block.iter().enumerate() {
+            let phase = 2. * PI * TONE_HZ * i as f32 / rate;
+            sin += s * phase.sin();
+            cos += s * phase.cos();
+        }
+        let n = block.len() as f32;
+        let (a, b) = (2. * sin / n, 2. * cos / n);
+        signal += (a * a + b * b) / 2. * n;
+        total += block.iter().map(|s| s * s).sum::<f32>();
+    }
+    let noise = (total - signal).max(1e-12);
+    let samples = tone.len().max(1) as f32;
+    let level = 10. * (total / samples).max(1e-12).log10();
+    (10. * (signal.max(1e-12) / noise).log10(), level)
+}
+
+/// Runs of [`DROPOUT_WINDOW`]s far below the median tone level, and the
+/// windows they cover.
+fn dropouts(tone: &[f32]) -> (usize, usize) {
+    let mut levels: Vec<f32> = tone
+        .chunks_exact(blocks(DROPOUT_WINDOW))
+        .map(|window| window.iter().map(|s| s * s).sum::<f32>().sqrt())
+        .collect();
+    let windows = levels.clone();
+    levels.sort_by(f32::total_cmp);
+    let Some(&median) = levels.get(levels.len() / 2) else {
+        return (0, 0);
+    };
+    let (mut runs, mut dropped, mut in_run) = (0, 0, false);
+    for level in windows {
+        let low = level < median * DROPOUT_LEVEL;
+        if low {
+            dropped += 1;
+            if !in_run {
+                runs += 1;
+            }
+        }
+        in_run = low;
+    }
+    (runs, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_a_delayed_noisy_signal_with_a_dropout() {
+        let delay = blocks(Duration::from_millis(120));
+        let mut recording = vec![0.; delay];
+        recording.extend(mono_signal());
+        recording.extend(vec![0.; blocks(MAX_LATENCY) - delay]);
+        // a little deterministic noise, and 20 ms of silence in the tone.
+        for (i, sample) in recording.iter_mut().enumerate() {
+            *sample += 0.001 * ((i * 7919 % 1000) as f32 / 500. - 1.);
+        }
+        let tone_start = delay + blocks(LEAD_IN + CHIRP + GAP);
+        let gap = tone_start + blocks(Duration::from_secs(1));
+        recording[gap..gap + blocks(Duration::from_millis(20))].fill(0.);
+
+        let report = analyze(&recording, 0.05).unwrap();
+        assert!((report.latency_ms - 170.).abs() < 1., "{report:?}");
+        assert!(report.correlation > 0.9, "{report:?}");
+        assert_eq!(report.dropouts, 1, "{report:?}");
+        // the dropout is most of the noise.
+        assert!(report.snr_db > 15., "{report:?}");
+
+        let thresholds = AnalysisThresholds {
+            min_snr_db: 20.,
+            max_latency: Duration::from_millis(100),
+            max_dropouts: 0,
+        };
+        assert!(report.check(&thresholds).is_err());
+        assert!(analyze(&vec![0.; recording.len()], 0.).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Spoken announcements ("caller joined") mixed into playback and, optionally,
+//! into the published audio.
+//!
+//! Text is synthesized off the audio threads; the samples are queued on clips
+//! that the playback mixer (and the capture insert, for [`AnnounceTarget::Call`])
+//! drain one tick at a time.
+
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{capture::AudioCapture, clip::Clip, playback::AudioPlayback};
+
+#[cfg(feature = "tts")]
+pub use super::tts::Speech;
+
+/// Stand-in when built without a speech engine.
+#[cfg(not(feature = "tts"))]
+#[derive(Debug, Clone)]
+pub struct Speech;
+
+#[cfg(not(feature = "tts"))]
+impl Speech {
+    pub fn new(_voice: Option<String>) -> Result<Self> {
+        anyhow::bail!("announcements need a build with the `tts` feature")
+    }
+
+    pub fn synthesize(&self, _text: &str) -> Result<Vec<f32>> {
+        unreachable!("Speech cannot be constructed without the tts feature")
+    }
+}
+
+/// Who hears announcements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceTarget {
+    /// Only the local speakers.
+    Local,
+    /// The local speakers and the remote side, mixed into the published audio.
+    Call,
+}
+
+impl FromStr for AnnounceTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "call" => Ok(Self::Call),
+            _ => Err(anyhow!("expected local or call, got `{s}`")),
+        }
+    }
+}
+
+/// Things worth announcing during a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallEvent {
+    Joined,
+    Left,
+    Paused,
+    Resumed,
+}
+
+/// Announcement templates; `{name}` is replaced by the participant's name and
+/// an empty template silences the event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Messages {
+    pub joined: String,
+    pub left: String,
+    pub paused: String,
+    pub resumed: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self {
+            joined: "{name} joined".to_string(),
+            left: "{name} left".to_string(),
+            paused: "{name} paused".to_string(),
+            resumed: "{name} is back".to_string(),
+        }
+    }
+}
+
+impl Messages {
+    fn text(&self, event: CallEvent, name: &str) -> Option<String> {
+        let template = match event {
+            CallEvent::Joined => &self.joined,
+            CallEvent::Left => &self.left,
+            CallEvent::Paused => &self.paused,
+            CallEvent::Resumed => &self.resumed,
+        };
+        (!template.is_empty()).then(|| template.replace("{name}", name))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnnounceOptions {
+    pub target: AnnounceTarget,
+    /// espeak-ng voice, e.g. `en-us`.
+    pub voice: Option<String>,
+    pub messages: Messages,
+}
+
+#[derive(Debug, Clone)]
+pub struct Announcer {
+    sender: mpsc::UnboundedSender<String>,
+    messages: Arc<Messages>,
+}
+
+impl Announcer {
+    pub async fn start(
+        options: AnnounceOptions,
+        playback: &AudioPlayback,
+        capture: &AudioCapture,
+    ) -> Result<Self> {
+        let speech = Speech::new(options.voice)?;
+        let mut clips = vec![Clip::default()];
+        playback.add_source(clips[0].clone()).await?;
+        if options.target == AnnounceTarget::Call {
+            clips.push(Clip::default());
+            capture.add_insert(clips[1].clone()).await?;
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(text) = receiver.recv().await {
+                let speech = speech.clone();
+                let synthesized = tokio::task::spawn_blocking({
+                    let text = text.clone();
+                    move || speech.synthesize(&text)
+                })
+                .await;
+                match synthesized {
+                    Ok(Ok(samples)) => {
+                        debug!(%text, samples = samples.len(), "announcing");
+                        for clip in &clips {
+                            clip.push(&samples);
+                        }
+                    }
+                    Ok(Err(err)) => warn!(%text, "failed to synthesize announcement: {err:?}"),
+                    Err(err) => warn!(%text, "speech synthesis panicked: {err}"),
+                }
+            }
+        });
+        Ok(Self {
+            sender,
+            messages: Arc::new(options.messages),
+        })
+    }
+
+    /// Speak arbitrary text.
+    pub fn say(&self, text: impl Into<String>) {
+        let _ = self.sender.send(text.into());
+    }
+
+    /// Announce `event` for the participant `name`, unless its message is off.
+    pub fn event(&self, event: CallEvent, name: &str) {
+        if let Some(text) = self.messages.text(event, name) {
+            self.say(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_fill_in_names_and_empty_ones_are_silent() {
+        let messages = Messages {
+            paused: String::new(),
+            ..Default::default()
+        };
+        assert_eq!(
+            messages.text(CallEvent::Joined, "caller").as_deref(),
+            Some("caller joined")
+        );
+        assert_eq!(messages.text(CallEvent::Paused, "caller"), None);
+    }
+}
@@ -0,0 +1,54 @@
+//! Short sine beeps, e.g. to warn that a call is about to end, and the
+//! doorbell chime that announces a caller.
+
+use std::{f32::consts::PI, time::Duration};
+
+use super::ENGINE_FORMAT;
+
+const FREQUENCY: f32 = 880.;
+const BEEP: Duration = Duration::from_millis(150);
+const GAP: Duration = Duration::from_millis(150);
+const RAMP: Duration = Duration::from_millis(5);
+const AMPLITUDE: f32 = 0.3;
+/// "Ding-dong": E5 then C5, each fading out like a struck bell.
+const CHIME: [f32; 2] = [659.25, 523.25];
+const CHIME_NOTE: Duration = Duration::from_millis(600);
+/// Time for a chime note to fade to 1/e.
+const CHIME_DECAY: f32 = 0.2;
+
+/// Interleaved stereo samples in [`ENGINE_FORMAT`] for `count` beeps.
+pub fn beeps(count: usize) -> Vec<f32> {
+    let sample_rate = ENGINE_FORMAT.sample_rate.0 as f32;
+    let beep_blocks = ENGINE_FORMAT.block_count(BEEP);
+    let ramp_blocks = ENGINE_FORMAT.block_count(RAMP) as f32;
+    let gap = ENGINE_FORMAT.sample_count(GAP);
+    let mut out = Vec::new();
+    for _ in 0..count {
+        for i in 0..beep_blocks {
+            let t = i as f32 / sample_rate;
+            let envelope = (i.min(beep_blocks - 1 - i) as f32 / ramp_blocks).min(1.);
+            let sample = AMPLITUDE * envelope * (2. * PI * FREQUENCY * t).sin();
+            out.extend([sample, sample]);
+        }
+        out.extend(std::iter::repeat_n(0., gap));
+    }
+    out
+}
+
+/// Interleaved stereo samples in [`ENGINE_FORMAT`] for a two-note chime.
+pub fn chime() -> Vec<f32> {
+    let sample_rate = ENGINE_FORMAT.sample_rate.0 as f32;
+    let note_blocks = ENGINE_FORMAT.block_count(CHIME_NOTE);
+    let ramp_blocks = ENGINE_FORMAT.block_count(RAMP) as f32;
+    let mut out = Vec::new();
+    for frequency in CHIME {
+        for i in 0..note_blocks {
+            let t = i as f32 / sample_rate;
+            let attack = (i as f32 / ramp_blocks).min(1.);
+            let envelope = attack * (-t / CHIME_DECAY).exp();
+            let sample = AMPLITUDE * envelope * (2. * PI * frequency * t).sin();
+            out.extend([sample, sample]);
+        }
+    }
+    out
+}
@@ -0,0 +1,163 @@
+//! Mixing N inputs into M buses, each hearing everything but itself.
+//!
+//! Every participant of a bridge has a bus of their own, which they are sent,
+//! and which by default carries everyone but them (mix-minus). The config's
+//! `[matrix]` changes what a bus hears, and adds buses that are nobody's,
+//! e.g. a program mix that `--record` writes next to the participants:
+//!
+//! ```toml
+//! [matrix]
+//! interpreter = ["host"]              # only the host
+//! audience = ["*", "-interpreter"]    # everyone but the interpreter
+//! program = ["*"]                     # everyone, for the recording
+//! ```
+//!
+//! `*` stands for everyone and `-name` leaves `name` out. Whatever the
+//! matrix says, a bus never carries its own input, and every bus carries the
+//! bridge's alerts.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// What each bus hears, by bus; buses not in it hear everyone else.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct RoutingMatrix(BTreeMap<String, Vec<String>>);
+
+impl RoutingMatrix {
+    /// Whether `bus` carries the input `input`.
+    pub fn hears(&self, bus: &str, input: &str) -> bool {
+        if bus == input {
+            return false;
+        }
+        let Some(rule) = self.0.get(bus) else {
+            return true;
+        };
+        let listed = |entry: &str| rule.iter().any(|rule| rule == entry);
+        (listed("*") || listed(input)) && !listed(&format!("-{input}"))
+    }
+
+    /// The buses the matrix names.
+    pub fn buses(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(String::as_str)
+    }
+}
+
+/// One input of a tick: whose it is, and its audio.
+pub trait BusInput {
+    fn name(&self) -> &str;
+    fn samples(&self) -> &[f32];
+}
+
+impl BusInput for (&str, &[f32]) {
+    fn name(&self) -> &str {
+        self.0
+    }
+
+    fn samples(&self) -> &[f32] {
+        self.1
+    }
+}
+
+/// Mixes one tick of the inputs into any of the buses.
+#[derive(Debug, Default)]
+pub struct BusMixer {
+    matrix: RoutingMatrix,
+    /// Everything of the current tick, for the buses that hear everyone
+    /// else.
+    sum: Vec<f32>,
+}
+
+impl BusMixer {
+    pub fn new(matrix: RoutingMatrix) -> Self {
+        Self {
+            matrix,
+            sum: Vec::new(),
+        }
+    }
+
+    pub fn matrix(&self) -> &RoutingMatrix {
+        &self.matrix
+    }
+
+    /// Start a tick of `shared` audio, which every bus carries, e.g. alerts,
+    /// and of `inputs` by name.
+    pub fn start(&mut self, shared: &[f32], inputs: &[impl BusInput]) {
+        self.sum.clear();
+        self.sum.extend_from_slice(shared);
+        for input in inputs {
+            for (sum, sample) in self.sum.iter_mut().zip(input.samples()) {
+                *sum += sample;
+            }
+        }
+    }
+
+    /// Mix `bus` into `out`, from the `shared` audio and `inputs` the tick
+    /// was started with, clamped so that many loud voices cannot wrap around.
+    pub fn mix(&self, bus: &str, shared: &[f32], inputs: &[impl BusInput], out: &mut [f32]) {
+        let own = inputs.iter().find(|input| input.name() == bus);
+        match own {
+            // everyone but the bus's own input: the sum, less that.
+            Some(own) if !self.matrix.0.contains_key(bus) => {
+                for ((out, sum), own) in out.iter_mut().zip(&self.sum).zip(own.samples()) {
+                    *out = sum - own;
+                }
+            }
+            _ => {
+                out.copy_from_slice(shared);
+                let heard = inputs
+                    .iter()
+                    .filter(|input| self.matrix.hears(bus, input.name()));
+                for input in heard {
+                    for (out, sample) in out.iter_mut().zip(input.samples()) {
+                        *out += sample;
+                    }
+                }
+            }
+        }
+        for out in out {
+            *out = out.clamp(-1., 1.);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buses_hear_what_the_matrix_routes_to_them() {
+        let matrix: RoutingMatrix = toml::from_str(
+            r#"
+            bob = ["alice"]
+            carol = ["*", "-alice"]
+            program = ["*"]
+            "#,
+        )
+        .unwrap();
+        let inputs: [(&str, &[f32]); 3] = [
+            ("alice", &[0.1, 0.2]),
+            ("bob", &[0.3, -0.2]),
+            ("carol", &[0.9, 0.9]),
+        ];
+        let shared = [0.; 2];
+        let mut buses = BusMixer::new(matrix);
+        buses.start(&shared, &inputs);
+        let mut out = [0.; 2];
+
+        // alice has no rule: everyone else, 1.2 clamped.
+        buses.mix("alice", &shared, &inputs, &mut out);
+        assert_eq!(out[0], 1.);
+        assert!((out[1] - 0.7).abs() < 1e-6);
+        buses.mix("bob", &shared, &inputs, &mut out);
+        assert!((out[0] - 0.1).abs() < 1e-6);
+        // neither alice nor herself.
+        buses.mix("carol", &shared, &inputs, &mut out);
+        assert!((out[0] - 0.3).abs() < 1e-6);
+        buses.mix("program", &shared, &inputs, &mut out);
+        assert_eq!(out[0], 1.);
+        assert!((out[1] - 0.9).abs() < 1e-6);
+        assert_eq!(buses.matrix().buses().count(), 3);
+    }
+}
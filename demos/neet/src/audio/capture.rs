@@ -4,7 +4,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
 use cpal::{
     traits::{DeviceTrait, StreamTrait},
     Device, SampleFormat,
@@ -18,19 +18,56 @@ use ringbuf::{
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, trace_span, warn, Level};
 
+#[cfg(feature = "virtual-audio")]
+use super::virtual_device::{start_virtual_capture, VirtualInput};
+#[cfg(target_os = "macos")]
+use super::vpio::{VoiceUnit, VOICE_PROCESSING_DEVICE};
 use super::{
-    device::{find_device, find_input_stream_config, Direction, StreamConfigWithFormat},
-    AudioFormat, WebrtcAudioProcessor, DURATION_10MS, DURATION_20MS, ENGINE_FORMAT,
+    device::{
+        find_device, find_input_stream_config, next_device, Direction, RunningStream,
+        StreamConfigWithFormat,
+    },
+    greeting::GreetingCue,
+    hooks::{FrameProcessor, Processors},
+    pipe::{pipe_command, start_pipe_capture, PIPE_PREFIX},
+    power::{BusyTimer, Pacing},
+    AudioFormat, AudioMode, AudioSource, WebrtcAudioProcessor, DURATION_10MS, DURATION_20MS,
+    ENGINE_FORMAT,
 };
-use crate::{codec::opus::MediaTrackOpusEncoder, media::MediaTrack};
+use crate::{
+    codec::{budget::ErrorBudget, track::MediaTrackEncoder, BitrateTarget, Codec},
+    error::NeetError,
+    media::{MediaTrack, OverflowPolicy, PauseState},
+    stats::Counter,
+};
+
+/// The input device name that opens no device: capture is silent, e.g.
+/// because what we send comes from elsewhere.
+pub const NO_INPUT_DEVICE: &str = "none";
+/// Captured audio buffered between the device and the capture loop.
+const CAPTURE_BUFFER_SIZE: usize = ENGINE_FORMAT.sample_count(DURATION_20MS) * 16;
 
 pub trait AudioSink: Send + 'static {
     fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>>;
 }
 
+/// Asks the capture thread to move to the next input device; answered with
+/// the new device's name.
+/// The input device to switch to, `None` for the next one, and where to
+/// send the new device's name.
+type SwitchRequest = (Option<String>, oneshot::Sender<Result<String>>);
+
 #[derive(Debug, Clone)]
 pub struct AudioCapture {
     sink_sender: mpsc::Sender<Box<dyn AudioSink>>,
+    processor_sender: mpsc::Sender<Box<dyn FrameProcessor>>,
+    switch_sender: mpsc::Sender<SwitchRequest>,
+    overflow: OverflowPolicy,
+    mode: AudioMode,
+    pacing: Pacing,
+    /// The unit capturing, if it is a voice processing one.
+    #[cfg(target_os = "macos")]
+    voice_unit: Option<VoiceUnit>,
 }
 
 impl AudioCapture {
@@ -38,49 +75,74 @@ impl AudioCapture {
         host: &cpal::Host,
         device: Option<&str>,
         processor: WebrtcAudioProcessor,
+        overflow: OverflowPolicy,
+        mode: AudioMode,
+        pacing: Pacing,
+        busy: Counter,
     ) -> Result<Self> {
-        let device = find_device(host, Direction::Capture, device)?;
-
-        // find a config for the capture stream. note that the returned config may not
-        // match the format. the passed format is a hint as to which stream config
-        // to prefer if there are multiple. if no matching format is found, the
-        // device's default stream config is used.
-        let stream_config = find_input_stream_config(&device, &ENGINE_FORMAT)?;
-
-        let buffer_size = ENGINE_FORMAT.sample_count(DURATION_20MS) * 16;
-        let (producer, consumer) = ringbuf::HeapRb::<f32>::new(buffer_size).split();
+        let device = InputDevice::find(host, device)?;
+        #[cfg(target_os = "macos")]
+        let voice_unit = match &device {
+            InputDevice::VoiceProcessing(unit) => Some(unit.clone()),
+            _ => None,
+        };
 
         // a channel to pass new sinks to the the audio thread.
         let (sink_sender, sink_receiver) = mpsc::channel(16);
+        // and processors of the captured audio.
+        let (processor_sender, processor_receiver) = mpsc::channel(16);
+        let (switch_sender, switch_receiver) = mpsc::channel(1);
 
         let (init_tx, init_rx) = oneshot::channel();
         std::thread::spawn(move || {
             if let Err(err) = audio_thread_priority::promote_current_thread_to_real_time(
-                buffer_size as u32,
+                CAPTURE_BUFFER_SIZE as u32,
                 ENGINE_FORMAT.sample_rate.0,
             ) {
                 warn!("failed to set capture thread to realtime priority: {err:?}");
             }
 
-            let stream = match start_capture_stream(&device, &stream_config, producer, processor) {
-                Ok(stream) => {
+            let input = match CaptureInput::start(device, processor, busy) {
+                Ok(input) => {
                     init_tx.send(Ok(())).unwrap();
-                    stream
+                    input
                 }
                 Err(err) => {
-                    let err = err.context("failed to start capture stream");
+                    let err = err.context(NeetError::AudioBackend(
+                        "failed to start capture stream".to_string(),
+                    ));
                     init_tx.send(Err(err)).unwrap();
                     return;
                 }
             };
-            capture_loop(consumer, sink_receiver);
-            drop(stream);
+            capture_loop(
+                input,
+                sink_receiver,
+                processor_receiver,
+                switch_receiver,
+                pacing,
+            );
         });
         init_rx.await??;
-        let handle = AudioCapture { sink_sender };
+        let handle = AudioCapture {
+            sink_sender,
+            processor_sender,
+            switch_sender,
+            overflow,
+            mode,
+            pacing,
+            #[cfg(target_os = "macos")]
+            voice_unit,
+        };
         Ok(handle)
     }
 
+    /// The voice processing unit capturing, for playback to start.
+    #[cfg(target_os = "macos")]
+    pub fn voice_unit(&self) -> Option<VoiceUnit> {
+        self.voice_unit.clone()
+    }
+
     pub async fn add_sink(&self, sink: impl AudioSink) -> Result<()> {
         self.sink_sender
             .send(Box::new(sink))
@@ -88,18 +150,234 @@ impl AudioCapture {
             .map_err(|_| anyhow!("failed to add captue sink: capture loop dead"))
     }
 
-    pub async fn create_opus_track(&self) -> Result<MediaTrack> {
-        let (encoder, track) = MediaTrackOpusEncoder::new(16, ENGINE_FORMAT)?;
+    /// Run `processor` over the captured audio, after echo cancellation and
+    /// before any sink sees it.
+    pub async fn add_processor(&self, processor: impl FrameProcessor) -> Result<()> {
+        self.processor_sender
+            .send(Box::new(processor))
+            .await
+            .map_err(|_| anyhow!("failed to add capture processor: capture loop dead"))
+    }
+
+    /// Mix `source` into the captured audio, after echo cancellation and
+    /// before any sink sees it.
+    pub async fn add_insert(&self, source: impl AudioSource) -> Result<()> {
+        let buf = vec![0.; ENGINE_FORMAT.sample_count(self.pacing.tick)];
+        let insert = Insert {
+            source: Box::new(source),
+            buf,
+        };
+        self.add_processor(insert).await
+    }
+
+    /// Stop capturing from the current input device and start on the next
+    /// one in `list-devices` order. Returns the new device's name.
+    pub async fn next_input(&self) -> Result<String> {
+        self.switch(None).await
+    }
+
+    /// Stop capturing from the current input device and start on `device`,
+    /// named as for `--input-device`. Returns the new device's name.
+    pub async fn switch_input(&self, device: &str) -> Result<String> {
+        self.switch(Some(device.to_string())).await
+    }
+
+    async fn switch(&self, device: Option<String>) -> Result<String> {
+        let (reply, answer) = oneshot::channel();
+        self.switch_sender
+            .send((device, reply))
+            .await
+            .map_err(|_| anyhow!("failed to switch input device: capture loop dead"))?;
+        answer.await?
+    }
+
+    /// A track of the captured audio encoded with `codec`, which has to be
+    /// stereo like the capture. Frames that overflow it are counted in
+    /// `dropped`; nothing is encoded while `paused`. Frames that fail to
+    /// encode are skipped within `errors`. While `greeting` plays, it is
+    /// encoded instead.
+    pub async fn create_track(
+        &self,
+        codec: Codec,
+        bitrate: BitrateTarget,
+        paused: PauseState,
+        dropped: Counter,
+        errors: ErrorBudget,
+        greeting: Option<&GreetingCue>,
+    ) -> Result<MediaTrack> {
+        // mono tracks downmix the engine's stereo.
+        ensure!(
+            codec.layout().channels() <= ENGINE_FORMAT.channel_count as usize,
+            "can't encode captured audio as {}",
+            codec.layout()
+        );
+        let (encoder, track) =
+            MediaTrackEncoder::new(codec, self.mode, 16, self.overflow, dropped, paused)?;
+        let mut encoder = encoder
+            .with_bitrate(bitrate)
+            .with_frame_duration(self.pacing.opus_frame)
+            .with_error_budget(errors);
+        if let Some(greeting) = greeting {
+            let tick_samples = ENGINE_FORMAT.sample_count(self.pacing.tick);
+            encoder = encoder.with_greeting(greeting.reader(tick_samples));
+        }
         self.add_sink(encoder).await?;
         Ok(track)
     }
 }
 
+/// Where captured audio comes from.
+enum InputDevice {
+    Cpal {
+        device: Device,
+        config: StreamConfigWithFormat,
+    },
+    #[cfg(feature = "virtual-audio")]
+    Virtual(VirtualInput),
+    /// A command writing PCM to stdout.
+    Pipe(String),
+    /// The capture side of a voice processing unit its playback starts.
+    #[cfg(target_os = "macos")]
+    VoiceProcessing(VoiceUnit),
+    None,
+}
+
+impl InputDevice {
+    fn name(&self) -> String {
+        match self {
+            InputDevice::Cpal { device, .. } => device.name().unwrap_or_default(),
+            #[cfg(feature = "virtual-audio")]
+            InputDevice::Virtual(_) => super::virtual_device::VIRTUAL_DEVICE.to_string(),
+            InputDevice::Pipe(command) => format!("{PIPE_PREFIX}{command}"),
+            #[cfg(target_os = "macos")]
+            InputDevice::VoiceProcessing(_) => VOICE_PROCESSING_DEVICE.to_string(),
+            InputDevice::None => NO_INPUT_DEVICE.to_string(),
+        }
+    }
+
+    /// The device after the one named `current`.
+    fn after(current: &str) -> Result<Self> {
+        ensure!(
+            current != NO_INPUT_DEVICE && pipe_command(current).is_none(),
+            "the input is not a device"
+        );
+        #[cfg(target_os = "macos")]
+        ensure!(
+            current != VOICE_PROCESSING_DEVICE,
+            "voice processing has no next device"
+        );
+        #[cfg(feature = "virtual-audio")]
+        if VirtualInput::from_device(current)?.is_some() {
+            anyhow::bail!("a virtual input has no next device");
+        }
+        let (_, device) = next_device(&cpal::default_host(), Direction::Capture, current)?;
+        let config = find_input_stream_config(&device, &ENGINE_FORMAT)?;
+        Ok(InputDevice::Cpal { device, config })
+    }
+
+    fn find(host: &cpal::Host, name: Option<&str>) -> Result<Self> {
+        if name == Some(NO_INPUT_DEVICE) {
+            return Ok(InputDevice::None);
+        }
+        if let Some(command) = name.and_then(pipe_command) {
+            return Ok(InputDevice::Pipe(command.to_string()));
+        }
+        #[cfg(target_os = "macos")]
+        if name == Some(VOICE_PROCESSING_DEVICE) {
+            return Ok(InputDevice::VoiceProcessing(VoiceUnit::default()));
+        }
+        #[cfg(feature = "virtual-audio")]
+        if let Some(input) = name.map(VirtualInput::from_device).transpose()?.flatten() {
+            return Ok(InputDevice::Virtual(input));
+        }
+        let device = find_device(host, Direction::Capture, name)?;
+        // find a config for the capture stream. note that the returned config may not
+        // match the format. the passed format is a hint as to which stream config
+        // to prefer if there are multiple. if no matching format is found, the
+        // device's default stream config is used.
+        let config = find_input_stream_config(&device, &ENGINE_FORMAT)?;
+        Ok(InputDevice::Cpal { device, config })
+    }
+
+    fn start(
+        self,
+        producer: Producer<f32>,
+        processor: WebrtcAudioProcessor,
+        busy: Counter,
+    ) -> Result<RunningStream> {
+        match self {
+            InputDevice::Cpal { device, config } => {
+                let stream = start_capture_stream(&device, &config, producer, processor, busy)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(feature = "virtual-audio")]
+            InputDevice::Virtual(input) => {
+                let stream = start_virtual_capture(input, producer, processor, busy)?;
+                Ok(Box::new(stream))
+            }
+            InputDevice::Pipe(command) => {
+                let stream = start_pipe_capture(&command, producer, processor, busy)?;
+                Ok(Box::new(stream))
+            }
+            // the unit starts with playback.
+            #[cfg(target_os = "macos")]
+            InputDevice::VoiceProcessing(unit) => {
+                unit.capture(producer, busy);
+                Ok(Box::new(()))
+            }
+            // nothing is ever produced.
+            InputDevice::None => Ok(Box::new(producer)),
+        }
+    }
+}
+
+/// A running input stream, and what it takes to start another one.
+struct CaptureInput {
+    name: String,
+    consumer: Consumer<f32>,
+    processor: WebrtcAudioProcessor,
+    busy: Counter,
+    /// Dropped last, once nothing reads from it anymore.
+    _stream: RunningStream,
+}
+
+impl CaptureInput {
+    fn start(device: InputDevice, processor: WebrtcAudioProcessor, busy: Counter) -> Result<Self> {
+        let name = device.name();
+        let (producer, consumer) = ringbuf::HeapRb::<f32>::new(CAPTURE_BUFFER_SIZE).split();
+        let stream = device.start(producer, processor.clone(), busy.clone())?;
+        Ok(Self {
+            name,
+            consumer,
+            processor,
+            busy,
+            _stream: stream,
+        })
+    }
+
+    /// Start `device`, or the next input device, leaving this one running if
+    /// that fails.
+    fn switch(&self, device: Option<&str>) -> Result<Self> {
+        let device = match device {
+            Some(name) => InputDevice::find(&cpal::default_host(), Some(name))?,
+            None => InputDevice::after(&self.name)?,
+        };
+        // nothing would start its unit.
+        #[cfg(target_os = "macos")]
+        ensure!(
+            !matches!(device, InputDevice::VoiceProcessing(_)),
+            "voice processing can't be switched to during a call"
+        );
+        Self::start(device, self.processor.clone(), self.busy.clone())
+    }
+}
+
 fn start_capture_stream(
     device: &Device,
     stream_config: &StreamConfigWithFormat,
     producer: Producer<f32>,
     processor: WebrtcAudioProcessor,
+    busy: Counter,
 ) -> Result<cpal::Stream> {
     let d = device.name()?;
     let config = &stream_config.config;
@@ -121,6 +399,7 @@ fn start_capture_stream(
         producer,
         processor: processor.clone(),
         resampler,
+        busy,
     };
     let stream = match stream_config.sample_format {
         SampleFormat::I8 => build_capture_stream::<i8>(device, config, state),
@@ -144,6 +423,8 @@ struct CaptureState {
     #[allow(unused)]
     processor: WebrtcAudioProcessor,
     resampler: FixedResampler<f32, 2>,
+    /// Time spent in the callback.
+    busy: Counter,
 }
 
 fn build_capture_stream<S: ToSample<f32> + cpal::SizedSample + Default>(
@@ -171,6 +452,7 @@ fn build_capture_stream<S: ToSample<f32> + cpal::SizedSample + Default>(
         config,
         move |data: &[S], info: &_| {
             let _guard = span.enter();
+            let _busy = BusyTimer::start(&state.busy);
             let start = Instant::now();
             let max_tick_time = state.format.duration_from_sample_count(data.len());
 
@@ -260,21 +542,27 @@ fn build_capture_stream<S: ToSample<f32> + cpal::SizedSample + Default>(
 }
 
 fn capture_loop(
-    mut consumer: Consumer<f32>,
+    mut input: CaptureInput,
     mut sink_receiver: mpsc::Receiver<Box<dyn AudioSink>>,
+    mut processor_receiver: mpsc::Receiver<Box<dyn FrameProcessor>>,
+    mut switch_receiver: mpsc::Receiver<SwitchRequest>,
+    pacing: Pacing,
 ) {
     let span = tracing::span!(Level::TRACE, "capture-loop");
     let _guard = span.enter();
     info!("capture loop start");
 
-    let tick_duration = DURATION_20MS;
+    let tick_duration = pacing.tick;
     let samples_per_tick = ENGINE_FORMAT.sample_count(tick_duration);
     let mut buf = vec![0.; samples_per_tick];
     let mut sinks = vec![];
+    let mut processors = Processors::default();
+    let busy = input.busy.clone();
 
     let mut tick = 0;
     loop {
         let start = Instant::now();
+        let timer = BusyTimer::start(&busy);
 
         // poll incoming sources
         loop {
@@ -290,7 +578,21 @@ fn capture_loop(
                 }
             }
         }
-        let count = consumer.pop_slice(&mut buf);
+        while let Ok(processor) = processor_receiver.try_recv() {
+            info!("new processor added to capture loop");
+            processors.push(processor);
+        }
+        if let Ok((device, reply)) = switch_receiver.try_recv() {
+            let result = input.switch(device.as_deref()).map(|next| {
+                info!(from = %input.name, to = %next.name, "switching input device");
+                input = next;
+                input.name.clone()
+            });
+            let _ = reply.send(result);
+        }
+        let count = input.consumer.pop_slice(&mut buf);
+
+        processors.run(&mut buf[..count]);
 
         sinks.retain_mut(|sink| match sink.tick(&buf[..count]) {
             Ok(ControlFlow::Continue(())) => true,
@@ -303,6 +605,7 @@ fn capture_loop(
                 false
             }
         });
+        drop(timer);
         trace!("tick {tick} took {:?} pulled {count}", start.elapsed());
         if start.elapsed() > tick_duration {
             warn!(
@@ -311,8 +614,29 @@ fn capture_loop(
             );
         } else {
             let sleep_time = tick_duration.saturating_sub(start.elapsed());
-            spin_sleep::sleep(sleep_time);
+            pacing.sleep(sleep_time);
         }
         tick += 1;
     }
 }
+
+/// A source mixed into the captured audio.
+struct Insert {
+    source: Box<dyn AudioSource>,
+    /// A tick of the source, allocated up front.
+    buf: Vec<f32>,
+}
+
+impl FrameProcessor for Insert {
+    fn process(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), ()>> {
+        let len = buf.len().min(self.buf.len());
+        let count = match self.source.tick(&mut self.buf[..len])? {
+            ControlFlow::Continue(count) => count,
+            ControlFlow::Break(()) => return Ok(ControlFlow::Break(())),
+        };
+        for (out, sample) in buf.iter_mut().zip(&self.buf[..count]) {
+            *out += sample;
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
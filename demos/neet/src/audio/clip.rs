@@ -0,0 +1,55 @@
+//! Queued samples that are played once, for synthesized audio such as
+//! announcements and DTMF tones.
+
+use std::{
+    collections::VecDeque,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use super::AudioSource;
+
+/// Samples in [`ENGINE_FORMAT`](super::ENGINE_FORMAT) waiting to be mixed in,
+/// shared between whoever produces them and a mixer.
+#[derive(Debug, Clone, Default)]
+pub struct Clip(Arc<Mutex<VecDeque<f32>>>);
+
+impl Clip {
+    /// Queue `samples` after whatever is still playing.
+    pub fn push(&self, samples: &[f32]) {
+        self.0.lock().unwrap().extend(samples);
+    }
+}
+
+impl AudioSource for Clip {
+    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+        // idle clips play silence rather than reporting an xrun every tick.
+        let mut queue = self.0.lock().unwrap();
+        let count = buf.len().min(queue.len());
+        for (out, sample) in buf.iter_mut().zip(queue.drain(..count)) {
+            *out = sample;
+        }
+        buf[count..].fill(0.);
+        Ok(ControlFlow::Continue(buf.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plays_queued_samples_then_silence() {
+        let mut clip = Clip::default();
+        clip.push(&[0.5; 3]);
+        let mut buf = [0.; 2];
+        assert_eq!(clip.tick(&mut buf).unwrap(), ControlFlow::Continue(2));
+        assert_eq!(buf, [0.5, 0.5]);
+        assert!(clip.tick(&mut buf).unwrap().is_continue());
+        assert_eq!(buf, [0.5, 0.]);
+        assert!(clip.tick(&mut buf).unwrap().is_continue());
+        assert_eq!(buf, [0., 0.]);
+    }
+}
@@ -0,0 +1,155 @@
+//! A virtual sink and source for the call (`--system-devices`), or a source
+//! alone (`--stream-feed`).
+//!
+//! Through PulseAudio, or PipeWire's PulseAudio server, we add a sink that
+//! other applications play into the call and a source they record the call
+//! from, as if neet were a sound card. Both are pipe modules: the sink
+//! writes what it is played into a FIFO that we capture from, and the source
+//! reads what we play from another. They are unloaded again once the audio
+//! stops, along with the FIFOs.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{ensure, Context, Result};
+use tracing::{info, warn};
+
+use super::{pipe::PIPE_PREFIX, ENGINE_FORMAT};
+
+/// Plays into a FIFO: what applications send into the call.
+const SINK_MODULE: &str = "module-pipe-sink";
+/// Records from a FIFO: what the call plays, for applications.
+const SOURCE_MODULE: &str = "module-pipe-source";
+
+/// A loaded sink and source, unloaded when dropped.
+#[derive(Debug)]
+pub struct Conduit {
+    dir: PathBuf,
+    modules: Vec<u32>,
+}
+
+impl Conduit {
+    /// Load `<name>-in`, the sink, and `<name>-out`, the source.
+    pub fn create(name: &str) -> Result<Self> {
+        let conduit = Self::load(
+            name,
+            &[
+                (SINK_MODULE, format!("{name}-in")),
+                (SOURCE_MODULE, format!("{name}-out")),
+            ],
+        )?;
+        info!(
+            sink = format!("{name}-in"),
+            source = format!("{name}-out"),
+            "added system audio devices for the call"
+        );
+        Ok(conduit)
+    }
+
+    /// Load only a source called `name`, recording what we play.
+    pub fn source(name: &str) -> Result<Self> {
+        Self::load(name, &[(SOURCE_MODULE, name.to_string())])
+    }
+
+    /// Load `modules`, each with the device name next to it.
+    fn load(name: &str, modules: &[(&str, String)]) -> Result<Self> {
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "system device names take letters, digits and dashes"
+        );
+        let dir = std::env::temp_dir().join(format!("neet-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let mut conduit = Self {
+            dir,
+            modules: Vec::new(),
+        };
+        for (module, name) in modules {
+            let id = load_module(module, &module_args(module, name, &conduit.fifo(module)))?;
+            conduit.modules.push(id);
+        }
+        Ok(conduit)
+    }
+
+    /// The capture device reading what the sink is played.
+    pub fn input_device(&self) -> String {
+        let fifo = self.fifo(SINK_MODULE);
+        format!("{PIPE_PREFIX}cat '{}'", fifo.display())
+    }
+
+    /// The playback device feeding the source.
+    pub fn output_device(&self) -> String {
+        let fifo = self.fifo(SOURCE_MODULE);
+        format!("{PIPE_PREFIX}cat > '{}'", fifo.display())
+    }
+
+    fn fifo(&self, module: &str) -> PathBuf {
+        self.dir.join(module)
+    }
+}
+
+impl Drop for Conduit {
+    fn drop(&mut self) {
+        for id in self.modules.drain(..).rev() {
+            let unloaded = Command::new("pactl")
+                .args(["unload-module", &id.to_string()])
+                .status();
+            if !matches!(unloaded, Ok(status) if status.success()) {
+                warn!(id, "failed to unload a system audio device");
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Arguments for `module`, space-separated as PulseAudio takes them, for a
+/// device called `name`.
+fn module_args(module: &str, name: &str, fifo: &Path) -> String {
+    let kind = match module {
+        SINK_MODULE => "sink",
+        _ => "source",
+    };
+    format!(
+        "{kind}_name={name} file={} format=s16le rate={} channels={} \
+         {kind}_properties=device.description={name}",
+        fifo.display(),
+        ENGINE_FORMAT.sample_rate.0,
+        ENGINE_FORMAT.channel_count,
+    )
+}
+
+fn load_module(module: &str, args: &str) -> Result<u32> {
+    let output = Command::new("pactl")
+        .arg("load-module")
+        .args([module, args])
+        .output()
+        .context("failed to run pactl; system audio devices need PulseAudio or PipeWire")?;
+    ensure!(
+        output.status.success(),
+        "pactl failed to load {module}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .parse()
+        .with_context(|| format!("pactl loaded {module} but gave no module index"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_args_name_the_devices_and_fifos() {
+        assert!(Conduit::create("neet call").is_err());
+        assert!(Conduit::create("").is_err());
+        let args = module_args(SOURCE_MODULE, "neet-out", Path::new("/tmp/neet/fifo"));
+        assert_eq!(
+            args,
+            "source_name=neet-out file=/tmp/neet/fifo format=s16le rate=48000 channels=2 \
+             source_properties=device.description=neet-out"
+        );
+    }
+}
@@ -1,14 +1,29 @@
-use anyhow::{Context, Result};
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
 use cpal::{
     traits::{DeviceTrait, HostTrait},
     BufferSize, Device, SampleFormat, StreamConfig,
     SupportedBufferSize::{Range, Unknown},
     SupportedStreamConfig, SupportedStreamConfigRange,
 };
+use regex::Regex;
+use serde::Serialize;
 use tracing::{debug, info};
 
-use super::AudioFormat;
-use crate::audio::DURATION_20MS;
+pub use self::bluetooth::HandsFree;
+pub use self::score::select_input_device;
+use super::{AnnounceOptions, AudioFormat, AudioMode, ExtraInput, PanMode};
+#[cfg(feature = "transcribe")]
+use crate::transcribe::TranscribeOptions;
+use crate::{
+    audio::{DEFAULT_LATENCY_BUDGET, DEFAULT_PLAYOUT_DELAY, DURATION_20MS},
+    error::NeetError,
+    media::{Injection, OverflowPolicy},
+};
+
+mod bluetooth;
+mod score;
 
 #[derive(Debug, Clone)]
 pub struct AudioConfig {
@@ -16,8 +31,70 @@ pub struct AudioConfig {
     pub input_device: Option<String>,
     /// The output device to use.
     pub output_device: Option<String>,
-    /// If true, audio processing with echo cancellation is enabled.
+    /// Voice call or music tuning.
+    pub mode: AudioMode,
+    /// If true, audio processing with echo cancellation is enabled. Music
+    /// mode never processes.
     pub processing_enabled: bool,
+    /// Wake up less often and encode longer frames, at the cost of latency.
+    pub low_power: bool,
+    /// What to do when encoded frames pile up between capture and publish.
+    pub capture_overflow: OverflowPolicy,
+    /// What to do when received frames pile up before the decoder.
+    pub playback_overflow: OverflowPolicy,
+    /// Audio to buffer before playing a remote track, and the least its
+    /// buffer may shrink to.
+    pub playout_delay: Duration,
+    /// Skip a remote track's buffer to live once its audio plays this late
+    /// after capture; `None` plays everything, however late.
+    pub latency_budget: Option<Duration>,
+    /// Artificial delay and jitter between capture and the network.
+    pub inject_capture: Injection,
+    /// Artificial delay and jitter between the network and playback.
+    pub inject_playback: Injection,
+    /// Where remote participants are placed in the stereo field.
+    pub pan: PanMode,
+    /// Attenuate playback by this many dB while the local user speaks; 0 disables.
+    pub duck_db: f32,
+    /// Remote tracks that duck the other remote tracks while they play, by
+    /// `duck_db`, or 12 dB if that is 0.
+    pub duck_for: Vec<String>,
+    /// Report DTMF digits in received audio.
+    pub detect_dtmf: bool,
+    /// Speak call events into playback (and possibly the call).
+    pub announce: Option<AnnounceOptions>,
+    /// Keep this much capture and playback audio for debug pictures.
+    pub scope: Option<Duration>,
+    /// Report a microphone that stays near-silent or stops delivering audio
+    /// for this long.
+    pub mic_watchdog: Option<Duration>,
+    /// Move on to the next input device when the watchdog fires.
+    pub mic_failover: bool,
+    /// Codec errors a track survives per minute.
+    pub codec_error_budget: u32,
+    /// Add a system sink and source with this name prefix and use them
+    /// instead of the devices.
+    pub system_devices: Option<String>,
+    /// Record from another input than a bluetooth headset's microphone, which
+    /// would drop the headset to hands-free quality.
+    pub keep_a2dp: bool,
+    /// Further input devices, each published as a track of its own.
+    pub extra_inputs: Vec<ExtraInput>,
+    /// Output device by remote track, for tracks that don't play on
+    /// `output_device`.
+    pub routes: BTreeMap<String, String>,
+    /// Also play remote audio into a system source of this name, for
+    /// streaming software to record.
+    pub stream_feed: Option<String>,
+    /// Tell WebSocket clients on this address who joins and leaves.
+    pub stream_events: Option<SocketAddr>,
+    /// Use Apple's voice processing unit as input and output device, and its
+    /// echo cancellation instead of ours.
+    #[cfg(target_os = "macos")]
+    pub voice_processing: bool,
+    /// Transcribe call audio with whisper.
+    #[cfg(feature = "transcribe")]
+    pub transcribe: Option<TranscribeOptions>,
 }
 
 impl Default for AudioConfig {
@@ -35,7 +112,34 @@ impl Default for AudioConfig {
         Self {
             input_device,
             output_device,
+            mode: AudioMode::default(),
             processing_enabled: true,
+            low_power: false,
+            capture_overflow: OverflowPolicy::default(),
+            playback_overflow: OverflowPolicy::default(),
+            playout_delay: DEFAULT_PLAYOUT_DELAY,
+            latency_budget: Some(DEFAULT_LATENCY_BUDGET),
+            inject_capture: Injection::default(),
+            inject_playback: Injection::default(),
+            pan: PanMode::default(),
+            duck_db: 0.,
+            duck_for: Vec::new(),
+            detect_dtmf: false,
+            announce: None,
+            scope: None,
+            mic_watchdog: None,
+            mic_failover: false,
+            codec_error_budget: 0,
+            system_devices: None,
+            keep_a2dp: false,
+            extra_inputs: Vec::new(),
+            routes: BTreeMap::new(),
+            stream_feed: None,
+            stream_events: None,
+            #[cfg(target_os = "macos")]
+            voice_processing: false,
+            #[cfg(feature = "transcribe")]
+            transcribe: None,
         }
     }
 }
@@ -46,23 +150,182 @@ pub enum Direction {
     Playback,
 }
 
+impl Direction {
+    fn label(self) -> &'static str {
+        match self {
+            Direction::Capture => "input",
+            Direction::Playback => "output",
+        }
+    }
+}
+
 pub fn list_devices() -> Result<Devices> {
     let host = cpal::default_host();
-    let input = host
-        .input_devices()?
-        .filter_map(|x| x.name().ok())
-        .collect();
-    let output = host
-        .output_devices()?
-        .filter_map(|x| x.name().ok())
-        .collect();
-    Ok(Devices { input, output })
+    Ok(Devices {
+        input: device_infos(&host, Direction::Capture)?,
+        output: device_infos(&host, Direction::Playback)?,
+    })
 }
 
-#[derive(Debug, Default)]
+/// Devices in the order `list-devices` numbers them.
+#[derive(Debug, Default, Serialize)]
 pub struct Devices {
-    pub input: Vec<String>,
-    pub output: Vec<String>,
+    pub input: Vec<DeviceInfo>,
+    pub output: Vec<DeviceInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub is_default: bool,
+    /// The configuration the device prefers, if it reports one.
+    pub default_config: Option<ConfigInfo>,
+    pub supported_configs: Vec<ConfigRangeInfo>,
+    /// Why the configurations could not be queried, if they couldn't.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigInfo {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub sample_format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConfigRangeInfo {
+    pub channels: u16,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub sample_format: String,
+}
+
+fn device_infos(host: &cpal::Host, direction: Direction) -> Result<Vec<DeviceInfo>> {
+    let default_name = match direction {
+        Direction::Capture => host.default_input_device(),
+        Direction::Playback => host.default_output_device(),
+    }
+    .and_then(|device| device.name().ok());
+    let devices = sorted_devices(host, direction)?;
+    Ok(devices
+        .into_iter()
+        .enumerate()
+        .map(|(index, (name, device))| {
+            let (default_config, supported) = match direction {
+                Direction::Capture => (
+                    device.default_input_config().ok(),
+                    device
+                        .supported_input_configs()
+                        .map(|configs| configs.collect::<Vec<_>>()),
+                ),
+                Direction::Playback => (
+                    device.default_output_config().ok(),
+                    device
+                        .supported_output_configs()
+                        .map(|configs| configs.collect::<Vec<_>>()),
+                ),
+            };
+            let (supported_configs, error) = match supported {
+                Ok(configs) => (configs.iter().map(ConfigRangeInfo::from).collect(), None),
+                Err(err) => (Vec::new(), Some(err.to_string())),
+            };
+            DeviceInfo {
+                index,
+                is_default: default_name.as_ref() == Some(&name),
+                name,
+                default_config: default_config.as_ref().map(ConfigInfo::from),
+                supported_configs,
+                error,
+            }
+        })
+        .collect())
+}
+
+impl From<&SupportedStreamConfig> for ConfigInfo {
+    fn from(config: &SupportedStreamConfig) -> Self {
+        Self {
+            channels: config.channels(),
+            sample_rate: config.sample_rate().0,
+            sample_format: config.sample_format().to_string(),
+        }
+    }
+}
+
+impl From<&SupportedStreamConfigRange> for ConfigRangeInfo {
+    fn from(config: &SupportedStreamConfigRange) -> Self {
+        Self {
+            channels: config.channels(),
+            min_sample_rate: config.min_sample_rate().0,
+            max_sample_rate: config.max_sample_rate().0,
+            sample_format: config.sample_format().to_string(),
+        }
+    }
+}
+
+/// Devices sorted by name, so indices don't depend on enumeration order.
+fn sorted_devices(host: &cpal::Host, direction: Direction) -> Result<Vec<(String, Device)>> {
+    let devices = match direction {
+        Direction::Capture => host.input_devices()?,
+        Direction::Playback => host.output_devices()?,
+    };
+    let mut devices: Vec<_> = devices
+        .filter_map(|device| Some((device.name().ok()?, device)))
+        .collect();
+    devices.sort_by(|(a, _), (b, _)| a.to_lowercase().cmp(&b.to_lowercase()).then(a.cmp(b)));
+    Ok(devices)
+}
+
+/// Pick a device by `spec`: an index from `list-devices`, a `/regex/`, an
+/// exact name, or a case-insensitive substring matching exactly one device.
+fn resolve_device(names: &[String], spec: &str, direction: Direction) -> Result<usize> {
+    let label = direction.label();
+    if let Ok(index) = spec.parse::<usize>() {
+        if index >= names.len() {
+            bail!(
+                "no {label} device with index {index}: there are {}",
+                names.len()
+            );
+        }
+        return Ok(index);
+    }
+    if let Some(index) = names.iter().position(|name| name == spec) {
+        return Ok(index);
+    }
+    let matches: Vec<usize> = match spec
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+    {
+        Some(pattern) => {
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("invalid {label} device pattern `{pattern}`"))?;
+            (0..names.len())
+                .filter(|&i| regex.is_match(&names[i]))
+                .collect()
+        }
+        None => {
+            let needle = spec.to_lowercase();
+            (0..names.len())
+                .filter(|&i| names[i].to_lowercase().contains(&needle))
+                .collect()
+        }
+    };
+    match matches.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(anyhow!(
+            "no {label} device matches `{spec}`; see `list-devices`"
+        )),
+        _ => {
+            let candidates: Vec<_> = matches
+                .iter()
+                .map(|&i| format!("[{i}] {}", names[i]))
+                .collect();
+            Err(anyhow!(
+                "`{spec}` matches several {label} devices: {}",
+                candidates.join(", ")
+            ))
+        }
+    }
 }
 
 pub fn find_device(host: &cpal::Host, direction: Direction, name: Option<&str>) -> Result<Device> {
@@ -88,19 +351,74 @@ pub fn find_device(host: &cpal::Host, direction: Direction, name: Option<&str>)
         };
         anyhow::Ok(default_device)
     };
+    // failing to enumerate devices is the backend's fault, not a missing device.
+    let backend = |err: anyhow::Error| {
+        err.context(NeetError::AudioBackend(format!(
+            "failed to list {} devices",
+            direction.label()
+        )))
+    };
 
-    let device = match &name {
-        Some(device) => iter()?.find(|x| x.name().map(|y| &y == device).unwrap_or(false)),
-        None => default()?,
+    let device = match (name, direction) {
+        (Some(spec), _) => {
+            let mut devices = sorted_devices(host, direction).map_err(backend)?;
+            let names: Vec<String> = devices.iter().map(|(name, _)| name.clone()).collect();
+            let index = resolve_device(&names, spec, direction)
+                .map_err(|err| NeetError::DeviceNotFound(format!("{err:#}")))?;
+            let (name, device) = devices.swap_remove(index);
+            info!(%name, "using {} device `{spec}`", direction.label());
+            Some(device)
+        }
+        // an input was picked with `select_input_device` before, if it could be.
+        (None, _) => default().map_err(backend)?,
     };
-    device.with_context(|| {
-        format!(
-            "could not find input audio device `{}`",
-            name.unwrap_or("default")
-        )
+    device.ok_or_else(|| {
+        NeetError::DeviceNotFound(format!(
+            "could not find a default {} device",
+            direction.label()
+        ))
+        .into()
     })
 }
 
+/// The bluetooth headset, if any, that recording from `input` while playing
+/// to `output` puts in hands-free mode.
+pub fn hands_free_input(
+    host: &cpal::Host,
+    input: Option<&str>,
+    output: Option<&str>,
+) -> Option<HandsFree> {
+    let input = find_device(host, Direction::Capture, input)
+        .ok()?
+        .name()
+        .ok()?;
+    let output = find_device(host, Direction::Playback, output)
+        .ok()?
+        .name()
+        .ok()?;
+    bluetooth::check_hands_free(host, &input, &output)
+}
+
+/// The device after the one named `current` in `list-devices` order, wrapping
+/// around, with its name.
+pub fn next_device(
+    host: &cpal::Host,
+    direction: Direction,
+    current: &str,
+) -> Result<(String, Device)> {
+    let mut devices = sorted_devices(host, direction)?;
+    let position = devices.iter().position(|(name, _)| name == current);
+    let index = position.map_or(0, |i| (i + 1) % devices.len().max(1));
+    if devices.is_empty() || position == Some(index) {
+        bail!("there is no other {} device", direction.label());
+    }
+    Ok(devices.swap_remove(index))
+}
+
+/// A running capture or playback stream, a cpal one or a virtual one, which
+/// stops when dropped.
+pub type RunningStream = Box<dyn std::any::Any>;
+
 #[derive(Debug)]
 pub struct StreamConfigWithFormat {
     pub sample_format: SampleFormat,
@@ -266,3 +584,28 @@ fn cmp_stream_format(
         (Unknown, Unknown) => Equal,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_device_by_index_pattern_and_substring() {
+        let names: Vec<String> = [
+            "Built-in Microphone",
+            "USB Headset Microphone (Jabra) #2",
+            "USB Headset",
+        ]
+        .map(String::from)
+        .to_vec();
+        let resolve = |spec| resolve_device(&names, spec, Direction::Capture);
+        assert_eq!(resolve("1").unwrap(), 1);
+        assert!(resolve("3").is_err());
+        assert_eq!(resolve("USB Headset").unwrap(), 2, "exact name wins");
+        assert_eq!(resolve("jabra").unwrap(), 1);
+        assert_eq!(resolve("/^Built-in/").unwrap(), 0);
+        let err = resolve("microphone").unwrap_err().to_string();
+        assert!(err.contains("[0] Built-in Microphone"), "{err}");
+        assert!(resolve("/(/").is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Noticing a bluetooth headset forced into hands-free mode.
+//!
+//! A bluetooth headset plays music-quality stereo over A2DP, but A2DP has no
+//! microphone. As soon as its microphone records, the headset switches to the
+//! hands-free profile (HFP), and playback drops to 8 or 16 kHz mono along
+//! with it. Recording from another microphone, e.g. the laptop's, keeps the
+//! headset in A2DP.
+//!
+//! cpal tells us little more than names and stream configurations, so the
+//! signs differ by platform: Windows and PulseAudio name hands-free inputs as
+//! such, macOS offers the headset as an input of the same name that only
+//! records speech rates, and on Linux the desktop's default source, which
+//! the `pipewire` and `default` devices record, may be the headset.
+
+use std::fmt;
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use tracing::debug;
+
+use super::score::{score_name, EXCLUDED};
+
+/// Inputs recording no more than this are speech-only, e.g. HFP's 8 kHz
+/// narrowband or 16 kHz wideband; macOS offers AirPods at 24 kHz.
+const SPEECH_RATE: u32 = 24_000;
+
+/// An input that keeps a bluetooth headset in hands-free mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandsFree {
+    pub input: String,
+    /// The best other input, to record from instead.
+    pub alternative: Option<String>,
+}
+
+impl fmt::Display for HandsFree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "input `{}` is a bluetooth headset's microphone, which switches the headset to \
+             low-quality hands-free mode (HFP) for playback too",
+            self.input
+        )?;
+        match &self.alternative {
+            Some(other) => write!(
+                f,
+                "; to keep A2DP, record elsewhere with --keep-a2dp or --input-device \"{other}\""
+            ),
+            None => write!(f, "; no other input is available to keep A2DP"),
+        }
+    }
+}
+
+/// Whether an input called `name` that records at most `max_rate` Hz is a
+/// headset's hands-free microphone, given the `output` device in use.
+pub fn is_hands_free(name: &str, max_rate: Option<u32>, output: &str) -> bool {
+    let lower = name.to_lowercase();
+    let has = |words: &[&str]| words.iter().any(|word| lower.contains(word));
+    if has(&["hands-free", "handsfree", "hfp", "head-unit", "head_unit"]) {
+        return true;
+    }
+    let speech_only = max_rate.is_some_and(|rate| rate <= SPEECH_RATE);
+    speech_only && (has(&["bluez", "bluetooth", "airpods"]) || name == output)
+}
+
+/// Whether recording from `input` while playing to `output` puts a
+/// bluetooth headset in hands-free mode, and what to record from instead.
+pub fn check_hands_free(host: &cpal::Host, input: &str, output: &str) -> Option<HandsFree> {
+    let devices: Vec<_> = host
+        .input_devices()
+        .ok()?
+        .filter_map(|device| {
+            let name = device.name().ok()?;
+            let max_rate = device
+                .supported_input_configs()
+                .ok()
+                .and_then(|configs| configs.map(|config| config.max_sample_rate().0).max());
+            Some((name, max_rate))
+        })
+        .collect();
+    let hands_free =
+        |name: &str, max_rate| is_hands_free(name, max_rate, output) || routes_to_bluetooth(name);
+    let (_, max_rate) = devices.iter().find(|(name, _)| name == input)?;
+    if !hands_free(input, *max_rate) {
+        return None;
+    }
+    let default = host
+        .default_input_device()
+        .and_then(|device| device.name().ok());
+    let alternative = devices
+        .iter()
+        .filter(|(name, max_rate)| name != input && !hands_free(name, *max_rate))
+        .map(|(name, _)| (name, score_name(name, default.as_ref() == Some(name)).score))
+        .filter(|(_, score)| *score > EXCLUDED)
+        .max_by_key(|(_, score)| *score)
+        .map(|(name, _)| name.clone());
+    Some(HandsFree {
+        input: input.to_string(),
+        alternative,
+    })
+}
+
+/// Whether `name` records the desktop's default source, and that is a
+/// bluetooth headset.
+#[cfg(target_os = "linux")]
+fn routes_to_bluetooth(name: &str) -> bool {
+    if !matches!(name, "pipewire" | "pulse" | "default") {
+        return false;
+    }
+    let source = std::process::Command::new("pactl")
+        .arg("get-default-source")
+        .output();
+    match source {
+        Ok(output) if output.status.success() => {
+            let source = String::from_utf8_lossy(&output.stdout);
+            debug!(source = %source.trim(), "default source");
+            source.starts_with("bluez")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn routes_to_bluetooth(_name: &str) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spots_hands_free_inputs_by_name_and_rate() {
+        let windows = "Headset (WH-1000XM4 Hands-Free AG Audio)";
+        assert!(is_hands_free(
+            windows,
+            Some(16_000),
+            "Headphones (WH-1000XM4 Stereo)"
+        ));
+        let pulse = "bluez_input.AC_80_0A_12_34_56.headset-head-unit";
+        assert!(is_hands_free(pulse, None, "pipewire"));
+        // macOS: the headset's input only records speech.
+        assert!(is_hands_free("AirPods Pro", Some(24_000), "AirPods Pro"));
+        assert!(!is_hands_free("AirPods Pro", Some(48_000), "AirPods Pro"));
+        assert!(!is_hands_free(
+            "MacBook Pro Microphone",
+            Some(48_000),
+            "AirPods Pro"
+        ));
+        // a USB headset is no bluetooth one.
+        assert!(!is_hands_free(
+            "Jabra Evolve2 Headset",
+            Some(48_000),
+            "Jabra Evolve2 Headset"
+        ));
+
+        let warning = HandsFree {
+            input: "AirPods Pro".to_string(),
+            alternative: Some("MacBook Pro Microphone".to_string()),
+        };
+        assert!(warning
+            .to_string()
+            .contains("--input-device \"MacBook Pro Microphone\""));
+    }
+}
@@ -0,0 +1,207 @@
+//! Picking an input device when none was requested.
+//!
+//! The system default is often wrong for calls: a monitor of the speakers, a
+//! built-in mic next to a connected headset, or a device that is muted in
+//! hardware and only delivers zeros. Devices are ranked by name, then the best
+//! few are opened briefly to check they actually deliver sound.
+
+use std::{
+    cmp::Reverse,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, SampleFormat, SizedSample,
+};
+use dasp_sample::ToSample;
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+/// Devices scoring below this are never picked automatically.
+pub(super) const EXCLUDED: i32 = -50;
+/// How many of the best-named devices to open and listen to.
+const PROBE_CANDIDATES: usize = 3;
+const PROBE_DURATION: Duration = Duration::from_millis(300);
+/// Any real microphone has a noise floor above this; exact zeros mean muted
+/// or dead.
+const PROBE_SILENCE: f32 = 1e-4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameScore {
+    pub score: i32,
+    pub reasons: Vec<&'static str>,
+}
+
+/// Rank a device by what its name says about it.
+pub fn score_name(name: &str, is_default: bool) -> NameScore {
+    let name = name.to_lowercase();
+    let has = |words: &[&str]| words.iter().any(|word| name.contains(word));
+    let mut score = 0;
+    let mut reasons = Vec::new();
+    if has(&[
+        "monitor",
+        "loopback",
+        "stereo mix",
+        "what u hear",
+        "blackhole",
+    ]) {
+        score -= 100;
+        reasons.push("captures playback, not a microphone");
+    }
+    if has(&["null", "discard"]) {
+        score -= 100;
+        reasons.push("null device");
+    }
+    if has(&[
+        "headset",
+        "headphone",
+        "hands-free",
+        "handsfree",
+        "communication",
+    ]) {
+        score += 30;
+        reasons.push("headset/communications device");
+    }
+    if has(&["mic", "input"]) {
+        score += 10;
+        reasons.push("named as a microphone");
+    }
+    if is_default {
+        score += 20;
+        reasons.push("system default");
+    }
+    // routes to the desktop's default source, with its device switching.
+    #[cfg(target_os = "linux")]
+    if name == "pipewire" {
+        score += 40;
+        reasons.push("pipewire");
+    }
+    NameScore { score, reasons }
+}
+
+/// The name of the best input device, or `None` if the host has none that
+/// qualify.
+pub async fn select_input_device() -> Result<Option<String>> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().and_then(|d| d.name().ok());
+    let mut candidates: Vec<(Device, String, NameScore)> = host
+        .input_devices()?
+        .filter_map(|device| {
+            let name = device.name().ok()?;
+            let score = score_name(&name, default_name.as_deref() == Some(&name));
+            debug!(%name, score = score.score, reasons = ?score.reasons, "input device candidate");
+            (score.score > EXCLUDED).then_some((device, name, score))
+        })
+        .collect();
+    candidates.sort_by_key(|(_, _, score)| Reverse(score.score));
+
+    let mut fallback = None;
+    for (device, name, score) in candidates.into_iter().take(PROBE_CANDIDATES) {
+        match probe(device.clone()).await {
+            Ok(true) => {
+                info!(%name, reasons = ?score.reasons, "selected input device: delivers sound");
+                return Ok(Some(name));
+            }
+            Ok(false) => {
+                debug!(%name, "input device delivers only silence");
+                fallback.get_or_insert((device, name, score));
+            }
+            Err(err) => debug!(%name, %err, "failed to probe input device"),
+        }
+    }
+    Ok(fallback.map(|(_, name, score)| {
+        info!(
+            %name,
+            reasons = ?score.reasons,
+            "selected input device, but it delivered only silence when probed"
+        );
+        name
+    }))
+}
+
+/// Open the device for a moment and report whether it delivers anything but
+/// silence. The stream lives on a thread of its own, as streams can't move
+/// between threads, while we wait.
+async fn probe(device: Device) -> Result<bool> {
+    let peak = Arc::new(Mutex::new(0f32));
+    let (opened_tx, opened) = oneshot::channel();
+    let (stop, stopped) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn({
+        let peak = peak.clone();
+        move || match open_probe(&device, peak) {
+            Ok(stream) => {
+                let _ = opened_tx.send(Ok(()));
+                // until the sender is dropped.
+                let _ = stopped.recv();
+                drop(stream);
+            }
+            Err(err) => {
+                let _ = opened_tx.send(Err(err));
+            }
+        }
+    });
+    opened
+        .await
+        .map_err(|_| anyhow!("input probe thread died"))??;
+    tokio::time::sleep(PROBE_DURATION).await;
+    drop(stop);
+    let peak = *peak.lock().unwrap();
+    Ok(peak > PROBE_SILENCE)
+}
+
+/// A playing stream of `device` that keeps the loudest sample in `peak`.
+fn open_probe(device: &Device, peak: Arc<Mutex<f32>>) -> Result<cpal::Stream> {
+    let supported = device.default_input_config()?;
+    let sample_format = supported.sample_format();
+    let stream = device.build_input_stream_raw(
+        &supported.config(),
+        sample_format,
+        {
+            move |data: &cpal::Data, _: &_| {
+                let chunk_peak = match sample_format {
+                    SampleFormat::I8 => peak_of::<i8>(data),
+                    SampleFormat::I16 => peak_of::<i16>(data),
+                    SampleFormat::I32 => peak_of::<i32>(data),
+                    SampleFormat::U16 => peak_of::<u16>(data),
+                    SampleFormat::F32 => peak_of::<f32>(data),
+                    _ => 0.,
+                };
+                let mut peak = peak.lock().unwrap();
+                *peak = peak.max(chunk_peak);
+            }
+        },
+        |err| debug!(%err, "input probe stream error"),
+        None,
+    )?;
+    stream.play()?;
+    Ok(stream)
+}
+
+fn peak_of<S: SizedSample + ToSample<f32>>(data: &cpal::Data) -> f32 {
+    data.as_slice::<S>()
+        .map(|samples| {
+            samples
+                .iter()
+                .map(|s| s.to_sample::<f32>().abs())
+                .fold(0., f32::max)
+        })
+        .unwrap_or(0.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headsets_beat_builtin_and_monitors_are_excluded() {
+        let headset = score_name("Jabra Evolve2 Headset Microphone", false);
+        let builtin = score_name("Built-in Microphone", true);
+        let monitor = score_name("Monitor of Built-in Audio Analog Stereo", false);
+        assert!(headset.score > builtin.score, "{headset:?} vs {builtin:?}");
+        assert!(monitor.score <= EXCLUDED);
+        assert_eq!(monitor.reasons, vec!["captures playback, not a microphone"]);
+    }
+}
@@ -0,0 +1,221 @@
+//! Clock drift compensation.
+//!
+//! The remote's capture device, our playback loop and our output device each
+//! run on their own clock, and no two of them agree exactly. A buffer between
+//! two clocks therefore slowly fills up or runs dry. A [`DriftEstimator`]
+//! watches such a buffer and tells its owner how far the level is off: the
+//! decoder of a remote track then plays a frame more or less per tick (see
+//! [`stretch`]), and the playback loop paces its ticks to the output device.
+
+use tracing::debug;
+
+/// Time constant of the smoothed buffer level, in ticks.
+const SMOOTHING_TICKS: f64 = 100.;
+/// Ticks to watch a buffer before its level becomes the one to hold.
+const SETTLE_TICKS: u32 = 250;
+/// Frames played between two drift estimates: 10 s.
+const ESTIMATE_FRAMES: f64 = 480_000.;
+
+/// Frames off target before the decoder starts correcting (10 ms), and
+/// within which it stops again (1 ms).
+const CORRECT_ABOVE: f64 = 480.;
+const CORRECT_WITHIN: f64 = 48.;
+
+/// Follows the level of a buffer between two clocks, in frames.
+#[derive(Debug)]
+pub struct DriftEstimator {
+    /// Names the buffer in logs.
+    name: &'static str,
+    /// Smoothed level.
+    level: f64,
+    /// The level to hold, taken once the buffer settled.
+    target: Option<f64>,
+    /// The least level to hold, however low the buffer settled.
+    min_level: f64,
+    ticks: u32,
+    window: Window,
+    /// How much faster the writing clock runs than the reading one.
+    drift_ppm: Option<f64>,
+}
+
+/// What happened to the buffer since the last drift estimate.
+#[derive(Debug, Default)]
+struct Window {
+    start: f64,
+    played: f64,
+    /// Frames the owner added to the level by correcting.
+    corrected: f64,
+}
+
+impl DriftEstimator {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            level: 0.,
+            target: None,
+            min_level: 0.,
+            ticks: 0,
+            window: Window::default(),
+            drift_ppm: None,
+        }
+    }
+
+    /// Never hold the buffer below `frames`.
+    pub fn with_min_level(mut self, frames: usize) -> Self {
+        self.min_level = frames as f64;
+        self
+    }
+
+    /// Settle on a new level, after the owner changed it on purpose. The
+    /// drift estimate is kept.
+    pub fn reset(&mut self) {
+        self.target = None;
+        self.ticks = 0;
+    }
+
+    /// Observe the buffer `level` after a tick that played `played` frames.
+    /// Returns how far the smoothed level is above the target, once the
+    /// buffer settled.
+    pub fn update(&mut self, level: usize, played: usize) -> Option<f64> {
+        let level = level as f64;
+        if self.ticks == 0 {
+            self.level = level;
+        } else {
+            self.level += (level - self.level) / SMOOTHING_TICKS;
+        }
+        self.ticks = self.ticks.saturating_add(1);
+        if self.ticks == SETTLE_TICKS {
+            self.target = Some(self.level.max(self.min_level));
+            self.window = Window {
+                start: self.level,
+                ..Window::default()
+            };
+        }
+        let target = self.target?;
+
+        self.window.played += played as f64;
+        if self.window.played >= ESTIMATE_FRAMES {
+            let drift = self.level - self.window.start - self.window.corrected;
+            let drift_ppm = drift / self.window.played * 1e6;
+            let drift_ppm = match self.drift_ppm {
+                Some(previous) => previous + (drift_ppm - previous) / 4.,
+                None => drift_ppm,
+            };
+            debug!(buffer = self.name, drift_ppm, "clock drift estimate");
+            self.drift_ppm = Some(drift_ppm);
+            self.window = Window {
+                start: self.level,
+                ..Window::default()
+            };
+        }
+        Some(self.level - target)
+    }
+
+    /// Record that the owner raised the level by `frames` (or lowered it, if
+    /// negative) to correct for drift, so it is not mistaken for drift.
+    pub fn corrected(&mut self, frames: f64) {
+        self.window.corrected += frames;
+    }
+
+    #[cfg(test)]
+    fn drift_ppm(&self) -> Option<f64> {
+        self.drift_ppm
+    }
+}
+
+/// How a decoder plays out its buffer this tick.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Correction {
+    #[default]
+    None,
+    /// Play one frame more than it takes from the buffer.
+    Insert,
+    /// Take one frame more than it plays.
+    Drop,
+}
+
+impl Correction {
+    /// The correction to apply given the buffer is `error` frames above its
+    /// target, with some hysteresis so it does not flap around the target.
+    pub fn next(self, error: f64) -> Self {
+        if error > CORRECT_ABOVE {
+            Correction::Drop
+        } else if error < -CORRECT_ABOVE {
+            Correction::Insert
+        } else if error.abs() < CORRECT_WITHIN {
+            Correction::None
+        } else {
+            self
+        }
+    }
+}
+
+/// Resample the stereo frames in `input` to fill `output` by linear
+/// interpolation. A frame more or less spread over a whole tick is inaudible.
+pub fn stretch(input: &[f32], output: &mut [f32]) {
+    let in_frames = input.len() / 2;
+    let out_frames = output.len() / 2;
+    if in_frames < 2 || out_frames < 2 {
+        let count = input.len().min(output.len());
+        output[..count].copy_from_slice(&input[..count]);
+        output[count..].fill(0.);
+        return;
+    }
+    let step = (in_frames - 1) as f64 / (out_frames - 1) as f64;
+    for (i, frame) in output.chunks_exact_mut(2).enumerate() {
+        let position = i as f64 * step;
+        let index = position as usize;
+        let next = (index + 1).min(in_frames - 1);
+        let fraction = (position - index as f64) as f32;
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            let from = input[index * 2 + channel];
+            let to = input[next * 2 + channel];
+            *sample = from + (to - from) * fraction;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_FRAMES: usize = 960;
+
+    #[test]
+    fn corrections_hold_the_level_of_a_drifting_buffer() {
+        let mut estimator = DriftEstimator::new("test");
+        let mut correction = Correction::None;
+        // the writer's clock runs 200 ppm fast.
+        let written = TICK_FRAMES as f64 * (1. + 200e-6);
+        let mut level = 2. * TICK_FRAMES as f64;
+        // ten minutes of 20 ms ticks.
+        for _ in 0..30_000 {
+            level += written;
+            let taken = match correction {
+                Correction::None => TICK_FRAMES,
+                Correction::Insert => TICK_FRAMES - 1,
+                Correction::Drop => TICK_FRAMES + 1,
+            };
+            level -= taken as f64;
+            estimator.corrected(TICK_FRAMES as f64 - taken as f64);
+            if let Some(error) = estimator.update(level as usize, TICK_FRAMES) {
+                correction = correction.next(error);
+            }
+        }
+        // uncorrected, it would have grown by 5760 frames.
+        assert!((level - 2. * TICK_FRAMES as f64).abs() < CORRECT_ABOVE * 2.);
+        let drift_ppm = estimator.drift_ppm().unwrap();
+        assert!((150. ..250.).contains(&drift_ppm), "{drift_ppm}");
+    }
+
+    #[test]
+    fn stretch_keeps_the_waveform() {
+        let input: Vec<f32> = (0..10).flat_map(|i| [i as f32, -(i as f32)]).collect();
+        let mut output = vec![0.; 22];
+        stretch(&input, &mut output);
+        assert_eq!(&output[..2], &[0., 0.]);
+        assert_eq!(&output[20..], &[9., -9.]);
+        assert!(output.chunks(2).all(|frame| frame[0] == -frame[1]));
+        assert!(output.windows(4).step_by(2).all(|w| w[2] > w[0]));
+    }
+}
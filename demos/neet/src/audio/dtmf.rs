@@ -0,0 +1,200 @@
+//! DTMF: tones mixed into the outgoing audio, and a Goertzel detector on the
+//! received audio that reports digits as events.
+
+use std::{f32::consts::PI, ops::ControlFlow, time::Duration};
+
+use anyhow::{bail, Result};
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use super::{clip::Clip, AudioSink, ENGINE_FORMAT};
+
+const LOW: [f32; 4] = [697., 770., 852., 941.];
+const HIGH: [f32; 4] = [1209., 1336., 1477., 1633.];
+const KEYPAD: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+const TONE: Duration = Duration::from_millis(100);
+const GAP: Duration = Duration::from_millis(60);
+const RAMP: Duration = Duration::from_millis(5);
+/// Per tone, so a digit peaks at -6 dBFS.
+const AMPLITUDE: f32 = 0.25;
+
+/// Detection block; 20 ms gives 50 Hz bins, finer than the DTMF spacing.
+const BLOCK: Duration = Duration::from_millis(20);
+/// Blocks quieter than this (mean square) hold no digit.
+const MIN_POWER: f32 = 1e-4;
+/// Share of the block's energy the two strongest tones must carry together.
+const MIN_TONE_SHARE: f32 = 0.8;
+/// And each of them alone, which bounds the twist between them.
+const MIN_SINGLE_SHARE: f32 = 0.15;
+/// Consecutive blocks a digit must last before it is reported.
+const MIN_BLOCKS: u32 = 2;
+
+/// Is `c` a key on the DTMF keypad?
+pub fn is_dtmf_digit(c: char) -> bool {
+    frequencies(c).is_some()
+}
+
+fn frequencies(digit: char) -> Option<(f32, f32)> {
+    let digit = digit.to_ascii_uppercase();
+    KEYPAD.iter().enumerate().find_map(|(row, keys)| {
+        let col = keys.iter().position(|&key| key == digit)?;
+        Some((LOW[row], HIGH[col]))
+    })
+}
+
+/// Interleaved stereo samples in [`ENGINE_FORMAT`] for `digits`, each a tone
+/// followed by a pause.
+pub fn tones(digits: &str) -> Result<Vec<f32>> {
+    let sample_rate = ENGINE_FORMAT.sample_rate.0 as f32;
+    let tone_blocks = ENGINE_FORMAT.block_count(TONE);
+    let ramp_blocks = ENGINE_FORMAT.block_count(RAMP) as f32;
+    let gap = ENGINE_FORMAT.sample_count(GAP);
+    let mut out = Vec::new();
+    for digit in digits.chars() {
+        let Some((low, high)) = frequencies(digit) else {
+            bail!("`{digit}` is not a DTMF digit (0-9, *, #, A-D)");
+        };
+        for i in 0..tone_blocks {
+            let t = i as f32 / sample_rate;
+            // short fades avoid clicks that would splatter into other bins.
+            let envelope = (i.min(tone_blocks - 1 - i) as f32 / ramp_blocks).min(1.);
+            let sample =
+                AMPLITUDE * envelope * ((2. * PI * low * t).sin() + (2. * PI * high * t).sin());
+            out.extend([sample, sample]);
+        }
+        out.extend(std::iter::repeat_n(0., gap));
+    }
+    Ok(out)
+}
+
+/// Queues tones on a capture insert.
+#[derive(Debug, Clone, Default)]
+pub struct DtmfSender(Clip);
+
+impl DtmfSender {
+    pub fn clip(&self) -> Clip {
+        self.0.clone()
+    }
+
+    pub fn send(&self, digits: &str) -> Result<()> {
+        self.0.push(&tones(digits)?);
+        Ok(())
+    }
+}
+
+/// Goertzel power of `frequency` in `samples`.
+fn goertzel(samples: &[f32], frequency: f32, sample_rate: f32) -> f32 {
+    let coeff = 2. * (2. * PI * frequency / sample_rate).cos();
+    let (mut s1, mut s2) = (0., 0.);
+    for &x in samples {
+        let s0 = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    s1 * s1 + s2 * s2 - coeff * s1 * s2
+}
+
+/// The digit in a mono block, if one dominates it.
+fn detect(block: &[f32]) -> Option<char> {
+    let n = block.len() as f32;
+    let energy = block.iter().map(|x| x * x).sum::<f32>();
+    if energy / n < MIN_POWER {
+        return None;
+    }
+    let sample_rate = ENGINE_FORMAT.sample_rate.0 as f32;
+    // share of the block's energy in a bin: 1.0 for a pure tone.
+    let share = |frequency| 2. * goertzel(block, frequency, sample_rate) / (n * energy);
+    let strongest = |group: &[f32; 4]| {
+        group
+            .iter()
+            .map(|&f| share(f))
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+    };
+    let (row, low) = strongest(&LOW);
+    let (col, high) = strongest(&HIGH);
+    (low >= MIN_SINGLE_SHARE && high >= MIN_SINGLE_SHARE && low + high >= MIN_TONE_SHARE)
+        .then_some(KEYPAD[row][col])
+}
+
+/// Receive-side sink reporting each digit once, when it starts.
+pub struct DtmfDetector {
+    events: broadcast::Sender<char>,
+    mono: Vec<f32>,
+    block_len: usize,
+    candidate: Option<char>,
+    blocks: u32,
+}
+
+impl DtmfDetector {
+    pub fn new(events: broadcast::Sender<char>) -> Self {
+        let block_len = ENGINE_FORMAT.block_count(BLOCK);
+        Self {
+            events,
+            mono: Vec::with_capacity(block_len),
+            block_len,
+            candidate: None,
+            blocks: 0,
+        }
+    }
+
+    fn block(&mut self) {
+        let digit = detect(&self.mono);
+        self.mono.clear();
+        if digit != self.candidate {
+            self.candidate = digit;
+            self.blocks = 0;
+        }
+        self.blocks += 1;
+        if let Some(digit) = digit {
+            if self.blocks == MIN_BLOCKS {
+                debug!(%digit, "DTMF digit detected");
+                // nobody listening is fine.
+                let _ = self.events.send(digit);
+            }
+        }
+    }
+}
+
+impl AudioSink for DtmfDetector {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        for frame in buf.chunks_exact(2) {
+            self.mono.push((frame[0] + frame[1]) / 2.);
+            if self.mono.len() == self.block_len {
+                self.block();
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_generated_digits_once_each() {
+        let (events, mut received) = broadcast::channel(16);
+        let mut detector = DtmfDetector::new(events);
+        let mut audio = tones("1590*#D").unwrap();
+        // noise must not register.
+        let mut seed = 1u32;
+        audio.extend((0..9600).map(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        }));
+        for tick in audio.chunks(1920) {
+            assert!(detector.tick(tick).unwrap().is_continue());
+        }
+        let digits: String = std::iter::from_fn(|| received.try_recv().ok()).collect();
+        assert_eq!(digits, "1590*#D");
+        assert!(tones("12x").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Sidechain ducking: other tracks get quieter while the local user talks,
+//! or while a priority track plays.
+//!
+//! A [`VoiceDetector`] on the capture side flags when the microphone carries
+//! speech, and the playback mixer's [`Ducker`] ramps its output down by the
+//! configured amount while the flag is set, and back up once it clears.
+//!
+//! A [`PriorityDuck`] does the same for remote tracks (`--duck-for`): a
+//! detector taps each priority track as it is decoded, and every other
+//! remote track is [`Ducked`] while one of them is active.
+
+use std::{
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+
+use super::{AudioSink, AudioSource, FrameProcessor};
+
+/// Capture level above which the local user counts as speaking.
+const VOICE_THRESHOLD_DBFS: f32 = -40.;
+/// Keep ducking through short pauses between words (in 20ms ticks).
+const HANGOVER_TICKS: u32 = 15;
+/// Ticks for the gain to come back up once the voice stops.
+const RELEASE_TICKS: f32 = 15.;
+
+/// Set while the local user is speaking.
+#[derive(Debug, Clone, Default)]
+pub struct VoiceActivity(Arc<AtomicBool>);
+
+impl VoiceActivity {
+    pub fn is_active(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, active: bool) {
+        self.0.store(active, Ordering::Relaxed);
+    }
+}
+
+/// Capture sink that updates a [`VoiceActivity`] from the input level.
+pub struct VoiceDetector {
+    activity: VoiceActivity,
+    hangover: u32,
+}
+
+impl VoiceDetector {
+    pub fn new(activity: VoiceActivity) -> Self {
+        Self {
+            activity,
+            hangover: 0,
+        }
+    }
+}
+
+impl AudioSink for VoiceDetector {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        if buf.is_empty() {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let power = buf.iter().map(|s| s * s).sum::<f32>() / buf.len() as f32;
+        let dbfs = 10. * power.max(1e-10).log10();
+        if dbfs > VOICE_THRESHOLD_DBFS {
+            self.hangover = HANGOVER_TICKS;
+        } else {
+            self.hangover = self.hangover.saturating_sub(1);
+        }
+        self.activity.set(self.hangover > 0);
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Gain applied to the playback mix, following the sidechain.
+#[derive(Debug)]
+pub struct Ducker {
+    activity: VoiceActivity,
+    /// Linear gain while ducked.
+    floor: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    /// Duck by `amount_db` decibels.
+    pub fn new(activity: VoiceActivity, amount_db: f32) -> Self {
+        Self {
+            activity,
+            floor: 10f32.powf(-amount_db.abs() / 20.),
+            gain: 1.,
+        }
+    }
+
+    /// Apply one tick of interleaved stereo, ramping from the previous gain to
+    /// avoid clicks. Ducks within a tick and releases over [`RELEASE_TICKS`].
+    pub fn process(&mut self, buf: &mut [f32]) {
+        let target = if self.activity.is_active() {
+            self.floor
+        } else {
+            (self.gain + (1. - self.floor) / RELEASE_TICKS).min(1.)
+        };
+        let start = self.gain;
+        self.gain = target;
+        if start == 1. && target == 1. {
+            return;
+        }
+        let frames = (buf.len() / 2).max(1) as f32;
+        for (i, frame) in buf.chunks_exact_mut(2).enumerate() {
+            let gain = start + (target - start) * (i + 1) as f32 / frames;
+            frame[0] *= gain;
+            frame[1] *= gain;
+        }
+    }
+}
+
+impl FrameProcessor for Ducker {
+    fn process(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), ()>> {
+        Ducker::process(self, buf);
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Remote tracks the others are ducked for, and whether one is active.
+#[derive(Debug, Clone)]
+pub struct PriorityDuck {
+    tracks: Arc<[String]>,
+    activity: VoiceActivity,
+    amount_db: f32,
+}
+
+impl PriorityDuck {
+    /// Duck the other tracks by `amount_db` while one of `tracks` is active.
+    pub fn new(tracks: Vec<String>, amount_db: f32) -> Self {
+        Self {
+            tracks: tracks.into(),
+            activity: VoiceActivity::default(),
+            amount_db,
+        }
+    }
+
+    pub fn is_priority(&self, track: &str) -> bool {
+        self.tracks.iter().any(|priority| priority == track)
+    }
+
+    /// A tap for a priority track's decoded audio.
+    pub fn detector(&self) -> VoiceDetector {
+        VoiceDetector::new(self.activity.clone())
+    }
+
+    /// `source`, another track, ducked while a priority track is active.
+    pub fn duck<S: AudioSource>(&self, source: S) -> Ducked<S> {
+        Ducked {
+            source,
+            ducker: Ducker::new(self.activity.clone(), self.amount_db),
+        }
+    }
+}
+
+/// A playback source that a [`Ducker`] turns down.
+pub struct Ducked<S> {
+    source: S,
+    ducker: Ducker,
+}
+
+impl<S: AudioSource> AudioSource for Ducked<S> {
+    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+        let flow = self.source.tick(buf)?;
+        if let ControlFlow::Continue(count) = flow {
+            self.ducker.process(&mut buf[..count]);
+        }
+        Ok(flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ducks_while_speaking_and_releases_after_hangover() {
+        let activity = VoiceActivity::default();
+        let mut detector = VoiceDetector::new(activity.clone());
+        let mut ducker = Ducker::new(activity.clone(), 20.);
+        let speech = vec![0.3; 1920];
+        let silence = vec![0.; 1920];
+
+        assert!(detector.tick(&speech).unwrap().is_continue());
+        assert!(activity.is_active());
+        let mut mix = vec![1.; 1920];
+        ducker.process(&mut mix);
+        assert!((mix[1918] - 0.1).abs() < 1e-4, "ducked to -20 dB");
+
+        for _ in 0..HANGOVER_TICKS {
+            assert!(detector.tick(&silence).unwrap().is_continue());
+        }
+        assert!(!activity.is_active());
+        for _ in 0..RELEASE_TICKS as usize {
+            mix.fill(1.);
+            ducker.process(&mut mix);
+        }
+        assert!((mix[1918] - 1.).abs() < 1e-4, "back to unity gain");
+    }
+
+    #[test]
+    fn priority_tracks_duck_the_others() {
+        struct Tone;
+        impl AudioSource for Tone {
+            fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+                buf.fill(1.);
+                Ok(ControlFlow::Continue(buf.len()))
+            }
+        }
+        let priority = PriorityDuck::new(vec!["audio".into()], 20.);
+        assert!(priority.is_priority("audio") && !priority.is_priority("program"));
+        let mut detector = priority.detector();
+        let mut program = priority.duck(Tone);
+        let mut buf = vec![0.; 1920];
+
+        assert!(program.tick(&mut buf).unwrap().is_continue());
+        assert_eq!(buf[1918], 1., "nothing to duck for yet");
+        assert!(detector.tick(&[0.3; 1920]).unwrap().is_continue());
+        assert!(program.tick(&mut buf).unwrap().is_continue());
+        assert!((buf[1918] - 0.1).abs() < 1e-4, "ducked to -20 dB");
+    }
+}
@@ -0,0 +1,113 @@
+//! A clean feed of the call for streaming (`--stream-feed`).
+//!
+//! Streaming software such as OBS records the remote participants from a
+//! system source of their own, `neet-stream` by default. It carries what
+//! they say and nothing else: no beeps, announcements or DTMF tones, and
+//! every remote at full volume, whatever the call's volume or routes. It is
+//! a mix-minus: our own voice is left out, so the stream takes the
+//! microphone from its own input without hearing it twice, and nothing the
+//! stream plays goes back into the call.
+
+use std::{ops::ControlFlow, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use ringbuf::{
+    traits::{Consumer as _, Observer as _, Producer as _, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use tracing::info;
+
+use super::{
+    capture::AudioSink, conduit::Conduit, playback::AudioPlayback, playback::AudioSource,
+    power::Pacing, unprocessed, ENGINE_FORMAT,
+};
+use crate::stats::{Counter, XrunStats};
+
+/// Audio of each remote kept between the call's playback and the feed's.
+const FEED_BUFFER: Duration = Duration::from_millis(200);
+
+/// The source streaming software records, removed when the last clone is
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct StreamFeed {
+    playback: AudioPlayback,
+    _conduit: Arc<Conduit>,
+}
+
+impl StreamFeed {
+    /// Add the system source `name` and start playing into it.
+    pub async fn start(
+        name: String,
+        low_power: bool,
+        pacing: Pacing,
+        busy: Counter,
+    ) -> Result<Self> {
+        let conduit = tokio::task::spawn_blocking(move || Conduit::source(&name)).await??;
+        let playback = AudioPlayback::build(
+            &cpal::default_host(),
+            Some(&conduit.output_device()),
+            unprocessed(low_power)?,
+            // the feed's hiccups are no news about the call.
+            XrunStats::default(),
+            pacing,
+            busy,
+        )
+        .await?;
+        info!("streaming software can record the remote participants from the feed source");
+        Ok(Self {
+            playback,
+            _conduit: Arc::new(conduit),
+        })
+    }
+
+    /// A tap for one remote's decoded audio, which the feed plays until the
+    /// tap is dropped.
+    pub async fn tap(&self) -> Result<FeedTap> {
+        let (producer, consumer) = HeapRb::new(ENGINE_FORMAT.sample_count(FEED_BUFFER)).split();
+        self.playback.add_source(FeedSource(consumer)).await?;
+        Ok(FeedTap(producer))
+    }
+}
+
+/// Hands a remote's audio over to the feed.
+pub struct FeedTap(HeapProd<f32>);
+
+impl AudioSink for FeedTap {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        // a feed that fell behind drops the newest audio rather than block.
+        self.0.push_slice(buf);
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Plays a remote's audio in the feed, ending when its tap does.
+struct FeedSource(HeapCons<f32>);
+
+impl AudioSource for FeedSource {
+    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+        if !self.0.write_is_held() && self.0.is_empty() {
+            return Ok(ControlFlow::Break(()));
+        }
+        // silence until the remote's audio arrives, rather than an xrun.
+        let count = self.0.pop_slice(buf);
+        buf[count..].fill(0.);
+        Ok(ControlFlow::Continue(buf.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_sources_end_with_their_tap() {
+        let (producer, consumer) = HeapRb::new(8).split();
+        let (mut tap, mut source) = (FeedTap(producer), FeedSource(consumer));
+        assert!(tap.tick(&[0.5; 2]).unwrap().is_continue());
+        let mut buf = [1.; 4];
+        assert_eq!(source.tick(&mut buf).unwrap(), ControlFlow::Continue(4));
+        assert_eq!(buf, [0.5, 0.5, 0., 0.]);
+        drop(tap);
+        assert_eq!(source.tick(&mut buf).unwrap(), ControlFlow::Break(()));
+    }
+}
@@ -0,0 +1,80 @@
+//! Smooth edges around gaps in played audio.
+//!
+//! Audio that stops dead, or starts again at full level, clicks. Where a
+//! source or the output device runs out of audio, the gap fades out from the
+//! last sample played and the audio after it fades back in.
+
+/// Length of the fades.
+const RAMP: std::time::Duration = std::time::Duration::from_micros(2500);
+
+#[derive(Debug)]
+pub struct GapSmoother {
+    channels: usize,
+    /// Frames a fade takes.
+    ramp: usize,
+    /// The last frame played, faded out from when a gap starts.
+    last: Vec<f32>,
+    in_gap: bool,
+}
+
+impl GapSmoother {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            ramp: (sample_rate as f64 * RAMP.as_secs_f64()) as usize,
+            last: vec![0.; channels],
+            in_gap: false,
+        }
+    }
+
+    /// `buf[..filled]` holds audio, the rest of `buf` is a gap. Fades the gap
+    /// out and fills it with silence, or fades audio in after a gap.
+    pub fn process(&mut self, buf: &mut [f32], filled: usize) {
+        let channels = self.channels;
+        let (audio, gap) = buf.split_at_mut(filled.min(buf.len()));
+        if self.in_gap && !audio.is_empty() {
+            for (i, frame) in audio.chunks_mut(channels).take(self.ramp).enumerate() {
+                let gain = i as f32 / self.ramp as f32;
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+            self.in_gap = false;
+        }
+        if let Some(last) = audio.rchunks_exact(channels).next() {
+            self.last.copy_from_slice(last);
+        }
+        if gap.is_empty() {
+            return;
+        }
+        for (i, frame) in gap.chunks_mut(channels).enumerate() {
+            let gain = 1. - (i + 1) as f32 / self.ramp as f32;
+            for (sample, last) in frame.iter_mut().zip(&self.last) {
+                *sample = last * gain.max(0.);
+            }
+        }
+        self.last.fill(0.);
+        self.in_gap = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_fade_out_and_back_in() {
+        // a ramp of 10 frames.
+        let mut smoother = GapSmoother::new(2, 4000);
+        let mut buf = vec![1.; 40];
+        smoother.process(&mut buf, 20);
+        assert_eq!(&buf[..20], &[1.; 20], "audio before the gap is untouched");
+        assert_eq!(&buf[20..22], &[0.9, 0.9]);
+        assert!(buf[20..].windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(&buf[38..], &[0., 0.]);
+
+        let mut buf = vec![1.; 40];
+        smoother.process(&mut buf, 40);
+        assert_eq!(&buf[..2], &[0., 0.]);
+        assert_eq!(&buf[10..12], &[0.5, 0.5]);
+        assert_eq!(&buf[20..], &[1.; 20], "full level after the fade in");
+    }
+}
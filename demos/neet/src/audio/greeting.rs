@@ -0,0 +1,160 @@
+//! The answering machine's greeting (`--greeting`): a WAV file that
+//! `--auto-answer` plays to each caller when they connect. It takes the
+//! microphone's place in what the answering session sends, a tick per tick
+//! of the capture whatever the microphone delivers, and the microphone goes
+//! live once it ends, or never if the caller hangs up first.
+
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+
+use super::{wav::read_wav, ENGINE_FORMAT};
+
+/// A greeting, read once and played to every caller.
+#[derive(derive_more::Debug, Clone)]
+pub struct Greeting {
+    path: PathBuf,
+    #[debug(skip)]
+    samples: Arc<[f32]>,
+}
+
+impl Greeting {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            samples: read_wav(path)?.into(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn duration(&self) -> Duration {
+        ENGINE_FORMAT.duration_from_sample_count(self.samples.len())
+    }
+}
+
+/// When a session greets, shared by the encoders of its voice, which send
+/// the greeting in the microphone's place meanwhile. Other sessions on the
+/// devices keep sending the microphone.
+#[derive(Debug, Clone, Default)]
+pub struct GreetingCue(Arc<CueState>);
+
+#[derive(derive_more::Debug, Default)]
+struct CueState {
+    /// The session's greeting, set when it first greets.
+    #[debug(skip)]
+    samples: OnceLock<Arc<[f32]>>,
+    /// Counts the greetings started.
+    started: AtomicU64,
+    /// The last greeting stopped.
+    stopped: AtomicU64,
+}
+
+impl GreetingCue {
+    /// Start `greeting` over, until it ends or the returned guard is dropped.
+    pub(super) fn play(&self, greeting: &Greeting) -> Result<GreetingGuard> {
+        let samples = self.0.samples.get_or_init(|| greeting.samples.clone());
+        ensure!(
+            Arc::ptr_eq(samples, &greeting.samples),
+            "a session greets with one greeting"
+        );
+        let greeting = self.0.started.fetch_add(1, Ordering::Relaxed) + 1;
+        Ok(GreetingGuard {
+            cue: self.clone(),
+            greeting,
+        })
+    }
+
+    /// What one encoder reads of it, a tick of `tick_samples` at a time.
+    pub fn reader(&self, tick_samples: usize) -> GreetingReader {
+        GreetingReader {
+            cue: self.clone(),
+            tick_samples,
+            greeting: 0,
+            position: 0,
+        }
+    }
+}
+
+/// Stops the greeting when dropped, e.g. when the caller hangs up.
+pub struct GreetingGuard {
+    cue: GreetingCue,
+    greeting: u64,
+}
+
+impl Drop for GreetingGuard {
+    fn drop(&mut self) {
+        self.cue
+            .0
+            .stopped
+            .fetch_max(self.greeting, Ordering::Relaxed);
+    }
+}
+
+/// One encoder's place in the greeting.
+pub struct GreetingReader {
+    cue: GreetingCue,
+    tick_samples: usize,
+    /// The greeting the position is in.
+    greeting: u64,
+    position: usize,
+}
+
+impl GreetingReader {
+    /// The next tick of the greeting while one plays, whatever the
+    /// microphone delivered this tick: it may lag, or deliver nothing.
+    pub fn next_tick(&mut self) -> Option<(Arc<[f32]>, Range<usize>)> {
+        let started = self.cue.0.started.load(Ordering::Relaxed);
+        if started <= self.cue.0.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+        if started != self.greeting {
+            self.greeting = started;
+            self.position = 0;
+        }
+        let samples = self.cue.0.samples.get()?.clone();
+        let start = self.position.min(samples.len());
+        let end = (start + self.tick_samples).min(samples.len());
+        self.position = end;
+        (start < end).then_some((samples, start..end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn greeting_plays_a_tick_at_a_time_until_it_ends_or_stops() {
+        let greeting = Greeting {
+            path: PathBuf::new(),
+            samples: vec![0.5; 6].into(),
+        };
+        let cue = GreetingCue::default();
+        let mut reader = cue.reader(4);
+        assert!(reader.next_tick().is_none());
+        let guard = cue.play(&greeting).unwrap();
+        assert_eq!(reader.next_tick().unwrap().1, 0..4);
+        assert_eq!(reader.next_tick().unwrap().1, 4..6);
+        assert!(reader.next_tick().is_none());
+
+        // the next caller hears it from the start; a hang-up stops it.
+        drop(guard);
+        let guard = cue.play(&greeting).unwrap();
+        assert_eq!(reader.next_tick().unwrap().1, 0..4);
+        drop(guard);
+        assert!(reader.next_tick().is_none());
+        // other sessions are never greeted.
+        assert!(GreetingCue::default().reader(4).next_tick().is_none());
+    }
+}
@@ -0,0 +1,87 @@
+//! Processing of our own on the audio threads: a [`FrameProcessor`] sees,
+//! and may change, every tick of captured audio before it is encoded, or of
+//! the mixed playback before it reaches the output device. The ducker and
+//! capture inserts hook in here.
+//!
+//! A processor is handed the engine's own buffer, no copy of it: interleaved
+//! stereo at [`ENGINE_FORMAT`](super::ENGINE_FORMAT), one tick long (10 or
+//! 20 ms, less when the input fell behind), which it changes in place.
+//! Processors run in the order they were added, on the capture or playback
+//! thread, and share its real-time contract: no blocking, no locks held
+//! elsewhere for long, no allocation, no I/O. Work like that belongs on
+//! another thread, fed e.g. through a ring buffer.
+
+use std::ops::ControlFlow;
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+pub trait FrameProcessor: Send + 'static {
+    /// Process one tick of audio in place. `Break` removes the processor, as
+    /// does an error.
+    fn process(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), ()>>;
+}
+
+/// The processors of one audio thread, in order.
+#[derive(Default)]
+pub struct Processors {
+    chain: Vec<Box<dyn FrameProcessor>>,
+}
+
+impl Processors {
+    pub fn push(&mut self, processor: Box<dyn FrameProcessor>) {
+        self.chain.push(processor);
+    }
+
+    /// Run every processor over `buf`, one tick.
+    pub fn run(&mut self, buf: &mut [f32]) {
+        self.chain
+            .retain_mut(|processor| match processor.process(buf) {
+                Ok(ControlFlow::Continue(())) => true,
+                Ok(ControlFlow::Break(())) => {
+                    debug!("remove audio processor: done");
+                    false
+                }
+                Err(err) => {
+                    warn!("remove audio processor: failed {err:?}");
+                    false
+                }
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Halve;
+
+    impl FrameProcessor for Halve {
+        fn process(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), ()>> {
+            buf.iter_mut().for_each(|sample| *sample /= 2.);
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+
+    struct Once;
+
+    impl FrameProcessor for Once {
+        fn process(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), ()>> {
+            buf.fill(1.);
+            Ok(ControlFlow::Break(()))
+        }
+    }
+
+    #[test]
+    fn processes_in_place_in_order_until_done() {
+        let mut processors = Processors::default();
+        processors.push(Box::new(Once));
+        processors.push(Box::new(Halve));
+        let mut buf = [0.; 4];
+        processors.run(&mut buf);
+        assert_eq!(buf, [0.5; 4]);
+        assert_eq!(processors.chain.len(), 1);
+        processors.run(&mut buf);
+        assert_eq!(buf, [0.25; 4]);
+    }
+}
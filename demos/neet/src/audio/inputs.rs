@@ -0,0 +1,105 @@
+//! Further input devices, published as tracks of their own
+//! (`--extra-input`).
+//!
+//! Next to the microphone, e.g. an instrument's audio interface goes out on
+//! a track named after it, which subscribers may play or not. Each input is
+//! captured on its own, without echo cancellation or noise suppression,
+//! which would take an instrument for noise, and is encoded with its own
+//! codec and bitrate. Muting mutes them all.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+
+use super::VOICE_TRACK;
+use crate::codec::{opus::OpusChannels, Codec};
+
+/// An input device published as a track of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraInput {
+    /// What subscribers know it by.
+    pub name: String,
+    /// As for `--input-device`.
+    pub device: String,
+    pub codec: Codec,
+    /// A fixed Opus bitrate in bits per second; by default it follows the
+    /// microphone's.
+    pub bitrate: Option<u32>,
+}
+
+/// `NAME=DEVICE`, optionally followed by `,codec=opus|flac` and
+/// `,bitrate=KBPS`.
+impl FromStr for ExtraInput {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (name, rest) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected NAME=DEVICE"))?;
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'),
+            "input names take letters, digits and dashes"
+        );
+        // routes tell inputs from the voice by name.
+        ensure!(
+            name != VOICE_TRACK,
+            "`{VOICE_TRACK}` is the microphone's name"
+        );
+        let mut input = ExtraInput {
+            name: name.to_string(),
+            device: rest.to_string(),
+            codec: Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            bitrate: None,
+        };
+        // options come last, so a device name may hold commas of its own.
+        while let Some((device, option)) = input.device.rsplit_once(',') {
+            let device = device.to_string();
+            match option.split_once('=') {
+                Some(("codec", "opus")) => {}
+                Some(("codec", "flac")) => input.codec = Codec::Flac,
+                Some(("codec", other)) => bail!("unknown codec `{other}`; use opus or flac"),
+                Some(("bitrate", kbps)) => {
+                    let kbps: u32 = kbps
+                        .parse()
+                        .with_context(|| format!("invalid bitrate `{kbps}`"))?;
+                    input.bitrate = Some(kbps * 1000);
+                }
+                _ => break,
+            }
+            input.device = device;
+        }
+        ensure!(!input.device.is_empty(), "no device for input `{name}`");
+        ensure!(
+            input.bitrate.is_none() || input.codec != Codec::Flac,
+            "FLAC has no bitrate to set"
+        );
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_devices_and_options() {
+        let guitar: ExtraInput = "guitar=/Scarlett/,codec=flac".parse().unwrap();
+        assert_eq!(guitar.name, "guitar");
+        assert_eq!(guitar.device, "/Scarlett/");
+        assert_eq!(guitar.codec, Codec::Flac);
+
+        let keys: ExtraInput = "keys=USB Audio, Line 2,bitrate=128".parse().unwrap();
+        assert_eq!(keys.device, "USB Audio, Line 2");
+        assert_eq!(keys.bitrate, Some(128_000));
+
+        assert!("guitar".parse::<ExtraInput>().is_err());
+        assert!("my guitar=x".parse::<ExtraInput>().is_err());
+        assert!("guitar=x,codec=aac".parse::<ExtraInput>().is_err());
+        assert!("guitar=,bitrate=64".parse::<ExtraInput>().is_err());
+        assert!("guitar=x,codec=flac,bitrate=64"
+            .parse::<ExtraInput>()
+            .is_err());
+    }
+}
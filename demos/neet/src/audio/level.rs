@@ -0,0 +1,143 @@
+//! Level metering: a capture sink feeding [`Level`], a terminal meter, and
+//! warnings for the usual "my mic doesn't work" problems.
+
+use std::{io::Write, ops::ControlFlow, time::Duration};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use super::AudioSink;
+use crate::{
+    media::Presence,
+    stats::{Level, LevelSnapshot, Stats, SILENCE_DBFS},
+};
+
+const METER_INTERVAL: Duration = Duration::from_millis(100);
+const METER_WIDTH: usize = 30;
+/// Levels below this are drawn as an empty meter.
+const METER_FLOOR_DBFS: f32 = -60.;
+/// A mic this quiet for [`SILENT_AFTER`] is probably muted or the wrong device.
+pub(super) const SILENT_DBFS: f32 = -70.;
+const SILENT_AFTER: Duration = Duration::from_secs(5);
+const CLIP_WARN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Capture sink that measures the microphone level.
+pub struct LevelSink(pub Level);
+
+impl AudioSink for LevelSink {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        self.0.measure(buf);
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Warn when the mic clips or stays silent, and optionally draw a meter of
+/// the mic and remote levels on stderr, marking a remote who muted, holds or
+/// stepped away, or who talks, if it sends its level.
+pub async fn watch_levels(stats: Stats, meter: bool) {
+    let mut interval = tokio::time::interval(METER_INTERVAL);
+    let silent_ticks = (SILENT_AFTER.as_millis() / METER_INTERVAL.as_millis()) as u32;
+    let clip_ticks = (CLIP_WARN_INTERVAL.as_millis() / METER_INTERVAL.as_millis()) as u32;
+    let mut quiet = 0;
+    let mut clipped = stats.capture_level.clipped.get();
+    let mut since_clip_warning = clip_ticks;
+    loop {
+        interval.tick().await;
+        let capture = stats.capture_level.snapshot();
+
+        if capture.rms_dbfs < SILENT_DBFS {
+            quiet += 1;
+            if quiet == silent_ticks {
+                warn!(
+                    "microphone is near-silent; check that the right input device is selected and not muted"
+                );
+            }
+        } else {
+            if quiet >= silent_ticks {
+                info!("microphone signal detected");
+            }
+            quiet = 0;
+        }
+
+        since_clip_warning += 1;
+        if capture.clipped > clipped && since_clip_warning >= clip_ticks {
+            warn!(
+                peak_dbfs = capture.peak_dbfs,
+                "microphone is clipping; lower the input gain"
+            );
+            since_clip_warning = 0;
+        }
+        clipped = capture.clipped;
+
+        if meter {
+            let quality = stats.quality.snapshot();
+            let mos = |mos: Option<f32>| mos.map_or("-".to_string(), |mos| format!("{mos:.1}"));
+            let talking = stats
+                .remote_level
+                .snapshot()
+                .is_some_and(|level| level.speaking);
+            let presence = match Presence::from_index(stats.remote_presence.get()) {
+                Presence::Here if talking => "talking".to_string(),
+                Presence::Here => String::new(),
+                presence => format!("({presence})"),
+            };
+            // padded so that the line keeps its length as the marker comes and goes.
+            let line = format!(
+                "mic {}  remote {} {presence:<9}  MOS rx {} tx {}",
+                render(&capture),
+                render(&stats.playback_level.snapshot()),
+                mos(quality.receive_mos),
+                mos(quality.send_mos),
+            );
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r{line}");
+            let _ = stderr.flush();
+        }
+    }
+}
+
+/// `[#####----|--] -23 dB`: the bar shows RMS, the marker the peak.
+fn render(level: &LevelSnapshot) -> String {
+    let position = |dbfs: f32| {
+        let fraction = (dbfs - METER_FLOOR_DBFS) / -METER_FLOOR_DBFS;
+        (fraction.clamp(0., 1.) * METER_WIDTH as f32).round() as usize
+    };
+    let rms = position(level.rms_dbfs);
+    let peak = position(level.peak_dbfs).max(rms);
+    let mut bar = String::with_capacity(METER_WIDTH);
+    for i in 0..METER_WIDTH {
+        bar.push(match i {
+            i if i < rms => '#',
+            i if i + 1 == peak => '|',
+            _ => '-',
+        });
+    }
+    if level.rms_dbfs <= SILENCE_DBFS {
+        format!("[{bar}]   -∞ dB")
+    } else {
+        format!("[{bar}] {:>4.0} dB", level.rms_dbfs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_tracks_level_and_counts_clipping() {
+        let level = Level::default();
+        assert!(render(&level.snapshot()).ends_with("-∞ dB"));
+
+        level.measure(&[0.1; 960]);
+        let snapshot = level.snapshot();
+        assert!((snapshot.rms_dbfs + 20.).abs() < 0.01);
+        assert_eq!(snapshot.clipped, 0);
+        assert_eq!(
+            render(&snapshot),
+            format!("[{}{}]  -20 dB", "#".repeat(20), "-".repeat(10))
+        );
+
+        level.measure(&[1.; 960]);
+        assert_eq!(level.snapshot().clipped, 1);
+    }
+}
@@ -0,0 +1,119 @@
+//! Per-session mixing policy, for a process that takes part in several
+//! sessions on the same devices.
+//!
+//! Every source in the playback mix belongs to a session and is placed and
+//! scaled by that session's [`SessionMix`], so sessions can be told apart
+//! (e.g. one on the left, one on the right) and turned down or muted without
+//! touching the others. It also counts the session's sources still playing,
+//! so a call can let them play out before it ends.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use tokio::sync::watch;
+
+use super::PanMode;
+
+/// A playback gain that can be changed while the session's tracks play.
+#[derive(Debug, Clone)]
+pub struct Gain(Arc<AtomicU32>);
+
+impl Default for Gain {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(1f32.to_bits())))
+    }
+}
+
+impl Gain {
+    /// Set the gain in dB; `-inf` mutes.
+    pub fn set_db(&self, db: f32) {
+        let linear = 10f32.powf(db / 20.);
+        self.0.store(linear.to_bits(), Ordering::Relaxed);
+    }
+
+    /// The linear factor.
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// The number of a session's sources in the playback mix.
+#[derive(Debug, Clone)]
+pub struct ActiveSources(Arc<watch::Sender<usize>>);
+
+impl Default for ActiveSources {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+}
+
+impl ActiveSources {
+    pub(super) fn add(&self) {
+        self.0.send_modify(|count| *count += 1);
+    }
+
+    pub(super) fn remove(&self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+
+    /// Wait until every source has played out and left the mix.
+    pub async fn drained(&self) {
+        let _ = self.0.subscribe().wait_for(|&count| count == 0).await;
+    }
+}
+
+/// How the sources of one session are mixed.
+#[derive(Debug, Clone, Default)]
+pub struct SessionMix {
+    /// The session, for the log; empty for local sounds such as alerts.
+    pub namespace: Arc<str>,
+    pub pan: PanMode,
+    pub gain: Gain,
+    pub sources: ActiveSources,
+}
+
+impl SessionMix {
+    pub fn new(namespace: &str, pan: PanMode) -> Self {
+        Self {
+            namespace: namespace.into(),
+            pan,
+            gain: Gain::default(),
+            sources: ActiveSources::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_is_shared_and_set_in_db() {
+        let mix = SessionMix::new("demo", PanMode::Off);
+        let playing = mix.clone();
+        assert_eq!(playing.gain.get(), 1.);
+        mix.gain.set_db(-6.);
+        assert!((playing.gain.get() - 0.501).abs() < 1e-3);
+        mix.gain.set_db(f32::NEG_INFINITY);
+        assert_eq!(playing.gain.get(), 0.);
+    }
+
+    #[tokio::test]
+    async fn drained_once_every_source_left() {
+        let sources = ActiveSources::default();
+        sources.drained().await;
+        sources.add();
+        sources.add();
+        let drained = tokio::spawn({
+            let sources = sources.clone();
+            async move { sources.drained().await }
+        });
+        sources.remove();
+        tokio::task::yield_now().await;
+        assert!(!drained.is_finished());
+        sources.remove();
+        drained.await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+//! Operating points of the audio engine.
+//!
+//! The defaults are tuned for a voice call: processing that cleans up speech,
+//! a codec that favours intelligibility at low bitrates, and buffers kept as
+//! short as the network allows. Music wants the opposite on every count.
+
+use std::time::Duration;
+
+use super::DEFAULT_PLAYOUT_DELAY;
+
+/// Bitrate of music, and the most the receiver reports may raise it back to.
+const MUSIC_BITRATE: u32 = 128_000;
+const MUSIC_PLAYOUT_DELAY: Duration = Duration::from_millis(150);
+/// Frames between the network and the decoder of a remote track.
+const VOICE_QUEUE_FRAMES: usize = 32;
+const MUSIC_QUEUE_FRAMES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AudioMode {
+    /// Speech: echo cancellation, noise suppression and gain control, an
+    /// adaptive bitrate and a short playout buffer.
+    #[default]
+    Voice,
+    /// Full-band stereo at a high bitrate, without processing, and with
+    /// deeper buffers that trade latency for fewer gaps.
+    Music,
+}
+
+impl AudioMode {
+    /// Whether echo cancellation, noise suppression and gain control run.
+    /// They treat music as noise to remove.
+    pub fn processing(self) -> bool {
+        self == AudioMode::Voice
+    }
+
+    /// A fixed encoder bitrate, or `None` for the codec's default.
+    pub fn bitrate(self) -> Option<u32> {
+        match self {
+            AudioMode::Voice => None,
+            AudioMode::Music => Some(MUSIC_BITRATE),
+        }
+    }
+
+    pub fn playout_delay(self) -> Duration {
+        match self {
+            AudioMode::Voice => DEFAULT_PLAYOUT_DELAY,
+            AudioMode::Music => MUSIC_PLAYOUT_DELAY,
+        }
+    }
+
+    pub fn queue_frames(self) -> usize {
+        match self {
+            AudioMode::Voice => VOICE_QUEUE_FRAMES,
+            AudioMode::Music => MUSIC_QUEUE_FRAMES,
+        }
+    }
+
+    /// Opus tuned for speech, or for full-band general audio.
+    pub(crate) fn application(self) -> opus::Application {
+        match self {
+            AudioMode::Voice => opus::Application::Voip,
+            AudioMode::Music => opus::Application::Audio,
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Quieting other applications during a call (`--duck-others`,
+//! `--pause-others`).
+//!
+//! Music and notifications from other applications reach the speakers and,
+//! through the microphone, the echo canceller, which would rather only hear
+//! the call. For the length of the call we lower the volume of every other
+//! stream PulseAudio, or PipeWire's PulseAudio server, is playing, and pause
+//! the media players that are playing through MPRIS (with `playerctl`).
+//! Both are restored on hangup; streams that start during the call are left
+//! alone.
+
+use std::process::Command;
+
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// A stream of another application, as `pactl -f json list sink-inputs`
+/// describes it.
+#[derive(Debug, Deserialize)]
+struct SinkInput {
+    index: u32,
+    /// Channel names, comma-separated in the order pactl takes volumes in.
+    channel_map: String,
+    /// By channel name.
+    volume: serde_json::Map<String, serde_json::Value>,
+    properties: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SinkInput {
+    fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key)?.as_str()
+    }
+
+    /// Raw volumes, in channel order.
+    fn volumes(&self) -> Vec<u64> {
+        self.channel_map
+            .split(',')
+            .filter_map(|channel| self.volume.get(channel)?.get("value")?.as_u64())
+            .collect()
+    }
+}
+
+/// Other applications quieted for the call, restored when dropped.
+#[derive(Debug, Default)]
+pub struct OtherApps {
+    /// Streams lowered, with their volumes before.
+    ducked: Vec<(u32, Vec<u64>)>,
+    /// Players paused.
+    paused: Vec<String>,
+}
+
+impl OtherApps {
+    /// Lower other streams by `duck_db`, if given, and pause playing media
+    /// players if `pause`. What fails is skipped with a warning.
+    pub fn quiet(duck_db: Option<f32>, pause: bool) -> Self {
+        let mut others = Self::default();
+        if let Some(db) = duck_db {
+            match others.duck(db) {
+                Ok(()) => info!(
+                    db,
+                    streams = others.ducked.len(),
+                    "lowered other applications"
+                ),
+                Err(err) => warn!("failed to lower other applications: {err:#}"),
+            }
+        }
+        if pause {
+            match others.pause() {
+                Ok(()) => info!(players = ?others.paused, "paused other media players"),
+                Err(err) => warn!("failed to pause other media players: {err:#}"),
+            }
+        }
+        others
+    }
+
+    fn duck(&mut self, db: f32) -> Result<()> {
+        let json = output("pactl", &["-f", "json", "list", "sink-inputs"])?;
+        let factor = 10f32.powf(-db.abs() / 20.);
+        for (index, volumes) in other_streams(&json, std::process::id())? {
+            let lowered: Vec<_> = volumes
+                .iter()
+                .map(|volume| ((*volume as f32 * factor) as u64).to_string())
+                .collect();
+            if set_volume(index, &lowered).is_ok() {
+                self.ducked.push((index, volumes));
+            }
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        let players = output("playerctl", &["--list-all"])?;
+        // our own player, with --tray, plays for as long as the call does.
+        for player in players.lines().filter(|player| !player.starts_with("neet")) {
+            let playing = output("playerctl", &["--player", player, "status"])
+                .is_ok_and(|status| status.trim() == "Playing");
+            if playing && output("playerctl", &["--player", player, "pause"]).is_ok() {
+                self.paused.push(player.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OtherApps {
+    fn drop(&mut self) {
+        for (index, volumes) in self.ducked.drain(..) {
+            let volumes: Vec<_> = volumes.iter().map(ToString::to_string).collect();
+            // the stream may have ended meanwhile.
+            let _ = set_volume(index, &volumes);
+        }
+        for player in self.paused.drain(..) {
+            if let Err(err) = output("playerctl", &["--player", &player, "play"]) {
+                warn!(player, "failed to resume a media player: {err:#}");
+            }
+        }
+    }
+}
+
+/// The streams in `json` not played by the process `pid`, with their
+/// volumes.
+fn other_streams(json: &str, pid: u32) -> Result<Vec<(u32, Vec<u64>)>> {
+    let inputs: Vec<SinkInput> =
+        serde_json::from_str(json).context("unexpected output from pactl")?;
+    let pid = pid.to_string();
+    Ok(inputs
+        .into_iter()
+        .filter(|input| input.property("application.process.id") != Some(pid.as_str()))
+        .map(|input| (input.index, input.volumes()))
+        .filter(|(_, volumes)| !volumes.is_empty())
+        .collect())
+}
+
+fn set_volume(index: u32, volumes: &[String]) -> Result<()> {
+    let mut args = vec!["set-sink-input-volume".to_string(), index.to_string()];
+    args.extend_from_slice(volumes);
+    let args: Vec<_> = args.iter().map(String::as_str).collect();
+    output("pactl", &args).map(drop)
+}
+
+/// What `program` prints, if it succeeds.
+fn output(program: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("failed to run {program}"))?;
+    ensure!(
+        output.status.success(),
+        "{program} failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_streams_of_other_applications() {
+        let json = r#"[
+            {"index": 41, "channel_map": "front-right,front-left", "volume": {
+                "front-left": {"value": 65536, "value_percent": "100%", "db": "0.00 dB"},
+                "front-right": {"value": 32768, "value_percent": "50%", "db": "-18.06 dB"}
+            }, "properties": {"application.name": "Firefox", "application.process.id": "200"}},
+            {"index": 42, "channel_map": "mono", "volume": {
+                "mono": {"value": 65536, "value_percent": "100%", "db": "0.00 dB"}
+            }, "properties": {"application.name": "neet", "application.process.id": "100"}}
+        ]"#;
+        assert_eq!(
+            other_streams(json, 100).unwrap(),
+            [(41, vec![32768, 65536])]
+        );
+        assert!(other_streams("not json", 100).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Call events for stream overlays (`--stream-events`).
+//!
+//! A WebSocket server tells every client connected to it who joins and
+//! leaves the call, one JSON text message per event, e.g.
+//! `{"event":"joined","name":"caller"}`, which an OBS browser source can
+//! show. Clients only listen; whatever they send is ignored.
+
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use futures_util::SinkExt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info};
+
+use super::CallEvent;
+
+/// Events buffered for a slow client before it misses some.
+const EVENT_CAPACITY: usize = 64;
+
+/// The WebSocket server, stopped when dropped.
+#[derive(Debug)]
+pub struct OverlayEvents {
+    events: broadcast::Sender<String>,
+    accept: JoinHandle<()>,
+}
+
+impl OverlayEvents {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen for overlays on {addr}"))?;
+        info!(addr = %listener.local_addr()?, "serving call events to overlays");
+        let events = broadcast::channel(EVENT_CAPACITY).0;
+        let accept = tokio::spawn(accept(listener, events.clone()));
+        Ok(Self { events, accept })
+    }
+
+    /// Tell the connected clients that `name` did `event`.
+    pub fn send(&self, event: CallEvent, name: &str) {
+        let message = serde_json::json!({ "event": event, "name": name });
+        // nobody connected is fine.
+        let _ = self.events.send(message.to_string());
+    }
+}
+
+impl Drop for OverlayEvents {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+async fn accept(listener: TcpListener, events: broadcast::Sender<String>) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        let events = events.subscribe();
+        tokio::spawn(async move {
+            if let Err(err) = serve(stream, events).await {
+                debug!(%peer, "overlay client left: {err:#}");
+            }
+        });
+    }
+}
+
+async fn serve(stream: TcpStream, mut events: broadcast::Receiver<String>) -> Result<()> {
+    let mut socket = tokio_tungstenite::accept_async(stream).await?;
+    loop {
+        match events.recv().await {
+            Ok(event) => socket.send(Message::text(event)).await?,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+    socket.close(None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn events_are_json() {
+        let overlay = OverlayEvents::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut events = overlay.events.subscribe();
+        overlay.send(CallEvent::Joined, "caller");
+        assert_eq!(
+            events.recv().await.unwrap(),
+            r#"{"event":"joined","name":"caller"}"#
+        );
+    }
+}
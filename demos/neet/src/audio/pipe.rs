@@ -0,0 +1,241 @@
+//! External commands as audio devices (`--input-cmd`, `--output-cmd`).
+//!
+//! `--input-device cmd:<command>` runs the command in a shell and captures
+//! what it writes to stdout; `--output-device cmd:<command>` writes played
+//! audio to its stdin. Both carry raw 16-bit little-endian PCM in
+//! [`ENGINE_FORMAT`], 48 kHz stereo, so any tool that can read or write
+//! that, e.g. `ffmpeg -f s16le -ar 48000 -ac 2` or GStreamer's `fdsrc` and
+//! `fdsink`, sources or sinks call audio.
+//!
+//! A command that writes faster than real time is held back by the capture
+//! buffer filling up; output goes out every 10 ms whether or not the command
+//! keeps up. The command is killed with the stream, along with whatever it
+//! started on Unix.
+
+use std::{
+    io::{Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use ringbuf::{
+    traits::{Consumer as _, Observer, Producer as _},
+    HeapCons as Consumer, HeapProd as Producer,
+};
+use tracing::info;
+
+use super::{power::BusyTimer, WebrtcAudioProcessor, DURATION_10MS, ENGINE_FORMAT};
+use crate::stats::{Counter, XrunStats};
+
+/// Prefix of a device name that is a command.
+pub const PIPE_PREFIX: &str = "cmd:";
+/// Audio read or written at a time, as the audio processor wants it.
+const PERIOD: std::time::Duration = DURATION_10MS;
+
+/// The command `device` names, if it names one.
+pub fn pipe_command(device: &str) -> Option<&str> {
+    device.strip_prefix(PIPE_PREFIX)
+}
+
+fn spawn(command: &str, stdin: Stdio, stdout: Stdio) -> Result<Child> {
+    #[cfg(unix)]
+    let mut shell = {
+        use std::os::unix::process::CommandExt as _;
+        let mut shell = Command::new("sh");
+        // a group of its own, to kill the pipeline the shell runs with it.
+        shell.arg("-c").process_group(0);
+        shell
+    };
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    shell
+        .arg(command)
+        .stdin(stdin)
+        .stdout(stdout)
+        .spawn()
+        .with_context(|| format!("failed to run `{command}`"))
+}
+
+/// A running command, fed or drained by a thread of its own.
+pub struct PipeStream {
+    child: Child,
+}
+
+impl Drop for PipeStream {
+    fn drop(&mut self) {
+        // the thread stops once the pipe breaks; it isn't waited for, in
+        // case something the command started holds the pipe open.
+        #[cfg(unix)]
+        // SAFETY: kill takes no pointers, and the group is the child's, whose
+        // pid can't be reused before it is waited for below.
+        unsafe {
+            libc::kill(-(self.child.id() as libc::pid_t), libc::SIGKILL);
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn start_pipe_capture(
+    command: &str,
+    producer: Producer<f32>,
+    processor: WebrtcAudioProcessor,
+    busy: Counter,
+) -> Result<PipeStream> {
+    #[cfg(feature = "audio-processing")]
+    processor.init_capture(ENGINE_FORMAT.channel_count as usize)?;
+    let mut child = spawn(command, Stdio::null(), Stdio::piped())?;
+    let capture = PipeCapture {
+        stdout: child.stdout.take().context("no stdout")?,
+        producer,
+        processor,
+        busy,
+    };
+    info!(command, "capturing from a command");
+    std::thread::Builder::new()
+        .name("pipe-capture".to_string())
+        .spawn(move || capture.run())?;
+    Ok(PipeStream { child })
+}
+
+struct PipeCapture {
+    stdout: ChildStdout,
+    producer: Producer<f32>,
+    #[allow(unused)]
+    processor: WebrtcAudioProcessor,
+    busy: Counter,
+}
+
+impl PipeCapture {
+    fn run(mut self) {
+        let mut buf = vec![0.; ENGINE_FORMAT.sample_count(PERIOD)];
+        let mut bytes = vec![0; buf.len() * 2];
+        loop {
+            if let Err(err) = self.stdout.read_exact(&mut bytes) {
+                info!("capture command ended: {err}");
+                return;
+            }
+            let _busy = BusyTimer::start(&self.busy);
+            for (sample, le) in buf.iter_mut().zip(bytes.chunks_exact(2)) {
+                *sample = i16::from_le_bytes([le[0], le[1]]) as f32 / i16::MAX as f32;
+            }
+            #[cfg(feature = "audio-processing")]
+            if let Err(err) = self.processor.process_capture_frame(&mut buf) {
+                tracing::warn!("failed to process piped capture: {err}");
+            }
+            // wait for room rather than drop audio from a fast command.
+            while self.producer.vacant_len() < buf.len() {
+                if !self.producer.read_is_held() {
+                    return;
+                }
+                std::thread::sleep(PERIOD);
+            }
+            self.producer.push_slice(&buf);
+        }
+    }
+}
+
+pub fn start_pipe_playback(
+    command: &str,
+    consumer: Consumer<f32>,
+    processor: WebrtcAudioProcessor,
+    xruns: XrunStats,
+    busy: Counter,
+) -> Result<PipeStream> {
+    #[cfg(feature = "audio-processing")]
+    processor.init_playback(ENGINE_FORMAT.channel_count as usize)?;
+    let mut child = spawn(command, Stdio::piped(), Stdio::null())?;
+    let playback = PipePlayback {
+        stdin: child.stdin.take().context("no stdin")?,
+        consumer,
+        processor,
+        xruns,
+        busy,
+    };
+    info!(command, "playing into a command");
+    std::thread::Builder::new()
+        .name("pipe-playback".to_string())
+        .spawn(move || playback.run())?;
+    Ok(PipeStream { child })
+}
+
+struct PipePlayback {
+    stdin: ChildStdin,
+    consumer: Consumer<f32>,
+    #[allow(unused)]
+    processor: WebrtcAudioProcessor,
+    xruns: XrunStats,
+    busy: Counter,
+}
+
+impl PipePlayback {
+    fn run(mut self) {
+        let mut buf = vec![0.; ENGINE_FORMAT.sample_count(PERIOD)];
+        let mut bytes = Vec::with_capacity(buf.len() * 2);
+        let start = Instant::now();
+        let mut periods = 0;
+        loop {
+            let busy = BusyTimer::start(&self.busy);
+            let count = self.consumer.pop_slice(&mut buf);
+            if count < buf.len() {
+                self.xruns.underruns.add(1);
+                buf[count..].fill(0.);
+            }
+            #[cfg(feature = "audio-processing")]
+            if let Err(err) = self.processor.process_render_frame(&mut buf) {
+                tracing::warn!("failed to process piped playback: {err}");
+            }
+            bytes.clear();
+            for sample in &buf {
+                let sample = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+            drop(busy);
+            if let Err(err) = self.stdin.write_all(&bytes) {
+                info!("playback command ended: {err}");
+                return;
+            }
+            // periods are due at fixed offsets from the start, so late
+            // wakeups are made up for instead of adding up.
+            periods += 1;
+            let due = start + PERIOD * periods;
+            spin_sleep::sleep(due.saturating_duration_since(Instant::now()));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::time::Duration;
+
+    use ringbuf::traits::Split;
+
+    use super::*;
+
+    #[test]
+    fn captures_pcm_from_a_command() {
+        assert_eq!(pipe_command("cmd:ffmpeg -i x"), Some("ffmpeg -i x"));
+        assert_eq!(pipe_command("jabra"), None);
+
+        // 10 ms of full-scale left, silent right.
+        let command = "for i in $(seq 480); do printf '\\377\\177\\000\\000'; done";
+        let (producer, mut consumer) = ringbuf::HeapRb::<f32>::new(4096).split();
+        #[cfg(feature = "audio-processing")]
+        let processor = WebrtcAudioProcessor::new(false, false).unwrap();
+        #[cfg(not(feature = "audio-processing"))]
+        let processor = WebrtcAudioProcessor;
+        let _stream = start_pipe_capture(command, producer, processor, Counter::default()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while consumer.occupied_len() < 960 && Instant::now() < deadline {
+            std::thread::sleep(PERIOD);
+        }
+        let mut captured = vec![0.; 960];
+        assert_eq!(consumer.pop_slice(&mut captured), 960);
+        assert!(captured.chunks(2).all(|frame| frame == [1., 0.]));
+    }
+}
@@ -1,6 +1,10 @@
 use std::{
     num::NonZeroUsize,
     ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -18,19 +22,76 @@ use ringbuf::{
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, trace, trace_span, warn, Level};
 
+#[cfg(feature = "virtual-audio")]
+use super::virtual_device::{is_virtual_output, start_virtual_playback};
+#[cfg(target_os = "macos")]
+use super::vpio::{VoiceUnit, VOICE_PROCESSING_DEVICE};
 use super::{
-    device::{find_device, find_output_stream_config, Direction, StreamConfigWithFormat},
-    AudioFormat, WebrtcAudioProcessor, DURATION_10MS, DURATION_20MS, ENGINE_FORMAT, SAMPLE_RATE,
+    device::{
+        find_device, find_output_stream_config, Direction, RunningStream, StreamConfigWithFormat,
+    },
+    drift::DriftEstimator,
+    gap::GapSmoother,
+    hooks::{FrameProcessor, Processors},
+    mix::SessionMix,
+    pan::{self, PanMode},
+    pipe::{pipe_command, start_pipe_playback},
+    power::{BusyTimer, Pacing},
+    AudioFormat, AudioSink, WebrtcAudioProcessor, DURATION_10MS, DURATION_20MS, ENGINE_FORMAT,
+    SAMPLE_RATE,
 };
-use crate::{codec::opus::MediaTrackOpusDecoder, media::MediaTrack};
+use crate::{
+    codec::opus::MediaTrackOpusDecoder,
+    error::NeetError,
+    media::MediaTrack,
+    stats::{Counter, XrunStats},
+};
+
+/// The output device name that opens no device: played audio is discarded,
+/// e.g. because received audio goes elsewhere.
+pub const NO_OUTPUT_DEVICE: &str = "none";
+/// Most the playback loop speeds up or slows down its ticks to follow the
+/// output device's clock.
+const MAX_PACE_ADJUST: f64 = 0.005;
+/// Audio left waiting for the output device after a stall, beyond which the
+/// excess is dropped.
+const STALL_BUFFER: Duration = Duration::from_millis(200);
 
 pub trait AudioSource: Send + 'static {
     fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>>;
 }
 
+/// A source in the playback mix and the session it belongs to. Counted in
+/// the session's active sources until dropped.
+struct MixerInput {
+    source: Box<dyn AudioSource>,
+    mix: SessionMix,
+    smoother: GapSmoother,
+}
+
+impl MixerInput {
+    fn new(source: Box<dyn AudioSource>, mix: SessionMix) -> Self {
+        mix.sources.add();
+        let smoother = GapSmoother::new(ENGINE_FORMAT.channel_count as usize, SAMPLE_RATE.0);
+        Self {
+            source,
+            mix,
+            smoother,
+        }
+    }
+}
+
+impl Drop for MixerInput {
+    fn drop(&mut self) {
+        self.mix.sources.remove();
+    }
+}
+
 #[derive(derive_more::Debug, Clone)]
 pub struct AudioPlayback {
-    source_sender: mpsc::Sender<Box<dyn AudioSource>>,
+    source_sender: mpsc::Sender<MixerInput>,
+    tap_sender: mpsc::Sender<Box<dyn AudioSink>>,
+    processor_sender: mpsc::Sender<Box<dyn FrameProcessor>>,
 }
 
 impl AudioPlayback {
@@ -38,14 +99,40 @@ impl AudioPlayback {
         host: &cpal::Host,
         device: Option<&str>,
         processor: WebrtcAudioProcessor,
+        xruns: XrunStats,
+        pacing: Pacing,
+        busy: Counter,
     ) -> Result<Self> {
-        let device = find_device(host, Direction::Playback, device)?;
-        let stream_config = find_output_stream_config(&device, &ENGINE_FORMAT)?;
+        let device = OutputDevice::find(host, device)?;
+        Self::start(device, processor, xruns, pacing, busy).await
+    }
 
+    /// Play on the voice processing `unit` that capture set up.
+    #[cfg(target_os = "macos")]
+    pub async fn build_voice_processing(
+        unit: VoiceUnit,
+        processor: WebrtcAudioProcessor,
+        xruns: XrunStats,
+        pacing: Pacing,
+        busy: Counter,
+    ) -> Result<Self> {
+        let device = OutputDevice::VoiceProcessing(unit);
+        Self::start(device, processor, xruns, pacing, busy).await
+    }
+
+    async fn start(
+        device: OutputDevice,
+        processor: WebrtcAudioProcessor,
+        xruns: XrunStats,
+        pacing: Pacing,
+        busy: Counter,
+    ) -> Result<Self> {
         let buffer_size = ENGINE_FORMAT.sample_count(DURATION_20MS) * 32;
         let (producer, consumer) = ringbuf::HeapRb::<f32>::new(buffer_size).split();
 
         let (source_sender, source_receiver) = mpsc::channel(16);
+        let (tap_sender, tap_receiver) = mpsc::channel(16);
+        let (processor_sender, processor_receiver) = mpsc::channel(16);
         let (init_tx, init_rx) = oneshot::channel();
 
         std::thread::spawn(move || {
@@ -55,22 +142,38 @@ impl AudioPlayback {
             ) {
                 warn!("failed to set playback thread to realtime priority: {err:?}");
             }
-            let stream = match start_playback_stream(&device, &stream_config, processor, consumer) {
-                Ok(stream) => {
-                    init_tx.send(Ok(())).unwrap();
-                    stream
-                }
-                Err(err) => {
-                    init_tx.send(Err(err)).unwrap();
-                    return;
-                }
-            };
-            playback_loop(producer, source_receiver);
+            let stream =
+                match device.start(processor, consumer, xruns.clone(), pacing, busy.clone()) {
+                    Ok(stream) => {
+                        init_tx.send(Ok(())).unwrap();
+                        stream
+                    }
+                    Err(err) => {
+                        let err = err.context(NeetError::AudioBackend(
+                            "failed to start playback stream".to_string(),
+                        ));
+                        init_tx.send(Err(err)).unwrap();
+                        return;
+                    }
+                };
+            playback_loop(
+                producer,
+                source_receiver,
+                tap_receiver,
+                processor_receiver,
+                xruns,
+                pacing,
+                busy,
+            );
             drop(stream);
         });
 
         init_rx.await??;
-        Ok(Self { source_sender })
+        Ok(Self {
+            source_sender,
+            tap_sender,
+            processor_sender,
+        })
     }
 
     pub async fn add_track(&self, track: MediaTrack) -> Result<()> {
@@ -79,30 +182,62 @@ impl AudioPlayback {
     }
 
     pub async fn add_source(&self, source: impl AudioSource) -> Result<()> {
+        self.add_mixed_source(source, SessionMix::default()).await
+    }
+
+    /// Add `source` to the mix as part of the session that `mix` describes.
+    pub async fn add_mixed_source(&self, source: impl AudioSource, mix: SessionMix) -> Result<()> {
+        let input = MixerInput::new(Box::new(source), mix);
         self.source_sender
-            .send(Box::new(source))
+            .send(input)
             .await
             .map_err(|_| anyhow!("failed to add audio source: playback loop dead"))?;
         Ok(())
     }
+
+    /// Feed the mixed output, as sent to the device, to `sink`.
+    pub async fn add_tap(&self, sink: impl AudioSink) -> Result<()> {
+        self.tap_sender
+            .send(Box::new(sink))
+            .await
+            .map_err(|_| anyhow!("failed to add playback tap: playback loop dead"))
+    }
+
+    /// Run `processor` over the mixed output, before taps and the device get
+    /// it.
+    pub async fn add_processor(&self, processor: impl FrameProcessor) -> Result<()> {
+        self.processor_sender
+            .send(Box::new(processor))
+            .await
+            .map_err(|_| anyhow!("failed to add playback processor: playback loop dead"))
+    }
 }
 
 fn playback_loop(
     mut producer: Producer<f32>,
-    mut source_receiver: mpsc::Receiver<Box<dyn AudioSource>>,
+    mut source_receiver: mpsc::Receiver<MixerInput>,
+    mut tap_receiver: mpsc::Receiver<Box<dyn AudioSink>>,
+    mut processor_receiver: mpsc::Receiver<Box<dyn FrameProcessor>>,
+    xruns: XrunStats,
+    pacing: Pacing,
+    busy: Counter,
 ) {
     let span = tracing::span!(Level::TRACE, "playback-loop");
     let _guard = span.enter();
     info!("playback loop start");
 
-    let tick_duration = DURATION_20MS;
+    let tick_duration = pacing.tick;
     let buffer_size = ENGINE_FORMAT.sample_count(tick_duration);
     let mut work_buf = vec![0.; buffer_size];
     let mut out_buf = vec![0.; buffer_size];
-    let mut sources: Vec<Box<dyn AudioSource>> = vec![];
+    let mut sources: Vec<MixerInput> = vec![];
+    let mut taps: Vec<Box<dyn AudioSink>> = vec![];
+    let mut processors = Processors::default();
+    // ticks are timed by the system clock, but the device plays by its own.
+    let mut drift = DriftEstimator::new("output device");
 
     // todo: do we want this?
-    let initial_latency = ENGINE_FORMAT.sample_count(DURATION_20MS);
+    let initial_latency = ENGINE_FORMAT.sample_count(tick_duration);
     let initial_silence = vec![0.; initial_latency];
     let n = producer.push_slice(&initial_silence);
     debug_assert_eq!(n, initial_silence.len());
@@ -110,12 +245,13 @@ fn playback_loop(
     let mut tick = 0;
     loop {
         let start = Instant::now();
+        let timer = BusyTimer::start(&busy);
 
         // pull incoming sources
         loop {
             match source_receiver.try_recv() {
                 Ok(source) => {
-                    info!("add new track to decoder");
+                    info!(session = &*source.mix.namespace, "add new track to decoder");
                     sources.push(source);
                 }
                 Err(mpsc::error::TryRecvError::Empty) => break,
@@ -125,12 +261,36 @@ fn playback_loop(
                 }
             }
         }
+        while let Ok(tap) = tap_receiver.try_recv() {
+            info!("new tap added to playback loop");
+            taps.push(tap);
+        }
+        while let Ok(processor) = processor_receiver.try_recv() {
+            info!("new processor added to playback loop");
+            processors.push(processor);
+        }
 
         out_buf.fill(0.);
-        sources.retain_mut(|source| match source.tick(&mut work_buf) {
+        // auto-placed sources are re-spread whenever one joins or leaves.
+        let auto_count = sources
+            .iter()
+            .filter(|input| input.mix.pan == PanMode::Auto)
+            .count();
+        let mut auto_index = 0;
+        sources.retain_mut(|input| match input.source.tick(&mut work_buf) {
             Ok(ControlFlow::Continue(count)) => {
-                for i in 0..count {
-                    out_buf[i] += work_buf[i];
+                match input.mix.pan {
+                    PanMode::Off => {}
+                    PanMode::Auto => {
+                        pan::apply(&mut work_buf[..count], pan::spread(auto_index, auto_count));
+                        auto_index += 1;
+                    }
+                    PanMode::Fixed(position) => pan::apply(&mut work_buf[..count], position),
+                }
+                input.smoother.process(&mut work_buf, count);
+                let gain = input.mix.gain.get();
+                for (out, sample) in out_buf.iter_mut().zip(&work_buf) {
+                    *out += gain * sample;
                 }
                 if count < work_buf.len() {
                     debug!(
@@ -151,8 +311,19 @@ fn playback_loop(
             }
         });
 
+        processors.run(&mut out_buf);
+        taps.retain_mut(|tap| match tap.tick(&out_buf) {
+            Ok(ControlFlow::Continue(())) => true,
+            Ok(ControlFlow::Break(())) => false,
+            Err(err) => {
+                warn!("remove playback tap: failed {err:?}");
+                false
+            }
+        });
+
         let len = producer.push_slice(&out_buf[..]);
         if len < out_buf.len() {
+            xruns.overruns.add(1);
             warn!(
                 "xrun: failed to push {} of {}",
                 out_buf.len() - len,
@@ -160,25 +331,134 @@ fn playback_loop(
             );
         }
 
+        // tick slower while the device falls behind, faster while it gets ahead.
+        let frames = buffer_size as f64 / 2.;
+        let pace = match drift.update(producer.occupied_len() / 2, len / 2) {
+            Some(error) => 1. + (error / frames).clamp(-1., 1.) * MAX_PACE_ADJUST,
+            None => 1.,
+        };
+        drift.corrected(frames * (1. - pace));
+        let tick_time = tick_duration.mul_f64(pace);
+
+        drop(timer);
         trace!("tick {tick} took {:?} pushed {len}", start.elapsed());
-        if start.elapsed() > tick_duration {
+        if start.elapsed() > tick_time {
             warn!(
                 "playback thread tick exceeded interval (took {:?})",
                 start.elapsed()
             );
         } else {
-            let sleep_time = tick_duration.saturating_sub(start.elapsed());
-            spin_sleep::sleep(sleep_time);
+            let sleep_time = tick_time.saturating_sub(start.elapsed());
+            pacing.sleep(sleep_time);
         }
         tick += 1;
     }
 }
 
+/// Where played audio goes.
+enum OutputDevice {
+    Cpal {
+        device: Device,
+        config: StreamConfigWithFormat,
+    },
+    #[cfg(feature = "virtual-audio")]
+    Virtual,
+    /// A command reading PCM from stdin.
+    Pipe(String),
+    /// A voice processing unit, started with the capture buffer its
+    /// capture side left.
+    #[cfg(target_os = "macos")]
+    VoiceProcessing(VoiceUnit),
+    None,
+}
+
+impl OutputDevice {
+    fn find(host: &cpal::Host, name: Option<&str>) -> Result<Self> {
+        if name == Some(NO_OUTPUT_DEVICE) {
+            return Ok(OutputDevice::None);
+        }
+        if let Some(command) = name.and_then(pipe_command) {
+            return Ok(OutputDevice::Pipe(command.to_string()));
+        }
+        #[cfg(target_os = "macos")]
+        // only playback started with the capture's unit can start it.
+        if name == Some(VOICE_PROCESSING_DEVICE) {
+            return Ok(OutputDevice::VoiceProcessing(VoiceUnit::default()));
+        }
+        #[cfg(feature = "virtual-audio")]
+        if is_virtual_output(name) {
+            return Ok(OutputDevice::Virtual);
+        }
+        let device = find_device(host, Direction::Playback, name)?;
+        let config = find_output_stream_config(&device, &ENGINE_FORMAT)?;
+        Ok(OutputDevice::Cpal { device, config })
+    }
+
+    fn start(
+        self,
+        processor: WebrtcAudioProcessor,
+        consumer: Consumer<f32>,
+        xruns: XrunStats,
+        pacing: Pacing,
+        busy: Counter,
+    ) -> Result<RunningStream> {
+        match self {
+            OutputDevice::Cpal { device, config } => {
+                let stream = start_playback_stream(
+                    &device, &config, processor, consumer, xruns, pacing, busy,
+                )?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(feature = "virtual-audio")]
+            OutputDevice::Virtual => {
+                let stream = start_virtual_playback(consumer, processor, xruns, busy)?;
+                Ok(Box::new(stream))
+            }
+            OutputDevice::Pipe(command) => {
+                let stream = start_pipe_playback(&command, consumer, processor, xruns, busy)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(target_os = "macos")]
+            OutputDevice::VoiceProcessing(unit) => {
+                let unit = unit.start(consumer, xruns, busy)?;
+                Ok(Box::new(unit))
+            }
+            OutputDevice::None => Ok(Box::new(Discard::start(consumer, pacing))),
+        }
+    }
+}
+
+/// Drains played audio as fast as a device would play it, until dropped.
+struct Discard(Arc<AtomicBool>);
+
+impl Discard {
+    fn start(mut consumer: Consumer<f32>, pacing: Pacing) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                consumer.skip(ENGINE_FORMAT.sample_count(pacing.tick));
+                pacing.sleep(pacing.tick);
+            }
+        });
+        Self(stopped)
+    }
+}
+
+impl Drop for Discard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
 fn start_playback_stream(
     device: &Device,
     stream_config: &StreamConfigWithFormat,
     processor: WebrtcAudioProcessor,
     consumer: Consumer<f32>,
+    xruns: XrunStats,
+    pacing: Pacing,
+    busy: Counter,
 ) -> Result<cpal::Stream> {
     let config = &stream_config.config;
     let format = stream_config.audio_format();
@@ -196,6 +476,11 @@ fn start_playback_stream(
         format,
         processor,
         resampler,
+        smoother: GapSmoother::new(format.channel_count as usize, format.sample_rate.0),
+        xruns,
+        // what the mixer keeps queued: its initial latency and a tick.
+        kept: ENGINE_FORMAT.sample_count(pacing.tick) * 2,
+        busy,
     };
     let stream = match stream_config.sample_format {
         SampleFormat::I8 => build_playback_stream::<i8>(device, config, state),
@@ -221,6 +506,12 @@ struct PlaybackState {
     #[allow(unused)]
     processor: WebrtcAudioProcessor,
     consumer: Consumer<f32>,
+    smoother: GapSmoother,
+    xruns: XrunStats,
+    /// Queued audio to keep when dropping what piled up in a stall.
+    kept: usize,
+    /// Time spent in the callback.
+    busy: Counter,
 }
 
 fn build_playback_stream<S: dasp_sample::FromSample<f32> + cpal::SizedSample + Default>(
@@ -234,13 +525,13 @@ fn build_playback_stream<S: dasp_sample::FromSample<f32> + cpal::SizedSample + D
     let mut resampled: Vec<f32> = Vec::with_capacity(frame_size);
     let mut tick = 0;
     let mut last_warning = Instant::now();
-    let mut underflows = 0;
     let span = trace_span!("playback-cb");
 
     device.build_output_stream::<S, _, _>(
         config,
         move |data: &mut [S], info: &_| {
             let _guard = span.enter();
+            let _busy = BusyTimer::start(&state.busy);
             let delay = {
                 let output_delay = info
                     .timestamp()
@@ -259,6 +550,20 @@ fn build_playback_stream<S: dasp_sample::FromSample<f32> + cpal::SizedSample + D
             #[cfg(feature = "audio-processing")]
             state.processor.set_playback_delay(delay);
 
+            // after a stall, e.g. a suspended system or a starved callback,
+            // drop what piled up rather than play it late.
+            let callback = state.format.duration_from_sample_count(data.len());
+            let stalled = ENGINE_FORMAT.sample_count(STALL_BUFFER.max(callback * 4));
+            let queued = state.consumer.occupied_len();
+            if queued > stalled {
+                let dropped = state.consumer.skip(queued - state.kept);
+                state.xruns.resets.add(1);
+                warn!(
+                    "playback stalled: dropped {:?} of queued audio",
+                    ENGINE_FORMAT.duration_from_sample_count(dropped)
+                );
+            }
+
             // pop from channel
             unprocessed.extend(state.consumer.pop_iter());
 
@@ -282,27 +587,31 @@ fn build_playback_stream<S: dasp_sample::FromSample<f32> + cpal::SizedSample + D
             processed.clear();
 
 
-            // copy to out
+            // copy to out, fading out into a gap if we ran short
             let out_len = resampled.len().min(data.len());
-            let remaining = resampled.len() - out_len;
-            for (i, sample) in data[..out_len].iter_mut().enumerate() {
-                *sample = resampled[i].to_sample()
+            if out_len < data.len() {
+                resampled.resize(data.len(), 0.);
+            }
+            state.smoother.process(&mut resampled[..data.len()], out_len);
+            for (sample, value) in data.iter_mut().zip(&resampled) {
+                *sample = value.to_sample()
             }
-            resampled.copy_within(out_len.., 0);
+            let remaining = resampled.len() - data.len();
+            resampled.copy_within(data.len().., 0);
             resampled.truncate(remaining);
 
             // trace!("out_len {out_len} resampled_remaining {} processed_remaining {}", resampled.len(), processed.len());
             if out_len < data.len() {
+                state.xruns.underruns.add(1);
                 let now = Instant::now();
                 if now.duration_since(last_warning) > Duration::from_secs(1) {
                     warn!(
-                        "[tick {tick}] playback xrun: {} of {} samples missing (buffered {}) (+ {} previous)",
+                        "[tick {tick}] playback xrun: {} of {} samples missing (buffered {}) ({} so far)",
                         data.len() - out_len,
                         data.len(),
                         unprocessed.len() + state.consumer.occupied_len(),
-                        underflows
+                        state.xruns.underruns.get()
                     );
-                    underflows += 1;
                     last_warning = now;
                 }
             }
@@ -0,0 +1,77 @@
+//! How often the pipeline wakes up, and how much of the time it works.
+//!
+//! By default the capture and playback loops tick every 20 ms and spin for
+//! the last stretch of each sleep to wake up on time, and Opus frames are
+//! 20 ms. In low-power mode, for laptops on battery, the loops tick every
+//! 40 ms and sleep through, Opus frames are 60 ms, and echo cancellation runs
+//! without its extended filter. The price is latency: about 80 ms more mouth
+//! to ear.
+
+use std::time::{Duration, Instant};
+
+use crate::stats::Counter;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pacing {
+    /// Interval of the capture and playback loops.
+    pub tick: Duration,
+    /// Duration of an encoded Opus frame.
+    pub opus_frame: Duration,
+    /// Spin for accurate wakeups, rather than leave them to the OS.
+    spin: bool,
+}
+
+impl Pacing {
+    pub fn new(low_power: bool) -> Self {
+        if low_power {
+            Self {
+                tick: Duration::from_millis(40),
+                opus_frame: Duration::from_millis(60),
+                spin: false,
+            }
+        } else {
+            Self {
+                tick: Duration::from_millis(20),
+                opus_frame: Duration::from_millis(20),
+                spin: true,
+            }
+        }
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        if self.spin {
+            spin_sleep::sleep(duration);
+        } else {
+            std::thread::sleep(duration);
+        }
+    }
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+/// Adds the time from its creation until it is dropped to `busy`, in
+/// microseconds. Wraps a loop tick or a device callback so the work of the
+/// pipeline can be told apart from the rest of the process.
+pub struct BusyTimer<'a> {
+    busy: &'a Counter,
+    start: Instant,
+}
+
+impl<'a> BusyTimer<'a> {
+    pub fn start(busy: &'a Counter) -> Self {
+        Self {
+            busy,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for BusyTimer<'_> {
+    fn drop(&mut self) {
+        self.busy.add(self.start.elapsed().as_micros() as u64);
+    }
+}
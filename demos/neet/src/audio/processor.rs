@@ -28,7 +28,9 @@ struct Inner {
 }
 
 impl WebrtcAudioProcessor {
-    pub fn new(enabled: bool) -> Result<Self> {
+    /// `light` skips the extended echo filter, which costs CPU for a longer
+    /// echo tail.
+    pub fn new(enabled: bool, light: bool) -> Result<Self> {
         let suppression_level = EchoCancellationSuppressionLevel::Moderate;
         // High pass filter is a prerequisite to running echo cancellation.
         let config = Config {
@@ -37,7 +39,7 @@ impl WebrtcAudioProcessor {
                 // stream_delay_ms: Some(20),
                 stream_delay_ms: None,
                 enable_delay_agnostic: true,
-                enable_extended_filter: true,
+                enable_extended_filter: !light,
             }),
             enable_high_pass_filter: true,
             // noise_suppression: Some(NoiseSuppression {
@@ -46,7 +48,7 @@ impl WebrtcAudioProcessor {
             ..Config::default()
         };
         // processor.set_config(config.clone());
-        info!("init audio processor (enabled={enabled})");
+        info!("init audio processor (enabled={enabled}, light={light})");
         Ok(Self(Arc::new(Inner {
             inner: Mutex::new(None),
             config: Mutex::new(config),
@@ -0,0 +1,319 @@
+//! Playing received tracks on other output devices than the call's own
+//! (`--route`, the config's `[routes]`, `ctl route`).
+//!
+//! A route takes a remote track, `audio` for the remote's voice or the name
+//! of one of its extra inputs, to an output device: the voice to
+//! headphones, say, and a program feed to a loopback device that OBS
+//! records. Each device is opened when a track first goes there and closed
+//! when the last one leaves, and plays without echo cancellation, which only
+//! the call's own output feeds.
+//! Changing a route moves the tracks already playing.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    select,
+    sync::{oneshot, watch},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{
+    mix::SessionMix,
+    playback::{AudioPlayback, AudioSource},
+    power::Pacing,
+    unprocessed,
+};
+use crate::stats::{Counter, XrunStats};
+
+/// The track name of the remote's voice, as opposed to its extra inputs.
+pub const VOICE_TRACK: &str = "audio";
+
+/// The call's output and the devices tracks are routed to instead.
+#[derive(Debug, Clone)]
+pub struct Outputs {
+    main: AudioPlayback,
+    /// Devices opened for routes, by name.
+    opened: Arc<tokio::sync::Mutex<HashMap<String, OpenedOutput>>>,
+    /// Output device by track name.
+    routes: Arc<watch::Sender<BTreeMap<String, String>>>,
+    low_power: bool,
+    pacing: Pacing,
+    xruns: XrunStats,
+    busy: Counter,
+}
+
+impl Outputs {
+    pub fn new(
+        main: AudioPlayback,
+        routes: BTreeMap<String, String>,
+        low_power: bool,
+        pacing: Pacing,
+        xruns: XrunStats,
+        busy: Counter,
+    ) -> Self {
+        Self {
+            main,
+            opened: Default::default(),
+            routes: Arc::new(watch::channel(routes).0),
+            low_power,
+            pacing,
+            xruns,
+            busy,
+        }
+    }
+
+    /// Play `track` on `device`, or the call's own output again if `None`.
+    pub fn set_route(&self, track: &str, device: Option<&str>) {
+        self.routes.send_modify(|routes| match device {
+            Some(device) => {
+                routes.insert(track.to_string(), device.to_string());
+            }
+            None => {
+                routes.remove(track);
+            }
+        });
+    }
+
+    /// The device each routed track plays on.
+    pub fn routes(&self) -> BTreeMap<String, String> {
+        self.routes.borrow().clone()
+    }
+
+    /// Play `source`, the remote's `track`, wherever it is routed, mixed as
+    /// `mix` says.
+    pub async fn play(&self, track: &str, source: impl AudioSource, mix: SessionMix) -> Result<()> {
+        let mut routes = self.routes.subscribe();
+        let device = routes.borrow_and_update().get(track).cloned();
+        let mut route = Route {
+            track: track.to_string(),
+            device: device.clone(),
+            moved: Default::default(),
+            handback: oneshot::channel().1,
+            ended: CancellationToken::new(),
+            mix,
+            routes,
+        };
+        self.attach(&mut route, device.as_deref(), Box::new(source))
+            .await?;
+        tokio::spawn(self.clone().follow_route(route));
+        Ok(())
+    }
+
+    /// Move the track to its device whenever its route changes, until it
+    /// ends.
+    async fn follow_route(self, mut route: Route) {
+        loop {
+            select! {
+                _ = route.ended.cancelled() => break,
+                changed = route.routes.changed() => if changed.is_err() {
+                    break;
+                }
+            }
+            let device = route.routes.borrow_and_update().get(&route.track).cloned();
+            if device == route.device {
+                continue;
+            }
+            let name = device.as_deref().unwrap_or("the call's output");
+            // out of the old output before into the new one, so no tick
+            // plays twice.
+            route.moved.store(true, Ordering::Relaxed);
+            let Ok(source) = (&mut route.handback).await else {
+                // it ended before it could leave.
+                break;
+            };
+            match self.attach(&mut route, device.as_deref(), source).await {
+                Ok(()) => {
+                    info!(track = route.track, device = name, "moved remote track");
+                    self.release(route.device.as_deref()).await;
+                    route.device = device;
+                }
+                Err(err) => {
+                    warn!(track = route.track, "failed to move to {name}: {err:#}");
+                    let Ok(source) = route.handback.try_recv() else {
+                        break;
+                    };
+                    // it plays on where it was, which still counts it.
+                    let old = route.device.clone();
+                    if let Err(err) = self.attach(&mut route, old.as_deref(), source).await {
+                        warn!(track = route.track, "failed to play it on: {err:#}");
+                        break;
+                    }
+                    self.release(old.as_deref()).await;
+                }
+            }
+        }
+        self.release(route.device.as_deref()).await;
+    }
+
+    /// Play `source` on `device` for `route`, as one of the device's tracks.
+    /// If that fails, the source comes back through `route.handback`.
+    async fn attach(
+        &self,
+        route: &mut Route,
+        device: Option<&str>,
+        source: Box<dyn AudioSource>,
+    ) -> Result<()> {
+        let (sender, handback) = oneshot::channel();
+        route.moved = Default::default();
+        route.handback = handback;
+        let routed = RoutedSource {
+            source: Some(source),
+            moved: route.moved.clone(),
+            ended: route.ended.clone(),
+            handback: Some(sender),
+        };
+        let playback = self.output(device).await?;
+        let added = playback.add_mixed_source(routed, route.mix.clone()).await;
+        if added.is_err() {
+            self.release(device).await;
+        }
+        added
+    }
+
+    /// The playback on `device`, opened if it isn't yet, or the call's own.
+    /// Each call counts one more track there, until [`Self::release`].
+    async fn output(&self, device: Option<&str>) -> Result<AudioPlayback> {
+        let Some(device) = device else {
+            return Ok(self.main.clone());
+        };
+        let mut opened = self.opened.lock().await;
+        if let Some(output) = opened.get_mut(device) {
+            output.tracks += 1;
+            return Ok(output.playback.clone());
+        }
+        let playback = AudioPlayback::build(
+            &cpal::default_host(),
+            Some(device),
+            unprocessed(self.low_power)?,
+            self.xruns.clone(),
+            self.pacing,
+            self.busy.clone(),
+        )
+        .await
+        .with_context(|| format!("failed to open output `{device}`"))?;
+        let output = OpenedOutput {
+            playback: playback.clone(),
+            tracks: 1,
+        };
+        opened.insert(device.to_string(), output);
+        Ok(playback)
+    }
+
+    /// One track less on `device`, which is closed once none is left.
+    async fn release(&self, device: Option<&str>) {
+        let Some(device) = device else {
+            return;
+        };
+        let mut opened = self.opened.lock().await;
+        let Some(output) = opened.get_mut(device) else {
+            return;
+        };
+        output.tracks -= 1;
+        if output.tracks == 0 {
+            // its playback loop stops with the last handle.
+            opened.remove(device);
+            info!(device, "closed output");
+        }
+    }
+}
+
+/// A device opened for routes.
+#[derive(Debug)]
+struct OpenedOutput {
+    playback: AudioPlayback,
+    /// How many tracks play there.
+    tracks: usize,
+}
+
+/// A routed track and where it plays.
+struct Route {
+    track: String,
+    device: Option<String>,
+    /// Set to take the track out of its current output.
+    moved: Arc<AtomicBool>,
+    /// Where the source comes back once it has left.
+    handback: oneshot::Receiver<Box<dyn AudioSource>>,
+    ended: CancellationToken,
+    mix: SessionMix,
+    routes: watch::Receiver<BTreeMap<String, String>>,
+}
+
+/// A track in one output's mix, until it ends or moves elsewhere. The source
+/// itself goes along rather than being shared, so the audio thread never
+/// waits on a lock.
+struct RoutedSource {
+    source: Option<Box<dyn AudioSource>>,
+    moved: Arc<AtomicBool>,
+    ended: CancellationToken,
+    handback: Option<oneshot::Sender<Box<dyn AudioSource>>>,
+}
+
+impl AudioSource for RoutedSource {
+    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+        let Some(source) = self.source.as_mut() else {
+            return Ok(ControlFlow::Break(()));
+        };
+        if self.moved.load(Ordering::Relaxed) {
+            return Ok(ControlFlow::Break(()));
+        }
+        let result = source.tick(buf);
+        if !matches!(result, Ok(ControlFlow::Continue(_))) {
+            self.ended.cancel();
+        }
+        result
+    }
+}
+
+impl Drop for RoutedSource {
+    fn drop(&mut self) {
+        // out of the mix; unless it ended, it goes on elsewhere.
+        if let (Some(source), Some(handback)) = (self.source.take(), self.handback.take()) {
+            if !self.ended.is_cancelled() {
+                let _ = handback.send(source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Silence;
+
+    impl AudioSource for Silence {
+        fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+            buf.fill(0.);
+            Ok(ControlFlow::Continue(buf.len()))
+        }
+    }
+
+    #[test]
+    fn moved_sources_leave_their_output() {
+        let ended = CancellationToken::new();
+        let (sender, mut handback) = oneshot::channel();
+        let mut routed = RoutedSource {
+            source: Some(Box::new(Silence)),
+            moved: Arc::new(AtomicBool::new(false)),
+            ended: ended.clone(),
+            handback: Some(sender),
+        };
+        let mut buf = [0.; 4];
+        assert_eq!(routed.tick(&mut buf).unwrap(), ControlFlow::Continue(4));
+        routed.moved.store(true, Ordering::Relaxed);
+        assert_eq!(routed.tick(&mut buf).unwrap(), ControlFlow::Break(()));
+        // leaving an output is no end of the track, which comes back to go on.
+        assert!(!ended.is_cancelled());
+        drop(routed);
+        assert!(handback.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,268 @@
+//! Pictures of what we recorded and played, for "it sounds distorted" reports.
+//!
+//! With `--scope <seconds>`, the last few seconds of processed capture and
+//! of the playback mix are kept in memory. On request they are drawn into a
+//! PNG: for each, a waveform with clipped samples in red, and a spectrogram
+//! from 0 Hz at the bottom to 24 kHz at the top. Clipping, dropouts, buzzing
+//! and missing bands are usually plain to see.
+
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use super::{png::Image, AudioSink, ENGINE_FORMAT, SAMPLE_RATE};
+
+/// Samples per pixel column, 10 ms.
+const HOP: usize = SAMPLE_RATE.0 as usize / 100;
+const FFT_SIZE: usize = 1024;
+const WAVEFORM_HEIGHT: usize = 96;
+/// Two FFT bins per row.
+const SPECTROGRAM_HEIGHT: usize = FFT_SIZE / 4;
+const GAP_HEIGHT: usize = 4;
+/// Spectrogram levels drawn from black to white.
+const FLOOR_DBFS: f32 = -100.;
+const CEILING_DBFS: f32 = -10.;
+const CLIP_LEVEL: f32 = 0.999;
+
+/// The last stretch of an audio stream, downmixed to mono.
+#[derive(Debug, Clone)]
+pub struct AudioHistory {
+    samples: Arc<Mutex<VecDeque<f32>>>,
+    capacity: usize,
+}
+
+impl AudioHistory {
+    pub fn new(duration: Duration) -> Self {
+        let capacity = ENGINE_FORMAT.block_count(duration);
+        Self {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// The history, padded with leading silence to its full length.
+    fn snapshot(&self) -> Vec<f32> {
+        let samples = self.samples.lock().unwrap();
+        let mut out = vec![0.; self.capacity - samples.len()];
+        out.extend(samples.iter());
+        out
+    }
+}
+
+impl AudioSink for AudioHistory {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        let channels = ENGINE_FORMAT.channel_count as usize;
+        let mut samples = self.samples.lock().unwrap();
+        for frame in buf.chunks_exact(channels) {
+            if samples.len() == self.capacity {
+                samples.pop_front();
+            }
+            samples.push_back(frame.iter().sum::<f32>() / channels as f32);
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Capture and playback histories of one set of devices.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub capture: AudioHistory,
+    pub playback: AudioHistory,
+}
+
+impl Scope {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            capture: AudioHistory::new(duration),
+            playback: AudioHistory::new(duration),
+        }
+    }
+
+    /// Draw both histories, capture above playback, into a PNG at `path`,
+    /// or at a timestamped file in the working directory.
+    pub async fn dump(&self, path: Option<PathBuf>) -> Result<PathBuf> {
+        let path = path.unwrap_or_else(|| {
+            let now = chrono::Local::now().format("%Y%m%d-%H%M%S");
+            PathBuf::from(format!("neet-scope-{now}.png"))
+        });
+        let capture = self.capture.snapshot();
+        let playback = self.playback.snapshot();
+        let png = tokio::task::spawn_blocking(move || render(&capture, &playback).encode()).await?;
+        std::fs::write(&path, png)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        info!(path = %path.display(), "wrote audio scope");
+        Ok(path)
+    }
+}
+
+fn render(capture: &[f32], playback: &[f32]) -> Image {
+    let columns = capture.len().max(playback.len()) / HOP;
+    let stream_height = WAVEFORM_HEIGHT + SPECTROGRAM_HEIGHT;
+    let mut image = Image::new(columns.max(1), stream_height * 2 + GAP_HEIGHT);
+    for x in 0..image.width {
+        for y in stream_height..stream_height + GAP_HEIGHT {
+            image.set(x, y, [128, 128, 128]);
+        }
+    }
+    draw_stream(&mut image, 0, capture);
+    draw_stream(&mut image, stream_height + GAP_HEIGHT, playback);
+    image
+}
+
+/// Waveform and spectrogram of `samples`, from row `top` down.
+fn draw_stream(image: &mut Image, top: usize, samples: &[f32]) {
+    let mut fft = Fft::new();
+    let second = SAMPLE_RATE.0 as usize / HOP;
+    for x in 0..samples.len() / HOP {
+        let column = &samples[x * HOP..(x + 1) * HOP];
+        let (min, max) = column
+            .iter()
+            .fold((0f32, 0f32), |(min, max), s| (min.min(*s), max.max(*s)));
+        let clipped = min <= -CLIP_LEVEL || max >= CLIP_LEVEL;
+        let color = if clipped {
+            [255, 40, 40]
+        } else {
+            [80, 220, 120]
+        };
+        let background = if x % second == 0 {
+            [40, 40, 40]
+        } else {
+            [0, 0, 0]
+        };
+        let row = |s: f32| {
+            let y = (1. - s.clamp(-1., 1.)) / 2. * (WAVEFORM_HEIGHT - 1) as f32;
+            y.round() as usize
+        };
+        for y in 0..WAVEFORM_HEIGHT {
+            let on = (row(max)..=row(min)).contains(&y);
+            image.set(x, top + y, if on { color } else { background });
+        }
+
+        let levels = fft.levels(&samples[x * HOP..]);
+        for (row, bins) in levels.chunks_exact(2).enumerate() {
+            let level = bins[0].max(bins[1]);
+            let y = top + WAVEFORM_HEIGHT + SPECTROGRAM_HEIGHT - 1 - row;
+            image.set(x, y, heat(level));
+        }
+    }
+}
+
+/// Black through blue, red and yellow to white as `dbfs` rises.
+fn heat(dbfs: f32) -> [u8; 3] {
+    let t = ((dbfs - FLOOR_DBFS) / (CEILING_DBFS - FLOOR_DBFS)).clamp(0., 1.);
+    let ramp = |from: f32, to: f32| (((t - from) / (to - from)).clamp(0., 1.) * 255.) as u8;
+    let blue = if t < 0.5 {
+        ramp(0., 0.25)
+    } else {
+        ramp(1., 0.5)
+    };
+    [ramp(0.25, 0.5), ramp(0.5, 0.75), blue.max(ramp(0.75, 1.))]
+}
+
+/// Windowed radix-2 FFT of [`FFT_SIZE`] samples.
+struct Fft {
+    window: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Fft {
+    fn new() -> Self {
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2. * PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
+        Self {
+            window,
+            re: vec![0.; FFT_SIZE],
+            im: vec![0.; FFT_SIZE],
+        }
+    }
+
+    /// Level of each of the first `FFT_SIZE / 2` bins of the samples at the
+    /// start of `samples`, zero-padded, in dBFS of a full-scale sine.
+    fn levels(&mut self, samples: &[f32]) -> Vec<f32> {
+        self.re.fill(0.);
+        self.im.fill(0.);
+        for ((re, s), w) in self.re.iter_mut().zip(samples).zip(&self.window) {
+            *re = s * w;
+        }
+        self.transform();
+        // the Hann window halves the amplitude, and a real sine splits over
+        // two mirrored bins.
+        let scale = 4. / FFT_SIZE as f32;
+        (0..FFT_SIZE / 2)
+            .map(|i| {
+                let magnitude = self.re[i].hypot(self.im[i]) * scale;
+                (20. * magnitude.max(1e-9).log10()).max(FLOOR_DBFS)
+            })
+            .collect()
+    }
+
+    fn transform(&mut self) {
+        let n = FFT_SIZE;
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if i < j {
+                self.re.swap(i, j);
+                self.im.swap(i, j);
+            }
+        }
+        let mut len = 2;
+        while len <= n {
+            let angle = -2. * PI / len as f32;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (sin, cos) = (angle * k as f32).sin_cos();
+                    let (a, b) = (start + k, start + k + len / 2);
+                    let re = self.re[b] * cos - self.im[b] * sin;
+                    let im = self.re[b] * sin + self.im[b] * cos;
+                    self.re[b] = self.re[a] - re;
+                    self.im[b] = self.im[a] - im;
+                    self.re[a] += re;
+                    self.im[a] += im;
+                }
+            }
+            len *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectrogram_shows_a_sine_in_its_bin() {
+        // 3 kHz falls right on bin 64 of a 1024-point FFT at 48 kHz.
+        let sine: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| 0.5 * (2. * PI * 3_000. * i as f32 / SAMPLE_RATE.0 as f32).sin())
+            .collect();
+        let levels = Fft::new().levels(&sine);
+        let (peak, level) = levels
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        assert_eq!(peak, 64);
+        assert!(
+            (level + 6.).abs() < 0.5,
+            "0.5 amplitude is -6 dBFS, got {level}"
+        );
+        assert!(levels[200] < -60.);
+
+        let image = render(&sine, &[]);
+        assert_eq!(
+            image.height,
+            2 * (WAVEFORM_HEIGHT + SPECTROGRAM_HEIGHT) + GAP_HEIGHT
+        );
+    }
+}
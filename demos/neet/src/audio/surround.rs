@@ -0,0 +1,256 @@
+//! Multi-channel capture for surround layouts.
+//!
+//! The audio engine is stereo: echo cancellation, meters, DTMF and ducking all
+//! work on two channels. A layout wider than stereo is captured by a stream of
+//! its own on the input device, resampled to 48 kHz and encoded as Opus
+//! multistream, without any of that processing. Mono is downmixed from the
+//! engine instead.
+
+use std::num::NonZeroUsize;
+
+use anyhow::{anyhow, Context, Result};
+use cpal::{
+    traits::{DeviceTrait, StreamTrait},
+    SampleFormat,
+};
+use dasp_sample::ToSample;
+use fixed_resample::{FixedResampler, ResampleQuality};
+use ringbuf::{
+    traits::{Consumer as _, Producer as _, Split},
+    HeapCons as Consumer, HeapProd as Producer,
+};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use super::{
+    device::{find_device, find_input_stream_config, Direction},
+    AudioFormat, DURATION_20MS, SAMPLE_RATE,
+};
+use crate::{
+    codec::{
+        multistream::{ChannelLayout, MultistreamEncoder},
+        BitrateTarget, Codec, Encoder,
+    },
+    error::NeetError,
+    media::{self, MediaFrame, MediaSender, MediaTrack, OverflowPolicy, PauseState, TrackKind},
+    stats::Counter,
+};
+
+/// Most channels a layout has.
+const MAX_CHANNELS: usize = 8;
+/// Encoded frames queued for publishing.
+const TRACK_FRAMES: usize = 16;
+
+/// What the capture thread needs besides the device.
+pub struct SurroundOptions {
+    pub device: Option<String>,
+    pub layout: ChannelLayout,
+    pub bitrate: BitrateTarget,
+    pub paused: PauseState,
+    pub overflow: OverflowPolicy,
+    pub dropped: Counter,
+}
+
+/// Capture `options.layout` from the input device and return the encoded
+/// track. Capture stops once the track is dropped.
+pub async fn surround_track(options: SurroundOptions) -> Result<MediaTrack> {
+    let layout = options.layout;
+    let (sender, receiver) = media::channel(TRACK_FRAMES, options.overflow, options.dropped);
+    let track = MediaTrack::new(
+        receiver,
+        Codec::OpusMultistream { layout },
+        TrackKind::Audio,
+    );
+    let format = AudioFormat::new(SAMPLE_RATE, layout.channels() as u16);
+    let buffer_size = format.sample_count(DURATION_20MS) * 16;
+    let (producer, consumer) = ringbuf::HeapRb::<f32>::new(buffer_size).split();
+    let encoder = MultistreamEncoder::new(layout)?;
+
+    let overruns = Counter::default();
+
+    let (init_tx, init_rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let stream = start_stream(
+            options.device.as_deref(),
+            layout,
+            producer,
+            overruns.clone(),
+        );
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = init_tx.send(Err(err));
+                return;
+            }
+        };
+        if init_tx.send(Ok(())).is_err() {
+            // nobody is waiting for the track any more.
+            return;
+        }
+        let encode = EncodeLoop {
+            consumer,
+            encoder,
+            sender,
+            bitrate: options.bitrate,
+            paused: options.paused,
+            overruns,
+        };
+        if let Err(err) = encode.run() {
+            warn!("surround capture stopped: {err:?}");
+        }
+        drop(stream);
+    });
+    init_rx.await??;
+    Ok(track)
+}
+
+fn start_stream(
+    device: Option<&str>,
+    layout: ChannelLayout,
+    producer: Producer<f32>,
+    overruns: Counter,
+) -> Result<cpal::Stream> {
+    let host = cpal::default_host();
+    let device = find_device(&host, Direction::Capture, device)?;
+    let name = device.name()?;
+    let wanted = AudioFormat::new(SAMPLE_RATE, layout.channels() as u16);
+    let stream_config = find_input_stream_config(&device, &wanted)?;
+    let format = stream_config.audio_format();
+    if (format.channel_count as usize) < layout.channels() {
+        return Err(anyhow!(NeetError::DeviceNotFound(format!(
+            "input device {name} has no {layout} ({}-channel) configuration",
+            layout.channels()
+        ))));
+    }
+    let state = StreamState {
+        format,
+        channels: layout.channels(),
+        producer,
+        overruns,
+        resampler: FixedResampler::new(
+            NonZeroUsize::new(layout.channels()).unwrap(),
+            format.sample_rate.0,
+            SAMPLE_RATE.0,
+            ResampleQuality::High,
+            true,
+        ),
+        input: Vec::new(),
+    };
+    let config = &stream_config.config;
+    let stream = match stream_config.sample_format {
+        SampleFormat::I16 => build_stream::<i16>(&device, config, state),
+        SampleFormat::I32 => build_stream::<i32>(&device, config, state),
+        SampleFormat::F32 => build_stream::<f32>(&device, config, state),
+        sample_format => {
+            error!("Unsupported sample format '{sample_format}'");
+            Err(cpal::BuildStreamError::StreamConfigNotSupported)
+        }
+    }
+    .with_context(|| format!("failed to build {layout} capture stream on {name}"))
+    .context(NeetError::AudioBackend(
+        "failed to start surround capture stream".to_string(),
+    ))?;
+    info!("starting {layout} capture stream on {name} with {format:?}");
+    stream.play()?;
+    Ok(stream)
+}
+
+struct StreamState {
+    format: AudioFormat,
+    /// Channels of the layout; any further device channels are ignored.
+    channels: usize,
+    producer: Producer<f32>,
+    /// Samples dropped because the encoder fell behind; reported by it, as
+    /// the stream callback must not log.
+    overruns: Counter,
+    resampler: FixedResampler<f32, MAX_CHANNELS>,
+    input: Vec<f32>,
+}
+
+fn build_stream<S: ToSample<f32> + cpal::SizedSample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut state: StreamState,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let device_channels = state.format.channel_count as usize;
+    device.build_input_stream::<S, _, _>(
+        config,
+        move |data: &[S], _: &_| {
+            let StreamState {
+                channels,
+                resampler,
+                producer,
+                overruns,
+                input,
+                ..
+            } = &mut state;
+            for frame in data.chunks_exact(device_channels) {
+                input.extend(frame[..*channels].iter().map(|s| s.to_sample::<f32>()));
+            }
+            resampler.process_interleaved(
+                input,
+                |samples| {
+                    let pushed = producer.push_slice(samples);
+                    overruns.add((samples.len() - pushed) as u64);
+                },
+                None,
+                false,
+            );
+            input.clear();
+        },
+        |err| error!("an error occurred on surround capture stream: {err}"),
+        None,
+    )
+}
+
+/// Encodes what the stream captured, a 20 ms frame at a time.
+struct EncodeLoop {
+    consumer: Consumer<f32>,
+    encoder: MultistreamEncoder,
+    sender: MediaSender,
+    bitrate: BitrateTarget,
+    paused: PauseState,
+    overruns: Counter,
+}
+
+impl EncodeLoop {
+    fn run(mut self) -> Result<()> {
+        let mut frame = vec![0.; self.encoder.frame_samples()];
+        let mut filled = 0;
+        let mut bitrate = 0;
+        let mut overruns = 0;
+        loop {
+            let dropped = self.overruns.get() - overruns;
+            if dropped > 0 {
+                warn!("surround capture xrun: dropped {dropped}");
+                overruns += dropped;
+            }
+            filled += self.consumer.pop_slice(&mut frame[filled..]);
+            if filled == frame.len() {
+                filled = 0;
+                let target = self.bitrate.get();
+                if target != 0 && target != bitrate {
+                    self.encoder.set_bitrate(target)?;
+                    bitrate = target;
+                }
+                if !self.paused.is_paused() {
+                    let payload = self.encoder.encode(&frame)?;
+                    let frame = MediaFrame {
+                        payload,
+                        sample_count: Some(frame.len() as u32),
+                        skipped_frames: None,
+                        skipped_samples: None,
+                        reset: false,
+                    };
+                    if self.sender.send(frame).is_err() {
+                        info!("stop surround capture: track receiver closed");
+                        return Ok(());
+                    }
+                }
+                // another frame may be waiting already.
+                continue;
+            }
+            spin_sleep::sleep(DURATION_20MS / 4);
+        }
+    }
+}
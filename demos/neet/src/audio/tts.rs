@@ -0,0 +1,66 @@
+//! Speech synthesis with espeak-ng.
+//!
+//! espeak-ng renders each utterance to a temporary WAV file (22.05 kHz mono),
+//! which is read back and resampled to
+//! [`ENGINE_FORMAT`](super::ENGINE_FORMAT).
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::wav::read_wav;
+
+const ESPEAK: &str = "espeak-ng";
+
+#[derive(Debug, Clone)]
+pub struct Speech {
+    voice: Option<String>,
+}
+
+impl Speech {
+    /// Check that espeak-ng is installed.
+    pub fn new(voice: Option<String>) -> Result<Self> {
+        let status = Command::new(ESPEAK)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .status()
+            .with_context(|| format!("failed to run {ESPEAK}; is it installed?"))?;
+        if !status.success() {
+            bail!("{ESPEAK} --version failed: {status}");
+        }
+        Ok(Self { voice })
+    }
+
+    /// Speak `text` into interleaved stereo samples in
+    /// [`ENGINE_FORMAT`](super::ENGINE_FORMAT).
+    pub fn synthesize(&self, text: &str) -> Result<Vec<f32>> {
+        let path = temp_wav_path();
+        let mut command = Command::new(ESPEAK);
+        command.arg("-w").arg(&path);
+        if let Some(voice) = &self.voice {
+            command.args(["-v", voice]);
+        }
+        let status = command
+            .arg("--")
+            .arg(text)
+            .status()
+            .with_context(|| format!("failed to run {ESPEAK}"))?;
+        let samples = if status.success() {
+            read_wav(&path).context("failed to read synthesized speech")
+        } else {
+            Err(anyhow!("{ESPEAK} failed: {status}"))
+        };
+        let _ = std::fs::remove_file(&path);
+        samples
+    }
+}
+
+fn temp_wav_path() -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("neet-tts-{}-{n}.wav", std::process::id()))
+}
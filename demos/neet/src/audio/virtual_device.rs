@@ -0,0 +1,303 @@
+//! A sound card in software, for CI containers and tests without audio
+//! hardware (feature `virtual-audio`).
+//!
+//! `--input-device virtual:<input>` and `--output-device virtual` replace the
+//! cpal streams with threads that produce and consume exactly 10 ms of audio
+//! every 10 ms, on a schedule that never drifts. Everything past the device
+//! callbacks runs unchanged, timing included. The input is one of:
+//!
+//! - `silence` (also plain `virtual`),
+//! - `sine:<hz>`, a tone at -6 dBFS,
+//! - `noise`, white noise at -20 dBFS from a fixed seed,
+//! - `loopback`, what the virtual output plays.
+
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use ringbuf::{
+    traits::{Consumer as _, Producer as _},
+    HeapCons as Consumer, HeapProd as Producer,
+};
+use tracing::{info, warn};
+
+use super::{power::BusyTimer, WebrtcAudioProcessor, DURATION_10MS, ENGINE_FORMAT, SAMPLE_RATE};
+use crate::stats::{Counter, XrunStats};
+
+/// Device name of the virtual devices, and prefix of the inputs.
+pub const VIRTUAL_DEVICE: &str = "virtual";
+/// Audio produced or consumed per period.
+const PERIOD: Duration = DURATION_10MS;
+const SINE_AMPLITUDE: f32 = 0.5;
+const NOISE_AMPLITUDE: f32 = 0.1;
+const NOISE_SEED: u32 = 0x9e37_79b9;
+/// Most of the virtual output kept for a `loopback` input.
+const LOOPBACK_CAPACITY: Duration = Duration::from_secs(1);
+
+/// What the virtual output played, for a `loopback` input to hear.
+static LOOPBACK: Mutex<VecDeque<f32>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtualInput {
+    Silence,
+    Sine(f32),
+    Noise,
+    Loopback,
+}
+
+impl VirtualInput {
+    /// The virtual input `device` names, or `None` for any other device.
+    pub fn from_device(device: &str) -> Result<Option<Self>> {
+        let input = match device.split_once(':') {
+            None if device == VIRTUAL_DEVICE => "silence",
+            Some((VIRTUAL_DEVICE, input)) => input,
+            _ => return Ok(None),
+        };
+        let input = match input.split_once(':') {
+            None if input == "silence" => VirtualInput::Silence,
+            None if input == "noise" => VirtualInput::Noise,
+            None if input == "loopback" => VirtualInput::Loopback,
+            Some(("sine", hz)) => {
+                let hz: f32 = hz.parse().context("invalid virtual sine frequency")?;
+                if !(hz > 0. && hz < SAMPLE_RATE.0 as f32 / 2.) {
+                    bail!("virtual sine frequency must be between 0 and 24000 Hz");
+                }
+                VirtualInput::Sine(hz)
+            }
+            _ => {
+                bail!("unknown virtual input `{input}`: try silence, sine:<hz>, noise or loopback")
+            }
+        };
+        Ok(Some(input))
+    }
+}
+
+/// Whether `device` names the virtual output.
+pub fn is_virtual_output(device: Option<&str>) -> bool {
+    device == Some(VIRTUAL_DEVICE)
+}
+
+/// Fills periods with a [`VirtualInput`], in [`ENGINE_FORMAT`].
+struct Generator {
+    input: VirtualInput,
+    /// Samples generated so far, per channel.
+    position: u64,
+    noise: u32,
+}
+
+impl Generator {
+    fn new(input: VirtualInput) -> Self {
+        Self {
+            input,
+            position: 0,
+            noise: NOISE_SEED,
+        }
+    }
+
+    fn fill(&mut self, buf: &mut [f32]) {
+        let channels = ENGINE_FORMAT.channel_count as usize;
+        if self.input == VirtualInput::Loopback {
+            let mut played = LOOPBACK.lock().unwrap();
+            let count = buf.len().min(played.len());
+            for (out, sample) in buf.iter_mut().zip(played.drain(..count)) {
+                *out = sample;
+            }
+            buf[count..].fill(0.);
+            return;
+        }
+        for frame in buf.chunks_exact_mut(channels) {
+            let sample = match self.input {
+                VirtualInput::Sine(hz) => {
+                    // the phase from the sample count, so it never drifts.
+                    let cycles = self.position as f64 * hz as f64 / SAMPLE_RATE.0 as f64;
+                    SINE_AMPLITUDE * (2. * PI * cycles.fract() as f32).sin()
+                }
+                VirtualInput::Noise => {
+                    // xorshift32
+                    self.noise ^= self.noise << 13;
+                    self.noise ^= self.noise >> 17;
+                    self.noise ^= self.noise << 5;
+                    NOISE_AMPLITUDE * (self.noise as f32 / u32::MAX as f32 * 2. - 1.)
+                }
+                VirtualInput::Silence | VirtualInput::Loopback => 0.,
+            };
+            frame.fill(sample);
+            self.position += 1;
+        }
+    }
+}
+
+/// A thread that runs once every [`PERIOD`], until dropped.
+pub struct VirtualStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl VirtualStream {
+    fn spawn(name: &str, mut period: impl FnMut() + Send + 'static) -> Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new().name(name.to_string()).spawn({
+            let stop = stop.clone();
+            move || {
+                // periods are due at fixed offsets from the start, so late
+                // wakeups are made up for instead of adding up.
+                let start = Instant::now();
+                let mut periods = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    period();
+                    periods += 1;
+                    let due = start + PERIOD * periods;
+                    spin_sleep::sleep(due.saturating_duration_since(Instant::now()));
+                }
+            }
+        })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct VirtualCapture {
+    generator: Generator,
+    producer: Producer<f32>,
+    #[allow(unused)]
+    processor: WebrtcAudioProcessor,
+    buf: Vec<f32>,
+    busy: Counter,
+}
+
+impl VirtualCapture {
+    fn period(&mut self) {
+        let _busy = BusyTimer::start(&self.busy);
+        self.generator.fill(&mut self.buf);
+        #[cfg(feature = "audio-processing")]
+        if let Err(err) = self.processor.process_capture_frame(&mut self.buf) {
+            warn!("failed to process virtual capture: {err}");
+        }
+        let n = self.producer.push_slice(&self.buf);
+        if n < self.buf.len() {
+            warn!(
+                "record xrun: failed to push out {} of {}",
+                self.buf.len() - n,
+                self.buf.len()
+            );
+        }
+    }
+}
+
+pub fn start_virtual_capture(
+    input: VirtualInput,
+    producer: Producer<f32>,
+    processor: WebrtcAudioProcessor,
+    busy: Counter,
+) -> Result<VirtualStream> {
+    #[cfg(feature = "audio-processing")]
+    processor.init_capture(ENGINE_FORMAT.channel_count as usize)?;
+    let mut capture = VirtualCapture {
+        generator: Generator::new(input),
+        producer,
+        processor,
+        buf: vec![0.; ENGINE_FORMAT.sample_count(PERIOD)],
+        busy,
+    };
+    info!("starting virtual capture stream with {input:?}");
+    VirtualStream::spawn("virtual-capture", move || capture.period())
+}
+
+struct VirtualPlayback {
+    consumer: Consumer<f32>,
+    #[allow(unused)]
+    processor: WebrtcAudioProcessor,
+    buf: Vec<f32>,
+    xruns: XrunStats,
+    busy: Counter,
+}
+
+impl VirtualPlayback {
+    fn period(&mut self) {
+        let _busy = BusyTimer::start(&self.busy);
+        let count = self.consumer.pop_slice(&mut self.buf);
+        if count < self.buf.len() {
+            self.xruns.underruns.add(1);
+            self.buf[count..].fill(0.);
+        }
+        #[cfg(feature = "audio-processing")]
+        if let Err(err) = self.processor.process_render_frame(&mut self.buf) {
+            warn!("failed to process virtual playback: {err}");
+        }
+        let mut played = LOOPBACK.lock().unwrap();
+        played.extend(&self.buf);
+        let capacity = ENGINE_FORMAT.sample_count(LOOPBACK_CAPACITY);
+        if played.len() > capacity {
+            let excess = played.len() - capacity;
+            played.drain(..excess);
+        }
+    }
+}
+
+pub fn start_virtual_playback(
+    consumer: Consumer<f32>,
+    processor: WebrtcAudioProcessor,
+    xruns: XrunStats,
+    busy: Counter,
+) -> Result<VirtualStream> {
+    #[cfg(feature = "audio-processing")]
+    processor.init_playback(ENGINE_FORMAT.channel_count as usize)?;
+    let mut playback = VirtualPlayback {
+        consumer,
+        processor,
+        buf: vec![0.; ENGINE_FORMAT.sample_count(PERIOD)],
+        xruns,
+        busy,
+    };
+    info!("starting virtual playback stream");
+    VirtualStream::spawn("virtual-playback", move || playback.period())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{AudioConfig, AudioContext};
+
+    #[tokio::test]
+    async fn loopback_analysis_runs_on_virtual_devices() {
+        assert_eq!(
+            VirtualInput::from_device("virtual:sine:440").unwrap(),
+            Some(VirtualInput::Sine(440.))
+        );
+        assert_eq!(VirtualInput::from_device("virtualbox").unwrap(), None);
+        assert!(VirtualInput::from_device("virtual:hum").is_err());
+
+        let audio = AudioContext::new(AudioConfig {
+            input_device: Some("virtual:loopback".to_string()),
+            output_device: Some(VIRTUAL_DEVICE.to_string()),
+            processing_enabled: false,
+            ..AudioConfig::default()
+        })
+        .await
+        .unwrap();
+        let report = audio.analyze_loopback().await.unwrap();
+        // a tick to mix the signal, one to capture it and a little buffering.
+        assert!(report.latency_ms < 100., "{report:?}");
+        assert_eq!(report.dropouts, 0, "{report:?}");
+        assert!(report.snr_db > 60., "{report:?}");
+    }
+}
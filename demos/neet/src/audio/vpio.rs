@@ -0,0 +1,151 @@
+//! Apple's voice processing I/O unit as the audio device
+//! (`--voice-processing`, macOS).
+//!
+//! Instead of cpal streams and the WebRTC processor, one CoreAudio
+//! `VoiceProcessingIO` unit records the microphone and plays the call, and
+//! cancels the echo of the one in the other, with Apple's own gain control.
+//! It copes with bluetooth headsets switching profiles better than the
+//! WebRTC processor does, and needs no native library of ours. The unit does
+//! the resampling; both directions run at 48 kHz mono, so capture is spread
+//! to both channels and playback mixed down.
+//!
+//! Capture starts first and leaves its buffer in a [`VoiceUnit`], which the
+//! audio context hands to its playback to start the unit for both.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use coreaudio::{
+    audio_unit::{
+        audio_format::LinearPcmFlags,
+        render_callback::{self, data},
+        AudioUnit, Element, IOType, SampleFormat, Scope, StreamFormat,
+    },
+    sys::{kAudioOutputUnitProperty_EnableIO, kAudioUnitProperty_StreamFormat},
+};
+use ringbuf::{
+    traits::{Consumer as _, Producer as _},
+    HeapCons as Consumer, HeapProd as Producer,
+};
+use tracing::{info, warn};
+
+use super::{power::BusyTimer, ENGINE_FORMAT};
+use crate::stats::{Counter, XrunStats};
+
+/// Device name of the voice processing unit, for input and output alike.
+pub const VOICE_PROCESSING_DEVICE: &str = "voice-processing";
+
+/// Most frames the callbacks convert at once; the unit asks for far fewer.
+const MAX_FRAMES: usize = 4096;
+
+type Args = render_callback::Args<data::Interleaved<f32>>;
+
+/// One audio context's unit, to be: the capture buffer, until playback
+/// starts the unit with it.
+#[derive(Clone, Default)]
+pub struct VoiceUnit(Arc<Mutex<Option<(Producer<f32>, Counter)>>>);
+
+/// The running unit, and the captured audio it had no room for.
+pub struct RunningVoiceUnit {
+    _unit: AudioUnit,
+    dropped: Counter,
+}
+
+impl Drop for RunningVoiceUnit {
+    fn drop(&mut self) {
+        let dropped = self.dropped.get();
+        if dropped > 0 {
+            warn!(dropped, "voice processing capture overflowed");
+        }
+    }
+}
+
+impl VoiceUnit {
+    /// Hand the capture buffer to the unit playback will start.
+    pub fn capture(&self, producer: Producer<f32>, busy: Counter) {
+        *self.0.lock().unwrap() = Some((producer, busy));
+    }
+
+    /// Start the unit, playing from `consumer` and capturing into the
+    /// buffer capture left. Runs until dropped.
+    pub fn start(
+        &self,
+        mut consumer: Consumer<f32>,
+        xruns: XrunStats,
+        busy: Counter,
+    ) -> Result<RunningVoiceUnit> {
+        let (mut producer, capture_busy) = self
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .context("voice processing needs to be the input device too")?;
+        let dropped = Counter::default();
+        let overflowed = dropped.clone();
+        let mut unit = AudioUnit::new(IOType::VoiceProcessingIO)?;
+        let enable = 1u32;
+        unit.set_property(
+            kAudioOutputUnitProperty_EnableIO,
+            Scope::Input,
+            Element::Input,
+            Some(&enable),
+        )?;
+        let format = StreamFormat {
+            sample_rate: ENGINE_FORMAT.sample_rate.0 as f64,
+            sample_format: SampleFormat::F32,
+            flags: LinearPcmFlags::IS_FLOAT | LinearPcmFlags::IS_PACKED,
+            channels: 1,
+        }
+        .to_asbd();
+        // what the microphone side gives us, and what we give the speaker side.
+        unit.set_property(
+            kAudioUnitProperty_StreamFormat,
+            Scope::Output,
+            Element::Input,
+            Some(&format),
+        )?;
+        unit.set_property(
+            kAudioUnitProperty_StreamFormat,
+            Scope::Input,
+            Element::Output,
+            Some(&format),
+        )?;
+
+        // allocated here, as the callbacks must not.
+        let mut stereo = vec![0.; MAX_FRAMES * 2];
+        unit.set_input_callback(move |args: Args| {
+            let _busy = BusyTimer::start(&capture_busy);
+            for frames in args.data.buffer.chunks(MAX_FRAMES) {
+                let stereo = &mut stereo[..frames.len() * 2];
+                for (frame, &sample) in stereo.chunks_exact_mut(2).zip(frames) {
+                    frame.fill(sample);
+                }
+                let pushed = producer.push_slice(stereo);
+                overflowed.add((stereo.len() - pushed) as u64);
+            }
+            Ok(())
+        })?;
+        let mut stereo = vec![0.; MAX_FRAMES * 2];
+        unit.set_render_callback(move |args: Args| {
+            let _busy = BusyTimer::start(&busy);
+            for out in args.data.buffer.chunks_mut(MAX_FRAMES) {
+                let stereo = &mut stereo[..out.len() * 2];
+                let popped = consumer.pop_slice(stereo);
+                if popped < stereo.len() {
+                    xruns.underruns.add(1);
+                    stereo[popped..].fill(0.);
+                }
+                for (sample, frame) in out.iter_mut().zip(stereo.chunks_exact(2)) {
+                    *sample = (frame[0] + frame[1]) / 2.;
+                }
+            }
+            Ok(())
+        })?;
+        unit.start()?;
+        info!("started the voice processing unit");
+        Ok(RunningVoiceUnit {
+            _unit: unit,
+            dropped,
+        })
+    }
+}
@@ -0,0 +1,163 @@
+//! Notices a microphone that stops working mid-call, the usual cause of "can
+//! you hear me?".
+//!
+//! With `--mic-watchdog <seconds>`, a capture sink watches every tick. When
+//! the input delivers only near-silence, or no audio at all, for that long,
+//! a warning is logged and the remote is told over the control track. With
+//! `--mic-failover`, the other input devices are then tried in turn, each
+//! for the same time, until one picks up a signal.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use super::{capture::AudioCapture, level::SILENT_DBFS, AudioSink};
+
+/// What the watchdog makes of the microphone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MicStatus {
+    #[default]
+    Ok,
+    /// Audio arrives, but only near-silence: muted, or the wrong device.
+    Silent,
+    /// No audio arrives at all: unplugged, or the driver stopped.
+    Stalled,
+}
+
+impl fmt::Display for MicStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MicStatus::Ok => "working",
+            MicStatus::Silent => "near-silent",
+            MicStatus::Stalled => "delivering no audio",
+        })
+    }
+}
+
+/// Capture sink that updates a [`MicStatus`] every tick.
+pub struct CaptureWatchdog {
+    after: Duration,
+    last_audio: Instant,
+    last_signal: Instant,
+    status: watch::Sender<MicStatus>,
+}
+
+impl CaptureWatchdog {
+    pub fn new(after: Duration, status: watch::Sender<MicStatus>) -> Self {
+        let now = Instant::now();
+        Self {
+            after,
+            last_audio: now,
+            last_signal: now,
+            status,
+        }
+    }
+
+    fn observe(&mut self, buf: &[f32], now: Instant) -> MicStatus {
+        if !buf.is_empty() {
+            self.last_audio = now;
+            let power = buf.iter().map(|s| s * s).sum::<f32>() / buf.len() as f32;
+            if 10. * power.log10() >= SILENT_DBFS {
+                self.last_signal = now;
+            }
+        }
+        if now - self.last_audio >= self.after {
+            MicStatus::Stalled
+        } else if now - self.last_signal >= self.after {
+            MicStatus::Silent
+        } else {
+            MicStatus::Ok
+        }
+    }
+}
+
+impl AudioSink for CaptureWatchdog {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        let status = self.observe(buf, Instant::now());
+        self.status
+            .send_if_modified(|current| std::mem::replace(current, status) != status);
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Log what the watchdog reports, and with `failover` move on to other input
+/// devices while the microphone does not work.
+pub async fn supervise(
+    mut status: watch::Receiver<MicStatus>,
+    capture: AudioCapture,
+    after: Duration,
+    failover: bool,
+) {
+    while status.changed().await.is_ok() {
+        let current = *status.borrow_and_update();
+        if current == MicStatus::Ok {
+            info!("microphone signal is back");
+            continue;
+        }
+        warn!("microphone has been {current} for {after:?}; check that it is plugged in and not muted");
+        if failover {
+            fail_over(&mut status, &capture, after).await;
+        }
+    }
+}
+
+/// Try each other input device once, giving each `after` to pick up a
+/// signal.
+async fn fail_over(
+    status: &mut watch::Receiver<MicStatus>,
+    capture: &AudioCapture,
+    after: Duration,
+) {
+    let mut tried = HashSet::new();
+    loop {
+        let device = match capture.next_input().await {
+            Ok(device) => device,
+            Err(err) => {
+                warn!("failed to switch input device: {err:#}");
+                return;
+            }
+        };
+        if !tried.insert(device.clone()) {
+            warn!(%device, "no input device picks up a signal; staying on this one");
+            return;
+        }
+        warn!(%device, "switched to the next input device");
+        let working = status.wait_for(|status| *status == MicStatus::Ok);
+        if let Ok(Ok(_)) = tokio::time::timeout(after, working).await {
+            info!(%device, "microphone signal is back on the new input device");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_silence_and_stalls_after_the_timeout() {
+        let (status, _) = watch::channel(MicStatus::Ok);
+        let mut watchdog = CaptureWatchdog::new(Duration::from_secs(2), status);
+        let start = watchdog.last_audio;
+        let at = |ms| start + Duration::from_millis(ms);
+        let speech = [0.1; 960];
+        let hiss = [1e-5; 960];
+
+        assert_eq!(watchdog.observe(&speech, at(0)), MicStatus::Ok);
+        assert_eq!(watchdog.observe(&hiss, at(1_900)), MicStatus::Ok);
+        assert_eq!(watchdog.observe(&hiss, at(2_000)), MicStatus::Silent);
+        assert_eq!(watchdog.observe(&speech, at(2_100)), MicStatus::Ok);
+        assert_eq!(watchdog.observe(&hiss, at(3_000)), MicStatus::Ok);
+        assert_eq!(watchdog.observe(&[], at(4_100)), MicStatus::Silent);
+        assert_eq!(watchdog.observe(&[], at(5_000)), MicStatus::Stalled);
+    }
+}
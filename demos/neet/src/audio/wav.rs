@@ -0,0 +1,88 @@
+//! WAV files in [`ENGINE_FORMAT`]: synthesized speech and greetings.
+
+use std::{num::NonZeroUsize, path::Path};
+
+use anyhow::{bail, Context, Result};
+use fixed_resample::{FixedResampler, LastPacketInfo, ResampleQuality};
+use hound::{SampleFormat, WavReader};
+
+use super::ENGINE_FORMAT;
+
+/// Read a mono or stereo WAV file, at any sample rate, as interleaved stereo
+/// samples in [`ENGINE_FORMAT`].
+pub fn read_wav(path: &Path) -> Result<Vec<f32>> {
+    let reader =
+        WavReader::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let full_scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / full_scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let stereo = match spec.channels {
+        1 => samples.iter().flat_map(|s| [*s, *s]).collect(),
+        2 => samples,
+        channels => bail!(
+            "{}: {channels} channels; expected mono or stereo",
+            path.display()
+        ),
+    };
+    Ok(resample(stereo, spec.sample_rate))
+}
+
+/// Interleaved stereo `samples` at `sample_rate`, at the engine's rate.
+fn resample(samples: Vec<f32>, sample_rate: u32) -> Vec<f32> {
+    let target = ENGINE_FORMAT.sample_rate.0;
+    if sample_rate == target {
+        return samples;
+    }
+    let mut resampler = FixedResampler::<f32, 2>::new(
+        NonZeroUsize::new(2).unwrap(),
+        sample_rate,
+        target,
+        ResampleQuality::High,
+        true,
+    );
+    let frames = (samples.len() / 2) as u64 * target as u64 / sample_rate as u64;
+    let mut out = Vec::with_capacity(frames as usize * 2);
+    resampler.process_interleaved(
+        &samples,
+        |samples| out.extend_from_slice(samples),
+        Some(LastPacketInfo {
+            desired_output_frames: Some(frames),
+        }),
+        true,
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use hound::{WavSpec, WavWriter};
+
+    use super::*;
+
+    #[test]
+    fn mono_files_play_on_both_channels() {
+        let path = std::env::temp_dir().join(format!("neet-wav-{}.wav", std::process::id()));
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: ENGINE_FORMAT.sample_rate.0,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for sample in [0, i16::MIN, 16384] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        let samples = read_wav(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.unwrap(), [0., 0., -1., -1., 0.5, 0.5]);
+    }
+}
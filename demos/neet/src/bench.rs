@@ -0,0 +1,265 @@
+//! `neet bench`: measures the codec and MoQ frame path without touching audio
+//! devices or the network, and reports the results as JSON.
+//!
+//! Allocations are only counted in a build with the `bench` feature, which
+//! swaps in [`CountingAllocator`]; otherwise they are reported as `"n/a"`, so
+//! that a shipping binary does not pay for counting on its audio threads.
+
+#[cfg(feature = "bench")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use std::{
+    f32::consts::PI,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use moq_lite as moq;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    audio::{AudioMode, ENGINE_FORMAT},
+    codec::{
+        opus::{MediaTrackOpusDecoder, OpusChannels, OpusEncoder},
+        Codec,
+    },
+    media::{self, MediaTrack, OverflowPolicy, TrackKind},
+    moq::{FrameArena, FrameHeader},
+    stats::Counter,
+};
+
+const FRAME_DURATION: Duration = Duration::from_millis(20);
+
+#[cfg(feature = "bench")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Global allocator wrapper that counts allocations so the bench can report
+/// allocations per frame. The overhead is a single relaxed atomic increment.
+#[cfg(feature = "bench")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "bench")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Allocations so far, if this build counts them.
+#[cfg(feature = "bench")]
+fn allocations() -> Option<u64> {
+    Some(ALLOCATIONS.load(Ordering::Relaxed))
+}
+
+#[cfg(not(feature = "bench"))]
+fn allocations() -> Option<u64> {
+    None
+}
+
+/// Allocations since `start`, a count [`allocations`] returned.
+fn allocations_since(start: Option<u64>) -> Option<u64> {
+    Some(allocations()? - start?)
+}
+
+/// Writes a rate that was not measured as `"n/a"`.
+fn or_na<S: Serializer>(rate: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match rate {
+        Some(rate) => serializer.serialize_f64(*rate),
+        None => serializer.serialize_str("n/a"),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Amount of synthetic audio to push through each stage.
+    pub duration: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub audio_seconds: f64,
+    pub frames: usize,
+    pub encode: ThroughputReport,
+    pub decode: ThroughputReport,
+    pub pipeline: LatencyReport,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThroughputReport {
+    pub elapsed_ms: f64,
+    /// Seconds of audio processed per wall-clock second.
+    pub realtime_factor: f64,
+    pub frames_per_sec: f64,
+    #[serde(serialize_with = "or_na")]
+    pub allocations_per_frame: Option<f64>,
+    /// Allocations per second of real-time audio.
+    #[serde(serialize_with = "or_na")]
+    pub allocations_per_sec: Option<f64>,
+}
+
+impl ThroughputReport {
+    fn new(elapsed: Duration, frames: usize, audio: Duration, allocations: Option<u64>) -> Self {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        Self {
+            elapsed_ms: secs * 1000.,
+            realtime_factor: audio.as_secs_f64() / secs,
+            frames_per_sec: frames as f64 / secs,
+            allocations_per_frame: allocations.map(|n| n as f64 / frames.max(1) as f64),
+            allocations_per_sec: allocations
+                .map(|n| n as f64 / audio.as_secs_f64().max(f64::EPSILON)),
+        }
+    }
+}
+
+/// Per-frame latency of encode → MoQ track → decode, in microseconds.
+#[derive(Debug, Serialize)]
+pub struct LatencyReport {
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    #[serde(serialize_with = "or_na")]
+    pub allocations_per_frame: Option<f64>,
+    #[serde(serialize_with = "or_na")]
+    pub allocations_per_sec: Option<f64>,
+}
+
+impl LatencyReport {
+    fn new(mut samples: Vec<Duration>, allocations: Option<u64>) -> Self {
+        samples.sort();
+        let percentile = |p: f64| {
+            let idx = ((samples.len() as f64 * p).ceil() as usize).saturating_sub(1);
+            samples.get(idx).map(|d| d.as_micros() as u64).unwrap_or(0)
+        };
+        Self {
+            p50_us: percentile(0.50),
+            p95_us: percentile(0.95),
+            p99_us: percentile(0.99),
+            max_us: samples.last().map(|d| d.as_micros() as u64).unwrap_or(0),
+            allocations_per_frame: allocations.map(|n| n as f64 / samples.len().max(1) as f64),
+            allocations_per_sec: allocations.map(|n| {
+                n as f64
+                    / (FRAME_DURATION * samples.len() as u32)
+                        .as_secs_f64()
+                        .max(f64::EPSILON)
+            }),
+        }
+    }
+}
+
+pub async fn run_bench(options: BenchOptions) -> Result<BenchReport> {
+    let input = test_tone(options.duration);
+
+    // encode
+    let mut encoder = OpusEncoder::new(OpusChannels::Stereo, AudioMode::Voice)?;
+    let allocs = allocations();
+    let start = Instant::now();
+    let packets = encoder
+        .push_slice(&input)
+        .map(|packet| packet.map(|(payload, _)| payload))
+        .collect::<Result<Vec<_>>>()?;
+    let encode_elapsed = start.elapsed();
+    let encode_allocs = allocations_since(allocs);
+    let frames = packets.len();
+    let audio = FRAME_DURATION * frames as u32;
+    let encode = ThroughputReport::new(encode_elapsed, frames, audio, encode_allocs);
+
+    // decode
+    let mut decoder = bench_decoder()?;
+    let allocs = allocations();
+    let start = Instant::now();
+    for payload in &packets {
+        let count = decoder.decode(payload)?;
+        decoder.advance(count);
+    }
+    let decode = ThroughputReport::new(start.elapsed(), frames, audio, allocations_since(allocs));
+
+    // full frame path: encode → frame header → MoQ group → decode
+    let mut encoder = OpusEncoder::new(OpusChannels::Stereo, AudioMode::Voice)?;
+    let mut decoder = bench_decoder()?;
+    let track = moq::Track::new("bench").produce();
+    let mut producer = track.producer;
+    let mut consumer = track.consumer;
+    let samples_per_frame = ENGINE_FORMAT.sample_count(FRAME_DURATION);
+    let mut latencies = Vec::with_capacity(frames);
+    let mut sequence = 0u32;
+    let mut arena = FrameArena::default();
+    let allocs = allocations();
+    for chunk in input.chunks_exact(samples_per_frame) {
+        let start = Instant::now();
+        for packet in encoder.push_slice(chunk) {
+            let payload = arena.encode(&FrameHeader::new(sequence), &packet?.0);
+            sequence = sequence.wrapping_add(1);
+            let mut group = producer.append_group();
+            let mut frame = group.create_frame(moq::Frame {
+                size: payload.len() as u64,
+            });
+            frame.write_chunk(payload);
+            frame.close();
+            group.close();
+
+            let mut group = consumer
+                .next_group()
+                .await?
+                .ok_or_else(|| anyhow!("bench track closed"))?;
+            while let Some(frame) = group.read_frame().await? {
+                let (_, payload) = FrameHeader::decode(frame)?;
+                let count = decoder.decode(&payload)?;
+                decoder.advance(count);
+            }
+        }
+        latencies.push(start.elapsed());
+    }
+    let pipeline = LatencyReport::new(latencies, allocations_since(allocs));
+
+    Ok(BenchReport {
+        audio_seconds: audio.as_secs_f64(),
+        frames,
+        encode,
+        decode,
+        pipeline,
+    })
+}
+
+fn bench_decoder() -> Result<MediaTrackOpusDecoder> {
+    let (_sender, receiver) = media::channel(1, OverflowPolicy::default(), Counter::default());
+    let track = MediaTrack::new(
+        receiver,
+        Codec::Opus {
+            channels: OpusChannels::Stereo,
+        },
+        TrackKind::Audio,
+    );
+    MediaTrackOpusDecoder::new(track).context("failed to create bench decoder")
+}
+
+/// A voice-like test signal: two partials with a slow amplitude envelope, so the
+/// encoder does not hit its trivial silence path.
+fn test_tone(duration: Duration) -> Vec<f32> {
+    let sample_rate = ENGINE_FORMAT.sample_rate.0 as f32;
+    (0..ENGINE_FORMAT.block_count(duration))
+        .map(|i| {
+            let t = i as f32 / sample_rate;
+            let envelope = 0.5 + 0.5 * (2. * PI * 3. * t).sin();
+            envelope * (0.3 * (2. * PI * 220. * t).sin() + 0.1 * (2. * PI * 1870. * t).sin())
+        })
+        .flat_map(|s| [s, s])
+        .collect()
+}
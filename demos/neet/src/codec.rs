@@ -1,9 +1,242 @@
-use self::opus::OpusChannels;
+//! Audio codecs, each behind the [`Encoder`] and [`Decoder`] traits.
+//!
+//! Catalogs name a codec and a channel layout; [`Codec::from_catalog`] finds
+//! the [`CodecFactory`] registered under that name, and the factory makes
+//! encoders and decoders for it. Capture, playback and the MoQ code only go
+//! through these, so adding a codec means a [`Codec`] variant and a factory
+//! here, nothing else.
 
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+
+use self::{
+    flac::FlacFactory,
+    multistream::ChannelLayout,
+    opus::{OpusChannels, OpusFactory, OPUS_SAMPLE_RATE},
+};
+use crate::audio::{AudioFormat, AudioMode};
+
+pub mod budget;
+pub mod flac;
+pub mod mkv;
+pub mod multistream;
 pub mod opus;
+pub mod track;
+
+/// Every codec we can encode and decode.
+static FACTORIES: &[&dyn CodecFactory] = &[&OpusFactory, &FlacFactory];
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 #[non_exhaustive]
 pub enum Codec {
-    Opus { channels: OpusChannels },
+    Opus {
+        channels: OpusChannels,
+    },
+    /// Opus multistream, see [`multistream`].
+    OpusMultistream {
+        layout: ChannelLayout,
+    },
+    /// Lossless 16-bit stereo, see [`flac`].
+    Flac,
+}
+
+impl Codec {
+    /// Sample rate and channel count of the decoded stream.
+    pub fn audio_format(&self) -> AudioFormat {
+        AudioFormat::new2(OPUS_SAMPLE_RATE, self.layout().channels() as u16)
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        match self {
+            Codec::Opus {
+                channels: OpusChannels::Mono,
+            } => ChannelLayout::Mono,
+            Codec::Opus {
+                channels: OpusChannels::Stereo,
+            } => ChannelLayout::Stereo,
+            Codec::OpusMultistream { layout } => *layout,
+            Codec::Flac => ChannelLayout::Stereo,
+        }
+    }
+
+    /// The codec a catalog lists as `name` in `layout`.
+    pub fn from_catalog(name: &str, layout: ChannelLayout) -> Result<Self> {
+        FACTORIES
+            .iter()
+            .find(|factory| factory.name() == name)
+            .ok_or_else(|| anyhow!("unknown codec {name}"))?
+            .codec(layout)
+    }
+
+    fn factory(&self) -> &'static dyn CodecFactory {
+        match self {
+            Codec::Opus { .. } | Codec::OpusMultistream { .. } => &OpusFactory,
+            Codec::Flac => &FlacFactory,
+        }
+    }
+
+    /// The codec's name in catalogs.
+    pub fn name(&self) -> &'static str {
+        self.factory().name()
+    }
+
+    pub fn encoder(&self, mode: AudioMode) -> Result<Box<dyn Encoder>> {
+        self.factory().encoder(*self, mode)
+    }
+
+    pub fn decoder(&self) -> Result<Box<dyn Decoder>> {
+        self.factory().decoder(*self)
+    }
+}
+
+/// Encodes frames of samples, interleaved in the codec's layout, to packets.
+pub trait Encoder: Send {
+    /// Samples in one frame.
+    fn frame_samples(&self) -> usize;
+
+    /// Encode one frame of [`Encoder::frame_samples`] samples.
+    fn encode(&mut self, frame: &[f32]) -> Result<Bytes>;
+
+    /// Aim for `bits_per_second`. Codecs without a bitrate ignore it.
+    fn set_bitrate(&mut self, _bits_per_second: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// Encode frames of `duration` instead, where the codec allows it.
+    fn set_frame_duration(&mut self, _duration: Duration) {}
+
+    /// Start over from a clean state, e.g. after a frame failed to encode.
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Decodes packets to samples interleaved in its layout.
+pub trait Decoder: Send {
+    fn layout(&self) -> ChannelLayout;
+
+    /// Decode `packet` into `out` and return the number of samples per
+    /// channel. An empty packet stands in for a lost one.
+    fn decode(&mut self, packet: &[u8], out: &mut Vec<f32>) -> Result<usize>;
+}
+
+/// Makes the encoders and decoders of one codec, listed in [`FACTORIES`]
+/// under the name catalogs use for it.
+pub trait CodecFactory: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The codec for audio in `layout`, if this one can carry it.
+    fn codec(&self, layout: ChannelLayout) -> Result<Codec>;
+
+    fn encoder(&self, codec: Codec, mode: AudioMode) -> Result<Box<dyn Encoder>>;
+
+    fn decoder(&self, codec: Codec) -> Result<Box<dyn Decoder>>;
+}
+
+/// How captured audio is published, as chosen with `--codec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CodecPreference {
+    #[default]
+    Opus,
+    /// Also publish a lossless FLAC rendition, which receivers that know it
+    /// play instead of the Opus one.
+    Flac,
+}
+
+/// Encoder bitrate requested from outside the audio thread, in bits per second.
+/// Zero leaves the codec's own choice.
+#[derive(Debug, Clone, Default)]
+pub struct BitrateTarget {
+    requested: Arc<AtomicU32>,
+    /// A ceiling over whatever is requested, e.g. to stay within a data
+    /// budget. Zero if none.
+    cap: Arc<AtomicU32>,
+    /// A ceiling the user set while running, from the config file. Zero if
+    /// none.
+    limit: Arc<AtomicU32>,
+}
+
+impl BitrateTarget {
+    pub fn set(&self, bits_per_second: u32) {
+        self.requested.store(bits_per_second, Ordering::Relaxed);
+    }
+
+    /// The bitrate requested, before any ceiling.
+    pub fn requested(&self) -> u32 {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// The bitrate to encode at: the requested one, lowered to the cap and
+    /// the limit.
+    pub fn get(&self) -> u32 {
+        let requested = self.requested.load(Ordering::Relaxed);
+        let limit = self.limit.load(Ordering::Relaxed);
+        [self.cap(), limit]
+            .into_iter()
+            .filter(|&ceiling| ceiling != 0)
+            .fold(requested, |rate, ceiling| match rate {
+                0 => ceiling,
+                rate => rate.min(ceiling),
+            })
+    }
+
+    pub fn set_limit(&self, bits_per_second: u32) {
+        self.limit.store(bits_per_second, Ordering::Relaxed);
+    }
+
+    pub fn set_cap(&self, bits_per_second: u32) {
+        self.cap.store(bits_per_second, Ordering::Relaxed);
+    }
+
+    pub fn cap(&self) -> u32 {
+        self.cap.load(Ordering::Relaxed)
+    }
+
+    pub fn is_capped(&self) -> bool {
+        self.cap() != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_names_find_the_factory_that_round_trips_the_codec() {
+        let codecs = [
+            Codec::Opus {
+                channels: OpusChannels::Mono,
+            },
+            Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            Codec::OpusMultistream {
+                layout: ChannelLayout::Surround51,
+            },
+            Codec::Flac,
+        ];
+        for codec in codecs {
+            let found = Codec::from_catalog(codec.name(), codec.layout()).unwrap();
+            assert_eq!(found, codec);
+
+            let mut encoder = codec.encoder(AudioMode::Voice).unwrap();
+            let frame = vec![0.1; encoder.frame_samples()];
+            let packet = encoder.encode(&frame).unwrap();
+            let mut decoder = codec.decoder().unwrap();
+            assert_eq!(decoder.layout(), codec.layout());
+            let mut decoded = Vec::new();
+            let frames = decoder.decode(&packet, &mut decoded).unwrap();
+            assert_eq!(frames * codec.layout().channels(), frame.len());
+        }
+        assert!(Codec::from_catalog("aac", ChannelLayout::Stereo).is_err());
+        assert!(Codec::from_catalog("flac", ChannelLayout::Mono).is_err());
+    }
 }
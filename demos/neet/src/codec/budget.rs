@@ -0,0 +1,88 @@
+//! How many codec errors a call survives.
+//!
+//! An encoder in a bad state or a malformed packet from a buggy peer should
+//! cost the frame it happened on, not the call. Encoders and decoders reset
+//! their codec and skip the frame, until more than `--codec-error-budget`
+//! errors happen within [`ERROR_WINDOW`]; then the error ends the call as
+//! before, since something is persistently wrong.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use tracing::warn;
+
+use crate::stats::Counter;
+
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// Errors tolerated per [`ERROR_WINDOW`]. The default tolerates none.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorBudget {
+    limit: usize,
+    /// When the errors within the window happened.
+    recent: VecDeque<Instant>,
+    /// Every error, tolerated or not.
+    errors: Counter,
+}
+
+impl ErrorBudget {
+    pub fn new(limit: u32, errors: Counter) -> Self {
+        Self {
+            limit: limit as usize,
+            recent: VecDeque::new(),
+            errors,
+        }
+    }
+
+    /// Count `err`, and give it back once the budget is spent.
+    pub fn spend(&mut self, err: Error) -> Result<()> {
+        self.spend_at(err, Instant::now())
+    }
+
+    fn spend_at(&mut self, err: Error, now: Instant) -> Result<()> {
+        self.errors.add(1);
+        while self
+            .recent
+            .front()
+            .is_some_and(|&at| now - at >= ERROR_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        if self.recent.len() >= self.limit {
+            return Err(err.context(format!(
+                "more than {} codec errors within {ERROR_WINDOW:?}",
+                self.limit
+            )));
+        }
+        self.recent.push_back(now);
+        warn!("{err:#}; resetting the codec and skipping the frame");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn fails_once_the_budget_for_the_window_is_spent() {
+        let errors = Counter::default();
+        let mut budget = ErrorBudget::new(2, errors.clone());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(budget.spend_at(anyhow!("bad packet"), at(0)).is_ok());
+        assert!(budget.spend_at(anyhow!("bad packet"), at(30)).is_ok());
+        assert!(budget.spend_at(anyhow!("bad packet"), at(59)).is_err());
+        // the first error has left the window.
+        assert!(budget.spend_at(anyhow!("bad packet"), at(61)).is_ok());
+        assert_eq!(errors.get(), 4);
+
+        assert!(ErrorBudget::default().spend(anyhow!("bad packet")).is_err());
+    }
+}
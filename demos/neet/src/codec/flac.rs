@@ -0,0 +1,570 @@
+//! Lossless audio as FLAC frames.
+//!
+//! Each 20 ms of captured stereo audio becomes one FLAC frame of 16-bit
+//! samples at 48 kHz (RFC 9639, section 9). There is no stream header: the
+//! catalog names the codec, and every frame header repeats the parameters a
+//! decoder needs. Frames use the fixed predictors with Rice-coded residuals,
+//! the channels coded independently, which takes roughly half the bits of
+//! raw PCM for music. At about 700 kbps this is for archiving or
+//! rebroadcasting a call over a fast link, not for everyday calls.
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+
+use super::{
+    multistream::ChannelLayout, opus::OPUS_SAMPLE_RATE, Codec, CodecFactory, Decoder, Encoder,
+};
+use crate::audio::AudioMode;
+
+const CHANNELS: usize = 2;
+/// Frames (samples per channel) in one FLAC frame: 20 ms.
+const BLOCK_SIZE: usize = OPUS_SAMPLE_RATE as usize / 50;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_FIXED_ORDER: usize = 4;
+/// The largest Rice parameter of the 4-bit coding method; 15 is an escape.
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// 14-bit sync code, a reserved bit and fixed-blocksize strategy.
+const SYNC: u32 = 0xfff8;
+/// Block size stored as a 16-bit value after the frame number.
+const BLOCK_SIZE_16BIT: u32 = 0b0111;
+const SAMPLE_RATE_48KHZ: u32 = 0b1010;
+const TWO_INDEPENDENT_CHANNELS: u32 = 0b0001;
+const SAMPLE_SIZE_16BIT: u32 = 0b100;
+
+const SUBFRAME_CONSTANT: u32 = 0b000000;
+const SUBFRAME_VERBATIM: u32 = 0b000001;
+const SUBFRAME_FIXED: u32 = 0b001000;
+
+pub struct FlacEncoder {
+    frame_number: u32,
+    /// One channel of the frame being encoded.
+    channel: Vec<i32>,
+    residual: Vec<i32>,
+}
+
+impl Default for FlacEncoder {
+    fn default() -> Self {
+        Self {
+            frame_number: 0,
+            channel: Vec::with_capacity(BLOCK_SIZE),
+            residual: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+}
+
+/// Makes [`Codec::Flac`] encoders and decoders, for catalogs' `flac`.
+pub struct FlacFactory;
+
+impl CodecFactory for FlacFactory {
+    fn name(&self) -> &'static str {
+        "flac"
+    }
+
+    fn codec(&self, layout: ChannelLayout) -> Result<Codec> {
+        ensure!(
+            layout == ChannelLayout::Stereo,
+            "FLAC is only supported in stereo"
+        );
+        Ok(Codec::Flac)
+    }
+
+    fn encoder(&self, _codec: Codec, _mode: AudioMode) -> Result<Box<dyn Encoder>> {
+        Ok(Box::new(FlacEncoder::default()))
+    }
+
+    fn decoder(&self, _codec: Codec) -> Result<Box<dyn Decoder>> {
+        Ok(Box::new(FlacDecoder::default()))
+    }
+}
+
+impl Encoder for FlacEncoder {
+    /// Samples of one frame, interleaved stereo.
+    fn frame_samples(&self) -> usize {
+        BLOCK_SIZE * CHANNELS
+    }
+
+    /// Encode one frame of [`Encoder::frame_samples`] samples.
+    fn encode(&mut self, frame: &[f32]) -> Result<Bytes> {
+        ensure!(frame.len() == self.frame_samples(), "partial FLAC frame");
+        let mut out = BitWriter::default();
+        out.write(SYNC, 16);
+        out.write(BLOCK_SIZE_16BIT, 4);
+        out.write(SAMPLE_RATE_48KHZ, 4);
+        out.write(TWO_INDEPENDENT_CHANNELS, 4);
+        out.write(SAMPLE_SIZE_16BIT, 3);
+        out.write(0, 1);
+        write_utf8(&mut out, self.frame_number);
+        out.write(BLOCK_SIZE as u32 - 1, 16);
+        let crc = crc8(out.bytes());
+        out.write(crc as u32, 8);
+
+        for channel in 0..CHANNELS {
+            self.channel.clear();
+            self.channel.extend(
+                frame
+                    .iter()
+                    .skip(channel)
+                    .step_by(CHANNELS)
+                    .map(|&sample| to_i16(sample)),
+            );
+            self.write_subframe(&mut out);
+        }
+
+        out.align();
+        let crc = crc16(out.bytes());
+        out.write(crc as u32, 16);
+        self.frame_number = (self.frame_number + 1) & 0x7fff_ffff;
+        Ok(Bytes::from(out.finish()))
+    }
+}
+
+impl FlacEncoder {
+    fn write_subframe(&mut self, out: &mut BitWriter) {
+        let samples = &self.channel;
+        if samples.iter().all(|&sample| sample == samples[0]) {
+            out.write(SUBFRAME_CONSTANT << 1, 8);
+            out.write_signed(samples[0], BITS_PER_SAMPLE);
+            return;
+        }
+        let order = (0..=MAX_FIXED_ORDER)
+            .min_by_key(|&order| {
+                fixed_residual(samples, order)
+                    .map(|residual| residual.unsigned_abs() as u64)
+                    .sum::<u64>()
+            })
+            .unwrap_or_default();
+        self.residual.clear();
+        self.residual.extend(fixed_residual(samples, order));
+        let sum: u64 = self.residual.iter().map(|&r| zigzag(r) as u64).sum();
+        let mean = sum / self.residual.len() as u64;
+        let parameter = (u64::BITS - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+
+        out.write((SUBFRAME_FIXED | order as u32) << 1, 8);
+        for &sample in &samples[..order] {
+            out.write_signed(sample, BITS_PER_SAMPLE);
+        }
+        // the 4-bit Rice coding method, with the whole block in one partition.
+        out.write(0b00, 2);
+        out.write(0, 4);
+        out.write(parameter, 4);
+        for &residual in &self.residual {
+            let value = zigzag(residual);
+            out.write_unary(value >> parameter);
+            out.write(value & ((1 << parameter) - 1), parameter);
+        }
+    }
+}
+
+pub struct FlacDecoder {
+    channel: Vec<i32>,
+}
+
+impl Default for FlacDecoder {
+    fn default() -> Self {
+        Self {
+            channel: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+}
+
+impl Decoder for FlacDecoder {
+    fn layout(&self) -> ChannelLayout {
+        ChannelLayout::Stereo
+    }
+
+    /// Decode a frame from [`FlacEncoder`] into `out`, interleaved stereo,
+    /// and return the number of samples per channel. An empty packet, for a
+    /// lost one, decodes to a frame of silence.
+    fn decode(&mut self, packet: &[u8], out: &mut Vec<f32>) -> Result<usize> {
+        if packet.is_empty() {
+            out.resize(out.len() + BLOCK_SIZE * CHANNELS, 0.);
+            return Ok(BLOCK_SIZE);
+        }
+        ensure!(packet.len() > 2, "truncated FLAC frame");
+        let (frame, footer) = packet.split_at(packet.len() - 2);
+        ensure!(
+            crc16(frame) == u16::from_be_bytes([footer[0], footer[1]]),
+            "corrupt FLAC frame"
+        );
+        let mut input = BitReader::new(frame);
+        ensure!(input.read(16)? == SYNC, "not a FLAC frame");
+        let block_size = input.read(4)?;
+        let sample_rate = input.read(4)?;
+        let channels = input.read(4)?;
+        let sample_size = input.read(3)?;
+        input.read(1)?;
+        if (block_size, sample_rate, channels, sample_size)
+            != (
+                BLOCK_SIZE_16BIT,
+                SAMPLE_RATE_48KHZ,
+                TWO_INDEPENDENT_CHANNELS,
+                SAMPLE_SIZE_16BIT,
+            )
+        {
+            bail!("unsupported FLAC frame: only 16-bit 48 kHz stereo is played");
+        }
+        read_utf8(&mut input)?;
+        let frames = input.read(16)? as usize + 1;
+        let header_len = input.position() / 8;
+        ensure!(
+            crc8(&frame[..header_len]) == input.read(8)? as u8,
+            "corrupt FLAC frame header"
+        );
+
+        let start = out.len();
+        out.resize(start + frames * CHANNELS, 0.);
+        for channel in 0..CHANNELS {
+            self.read_subframe(&mut input, frames)?;
+            for (frame, &sample) in out[start..].chunks_exact_mut(CHANNELS).zip(&self.channel) {
+                frame[channel] = sample as f32 / 32768.;
+            }
+        }
+        Ok(frames)
+    }
+}
+
+impl FlacDecoder {
+    fn read_subframe(&mut self, input: &mut BitReader, frames: usize) -> Result<()> {
+        self.channel.clear();
+        let header = input.read(8)?;
+        ensure!(header & 1 == 0, "unsupported FLAC subframe: wasted bits");
+        let kind = header >> 1;
+        match kind {
+            SUBFRAME_CONSTANT => {
+                let sample = input.read_signed(BITS_PER_SAMPLE)?;
+                self.channel.resize(frames, sample);
+            }
+            SUBFRAME_VERBATIM => {
+                for _ in 0..frames {
+                    self.channel.push(input.read_signed(BITS_PER_SAMPLE)?);
+                }
+            }
+            _ if kind & !0b111 == SUBFRAME_FIXED && kind & 0b111 <= MAX_FIXED_ORDER as u32 => {
+                let order = (kind & 0b111) as usize;
+                ensure!(order <= frames, "corrupt FLAC subframe");
+                for _ in 0..order {
+                    self.channel.push(input.read_signed(BITS_PER_SAMPLE)?);
+                }
+                self.read_residual(input, frames, order)?;
+            }
+            _ => bail!("unsupported FLAC subframe type {kind:#08b}"),
+        }
+        Ok(())
+    }
+
+    /// Read the residual of a fixed subframe and restore the samples. The
+    /// remote picks the residuals, so a sample they restore to outside the
+    /// 16-bit range fails the frame.
+    fn read_residual(&mut self, input: &mut BitReader, frames: usize, order: usize) -> Result<()> {
+        ensure!(input.read(2)? == 0b00, "unsupported FLAC residual coding");
+        let partition_order = input.read(4)?;
+        let partitions = 1 << partition_order;
+        ensure!(frames.is_multiple_of(partitions), "corrupt FLAC residual");
+        for partition in 0..partitions {
+            let parameter = input.read(4)?;
+            ensure!(
+                parameter <= MAX_RICE_PARAMETER,
+                "unsupported FLAC escaped partition"
+            );
+            let mut count = frames / partitions;
+            if partition == 0 {
+                ensure!(count >= order, "corrupt FLAC residual");
+                count -= order;
+            }
+            for _ in 0..count {
+                let value = (input.read_unary()? << parameter) | input.read(parameter)?;
+                let residual = unzigzag(value);
+                let n = self.channel.len();
+                let history = &self.channel[n - order..];
+                let sample = residual as i64 + fixed_prediction(history);
+                let sample = i16::try_from(sample).context("corrupt FLAC residual")?;
+                self.channel.push(sample as i32);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_i16(sample: f32) -> i32 {
+    (sample * 32768.)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i32
+}
+
+/// The prediction of the fixed predictor of order `history.len()` for the
+/// sample following `history`, wide enough that no history overflows it.
+fn fixed_prediction(history: &[i32]) -> i64 {
+    let wide = i64::from;
+    match *history {
+        [] => 0,
+        [a] => wide(a),
+        [b, a] => 2 * wide(a) - wide(b),
+        [c, b, a] => 3 * wide(a) - 3 * wide(b) + wide(c),
+        [d, c, b, a] => 4 * wide(a) - 6 * wide(b) + 4 * wide(c) - wide(d),
+        _ => unreachable!("fixed predictors go up to order 4"),
+    }
+}
+
+fn fixed_residual(samples: &[i32], order: usize) -> impl Iterator<Item = i32> + '_ {
+    // 16-bit samples leave residuals well within 32 bits.
+    samples
+        .windows(order + 1)
+        .map(move |window| (window[order] as i64 - fixed_prediction(&window[..order])) as i32)
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Frame numbers are coded like UTF-8, extended to 31 bits.
+fn write_utf8(out: &mut BitWriter, value: u32) {
+    if value < 0x80 {
+        out.write(value, 8);
+        return;
+    }
+    let continuation = match value {
+        0..=0x7ff => 1,
+        0x800..=0xffff => 2,
+        0x1_0000..=0x1f_ffff => 3,
+        0x20_0000..=0x3ff_ffff => 4,
+        _ => 5,
+    };
+    let lead = (0xff00_u32 >> (continuation + 1)) & 0xff;
+    out.write(lead | (value >> (6 * continuation)), 8);
+    for index in (0..continuation).rev() {
+        out.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+    }
+}
+
+fn read_utf8(input: &mut BitReader) -> Result<u32> {
+    let lead = input.read(8)?;
+    let continuation = (lead << 24).leading_ones().saturating_sub(1);
+    ensure!(continuation <= 5, "corrupt FLAC frame number");
+    let mut value = lead & (0x7f >> continuation);
+    for _ in 0..continuation {
+        let byte = input.read(8)?;
+        ensure!(byte & 0xc0 == 0x80, "corrupt FLAC frame number");
+        value = (value << 6) | (byte & 0x3f);
+    }
+    Ok(value)
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Bits not yet in `bytes`, in the low `pending` bits.
+    acc: u64,
+    pending: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits == 32 || value >> bits == 0);
+        self.acc = (self.acc << bits) | value as u64;
+        self.pending += bits;
+        while self.pending >= 8 {
+            self.pending -= 8;
+            self.bytes.push((self.acc >> self.pending) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u32 & (u32::MAX >> (32 - bits)), bits);
+    }
+
+    fn write_unary(&mut self, zeros: u32) {
+        let mut zeros = zeros;
+        while zeros >= 24 {
+            self.write(0, 24);
+            zeros -= 24;
+        }
+        self.write(1, zeros + 1);
+    }
+
+    /// Pad with zero bits up to the next byte.
+    fn align(&mut self) {
+        if self.pending > 0 {
+            self.write(0, 8 - self.pending);
+        }
+    }
+
+    /// The whole bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    /// In bits.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn bit(&mut self) -> Result<u32> {
+        let Some(byte) = self.bytes.get(self.position / 8) else {
+            bail!("truncated FLAC frame");
+        };
+        let bit = (byte >> (7 - self.position % 8)) & 1;
+        self.position += 1;
+        Ok(bit as u32)
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32> {
+        (0..bits).try_fold(0, |value, _| Ok((value << 1) | self.bit()?))
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Result<i32> {
+        let value = self.read(bits)?;
+        let shift = 32 - bits;
+        Ok(((value << shift) as i32) >> shift)
+    }
+
+    fn read_unary(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_round_trip_bit_exact() {
+        let mut encoder = FlacEncoder::default();
+        let mut decoder = FlacDecoder::default();
+        // a tone on the left, silence on the right, then full-scale noise.
+        let tone: Vec<f32> = (0..BLOCK_SIZE)
+            .flat_map(|i| [(i as f32 / 10.).sin() * 0.5, 0.])
+            .collect();
+        let mut seed = 1_u32;
+        let noise: Vec<f32> = (0..BLOCK_SIZE * CHANNELS)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 16) as i16 as f32 / 32768.
+            })
+            .collect();
+        for input in [&tone, &noise] {
+            let packet = encoder.encode(input).unwrap();
+            let mut output = Vec::new();
+            assert_eq!(decoder.decode(&packet, &mut output).unwrap(), BLOCK_SIZE);
+            let expected: Vec<f32> = input.iter().map(|&s| to_i16(s) as f32 / 32768.).collect();
+            assert_eq!(output, expected);
+        }
+        let packet = encoder.encode(&tone).unwrap();
+        assert!(packet.len() < BLOCK_SIZE * CHANNELS, "the tone compresses");
+
+        let mut corrupt = packet.to_vec();
+        corrupt[20] ^= 1;
+        assert!(decoder.decode(&corrupt, &mut Vec::new()).is_err());
+
+        // frames are counted in interleaved samples, so they time as 20 ms.
+        let format = Codec::Flac.audio_format();
+        let duration = format.duration_from_sample_count(encoder.frame_samples());
+        assert_eq!(duration, std::time::Duration::from_millis(20));
+    }
+
+    /// A frame of five samples per channel, each channel a fixed subframe of
+    /// order 4 from `warm_up` with a single `residual`.
+    fn crafted_frame(warm_up: [i32; 4], residual: i32) -> Vec<u8> {
+        let mut out = BitWriter::default();
+        out.write(SYNC, 16);
+        out.write(BLOCK_SIZE_16BIT, 4);
+        out.write(SAMPLE_RATE_48KHZ, 4);
+        out.write(TWO_INDEPENDENT_CHANNELS, 4);
+        out.write(SAMPLE_SIZE_16BIT, 3);
+        out.write(0, 1);
+        write_utf8(&mut out, 0);
+        out.write(5 - 1, 16);
+        let crc = crc8(out.bytes());
+        out.write(crc as u32, 8);
+        for _ in 0..CHANNELS {
+            out.write((SUBFRAME_FIXED | 4) << 1, 8);
+            for sample in warm_up {
+                out.write_signed(sample, BITS_PER_SAMPLE);
+            }
+            out.write(0b00, 2);
+            out.write(0, 4);
+            out.write(MAX_RICE_PARAMETER, 4);
+            let value = zigzag(residual);
+            out.write_unary(value >> MAX_RICE_PARAMETER);
+            out.write(value & ((1 << MAX_RICE_PARAMETER) - 1), MAX_RICE_PARAMETER);
+        }
+        out.align();
+        let crc = crc16(out.bytes());
+        out.write(crc as u32, 16);
+        out.finish()
+    }
+
+    #[test]
+    fn rejects_residuals_that_leave_the_sample_range() {
+        let mut decoder = FlacDecoder::default();
+        let mut output = Vec::new();
+        let packet = crafted_frame([0, 1, 2, 3], 1);
+        assert_eq!(decoder.decode(&packet, &mut output).unwrap(), 5);
+        assert_eq!(output[8] * 32768., 5.);
+
+        // predicted from full-scale swings, or past what 32 bits hold.
+        let swings = [i16::MAX, i16::MIN, i16::MAX, i16::MIN].map(i32::from);
+        for (warm_up, residual) in [(swings, 0), (swings, i32::MIN), ([0; 4], i32::MAX)] {
+            let packet = crafted_frame(warm_up, residual);
+            assert!(decoder.decode(&packet, &mut Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn frame_numbers_use_the_utf8_coding() {
+        for value in [0, 0x7f, 0x80, 0x7ff, 0x800, 0xffff, 0x1_0000, 0x7fff_ffff] {
+            let mut out = BitWriter::default();
+            write_utf8(&mut out, value);
+            let bytes = out.finish();
+            assert_eq!(read_utf8(&mut BitReader::new(&bytes)).unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! A Matroska muxer for recordings, e.g. `neet bridge --record`.
+//!
+//! The file is playable from the moment it is created: the segment is left
+//! open-ended and clusters are appended whole, so a recording cut short by a
+//! crash keeps everything up to its last cluster. Matroska wants every track
+//! listed before the first cluster, while participants join during the call,
+//! so room is reserved after the segment info and the track list, with each
+//! track's participant tags and the chapters marked so far, is rewritten
+//! there whenever a track or chapter is added. [`MkvWriter::finish`] fills in
+//! the segment size and the duration.
+//!
+//! Only audio is written so far; video would be another [`TrackCodec`].
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{ensure, Context, Result};
+
+use super::opus::OPUS_SAMPLE_RATE;
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const VOID: u32 = 0xEC;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMECODE_SCALE: u32 = 0x2AD7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const DATE_UTC: u32 = 0x4461;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const NAME: u32 = 0x536E;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const SEEK_PRE_ROLL: u32 = 0x56BB;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const TAGS: u32 = 0x1254_C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TAG_TRACK_UID: u32 = 0x63C5;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+const CHAPTERS: u32 = 0x1043_A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_UID: u32 = 0x73C4;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const CHAP_LANGUAGE: u32 = 0x437C;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMECODE: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+const TRACK_TYPE_AUDIO: u64 = 2;
+/// Element sizes always take 8 bytes, which lets them be patched in place.
+const SIZE_LENGTH: usize = 8;
+/// The size of an element still being written.
+const UNKNOWN_SIZE: u64 = (1 << 56) - 1;
+/// A duration element: two bytes of ID, the size and a 64-bit float.
+const DURATION_LENGTH: usize = 2 + SIZE_LENGTH + 8;
+/// Room for the track list, tags and chapters; plenty for every track there
+/// can be and a hundred chapters, with long names.
+const METADATA_ROOM: usize = 64 * 1024;
+/// Block headers carry the track number in one byte.
+const MAX_TRACKS: usize = 126;
+/// Longest participant name or chapter title kept, in bytes, so that a few
+/// long ones can't take up the room of all the others.
+const MAX_NAME_LENGTH: usize = 64;
+/// Timecodes are in milliseconds.
+const TIMECODE_SCALE_NS: u64 = 1_000_000;
+/// A new cluster starts this often, well within the ±32 s a block's
+/// timecode can be from its cluster's.
+const CLUSTER_DURATION: Duration = Duration::from_secs(5);
+/// Matroska dates count from 2001.
+const MATROSKA_EPOCH: Duration = Duration::from_secs(978_307_200);
+/// Samples the encoder delays its output by, which players skip.
+const OPUS_PRE_SKIP: u16 = 312;
+/// Audio a decoder needs before a seek target to converge, per the Opus
+/// Matroska mapping.
+const OPUS_SEEK_PRE_ROLL: Duration = Duration::from_millis(80);
+
+/// What a track carries, with the parameters its decoder needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackCodec {
+    Opus { channels: u8 },
+}
+
+/// A track and whose it is.
+#[derive(Debug, Clone)]
+pub struct MkvTrack {
+    pub codec: TrackCodec,
+    /// The participant it records, as the roster names them; also the
+    /// track's name.
+    pub participant: String,
+}
+
+impl MkvTrack {
+    fn entry(&self, number: u64) -> Vec<u8> {
+        let mut entry = Vec::new();
+        uint(&mut entry, TRACK_NUMBER, number);
+        uint(&mut entry, TRACK_UID, number);
+        element(&mut entry, NAME, truncated(&self.participant).as_bytes());
+        match self.codec {
+            TrackCodec::Opus { channels } => {
+                uint(&mut entry, TRACK_TYPE, TRACK_TYPE_AUDIO);
+                element(&mut entry, CODEC_ID, b"A_OPUS");
+                element(&mut entry, CODEC_PRIVATE, &opus_head(channels));
+                uint(
+                    &mut entry,
+                    SEEK_PRE_ROLL,
+                    OPUS_SEEK_PRE_ROLL.as_nanos() as u64,
+                );
+                let mut audio = Vec::new();
+                element(
+                    &mut audio,
+                    SAMPLING_FREQUENCY,
+                    &(OPUS_SAMPLE_RATE as f64).to_be_bytes(),
+                );
+                uint(&mut audio, CHANNELS, channels as u64);
+                element(&mut entry, AUDIO, &audio);
+            }
+        }
+        entry
+    }
+
+    fn tag(&self, number: u64) -> Vec<u8> {
+        let mut targets = Vec::new();
+        uint(&mut targets, TAG_TRACK_UID, number);
+        let mut simple = Vec::new();
+        element(&mut simple, TAG_NAME, b"PARTICIPANT");
+        element(
+            &mut simple,
+            TAG_STRING,
+            truncated(&self.participant).as_bytes(),
+        );
+        let mut tag = Vec::new();
+        element(&mut tag, TARGETS, &targets);
+        element(&mut tag, SIMPLE_TAG, &simple);
+        tag
+    }
+}
+
+/// Writes a Matroska file as a recording goes.
+pub struct MkvWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    tracks: Vec<MkvTrack>,
+    /// Titled moments, in order.
+    chapters: Vec<(Duration, String)>,
+    /// Where the segment's size, the duration placeholder and the room for
+    /// the track list are.
+    segment_size_at: u64,
+    segment_start: u64,
+    duration_at: u64,
+    metadata_at: u64,
+    /// The blocks of the cluster being filled, and its start.
+    cluster: Vec<u8>,
+    cluster_start: Option<Duration>,
+    end: Duration,
+}
+
+impl MkvWriter {
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        let mut header = Vec::new();
+        uint(&mut header, EBML_VERSION, 1);
+        uint(&mut header, EBML_READ_VERSION, 1);
+        uint(&mut header, EBML_MAX_ID_LENGTH, 4);
+        uint(&mut header, EBML_MAX_SIZE_LENGTH, SIZE_LENGTH as u64);
+        element(&mut header, DOC_TYPE, b"matroska");
+        uint(&mut header, DOC_TYPE_VERSION, 4);
+        uint(&mut header, DOC_TYPE_READ_VERSION, 2);
+        let mut head = Vec::new();
+        element(&mut head, EBML, &header);
+
+        id(&mut head, SEGMENT);
+        let segment_size_at = head.len() as u64;
+        size(&mut head, UNKNOWN_SIZE);
+        let segment_start = head.len() as u64;
+
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_sub(MATROSKA_EPOCH);
+        let mut info = Vec::new();
+        uint(&mut info, TIMECODE_SCALE, TIMECODE_SCALE_NS);
+        element(&mut info, MUXING_APP, b"neet");
+        element(&mut info, WRITING_APP, b"neet");
+        element(&mut info, DATE_UTC, &(date.as_nanos() as i64).to_be_bytes());
+        // the duration is only known at the end.
+        let duration_in_info = info.len();
+        void(&mut info, DURATION_LENGTH);
+        id(&mut head, INFO);
+        size(&mut head, info.len() as u64);
+        let duration_at = (head.len() + duration_in_info) as u64;
+        head.extend_from_slice(&info);
+
+        let metadata_at = head.len() as u64;
+        void(&mut head, METADATA_ROOM);
+
+        let mut file = BufWriter::new(file);
+        file.write_all(&head)?;
+        file.flush()?;
+        Ok(Self {
+            path,
+            file,
+            tracks: Vec::new(),
+            chapters: Vec::new(),
+            segment_size_at,
+            segment_start,
+            duration_at,
+            metadata_at,
+            cluster: Vec::new(),
+            cluster_start: None,
+            end: Duration::ZERO,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `track` and return its number.
+    pub fn add_track(&mut self, track: MkvTrack) -> Result<u64> {
+        ensure!(
+            self.tracks.len() < MAX_TRACKS,
+            "more than {MAX_TRACKS} tracks"
+        );
+        self.tracks.push(track);
+        if let Err(err) = self.write_metadata() {
+            self.tracks.pop();
+            return Err(err);
+        }
+        Ok(self.tracks.len() as u64)
+    }
+
+    /// Add a chapter called `title` starting at `at` from the start of the
+    /// recording.
+    pub fn add_chapter(&mut self, at: Duration, title: impl Into<String>) -> Result<()> {
+        self.chapters.push((at, title.into()));
+        if let Err(err) = self.write_metadata() {
+            self.chapters.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Write the tracks, their tags and the chapters into the room reserved
+    /// for them.
+    fn write_metadata(&mut self) -> Result<()> {
+        let mut entries = Vec::new();
+        let mut tags = Vec::new();
+        for (number, track) in (1..).zip(&self.tracks) {
+            element(&mut entries, TRACK_ENTRY, &track.entry(number));
+            element(&mut tags, TAG, &track.tag(number));
+        }
+        let mut room = Vec::with_capacity(METADATA_ROOM);
+        element(&mut room, TRACKS, &entries);
+        element(&mut room, TAGS, &tags);
+        if !self.chapters.is_empty() {
+            let mut edition = Vec::new();
+            for (uid, (at, title)) in (1..).zip(&self.chapters) {
+                let mut display = Vec::new();
+                element(&mut display, CHAP_STRING, truncated(title).as_bytes());
+                element(&mut display, CHAP_LANGUAGE, b"eng");
+                let mut atom = Vec::new();
+                uint(&mut atom, CHAPTER_UID, uid);
+                uint(&mut atom, CHAPTER_TIME_START, at.as_nanos() as u64);
+                element(&mut atom, CHAPTER_DISPLAY, &display);
+                element(&mut edition, CHAPTER_ATOM, &atom);
+            }
+            let mut chapters = Vec::new();
+            element(&mut chapters, EDITION_ENTRY, &edition);
+            element(&mut room, CHAPTERS, &chapters);
+        }
+        let left = METADATA_ROOM.saturating_sub(room.len());
+        ensure!(
+            left > SIZE_LENGTH,
+            "no room left in the recording's metadata"
+        );
+        void(&mut room, left);
+        self.rewrite(self.metadata_at, &room)
+    }
+
+    /// Add an encoded `frame` of `duration` to `track`, starting at
+    /// `timecode` from the start of the recording. Frames must come in order
+    /// of their timecodes.
+    pub fn write(
+        &mut self,
+        track: u64,
+        timecode: Duration,
+        duration: Duration,
+        frame: &[u8],
+    ) -> Result<()> {
+        ensure!(
+            (1..=self.tracks.len() as u64).contains(&track),
+            "no track {track}"
+        );
+        let start = match self.cluster_start {
+            Some(start) if timecode >= start && timecode - start < CLUSTER_DURATION => start,
+            _ => {
+                self.flush_cluster()?;
+                self.cluster_start = Some(timecode);
+                timecode
+            }
+        };
+        let mut block = Vec::with_capacity(frame.len() + 4);
+        block.push(0x80 | track as u8);
+        block.extend_from_slice(&((timecode - start).as_millis() as i16).to_be_bytes());
+        // a keyframe: every Opus frame decodes on its own.
+        block.push(0x80);
+        block.extend_from_slice(frame);
+        element(&mut self.cluster, SIMPLE_BLOCK, &block);
+        self.end = self.end.max(timecode + duration);
+        Ok(())
+    }
+
+    /// Append the cluster being filled, whole, so that it survives a crash.
+    fn flush_cluster(&mut self) -> Result<()> {
+        let Some(start) = self.cluster_start.take() else {
+            return Ok(());
+        };
+        let mut body = Vec::with_capacity(self.cluster.len() + 16);
+        uint(&mut body, TIMECODE, start.as_millis() as u64);
+        body.append(&mut self.cluster);
+        let mut cluster = Vec::with_capacity(body.len() + 12);
+        element(&mut cluster, CLUSTER, &body);
+        self.file.write_all(&cluster)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Write `bytes` over what is at `at`, then carry on at the end.
+    fn rewrite(&mut self, at: u64, bytes: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.write_all(bytes)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Write the last cluster and fill in the segment size and duration.
+    pub fn finish(mut self) -> Result<()> {
+        self.flush_cluster()?;
+        let mut duration = Vec::with_capacity(DURATION_LENGTH);
+        element(
+            &mut duration,
+            DURATION,
+            &(self.end.as_millis() as f64).to_be_bytes(),
+        );
+        self.rewrite(self.duration_at, &duration)?;
+        let end = self.file.stream_position()?;
+        let mut segment_size = Vec::with_capacity(SIZE_LENGTH);
+        size(&mut segment_size, end - self.segment_start);
+        self.rewrite(self.segment_size_at, &segment_size)?;
+        self.file.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// The Opus identification header, channel mapping family 0.
+fn opus_head(channels: u8) -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.extend_from_slice(&[1, channels]);
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+    // no output gain, mapping family 0.
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+/// `name` cut to [`MAX_NAME_LENGTH`] bytes, at a character boundary.
+fn truncated(name: &str) -> &str {
+    let mut end = name.len().min(MAX_NAME_LENGTH);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+fn id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count();
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+fn size(out: &mut Vec<u8>, size: u64) {
+    out.push(0x01);
+    out.extend_from_slice(&size.to_be_bytes()[1..]);
+}
+
+fn element(out: &mut Vec<u8>, element_id: u32, body: &[u8]) {
+    id(out, element_id);
+    size(out, body.len() as u64);
+    out.extend_from_slice(body);
+}
+
+fn uint(out: &mut Vec<u8>, element_id: u32, value: u64) {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&byte| byte == 0).count().min(7);
+    element(out, element_id, &bytes[skip..]);
+}
+
+/// Padding that takes up exactly `length` bytes.
+fn void(out: &mut Vec<u8>, length: usize) {
+    let body = length - 1 - SIZE_LENGTH;
+    id(out, VOID);
+    size(out, body as u64);
+    out.resize(out.len() + body, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_tracks_and_chapters_added_midway_ahead_of_the_clusters() {
+        let path = std::env::temp_dir().join(format!("neet-mkv-{}.mkv", std::process::id()));
+        let frame = Duration::from_millis(20);
+        let track = |participant: &str| MkvTrack {
+            codec: TrackCodec::Opus { channels: 2 },
+            participant: participant.to_string(),
+        };
+        let mut writer = MkvWriter::create(&path).unwrap();
+        let alice = writer.add_track(track("alice")).unwrap();
+        for tick in 0..300 {
+            if tick == 100 {
+                // bob joins after 2 s.
+                assert_eq!(writer.add_track(track("bob")).unwrap(), 2);
+            }
+            writer
+                .write(alice, frame * tick, frame, &[1, 2, 3])
+                .unwrap();
+            if tick >= 100 {
+                writer.write(2, frame * tick, frame, &[4, 5]).unwrap();
+            }
+        }
+        assert!(writer.write(3, frame * 300, frame, &[]).is_err());
+        writer
+            .add_chapter(Duration::from_secs(3), "bob: intro")
+            .unwrap();
+        let duration_at = writer.duration_at as usize;
+
+        let read = || std::fs::read(&path).unwrap();
+        let find = |file: &[u8], needle: &[u8]| {
+            file.windows(needle.len())
+                .position(|window| window == needle)
+        };
+        let cluster_id = [0x1F, 0x43, 0xB6, 0x75];
+        // playable before it is finished: both tracks, one cluster so far.
+        let partial = read();
+        let first_cluster = find(&partial, &cluster_id).unwrap();
+        assert!(find(&partial, b"bob").unwrap() < first_cluster);
+        assert!(find(&partial, b"bob: intro").unwrap() < first_cluster);
+        assert_eq!(partial[duration_at], 0xEC, "no duration yet");
+
+        writer.finish().unwrap();
+        let file = read();
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]));
+        assert_eq!(
+            file.windows(8)
+                .filter(|window| window == b"OpusHead")
+                .count(),
+            2
+        );
+        assert_eq!(
+            file.windows(11)
+                .filter(|window| window == b"PARTICIPANT")
+                .count(),
+            2
+        );
+        // 6 s of audio in 5 s clusters.
+        assert_eq!(
+            file.windows(4)
+                .filter(|window| window == &cluster_id)
+                .count(),
+            2
+        );
+        assert_eq!(file[duration_at..duration_at + 2], [0x44, 0x89]);
+        let duration = duration_at + 2 + SIZE_LENGTH;
+        let duration = f64::from_be_bytes(file[duration..duration + 8].try_into().unwrap());
+        assert_eq!(duration, 6000.);
+        // the segment ends where the file does.
+        let segment = find(&file, &[0x18, 0x53, 0x80, 0x67]).unwrap() + 4;
+        let mut size = [0; 8];
+        size[1..].copy_from_slice(&file[segment + 1..segment + SIZE_LENGTH]);
+        assert_eq!(
+            u64::from_be_bytes(size) as usize,
+            file.len() - segment - SIZE_LENGTH
+        );
+    }
+
+    #[test]
+    fn long_names_leave_room_for_every_track() {
+        let path = std::env::temp_dir().join(format!("neet-mkv-names-{}.mkv", std::process::id()));
+        let mut writer = MkvWriter::create(&path).unwrap();
+        let name = "é".repeat(1000);
+        for _ in 0..MAX_TRACKS {
+            writer
+                .add_track(MkvTrack {
+                    codec: TrackCodec::Opus { channels: 2 },
+                    participant: name.clone(),
+                })
+                .unwrap();
+        }
+        for chapter in 0..100 {
+            writer
+                .add_chapter(Duration::from_secs(chapter), name.clone())
+                .unwrap();
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(truncated(&name).len(), MAX_NAME_LENGTH);
+        assert_eq!(truncated("alice"), "alice");
+    }
+}
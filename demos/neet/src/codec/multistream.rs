@@ -0,0 +1,366 @@
+//! Opus multistream, for more than two channels.
+//!
+//! A multistream packet carries one Opus stream per channel pair plus one per
+//! remaining channel, laid out as in Ogg Opus channel mapping family 1 (RFC
+//! 7845, section 5.1.1.2). Every stream but the last is prefixed with its
+//! length as a big-endian `u16`; this framing is our own, not libopus's
+//! self-delimited one. A single stream needs no prefix, so mono and stereo
+//! packets are plain Opus packets.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use super::{opus::OPUS_SAMPLE_RATE, Decoder, Encoder};
+
+/// Samples of one 20 ms frame, per channel.
+const FRAME_SAMPLES: usize = OPUS_SAMPLE_RATE as usize / 50;
+/// Samples of the longest Opus packet, 120 ms, per channel.
+const MAX_PACKET_SAMPLES: usize = FRAME_SAMPLES * 6;
+
+/// Mid-level gain of a channel folded into both sides of a stereo downmix.
+const CENTER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Channel layouts in Vorbis channel order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    /// Front left, front right, rear left, rear right.
+    Quad,
+    /// Front left, center, front right, rear left, rear right, LFE.
+    #[serde(rename = "5.1")]
+    Surround51,
+    /// Front left, center, front right, side left, side right, rear left,
+    /// rear right, LFE.
+    #[serde(rename = "7.1")]
+    Surround71,
+}
+
+impl ChannelLayout {
+    pub fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Quad => 4,
+            ChannelLayout::Surround51 => 6,
+            ChannelLayout::Surround71 => 8,
+        }
+    }
+
+    /// Opus streams and how many of them are coupled (stereo) streams.
+    fn streams(self) -> (usize, usize) {
+        match self {
+            ChannelLayout::Mono => (1, 0),
+            ChannelLayout::Stereo => (1, 1),
+            ChannelLayout::Quad => (2, 2),
+            ChannelLayout::Surround51 => (4, 2),
+            ChannelLayout::Surround71 => (5, 3),
+        }
+    }
+
+    /// For each channel, the decoded channel it is carried in: coupled
+    /// streams first, two channels each, then the mono streams.
+    fn mapping(self) -> &'static [usize] {
+        match self {
+            ChannelLayout::Mono => &[0],
+            ChannelLayout::Stereo => &[0, 1],
+            ChannelLayout::Quad => &[0, 1, 2, 3],
+            ChannelLayout::Surround51 => &[0, 4, 1, 2, 3, 5],
+            ChannelLayout::Surround71 => &[0, 6, 1, 2, 3, 4, 5, 7],
+        }
+    }
+
+    /// Left and right gain of each channel in a stereo downmix. The LFE
+    /// channel is left out.
+    fn downmix_gains(self) -> &'static [(f32, f32)] {
+        match self {
+            ChannelLayout::Mono => &[(1., 1.)],
+            ChannelLayout::Stereo => &[(1., 0.), (0., 1.)],
+            ChannelLayout::Quad => &[(1., 0.), (0., 1.), (CENTER, 0.), (0., CENTER)],
+            ChannelLayout::Surround51 => &[
+                (1., 0.),
+                (CENTER, CENTER),
+                (0., 1.),
+                (CENTER, 0.),
+                (0., CENTER),
+                (0., 0.),
+            ],
+            ChannelLayout::Surround71 => &[
+                (1., 0.),
+                (CENTER, CENTER),
+                (0., 1.),
+                (CENTER, 0.),
+                (0., CENTER),
+                (CENTER, 0.),
+                (0., CENTER),
+                (0., 0.),
+            ],
+        }
+    }
+
+    /// Append `samples`, interleaved in this layout, to `out` as stereo.
+    pub fn downmix(self, samples: &[f32], out: &mut Vec<f32>) {
+        let gains = self.downmix_gains();
+        // keep a full-scale signal on every channel from clipping.
+        let scale = 1. / gains.iter().map(|(left, _)| left).sum::<f32>();
+        for frame in samples.chunks_exact(gains.len()) {
+            let (left, right) = frame
+                .iter()
+                .zip(gains)
+                .fold((0., 0.), |(left, right), (sample, (l, r))| {
+                    (left + sample * l, right + sample * r)
+                });
+            out.extend([left * scale, right * scale]);
+        }
+    }
+}
+
+impl fmt::Display for ChannelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChannelLayout::Mono => "mono",
+            ChannelLayout::Stereo => "stereo",
+            ChannelLayout::Quad => "quad",
+            ChannelLayout::Surround51 => "5.1",
+            ChannelLayout::Surround71 => "7.1",
+        })
+    }
+}
+
+impl FromStr for ChannelLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "mono" => ChannelLayout::Mono,
+            "stereo" => ChannelLayout::Stereo,
+            "quad" => ChannelLayout::Quad,
+            "5.1" => ChannelLayout::Surround51,
+            "7.1" => ChannelLayout::Surround71,
+            _ => bail!("unknown channel layout `{s}`: expected mono, stereo, quad, 5.1 or 7.1"),
+        })
+    }
+}
+
+/// The channels of `layout` that stream `index` carries, in stream order.
+fn stream_channels(layout: ChannelLayout, index: usize) -> Vec<usize> {
+    let (_, coupled) = layout.streams();
+    let decoded = if index < coupled {
+        vec![index * 2, index * 2 + 1]
+    } else {
+        vec![coupled + index]
+    };
+    decoded
+        .into_iter()
+        .map(|decoded| {
+            layout
+                .mapping()
+                .iter()
+                .position(|&mapped| mapped == decoded)
+                .expect("every decoded channel is mapped")
+        })
+        .collect()
+}
+
+fn opus_channels(count: usize) -> opus::Channels {
+    match count {
+        1 => opus::Channels::Mono,
+        _ => opus::Channels::Stereo,
+    }
+}
+
+pub struct MultistreamEncoder {
+    layout: ChannelLayout,
+    /// Each stream's encoder and the channels it carries.
+    streams: Vec<(opus::Encoder, Vec<usize>)>,
+    input: Vec<f32>,
+    out_buf: BytesMut,
+}
+
+impl MultistreamEncoder {
+    pub fn new(layout: ChannelLayout) -> Result<Self> {
+        let (count, _) = layout.streams();
+        let streams = (0..count)
+            .map(|index| {
+                let channels = stream_channels(layout, index);
+                let encoder = opus::Encoder::new(
+                    OPUS_SAMPLE_RATE,
+                    opus_channels(channels.len()),
+                    opus::Application::Audio,
+                )?;
+                Ok((encoder, channels))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            layout,
+            streams,
+            input: Vec::with_capacity(FRAME_SAMPLES * 2),
+            out_buf: BytesMut::new(),
+        })
+    }
+}
+
+impl Encoder for MultistreamEncoder {
+    /// Samples of one frame, interleaved in the layout.
+    fn frame_samples(&self) -> usize {
+        FRAME_SAMPLES * self.layout.channels()
+    }
+
+    /// Split `bits_per_second` over the streams, a coupled stream counting
+    /// twice.
+    fn set_bitrate(&mut self, bits_per_second: u32) -> Result<()> {
+        let share = bits_per_second / self.layout.channels() as u32;
+        for (encoder, channels) in &mut self.streams {
+            let bits = share * channels.len() as u32;
+            encoder.set_bitrate(opus::Bitrate::Bits(bits as i32))?;
+        }
+        Ok(())
+    }
+
+    /// Encode one 20 ms frame of [`Self::frame_samples`] samples.
+    fn encode(&mut self, frame: &[f32]) -> Result<Bytes> {
+        ensure!(
+            frame.len() == self.frame_samples(),
+            "partial multistream frame"
+        );
+        let channel_count = self.layout.channels();
+        let last = self.streams.len() - 1;
+        let mut packet = BytesMut::new();
+        for (index, (encoder, channels)) in self.streams.iter_mut().enumerate() {
+            self.input.clear();
+            for samples in frame.chunks_exact(channel_count) {
+                self.input
+                    .extend(channels.iter().map(|&channel| samples[channel]));
+            }
+            self.out_buf.resize(self.input.len(), 0);
+            let size = encoder.encode_float(&self.input, &mut self.out_buf)?;
+            if index != last {
+                packet.put_u16(size as u16);
+            }
+            packet.extend_from_slice(&self.out_buf[..size]);
+        }
+        Ok(packet.freeze())
+    }
+}
+
+pub struct MultistreamDecoder {
+    layout: ChannelLayout,
+    streams: Vec<(opus::Decoder, Vec<usize>)>,
+    decode_buf: Vec<f32>,
+    /// Samples per channel of the last packet, which a concealed one
+    /// stands in for.
+    last_frames: usize,
+}
+
+impl MultistreamDecoder {
+    pub fn new(layout: ChannelLayout) -> Result<Self> {
+        let (count, _) = layout.streams();
+        let streams = (0..count)
+            .map(|index| {
+                let channels = stream_channels(layout, index);
+                let decoder = opus::Decoder::new(OPUS_SAMPLE_RATE, opus_channels(channels.len()))?;
+                Ok((decoder, channels))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            layout,
+            streams,
+            decode_buf: vec![0.; MAX_PACKET_SAMPLES * 2],
+            last_frames: FRAME_SAMPLES,
+        })
+    }
+}
+
+impl Decoder for MultistreamDecoder {
+    fn layout(&self) -> ChannelLayout {
+        self.layout
+    }
+
+    /// Decode `packet` into `out`, interleaved in the layout, and return the
+    /// number of samples per channel. An empty packet conceals a lost one.
+    fn decode(&mut self, packet: &[u8], out: &mut Vec<f32>) -> Result<usize> {
+        let channel_count = self.layout.channels();
+        let start = out.len();
+        let last = self.streams.len() - 1;
+        let mut rest = packet;
+        let mut frames = None;
+        for (index, (decoder, channels)) in self.streams.iter_mut().enumerate() {
+            let data = if packet.is_empty() || index == last {
+                std::mem::take(&mut rest)
+            } else {
+                let [high, low, tail @ ..] = rest else {
+                    bail!("truncated multistream packet");
+                };
+                let size = u16::from_be_bytes([*high, *low]) as usize;
+                ensure!(size <= tail.len(), "truncated multistream packet");
+                let (data, tail) = tail.split_at(size);
+                rest = tail;
+                data
+            };
+            let len = match packet.is_empty() {
+                true => self.last_frames,
+                false => MAX_PACKET_SAMPLES,
+            };
+            let decode_buf = &mut self.decode_buf[..len * channels.len()];
+            let decoded = decoder.decode_float(data, decode_buf, false)?;
+            match frames {
+                None => {
+                    frames = Some(decoded);
+                    out.resize(start + decoded * channel_count, 0.);
+                }
+                Some(frames) if frames != decoded => {
+                    return Err(anyhow!("multistream streams decoded to different lengths"))
+                }
+                Some(_) => {}
+            }
+            let decoded = &self.decode_buf[..decoded * channels.len()];
+            let frames = out[start..].chunks_exact_mut(channel_count);
+            for (frame, samples) in frames.zip(decoded.chunks_exact(channels.len())) {
+                for (&channel, sample) in channels.iter().zip(samples) {
+                    frame[channel] = *sample;
+                }
+            }
+        }
+        let frames = frames.unwrap_or_default();
+        if !packet.is_empty() && frames > 0 {
+            self.last_frames = frames;
+        }
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surround_round_trips_through_its_streams() {
+        let layout = ChannelLayout::Surround51;
+        let mut encoder = MultistreamEncoder::new(layout).unwrap();
+        // every channel at its own level, so a misrouted stream shows.
+        let frame: Vec<f32> = (0..FRAME_SAMPLES)
+            .flat_map(|_| (0..6).map(|channel| channel as f32 / 8.))
+            .collect();
+        let packet = encoder.encode(&frame).unwrap();
+
+        let mut decoder = MultistreamDecoder::new(layout).unwrap();
+        let mut decoded = Vec::new();
+        assert_eq!(
+            decoder.decode(&packet, &mut decoded).unwrap(),
+            FRAME_SAMPLES
+        );
+        for (got, want) in decoded.iter().zip(&frame) {
+            assert!((got - want).abs() < 0.02, "{got} != {want}");
+        }
+
+        let mut stereo = Vec::new();
+        layout.downmix(&decoded[..6], &mut stereo);
+        assert!(stereo[1] > stereo[0], "the right channels are louder");
+        assert_eq!("5.1".parse::<ChannelLayout>().unwrap(), layout);
+        assert_eq!(layout.to_string(), "5.1");
+    }
+}
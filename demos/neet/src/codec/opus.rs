@@ -1,20 +1,45 @@
-use std::{ops::ControlFlow, time::Duration};
+use std::{
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
-use tokio::sync::broadcast::{self, error::TryRecvError};
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
-use super::Codec;
+use super::{
+    budget::ErrorBudget,
+    multistream::{ChannelLayout, MultistreamDecoder, MultistreamEncoder},
+    Codec, CodecFactory, Decoder, Encoder,
+};
 use crate::{
-    audio::{AudioFormat, AudioSink, AudioSource},
-    media::{MediaFrame, MediaTrack, TrackKind},
+    audio::{
+        stretch, AudioFormat, AudioMode, AudioSink, AudioSource, Correction, DriftEstimator,
+        DEFAULT_LATENCY_BUDGET,
+    },
+    media::{MediaFrame, MediaTrack, TryRecvError},
+    stats::{Counter, Gauge, Level},
 };
 
 pub const OPUS_SAMPLE_RATE: u32 = 48_000;
 pub const OPUS_STREAM_PARAMS: AudioFormat = AudioFormat::new2(OPUS_SAMPLE_RATE, 2);
 
 const DURATION_20MS: Duration = Duration::from_millis(20);
+/// A gap between two ticks this long means playback stalled, and whatever
+/// piled up meanwhile is dropped rather than played late.
+const STALL: Duration = Duration::from_millis(200);
+/// Most lost frames concealed in a row. Concealment fades to silence anyway,
+/// so a longer gap is skipped over rather than played out as added latency.
+const MAX_CONCEALED_FRAMES: u32 = 5;
+/// Least audio skipped to resync. Less than this over the budget is left to
+/// the drift correction, which does not chop the audio.
+const MIN_RESYNC: Duration = Duration::from_millis(60);
+
+/// Number of encoded packets carved out of a single allocation. Packets are handed out
+/// as `Bytes` views into this arena; once all of them have been dropped downstream,
+/// `BytesMut::reserve` reclaims the allocation instead of allocating a new one.
+/// Kept below 64KiB so `BytesMut` remembers the full capacity when it reallocates.
+const PACKET_ARENA_FRAMES: usize = 32;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum OpusChannels {
@@ -31,26 +56,79 @@ impl From<OpusChannels> for ::opus::Channels {
     }
 }
 
+/// Makes Opus encoders and decoders, for catalogs' `opus`: plain Opus for
+/// mono and stereo, multistream for more channels.
+pub struct OpusFactory;
+
+impl CodecFactory for OpusFactory {
+    fn name(&self) -> &'static str {
+        "opus"
+    }
+
+    fn codec(&self, layout: ChannelLayout) -> Result<Codec> {
+        Ok(match layout {
+            ChannelLayout::Mono => Codec::Opus {
+                channels: OpusChannels::Mono,
+            },
+            ChannelLayout::Stereo => Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            layout => Codec::OpusMultistream { layout },
+        })
+    }
+
+    fn encoder(&self, codec: Codec, mode: AudioMode) -> Result<Box<dyn Encoder>> {
+        Ok(match codec {
+            Codec::Opus { channels } => Box::new(OpusEncoder::new(channels, mode)?),
+            codec => Box::new(MultistreamEncoder::new(codec.layout())?),
+        })
+    }
+
+    fn decoder(&self, codec: Codec) -> Result<Box<dyn Decoder>> {
+        Ok(Box::new(MultistreamDecoder::new(codec.layout())?))
+    }
+}
+
+/// Plays out a remote track. Despite the name it decodes any [`Codec`].
 pub struct MediaTrackOpusDecoder {
     track: MediaTrack,
-    decoder: opus::Decoder,
+    decoder: Box<dyn Decoder>,
     audio_buf: Vec<f32>,
     decode_buf: Vec<f32>,
     underflows: usize,
     remaining_silence_ticks: usize,
-    audio_format: AudioFormat,
+    last_tick: Option<Instant>,
+    stalls: Option<Counter>,
+    /// Samples to buffer before playing starts.
+    playout_delay: usize,
+    playing: bool,
+    /// Keeps the buffer level steady while the remote's clock drifts from ours.
+    drift: DriftEstimator,
+    correction: Correction,
+    buffered: Option<Gauge>,
+    level: Option<Level>,
+    taps: Vec<Box<dyn AudioSink>>,
+    errors: ErrorBudget,
+    latency: Option<LatencyBudget>,
+}
+
+/// How late the audio may play before the buffer is skipped to live.
+struct LatencyBudget {
+    limit: Duration,
+    /// The one-way delay of the frames arriving, 0 if unknown.
+    delay: Option<Gauge>,
+    /// The lowest delay seen, in microseconds. Only the delay above it
+    /// counts, so that clocks out of sync don't skew the budget.
+    floor: Option<u64>,
+    resyncs: Counter,
+    skipped_us: Counter,
 }
 
 impl MediaTrackOpusDecoder {
     pub fn new(track: MediaTrack) -> Result<Self> {
-        let channel_count = match track.codec() {
-            Codec::Opus { channels } => channels,
-        };
-        let audio_format = AudioFormat::new2(OPUS_SAMPLE_RATE, channel_count as u16);
-        let decoder =
-            opus::Decoder::new(OPUS_STREAM_PARAMS.sample_rate.0, channel_count.into()).unwrap();
-        let buffer_size = audio_format.sample_count(DURATION_20MS);
-        let decode_buf = vec![0.; buffer_size];
+        let decoder = track.codec().decoder()?;
+        let decode_buf =
+            Vec::with_capacity(track.codec().audio_format().sample_count(DURATION_20MS));
         let audio_buf = vec![];
         Ok(Self {
             track,
@@ -59,23 +137,162 @@ impl MediaTrackOpusDecoder {
             decode_buf,
             underflows: 0,
             remaining_silence_ticks: 0,
-            audio_format,
+            last_tick: None,
+            stalls: None,
+            playout_delay: 0,
+            playing: false,
+            drift: DriftEstimator::new("remote track"),
+            correction: Correction::None,
+            buffered: None,
+            level: None,
+            taps: Vec::new(),
+            errors: ErrorBudget::default(),
+            latency: Some(LatencyBudget {
+                limit: DEFAULT_LATENCY_BUDGET,
+                delay: None,
+                floor: None,
+                resyncs: Counter::default(),
+                skipped_us: Counter::default(),
+            }),
         })
     }
 
+    /// Skip the buffer back to the playout delay whenever the audio would
+    /// play more than `limit` later than it has to, counting how far `delay`,
+    /// the one-way delay of the frames arriving, is above the lowest seen
+    /// so far. Counts each resync in
+    /// `resyncs` and the audio skipped in `skipped_us`.
+    pub fn with_latency_budget(
+        mut self,
+        limit: Duration,
+        delay: Gauge,
+        resyncs: Counter,
+        skipped_us: Counter,
+    ) -> Self {
+        self.latency = Some(LatencyBudget {
+            limit,
+            delay: Some(delay),
+            floor: None,
+            resyncs,
+            skipped_us,
+        });
+        self
+    }
+
+    /// Play everything that arrives, however late.
+    pub fn without_latency_budget(mut self) -> Self {
+        self.latency = None;
+        self
+    }
+
+    /// Survive decoding errors within `budget`.
+    pub fn with_error_budget(mut self, budget: ErrorBudget) -> Self {
+        self.errors = budget;
+        self
+    }
+
+    /// Measure the level of the audio this track plays.
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Also feed the audio this track plays to `sink`, e.g. for transcription
+    /// or DTMF detection.
+    pub fn with_tap(mut self, sink: impl AudioSink) -> Self {
+        self.taps.push(Box::new(sink));
+        self
+    }
+
+    /// Buffer `delay` of audio before playing, and never shrink the buffer
+    /// below it.
+    pub fn with_playout_delay(mut self, delay: Duration) -> Self {
+        // audio_buf is always upmixed to stereo.
+        self.playout_delay = OPUS_STREAM_PARAMS.sample_count(delay);
+        self.drift = DriftEstimator::new("remote track").with_min_level(self.playout_delay / 2);
+        self
+    }
+
+    /// Count the times the buffer was reset after playback stalled.
+    pub fn with_stall_counter(mut self, counter: Counter) -> Self {
+        self.stalls = Some(counter);
+        self
+    }
+
+    /// Report how much decoded audio is waiting to be played, in microseconds.
+    pub fn with_buffer_gauge(mut self, gauge: Gauge) -> Self {
+        self.buffered = Some(gauge);
+        self
+    }
+
+    /// Decode `buf` and return the number of samples it added to the
+    /// (stereo) playout buffer.
     pub fn decode(&mut self, buf: &[u8]) -> Result<usize> {
-        let block_count = self
-            .decoder
-            .decode_float(buf, &mut self.decode_buf, false)?;
-        let sample_count = block_count * self.audio_format.channel_count as usize;
-        let decoded = &self.decode_buf[..sample_count];
-        // we need to upscale to two channels, AudioSource tick always expects stereo.
-        match self.audio_format.channel_count {
-            1 => self.audio_buf.extend(decoded.iter().flat_map(|s| [s, s])),
-            2 => self.audio_buf.extend(decoded),
-            _ => unreachable!(),
+        self.decode_buf.clear();
+        self.decoder.decode(buf, &mut self.decode_buf)?;
+        let before = self.audio_buf.len();
+        let decoded = &self.decode_buf;
+        // we need to convert to two channels, AudioSource tick always expects stereo.
+        match self.decoder.layout() {
+            ChannelLayout::Mono => self.audio_buf.extend(decoded.iter().flat_map(|s| [s, s])),
+            ChannelLayout::Stereo => self.audio_buf.extend(decoded),
+            layout => layout.downmix(decoded, &mut self.audio_buf),
         }
-        Ok(sample_count)
+        Ok(self.audio_buf.len() - before)
+    }
+
+    /// [`decode`](Self::decode), but a packet that fails to decode only
+    /// resets the decoder, while the error budget lasts.
+    fn decode_or_skip(&mut self, buf: &[u8]) -> Result<usize> {
+        match self.decode(buf) {
+            Ok(sample_count) => Ok(sample_count),
+            Err(err) => {
+                self.errors
+                    .spend(err.context("failed to decode a remote frame"))?;
+                self.decoder = self.track.codec().decoder()?;
+                Ok(0)
+            }
+        }
+    }
+
+    fn meter(&self, played: &[f32]) {
+        if let Some(level) = &self.level {
+            level.measure(played);
+        }
+    }
+
+    /// Skip to live if the buffer pushes the audio over the latency budget,
+    /// keeping at least `keep` samples.
+    fn resync(&mut self, keep: usize) {
+        let Some(latency) = &mut self.latency else {
+            return;
+        };
+        let buffered = OPUS_STREAM_PARAMS.duration_from_sample_count(self.audio_buf.len());
+        let delay = latency.delay.as_ref().map_or(0, Gauge::get);
+        let floor = match latency.floor {
+            _ if delay == 0 => 0,
+            Some(floor) if floor <= delay => floor,
+            _ => *latency.floor.insert(delay),
+        };
+        let delay = Duration::from_micros(delay - floor);
+        if delay + buffered <= latency.limit {
+            return;
+        }
+        // audio_buf is always upmixed to stereo, so skip whole frames.
+        let skip = self.audio_buf.len().saturating_sub(keep) & !1;
+        if skip < OPUS_STREAM_PARAMS.sample_count(MIN_RESYNC) {
+            return;
+        }
+        let skipped = OPUS_STREAM_PARAMS.duration_from_sample_count(skip);
+        info!(
+            delay = ?delay + buffered,
+            "resynced to live, skipped {} ms",
+            skipped.as_millis()
+        );
+        latency.resyncs.add(1);
+        latency.skipped_us.add(skipped.as_micros() as u64);
+        self.advance(skip);
+        self.drift.reset();
     }
 
     pub fn advance(&mut self, n: usize) {
@@ -85,20 +302,28 @@ impl MediaTrackOpusDecoder {
         self.audio_buf.copy_within(n.., 0);
         self.audio_buf.truncate(self.audio_buf.len() - n);
     }
-}
 
-impl AudioSource for MediaTrackOpusDecoder {
-    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+    /// [`AudioSource::tick`] with the clock at `now`, e.g. a replay's clock
+    /// rather than the wall clock.
+    pub fn tick_at(&mut self, buf: &mut [f32], now: Instant) -> Result<ControlFlow<(), usize>> {
         // decode everything that is ready to recv'd on the track channel.
+        let mut closed = false;
         loop {
             let (skipped_frames, payload) = match self.track.try_recv() {
                 Ok(frame) => {
                     let MediaFrame {
                         payload,
                         skipped_frames,
+                        reset,
                         ..
                     } = frame;
                     trace!("opus decoder: mediatrack recv frame");
+                    if reset {
+                        // a new encoder; what is buffered still plays out.
+                        debug!("remote stream restarted; resetting the decoder");
+                        self.decoder = self.track.codec().decoder()?;
+                        self.drift.reset();
+                    }
                     (skipped_frames, Some(payload))
                 }
                 Err(TryRecvError::Empty) => {
@@ -110,13 +335,21 @@ impl AudioSource for MediaTrackOpusDecoder {
                     (Some(count as u32), None)
                 }
                 Err(TryRecvError::Closed) => {
-                    info!("stop opus to audio loop: media track sender dropped");
-                    return Ok(ControlFlow::Break(()));
+                    closed = true;
+                    break;
                 }
             };
             if let Some(skipped_count) = skipped_frames {
-                for _ in 0..skipped_count {
-                    let sample_count = self.decode(&[])?;
+                // each concealed frame is as long as the last one decoded.
+                let concealed = skipped_count.min(MAX_CONCEALED_FRAMES);
+                if concealed < skipped_count {
+                    debug!(
+                        skipped_count,
+                        concealed, "long loss run; concealing only its start"
+                    );
+                }
+                for _ in 0..concealed {
+                    let sample_count = self.decode_or_skip(&[])?;
                     trace!(
                         "decoder: {sample_count} samples from skipped frames, now at {}",
                         self.audio_buf.len()
@@ -124,7 +357,7 @@ impl AudioSource for MediaTrackOpusDecoder {
                 }
             }
             if let Some(payload) = payload {
-                let sample_count = self.decode(&payload)?;
+                let sample_count = self.decode_or_skip(&payload)?;
                 trace!(
                     "decoder: {sample_count} samples from payload, now at {}",
                     self.audio_buf.len()
@@ -132,9 +365,47 @@ impl AudioSource for MediaTrackOpusDecoder {
             }
         }
 
+        // play out what is still buffered once the sender is gone, then stop.
+        if closed {
+            if self.audio_buf.is_empty() {
+                info!("stop opus to audio loop: media track sender dropped");
+                return Ok(ControlFlow::Break(()));
+            }
+            let count = buf.len().min(self.audio_buf.len());
+            buf[..count].copy_from_slice(&self.audio_buf[..count]);
+            self.advance(count);
+            return Ok(ControlFlow::Continue(count));
+        }
+
+        let stalled = self
+            .last_tick
+            .replace(now)
+            .is_some_and(|last| now - last > STALL);
+        let keep = self.playout_delay.max(buf.len());
+        if stalled && self.audio_buf.len() > keep {
+            warn!(
+                "playback stalled; resetting the jitter buffer to {:?}",
+                OPUS_STREAM_PARAMS.duration_from_sample_count(keep)
+            );
+            self.advance(self.audio_buf.len() - keep);
+            self.drift.reset();
+            if let Some(stalls) = &self.stalls {
+                stalls.add(1);
+            }
+        }
+
+        if !self.playing {
+            if self.audio_buf.len() < self.playout_delay.max(buf.len()) {
+                self.meter(&[]);
+                return Ok(ControlFlow::Continue(0));
+            }
+            self.playing = true;
+        }
+
         // TODO: right now a very hacky way to add some latency if we don't get enough packets.
         if self.remaining_silence_ticks > 0 {
             self.remaining_silence_ticks -= 1;
+            self.meter(&[]);
             return Ok(ControlFlow::Continue(0));
         } else if self.audio_buf.len() < buf.len() {
             self.underflows += 1;
@@ -142,71 +413,60 @@ impl AudioSource for MediaTrackOpusDecoder {
                 self.remaining_silence_ticks = 4;
                 tracing::debug!("increase silence");
                 self.underflows = 0;
+                self.drift.reset();
             }
+            self.meter(&[]);
             return Ok(ControlFlow::Continue(0));
         }
 
-        // TODO: a very hacky way to decrease latency if we buffered too much
-        if self
-            .audio_format
-            .duration_from_sample_count(self.audio_buf.len())
-            > Duration::from_secs(1)
-        {
-            self.advance(self.audio_format.sample_count(Duration::from_millis(500)));
+        self.resync(self.playout_delay.max(buf.len()));
+
+        // audio_buf is always upmixed to stereo, so a frame is two samples.
+        let count = buf.len();
+        let taken = match self.correction {
+            Correction::Insert => count - 2,
+            Correction::Drop if self.audio_buf.len() >= count + 2 => count + 2,
+            _ => count,
+        };
+        if taken == count {
+            buf.copy_from_slice(&self.audio_buf[..count]);
+        } else {
+            stretch(&self.audio_buf[..taken], buf);
+            self.drift.corrected((count as f64 - taken as f64) / 2.);
+        }
+        self.advance(taken);
+        if let Some(error) = self.drift.update(self.audio_buf.len() / 2, count / 2) {
+            self.correction = self.correction.next(error);
+        }
+        self.meter(&buf[..count]);
+        if count > 0 {
+            let played = &buf[..count];
+            self.taps.retain_mut(|tap| match tap.tick(played) {
+                Ok(ControlFlow::Continue(())) => true,
+                Ok(ControlFlow::Break(())) => {
+                    debug!("remove tap: closed");
+                    false
+                }
+                Err(err) => {
+                    warn!("remove tap: failed {err:?}");
+                    false
+                }
+            });
         }
 
-        let count = buf.len().min(self.audio_buf.len());
-        buf.copy_from_slice(&self.audio_buf[..count]);
-        self.advance(count);
+        if let Some(gauge) = &self.buffered {
+            // audio_buf is always upmixed to stereo.
+            let buffered = OPUS_STREAM_PARAMS.duration_from_sample_count(self.audio_buf.len());
+            gauge.set(buffered.as_micros() as u64);
+        }
 
         Ok(ControlFlow::Continue(count))
     }
 }
 
-pub struct MediaTrackOpusEncoder {
-    sender: broadcast::Sender<MediaFrame>,
-    encoder: OpusEncoder,
-}
-
-impl MediaTrackOpusEncoder {
-    pub fn new(track_channel_cap: usize, audio_format: AudioFormat) -> Result<(Self, MediaTrack)> {
-        debug_assert_eq!(audio_format.sample_rate.0, OPUS_SAMPLE_RATE);
-        let (sender, receiver) = broadcast::channel(track_channel_cap);
-        let channels = match audio_format.channel_count {
-            1 => OpusChannels::Mono,
-            2 => OpusChannels::Stereo,
-            _ => bail!("unsupported channel count"),
-        };
-        let track = MediaTrack::new(receiver, Codec::Opus { channels }, TrackKind::Audio);
-        let encoder = MediaTrackOpusEncoder {
-            sender,
-            encoder: OpusEncoder::new(channels),
-        };
-        Ok((encoder, track))
-    }
-}
-
-impl AudioSink for MediaTrackOpusEncoder {
-    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
-        for (payload, sample_count) in self.encoder.push_slice(buf) {
-            let payload_len = payload.len();
-            let frame = MediaFrame {
-                payload,
-                sample_count: Some(sample_count),
-                skipped_frames: None,
-                skipped_samples: None,
-            };
-            match self.sender.send(frame) {
-                Err(_) => {
-                    info!("closing encoder loop: track receiver closed.");
-                    return Ok(ControlFlow::Break(()));
-                }
-                Ok(_) => {
-                    trace!("sent opus {sample_count}S {payload_len}B")
-                }
-            }
-        }
-        Ok(ControlFlow::Continue(()))
+impl AudioSource for MediaTrackOpusDecoder {
+    fn tick(&mut self, buf: &mut [f32]) -> Result<ControlFlow<(), usize>> {
+        self.tick_at(buf, Instant::now())
     }
 }
 
@@ -214,61 +474,172 @@ pub struct OpusEncoder {
     encoder: opus::Encoder,
     samples: Vec<f32>,
     out_buf: BytesMut,
+    format: AudioFormat,
     samples_per_frame: usize,
 }
 
 impl OpusEncoder {
-    pub fn new(channels: OpusChannels) -> Self {
+    pub fn new(channels: OpusChannels, mode: AudioMode) -> Result<Self> {
         let format = AudioFormat::new2(OPUS_SAMPLE_RATE, channels as u16);
         let mut encoder =
-            opus::Encoder::new(OPUS_SAMPLE_RATE, channels.into(), opus::Application::Voip).unwrap();
+            opus::Encoder::new(OPUS_SAMPLE_RATE, channels.into(), mode.application())?;
+        if let Some(bitrate) = mode.bitrate() {
+            encoder.set_bitrate(opus::Bitrate::Bits(bitrate as i32))?;
+        }
         debug!(
             "initialized opus encoder: channels {} bitrate {:?} bandwidth {:?}",
             channels as u16,
             encoder.get_bitrate().unwrap(),
             encoder.get_bandwidth()
         );
-        let mut out_buf = BytesMut::new();
         let samples_per_frame = format.sample_count(DURATION_20MS);
-        out_buf.resize(samples_per_frame, 0);
-        let samples = Vec::new();
-        Self {
+        let out_buf = BytesMut::with_capacity(samples_per_frame * PACKET_ARENA_FRAMES);
+        let samples = Vec::with_capacity(samples_per_frame);
+        Ok(Self {
             encoder,
             out_buf,
             samples,
+            format,
             samples_per_frame,
-        }
+        })
     }
 
     pub fn push_slice<'a>(
         &'a mut self,
         samples: &'a [f32],
-    ) -> impl Iterator<Item = (Bytes, u32)> + 'a {
+    ) -> impl Iterator<Item = Result<(Bytes, u32)>> + 'a {
         let mut iter = samples.iter();
         std::iter::from_fn(move || {
             for sample in iter.by_ref() {
-                if let Some((payload, sample_count)) = self.push_sample(*sample) {
-                    return Some((payload, sample_count));
+                if let Some(packet) = self.push_sample(*sample).transpose() {
+                    return Some(packet);
                 }
             }
             None
         })
     }
 
-    pub fn push_sample(&mut self, sample: f32) -> Option<(Bytes, u32)> {
+    /// Add a sample, and return a packet once a frame is complete. A frame
+    /// that fails to encode is dropped and the encoder reset.
+    pub fn push_sample(&mut self, sample: f32) -> Result<Option<(Bytes, u32)>> {
         self.samples.push(sample);
-        if self.samples.len() >= self.samples_per_frame {
-            let sample_count = self.samples.len() as u32;
-            let size = self
-                .encoder
-                .encode_float(&self.samples, &mut self.out_buf)
-                .expect("failed to encode");
-            self.samples.clear();
-            let encoded = self.out_buf.split_to(size).freeze();
-            self.out_buf.resize(self.samples_per_frame, 0);
-            Some((encoded, sample_count))
-        } else {
-            None
+        if self.samples.len() < self.samples_per_frame {
+            return Ok(None);
         }
+        let sample_count = self.samples.len() as u32;
+        let encoded = encode_into(&mut self.encoder, &self.samples, &mut self.out_buf);
+        self.samples.clear();
+        match encoded {
+            Ok(packet) => Ok(Some((packet, sample_count))),
+            Err(err) => {
+                self.encoder.reset_state()?;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn frame_samples(&self) -> usize {
+        self.samples_per_frame
+    }
+
+    fn encode(&mut self, frame: &[f32]) -> Result<Bytes> {
+        encode_into(&mut self.encoder, frame, &mut self.out_buf)
+    }
+
+    fn set_bitrate(&mut self, bits_per_second: u32) -> Result<()> {
+        self.encoder
+            .set_bitrate(opus::Bitrate::Bits(bits_per_second as i32))?;
+        debug!("opus encoder bitrate set to {bits_per_second}");
+        Ok(())
+    }
+
+    /// Opus takes frames of 2.5, 5, 10, 20, 40 or 60 ms. Longer frames mean
+    /// fewer packets and less work per second of audio, but more latency.
+    fn set_frame_duration(&mut self, duration: Duration) {
+        self.samples_per_frame = self.format.sample_count(duration);
+        self.samples.reserve(self.samples_per_frame);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.samples.clear();
+        self.encoder.reset_state()?;
+        Ok(())
+    }
+}
+
+/// Encode `frame` into the arena `out_buf` and split the packet off it.
+fn encode_into(
+    encoder: &mut opus::Encoder,
+    frame: &[f32],
+    out_buf: &mut BytesMut,
+) -> Result<Bytes> {
+    // reuses the arena if there is room left (or all earlier packets were
+    // dropped), so steady-state encoding does not allocate per frame.
+    out_buf.resize(frame.len(), 0);
+    let encoded = encoder.encode_float(frame, out_buf);
+    let size = match encoded {
+        Ok(size) => size,
+        Err(err) => {
+            out_buf.clear();
+            return Err(anyhow!(err).context("failed to encode a frame"));
+        }
+    };
+    let packet = out_buf.split_to(size).freeze();
+    out_buf.clear();
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{self, OverflowPolicy, TrackKind};
+
+    #[test]
+    fn skips_to_live_once_over_the_latency_budget() {
+        let (_sender, receiver) = media::channel(8, OverflowPolicy::default(), Counter::default());
+        let codec = Codec::Opus {
+            channels: OpusChannels::Stereo,
+        };
+        let track = MediaTrack::new(receiver, codec, TrackKind::Audio);
+        let (delay, resyncs, skipped_us) =
+            (Gauge::default(), Counter::default(), Counter::default());
+        let mut decoder = MediaTrackOpusDecoder::new(track)
+            .unwrap()
+            .with_playout_delay(Duration::from_millis(40))
+            .with_latency_budget(
+                Duration::from_millis(500),
+                delay.clone(),
+                resyncs.clone(),
+                skipped_us.clone(),
+            );
+        let keep = decoder.playout_delay;
+        let ms = |ms| OPUS_STREAM_PARAMS.sample_count(Duration::from_millis(ms));
+
+        // a clock seconds off adds no delay by itself.
+        delay.set(3_100_000);
+        decoder.audio_buf = vec![0.; ms(300)];
+        decoder.resync(keep);
+        assert_eq!(decoder.audio_buf.len(), ms(300));
+
+        // within the budget.
+        delay.set(100_000);
+        decoder.resync(keep);
+        assert_eq!(decoder.audio_buf.len(), ms(300));
+
+        // a hiccup piled up audio: back to the playout delay.
+        decoder.audio_buf = vec![0.; ms(700)];
+        decoder.resync(keep);
+        assert_eq!(decoder.audio_buf.len(), keep);
+        assert_eq!(resyncs.get(), 1);
+        assert_eq!(skipped_us.get() / 1000, 660);
+
+        // a delay growing by far is not fixed by chopping the buffer either.
+        delay.set(900_000);
+        decoder.audio_buf = vec![0.; ms(80)];
+        decoder.resync(keep);
+        assert_eq!(decoder.audio_buf.len(), ms(80));
+        assert_eq!(resyncs.get(), 1);
     }
 }
@@ -0,0 +1,195 @@
+//! Encoding captured audio onto a media track, with whichever [`Codec`] the
+//! track carries.
+
+use std::{ops::ControlFlow, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tracing::{debug, info, trace};
+
+use super::{budget::ErrorBudget, multistream::ChannelLayout, BitrateTarget, Codec, Encoder};
+use crate::{
+    audio::{AudioMode, AudioSink, GreetingReader, ENGINE_FORMAT},
+    media::{
+        self, MediaFrame, MediaSender, MediaTrack, OverflowPolicy, PauseState, SendError, TrackKind,
+    },
+    stats::Counter,
+};
+
+/// Captured audio a mono track downmixes per tick without reallocating.
+const DOWNMIX_CAPACITY: Duration = Duration::from_millis(100);
+
+/// An audio sink that encodes captured audio to frames on a track.
+pub struct MediaTrackEncoder {
+    sender: MediaSender,
+    encoder: Box<dyn Encoder>,
+    /// The frame being collected.
+    samples: Vec<f32>,
+    /// The tick downmixed, for a mono track; stereo tracks take ticks as
+    /// they are.
+    mono: Option<Vec<f32>>,
+    bitrate: BitrateTarget,
+    /// The bitrate last set on the encoder, 0 if none.
+    applied: u32,
+    paused: PauseState,
+    errors: ErrorBudget,
+    /// Set if a greeting may take the microphone's place.
+    greeting: Option<GreetingReader>,
+}
+
+impl MediaTrackEncoder {
+    pub fn new(
+        codec: Codec,
+        mode: AudioMode,
+        track_channel_cap: usize,
+        overflow: OverflowPolicy,
+        dropped: Counter,
+        paused: PauseState,
+    ) -> Result<(Self, MediaTrack)> {
+        let (sender, receiver) = media::channel(track_channel_cap, overflow, dropped);
+        let track = MediaTrack::new(receiver, codec, TrackKind::Audio);
+        let encoder = codec.encoder(mode)?;
+        let mono = (codec.layout() == ChannelLayout::Mono)
+            .then(|| Vec::with_capacity(ENGINE_FORMAT.sample_count(DOWNMIX_CAPACITY) / 2));
+        let encoder = MediaTrackEncoder {
+            sender,
+            samples: Vec::with_capacity(encoder.frame_samples()),
+            mono,
+            encoder,
+            bitrate: BitrateTarget::default(),
+            applied: 0,
+            paused,
+            errors: ErrorBudget::default(),
+            greeting: None,
+        };
+        Ok((encoder, track))
+    }
+
+    /// Follow `bitrate`, where the codec has one.
+    pub fn with_bitrate(mut self, bitrate: BitrateTarget) -> Self {
+        self.bitrate = bitrate;
+        self
+    }
+
+    /// Survive encoding errors within `budget`.
+    pub fn with_error_budget(mut self, budget: ErrorBudget) -> Self {
+        self.errors = budget;
+        self
+    }
+
+    /// Encode the session's greeting instead of the captured audio while it
+    /// plays.
+    pub fn with_greeting(mut self, greeting: GreetingReader) -> Self {
+        self.greeting = Some(greeting);
+        self
+    }
+
+    /// Encode frames of `duration` rather than 20 ms, where the codec allows
+    /// it.
+    pub fn with_frame_duration(mut self, duration: Duration) -> Self {
+        self.encoder.set_frame_duration(duration);
+        self
+    }
+
+    /// Send the frame being collected, padded with silence, so that the end
+    /// of the audio isn't lost when the track closes.
+    fn flush(&mut self) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let frame_samples = self.encoder.frame_samples();
+        self.samples.resize(frame_samples, 0.);
+        let encoded = self.encoder.encode(&self.samples);
+        self.samples.clear();
+        match encoded {
+            Ok(payload) => {
+                let _ = self.send(payload, frame_samples);
+            }
+            Err(err) => debug!("failed to encode the last frame: {err:#}"),
+        }
+    }
+
+    fn send(&mut self, payload: Bytes, frame_samples: usize) -> Result<(), SendError> {
+        trace!("sent {frame_samples}S {}B", payload.len());
+        self.sender.send(MediaFrame {
+            payload,
+            sample_count: Some(frame_samples as u32),
+            skipped_frames: None,
+            skipped_samples: None,
+            reset: false,
+        })
+    }
+}
+
+impl AudioSink for MediaTrackEncoder {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        let finishing = self.sender.is_finishing();
+        if self.paused.is_paused() {
+            self.samples.clear();
+            return Ok(match finishing {
+                true => ControlFlow::Break(()),
+                false => ControlFlow::Continue(()),
+            });
+        }
+        let target = self.bitrate.get();
+        if target != 0 && target != self.applied {
+            self.encoder.set_bitrate(target)?;
+            self.applied = target;
+        }
+        let greeting = self.greeting.as_mut().and_then(GreetingReader::next_tick);
+        let buf = match &greeting {
+            Some((samples, range)) => &samples[range.clone()],
+            None => buf,
+        };
+        let flow = match self.mono.take() {
+            Some(mut mono) => {
+                mono.clear();
+                mono.extend(buf.chunks_exact(2).map(|frame| (frame[0] + frame[1]) / 2.));
+                let flow = self.encode(&mono);
+                self.mono = Some(mono);
+                flow
+            }
+            None => self.encode(buf),
+        };
+        if flow?.is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+        if finishing {
+            self.flush();
+            return Ok(ControlFlow::Break(()));
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+impl MediaTrackEncoder {
+    /// Send every frame `samples` completes, and keep the rest for the next.
+    fn encode(&mut self, samples: &[f32]) -> Result<ControlFlow<(), ()>> {
+        let mut rest = samples;
+        while !rest.is_empty() {
+            let frame_samples = self.encoder.frame_samples();
+            let needed = frame_samples - self.samples.len();
+            let (chunk, tail) = rest.split_at(needed.min(rest.len()));
+            self.samples.extend_from_slice(chunk);
+            rest = tail;
+            if self.samples.len() < frame_samples {
+                break;
+            }
+            let encoded = self.encoder.encode(&self.samples);
+            self.samples.clear();
+            let payload = match encoded {
+                Ok(payload) => payload,
+                Err(err) => {
+                    self.encoder.reset()?;
+                    self.errors.spend(err)?;
+                    continue;
+                }
+            };
+            if self.send(payload, frame_samples).is_err() {
+                info!("closing encoder loop: track receiver closed.");
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
@@ -0,0 +1,243 @@
+//! Optional TOML config file.
+//!
+//! Looked up at `--config <path>`, else `$XDG_CONFIG_HOME/neet/config.toml`
+//! (or `~/.config/neet/config.toml`). Command-line flags override file values.
+//!
+//! ```toml
+//! [transport]
+//! bind = "192.168.1.20:0"
+//! ip_version = "ipv4"
+//! congestion = "cubic"
+//! initial_rtt_ms = 300
+//! max_idle_timeout_ms = 30000
+//! keep_alive_ms = 5000
+//! connect_retries = 5
+//! retry_backoff_ms = 500
+//! wait_for_relay = true      # e.g. for a daemon started at boot
+//!
+//! [announcements]
+//! voice = "en-us"
+//! joined = "{name} is here"
+//! paused = ""   # don't announce pauses
+//!
+//! [live]
+//! log = "info,neet_cli::moq=debug"
+//! max_bitrate_kbps = 32
+//! volume_db = -6
+//!
+//! [routes]                  # output device by remote track
+//! audio = "Headphones"      # the remote's voice
+//! program = "/Loopback/"    # one of its extra inputs
+//!
+//! [matrix]                  # what each of a bridge's buses hears
+//! interpreter = ["host"]
+//! program = ["*"]           # everyone, recorded with --record
+//! ```
+//!
+//! The daemon re-reads the file on SIGHUP or `ctl reload`, and applies the
+//! `[live]` and `[routes]` sections to the calls already running. The other
+//! sections only apply to the calls that follow.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    audio::{Messages, RoutingMatrix},
+    moq::{CongestionController, IpVersion, TransportOptions},
+};
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub transport: TransportSection,
+    pub announcements: AnnouncementsSection,
+    pub live: LiveSection,
+    /// Output device by remote track, as for `--route`.
+    pub routes: BTreeMap<String, String>,
+    /// What each bus of a bridge hears, see [`RoutingMatrix`].
+    pub matrix: RoutingMatrix,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSection {
+    pub bind: Option<SocketAddr>,
+    pub ip_version: Option<IpVersion>,
+    pub congestion: Option<CongestionController>,
+    pub initial_rtt_ms: Option<u64>,
+    pub max_idle_timeout_ms: Option<u64>,
+    pub keep_alive_ms: Option<u64>,
+    pub connect_retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub wait_for_relay: bool,
+}
+
+impl TransportSection {
+    pub fn options(&self) -> TransportOptions {
+        TransportOptions {
+            bind: self.bind,
+            ip_version: self.ip_version,
+            congestion: self.congestion,
+            initial_rtt: self.initial_rtt_ms.map(Duration::from_millis),
+            max_idle_timeout: self.max_idle_timeout_ms.map(Duration::from_millis),
+            keep_alive: self.keep_alive_ms.map(Duration::from_millis),
+            connect_retries: self.connect_retries,
+            retry_backoff: self.retry_backoff_ms.map(Duration::from_millis),
+            wait_for_relay: self.wait_for_relay,
+        }
+    }
+}
+
+/// Voice and message templates for `--announce`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnouncementsSection {
+    pub voice: Option<String>,
+    pub joined: Option<String>,
+    pub left: Option<String>,
+    pub paused: Option<String>,
+    pub resumed: Option<String>,
+}
+
+impl AnnouncementsSection {
+    pub fn messages(&self) -> Messages {
+        let defaults = Messages::default();
+        let pick = |value: &Option<String>, default: String| value.clone().unwrap_or(default);
+        Messages {
+            joined: pick(&self.joined, defaults.joined),
+            left: pick(&self.left, defaults.left),
+            paused: pick(&self.paused, defaults.paused),
+            resumed: pick(&self.resumed, defaults.resumed),
+        }
+    }
+}
+
+/// Settings that can change while calls run.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveSection {
+    /// Log filter, as in `RUST_LOG`, which it overrides.
+    pub log: Option<String>,
+    /// Ceiling on the bitrate we send at.
+    pub max_bitrate_kbps: Option<u32>,
+    /// Playback volume of every call, in dB.
+    pub volume_db: Option<f32>,
+}
+
+impl LiveSection {
+    /// The log filter, checked.
+    pub fn log_filter(&self) -> Result<Option<EnvFilter>> {
+        self.log
+            .as_deref()
+            .map(|log| {
+                EnvFilter::try_new(log).with_context(|| format!("invalid log filter `{log}`"))
+            })
+            .transpose()
+    }
+
+    /// The bitrate ceiling in bits per second, zero if none.
+    pub fn bitrate_limit(&self) -> u32 {
+        // past u32::MAX, the ceiling is as good as none.
+        self.max_bitrate_kbps
+            .map_or(0, |kbps| kbps.saturating_mul(1000))
+    }
+}
+
+impl Config {
+    /// Load `path`, or the default location if it exists. An explicit path
+    /// that cannot be read is an error; a missing default file is not.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let config: Self = toml::from_str(&text)
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+        config
+            .live
+            .log_filter()
+            .with_context(|| format!("failed to parse config file {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+/// `$XDG_CONFIG_HOME/neet`, or `~/.config/neet`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("neet"))
+}
+
+/// `$XDG_DATA_HOME/neet`, or `~/.local/share/neet`.
+pub fn data_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+        })?;
+    Some(base.join("neet"))
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_transport_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [transport]
+            ip_version = "ipv6"
+            congestion = "new-reno"
+            keep_alive_ms = 5000
+            wait_for_relay = true
+            "#,
+        )
+        .unwrap();
+        let options = config.transport.options();
+        assert_eq!(options.ip_version, Some(IpVersion::Ipv6));
+        assert_eq!(options.congestion, Some(CongestionController::NewReno));
+        assert_eq!(options.keep_alive, Some(Duration::from_secs(5)));
+        assert_eq!(options.initial_rtt, None);
+        assert!(options.wait_for_relay);
+        assert!(toml::from_str::<Config>("[transport]\nbogus = 1").is_err());
+    }
+
+    #[test]
+    fn checks_live_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [live]
+            log = "debug"
+            max_bitrate_kbps = 32
+            "#,
+        )
+        .unwrap();
+        assert!(config.live.log_filter().unwrap().is_some());
+        assert_eq!(config.live.bitrate_limit(), 32_000);
+        assert_eq!(Config::default().live.bitrate_limit(), 0);
+        let huge: Config = toml::from_str("[live]\nmax_bitrate_kbps = 5000000").unwrap();
+        assert_eq!(huge.live.bitrate_limit(), u32::MAX);
+
+        let config: Config = toml::from_str("[live]\nlog = \"neet=loud\"").unwrap();
+        assert!(config.live.log_filter().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Saved contacts: `neet contacts add alice --session our-weekly`, then
+//! `neet call alice`.
+//!
+//! Kept at `$XDG_CONFIG_HOME/neet/contacts.toml`, a table per contact with
+//! their session, relay and the fingerprint of their identity key. Calling a
+//! contact with a fingerprint only plays a remote that proves it holds that
+//! key, see `--fingerprint`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::config_dir, identity::Fingerprint};
+
+const CONTACTS_FILE: &str = "contacts.toml";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Contact {
+    pub session: String,
+    /// The relay, if not the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+}
+
+/// The contacts file and what is in it.
+#[derive(Debug, Clone)]
+pub struct Contacts {
+    path: PathBuf,
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl Contacts {
+    /// Read the contacts at `path`, or the default location; none if the
+    /// file does not exist yet.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => default_path().context("cannot find a config directory for contacts")?,
+        };
+        let contacts = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("failed to parse contacts {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read contacts {}", path.display()))
+            }
+        };
+        Ok(Self { path, contacts })
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Contact> {
+        self.contacts.get(name).ok_or_else(|| {
+            anyhow!("no contact named `{name}`; add them with `neet contacts add {name}`")
+        })
+    }
+
+    /// The name the contact with `fingerprint` is saved as, if any.
+    pub fn find(&self, fingerprint: Fingerprint) -> Option<&str> {
+        self.iter()
+            .find(|(_, contact)| contact.fingerprint == Some(fingerprint))
+            .map(|(name, _)| name)
+    }
+
+    /// Save `contact` as `name`, replacing whoever was saved as that. True if
+    /// someone was.
+    pub fn add(&mut self, name: &str, contact: Contact) -> bool {
+        self.contacts.insert(name.to_string(), contact).is_some()
+    }
+
+    /// False if nobody was saved as `name`.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.contacts.remove(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Contact)> {
+        self.contacts
+            .iter()
+            .map(|(name, contact)| (name.as_str(), contact))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let text = toml::to_string(&self.contacts)?;
+        fs::write(&self.path, text)
+            .with_context(|| format!("failed to write contacts {}", self.path.display()))
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join(CONTACTS_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_and_finds_contacts() {
+        let path = std::env::temp_dir().join(format!("neet-contacts-{}.toml", std::process::id()));
+        let alice = Contact {
+            session: "our-weekly".into(),
+            relay: Some("https://relay.example/anon".into()),
+            fingerprint: Some("00112233445566778899aabbccddeeff".parse().unwrap()),
+        };
+        let mut contacts = Contacts::load(Some(&path)).unwrap();
+        assert!(!contacts.add("alice", alice.clone()));
+        assert!(!contacts.add(
+            "bob",
+            Contact {
+                session: "bob-and-me".into(),
+                relay: None,
+                fingerprint: None,
+            }
+        ));
+        contacts.save().unwrap();
+
+        let mut contacts = Contacts::load(Some(&path)).unwrap();
+        assert_eq!(contacts.get("alice").unwrap(), &alice);
+        assert_eq!(contacts.find(alice.fingerprint.unwrap()), Some("alice"));
+        assert!(contacts.remove("bob"));
+        assert!(contacts.get("bob").is_err());
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.contains("fingerprint = \"0011:2233:4455:6677:8899:aabb:ccdd:eeff\""));
+    }
+}
@@ -0,0 +1,1111 @@
+//! `neet daemon`: stays resident and places or answers calls on request.
+//!
+//! Requests come in on a Unix control socket, one per connection, as a JSON
+//! array of the same arguments as the command line on a single line
+//! (`["call", "--session", "demo"]`, `listen ...`, `join ...`, `bridge ...`,
+//! `hangup`, `scope`, `status`, `reload`). The reply is written back and the
+//! connection closed; `neet ctl` does both ends of that. The socket is only
+//! open to our own user. Several calls can run at once: calls on the same
+//! devices share one [`AudioContext`], each with its own mix. Under systemd
+//! the daemon reports readiness and status with sd-notify, and SIGHUP reloads
+//! the config file, applying its `[live]` section to the calls already
+//! running.
+//!
+//! With `--inbox` the daemon also follows its identity's inbox on a relay and
+//! rings for every `call --invite` that arrives there, until it is answered
+//! (`answer`), declined (`decline`) or the caller gives up.
+//!
+//! With `--wake-model` it keeps its devices open and takes requests spoken
+//! into the microphone too (see [`crate::wake`]).
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, oneshot},
+    task::AbortHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{
+    audio::{AudioContext, PanMode, SessionMix},
+    build_audio_config,
+    config::Config,
+    contacts::Contacts,
+    identity::Fingerprint,
+    moq::{watch_inbox, InboxEvent, Role, Standby, TransportOptions},
+    run_bridge_command, run_join, run_session, set_log_filter, AudioArgs, BridgeArgs, JoinArgs,
+    SessionArgs, SessionEnv,
+};
+
+const SOCKET_FILE: &str = "neet.sock";
+/// Requests waiting for the daemon loop.
+const REQUEST_QUEUE: usize = 8;
+/// How long calls get to hang up cleanly when the daemon stops.
+const STOP_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to wait before following the inbox again after losing it.
+const INBOX_RETRY: Duration = Duration::from_secs(5);
+/// How often the chime repeats while an invite rings.
+const RING_INTERVAL: Duration = Duration::from_secs(4);
+
+/// `$XDG_RUNTIME_DIR/neet.sock`, else in a directory of our own in the
+/// temporary directory (`neet-<uid>`).
+pub fn default_socket() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join(format!("neet-{}", uid())))
+        .join(SOCKET_FILE)
+}
+
+fn uid() -> u32 {
+    // SAFETY: getuid takes no arguments and always succeeds.
+    unsafe { libc::getuid() }
+}
+
+/// Create the directory of `socket` for our user alone, unless it exists,
+/// and refuse one that someone else owns or could write to.
+fn prepare_socket_dir(socket: &Path) -> Result<()> {
+    let Some(dir) = socket.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Ok(());
+    };
+    if !dir.exists() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let metadata = std::fs::metadata(dir)?;
+    // a shared directory such as /tmp is sticky, so nobody can replace our
+    // socket there.
+    let sticky = metadata.mode() & 0o1000 != 0;
+    if metadata.uid() != uid() && metadata.uid() != 0 {
+        bail!("{} belongs to another user", dir.display());
+    }
+    if metadata.mode() & 0o022 != 0 && !sticky {
+        bail!("{} is writable by other users", dir.display());
+    }
+    Ok(())
+}
+
+/// What can be asked of the daemon.
+#[derive(Parser, Debug)]
+#[command(
+    name = "neet ctl",
+    no_binary_name = true,
+    disable_help_subcommand = true
+)]
+enum Request {
+    /// Dial a listener
+    Call {
+        #[command(flatten)]
+        session: SessionArgs,
+        #[command(flatten)]
+        mix: MixArgs,
+    },
+    /// Wait for a caller
+    Listen {
+        #[command(flatten)]
+        session: SessionArgs,
+        #[command(flatten)]
+        mix: MixArgs,
+    },
+    /// Join a conference bridge
+    Join {
+        #[command(flatten)]
+        args: JoinArgs,
+        #[command(flatten)]
+        mix: MixArgs,
+    },
+    /// Run a conference bridge
+    Bridge(BridgeArgs),
+    /// Answer a ringing invite (the only one, or the named caller's)
+    Answer {
+        from: Option<String>,
+        #[command(flatten)]
+        mix: MixArgs,
+    },
+    /// Turn down a ringing invite (the only one, or the named caller's)
+    Decline { from: Option<String> },
+    /// End a call (the only one, or the named one)
+    Hangup { name: Option<String> },
+    /// Change the playback volume of a call, in dB (-inf mutes it)
+    Volume {
+        name: String,
+        #[arg(allow_hyphen_values = true)]
+        db: f32,
+    },
+    /// Play a call's remote TRACK (`audio`, or an extra input's name) on DEVICE, or back on the call's output device without one
+    Route {
+        name: String,
+        track: String,
+        device: Option<String>,
+    },
+    /// Draw a call's recent capture and playback audio into a PNG (needs --scope)
+    Scope {
+        name: String,
+        /// Where to write it (default: a timestamped file in the daemon's directory)
+        path: Option<PathBuf>,
+    },
+    /// Describe the running calls
+    Status,
+    /// Re-read the config file (also on SIGHUP)
+    Reload,
+}
+
+/// Devices and mixing for one call, overriding the daemon's audio options.
+#[derive(Args, Debug)]
+struct MixArgs {
+    /// Input device for this call (default: the daemon's)
+    #[arg(long)]
+    input_device: Option<String>,
+    /// Output device for this call (default: the daemon's)
+    #[arg(long)]
+    output_device: Option<String>,
+    /// Stereo placement of this call's remote audio (default: the daemon's --pan)
+    #[arg(long, allow_hyphen_values = true)]
+    pan: Option<PanMode>,
+    /// Playback volume of this call in dB (default: the config's, else 0)
+    #[arg(long, allow_hyphen_values = true, value_name = "DB")]
+    volume: Option<f32>,
+}
+
+/// The devices a call uses, which calls on the same ones share.
+type DeviceKey = (Option<String>, Option<String>);
+
+struct ActiveCall {
+    /// Tells this call apart from an earlier one under the same name.
+    id: u64,
+    description: String,
+    started: Instant,
+    /// Cancelled to hang up cleanly.
+    shutdown: CancellationToken,
+    task: AbortHandle,
+    devices: Option<DeviceKey>,
+    /// The call's own audio on those devices.
+    audio: Option<AudioContext>,
+    /// Its playback volume in dB, if given for it rather than by the config.
+    volume: Option<f32>,
+}
+
+/// A call that ended by itself: its name, id and outcome.
+type Finished = (String, u64, Result<()>);
+
+/// An invite waiting to be answered.
+struct Ringing {
+    session: String,
+    from: Fingerprint,
+    since: Instant,
+}
+
+pub struct Daemon {
+    config_path: Option<PathBuf>,
+    config: Config,
+    audio: AudioArgs,
+    /// Running calls by name: the session, or `<session>/<name>` for `join`.
+    calls: BTreeMap<String, ActiveCall>,
+    /// Open devices, while a call uses them.
+    devices: HashMap<DeviceKey, AudioContext>,
+    next_id: u64,
+    finished: mpsc::UnboundedSender<Finished>,
+    /// The relay to follow our inbox on, and whose inbox it is.
+    inbox: Option<(Url, Fingerprint)>,
+    /// Invites ringing, by caller: their contact name, else their fingerprint.
+    ringing: BTreeMap<String, Ringing>,
+    /// Connections kept warm for calls.
+    standby: Vec<Standby>,
+    /// The whisper model to hear spoken requests with.
+    #[cfg(feature = "wake-word")]
+    wake_model: Option<PathBuf>,
+    /// The devices spoken requests come from, kept open without a call.
+    listening: Option<DeviceKey>,
+}
+
+impl Daemon {
+    pub fn new(config_path: Option<PathBuf>, config: Config, audio: AudioArgs) -> Self {
+        Self {
+            config_path,
+            config,
+            audio,
+            calls: BTreeMap::new(),
+            devices: HashMap::new(),
+            next_id: 0,
+            finished: mpsc::unbounded_channel().0,
+            inbox: None,
+            ringing: BTreeMap::new(),
+            standby: Vec::new(),
+            #[cfg(feature = "wake-word")]
+            wake_model: None,
+            listening: None,
+        }
+    }
+
+    /// Start calls that lead where one of `standby` does on it.
+    pub fn with_standby(mut self, standby: Vec<Standby>) -> Self {
+        self.standby = standby;
+        self
+    }
+
+    /// Ring for invites to `owner` on `relay`.
+    pub fn with_inbox(mut self, relay: Url, owner: Fingerprint) -> Self {
+        self.inbox = Some((relay, owner));
+        self
+    }
+
+    /// Take requests spoken into the microphone, heard with the whisper
+    /// `model`.
+    #[cfg(feature = "wake-word")]
+    pub fn with_wake_word(mut self, model: PathBuf) -> Self {
+        self.wake_model = Some(model);
+        self
+    }
+
+    /// Serve requests on `socket` until SIGTERM or Ctrl+C.
+    pub async fn run(mut self, socket: &Path) -> Result<()> {
+        if socket.exists() {
+            // a daemon that is still running would answer.
+            if UnixStream::connect(socket).await.is_ok() {
+                bail!("another daemon is listening on {}", socket.display());
+            }
+            std::fs::remove_file(socket)?;
+        }
+        prepare_socket_dir(socket)?;
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("failed to listen on {}", socket.display()))?;
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+        let mut hangups = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let (requests_tx, mut requests) = mpsc::channel(REQUEST_QUEUE);
+        let (finished_tx, mut finished) = mpsc::unbounded_channel();
+        self.finished = finished_tx;
+        let (invites_tx, mut invites) = mpsc::unbounded_channel();
+        let inbox = self.inbox.clone().map(|(relay, owner)| {
+            info!(%relay, %owner, "ringing for invites");
+            tokio::spawn(follow_inbox(
+                relay,
+                self.config.transport.options(),
+                owner,
+                invites_tx,
+            ))
+        });
+        let (spoken_tx, mut spoken) = mpsc::unbounded_channel::<String>();
+        #[cfg(feature = "wake-word")]
+        self.listen_for_requests(spoken_tx).await?;
+        #[cfg(not(feature = "wake-word"))]
+        drop(spoken_tx);
+
+        info!(socket = %socket.display(), "daemon ready");
+        notify("READY=1\nSTATUS=idle");
+        let mut ring = tokio::time::interval(RING_INTERVAL);
+        loop {
+            select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) if from_us(&stream) => {
+                        tokio::spawn(serve_connection(stream, requests_tx.clone()));
+                    }
+                    Ok(_) => warn!("refused a control connection from another user"),
+                    Err(err) => warn!("failed to accept control connection: {err}"),
+                },
+                Some((request, reply)) = requests.recv() => {
+                    let response = self.handle(request).await;
+                    let _ = reply.send(response);
+                }
+                Some(event) = invites.recv() => {
+                    if matches!(event, InboxEvent::Invited { .. }) {
+                        ring.reset_immediately();
+                    }
+                    self.invited(event);
+                }
+                _ = ring.tick(), if !self.ringing.is_empty() => self.ring().await,
+                Some(line) = spoken.recv() => self.spoken(&line).await,
+                Some((name, id, result)) = finished.recv() => {
+                    if self.calls.get(&name).is_some_and(|call| call.id == id) {
+                        let call = self.remove(&name);
+                        match result {
+                            Ok(()) => info!(call = call.description, "call ended"),
+                            Err(err) => warn!(call = call.description, "call failed: {err:#}"),
+                        }
+                    }
+                }
+                _ = hangups.recv() => {
+                    if let Err(err) = self.reload() {
+                        warn!("{err:#}");
+                    }
+                }
+                _ = terminate.recv() => break,
+                _ = tokio::signal::ctrl_c() => break,
+            }
+        }
+
+        info!("daemon stopping");
+        notify("STOPPING=1");
+        if let Some(inbox) = inbox {
+            inbox.abort();
+        }
+        for call in self.calls.values() {
+            call.shutdown.cancel();
+        }
+        let hung_up = async {
+            while !self.calls.is_empty() {
+                let Some((name, id, _)) = finished.recv().await else {
+                    break;
+                };
+                if self.calls.get(&name).is_some_and(|call| call.id == id) {
+                    self.calls.remove(&name);
+                }
+            }
+        };
+        if tokio::time::timeout(STOP_TIMEOUT, hung_up).await.is_err() {
+            for call in self.calls.values() {
+                call.task.abort();
+            }
+        }
+        let _ = std::fs::remove_file(socket);
+        Ok(())
+    }
+
+    async fn handle(&mut self, args: Vec<String>) -> String {
+        let request = match Request::try_parse_from(args) {
+            Ok(request) => request,
+            Err(err) => return err.to_string(),
+        };
+        let result = match request {
+            Request::Call { session, mix } => {
+                let name = session.label().to_string();
+                let description = format!("call {name}");
+                self.start_session(Role::Caller, name, description, session, mix)
+                    .await
+            }
+            Request::Listen { session, mix } => {
+                let name = session.label().to_string();
+                let description = format!("listen {name}");
+                self.start_session(Role::Listener, name, description, session, mix)
+                    .await
+            }
+            Request::Answer { from, mix } => self.answer(from, mix).await,
+            Request::Decline { from } => self.take_invite(from).map(|(from, ringing)| {
+                info!(from, session = ringing.session, "declined");
+                self.close_unused_devices();
+                self.notify_status();
+                format!("declined {from}")
+            }),
+            Request::Join { args, mix } => {
+                let name = format!("{}/{}", args.session.label(), args.name);
+                let description = format!("join {} as {}", args.session.label(), args.name);
+                self.start_audio(
+                    name,
+                    description,
+                    mix,
+                    |audio_args, config, env| async move {
+                        run_join(args, audio_args, &config, env).await
+                    },
+                )
+                .await
+            }
+            Request::Bridge(args) => {
+                let name = args.session.clone();
+                let description = format!("bridge {name}");
+                let config = self.config.clone();
+                let shutdown = CancellationToken::new();
+                let task = {
+                    let shutdown = shutdown.clone();
+                    async move {
+                        select! {
+                            result = run_bridge_command(args, &config) => result,
+                            _ = shutdown.cancelled() => Ok(()),
+                        }
+                    }
+                };
+                self.start(name, description, None, None, shutdown, task)
+            }
+            Request::Hangup { name } => self.hangup(name),
+            Request::Volume { name, db } => match self.calls.get_mut(&name) {
+                Some(ActiveCall {
+                    audio: Some(audio),
+                    volume,
+                    ..
+                }) => {
+                    audio.gain().set_db(db);
+                    *volume = Some(db);
+                    Ok(format!("{name} at {db} dB"))
+                }
+                Some(_) => Err(anyhow!("{name} plays no audio")),
+                None => Err(anyhow!("no call named {name}")),
+            },
+            Request::Route {
+                name,
+                track,
+                device,
+            } => match self.calls.get(&name) {
+                Some(ActiveCall {
+                    audio: Some(audio), ..
+                }) => {
+                    audio.set_route(&track, device.as_deref());
+                    let device = device.as_deref().unwrap_or("the call's output");
+                    Ok(format!("{track} of {name} on {device}"))
+                }
+                Some(_) => Err(anyhow!("{name} plays no audio")),
+                None => Err(anyhow!("no call named {name}")),
+            },
+            Request::Scope { name, path } => match self.calls.get(&name) {
+                Some(ActiveCall {
+                    devices: Some(key), ..
+                }) => match self.devices.get(key) {
+                    Some(audio) => audio
+                        .dump_scope(path)
+                        .await
+                        .map(|path| format!("wrote {}", path.display())),
+                    None => Err(anyhow!("{name} has no open devices")),
+                },
+                Some(_) => Err(anyhow!("{name} plays no audio")),
+                None => Err(anyhow!("no call named {name}")),
+            },
+            Request::Status => Ok(self.status()),
+            Request::Reload => self.reload().map(|()| "config reloaded".to_string()),
+        };
+        match result {
+            Ok(reply) => format!("ok: {reply}\n"),
+            Err(err) => format!("error: {err:#}\n"),
+        }
+    }
+
+    /// Start a call or listen in `session`.
+    async fn start_session(
+        &mut self,
+        role: Role,
+        name: String,
+        description: String,
+        session: SessionArgs,
+        mix: MixArgs,
+    ) -> Result<String> {
+        self.start_audio(
+            name,
+            description,
+            mix,
+            |audio_args, config, env| async move {
+                run_session(role, session, audio_args, &config, None, env, None).await
+            },
+        )
+        .await
+    }
+
+    /// Open the daemon's devices and listen on them for spoken requests,
+    /// with `--wake-model`.
+    #[cfg(feature = "wake-word")]
+    async fn listen_for_requests(&mut self, requests: mpsc::UnboundedSender<String>) -> Result<()> {
+        let Some(model) = self.wake_model.clone() else {
+            return Ok(());
+        };
+        let key = (
+            self.audio.input_device.clone(),
+            self.audio.output_device.clone(),
+        );
+        let devices = AudioContext::new(build_audio_config(&self.audio, &self.config))
+            .await
+            .context("failed to open the audio devices")?;
+        devices
+            .add_capture_sink(crate::wake::listen(&model, requests)?)
+            .await?;
+        self.devices.insert(key.clone(), devices);
+        self.listening = Some(key);
+        info!(model = %model.display(), "say \"hey neet\" and a request");
+        Ok(())
+    }
+
+    /// Carry out a spoken request, and beep once if it went through, three
+    /// times if it didn't.
+    async fn spoken(&mut self, line: &str) {
+        let args = line.split_whitespace().map(str::to_string).collect();
+        let reply = self.handle(args).await;
+        let done = reply.starts_with("ok");
+        if done {
+            info!(request = line, "{}", reply.trim_end());
+        } else {
+            warn!(request = line, "{}", reply.trim_end());
+        }
+        let devices = self
+            .listening
+            .as_ref()
+            .and_then(|key| self.devices.get(key));
+        if let Some(devices) = devices {
+            devices.beep(if done { 1 } else { 3 });
+        }
+    }
+
+    /// Ring for an invite, or stop ringing once the caller gives up.
+    fn invited(&mut self, event: InboxEvent) {
+        match event {
+            // announced again after we lost the inbox, but already answered.
+            InboxEvent::Invited { invite, .. } if self.calls.contains_key(&invite.session) => {
+                return
+            }
+            InboxEvent::Invited { from, invite } => {
+                let caller = match Contacts::load(None) {
+                    Ok(contacts) => contacts.find(from).map(str::to_string),
+                    Err(err) => {
+                        warn!("{err:#}");
+                        None
+                    }
+                }
+                .unwrap_or_else(|| from.to_string());
+                info!(
+                    from = caller,
+                    session = invite.session,
+                    "incoming call; `neet ctl answer` to take it"
+                );
+                self.ringing.insert(
+                    caller,
+                    Ringing {
+                        session: invite.session,
+                        from,
+                        since: Instant::now(),
+                    },
+                );
+            }
+            InboxEvent::Withdrawn { session } => {
+                let Some(caller) = self
+                    .ringing
+                    .iter()
+                    .find(|(_, ringing)| ringing.session == session)
+                    .map(|(caller, _)| caller.clone())
+                else {
+                    return;
+                };
+                self.ringing.remove(&caller);
+                info!(from = caller, "missed call");
+                self.close_unused_devices();
+            }
+        }
+        self.notify_status();
+    }
+
+    /// Chime on the daemon's own devices, opening them unless a call or
+    /// spoken requests already have.
+    async fn ring(&mut self) {
+        let key = (
+            self.audio.input_device.clone(),
+            self.audio.output_device.clone(),
+        );
+        if !self.devices.contains_key(&key) {
+            match AudioContext::new(build_audio_config(&self.audio, &self.config)).await {
+                Ok(devices) => {
+                    self.devices.insert(key.clone(), devices);
+                }
+                Err(err) => {
+                    warn!("failed to open the audio devices to ring: {err:#}");
+                    return;
+                }
+            }
+        }
+        self.devices[&key].chime();
+    }
+
+    /// Stop ringing for the invite from `from`, or the only one.
+    fn take_invite(&mut self, from: Option<String>) -> Result<(String, Ringing)> {
+        let from = match from {
+            Some(from) if self.ringing.contains_key(&from) => from,
+            Some(from) => bail!("no invite from {from}"),
+            None => match self.ringing.len() {
+                0 => bail!("nobody is calling"),
+                1 => self.ringing.keys().next().unwrap().clone(),
+                _ => {
+                    let callers: Vec<_> = self.ringing.keys().map(String::as_str).collect();
+                    bail!("several callers; name one of {}", callers.join(", "));
+                }
+            },
+        };
+        let ringing = self.ringing.remove(&from).expect("the invite is ringing");
+        Ok((from, ringing))
+    }
+
+    /// Listen in the session of an invite, for whoever signed it.
+    async fn answer(&mut self, from: Option<String>, mix: MixArgs) -> Result<String> {
+        let Some((relay, _)) = self.inbox.clone() else {
+            bail!("not following an inbox; start the daemon with --inbox");
+        };
+        let (from, ringing) = self.take_invite(from)?;
+        let mut session = SessionArgs::defaults(&ringing.session);
+        session.relay = vec![relay];
+        session.fingerprint = Some(ringing.from);
+        let description = format!("answer {from}");
+        let started = self
+            .start_session(Role::Listener, ringing.session, description, session, mix)
+            .await;
+        self.close_unused_devices();
+        started
+    }
+
+    /// Start a call on the devices `mix` selects, opening them unless another
+    /// call already uses them.
+    async fn start_audio<F>(
+        &mut self,
+        name: String,
+        description: String,
+        mix: MixArgs,
+        session: impl FnOnce(AudioArgs, Config, SessionEnv) -> F,
+    ) -> Result<String>
+    where
+        F: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        self.check_free(&name)?;
+        let mut audio_args = self.audio.clone();
+        if mix.input_device.is_some() {
+            audio_args.input_device = mix.input_device;
+        }
+        if mix.output_device.is_some() {
+            audio_args.output_device = mix.output_device;
+        }
+        let key = (
+            audio_args.input_device.clone(),
+            audio_args.output_device.clone(),
+        );
+        let devices = match self.devices.get(&key) {
+            Some(devices) => devices.clone(),
+            None => {
+                let devices = AudioContext::new(build_audio_config(&audio_args, &self.config))
+                    .await
+                    .context("failed to open the audio devices")?;
+                self.devices.insert(key.clone(), devices.clone());
+                devices
+            }
+        };
+        let session_mix = SessionMix::new(&name, mix.pan.unwrap_or(audio_args.pan));
+        let live = &self.config.live;
+        session_mix
+            .gain
+            .set_db(mix.volume.or(live.volume_db).unwrap_or(0.));
+        let audio = devices.session(session_mix);
+        audio.bitrate().set_limit(live.bitrate_limit());
+        let env = SessionEnv {
+            shared: Some(audio.clone()),
+            shutdown: CancellationToken::new(),
+            peer: None,
+            standby: self.standby.clone(),
+        };
+        let shutdown = env.shutdown.clone();
+        let task = session(audio_args, self.config.clone(), env);
+        let started = self.start(
+            name.clone(),
+            description,
+            Some(key),
+            Some(audio),
+            shutdown,
+            task,
+        );
+        if let Some(call) = self.calls.get_mut(&name) {
+            call.volume = mix.volume;
+        }
+        started
+    }
+
+    fn start(
+        &mut self,
+        name: String,
+        description: String,
+        devices: Option<DeviceKey>,
+        audio: Option<AudioContext>,
+        shutdown: CancellationToken,
+        task: impl std::future::Future<Output = Result<()>> + Send + 'static,
+    ) -> Result<String> {
+        self.check_free(&name)?;
+        info!(call = description, "starting call");
+        self.next_id += 1;
+        let id = self.next_id;
+        let finished = self.finished.clone();
+        let task = tokio::spawn({
+            let name = name.clone();
+            async move {
+                let _ = finished.send((name, id, task.await));
+            }
+        });
+        self.calls.insert(
+            name,
+            ActiveCall {
+                id,
+                description: description.clone(),
+                started: Instant::now(),
+                shutdown,
+                task: task.abort_handle(),
+                devices,
+                audio,
+                volume: None,
+            },
+        );
+        self.notify_status();
+        Ok(description)
+    }
+
+    fn check_free(&self, name: &str) -> Result<()> {
+        match self.calls.get(name) {
+            Some(call) => bail!("busy with {}; hang up first", call.description),
+            None => Ok(()),
+        }
+    }
+
+    fn hangup(&mut self, name: Option<String>) -> Result<String> {
+        let name = match name {
+            Some(name) if self.calls.contains_key(&name) => name,
+            Some(name) => bail!("no call named {name}"),
+            None => match self.calls.len() {
+                0 => return Ok("no call".to_string()),
+                1 => self.calls.keys().next().unwrap().clone(),
+                _ => {
+                    let names: Vec<_> = self.calls.keys().map(String::as_str).collect();
+                    bail!("several calls; name one of {}", names.join(", "));
+                }
+            },
+        };
+        let call = self.remove(&name);
+        call.shutdown.cancel();
+        Ok(format!("hung up {}", call.description))
+    }
+
+    /// Forget a call, and close its devices unless they are still needed.
+    fn remove(&mut self, name: &str) -> ActiveCall {
+        let call = self.calls.remove(name).expect("the call is running");
+        self.close_unused_devices();
+        self.notify_status();
+        call
+    }
+
+    /// Close the devices no call uses, no spoken requests come from and no
+    /// invite rings on.
+    fn close_unused_devices(&mut self) {
+        let ring = (!self.ringing.is_empty()).then(|| {
+            (
+                self.audio.input_device.clone(),
+                self.audio.output_device.clone(),
+            )
+        });
+        let (calls, listening) = (&self.calls, &self.listening);
+        self.devices.retain(|key, _| {
+            listening.as_ref() == Some(key)
+                || ring.as_ref() == Some(key)
+                || calls
+                    .values()
+                    .any(|call| call.devices.as_ref() == Some(key))
+        });
+    }
+
+    fn status(&self) -> String {
+        if self.calls.is_empty() && self.ringing.is_empty() {
+            return "idle".to_string();
+        }
+        let calls = self.calls.values().map(|call| {
+            let mut status = format!(
+                "{} for {}s",
+                call.description,
+                call.started.elapsed().as_secs()
+            );
+            let routes = call.audio.as_ref().map(AudioContext::routes);
+            for (track, device) in routes.unwrap_or_default() {
+                status.push_str(&format!(", {track} on {device}"));
+            }
+            status
+        });
+        let ringing = self.ringing.iter().map(|(from, ringing)| {
+            format!("{from} ringing for {}s", ringing.since.elapsed().as_secs())
+        });
+        calls.chain(ringing).collect::<Vec<_>>().join("; ")
+    }
+
+    fn notify_status(&self) {
+        notify(&format!("STATUS={}", self.status()));
+    }
+
+    /// Re-read the config file: its `[live]` and `[routes]` sections for the running calls
+    /// too, the rest for the calls that follow.
+    fn reload(&mut self) -> Result<()> {
+        notify("RELOADING=1");
+        let result = Config::load(self.config_path.as_deref());
+        notify("READY=1");
+        let config = result.context("keeping the previous config")?;
+        self.apply_live(&config)?;
+        if config.transport != self.config.transport && !self.calls.is_empty() {
+            warn!("transport settings changed; running calls keep the old ones");
+        }
+        self.config = config;
+        info!("config reloaded");
+        Ok(())
+    }
+
+    fn apply_live(&self, config: &Config) -> Result<()> {
+        let live = &config.live;
+        if let Some(filter) = live.log_filter()? {
+            set_log_filter(filter)?;
+        }
+        for call in self.calls.values() {
+            let Some(audio) = &call.audio else {
+                continue;
+            };
+            audio.bitrate().set_limit(live.bitrate_limit());
+            // a call's own volume outlasts the config's.
+            if call.volume.is_none() {
+                audio.gain().set_db(live.volume_db.unwrap_or(0.));
+            }
+        }
+        // routes changed with `ctl route` stay, unless the file changes them.
+        for (track, device) in changed_routes(&self.config.routes, &config.routes) {
+            for audio in self.devices.values() {
+                audio.set_route(track, device);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The tracks whose output device differs between `old` and `new`, and
+/// where they go now (the call's own device without one).
+fn changed_routes<'a>(
+    old: &'a BTreeMap<String, String>,
+    new: &'a BTreeMap<String, String>,
+) -> Vec<(&'a str, Option<&'a str>)> {
+    let tracks = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+    tracks
+        .into_iter()
+        .filter(|track| old.get(*track) != new.get(*track))
+        .map(|track| (track.as_str(), new.get(track).map(String::as_str)))
+        .collect()
+}
+
+/// Follow the inbox of `owner`, reconnecting whenever the relay drops us.
+async fn follow_inbox(
+    relay: Url,
+    transport: TransportOptions,
+    owner: Fingerprint,
+    invites: mpsc::UnboundedSender<InboxEvent>,
+) {
+    loop {
+        match watch_inbox(relay.clone(), transport.clone(), owner, invites.clone()).await {
+            Ok(()) => return,
+            Err(err) => warn!("lost the inbox: {err:#}; retrying"),
+        }
+        tokio::time::sleep(INBOX_RETRY).await;
+    }
+}
+
+fn from_us(stream: &UnixStream) -> bool {
+    stream.peer_cred().is_ok_and(|peer| peer.uid() == uid())
+}
+
+/// Read one request line, pass it to the daemon and write back the reply.
+async fn serve_connection(
+    stream: UnixStream,
+    requests: mpsc::Sender<(Vec<String>, oneshot::Sender<String>)>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    if let Err(err) = BufReader::new(reader).read_line(&mut line).await {
+        warn!("failed to read control request: {err}");
+        return;
+    }
+    let args = match decode_request(&line) {
+        Ok(args) => args,
+        Err(err) => {
+            let _ = writer
+                .write_all(format!("error: {err:#}\n").as_bytes())
+                .await;
+            return;
+        }
+    };
+    let (reply_tx, reply) = oneshot::channel();
+    if requests.send((args, reply_tx)).await.is_err() {
+        return;
+    }
+    if let Ok(reply) = reply.await {
+        let _ = writer.write_all(reply.as_bytes()).await;
+    }
+}
+
+/// Send `request` to the daemon at `socket` and return its reply.
+pub async fn send_request(socket: &Path, request: &[String]) -> Result<String> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("no daemon listening on {}", socket.display()))?;
+    stream.write_all(encode_request(request).as_bytes()).await?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+/// `request` as a line of the control protocol: its arguments as a JSON
+/// array, so that they keep their spaces.
+fn encode_request(request: &[String]) -> String {
+    let mut line = serde_json::to_string(request).expect("strings serialize");
+    line.push('\n');
+    line
+}
+
+fn decode_request(line: &str) -> Result<Vec<String>> {
+    serde_json::from_str(line).context("malformed request; expected a JSON array of arguments")
+}
+
+/// Tell systemd about our state, if it is listening (`Type=notify`).
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        let path = path.to_string_lossy();
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            _ => socket.send_to(state.as_bytes(), &*path),
+        }
+    });
+    if let Err(err) = result {
+        warn!("sd-notify failed: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct Audio {
+        #[command(flatten)]
+        audio: AudioArgs,
+    }
+
+    fn daemon() -> Daemon {
+        let audio = Audio::parse_from(["neet"]).audio;
+        Daemon::new(None, Config::default(), audio)
+    }
+
+    fn ring(daemon: &mut Daemon, from: &str) {
+        let ringing = Ringing {
+            session: format!("{from}-session"),
+            from: Fingerprint::of(&ed25519_dalek::SigningKey::from_bytes(&[1; 32]).verifying_key()),
+            since: Instant::now(),
+        };
+        daemon.ringing.insert(from.to_string(), ringing);
+    }
+
+    #[test]
+    fn arguments_keep_their_spaces() {
+        let request = [
+            "listen",
+            "--auto-answer",
+            "--session",
+            "front door",
+            "--greeting",
+            "hi there.wav",
+        ]
+        .map(str::to_string);
+        let line = encode_request(&request);
+        assert_eq!(line.lines().count(), 1);
+        let args = decode_request(&line).unwrap();
+        assert_eq!(args, request);
+        let Request::Listen { session, .. } = Request::try_parse_from(args).unwrap() else {
+            panic!("not a listen request");
+        };
+        assert_eq!(session.label(), "front door");
+
+        assert!(decode_request("listen --session door\n").is_err());
+        assert!(Request::try_parse_from(["volume", "door", "loud"]).is_err());
+        assert!(Request::try_parse_from(["explode"]).is_err());
+        let Request::Volume { db, .. } =
+            Request::try_parse_from(["volume", "door", "-inf"]).unwrap()
+        else {
+            panic!("not a volume request");
+        };
+        assert_eq!(db, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn takes_the_only_invite_or_the_named_one() {
+        let mut daemon = daemon();
+        assert!(daemon.take_invite(None).is_err());
+        ring(&mut daemon, "alice");
+        ring(&mut daemon, "bob");
+        let Err(err) = daemon.take_invite(None) else {
+            panic!("took one of several invites");
+        };
+        assert_eq!(err.to_string(), "several callers; name one of alice, bob");
+        assert!(daemon.take_invite(Some("carol".to_string())).is_err());
+        let (from, ringing) = daemon.take_invite(Some("bob".to_string())).unwrap();
+        assert_eq!(
+            (from.as_str(), ringing.session.as_str()),
+            ("bob", "bob-session")
+        );
+        let (from, _) = daemon.take_invite(None).unwrap();
+        assert_eq!(from, "alice");
+        assert!(daemon.ringing.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hangs_up_the_only_call_or_the_named_one() {
+        let mut daemon = daemon();
+        assert_eq!(daemon.hangup(None).unwrap(), "no call");
+        for name in ["door", "team"] {
+            let shutdown = CancellationToken::new();
+            let task = {
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown.cancelled().await;
+                    Ok(())
+                }
+            };
+            let description = format!("bridge {name}");
+            daemon
+                .start(name.to_string(), description, None, None, shutdown, task)
+                .unwrap();
+        }
+        assert!(daemon.hangup(None).is_err());
+        assert!(daemon.hangup(Some("garage".to_string())).is_err());
+        assert_eq!(
+            daemon.hangup(Some("team".to_string())).unwrap(),
+            "hung up bridge team"
+        );
+        assert_eq!(daemon.hangup(None).unwrap(), "hung up bridge door");
+        assert!(daemon.calls.is_empty());
+    }
+
+    #[test]
+    fn reloads_only_the_routes_that_changed() {
+        let routes = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(track, device)| (track.to_string(), device.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let old = routes(&[
+            ("music", "speakers"),
+            ("voice", "headset"),
+            ("alerts", "hdmi"),
+        ]);
+        let new = routes(&[
+            ("music", "speakers"),
+            ("voice", "desk"),
+            ("chat", "headset"),
+        ]);
+        assert_eq!(
+            changed_routes(&old, &new),
+            [
+                ("alerts", None),
+                ("chat", Some("headset")),
+                ("voice", Some("desk")),
+            ]
+        );
+        assert!(changed_routes(&new, &new).is_empty());
+    }
+}
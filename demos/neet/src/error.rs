@@ -0,0 +1,97 @@
+//! Failure categories that scripts can tell apart by exit code.
+//!
+//! Errors stay `anyhow` inside the crate. Where a failure falls into one of
+//! these categories, it is tagged by wrapping it in a [`NeetError`], usually
+//! as context so the underlying cause is still printed.
+
+use std::{fmt, process::ExitCode};
+
+/// Any error that is not one of the categories below.
+pub const EXIT_FAILURE: u8 = 1;
+
+#[derive(Debug)]
+pub enum NeetError {
+    /// Arguments that parse but don't go together, like clap's usage errors.
+    InvalidArguments(String),
+    /// No audio device matches the one asked for, or there is no default.
+    DeviceNotFound(String),
+    /// The relay (or the peer, for direct calls) could not be reached.
+    RelayUnreachable(String),
+    /// The session turned us away: kicked or locked out by the moderator, or
+    /// another run holds our role.
+    SessionRejected(String),
+    /// The audio backend failed to start a stream on a device it found.
+    AudioBackend(String),
+    /// The connection failed after it was established.
+    Transport(String),
+    /// An audio quality check measured worse than its thresholds.
+    QualityCheck(String),
+}
+
+impl NeetError {
+    /// The process exit code for this category. 2 is also what clap exits
+    /// with for usage errors.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            NeetError::InvalidArguments(_) => 2,
+            NeetError::DeviceNotFound(_) => 3,
+            NeetError::RelayUnreachable(_) => 4,
+            NeetError::SessionRejected(_) => 5,
+            NeetError::AudioBackend(_) => 6,
+            NeetError::Transport(_) => 7,
+            NeetError::QualityCheck(_) => 8,
+        }
+    }
+}
+
+impl fmt::Display for NeetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NeetError::InvalidArguments(message)
+            | NeetError::DeviceNotFound(message)
+            | NeetError::RelayUnreachable(message)
+            | NeetError::SessionRejected(message)
+            | NeetError::AudioBackend(message)
+            | NeetError::Transport(message)
+            | NeetError::QualityCheck(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for NeetError {}
+
+/// The exit code for `err`: that of the outermost [`NeetError`] it carries,
+/// or [`EXIT_FAILURE`].
+pub fn exit_code(err: &anyhow::Error) -> ExitCode {
+    ExitCode::from(code(err))
+}
+
+fn code(err: &anyhow::Error) -> u8 {
+    err.downcast_ref::<NeetError>()
+        .map_or(EXIT_FAILURE, NeetError::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn exit_code_found_through_context() {
+        let rejected = anyhow::Error::from(NeetError::SessionRejected("locked".into()))
+            .context("subscribe task failed");
+        assert_eq!(code(&rejected), 5);
+
+        let unreachable = Err::<(), _>(anyhow!("timed out"))
+            .context(NeetError::RelayUnreachable("could not reach relay".into()))
+            .unwrap_err();
+        assert_eq!(code(&unreachable), 4);
+        assert_eq!(unreachable.to_string(), "could not reach relay");
+
+        let invalid = anyhow::Error::from(NeetError::InvalidArguments("--fanout".into()));
+        assert_eq!(code(&invalid), 2);
+
+        assert_eq!(code(&anyhow!("something else")), EXIT_FAILURE);
+    }
+}
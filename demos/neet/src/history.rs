@@ -0,0 +1,268 @@
+//! `neet history`: a log of past calls.
+//!
+//! Every call, conference and bridge appends one line of JSON to
+//! `$XDG_DATA_HOME/neet/history.jsonl` (or `~/.local/share/neet/`) when it
+//! ends: the session, who was on it, when, how it went and where it was
+//! recorded. A line per call keeps appends cheap and lets a crash lose at
+//! most the call it interrupted; lines that do not parse are skipped.
+
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{config::data_dir, moq::format_bytes, stats::Stats};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// One past call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CallRecord {
+    pub session: String,
+    /// `listen`, `call`, `join` or `bridge`.
+    pub kind: String,
+    /// Who we heard, by name where known, else by role.
+    pub peers: Vec<String>,
+    /// Unix seconds.
+    pub started_at: u64,
+    pub ended_at: u64,
+    /// Why the call failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Average MOS of each direction, if rated.
+    pub receive_mos: Option<f32>,
+    pub send_mos: Option<f32>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<PathBuf>,
+}
+
+impl CallRecord {
+    /// A call of `kind` in `session` starting now.
+    pub fn start(kind: &str, session: &str) -> Self {
+        let now = unix_now();
+        Self {
+            session: session.to_string(),
+            kind: kind.to_string(),
+            peers: Vec::new(),
+            started_at: now,
+            ended_at: now,
+            error: None,
+            receive_mos: None,
+            send_mos: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            recording: None,
+        }
+    }
+
+    pub fn with_recording(mut self, path: Option<&Path>) -> Self {
+        self.recording = path.map(Path::to_path_buf);
+        self
+    }
+
+    pub fn add_peer(&mut self, peer: &str) {
+        if !self.peers.iter().any(|known| known == peer) {
+            self.peers.push(peer.to_string());
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.ended_at.saturating_sub(self.started_at))
+    }
+
+    /// End the call with `result` and the quality and data usage in `stats`,
+    /// and add it to the history. Failing to is logged, never fatal.
+    pub fn finish(mut self, result: &Result<()>, stats: &Stats) {
+        self.ended_at = unix_now();
+        self.error = result.as_ref().err().map(|err| format!("{err:#}"));
+        let quality = stats.quality.snapshot();
+        self.receive_mos = quality.receive_mos_avg;
+        self.send_mos = quality.send_mos_avg;
+        self.bytes_sent = stats.connection.total_sent.get();
+        self.bytes_received = stats.connection.total_received.get();
+        let Some(path) = default_path() else {
+            debug!("no data directory; not keeping call history");
+            return;
+        };
+        if let Err(err) = append(&path, &self) {
+            warn!("failed to add the call to the history: {err:#}");
+        }
+    }
+}
+
+impl fmt::Display for CallRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = match Local.timestamp_opt(self.started_at as i64, 0).single() {
+            Some(started) => started.format("%Y-%m-%d %H:%M").to_string(),
+            None => self.started_at.to_string(),
+        };
+        write!(
+            f,
+            "{started}  {:<6} {}  {}",
+            self.kind,
+            self.session,
+            humantime::format_duration(self.duration())
+        )?;
+        match self.peers.as_slice() {
+            [] => write!(f, "  nobody answered")?,
+            peers => write!(f, "  with {}", peers.join(", "))?,
+        }
+        let mos = |mos: Option<f32>| mos.map_or("-".to_string(), |mos| format!("{mos:.1}"));
+        if self.receive_mos.is_some() || self.send_mos.is_some() {
+            write!(
+                f,
+                "  MOS {} in, {} out",
+                mos(self.receive_mos),
+                mos(self.send_mos)
+            )?;
+        }
+        write!(
+            f,
+            "  {} sent, {} received",
+            format_bytes(self.bytes_sent),
+            format_bytes(self.bytes_received)
+        )?;
+        if let Some(recording) = &self.recording {
+            write!(f, "  recorded to {}", recording.display())?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, "  failed: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which calls to list.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub session: Option<String>,
+    pub peer: Option<String>,
+    /// Only calls started within this long.
+    pub since: Option<Duration>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &CallRecord) -> bool {
+        let cutoff = self
+            .since
+            .map(|since| unix_now().saturating_sub(since.as_secs()));
+        self.session
+            .as_ref()
+            .is_none_or(|session| &record.session == session)
+            && self
+                .peer
+                .as_ref()
+                .is_none_or(|peer| record.peers.contains(peer))
+            && cutoff.is_none_or(|cutoff| record.started_at >= cutoff)
+    }
+}
+
+/// `$XDG_DATA_HOME/neet/history.jsonl`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(data_dir()?.join(HISTORY_FILE))
+}
+
+pub fn append(path: &Path, record: &CallRecord) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    // one write, so that concurrent calls never interleave their lines.
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Every call in the history at `path`, oldest first; none if it does not
+/// exist yet.
+pub fn load(path: &Path) -> Result<Vec<CallRecord>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut records = Vec::new();
+    for (number, line) in (1..).zip(text.lines()) {
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(err) => warn!("skipping line {number} of {}: {err}", path.display()),
+        }
+    }
+    Ok(records)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_appended_calls_by_session_and_peer() {
+        let path = std::env::temp_dir().join(format!("neet-history-{}.jsonl", std::process::id()));
+        let mut call = CallRecord::start("call", "demo");
+        call.add_peer("listener");
+        call.add_peer("listener");
+        call.receive_mos = Some(4.3);
+        let mut bridge =
+            CallRecord::start("bridge", "standup").with_recording(Some(Path::new("standup.mkv")));
+        bridge.add_peer("alice");
+        bridge.add_peer("bob");
+        bridge.started_at -= 3 * 24 * 3600;
+        append(&path, &call).unwrap();
+        append(&path, &bridge).unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not a call\n")
+            .unwrap();
+
+        let records = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records, [call.clone(), bridge.clone()]);
+        assert_eq!(call.peers, ["listener"]);
+
+        let matching = |filter: Filter| {
+            records
+                .iter()
+                .filter(|record| filter.matches(record))
+                .map(|record| record.session.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(matching(Filter::default()), ["demo", "standup"]);
+        let bob = Filter {
+            peer: Some("bob".into()),
+            ..Filter::default()
+        };
+        assert_eq!(matching(bob), ["standup"]);
+        let today = Filter {
+            since: Some(Duration::from_secs(24 * 3600)),
+            ..Filter::default()
+        };
+        assert_eq!(matching(today), ["demo"]);
+        assert!(bridge.to_string().contains("with alice, bob"));
+        assert!(bridge.to_string().contains("recorded to standup.mkv"));
+        assert!(load(&path).unwrap().is_empty());
+    }
+}
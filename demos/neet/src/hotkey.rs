@@ -0,0 +1,135 @@
+//! A global hotkey that mutes and unmutes (`--mute-hotkey`, feature
+//! `hotkey`).
+//!
+//! The key works whichever window has focus, so the call can be muted from
+//! the application being presented. It toggles the same pause state as the
+//! `pause` command, so the remote is told about it. Hotkeys are grabbed
+//! through X11 on Linux (not Wayland) and `RegisterHotKey` on Windows, whose
+//! messages our thread pumps itself. macOS only delivers them to an event
+//! loop on the main thread, which the async runtime occupies.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use global_hotkey::{hotkey::HotKey, GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::media::PauseState;
+
+/// How long the hotkey thread waits for a key before looking whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A registered mute hotkey, unregistered when dropped.
+pub struct MuteHotkey {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MuteHotkey {
+    /// Register `key`, e.g. `ctrl+shift+m`, to toggle `paused`.
+    pub async fn start(key: &str, paused: PauseState) -> Result<Self> {
+        if cfg!(target_os = "macos") {
+            anyhow::bail!("--mute-hotkey is not supported on macOS yet");
+        }
+        let hotkey: HotKey = key
+            .parse()
+            .with_context(|| format!("invalid hotkey `{key}`"))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let thread = std::thread::Builder::new()
+            .name("hotkey".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || {
+                    // on Windows, the thread that registers gets the key's messages.
+                    let manager = match GlobalHotKeyManager::new()
+                        .and_then(|manager| manager.register(hotkey).map(|()| manager))
+                    {
+                        Ok(manager) => manager,
+                        Err(err) => {
+                            let _ = ready_tx.send(Err(anyhow!(err)));
+                            return;
+                        }
+                    };
+                    let _ = ready_tx.send(Ok(()));
+                    toggle_on_press(hotkey.id(), &paused, &stop);
+                    if let Err(err) = manager.unregister(hotkey) {
+                        warn!("failed to unregister the mute hotkey: {err}");
+                    }
+                }
+            })?;
+        ready_rx
+            .await?
+            .with_context(|| format!("failed to register the hotkey `{key}`"))?;
+        info!(key, "press the hotkey to mute or unmute from any window");
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for MuteHotkey {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn toggle_on_press(id: u32, paused: &PauseState, stop: &AtomicBool) {
+    let events = GlobalHotKeyEvent::receiver();
+    while !stop.load(Ordering::Relaxed) {
+        #[cfg(windows)]
+        pump_messages();
+        let Ok(event) = events.recv_timeout(POLL_INTERVAL) else {
+            continue;
+        };
+        if event.id != id || event.state != HotKeyState::Pressed {
+            continue;
+        }
+        let muted = !paused.is_paused();
+        paused.set(muted);
+        info!("{} by hotkey", if muted { "muted" } else { "unmuted" });
+    }
+}
+
+/// Dispatch the thread's window messages, among them the hotkey's.
+#[cfg(windows)]
+fn pump_messages() {
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        DispatchMessageW, PeekMessageW, TranslateMessage, MSG, PM_REMOVE,
+    };
+    // SAFETY: `msg` is a valid MSG for PeekMessageW to fill in, and only
+    // passed on once it has.
+    unsafe {
+        let mut msg: MSG = std::mem::zeroed();
+        while PeekMessageW(&mut msg, std::ptr::null_mut(), 0, 0, PM_REMOVE) != 0 {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_unknown_keys() {
+        let err = MuteHotkey::start("ctrl+shift+nope", PauseState::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("ctrl+shift+nope"));
+    }
+}
@@ -0,0 +1,134 @@
+//! The long-term ed25519 key that identifies this user, e.g. as the moderator
+//! of a session.
+//!
+//! Stored hex-encoded at `$XDG_CONFIG_HOME/neet/identity.key` (or the path
+//! given with `--identity`) and created on first use. Others know it by its
+//! [`Fingerprint`], e.g. in their contacts.
+
+use std::{
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::config_dir;
+
+const IDENTITY_FILE: &str = "identity.key";
+const FINGERPRINT_BYTES: usize = 16;
+
+/// The start of a public key's SHA-256, short enough to read out over the
+/// phone.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Fingerprint([u8; FINGERPRINT_BYTES]);
+
+impl Fingerprint {
+    pub fn of(key: &VerifyingKey) -> Self {
+        let hash = Sha256::digest(key.as_bytes());
+        let mut fingerprint = [0; FINGERPRINT_BYTES];
+        fingerprint.copy_from_slice(&hash[..FINGERPRINT_BYTES]);
+        Self(fingerprint)
+    }
+}
+
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|&c| c != ':').collect();
+        let bytes = hex::decode(digits)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "expected a fingerprint of {} hex digits, got `{s}`",
+                    FINGERPRINT_BYTES * 2
+                )
+            })?;
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for Fingerprint {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Fingerprint> for String {
+    fn from(fingerprint: Fingerprint) -> Self {
+        fingerprint.to_string()
+    }
+}
+
+/// Groups of four hex digits, e.g. `3f2a:09bc:...`.
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, pair) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            f.write_str(&hex::encode(pair))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fingerprint({self})")
+    }
+}
+
+/// Read the identity key at `path` (default location if `None`), creating it
+/// if it does not exist yet.
+pub fn load_or_create(path: Option<&Path>) -> Result<SigningKey> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => default_path().context("cannot find a config directory; pass --identity")?,
+    };
+    if path.exists() {
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read identity key {}", path.display()))?;
+        let bytes: [u8; 32] = hex::decode(text.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("{} is not a hex-encoded identity key", path.display()))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).map_err(|err| anyhow!("no randomness for a key: {err}"))?;
+    let key = SigningKey::from_bytes(&bytes);
+    write_private(&path, &hex::encode(bytes))
+        .with_context(|| format!("failed to write identity key {}", path.display()))?;
+    info!(path = %path.display(), "created a new identity key");
+    Ok(key)
+}
+
+fn default_path() -> Option<PathBuf> {
+    Some(config_dir()?.join(IDENTITY_FILE))
+}
+
+/// Create `path` readable by its owner only.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{contents}")?;
+    Ok(())
+}
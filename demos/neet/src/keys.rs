@@ -0,0 +1,327 @@
+//! Single-key commands during a call (`--keys`).
+//!
+//! The terminal goes into raw mode, so a key acts as soon as it is pressed:
+//! `m` mutes, `+` and `-` change the playback volume, `d` opens a menu of
+//! input devices, `s` logs a snapshot of the call's stats, `c` agrees to be
+//! recorded and `q` hangs up.
+//! With `--send-dtmf`, digits, `*` and `#` dial at once. `:` reads a line
+//! command, e.g. a moderator's `kick`, until enter. Raw mode also stops the
+//! terminal from turning `\n` into `\r\n`, so the log goes through
+//! [`LogWriter`], which does that itself while raw mode is on.
+
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{audio::AudioContext, moq::Moderator, stats::StatsSnapshot};
+
+const HELP: &str =
+    "keys: m mute, +/- volume, d input device, s stats, c consent to recording, : line command, \
+     q hang up, ? help";
+/// How many input devices the menu offers, one per digit.
+const MENU_DEVICES: usize = 10;
+/// Volume change per `+` or `-`.
+const VOLUME_STEP_DB: f32 = 3.;
+const MIN_VOLUME_DB: f32 = -30.;
+const MAX_VOLUME_DB: f32 = 12.;
+/// How often the key reader looks whether the call is over.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the terminal is in raw mode.
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Log output on stdout, returning the carriage itself in raw mode.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout().lock();
+        if !RAW_MODE.load(Ordering::Relaxed) {
+            return stdout.write(buf);
+        }
+        for line in buf.split_inclusive(|&byte| byte == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(line) => {
+                    stdout.write_all(line)?;
+                    stdout.write_all(b"\r\n")?;
+                }
+                None => stdout.write_all(line)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Raw mode, left again when dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        RAW_MODE.store(true, Ordering::Relaxed);
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        RAW_MODE.store(false, Ordering::Relaxed);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// What a key does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyCommand {
+    Mute,
+    VolumeUp,
+    VolumeDown,
+    Devices,
+    Stats,
+    Consent,
+    HangUp,
+    Help,
+    /// Start typing a line command.
+    Line,
+    /// A digit, picking from the device menu or dialled.
+    Pick(usize),
+    /// `*` or `#`, dialled.
+    Dial(char),
+}
+
+impl KeyCommand {
+    fn from_key(key: &KeyEvent) -> Option<Self> {
+        let command = match key.code {
+            // raw mode turns Ctrl+C into a key too.
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Self::HangUp,
+            KeyCode::Char('m') => Self::Mute,
+            KeyCode::Char('+' | '=') => Self::VolumeUp,
+            KeyCode::Char('-') => Self::VolumeDown,
+            KeyCode::Char('d') => Self::Devices,
+            KeyCode::Char('s') => Self::Stats,
+            KeyCode::Char('c') => Self::Consent,
+            KeyCode::Char('q') => Self::HangUp,
+            KeyCode::Char('?' | 'h') => Self::Help,
+            KeyCode::Char(':') => Self::Line,
+            KeyCode::Char(tone @ ('*' | '#')) => Self::Dial(tone),
+            KeyCode::Char(digit @ '0'..='9') => Self::Pick(digit as usize - '0' as usize),
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// Act on keys pressed until the call is over, hanging up with `shutdown`.
+pub async fn read_keys(
+    audio: AudioContext,
+    send_dtmf: bool,
+    moderator: Option<Moderator>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let raw = RawMode::enable()?;
+    info!("{HELP}");
+    let (sender, mut keys) = mpsc::unbounded_channel();
+    std::thread::Builder::new()
+        .name("keys".to_string())
+        .spawn(move || {
+            while !sender.is_closed() {
+                match event::poll(POLL_INTERVAL).and_then(|_| event::read()) {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        let _ = sender.send(key);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        warn!("failed to read keys: {err}");
+                        return;
+                    }
+                }
+            }
+        })?;
+
+    let mut volume_db = 20. * audio.gain().get().log10();
+    // the `list-devices` indices of the input devices, by digit, while the menu is open.
+    let mut menu: Option<Vec<usize>> = None;
+    // the line command typed so far, after `:`.
+    let mut line: Option<String> = None;
+    while let Some(key) = keys.recv().await {
+        if let Some(typed) = &mut line {
+            match key.code {
+                KeyCode::Enter => {
+                    let typed = line.take().unwrap_or_default();
+                    LogWriter.write_all(b"\n")?;
+                    let done = crate::run_command(&audio, send_dtmf, moderator.as_ref(), &typed);
+                    if let Err(err) = done.await {
+                        warn!("{err:#}");
+                    }
+                }
+                KeyCode::Esc => {
+                    line = None;
+                    LogWriter.write_all(b"\n")?;
+                }
+                KeyCode::Backspace if !typed.is_empty() => {
+                    typed.pop();
+                    LogWriter.write_all(b"\x08 \x08")?;
+                }
+                KeyCode::Char(char) => {
+                    typed.push(char);
+                    write!(LogWriter, "{char}")?;
+                }
+                _ => {}
+            }
+            LogWriter.flush()?;
+            continue;
+        }
+        let Some(command) = KeyCommand::from_key(&key) else {
+            menu = None;
+            continue;
+        };
+        match command {
+            KeyCommand::Pick(digit) if menu.is_some() => {
+                let Some(index) = menu.take().unwrap_or_default().get(digit).copied() else {
+                    info!("no input device {digit}");
+                    continue;
+                };
+                match audio.switch_input(&index.to_string()).await {
+                    Ok(device) => info!(%device, "switched input device"),
+                    Err(err) => warn!("failed to switch input device: {err:#}"),
+                }
+                continue;
+            }
+            KeyCommand::Mute => {
+                let muted = !audio.pause_state().is_paused();
+                audio.set_paused(muted);
+                info!("{}", if muted { "muted" } else { "unmuted" });
+            }
+            KeyCommand::VolumeUp | KeyCommand::VolumeDown => {
+                let step = match command {
+                    KeyCommand::VolumeUp => VOLUME_STEP_DB,
+                    _ => -VOLUME_STEP_DB,
+                };
+                volume_db = (volume_db + step).clamp(MIN_VOLUME_DB, MAX_VOLUME_DB);
+                audio.gain().set_db(volume_db);
+                info!("volume {volume_db:+.0} dB");
+            }
+            KeyCommand::Devices => {
+                let devices = match AudioContext::list_devices().await {
+                    Ok(devices) => devices,
+                    Err(err) => {
+                        warn!("failed to list input devices: {err:#}");
+                        menu = None;
+                        continue;
+                    }
+                };
+                let shown = &devices.input[..devices.input.len().min(MENU_DEVICES)];
+                let mut list = "input devices:".to_string();
+                for (digit, device) in shown.iter().enumerate() {
+                    let default = if device.is_default { " (default)" } else { "" };
+                    list += &format!("  [{digit}] {}{default}", device.name);
+                }
+                info!("{list}; press a digit to switch, any other key to cancel");
+                menu = Some(shown.iter().map(|device| device.index).collect());
+                continue;
+            }
+            KeyCommand::Stats => info!("{}", stats_line(&audio.stats().snapshot())),
+            KeyCommand::Consent => {
+                audio.consent_to_recording();
+                info!("agreed to be recorded");
+            }
+            KeyCommand::HangUp if shutdown.is_cancelled() => {
+                // a second time quits at once, as Ctrl+C does.
+                drop(raw);
+                std::process::exit(130);
+            }
+            KeyCommand::HangUp => {
+                info!("hanging up; press q again to quit at once");
+                shutdown.cancel();
+            }
+            KeyCommand::Help => info!("{HELP}"),
+            KeyCommand::Line => {
+                LogWriter.write_all(b":")?;
+                LogWriter.flush()?;
+                line = Some(String::new());
+            }
+            KeyCommand::Pick(digit) if send_dtmf => dial(&audio, char::from(b'0' + digit as u8)),
+            KeyCommand::Dial(tone) if send_dtmf => dial(&audio, tone),
+            KeyCommand::Pick(_) | KeyCommand::Dial(_) => {}
+        }
+        menu = None;
+    }
+    Ok(())
+}
+
+/// Send one DTMF tone.
+fn dial(audio: &AudioContext, tone: char) {
+    match audio.send_dtmf(&tone.to_string()) {
+        Ok(()) => info!(digits = %tone, "sent DTMF"),
+        Err(err) => warn!("failed to send DTMF: {err:#}"),
+    }
+}
+
+/// The call's stats at a glance.
+fn stats_line(stats: &StatsSnapshot) -> String {
+    let mos = |mos: Option<f32>| mos.map_or("-".to_string(), |mos| format!("{mos:.1}"));
+    format!(
+        "received {} frames, {} lost, {} late; jitter {} ms, delay {} ms, buffer {} ms; \
+         rtt {} ms; MOS rx {} tx {}; {} underruns",
+        stats.received_frames,
+        stats.received_lost,
+        stats.received_late,
+        stats.jitter_us / 1000,
+        stats.one_way_delay_us / 1000,
+        stats.playback_buffer_us / 1000,
+        stats.connection.rtt_us / 1000,
+        mos(stats.quality.receive_mos),
+        mos(stats.quality.send_mos),
+        stats.playback_xruns.underruns,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_map_to_commands() {
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(KeyCommand::from_key(&ctrl_c), Some(KeyCommand::HangUp));
+        assert_eq!(
+            KeyCommand::from_key(&key(KeyCode::Char('c'))),
+            Some(KeyCommand::Consent)
+        );
+        assert_eq!(
+            KeyCommand::from_key(&key(KeyCode::Char('+'))),
+            Some(KeyCommand::VolumeUp)
+        );
+        assert_eq!(
+            KeyCommand::from_key(&key(KeyCode::Char('7'))),
+            Some(KeyCommand::Pick(7))
+        );
+        assert_eq!(
+            KeyCommand::from_key(&key(KeyCode::Char('#'))),
+            Some(KeyCommand::Dial('#'))
+        );
+        let line = stats_line(&StatsSnapshot {
+            received_frames: 500,
+            jitter_us: 4_200,
+            ..Default::default()
+        });
+        assert!(line.starts_with("received 500 frames, 0 lost, 0 late; jitter 4 ms"));
+        assert!(line.contains("MOS rx - tx -"));
+    }
+}
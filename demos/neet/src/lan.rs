@@ -0,0 +1,184 @@
+//! `neet lan`: calls between machines on the same network, without a relay or
+//! a shared session string.
+//!
+//! A waiting peer accepts QUIC connections on an embedded MoQ endpoint and
+//! advertises it over mDNS as `<name>._neet._udp.local.`. A caller browses
+//! for that name and dials the endpoint directly (see [`crate::moq::Endpoint`]).
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tracing::{debug, warn};
+
+const SERVICE_TYPE: &str = "_neet._udp.local.";
+/// How long a lookup waits for peers to answer.
+pub const BROWSE_TIMEOUT: Duration = Duration::from_secs(3);
+/// The session both peers of a LAN call use; only they are on the endpoint.
+pub const SESSION: &str = "lan";
+
+/// A peer found on the network.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub name: String,
+    /// Where its endpoint can be reached, best first.
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// This machine's host name, the default name to advertise.
+pub fn default_name() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "neet".to_string())
+}
+
+/// `name` as an mDNS host name: letters, digits and dashes.
+pub fn host_name(name: &str) -> String {
+    let host: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    format!("{host}.local.")
+}
+
+/// Advertises our endpoint until dropped.
+pub struct Advertisement(ServiceDaemon);
+
+impl Advertisement {
+    pub fn start(name: &str, port: u16) -> Result<Self> {
+        if name.is_empty() || name.contains('.') {
+            bail!("the LAN name must be non-empty and must not contain `.`");
+        }
+        let daemon = ServiceDaemon::new()?;
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &host_name(name),
+            "",
+            port,
+            HashMap::from([("version".to_string(), env!("CARGO_PKG_VERSION").to_string())]),
+        )?
+        .enable_addr_auto();
+        daemon.register(info)?;
+        Ok(Self(daemon))
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.shutdown() {
+            warn!("failed to withdraw the mDNS advertisement: {err}");
+        }
+    }
+}
+
+/// Everyone who answers within `timeout`.
+pub async fn browse(timeout: Duration) -> Result<Vec<Peer>> {
+    let mut peers = Vec::new();
+    browse_until(timeout, |peer| {
+        peers.push(peer);
+        false
+    })
+    .await?;
+    Ok(peers)
+}
+
+/// The peer advertised as `name`.
+pub async fn find(name: &str) -> Result<Peer> {
+    let mut found = None;
+    browse_until(BROWSE_TIMEOUT, |peer| {
+        let matches = peer.name.eq_ignore_ascii_case(name);
+        if matches {
+            found = Some(peer);
+        }
+        matches
+    })
+    .await?;
+    found.ok_or_else(|| anyhow!("nobody called `{name}` answered on the local network"))
+}
+
+/// Pass resolved peers to `done` until it returns true or `timeout` passes.
+async fn browse_until(timeout: Duration, mut done: impl FnMut(Peer) -> bool) -> Result<()> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let search = async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            let Some(name) = instance_name(info.get_fullname()) else {
+                continue;
+            };
+            let addrs = dial_addrs(info.get_addresses(), info.get_port());
+            debug!(name, ?addrs, "found peer");
+            if !addrs.is_empty()
+                && done(Peer {
+                    name: name.to_string(),
+                    addrs,
+                })
+            {
+                return;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(timeout, search).await;
+    let _ = daemon.shutdown();
+    Ok(())
+}
+
+/// `alice` out of `alice._neet._udp.local.`.
+fn instance_name(fullname: &str) -> Option<&str> {
+    fullname
+        .strip_suffix(SERVICE_TYPE)?
+        .strip_suffix('.')
+        .filter(|name| !name.is_empty())
+}
+
+/// IPv4 first; link-local IPv6 addresses are skipped, as they need a scope
+/// that mDNS does not tell us.
+fn dial_addrs(ips: &HashSet<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    let mut ips: Vec<_> = ips
+        .iter()
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .copied()
+        .collect();
+    ips.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_named_and_dialed_over_usable_addresses() {
+        assert_eq!(instance_name("alice._neet._udp.local."), Some("alice"));
+        assert_eq!(instance_name("._neet._udp.local."), None);
+        assert_eq!(instance_name("alice._other._udp.local."), None);
+        assert_eq!(host_name("Bob's Mac"), "Bob-s-Mac.local.");
+
+        let ips = HashSet::from([
+            "fe80::1".parse().unwrap(),
+            "fd00::2".parse().unwrap(),
+            "192.168.1.20".parse().unwrap(),
+        ]);
+        let addrs: Vec<String> = dial_addrs(&ips, 4443)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(addrs, ["192.168.1.20:4443", "[fd00::2]:4443"]);
+    }
+}
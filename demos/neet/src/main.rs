@@ -1,18 +1,86 @@
 mod audio;
+mod bench;
 mod codec;
+mod config;
+mod contacts;
+#[cfg(unix)]
+mod daemon;
+mod error;
+mod history;
+#[cfg(feature = "hotkey")]
+mod hotkey;
+mod identity;
+mod keys;
+mod lan;
 mod media;
 mod moq;
+mod quality;
+mod rtp;
+mod schedule;
+mod stats;
+#[cfg(feature = "transcribe")]
+mod transcribe;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+mod tray;
+#[cfg(all(unix, feature = "wake-word"))]
+mod wake;
 
-use anyhow::Result;
+use std::{
+    collections::HashSet,
+    io::IsTerminal,
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    process::ExitCode,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
 use clap::{Args, Parser, Subcommand};
-use tracing_subscriber::EnvFilter;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    select,
+};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt as _, reload, util::SubscriberInitExt as _, EnvFilter, Registry,
+};
 
+#[cfg(feature = "p2p")]
+use crate::moq::{P2pTransport, TransportKind, STUN_SERVER};
+#[cfg(feature = "transcribe")]
+use crate::transcribe::{TranscribeOptions, TranscribeSources};
 use crate::{
-    audio::{AudioConfig, AudioContext},
-    moq::{MoqOptions, Role},
+    audio::{
+        is_dtmf_digit, watch_levels, AnalysisThresholds, AnnounceOptions, AnnounceTarget,
+        AudioConfig, AudioContext, AudioMode, ExtraInput, Greeting, OtherApps, Pacing, PanMode,
+        DEFAULT_LATENCY_BUDGET, NO_INPUT_DEVICE, NO_OUTPUT_DEVICE, PIPE_PREFIX,
+    },
+    bench::BenchOptions,
+    codec::{multistream::ChannelLayout, CodecPreference},
+    config::Config,
+    contacts::{Contact, Contacts},
+    error::NeetError,
+    history::{CallRecord, Filter},
+    identity::Fingerprint,
+    media::{DirectedMs, Direction, Injection, OverflowPolicy, Presence},
+    moq::{
+        fresh_session, run_bridge, send_invite, BridgeOptions, Capabilities, Capability,
+        CongestionController, Delivery, DialTransport, Endpoint, FrameTrace, GroupStrategy,
+        InstanceId, Invite, IpVersion, Moderator, ModeratorCommand, ModeratorKey, MoqOptions, Pin,
+        PriorityOverride, PriorityScheme, Redundancy, RelayTransport, RemoteTrack, RemoteTracks,
+        ReplayOptions, Role, Standby, TrackPriorities, Transport, TransportOptions,
+    },
+    schedule::{MaxDuration, StartAt},
 };
 
 const DEFAULT_RELAY: &str = "https://moq.justinmoon.com/anon";
+/// Name in the self-signed certificate of a `--direct` endpoint.
+const DIRECT_HOST: &str = "neet";
+
+#[cfg(feature = "bench")]
+#[global_allocator]
+static GLOBAL: bench::CountingAllocator = bench::CountingAllocator;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -22,6 +90,10 @@ const DEFAULT_RELAY: &str = "https://moq.justinmoon.com/anon";
     disable_help_subcommand = true
 )]
 struct Cli {
+    /// Config file (default: $XDG_CONFIG_HOME/neet/config.toml)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(flatten)]
     audio: AudioArgs,
 
@@ -31,25 +103,404 @@ struct Cli {
 
 #[derive(Debug, Clone, Args)]
 struct AudioArgs {
-    /// Input device name (default system microphone)
+    /// Input device: index from list-devices, /regex/, name or unique substring (default: best microphone)
     #[arg(long)]
     input_device: Option<String>,
-    /// Output device name (default system speakers)
+    /// Output device: index from list-devices, /regex/, name or unique substring (default system speakers)
     #[arg(long)]
     output_device: Option<String>,
+    /// Capture raw 48 kHz stereo s16le PCM from this shell command's stdout instead of a device
+    #[arg(long, value_name = "COMMAND", conflicts_with = "input_device")]
+    input_cmd: Option<String>,
+    /// Play raw 48 kHz stereo s16le PCM into this shell command's stdin instead of a device
+    #[arg(long, value_name = "COMMAND", conflicts_with = "output_device")]
+    output_cmd: Option<String>,
+    /// Add a PulseAudio/PipeWire sink <NAME>-in and source <NAME>-out that other apps play into and record the call with
+    #[arg(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        default_missing_value = "neet",
+        conflicts_with_all = ["input_device", "output_device", "input_cmd", "output_cmd"]
+    )]
+    system_devices: Option<String>,
+    /// Record from another input than a bluetooth headset's microphone, keeping the headset in high-quality A2DP
+    #[arg(long, conflicts_with_all = ["input_device", "input_cmd", "system_devices"])]
+    keep_a2dp: bool,
+    /// Also publish this device as a track of its own, unprocessed: NAME=DEVICE[,codec=opus|flac][,bitrate=KBPS] (repeatable)
+    #[arg(long, value_name = "NAME=DEVICE")]
+    extra_input: Vec<ExtraInput>,
+    /// Play a remote track on another output device: TRACK=DEVICE, where TRACK is `audio` for the remote's voice or one of its extra inputs (repeatable)
+    #[arg(long, value_name = "TRACK=DEVICE", value_parser = parse_route)]
+    route: Vec<(String, String)>,
+    /// Also play the remote participants, and nothing else, into a PulseAudio/PipeWire source <NAME> for OBS or other streaming software to record
+    #[arg(long, value_name = "NAME", num_args = 0..=1, default_missing_value = "neet-stream")]
+    stream_feed: Option<String>,
+    /// Tell WebSocket clients on this address, e.g. an OBS browser source, who joins and leaves the call
+    #[arg(long, value_name = "ADDR:PORT")]
+    stream_events: Option<SocketAddr>,
+    /// Record and play through Apple's voice processing, with its echo cancellation and gain control, instead of the WebRTC processor
+    #[cfg(target_os = "macos")]
+    #[arg(
+        long,
+        conflicts_with_all = ["input_device", "output_device", "input_cmd", "output_cmd", "system_devices", "keep_a2dp"]
+    )]
+    voice_processing: bool,
+    /// Tune for a voice call, or for music: full-band stereo at 128 kbps, no processing, deeper buffers
+    #[arg(long, value_enum, default_value_t = AudioMode::Voice)]
+    mode: AudioMode,
     /// Disable audio processing / echo cancellation
     #[arg(long)]
     disable_processing: bool,
+    /// Save battery: wake up less often, encode 60 ms frames and lighten echo cancellation (adds latency)
+    #[arg(long)]
+    low_power: bool,
+    /// Overflow policy between capture and publish: drop-oldest or drop-newest (the audio thread never blocks)
+    #[arg(long, default_value = "drop-oldest", value_parser = OverflowPolicy::parse_non_blocking)]
+    capture_overflow: OverflowPolicy,
+    /// Overflow policy between network and playback: drop-oldest, drop-newest or block:<ms>
+    #[arg(long, default_value = "drop-oldest")]
+    playback_overflow: OverflowPolicy,
+    /// Audio to buffer before playing the remote, and the least the buffer shrinks to (default 40, 150 with --mode music; 20 on a LAN, 100+ on flaky Wi-Fi)
+    #[arg(long, value_name = "MS")]
+    playout_delay_ms: Option<u64>,
+    /// Skip remote audio to live once it plays this much later than it has to, e.g. after a network hiccup (0 = off)
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_LATENCY_BUDGET.as_millis() as u64)]
+    latency_budget: u64,
+    /// For testing: hold the frames of a path back this long, as capture=MS or playback=MS
+    #[arg(long, value_name = "PATH=MS")]
+    inject_delay_ms: Vec<DirectedMs>,
+    /// For testing: hold the frames of a path back up to this much longer, at random, as capture=MS or playback=MS
+    #[arg(long, value_name = "PATH=MS")]
+    inject_jitter_ms: Vec<DirectedMs>,
+    /// Stereo placement of remote participants: off, auto (spread evenly) or a position from -1 to 1
+    #[arg(long, default_value = "off", allow_hyphen_values = true)]
+    pan: PanMode,
+    /// Lower playback by this many dB while you speak (0 = off)
+    #[arg(long, default_value_t = 0., value_name = "DB")]
+    duck: f32,
+    /// Lower the other remote tracks while this one plays, by --duck dB or else 12 (repeatable)
+    #[arg(long, value_name = "TRACK")]
+    duck_for: Vec<String>,
+    /// Draw live mic and remote level meters on stderr
+    #[arg(long)]
+    meter: bool,
+    /// Single-key commands during a call instead of typed lines: m mute, +/- volume, d input device, s stats, q hang up
+    #[arg(long)]
+    keys: bool,
+    /// Mute and unmute with this key combination from any window, e.g. ctrl+shift+m
+    #[cfg(feature = "hotkey")]
+    #[arg(long, value_name = "KEYS")]
+    mute_hotkey: Option<String>,
+    /// Show a tray icon for muting and hanging up, and take media keys and headset buttons (MPRIS)
+    #[cfg(all(feature = "tray", target_os = "linux"))]
+    #[arg(long)]
+    tray: bool,
+    /// Lower other applications' audio by this many dB during the call (PulseAudio/PipeWire)
+    #[arg(long, value_name = "DB")]
+    duck_others: Option<f32>,
+    /// Pause media players that are playing (MPRIS, with playerctl) during the call
+    #[arg(long)]
+    pause_others: bool,
+    /// Dial DTMF tones into the call by typing digits (0-9, *, #, A-D) and enter
+    #[arg(long)]
+    send_dtmf: bool,
+    /// Report DTMF digits heard in remote audio
+    #[arg(long)]
+    detect_dtmf: bool,
+    /// Speak call events (joins, leaves, pauses) to yourself (local) or into the call too (call)
+    #[arg(long, value_name = "TARGET")]
+    announce: Option<AnnounceTarget>,
+    /// espeak-ng voice for announcements, e.g. en-us
+    #[arg(long, requires = "announce")]
+    voice: Option<String>,
+    /// Keep this many seconds of capture and playback audio; type `scope` to draw them into a PNG
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..=60))]
+    scope: Option<u64>,
+    /// Warn (and tell the remote) when the mic is near-silent or delivers nothing for this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    mic_watchdog: Option<u64>,
+    /// When the mic watchdog fires, try the other input devices in turn
+    #[arg(long, requires = "mic_watchdog")]
+    mic_failover: bool,
+    /// Skip frames that fail to encode or decode, up to this many a minute, instead of ending the call
+    #[arg(long, value_name = "ERRORS", default_value_t = 10)]
+    codec_error_budget: u32,
+    /// Transcribe the call with this whisper model (ggml .bin file)
+    #[cfg(feature = "transcribe")]
+    #[arg(long, value_name = "PATH")]
+    transcribe_model: Option<PathBuf>,
+    /// Whose audio to transcribe: local, remote or both
+    #[cfg(feature = "transcribe")]
+    #[arg(long, default_value = "both")]
+    transcribe: TranscribeSources,
+    /// Spoken language for transcription, e.g. en (default: detect)
+    #[cfg(feature = "transcribe")]
+    #[arg(long, value_name = "LANG")]
+    transcribe_language: Option<String>,
+    /// Also write the transcript to this file
+    #[cfg(feature = "transcribe")]
+    #[arg(long, value_name = "PATH", requires = "transcribe_model")]
+    transcript: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Args)]
 struct SessionArgs {
+    /// A saved contact to call or wait for, instead of --session (see `neet contacts`)
+    #[arg(conflicts_with = "session")]
+    contact: Option<String>,
     /// Shared session identifier for this call
+    #[arg(long, required_unless_present_any = ["contact", "invite"])]
+    session: Option<String>,
+    /// Ring the contact's `neet daemon --inbox` in a fresh session instead of a shared one (call only)
+    #[arg(long, conflicts_with_all = ["session", "direct", "fanout"])]
+    invite: bool,
+    /// MoQ relay base URL (defaults to the contact's, else the hosted relay); repeat it with --fanout
     #[arg(long)]
-    session: String,
-    /// MoQ relay base URL (defaults to hosted relay)
-    #[arg(long, default_value = DEFAULT_RELAY)]
-    relay: url::Url,
+    relay: Vec<url::Url>,
+    /// Publish on every --relay; the call itself runs on the first
+    #[arg(long)]
+    fanout: bool,
+    /// Skip the relay: `listen` accepts QUIC connections at this address, `call` dials it
+    #[arg(long, value_name = "ADDR:PORT")]
+    direct: Option<SocketAddr>,
+    /// Relay, or p2p to connect straight through both sides' NATs (see --peer)
+    #[cfg(feature = "p2p")]
+    #[arg(
+        long = "transport",
+        value_name = "KIND",
+        value_enum,
+        default_value_t = TransportKind::Relay,
+        conflicts_with_all = ["direct", "fanout", "preconnect"]
+    )]
+    transport_kind: TransportKind,
+    /// The public address the peer printed, for --transport p2p (asked for without it)
+    #[cfg(feature = "p2p")]
+    #[arg(long, value_name = "ADDR:PORT")]
+    peer: Option<SocketAddr>,
+    /// STUN server that tells us our public address, for --transport p2p
+    #[cfg(feature = "p2p")]
+    #[arg(long, value_name = "HOST:PORT", default_value = STUN_SERVER)]
+    stun: String,
+    /// How published frames are grouped: frame, frames:<n> or ms:<n>
+    #[arg(long, default_value = "frame")]
+    group: GroupStrategy,
+    /// Relative priority of tracks under congestion
+    #[arg(long, value_enum, default_value_t = PriorityScheme::AudioFirst)]
+    priority_scheme: PriorityScheme,
+    /// Override a single track's priority (0-255, higher is served first), e.g. audio=255
+    #[arg(long = "track-priority", value_name = "TRACK=PRIORITY")]
+    track_priorities: Vec<PriorityOverride>,
+    /// Frame delivery: reliable, or datagram to drop late frames for lower latency
+    #[arg(long, value_enum, default_value_t = Delivery::Reliable)]
+    delivery: Delivery,
+    /// Repeat this many earlier frames in every frame while the remote reports loss (0 = off)
+    #[arg(long, default_value_t = 0, value_name = "FRAMES")]
+    redundancy: u8,
+    /// Remote loss in percent above which redundancy kicks in (0 = always)
+    #[arg(long, default_value_t = 5., value_name = "PCT")]
+    redundancy_threshold: f32,
+    /// Publish several bitrate layers and let the receiver pick one (both peers must enable it)
+    #[arg(long)]
+    simulcast: bool,
+    /// Moderate the session with your identity key (type `mute <name>`, `kick <name>`, `lock`, `unlock`)
+    #[arg(long)]
+    moderator: bool,
+    /// Obey moderator commands signed with this public key (64 hex digits)
+    #[arg(long, value_name = "KEY")]
+    moderator_key: Option<ModeratorKey>,
+    /// Identity key file (default: $XDG_CONFIG_HOME/neet/identity.key, created if missing)
+    #[arg(long, value_name = "PATH")]
+    identity: Option<PathBuf>,
+    /// Only play peers who prove they know this PIN (they must pass it too)
+    #[arg(long)]
+    pin: Option<Pin>,
+    /// Only play a remote whose identity key has this fingerprint (see `neet contacts fingerprint`)
+    #[arg(long, value_name = "FINGERPRINT")]
+    fingerprint: Option<Fingerprint>,
+    /// Keep the microphone off until a caller connects, then answer at once (listen only)
+    #[arg(long)]
+    auto_answer: bool,
+    /// Play this WAV file to each caller --auto-answer answers, before they hear the microphone
+    #[arg(long, value_name = "PATH", requires = "auto_answer")]
+    greeting: Option<PathBuf>,
+    /// After a hangup or a dropped connection, wait for the next call instead of exiting
+    #[arg(long, visible_alias = "persistent")]
+    auto_reconnect: bool,
+    /// Connect ahead of time and keep a spare connection warm, for --start-at and --auto-reconnect
+    #[arg(long, conflicts_with = "direct")]
+    preconnect: bool,
+    /// Play a chime when the remote connects
+    #[arg(long)]
+    announce_chime: bool,
+    /// Play a notice when the call starts being recorded: spoken with --announce, else two beeps
+    #[arg(long)]
+    recording_notice: bool,
+    /// Write the frames received from the remote, with their arrival times, to this trace file (see `neet replay`)
+    #[arg(long, value_name = "PATH")]
+    dump_trace: Option<PathBuf>,
+    /// Take over from another run with our role that is still in the session (e.g. one that crashed)
+    #[arg(long)]
+    force: bool,
+    /// Treat the remote as lost after this many milliseconds without a heartbeat (0 = never)
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    liveness_timeout: u64,
+    /// Channel layout to capture and send: mono, stereo, quad, 5.1 or 7.1
+    #[arg(
+        long = "channels",
+        value_name = "LAYOUT",
+        default_value = "stereo",
+        conflicts_with = "simulcast"
+    )]
+    layout: ChannelLayout,
+    /// Also publish lossless FLAC (about 700 kbps), played instead of Opus by receivers that support it
+    #[arg(long, value_enum, default_value_t = CodecPreference::Opus, conflicts_with_all = ["simulcast", "layout"])]
+    codec: CodecPreference,
+    /// Send the Opus RTP stream arriving at this UDP address instead of the microphone, e.g. 0.0.0.0:5004
+    #[arg(long, value_name = "ADDR:PORT", conflicts_with_all = ["simulcast", "layout", "codec"])]
+    input_rtp: Option<SocketAddr>,
+    /// Send received audio as an Opus RTP stream to this UDP address instead of the speakers
+    #[arg(long, value_name = "ADDR:PORT")]
+    output_rtp: Option<SocketAddr>,
+    /// Keep each direction of the call under this data rate by lowering the bitrate and dropping redundancy
+    #[arg(long, value_name = "KBPS", value_parser = clap::value_parser!(u32).range(1..))]
+    max_bandwidth_kbps: Option<u32>,
+    /// Subscribe to only these of the remote's tracks: audio, control, report, heartbeat (default: all)
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "TRACKS")]
+    tracks: Option<Vec<RemoteTrack>>,
+    /// Play only these of the remote's extra inputs, by name (default: all)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "NAMES",
+        conflicts_with = "output_rtp"
+    )]
+    play_inputs: Option<Vec<String>>,
+    /// Measure the bandwidth to the relay for 2 s before the call and start at a bitrate that fits
+    #[arg(long, conflicts_with = "direct")]
+    probe_bandwidth: bool,
+    /// While the connection stalls, hold back all but the newest this many milliseconds of audio (0 = off)
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    send_backlog: u64,
+    /// Spread bursts of outgoing frames over their duration instead of sending them at once, for constrained uplinks
+    #[arg(long)]
+    pace: bool,
+    /// Tell receivers the microphone level in every frame, for talking indicators
+    #[arg(long)]
+    send_audio_level: bool,
+    /// Refuse a remote that can't do all of these, instead of falling back to what it can
+    #[arg(long, value_name = "CAPABILITY", value_enum, value_delimiter = ',')]
+    require: Vec<Capability>,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    #[command(flatten)]
+    transport: TransportArgs,
+}
+
+impl SessionArgs {
+    /// Every option at its default, in `session`.
+    fn defaults(session: &str) -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            args: SessionArgs,
+        }
+        Defaults::parse_from(["neet", "--session", session]).args
+    }
+
+    /// What the user called: the contact, else the session.
+    fn label(&self) -> &str {
+        self.contact
+            .as_deref()
+            .or(self.session.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The session to take part in. A contact fills it in, along with their
+    /// relay unless --relay was given and their fingerprint unless
+    /// --fingerprint was. An invite makes up a new one. Without a relay
+    /// either way, it is the hosted one.
+    fn resolve(&mut self) -> Result<String> {
+        if self.invite {
+            self.session = Some(fresh_session()?);
+        }
+        let contact = match &self.contact {
+            Some(name) => Some(Contacts::load(None)?.get(name)?.clone()),
+            None => None,
+        };
+        if self.relay.is_empty() {
+            let relay = contact
+                .as_ref()
+                .and_then(|contact| contact.relay.as_deref())
+                .unwrap_or(DEFAULT_RELAY);
+            self.relay = vec![relay
+                .parse()
+                .with_context(|| format!("bad relay {relay}"))?];
+        }
+        let Some(contact) = contact else {
+            return self.session.clone().context("pass --session or a contact");
+        };
+        self.fingerprint = self.fingerprint.or(contact.fingerprint);
+        let session = self.session.get_or_insert_with(|| contact.session.clone());
+        Ok(session.clone())
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+struct ScheduleArgs {
+    /// Wait until this time before joining: HH:MM[:SS] local time or an RFC 3339 timestamp
+    #[arg(long, value_name = "TIME")]
+    start_at: Option<StartAt>,
+    /// Hang up after this long, e.g. 30m or 1h30m, with warning beeps 5 and 1 minutes before
+    #[arg(long, value_name = "DURATION")]
+    max_duration: Option<MaxDuration>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct TransportArgs {
+    /// Local UDP address to connect from, e.g. 192.168.1.20:0 on a multi-homed host
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<SocketAddr>,
+    /// Only reach the relay over this address family
+    #[arg(long, value_enum)]
+    ip_version: Option<IpVersion>,
+    /// QUIC congestion controller
+    #[arg(long, value_enum)]
+    congestion: Option<CongestionController>,
+    /// Initial RTT estimate in milliseconds, before the first measurement
+    #[arg(long, value_name = "MS")]
+    initial_rtt: Option<u64>,
+    /// Close the connection after this many milliseconds without traffic
+    #[arg(long, value_name = "MS")]
+    idle_timeout: Option<u64>,
+    /// Send keep-alive packets at this interval in milliseconds
+    #[arg(long, value_name = "MS")]
+    keep_alive: Option<u64>,
+    /// Try reaching the relay this many more times when it does not answer
+    #[arg(long, value_name = "N")]
+    connect_retries: Option<u32>,
+    /// Wait this many milliseconds before the first retry, doubling (with jitter) up to a minute
+    #[arg(long, value_name = "MS")]
+    retry_backoff: Option<u64>,
+    /// Keep trying until the relay is reachable, e.g. when started at boot
+    #[arg(long)]
+    wait_for_relay: bool,
+}
+
+impl TransportArgs {
+    fn options(&self) -> TransportOptions {
+        TransportOptions {
+            bind: self.bind,
+            ip_version: self.ip_version,
+            congestion: self.congestion,
+            initial_rtt: self.initial_rtt.map(Duration::from_millis),
+            max_idle_timeout: self.idle_timeout.map(Duration::from_millis),
+            keep_alive: self.keep_alive.map(Duration::from_millis),
+            connect_retries: self.connect_retries,
+            retry_backoff: self.retry_backoff.map(Duration::from_millis),
+            wait_for_relay: self.wait_for_relay,
+        }
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -58,74 +509,1159 @@ enum Command {
     Listen(SessionArgs),
     /// Dial a listener using the shared session identifier
     Call(SessionArgs),
+    /// Join a conference bridge, hearing everyone else in the session
+    Join(JoinArgs),
+    /// Run a conference bridge that mixes everyone who joins the session
+    Bridge(BridgeArgs),
+    /// Call someone on the local network, found over mDNS, without a relay or session string
+    Lan(LanArgs),
+    /// Stay resident and place or answer calls on request over a control socket
+    #[cfg(unix)]
+    Daemon(DaemonArgs),
+    /// Send a request (e.g. `call --session demo`, `hangup`, `status`) to a running daemon
+    #[cfg(unix)]
+    Ctl(CtlArgs),
     /// Run local microphone → speakers loopback without networking
-    Loopback,
+    Loopback(LoopbackArgs),
     /// List available audio input and output devices
-    ListDevices,
+    ListDevices(ListDevicesArgs),
+    /// Benchmark encode/decode and the MoQ frame path, printing JSON results
+    Bench(BenchArgs),
+    /// Play a trace from --dump-trace through the receive path into a WAV file, printing JSON statistics
+    Replay(ReplayArgs),
+    /// List past calls, oldest first
+    History(HistoryArgs),
+    /// Save, list and remove contacts to call by name, e.g. `neet call alice`
+    #[command(subcommand)]
+    Contacts(ContactsCommand),
+}
+
+#[derive(Debug, Clone, Args)]
+struct JoinArgs {
+    /// Your name in the conference; must be unique within the session
+    #[arg(long)]
+    name: String,
+    #[command(flatten)]
+    session: SessionArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+struct BridgeArgs {
+    /// Session to bridge
+    #[arg(long)]
+    session: String,
+    /// MoQ relay base URL (defaults to hosted relay)
+    #[arg(long, default_value = DEFAULT_RELAY)]
+    relay: url::Url,
+    /// Drop kicked participants and refuse new ones while locked, per commands signed with this key
+    #[arg(long, value_name = "KEY")]
+    moderator_key: Option<ModeratorKey>,
+    /// Only mix participants who prove they know this PIN
+    #[arg(long)]
+    pin: Option<Pin>,
+    /// Record every participant to this Matroska file, each on a track named after them
+    #[arg(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+    /// Record nobody until every participant in the mix typed `consent`
+    #[arg(long, requires = "record")]
+    require_recording_consent: bool,
+    #[command(flatten)]
+    schedule: ScheduleArgs,
+    #[command(flatten)]
+    transport: TransportArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+struct LanArgs {
+    /// Name to be called by (default: the host name)
+    #[arg(long)]
+    name: Option<String>,
+    /// Call the peer advertised under this name instead of waiting for a call
+    #[arg(long, value_name = "NAME", conflicts_with = "name")]
+    call: Option<String>,
+    /// List the peers on the network and exit
+    #[arg(long, conflicts_with_all = ["name", "call"])]
+    list: bool,
+    /// UDP port to accept calls on (0 = any free one)
+    #[arg(long, default_value_t = 0)]
+    port: u16,
+    /// Only play peers who prove they know this PIN (they must pass it too)
+    #[arg(long)]
+    pin: Option<Pin>,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone, Args)]
+struct DaemonArgs {
+    /// Control socket (default: $XDG_RUNTIME_DIR/neet.sock)
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// Ring for `call --invite` sent to your identity through this relay (default: the hosted relay)
+    #[arg(long, value_name = "RELAY", num_args = 0..=1, default_missing_value = DEFAULT_RELAY)]
+    inbox: Option<url::Url>,
+    /// Identity key file whose inbox to follow (default: $XDG_CONFIG_HOME/neet/identity.key)
+    #[arg(long, value_name = "PATH", requires = "inbox")]
+    identity: Option<PathBuf>,
+    /// Keep a connection to this session, or contact's, warm so calls there start at once (repeatable)
+    #[arg(long, value_name = "SESSION")]
+    preconnect: Vec<String>,
+    /// Take spoken requests, such as "hey neet, call alice", with this whisper model (ggml .bin file)
+    #[cfg(feature = "wake-word")]
+    #[arg(long, value_name = "PATH")]
+    wake_model: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone, Args)]
+struct CtlArgs {
+    /// Control socket of the daemon (default: $XDG_RUNTIME_DIR/neet.sock)
+    #[arg(long, value_name = "PATH")]
+    socket: Option<PathBuf>,
+    /// The request and its arguments
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+    request: Vec<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+struct LoopbackArgs {
+    /// Play a test signal instead, measure latency, SNR and dropouts in what the mic records, print them as JSON and fail below the thresholds
+    #[arg(long)]
+    analyze: bool,
+    /// Lowest acceptable SNR in dB
+    #[arg(long, default_value_t = 20., value_name = "DB", requires = "analyze")]
+    min_snr_db: f32,
+    /// Highest acceptable latency from playing the signal to capturing it
+    #[arg(long, default_value_t = 300, value_name = "MS", requires = "analyze")]
+    max_latency_ms: u64,
+    /// Most dropouts allowed in the test tone
+    #[arg(long, default_value_t = 0, requires = "analyze")]
+    max_dropouts: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ListDevicesArgs {
+    /// Also print the sample rates, channel counts and sample formats each device supports
+    #[arg(long)]
+    details: bool,
+    /// Print everything as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+struct BenchArgs {
+    /// Seconds of synthetic audio to process
+    #[arg(long, default_value_t = 10)]
+    seconds: u64,
+}
+
+#[derive(Debug, Clone, Args)]
+struct ReplayArgs {
+    /// Trace written with --dump-trace
+    trace: PathBuf,
+    /// WAV file to write what the trace plays as
+    #[arg(long, short, value_name = "PATH")]
+    output: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+enum ContactsCommand {
+    /// Save a contact, replacing anyone saved under the same name
+    Add {
+        name: String,
+        /// The session you share with them
+        #[arg(long)]
+        session: String,
+        /// Their relay, if not the default one
+        #[arg(long)]
+        relay: Option<url::Url>,
+        /// Their identity fingerprint, checked on every call (they print it with `neet contacts fingerprint`)
+        #[arg(long)]
+        fingerprint: Option<Fingerprint>,
+    },
+    /// Forget a contact
+    Remove { name: String },
+    /// List saved contacts
+    List,
+    /// Print your own identity fingerprint, for others to save with you
+    Fingerprint {
+        /// Identity key file (default: $XDG_CONFIG_HOME/neet/identity.key, created if missing)
+        #[arg(long, value_name = "PATH")]
+        identity: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Args)]
+struct HistoryArgs {
+    /// Only calls in this session
+    #[arg(long)]
+    session: Option<String>,
+    /// Only calls with this peer, e.g. a bridge participant or LAN name
+    #[arg(long)]
+    peer: Option<String>,
+    /// Only calls started within this long, e.g. `7d` or `12h`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    since: Option<Duration>,
+    /// Only the last N calls
+    #[arg(long, short = 'n', value_name = "N")]
+    limit: Option<usize>,
+    /// Print the calls as JSON lines
+    #[arg(long)]
+    json: bool,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     init_tracing();
 
-    let cli = Cli::parse();
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            error::exit_code(&err)
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    // tracks are known by name, so two inputs can't share one.
+    let mut names = HashSet::new();
+    if let Some(input) = cli
+        .audio
+        .extra_input
+        .iter()
+        .find(|input| !names.insert(&input.name))
+    {
+        anyhow::bail!(NeetError::InvalidArguments(format!(
+            "--extra-input {} is given twice",
+            input.name
+        )));
+    }
+    let config = Config::load(cli.config.as_deref())?;
+    if let Some(filter) = config.live.log_filter()? {
+        set_log_filter(filter)?;
+    }
     match cli.command {
-        Command::Listen(session) => run_session(Role::Listener, session, cli.audio).await?,
-        Command::Call(session) => run_session(Role::Caller, session, cli.audio).await?,
-        Command::Loopback => run_loopback(cli.audio).await?,
-        Command::ListDevices => run_list_devices().await?,
+        Command::Listen(session) => {
+            run_session(
+                Role::Listener,
+                session,
+                cli.audio,
+                &config,
+                None,
+                SessionEnv::standalone(),
+                None,
+            )
+            .await?
+        }
+        Command::Call(session) => {
+            run_session(
+                Role::Caller,
+                session,
+                cli.audio,
+                &config,
+                None,
+                SessionEnv::standalone(),
+                None,
+            )
+            .await?
+        }
+        Command::Join(args) => run_join(args, cli.audio, &config, SessionEnv::standalone()).await?,
+        Command::Bridge(args) => run_bridge_command(args, &config).await?,
+        Command::Lan(args) => run_lan(args, cli.audio, &config).await?,
+        #[cfg(unix)]
+        Command::Daemon(args) => {
+            let socket = args.socket.unwrap_or_else(daemon::default_socket);
+            let standby = args
+                .preconnect
+                .iter()
+                .map(|name| preconnect(name, &config))
+                .collect::<Result<_>>()?;
+            let mut daemon =
+                daemon::Daemon::new(cli.config, config, cli.audio).with_standby(standby);
+            if let Some(relay) = args.inbox {
+                let key = identity::load_or_create(args.identity.as_deref())?;
+                daemon = daemon.with_inbox(relay, Fingerprint::of(&key.verifying_key()));
+            }
+            #[cfg(feature = "wake-word")]
+            if let Some(model) = args.wake_model {
+                daemon = daemon.with_wake_word(model);
+            }
+            daemon.run(&socket).await?
+        }
+        #[cfg(unix)]
+        Command::Ctl(args) => {
+            let socket = args.socket.unwrap_or_else(daemon::default_socket);
+            print!("{}", daemon::send_request(&socket, &args.request).await?);
+        }
+        Command::Loopback(args) => run_loopback(args, cli.audio, &config).await?,
+        Command::ListDevices(args) => run_list_devices(args).await?,
+        Command::Bench(args) => run_bench(args).await?,
+        Command::Replay(args) => run_replay(args, cli.audio).await?,
+        Command::History(args) => run_history(args)?,
+        Command::Contacts(command) => run_contacts(command)?,
     }
 
     Ok(())
 }
 
+/// Changes the log filter while running.
+static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
 fn init_tracing() {
     let default_level = "info".to_string();
     let filter = std::env::var("RUST_LOG").unwrap_or(default_level);
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::new(filter))
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(filter));
+    let initialized = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer().with_writer(|| keys::LogWriter))
         .try_init();
+    if initialized.is_ok() {
+        let _ = LOG_FILTER.set(handle);
+    }
+}
+
+/// Replace the log filter, e.g. from a reloaded config file.
+fn set_log_filter(filter: EnvFilter) -> Result<()> {
+    match LOG_FILTER.get() {
+        Some(handle) => handle
+            .reload(filter)
+            .context("failed to change the log filter"),
+        None => Ok(()),
+    }
+}
+
+/// `TRACK=DEVICE`, for `--route`.
+fn parse_route(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((track, device)) if !track.is_empty() && !device.is_empty() => {
+            Ok((track.to_string(), device.to_string()))
+        }
+        _ => anyhow::bail!("expected TRACK=DEVICE"),
+    }
 }
 
-fn build_audio_config(args: &AudioArgs) -> AudioConfig {
+fn build_audio_config(args: &AudioArgs, config: &Config) -> AudioConfig {
     AudioConfig {
-        input_device: args.input_device.clone(),
-        output_device: args.output_device.clone(),
+        input_device: match &args.input_cmd {
+            Some(command) => Some(format!("{PIPE_PREFIX}{command}")),
+            None => args.input_device.clone(),
+        },
+        output_device: match &args.output_cmd {
+            Some(command) => Some(format!("{PIPE_PREFIX}{command}")),
+            None => args.output_device.clone(),
+        },
+        mode: args.mode,
         processing_enabled: !args.disable_processing,
+        low_power: args.low_power,
+        capture_overflow: args.capture_overflow,
+        playback_overflow: args.playback_overflow,
+        playout_delay: args
+            .playout_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(args.mode.playout_delay()),
+        latency_budget: (args.latency_budget > 0)
+            .then(|| Duration::from_millis(args.latency_budget)),
+        inject_capture: Injection::for_direction(
+            &args.inject_delay_ms,
+            &args.inject_jitter_ms,
+            Direction::Capture,
+        ),
+        inject_playback: Injection::for_direction(
+            &args.inject_delay_ms,
+            &args.inject_jitter_ms,
+            Direction::Playback,
+        ),
+        pan: args.pan,
+        duck_db: args.duck,
+        duck_for: args.duck_for.clone(),
+        detect_dtmf: args.detect_dtmf,
+        announce: args.announce.map(|target| AnnounceOptions {
+            target,
+            voice: args
+                .voice
+                .clone()
+                .or_else(|| config.announcements.voice.clone()),
+            messages: config.announcements.messages(),
+        }),
+        scope: args.scope.map(Duration::from_secs),
+        mic_watchdog: args.mic_watchdog.map(Duration::from_secs),
+        mic_failover: args.mic_failover,
+        codec_error_budget: args.codec_error_budget,
+        system_devices: args.system_devices.clone(),
+        keep_a2dp: args.keep_a2dp,
+        extra_inputs: args.extra_input.clone(),
+        routes: config
+            .routes
+            .clone()
+            .into_iter()
+            .chain(args.route.iter().cloned())
+            .collect(),
+        stream_feed: args.stream_feed.clone(),
+        stream_events: args.stream_events,
+        #[cfg(target_os = "macos")]
+        voice_processing: args.voice_processing,
+        #[cfg(feature = "transcribe")]
+        transcribe: args
+            .transcribe_model
+            .clone()
+            .map(|model| TranscribeOptions {
+                model,
+                sources: args.transcribe,
+                output: args.transcript.clone(),
+                language: args.transcribe_language.clone(),
+            }),
     }
 }
 
-async fn run_session(role: Role, session: SessionArgs, audio_args: AudioArgs) -> Result<()> {
-    let audio_config = build_audio_config(&audio_args);
-    let audio = AudioContext::new(audio_config).await?;
+/// What runs a session provides besides its arguments.
+struct SessionEnv {
+    /// Audio devices the session shares, instead of opening its own.
+    shared: Option<AudioContext>,
+    /// Cancelled to hang up.
+    shutdown: CancellationToken,
+    /// Who the call is with, if known before it starts.
+    peer: Option<String>,
+    /// Warm connections the session takes over if one leads where it goes.
+    standby: Vec<Standby>,
+}
+
+impl SessionEnv {
+    /// A session on its own devices that hangs up on Ctrl+C.
+    fn standalone() -> Self {
+        let shutdown = CancellationToken::new();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    tracing::info!("hanging up; press Ctrl+C again to quit at once");
+                    shutdown.cancel();
+                }
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            }
+        });
+        Self {
+            shared: None,
+            shutdown,
+            peer: None,
+            standby: Vec::new(),
+        }
+    }
+}
+
+/// A standby connection to the session `name`, or to that contact's.
+#[cfg(unix)]
+fn preconnect(name: &str, config: &Config) -> Result<Standby> {
+    let mut session = SessionArgs::defaults(name);
+    if Contacts::load(None)?.get(name).is_ok() {
+        session.session = None;
+        session.contact = Some(name.to_string());
+    }
+    let session_id = session.resolve()?;
+    let route = RelayTransport::new(&session.relay[0], &session_id, config.transport.options())?;
+    Ok(Standby::spawn(Arc::new(route)))
+}
 
+/// Take part in `session`, on the audio devices `env` shares if any, else on
+/// the ones `audio_args` selects, and over `route` if given, else as `session`
+/// says.
+async fn run_session(
+    role: Role,
+    mut session: SessionArgs,
+    audio_args: AudioArgs,
+    config: &Config,
+    bridge_name: Option<String>,
+    env: SessionEnv,
+    route: Option<Arc<dyn Transport>>,
+) -> Result<()> {
+    let session_id = session.resolve()?;
+    if session.auto_answer && (role != Role::Listener || bridge_name.is_some()) {
+        anyhow::bail!(NeetError::InvalidArguments(
+            "--auto-answer only applies to listen".into()
+        ));
+    }
+    if session.fingerprint.is_some() && bridge_name.is_some() {
+        anyhow::bail!(NeetError::InvalidArguments(
+            "--fingerprint checks the one peer of a call; a conference has many".into()
+        ));
+    }
+    let unsupported = Capabilities::OURS.missing(&session.require);
+    if let Some(capability) = unsupported.first() {
+        anyhow::bail!(NeetError::InvalidArguments(format!(
+            "--require {capability}: this build of neet can't do it either"
+        )));
+    }
+    let invitee = match session.fingerprint {
+        _ if !session.invite => None,
+        _ if role != Role::Caller || bridge_name.is_some() || route.is_some() => {
+            anyhow::bail!(NeetError::InvalidArguments(
+                "--invite only applies to call over a relay".into()
+            ))
+        }
+        Some(fingerprint) => Some(fingerprint),
+        None => anyhow::bail!(NeetError::InvalidArguments(
+            "--invite needs whom to ring: a contact with a fingerprint, or --fingerprint".into()
+        )),
+    };
+    let transport = session.transport.options().or(config.transport.options());
+    let inbox_transport = transport.clone();
+    let fanout = match (session.relay.as_slice(), session.fanout) {
+        ([_], false) => Vec::new(),
+        (_, false) => anyhow::bail!(NeetError::InvalidArguments(
+            "pass --fanout to publish on several relays".into()
+        )),
+        (_, true) if route.is_some() || session.direct.is_some() => {
+            anyhow::bail!(NeetError::InvalidArguments(
+                "--fanout publishes through relays, not directly".into()
+            ))
+        }
+        ([_], true) => anyhow::bail!(NeetError::InvalidArguments(
+            "--fanout needs more than one --relay".into()
+        )),
+        ([_, others @ ..], true) => others
+            .iter()
+            .map(|relay| {
+                let route = RelayTransport::new(relay, &session_id, transport.clone())?;
+                Ok(Arc::new(route) as Arc<dyn Transport>)
+            })
+            .collect::<Result<_>>()?,
+        ([], _) => unreachable!("resolving the session picks a relay"),
+    };
+    #[cfg(feature = "p2p")]
+    let route = match (route, session.transport_kind) {
+        (None, TransportKind::P2p) if bridge_name.is_some() => {
+            anyhow::bail!(NeetError::InvalidArguments(
+                "--transport p2p connects two peers; a conference needs a relay".into()
+            ))
+        }
+        (None, TransportKind::P2p) => {
+            let options = transport.clone();
+            let p2p = P2pTransport::punch(role, session.peer, &session.stun, DIRECT_HOST, options);
+            Some(Arc::new(p2p.await?) as Arc<dyn Transport>)
+        }
+        (route, _) => route,
+    };
+    let route: Arc<dyn Transport> = match (route, session.direct) {
+        (Some(route), _) => route,
+        (None, Some(_)) if bridge_name.is_some() => {
+            anyhow::bail!(NeetError::InvalidArguments(
+                "--direct connects two peers; a conference needs a relay".into()
+            ))
+        }
+        (None, Some(addr)) if role == Role::Listener => {
+            Arc::new(Endpoint::bind(addr, DIRECT_HOST)?)
+        }
+        (None, Some(addr)) => Arc::new(DialTransport::new(addr, transport)),
+        (None, None) => Arc::new(RelayTransport::new(
+            &session.relay[0],
+            &session_id,
+            transport,
+        )?),
+    };
+    let standby = env
+        .standby
+        .into_iter()
+        .find(|standby| standby.target() == route.target());
+    let standby = match standby {
+        Some(standby) => Some(standby),
+        None if session.preconnect && session.direct.is_none() => {
+            Some(Standby::spawn(route.clone()))
+        }
+        None if session.preconnect => {
+            anyhow::bail!(NeetError::InvalidArguments(
+                "--preconnect keeps a relay connection warm, not a direct one".into()
+            ))
+        }
+        None => None,
+    };
+    let identity = match identity::load_or_create(session.identity.as_deref()) {
+        Ok(key) => Some(key),
+        // only moderating and inviting need one; otherwise we just cannot
+        // prove who we are.
+        Err(err) if !session.moderator && !session.invite => {
+            tracing::debug!("no identity key: {err:#}");
+            None
+        }
+        Err(err) => return Err(err),
+    };
+    let moderator = if let Some(key) = identity.clone().filter(|_| session.moderator) {
+        let moderator = Moderator::new(key, &session_id);
+        tracing::info!(
+            key = %moderator.public_key(),
+            "moderating; participants should pass this as --moderator-key"
+        );
+        Some(moderator)
+    } else {
+        None
+    };
+    if let Some(start) = session.schedule.start_at {
+        select! {
+            _ = start.wait() => {}
+            _ = env.shutdown.cancelled() => return Ok(()),
+        }
+    }
+    let audio = match env.shared {
+        Some(audio) => audio,
+        None => {
+            let mut audio_config = build_audio_config(&audio_args, config);
+            if session.input_rtp.is_some() {
+                audio_config.input_device = Some(NO_INPUT_DEVICE.to_string());
+            }
+            if session.output_rtp.is_some() {
+                audio_config.output_device = Some(NO_OUTPUT_DEVICE.to_string());
+            }
+            let audio = AudioContext::new(audio_config).await?;
+            audio.bitrate().set_limit(config.live.bitrate_limit());
+            audio
+        }
+    };
+
+    let tracks = session.tracks.map(RemoteTracks::new).unwrap_or_default();
+    if (session.pin.is_some() || session.moderator_key.is_some())
+        && !tracks.wants(RemoteTrack::Control)
+    {
+        anyhow::bail!(NeetError::InvalidArguments(
+            "--pin and --moderator-key need the remote's control track in --tracks".into()
+        ));
+    }
+    let kind = match (&bridge_name, role) {
+        (Some(_), _) => "join",
+        (None, Role::Listener) => "listen",
+        (None, Role::Caller) => "call",
+    };
+    let mut record = CallRecord::start(kind, &session_id);
     let options = MoqOptions {
-        relay_url: session.relay,
-        session_id: session.session,
+        route,
+        session_id,
         role,
+        group_strategy: session.group,
+        priorities: TrackPriorities::new(session.priority_scheme, session.track_priorities),
+        delivery: session.delivery,
+        redundancy: Redundancy {
+            frames: session.redundancy,
+            loss_threshold_pct: session.redundancy_threshold,
+        },
+        simulcast: session.simulcast,
+        bridge_name,
+        moderator: moderator.clone(),
+        moderator_key: session.moderator_key,
+        pin: session.pin,
+        identity,
+        fingerprint: session.fingerprint,
+        max_duration: session.schedule.max_duration.map(|limit| limit.0),
+        auto_answer: session.auto_answer,
+        auto_reconnect: session.auto_reconnect,
+        chime: session.announce_chime,
+        greeting: session
+            .greeting
+            .as_deref()
+            .map(Greeting::load)
+            .transpose()?,
+        recording_notice: session.recording_notice,
+        trace: session
+            .dump_trace
+            .as_deref()
+            .map(FrameTrace::create)
+            .transpose()?,
+        fanout,
+        shutdown: env.shutdown.clone(),
+        instance: InstanceId::new()?,
+        force: session.force,
+        liveness_timeout: (session.liveness_timeout > 0)
+            .then(|| Duration::from_millis(session.liveness_timeout)),
+        layout: session.layout,
+        codec: session.codec,
+        input_rtp: session.input_rtp,
+        output_rtp: session.output_rtp,
+        max_bandwidth: session
+            .max_bandwidth_kbps
+            .map(|kbps| u64::from(kbps) * 1000),
+        tracks,
+        probe_bandwidth: session.probe_bandwidth,
+        send_backlog: (session.send_backlog > 0)
+            .then(|| Duration::from_millis(session.send_backlog)),
+        pace: session.pace,
+        standby,
+        play_inputs: session.play_inputs,
+        send_audio_level: session.send_audio_level,
+        extensions: Default::default(),
+        require: session.require,
+        peer: Default::default(),
     };
 
-    crate::moq::run_audio_session(options, audio).await
+    // before anything is spawned, which an early return would leave running.
+    #[cfg(feature = "hotkey")]
+    let _hotkey = match &audio_args.mute_hotkey {
+        Some(key) => Some(hotkey::MuteHotkey::start(key, audio.pause_state().clone()).await?),
+        None => None,
+    };
+    let (duck_others, pause_others) = (audio_args.duck_others, audio_args.pause_others);
+    let _others = match duck_others.is_some() || pause_others {
+        true => {
+            let quiet = move || OtherApps::quiet(duck_others, pause_others);
+            Some(tokio::task::spawn_blocking(quiet).await?)
+        }
+        false => None,
+    };
+    let commands = if audio_args.keys && std::io::stdin().is_terminal() {
+        tokio::spawn(keys::read_keys(
+            audio.clone(),
+            audio_args.send_dtmf,
+            moderator,
+            env.shutdown.clone(),
+        ))
+    } else {
+        if audio_args.keys {
+            tracing::warn!("--keys needs a terminal; reading line commands instead");
+        }
+        tokio::spawn(read_commands(
+            audio.clone(),
+            audio_args.send_dtmf,
+            moderator,
+        ))
+    };
+    let levels = tokio::spawn(watch_levels(audio.stats().clone(), audio_args.meter));
+    let dtmf = audio
+        .dtmf_events()
+        .map(|events| tokio::spawn(log_dtmf(events)));
+    let peer = env
+        .peer
+        .unwrap_or_else(|| options.remote_label().to_string());
+    let invite = match (invitee, &options.identity) {
+        (Some(to), Some(key)) => {
+            let invite = Invite::new(key, to, &options.session_id);
+            tracing::info!(%to, session = options.session_id, "ringing");
+            let relay = session.relay[0].clone();
+            Some(tokio::spawn(send_invite(
+                relay,
+                inbox_transport,
+                to,
+                invite,
+            )))
+        }
+        _ => None,
+    };
+    // the call goes on without them, e.g. outside a desktop session.
+    #[cfg(all(feature = "tray", target_os = "linux"))]
+    let _tray = match audio_args.tray {
+        true => {
+            let controls =
+                tray::CallControls::start(&peer, audio.pause_state().clone(), env.shutdown.clone());
+            controls
+                .await
+                .inspect_err(|err| tracing::warn!("{err:#}"))
+                .ok()
+        }
+        false => None,
+    };
+    let stats = audio.stats().clone();
+    let shutdown = options.shutdown.clone();
+    let call = crate::moq::run_audio_session(options, audio);
+    let result = match invite {
+        None => call.await,
+        Some(mut invite) => {
+            tokio::pin!(call);
+            select! {
+                result = &mut call => {
+                    invite.abort();
+                    result
+                }
+                // nobody can answer an invite that isn't there; don't wait.
+                rang = &mut invite => {
+                    let err = match rang {
+                        Ok(Ok(())) => anyhow::anyhow!("the invite was taken down"),
+                        Ok(Err(err)) => err,
+                        Err(err) => err.into(),
+                    };
+                    shutdown.cancel();
+                    call.await.and(Err(err.context("failed to ring")))
+                }
+            }
+        }
+    };
+    if stats.received_frames.get() > 0 {
+        record.add_peer(&peer);
+    }
+    record.finish(&result, &stats);
+    commands.abort();
+    if let Some(dtmf) = dtmf {
+        dtmf.abort();
+    }
+    levels.abort();
+    if audio_args.meter {
+        eprintln!();
+    }
+    result
 }
 
-async fn run_loopback(audio_args: AudioArgs) -> Result<()> {
-    let audio_config = build_audio_config(&audio_args);
+async fn run_join(
+    args: JoinArgs,
+    audio_args: AudioArgs,
+    config: &Config,
+    env: SessionEnv,
+) -> Result<()> {
+    if args.name.is_empty() || args.name.contains('/') {
+        anyhow::bail!(NeetError::InvalidArguments(
+            "--name must be non-empty and must not contain `/`".into()
+        ));
+    }
+    let name = Some(args.name);
+    run_session(
+        Role::Caller,
+        args.session,
+        audio_args,
+        config,
+        name,
+        env,
+        None,
+    )
+    .await
+}
+
+async fn run_lan(args: LanArgs, audio_args: AudioArgs, config: &Config) -> Result<()> {
+    if args.list {
+        for peer in lan::browse(lan::BROWSE_TIMEOUT).await? {
+            let addrs: Vec<_> = peer.addrs.iter().map(ToString::to_string).collect();
+            println!("{}\t{}", peer.name, addrs.join(", "));
+        }
+        return Ok(());
+    }
+    let mut session = SessionArgs::defaults(lan::SESSION);
+    session.pin = args.pin;
+    match args.call {
+        Some(name) => {
+            let peer = lan::find(&name).await?;
+            tracing::info!(peer = peer.name, addrs = ?peer.addrs, "found peer");
+            let transport = session.transport.options().or(config.transport.options());
+            let route = Arc::new(DialTransport::new(peer.addrs[0], transport));
+            let env = SessionEnv {
+                peer: Some(peer.name),
+                ..SessionEnv::standalone()
+            };
+            run_session(
+                Role::Caller,
+                session,
+                audio_args,
+                config,
+                None,
+                env,
+                Some(route),
+            )
+            .await
+        }
+        None => {
+            let name = args.name.unwrap_or_else(lan::default_name);
+            let bind = (Ipv6Addr::UNSPECIFIED, args.port).into();
+            let endpoint = Endpoint::bind(bind, &lan::host_name(&name))?;
+            let _advertisement = lan::Advertisement::start(&name, endpoint.local_addr().port())?;
+            tracing::info!(
+                name,
+                "waiting for a call; on the same network, run `neet lan --call {name}`"
+            );
+            let route = Arc::new(endpoint);
+            run_session(
+                Role::Listener,
+                session,
+                audio_args,
+                config,
+                None,
+                SessionEnv::standalone(),
+                Some(route),
+            )
+            .await
+        }
+    }
+}
+
+async fn run_bridge_command(args: BridgeArgs, config: &Config) -> Result<()> {
+    let options = BridgeOptions {
+        relay_url: args.relay,
+        session_id: args.session,
+        priorities: TrackPriorities::new(PriorityScheme::AudioFirst, Vec::new()),
+        transport: args.transport.options().or(config.transport.options()),
+        moderator_key: args.moderator_key,
+        pin: args.pin,
+        max_duration: args.schedule.max_duration.map(|limit| limit.0),
+        record: args.record,
+        require_recording_consent: args.require_recording_consent,
+        matrix: config.matrix.clone(),
+        shutdown: SessionEnv::standalone().shutdown,
+    };
+    if let Some(start) = args.schedule.start_at {
+        start.wait().await;
+    }
+    run_bridge(options).await
+}
+
+/// Line commands typed on stdin while a call is running.
+async fn read_commands(
+    audio: AudioContext,
+    send_dtmf: bool,
+    moderator: Option<Moderator>,
+) -> Result<()> {
+    tracing::info!(
+        "type `pause`, `hold` or `away` and press enter to pause publishing, `resume` to go on"
+    );
+    tracing::info!("type `mark` and an optional label to mark this moment");
+    tracing::info!("type `consent` to agree to be recorded");
+    if send_dtmf {
+        tracing::info!("type digits and press enter to dial them as DTMF tones");
+    }
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await? {
+        run_command(&audio, send_dtmf, moderator.as_ref(), &line).await?;
+    }
+    Ok(())
+}
+
+/// Act on one line command, as typed on stdin or after `:` with `--keys`.
+async fn run_command(
+    audio: &AudioContext,
+    send_dtmf: bool,
+    moderator: Option<&Moderator>,
+    line: &str,
+) -> Result<()> {
+    let digits = line.trim();
+    if send_dtmf && !digits.is_empty() && digits.chars().all(is_dtmf_digit) {
+        audio.send_dtmf(digits)?;
+        tracing::info!(digits, "sent DTMF");
+        return Ok(());
+    }
+    let word = line.split_whitespace().next().unwrap_or_default();
+    if ModeratorCommand::KEYWORDS.contains(&word) {
+        match moderator {
+            Some(moderator) => {
+                if let Err(err) = line.parse().and_then(|command| moderator.issue(command)) {
+                    tracing::warn!("{err:#}");
+                }
+            }
+            None => tracing::warn!("`{word}` needs --moderator"),
+        }
+        return Ok(());
+    }
+    if word == "scope" {
+        let path = line.split_whitespace().nth(1).map(PathBuf::from);
+        if let Err(err) = audio.dump_scope(path).await {
+            tracing::warn!("{err:#}");
+        }
+        return Ok(());
+    }
+    if let Some(text) = line.trim().strip_prefix("say ") {
+        match audio.announcer() {
+            Some(announcer) => announcer.say(text.trim()),
+            None => tracing::warn!("`say` needs --announce"),
+        }
+        return Ok(());
+    }
+    if word == "mark" {
+        let label = line.trim()[word.len()..].trim();
+        let label = if label.is_empty() { "mark" } else { label };
+        audio.mark(label);
+        tracing::info!("marked this moment: {label}");
+        return Ok(());
+    }
+    match line.trim() {
+        "p" | "pause" => {
+            if !audio.set_paused(true) {
+                tracing::info!("already paused");
+            }
+        }
+        "r" | "resume" | "back" => {
+            if !audio.set_paused(false) {
+                tracing::info!("not paused");
+            }
+        }
+        "hold" => {
+            if !audio.pause_state().set_presence(Presence::Held) {
+                tracing::info!("already on hold");
+            }
+        }
+        "away" => {
+            if !audio.pause_state().set_presence(Presence::Away) {
+                tracing::info!("already away");
+            }
+        }
+        "consent" => {
+            audio.consent_to_recording();
+            tracing::info!("agreed to be recorded");
+        }
+        "" => {}
+        other => {
+            tracing::warn!("unknown command `{other}`; try `pause`, `hold`, `away` or `resume`")
+        }
+    }
+    Ok(())
+}
+
+/// Log digits detected in remote audio.
+async fn log_dtmf(mut events: tokio::sync::broadcast::Receiver<char>) {
+    use tokio::sync::broadcast::error::RecvError;
+    loop {
+        match events.recv().await {
+            Ok(digit) => tracing::info!(%digit, "received DTMF"),
+            Err(RecvError::Lagged(missed)) => tracing::warn!(missed, "missed DTMF digits"),
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn run_loopback(args: LoopbackArgs, audio_args: AudioArgs, config: &Config) -> Result<()> {
+    let mut audio_config = build_audio_config(&audio_args, config);
+    if args.analyze {
+        // echo cancellation would remove the very signal we listen for.
+        audio_config.processing_enabled = false;
+        let audio = AudioContext::new(audio_config).await?;
+        tracing::info!("playing the test signal");
+        let report = audio.analyze_loopback().await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return report.check(&AnalysisThresholds {
+            min_snr_db: args.min_snr_db,
+            max_latency: Duration::from_millis(args.max_latency_ms),
+            max_dropouts: args.max_dropouts,
+        });
+    }
     let audio = AudioContext::new(audio_config).await?;
     audio.feedback_encoded().await?;
     tracing::info!("loopback running – press Ctrl+C to stop");
+    let levels = tokio::spawn(watch_levels(audio.stats().clone(), audio_args.meter));
     tokio::signal::ctrl_c().await?;
+    levels.abort();
     Ok(())
 }
 
-async fn run_list_devices() -> Result<()> {
+async fn run_list_devices(args: ListDevicesArgs) -> Result<()> {
     let devices = AudioContext::list_devices().await?;
-    println!("Input devices:");
-    for name in devices.input {
-        println!("  {name}");
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+    for (title, devices) in [("Input", &devices.input), ("Output", &devices.output)] {
+        println!("{title} devices:");
+        for device in devices {
+            let default = if device.is_default { " (default)" } else { "" };
+            println!("  [{}] {}{default}", device.index, device.name);
+            if !args.details {
+                continue;
+            }
+            if let Some(config) = &device.default_config {
+                println!(
+                    "      preferred: {} ch, {} Hz, {}",
+                    config.channels, config.sample_rate, config.sample_format
+                );
+            }
+            for config in &device.supported_configs {
+                println!(
+                    "      supports:  {} ch, {}-{} Hz, {}",
+                    config.channels,
+                    config.min_sample_rate,
+                    config.max_sample_rate,
+                    config.sample_format
+                );
+            }
+            if let Some(err) = &device.error {
+                println!("      could not query configurations: {err}");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_bench(args: BenchArgs) -> Result<()> {
+    let options = BenchOptions {
+        duration: std::time::Duration::from_secs(args.seconds),
+    };
+    let report = crate::bench::run_bench(options).await?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+/// Replay with the receive path set up as `audio` would set up a call's.
+async fn run_replay(args: ReplayArgs, audio: AudioArgs) -> Result<()> {
+    let options = ReplayOptions {
+        trace: args.trace,
+        output: args.output,
+        tick: Pacing::new(audio.low_power).tick,
+        playout_delay: audio
+            .playout_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(audio.mode.playout_delay()),
+        latency_budget: (audio.latency_budget > 0)
+            .then(|| Duration::from_millis(audio.latency_budget)),
+        queue_frames: audio.mode.queue_frames(),
+        overflow: audio.playback_overflow,
+    };
+    let stats = crate::moq::replay_trace(&options).await?;
+    tracing::info!(output = %options.output.display(), "replayed the trace");
+    println!("{}", serde_json::to_string_pretty(&stats.snapshot())?);
+    Ok(())
+}
+
+fn run_contacts(command: ContactsCommand) -> Result<()> {
+    let mut contacts = Contacts::load(None)?;
+    match command {
+        ContactsCommand::Add {
+            name,
+            session,
+            relay,
+            fingerprint,
+        } => {
+            let contact = Contact {
+                session,
+                relay: relay.map(String::from),
+                fingerprint,
+            };
+            if contacts.add(&name, contact) {
+                tracing::info!("replaced contact `{name}`");
+            }
+            contacts.save()?;
+        }
+        ContactsCommand::Remove { name } => {
+            if !contacts.remove(&name) {
+                anyhow::bail!("no contact named `{name}`");
+            }
+            contacts.save()?;
+        }
+        ContactsCommand::List => {
+            for (name, contact) in contacts.iter() {
+                let relay = contact.relay.as_deref().unwrap_or(DEFAULT_RELAY);
+                let fingerprint = contact
+                    .fingerprint
+                    .map_or("unverified".to_string(), |fingerprint| {
+                        fingerprint.to_string()
+                    });
+                println!("{name}\t{}\t{relay}\t{fingerprint}", contact.session);
+            }
+        }
+        ContactsCommand::Fingerprint { identity } => {
+            let key = identity::load_or_create(identity.as_deref())?;
+            println!("{}", Fingerprint::of(&key.verifying_key()));
+        }
+    }
+    Ok(())
+}
+
+fn run_history(args: HistoryArgs) -> Result<()> {
+    let path = history::default_path().context("cannot find a data directory for the history")?;
+    let filter = Filter {
+        session: args.session,
+        peer: args.peer,
+        since: args.since,
+    };
+    let mut records: Vec<_> = history::load(&path)?
+        .into_iter()
+        .filter(|record| filter.matches(record))
+        .collect();
+    if let Some(limit) = args.limit {
+        records.drain(..records.len().saturating_sub(limit));
     }
-    println!("Output devices:");
-    for name in devices.output {
-        println!("  {name}");
+    for record in records {
+        if args.json {
+            println!("{}", serde_json::to_string(&record)?);
+        } else {
+            println!("{record}");
+        }
     }
     Ok(())
 }
@@ -1,32 +1,117 @@
+use std::{fmt, sync::Arc};
+
 use bytes::Bytes;
-use tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
+pub use self::{
+    inject::{DirectedMs, Direction, Injection},
+    queue::{
+        channel, MediaReceiver, MediaSender, OverflowPolicy, RecvError, SendError, TryRecvError,
+    },
+};
 use crate::codec::Codec;
 
+mod inject;
+mod queue;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrackKind {
     Audio,
+    /// Small periodic metadata, e.g. receiver reports.
+    Control,
+}
+
+/// Whether someone is there to talk to, as told to the remote on the
+/// control track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Presence {
+    #[default]
+    Here,
+    Muted,
+    Held,
+    Away,
+}
+
+impl Presence {
+    const ALL: [Presence; 4] = [Self::Here, Self::Muted, Self::Held, Self::Away];
+
+    /// Everything but being here pauses capture.
+    pub fn is_paused(self) -> bool {
+        self != Self::Here
+    }
+
+    /// For keeping in a [`crate::stats::Gauge`].
+    pub fn index(self) -> u64 {
+        self as u64
+    }
+
+    pub fn from_index(index: u64) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Presence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Presence::Here => "here",
+            Presence::Muted => "muted",
+            Presence::Held => "on hold",
+            Presence::Away => "away",
+        })
+    }
+}
+
+/// Whether capture is paused, and why. Encoders produce nothing while it is
+/// set, and publishers watch it to tell the remote about the gap.
+#[derive(Debug, Clone)]
+pub struct PauseState(Arc<watch::Sender<Presence>>);
+
+impl Default for PauseState {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(Presence::Here).0))
+    }
+}
+
+impl PauseState {
+    /// Mute or unmute. Returns whether the state changed.
+    pub fn set(&self, paused: bool) -> bool {
+        self.set_presence(match paused {
+            true => Presence::Muted,
+            false => Presence::Here,
+        })
+    }
+
+    /// Returns whether the state changed.
+    pub fn set_presence(&self, presence: Presence) -> bool {
+        self.0
+            .send_if_modified(|current| std::mem::replace(current, presence) != presence)
+    }
+
+    pub fn presence(&self) -> Presence {
+        *self.0.borrow()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.presence().is_paused()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Presence> {
+        self.0.subscribe()
+    }
 }
 
 #[derive(Debug)]
 pub struct MediaTrack {
-    receiver: broadcast::Receiver<MediaFrame>,
+    receiver: MediaReceiver,
     codec: Codec,
+    #[allow(dead_code)]
     kind: TrackKind,
 }
 
-impl Clone for MediaTrack {
-    fn clone(&self) -> Self {
-        Self {
-            receiver: self.receiver.resubscribe(),
-            codec: self.codec,
-            kind: self.kind,
-        }
-    }
-}
-
 impl MediaTrack {
-    pub fn new(receiver: broadcast::Receiver<MediaFrame>, codec: Codec, kind: TrackKind) -> Self {
+    pub fn new(receiver: MediaReceiver, codec: Codec, kind: TrackKind) -> Self {
         Self {
             receiver,
             codec,
@@ -34,14 +119,19 @@ impl MediaTrack {
         }
     }
 
-    pub async fn recv(&mut self) -> Result<MediaFrame, broadcast::error::RecvError> {
+    pub async fn recv(&mut self) -> Result<MediaFrame, RecvError> {
         self.receiver.recv().await
     }
 
-    pub fn try_recv(&mut self) -> Result<MediaFrame, broadcast::error::TryRecvError> {
+    pub fn try_recv(&mut self) -> Result<MediaFrame, TryRecvError> {
         self.receiver.try_recv()
     }
 
+    /// Ask the encoder to send the rest of its audio and close the track.
+    pub fn finish(&self) {
+        self.receiver.finish()
+    }
+
     pub fn codec(&self) -> Codec {
         self.codec
     }
@@ -50,9 +140,13 @@ impl MediaTrack {
 #[derive(Debug, Clone)]
 pub struct MediaFrame {
     pub payload: Bytes,
+    /// Samples in the frame, interleaved: every channel counts.
     #[allow(dead_code)]
     pub sample_count: Option<u32>,
     pub skipped_frames: Option<u32>,
     #[allow(dead_code)]
     pub skipped_samples: Option<u32>,
+    /// The stream starts over at this frame, e.g. because the remote restarted
+    /// its encoder; decoders drop the state they built up before it.
+    pub reset: bool,
 }
@@ -0,0 +1,202 @@
+//! Artificial delay and jitter on the capture or playback path
+//! (`--inject-delay-ms`, `--inject-jitter-ms`), to exercise jitter buffers,
+//! latency displays and echo cancellation against a long round trip
+//! reproducibly on one machine.
+//!
+//! The frames of an [`Injection`]'s track are held back on their way from
+//! the encoder to the network, or from the network to the decoder, each for
+//! the delay plus a random share of the jitter. Like a reliable stream, a
+//! frame never overtakes the one before it. The randomness starts from a
+//! fixed seed, so every run sees the same jitter.
+
+use std::{collections::VecDeque, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context};
+use tokio::{select, time::Instant};
+use tracing::debug;
+
+use super::{channel, MediaTrack, OverflowPolicy, RecvError};
+use crate::stats::Counter;
+
+/// Frames waiting for whoever reads an injected track.
+const OUTPUT_FRAMES: usize = 16;
+const SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the microphone to the network.
+    Capture,
+    /// From the network to the speakers.
+    Playback,
+}
+
+/// An amount of milliseconds on one path, written `capture=MS` or
+/// `playback=MS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectedMs {
+    pub direction: Direction,
+    pub ms: u64,
+}
+
+impl FromStr for DirectedMs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (direction, ms) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected capture=MS or playback=MS, got `{s}`"))?;
+        let direction = match direction {
+            "capture" => Direction::Capture,
+            "playback" => Direction::Playback,
+            other => {
+                return Err(anyhow!(
+                    "unknown path `{other}`; expected capture or playback"
+                ))
+            }
+        };
+        let ms = ms
+            .parse()
+            .with_context(|| format!("bad milliseconds `{ms}`"))?;
+        Ok(Self { direction, ms })
+    }
+}
+
+/// Delay and jitter for the tracks of one path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Injection {
+    pub delay: Duration,
+    /// The most a frame is held back on top of the delay.
+    pub jitter: Duration,
+}
+
+impl Injection {
+    /// What `delays` and `jitters` ask for on `direction`; the last one
+    /// given counts.
+    pub fn for_direction(
+        delays: &[DirectedMs],
+        jitters: &[DirectedMs],
+        direction: Direction,
+    ) -> Self {
+        let ms = |given: &[DirectedMs]| {
+            given
+                .iter()
+                .rfind(|given| given.direction == direction)
+                .map_or(Duration::ZERO, |given| Duration::from_millis(given.ms))
+        };
+        Self {
+            delay: ms(delays),
+            jitter: ms(jitters),
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.delay.is_zero() && self.jitter.is_zero()
+    }
+
+    /// `track`, its frames held back as configured. Frames the reader does
+    /// not take in time are dropped and added to `dropped`.
+    pub fn apply(self, mut track: MediaTrack, dropped: Counter) -> MediaTrack {
+        if self.is_none() {
+            return track;
+        }
+        let (mut sender, receiver) = channel(OUTPUT_FRAMES, OverflowPolicy::default(), dropped);
+        let injected = MediaTrack::new(receiver, track.codec, track.kind);
+        let mut jitter = Jitter::new(self.jitter);
+        tokio::spawn(async move {
+            let mut held = VecDeque::new();
+            let mut last_due = Instant::now();
+            let mut open = true;
+            while open || !held.is_empty() {
+                let next_due = held.front().map_or(last_due, |(due, _)| *due);
+                select! {
+                    frame = track.recv(), if open => match frame {
+                        Ok(frame) => {
+                            last_due = last_due.max(Instant::now() + self.delay + jitter.next());
+                            held.push_back((last_due, frame));
+                        }
+                        Err(RecvError::Lagged(skipped)) => debug!(skipped, "injected track lagged"),
+                        Err(RecvError::Closed) => open = false,
+                    },
+                    _ = tokio::time::sleep_until(next_due), if !held.is_empty() => {
+                        let (_, frame) = held.pop_front().expect("a frame is held");
+                        if sender.send(frame).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        injected
+    }
+}
+
+/// Random shares of the jitter, from xorshift64.
+struct Jitter {
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    fn new(max: Duration) -> Self {
+        Self { max, state: SEED }
+    }
+
+    fn next(&mut self) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        let fraction = (self.state >> 11) as f64 / (1_u64 << 53) as f64;
+        self.max.mul_f64(fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        codec::{opus::OpusChannels, Codec},
+        media::{MediaFrame, TrackKind},
+    };
+
+    fn frame(n: u8) -> MediaFrame {
+        MediaFrame {
+            payload: Bytes::from(vec![n]),
+            sample_count: None,
+            skipped_frames: None,
+            skipped_samples: None,
+            reset: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_arrive_late_and_in_order() {
+        let delays = ["capture=30".parse().unwrap()];
+        let jitters = ["capture=20".parse().unwrap(), "playback=5".parse().unwrap()];
+        let injection = Injection::for_direction(&delays, &jitters, Direction::Capture);
+        assert_eq!(injection.jitter, Duration::from_millis(20));
+        assert!(Injection::for_direction(&delays, &[], Direction::Playback).is_none());
+        assert!("mic=5".parse::<DirectedMs>().is_err());
+
+        let (mut sender, receiver) = channel(16, OverflowPolicy::default(), Counter::default());
+        let codec = Codec::Opus {
+            channels: OpusChannels::Stereo,
+        };
+        let track = MediaTrack::new(receiver, codec, TrackKind::Audio);
+        let mut track = injection.apply(track, Counter::default());
+        let mut sent = Vec::new();
+        for n in 0..5 {
+            sender.send(frame(n)).unwrap();
+            sent.push(Instant::now());
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(sender);
+        for (n, sent) in sent.into_iter().enumerate() {
+            let frame = track.recv().await.unwrap();
+            assert_eq!(frame.payload[..], [n as u8]);
+            assert!(sent.elapsed() >= injection.delay);
+        }
+        assert!(matches!(track.recv().await, Err(RecvError::Closed)));
+    }
+}
@@ -0,0 +1,366 @@
+//! Bounded single-producer/single-consumer frame queue.
+//!
+//! Replaces `tokio::sync::broadcast` for the capture → publish and
+//! receive → playback paths: frames are moved instead of cloned per subscriber,
+//! and overflow behavior is explicit rather than a side effect of `Lagged`.
+//!
+//! Frames go through a lock-free ring buffer, so that the capture thread can
+//! send without taking a lock. Only waking a parked receiver, or an async
+//! sender waiting for room, goes through a [`Notify`].
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use ringbuf::{
+    traits::{Consumer as _, Observer as _, Producer as _, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use tokio::sync::Notify;
+
+use super::MediaFrame;
+use crate::stats::Counter;
+
+/// What a full queue does with a new frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued frame; keeps latency bounded (live calls).
+    #[default]
+    DropOldest,
+    /// Discard the new frame; keeps what is already queued intact.
+    DropNewest,
+    /// Wait up to the timeout for the consumer to make room, then drop the
+    /// new frame; favors completeness over latency (recording). Only
+    /// [`MediaSender::send_async`] waits: a synchronous sender is on an audio
+    /// thread, which must never stall, and drops the new frame at once.
+    Block(Duration),
+}
+
+impl OverflowPolicy {
+    /// Parse a policy for a queue fed from the audio thread, which must not
+    /// block.
+    pub fn parse_non_blocking(s: &str) -> anyhow::Result<Self> {
+        match s.parse()? {
+            Self::Block(_) => {
+                bail!("block:<ms> would stall the audio thread; use drop-oldest or drop-newest")
+            }
+            policy => Ok(policy),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            _ => {
+                let ms = s.strip_prefix("block:").ok_or_else(|| {
+                    anyhow!("expected drop-oldest, drop-newest or block:<ms>, got `{s}`")
+                })?;
+                let ms = ms
+                    .parse()
+                    .with_context(|| format!("invalid block timeout `{ms}`"))?;
+                Ok(Self::Block(Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The sender was dropped and all queued frames have been received.
+    Closed,
+    /// The receiver fell behind and this many frames were dropped.
+    Lagged(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+    Lagged(u64),
+}
+
+/// Returned by [`MediaSender::send`] when the receiver is gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError;
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "media queue receiver closed")
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[derive(Debug)]
+struct Shared {
+    policy: OverflowPolicy,
+    /// Frames the receiver is handed at most, oldest dropped first with
+    /// [`OverflowPolicy::DropOldest`].
+    capacity: usize,
+    /// Frames dropped since the receiver last observed a lag.
+    dropped: AtomicU64,
+    sender_closed: AtomicBool,
+    receiver_closed: AtomicBool,
+    /// The receiver asked the sender to send what it holds back and close.
+    finishing: AtomicBool,
+    /// Wakes the receiver when a frame arrives or the sender closes.
+    readable: Notify,
+    /// Wakes a blocked async sender when the receiver pops a frame.
+    writable_async: Notify,
+    /// Total frames dropped by this queue, for stats.
+    dropped_total: Counter,
+}
+
+impl Shared {
+    fn dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+        self.dropped_total.add(count);
+    }
+}
+
+/// Create a queue holding at most `capacity` frames; `policy` decides what
+/// happens when it is full. Every dropped frame is added to `dropped`.
+pub fn channel(
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: Counter,
+) -> (MediaSender, MediaReceiver) {
+    assert!(capacity > 0, "media queue capacity must be non-zero");
+    // the sender cannot take frames back out, so dropping the oldest is up
+    // to the receiver; the slack holds what piles up until it reads again.
+    let ring = match policy {
+        OverflowPolicy::DropOldest => 2 * capacity,
+        OverflowPolicy::DropNewest | OverflowPolicy::Block(_) => capacity,
+    };
+    let (producer, consumer) = HeapRb::new(ring).split();
+    let shared = Arc::new(Shared {
+        policy,
+        capacity,
+        dropped: AtomicU64::new(0),
+        sender_closed: AtomicBool::new(false),
+        receiver_closed: AtomicBool::new(false),
+        finishing: AtomicBool::new(false),
+        readable: Notify::new(),
+        writable_async: Notify::new(),
+        dropped_total: dropped,
+    });
+    (
+        MediaSender {
+            frames: producer,
+            shared: shared.clone(),
+        },
+        MediaReceiver {
+            frames: consumer,
+            shared,
+        },
+    )
+}
+
+#[derive(derive_more::Debug)]
+pub struct MediaSender {
+    #[debug(skip)]
+    frames: HeapProd<MediaFrame>,
+    shared: Arc<Shared>,
+}
+
+impl MediaSender {
+    /// Enqueue a frame without ever waiting: with [`OverflowPolicy::Block`] a
+    /// full queue drops it as with `DropNewest`. Use
+    /// [`MediaSender::send_async`] from async tasks.
+    pub fn send(&mut self, frame: MediaFrame) -> Result<(), SendError> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(SendError);
+        }
+        // the new frame is the one that doesn't make it.
+        if self.frames.try_push(frame).is_err() {
+            self.shared.dropped(1);
+            return Ok(());
+        }
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Enqueue a frame, awaiting room instead of blocking the thread.
+    pub async fn send_async(&mut self, frame: MediaFrame) -> Result<(), SendError> {
+        if let OverflowPolicy::Block(timeout) = self.shared.policy {
+            let deadline = tokio::time::Instant::now() + timeout;
+            let shared = &self.shared;
+            loop {
+                let notified = shared.writable_async.notified();
+                if !self.frames.is_full() || shared.receiver_closed.load(Ordering::Acquire) {
+                    break;
+                }
+                if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                    break;
+                }
+            }
+        }
+        self.send(frame)
+    }
+
+    /// Whether the receiver asked for what is still held back, e.g. the
+    /// frame being collected, before the sender closes.
+    pub fn is_finishing(&self) -> bool {
+        self.shared.finishing.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MediaSender {
+    fn drop(&mut self) {
+        self.shared.sender_closed.store(true, Ordering::Release);
+        self.shared.readable.notify_one();
+    }
+}
+
+#[derive(derive_more::Debug)]
+pub struct MediaReceiver {
+    #[debug(skip)]
+    frames: HeapCons<MediaFrame>,
+    shared: Arc<Shared>,
+}
+
+impl MediaReceiver {
+    pub async fn recv(&mut self) -> Result<MediaFrame, RecvError> {
+        loop {
+            match self.try_recv() {
+                Ok(frame) => return Ok(frame),
+                Err(TryRecvError::Lagged(n)) => return Err(RecvError::Lagged(n)),
+                Err(TryRecvError::Closed) => return Err(RecvError::Closed),
+                Err(TryRecvError::Empty) => self.shared.readable.notified().await,
+            }
+        }
+    }
+
+    /// Ask the sender to send what it still holds back, then close.
+    pub fn finish(&self) {
+        self.shared.finishing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn try_recv(&mut self) -> Result<MediaFrame, TryRecvError> {
+        if self.shared.policy == OverflowPolicy::DropOldest {
+            let excess = self
+                .frames
+                .occupied_len()
+                .saturating_sub(self.shared.capacity);
+            if excess > 0 {
+                let skipped = self.frames.skip(excess);
+                self.shared.dropped(skipped as u64);
+            }
+        }
+        let dropped = self.shared.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            return Err(TryRecvError::Lagged(dropped));
+        }
+        let frame = match self.frames.try_pop() {
+            Some(frame) => frame,
+            // the sender's last frames came before it closed.
+            None if self.shared.sender_closed.load(Ordering::Acquire) => {
+                self.frames.try_pop().ok_or(TryRecvError::Closed)?
+            }
+            None => return Err(TryRecvError::Empty),
+        };
+        self.shared.writable_async.notify_one();
+        Ok(frame)
+    }
+}
+
+impl Drop for MediaReceiver {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+        self.shared.writable_async.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn frame(byte: u8) -> MediaFrame {
+        MediaFrame {
+            payload: Bytes::from(vec![byte]),
+            sample_count: None,
+            skipped_frames: None,
+            skipped_samples: None,
+            reset: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_oldest_and_reports_lag() {
+        let dropped = Counter::default();
+        let (mut sender, mut receiver) = channel(2, OverflowPolicy::DropOldest, dropped.clone());
+        for byte in 0..4 {
+            sender.send(frame(byte)).unwrap();
+        }
+        drop(sender);
+
+        assert_eq!(receiver.recv().await.unwrap_err(), RecvError::Lagged(2));
+        assert_eq!(receiver.recv().await.unwrap().payload[0], 2);
+        assert_eq!(receiver.recv().await.unwrap().payload[0], 3);
+        assert_eq!(receiver.recv().await.unwrap_err(), RecvError::Closed);
+        assert_eq!(dropped.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_newest_when_blocking_times_out() {
+        let block = OverflowPolicy::Block(Duration::from_millis(5));
+        for policy in [OverflowPolicy::DropNewest, block] {
+            let (mut sender, mut receiver) = channel(2, policy, Counter::default());
+            for byte in 0..3 {
+                sender.send_async(frame(byte)).await.unwrap();
+            }
+            assert_eq!(receiver.recv().await.unwrap_err(), RecvError::Lagged(1));
+            assert_eq!(receiver.recv().await.unwrap().payload[0], 0);
+            assert_eq!(receiver.recv().await.unwrap().payload[0], 1);
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!(
+            "drop-newest".parse::<OverflowPolicy>().unwrap(),
+            OverflowPolicy::DropNewest
+        );
+        assert_eq!(
+            "block:50".parse::<OverflowPolicy>().unwrap(),
+            OverflowPolicy::Block(Duration::from_millis(50))
+        );
+        assert!("block".parse::<OverflowPolicy>().is_err());
+        assert!(OverflowPolicy::parse_non_blocking("block:50").is_err());
+        assert_eq!(
+            OverflowPolicy::parse_non_blocking("drop-newest").unwrap(),
+            OverflowPolicy::DropNewest
+        );
+    }
+
+    #[test]
+    fn synchronous_send_never_waits() {
+        let block = OverflowPolicy::Block(Duration::from_secs(10));
+        let (mut sender, _receiver) = channel(1, block, Counter::default());
+        let start = std::time::Instant::now();
+        sender.send(frame(0)).unwrap();
+        sender.send(frame(1)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn send_fails_once_receiver_is_dropped() {
+        let (mut sender, receiver) = channel(2, OverflowPolicy::default(), Counter::default());
+        drop(receiver);
+        assert_eq!(sender.send(frame(0)), Err(SendError));
+    }
+}
@@ -1,20 +1,148 @@
-use std::fmt;
+use std::{
+    cmp::Ordering,
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use ed25519_dalek::SigningKey;
 use moq_lite as moq;
-use tokio::{select, sync::broadcast as chan};
+use tokio::{
+    select,
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, warn};
 use url::Url;
 
+#[cfg(feature = "p2p")]
+pub use self::p2p::{P2pTransport, TransportKind, STUN_SERVER};
+use self::{
+    attach::AttachedTrack,
+    audio_level::{AudioLevelDecoder, AudioLevelEncoder},
+    backlog::SendQueue,
+    bandwidth::enforce_bandwidth_cap,
+    bridge::bridge_mix_path,
+    catalog::{
+        publish_catalog, read_catalog, Catalog, InputEntry, CATALOG_TRACK_NAME, CATALOG_VERSION,
+    },
+    control::{
+        next_frame, publish_control, send_marks, send_mic_status, send_presence, ControlChannel,
+        ControlReader, ControlVerifier, CONTROL_TRACK_NAME,
+    },
+    epoch::{CallEpoch, MediaClock},
+    fanout::{spawn_fanout, Published},
+    fingerprint::IdentityCheck,
+    frame::{Arrival, JitterEstimator, SequenceTracker, FLAG_END, FLAG_PAUSED, FLAG_RESET},
+    group::GroupBatcher,
+    heartbeat::{publish_heartbeats, watch_heartbeats, HEARTBEAT_TRACK_NAME},
+    instance::split_path,
+    media_transport::{MediaEvent, MediaTransport, MoqRelay},
+    pin::{PinCheck, Verdict, CHALLENGE_INTERVAL},
+    probe::{probe_bandwidth, PROBE_PATH},
+    queue::{caller_waiting, wait_turn, Turn, QUEUE_PATH},
+    redundancy::RedundancyEncoder,
+    report::{consume_reports, publish_reports, BitrateController, MAX_BITRATE, REPORT_TRACK_NAME},
+    retry::Backoff,
+    simulcast::{receive_simulcast, LAYERS},
+    transport::{Connection, Side},
+};
+pub use self::{
+    attach::{RemoteTrack, RemoteTracks},
+    bridge::{run_bridge, BridgeOptions},
+    capability::{Capabilities, Capability, PeerCapabilities},
+    control::{Moderator, ModeratorCommand, ModeratorKey},
+    delivery::Delivery,
+    direct::{DialTransport, Endpoint},
+    extension::{ExtensionDecoder, ExtensionEncoder, FrameExtensions},
+    frame::{FrameArena, FrameHeader},
+    group::GroupStrategy,
+    instance::InstanceId,
+    invite::{fresh_session, send_invite, watch_inbox, InboxEvent, Invite},
+    pin::Pin,
+    priority::{PriorityOverride, PriorityScheme, TrackPriorities},
+    redundancy::Redundancy,
+    standby::Standby,
+    trace::{replay_trace, FrameTrace, ReplayOptions},
+    transport::{CongestionController, IpVersion, RelayTransport, Transport, TransportOptions},
+};
 use crate::{
-    audio::AudioContext,
-    codec::{opus::OpusChannels, Codec},
-    media::{MediaFrame, MediaTrack, TrackKind},
+    audio::{Announcer, AudioContext, CallEvent, Greeting, VOICE_TRACK},
+    codec::{multistream::ChannelLayout, opus::OpusChannels, Codec, CodecPreference},
+    error::NeetError,
+    identity::Fingerprint,
+    media::{MediaFrame, MediaSender, MediaTrack, PauseState, Presence, RecvError, TrackKind},
+    quality::monitor_quality,
+    schedule::time_limit,
+    stats::{ConnectionStats, Stats},
 };
 
+mod attach;
+mod audio_level;
+mod backlog;
+mod bandwidth;
+mod bridge;
+mod capability;
+mod catalog;
+mod control;
+mod delivery;
+mod direct;
+mod epoch;
+mod extension;
+mod fanout;
+mod fingerprint;
+mod frame;
+mod group;
+mod heartbeat;
+mod instance;
+mod invite;
+mod media_transport;
+#[cfg(feature = "p2p")]
+mod p2p;
+mod pacing;
+mod pin;
+mod priority;
+mod probe;
+mod queue;
+mod quic;
+mod redundancy;
+mod report;
+mod retry;
+mod simulcast;
+mod standby;
+mod trace;
+mod transport;
+
 /// Default namespace appended to the relay path before the session identifier.
 const SESSION_NAMESPACE: &str = "neet";
 const AUDIO_TRACK_NAME: &str = "audio";
+/// The lossless rendition of the audio, offered next to it.
+const FLAC_TRACK_NAME: &str = "audio-flac";
+/// Prefix of the tracks further inputs are published on, before their name.
+const INPUT_TRACK_PREFIX: &str = "input-";
+/// Assumed frame duration when a frame does not carry its sample count.
+const DEFAULT_FRAME_DURATION: Duration = Duration::from_millis(20);
+/// How often QUIC connection statistics are sampled.
+const CONNECTION_STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait before reconnecting after a failure, doubling up to the maximum,
+/// with jitter.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// A connection that lasted this long resets the backoff.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+/// How long hanging up may take before the connection is dropped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a track's encoder may take to send the rest of its audio at
+/// hangup; a few capture ticks.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long the remote audio still queued may play after the call ends.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to collect the session's announcements before publishing.
+const ANNOUNCE_SETTLE: Duration = Duration::from_millis(300);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Role {
@@ -54,38 +182,450 @@ impl Role {
 
 #[derive(Clone)]
 pub struct MoqOptions {
-    pub relay_url: Url,
+    /// How the call reaches the other side.
+    pub route: Arc<dyn Transport>,
     pub session_id: String,
     pub role: Role,
+    pub group_strategy: GroupStrategy,
+    pub priorities: TrackPriorities,
+    pub delivery: Delivery,
+    pub redundancy: Redundancy,
+    /// Publish and receive several bitrate layers instead of a single track.
+    pub simulcast: bool,
+    /// Join a conference bridge under this name instead of calling one peer.
+    pub bridge_name: Option<String>,
+    /// Sign and publish moderator commands for this session.
+    pub moderator: Option<Moderator>,
+    /// Enforce commands signed with this key.
+    pub moderator_key: Option<ModeratorKey>,
+    /// Only play peers who prove they know this PIN.
+    pub pin: Option<Pin>,
+    /// Answer identity challenges with this key.
+    pub identity: Option<SigningKey>,
+    /// Only play a remote whose identity key has this fingerprint.
+    pub fingerprint: Option<Fingerprint>,
+    /// Hang up after this long.
+    pub max_duration: Option<Duration>,
+    /// Keep the microphone paused except while a remote is connected.
+    pub auto_answer: bool,
+    /// Wait for the next remote when one hangs up, and reconnect when the
+    /// relay connection drops, instead of ending the call.
+    pub auto_reconnect: bool,
+    /// Chime when a remote connects.
+    pub chime: bool,
+    /// Played to each caller `auto_answer` answers, before the microphone.
+    pub greeting: Option<Greeting>,
+    /// Play a notice when the remote starts recording the call.
+    pub recording_notice: bool,
+    /// Write the frames the remote's voice track delivers here.
+    pub trace: Option<FrameTrace>,
+    /// Also publish our broadcast on these relays.
+    pub fanout: Vec<Arc<dyn Transport>>,
+    /// Cancelled to hang up: capture stops, what was already encoded is
+    /// published with an end-of-stream marker, and playback drains.
+    pub shutdown: CancellationToken,
+    /// This run, among others publishing as the same role.
+    pub instance: InstanceId,
+    /// Take over from an older instance of our role instead of refusing.
+    pub force: bool,
+    /// Consider the remote lost after this long without a heartbeat.
+    pub liveness_timeout: Option<Duration>,
+    /// Capture and send this layout; anything but stereo bypasses the audio
+    /// engine.
+    pub layout: ChannelLayout,
+    /// Also publish lossless audio for receivers that can play it.
+    pub codec: CodecPreference,
+    /// Publish the Opus arriving over RTP here instead of captured audio.
+    pub input_rtp: Option<SocketAddr>,
+    /// Send remote audio here over RTP instead of playing it.
+    pub output_rtp: Option<SocketAddr>,
+    /// Hold each direction of the call under this many bits per second.
+    pub max_bandwidth: Option<u64>,
+    /// Subscribe to only these of the remote's tracks.
+    pub tracks: RemoteTracks,
+    /// Measure the bandwidth before each connection's call starts.
+    pub probe_bandwidth: bool,
+    /// Hold back all but the newest this much audio while the connection
+    /// does not keep up.
+    pub send_backlog: Option<Duration>,
+    /// Spread bursts of outgoing frames over their duration.
+    pub pace: bool,
+    /// A warm connection to take over instead of connecting.
+    pub standby: Option<Standby>,
+    /// Play only these of the remote's further inputs; all if unset.
+    pub play_inputs: Option<Vec<String>>,
+    /// Send the microphone's level in the frames of our audio.
+    pub send_audio_level: bool,
+    /// Refuse a remote that lacks any of these.
+    pub require: Vec<Capability>,
+    /// What we and the remote both understand, as learned from its catalog.
+    pub peer: PeerCapabilities,
+    /// What frames carry in their extension area, and what we read there.
+    pub extensions: FrameExtensions,
+}
+
+impl MoqOptions {
+    /// Where our broadcast is announced: a path of this instance's own
+    /// under our role, or our name on a bridge.
+    fn broadcast_path(&self) -> String {
+        match &self.bridge_name {
+            Some(name) => name.clone(),
+            None => self.instance.path(self.role.publish_path()),
+        }
+    }
+
+    /// What our catalog says we can receive.
+    fn capabilities(&self) -> Capabilities {
+        match self.role {
+            Role::Listener if self.auto_reconnect && self.bridge_name.is_none() => {
+                Capabilities::OURS.with(Capability::Queue)
+            }
+            _ => Capabilities::OURS,
+        }
+    }
+
+    /// Where the audio we play comes from: the peer, or our bridge mix.
+    fn subscribe_path(&self) -> String {
+        match &self.bridge_name {
+            Some(name) => bridge_mix_path(name),
+            None => self.role.subscribe_path().to_string(),
+        }
+    }
+
+    fn local_label(&self) -> &str {
+        match &self.bridge_name {
+            Some(name) => name,
+            None => self.role.local_label(),
+        }
+    }
+
+    pub fn remote_label(&self) -> &'static str {
+        match &self.bridge_name {
+            Some(_) => "conference",
+            None => self.role.remote_label(),
+        }
+    }
 }
 
 impl fmt::Debug for MoqOptions {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MoqOptions")
-            .field("relay_url", &self.relay_url)
+            .field("route", &self.route)
             .field("session_id", &self.session_id)
             .field("role", &self.role)
+            .field("group_strategy", &self.group_strategy)
+            .field("priorities", &self.priorities)
+            .field("delivery", &self.delivery)
+            .field("redundancy", &self.redundancy)
+            .field("simulcast", &self.simulcast)
+            .field("bridge_name", &self.bridge_name)
+            .field("moderator", &self.moderator)
+            .field("moderator_key", &self.moderator_key)
+            .field("pin", &self.pin)
+            .field(
+                "identity",
+                &self
+                    .identity
+                    .as_ref()
+                    .map(|key| Fingerprint::of(&key.verifying_key())),
+            )
+            .field("fingerprint", &self.fingerprint)
+            .field("max_duration", &self.max_duration)
+            .field("auto_answer", &self.auto_answer)
+            .field("auto_reconnect", &self.auto_reconnect)
+            .field("chime", &self.chime)
+            .field("greeting", &self.greeting)
+            .field("recording_notice", &self.recording_notice)
+            .field("trace", &self.trace)
+            .field("fanout", &self.fanout)
+            .field("shutdown", &self.shutdown.is_cancelled())
+            .field("instance", &self.instance)
+            .field("force", &self.force)
+            .field("liveness_timeout", &self.liveness_timeout)
+            .field("layout", &self.layout)
+            .field("codec", &self.codec)
+            .field("input_rtp", &self.input_rtp)
+            .field("output_rtp", &self.output_rtp)
+            .field("max_bandwidth", &self.max_bandwidth)
+            .field("tracks", &self.tracks)
+            .field("probe_bandwidth", &self.probe_bandwidth)
+            .field("send_backlog", &self.send_backlog)
+            .field("pace", &self.pace)
+            .field("standby", &self.standby.as_ref().map(Standby::target))
+            .field("play_inputs", &self.play_inputs)
+            .field("send_audio_level", &self.send_audio_level)
+            .field("require", &self.require)
+            .field("peer", &self.peer.get())
+            .field("extensions", &self.extensions)
             .finish()
     }
 }
 
 pub async fn run_audio_session(options: MoqOptions, audio: AudioContext) -> Result<()> {
-    let mut url = options.relay_url.clone();
-    append_session_path(&mut url, &options.session_id).with_context(|| {
-        format!(
-            "failed to extend relay url with session '{}': {url}",
-            options.session_id
-        )
-    })?;
+    info!(role = ?options.role, "starting call");
+    let call_started = Instant::now();
+    let busy_at_start = audio.stats().pipeline_busy_us.get();
+    let quality = tokio::spawn(monitor_quality(audio.stats().clone()));
+    let bandwidth = options.max_bandwidth.map(|cap| {
+        tokio::spawn(enforce_bandwidth_cap(
+            cap,
+            audio.stats().clone(),
+            audio.bitrate().clone(),
+            audio.mode().bitrate().unwrap_or(MAX_BITRATE),
+        ))
+    });
+    // each fan-out relay republishes whatever the call's relay currently has.
+    let (published, published_rx) = watch::channel(None);
+    let fanout: Vec<_> = options
+        .fanout
+        .iter()
+        .map(|route| spawn_fanout(route.clone(), published_rx.clone()))
+        .collect();
 
-    info!(role = ?options.role, %url, "connecting to relay");
+    let connections = async {
+        let mut backoff = Backoff::new(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF);
+        loop {
+            let started = Instant::now();
+            let result = run_connection(&options, &audio, &published).await;
+            let err = match result {
+                Err(err)
+                    if options.auto_reconnect
+                        && !options.shutdown.is_cancelled()
+                        && !matches!(
+                            err.downcast_ref::<NeetError>(),
+                            Some(NeetError::SessionRejected(_))
+                        ) =>
+                {
+                    err
+                }
+                result => return result,
+            };
+            if started.elapsed() >= STABLE_CONNECTION {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            warn!("{err:#}; reconnecting in {:.1}s", delay.as_secs_f32());
+            select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = options.shutdown.cancelled() => return Ok(()),
+            }
+            audio.stats().connection.reconnects.add(1);
+        }
+    };
+    let hangup = async {
+        if let Some(limit) = options.max_duration {
+            time_limit(limit, |warning| audio.beep(warning)).await;
+            options.shutdown.cancel();
+        }
+        options.shutdown.cancelled().await;
+        // a relay that stops answering must not keep us from exiting.
+        tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+        warn!("timed out hanging up; dropping the connection");
+    };
+    let result = select! {
+        res = connections => res,
+        _ = hangup => Ok(()),
+    };
 
-    let client = moq_native::Client::new(moq_native::ClientConfig::default())
-        .context("failed to build MoQ client")?;
-    let connection = client
-        .connect(url.clone())
+    quality.abort();
+    if let Some(bandwidth) = bandwidth {
+        bandwidth.abort();
+    }
+    // fan-out relays close their sessions once the call's broadcast is gone.
+    drop(published);
+    for (task, health) in fanout {
+        let abort = task.abort_handle();
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await.is_err() {
+            abort.abort();
+        }
+        info!(relay = health.target, health = ?health.snapshot(), "fan-out relay health");
+    }
+    if tokio::time::timeout(DRAIN_TIMEOUT, audio.playback_drained())
         .await
-        .context("failed to connect to relay")?;
+        .is_err()
+    {
+        debug!("remote audio still queued at hangup");
+    }
+    let summary = audio.stats().quality.snapshot();
+    info!(
+        receive_mos = summary.receive_mos_avg,
+        send_mos = summary.send_mos_avg,
+        "call quality (average MOS)"
+    );
+    info!(stats = ?audio.stats().snapshot(), "call statistics");
+    let connection = &audio.stats().connection;
+    info!(
+        sent = %format_bytes(connection.total_sent.get()),
+        received = %format_bytes(connection.total_received.get()),
+        "data usage"
+    );
+    let busy = audio.stats().pipeline_busy_us.get() - busy_at_start;
+    let elapsed = call_started.elapsed().as_micros().max(1) as f64;
+    info!(
+        cpu_pct = format!("{:.1}", busy as f64 * 100. / elapsed),
+        "audio pipeline CPU (share of one core)"
+    );
+    result
+}
+
+/// `bytes` in kB or MB, for people checking a data plan.
+pub fn format_bytes(bytes: u64) -> String {
+    if bytes < 1_000_000 {
+        format!("{:.1} kB", bytes as f64 / 1e3)
+    } else {
+        format!("{:.2} MB", bytes as f64 / 1e6)
+    }
+}
+
+/// One connection to the relay, until the call ends or the connection drops.
+async fn run_connection(
+    options: &MoqOptions,
+    audio: &AudioContext,
+    published: &watch::Sender<Option<Published>>,
+) -> Result<()> {
+    if options.auto_answer {
+        // nothing goes out until someone calls.
+        audio.set_paused(true);
+    }
+    let warm = match &options.standby {
+        Some(standby) => standby.take().await,
+        None => None,
+    };
+    // a warm connection already knows who is here.
+    let settle_for = if warm.is_some() {
+        Duration::ZERO
+    } else {
+        ANNOUNCE_SETTLE
+    };
+    let relay: Box<dyn MediaTransport> = match warm {
+        Some(mut relay) => {
+            info!("taking over the standby connection");
+            relay.hand_over(audio.stats().connection.clone());
+            Box::new(relay)
+        }
+        None => {
+            Box::new(MoqRelay::connect(&*options.route, audio.stats().connection.clone()).await?)
+        }
+    };
+    // see who is already here before announcing ourselves, and wait our
+    // turn if the listener is on another call.
+    let Turn {
+        listener: remote,
+        place: _place,
+    } = if options.role == Role::Caller && options.bridge_name.is_none() && !options.force {
+        wait_turn(&*relay, options, audio, settle_for).await?
+    } else {
+        Turn {
+            listener: settle(&*relay, options, settle_for, audio).await?,
+            place: None,
+        }
+    };
+    if options.shutdown.is_cancelled() {
+        return Ok(());
+    }
+    let redundancy = if options.probe_bandwidth {
+        start_from_probe(&*relay, options, audio).await
+    } else {
+        options.redundancy
+    };
+
+    // frames for our control track, from the moderator and the PIN handshake.
+    let control = options
+        .moderator
+        .as_ref()
+        .map(Moderator::channel)
+        .unwrap_or_default();
+
+    // Start piping capture audio -> MoQ
+    let publish_task = publish_audio(
+        audio.clone(),
+        options,
+        &*relay,
+        control.clone(),
+        published,
+        redundancy,
+    );
+
+    // Start reading remote MoQ audio -> playback
+    let subscribe_task = subscribe_audio(audio.clone(), options, &*relay, remote, control);
+
+    // the session can only be closed once nothing borrows it.
+    let result = {
+        tokio::pin!(publish_task);
+        tokio::pin!(subscribe_task);
+
+        let session_closed = relay.closed();
+        tokio::pin!(session_closed);
+
+        select! {
+            biased;
+            _ = options.shutdown.cancelled() => {
+                // both sides wind down on their own; let them finish.
+                let (published, subscribed) = tokio::join!(publish_task, subscribe_task);
+                published
+                    .context("publish task failed")
+                    .and(subscribed.context("subscribe task failed"))
+            }
+            res = &mut publish_task => {
+                res.context("publish task failed")
+            }
+            res = &mut subscribe_task => {
+                res.context("subscribe task failed")
+            }
+            err = &mut session_closed => {
+                Err(NeetError::Transport(format!("MoQ session closed: {err}")).into())
+            }
+        }
+    };
+    if options.shutdown.is_cancelled() {
+        // the relay drops our announcements with the session.
+        relay.close();
+    }
+    result
+}
+
+/// With `--probe-bandwidth`, measure the path through the relay and set the
+/// starting bitrate to fit it. Returns the redundancy that fits too.
+async fn start_from_probe(
+    transport: &dyn MediaTransport,
+    options: &MoqOptions,
+    audio: &AudioContext,
+) -> Redundancy {
+    info!("probing the bandwidth to the relay");
+    let path = options.instance.path(PROBE_PATH);
+    let Some(rate) = probe_bandwidth(transport, &path).await else {
+        return options.redundancy;
+    };
+    let max_bitrate = audio.mode().bitrate().unwrap_or(MAX_BITRATE);
+    let (bitrate, redundancy) = probe::fit(rate, max_bitrate, options.redundancy);
+    info!(
+        rate_bps = rate,
+        bitrate,
+        redundancy = redundancy.frames,
+        "starting at the probed bandwidth"
+    );
+    audio.bitrate().set(bitrate);
+    redundancy
+}
+
+/// A MoQ session with the relay (or the peer), joined to one call session.
+struct Relay {
+    session: moq::Session,
+    /// Broadcasts published here are announced through the relay.
+    publish: moq::OriginProducer,
+    /// Broadcasts announced by everyone else in the session.
+    subscribe: moq::OriginConsumer,
+    connection: Connection,
+    /// Samples QUIC statistics until aborted.
+    sampler: JoinHandle<()>,
+}
+
+/// Open a connection over `route` and start a MoQ session on it.
+async fn connect(route: &dyn Transport, stats: ConnectionStats) -> Result<Relay> {
+    let started = Instant::now();
+    let (connection, side) = route.open().await.with_context(|| {
+        NeetError::RelayUnreachable(format!("could not reach {}", route.target()))
+    })?;
 
     let moq::Produce {
         producer: publish_producer,
@@ -96,35 +636,58 @@ pub async fn run_audio_session(options: MoqOptions, audio: AudioContext) -> Resu
         consumer: subscribe_consumer,
     } = moq::Origin::produce();
 
-    let session = moq::Session::connect(connection, publish_consumer, Some(subscribe_producer))
-        .await
-        .context("failed to establish MoQ session")?;
-
-    // Start piping capture audio -> MoQ
-    let publish_task = publish_audio(audio.clone(), options.role, publish_producer);
-
-    // Start reading remote MoQ audio -> playback
-    let subscribe_task = subscribe_audio(audio.clone(), options.role, subscribe_consumer);
-
-    tokio::pin!(publish_task);
-    tokio::pin!(subscribe_task);
+    let stats_connection = connection.clone();
+    let session = if side == Side::Server {
+        moq::Session::accept(connection, publish_consumer, Some(subscribe_producer)).await
+    } else {
+        moq::Session::connect(connection, publish_consumer, Some(subscribe_producer)).await
+    }
+    .context(NeetError::Transport(
+        "failed to establish MoQ session".to_string(),
+    ))?;
+    let took = started.elapsed();
+    debug!(target = route.target(), ?took, "connected");
+    stats.connect_us.set(took.as_micros() as u64);
 
-    let session_closed = async { session.closed().await };
-    tokio::pin!(session_closed);
+    Ok(Relay {
+        session,
+        publish: publish_producer,
+        subscribe: subscribe_consumer,
+        sampler: sample_connection(stats_connection.clone(), stats),
+        connection: stats_connection,
+    })
+}
 
-    select! {
-        res = &mut publish_task => {
-            res.context("publish task failed")?
-        }
-        res = &mut subscribe_task => {
-            res.context("subscribe task failed")?
-        }
-        err = &mut session_closed => {
-            return Err(anyhow!("MoQ session closed: {err}"));
+/// Sample the transport stats of `connection` into `stats` until aborted, so
+/// that pipeline problems can be told apart from network ones.
+fn sample_connection(connection: Connection, stats: ConnectionStats) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CONNECTION_STATS_INTERVAL);
+        let (mut last_sent, mut last_received) = (0, 0);
+        let mut last_sample = Instant::now();
+        loop {
+            interval.tick().await;
+            let sample = connection.stats();
+            let (sent, received) = (sample.udp_tx.bytes, sample.udp_rx.bytes);
+            let elapsed = last_sample.elapsed().as_secs_f64().max(1e-3);
+            last_sample = Instant::now();
+            stats.total_sent.add(sent - last_sent);
+            stats.total_received.add(received - last_received);
+            stats
+                .send_bps
+                .set(((sent - last_sent) as f64 * 8. / elapsed) as u64);
+            stats
+                .receive_bps
+                .set(((received - last_received) as f64 * 8. / elapsed) as u64);
+            (last_sent, last_received) = (sent, received);
+            stats.rtt_us.set(sample.path.rtt.as_micros() as u64);
+            stats.cwnd.set(sample.path.cwnd);
+            stats.lost_packets.set(sample.path.lost_packets);
+            stats.bytes_sent.set(sample.udp_tx.bytes);
+            stats.bytes_received.set(sample.udp_rx.bytes);
+            debug!(connection = ?stats.snapshot(), "connection statistics");
         }
-    }
-
-    Ok(())
+    })
 }
 
 fn append_session_path(url: &mut Url, session: &str) -> Result<()> {
@@ -142,174 +705,1107 @@ fn append_session_path(url: &mut Url, session: &str) -> Result<()> {
     Ok(())
 }
 
-fn publish_audio(
+async fn publish_audio(
     audio: AudioContext,
-    role: Role,
-    origin: moq::OriginProducer,
-) -> impl std::future::Future<Output = Result<()>> {
-    async move {
-        let capture_track = audio
+    options: &MoqOptions,
+    transport: &dyn MediaTransport,
+    control: ControlChannel,
+    fanout: &watch::Sender<Option<Published>>,
+    redundancy: Redundancy,
+) -> Result<()> {
+    let mut capture_tracks = Vec::new();
+    if let Some(addr) = options.input_rtp {
+        capture_tracks.push((AUDIO_TRACK_NAME.to_string(), audio.rtp_track(addr).await?));
+    } else if options.simulcast {
+        for layer in LAYERS {
+            let track = audio
+                .capture_track_at(layer.bitrate)
+                .await
+                .with_context(|| format!("failed to create capture track for {}", layer.name))?;
+            capture_tracks.push((layer.name.to_string(), track));
+        }
+    } else if options.layout == ChannelLayout::Mono {
+        let track = audio
+            .mono_track()
+            .await
+            .context("failed to create mono capture track")?;
+        capture_tracks.push((AUDIO_TRACK_NAME.to_string(), track));
+    } else if options.layout != ChannelLayout::Stereo {
+        let track = audio
+            .surround_track(options.layout)
+            .await
+            .with_context(|| format!("failed to create {} capture track", options.layout))?;
+        capture_tracks.push((AUDIO_TRACK_NAME.to_string(), track));
+    } else {
+        let track = audio
             .capture_track()
             .await
             .context("failed to create capture track")?;
+        capture_tracks.push((AUDIO_TRACK_NAME.to_string(), track));
+    }
+    // every track counts from the same epoch, so they line up.
+    let epoch = CallEpoch::now();
+    let mut catalog = Catalog::new(capture_tracks[0].1.codec())
+        .with_epoch(epoch)
+        .with_capabilities(options.capabilities());
+    if options.codec == CodecPreference::Flac {
+        let track = audio
+            .flac_track()
+            .await
+            .context("failed to create FLAC capture track")?;
+        capture_tracks.push((FLAC_TRACK_NAME.to_string(), track));
+        catalog = catalog.with_lossless();
+    }
+    for (name, track) in audio
+        .input_tracks()
+        .await
+        .context("failed to create input tracks")?
+    {
+        let track_name = format!("{INPUT_TRACK_PREFIX}{name}");
+        catalog = catalog.with_input(&name, &track_name, track.codec());
+        capture_tracks.push((track_name, track));
+    }
 
-        let mut broadcast = moq::Broadcast::produce();
-        let track_producer = broadcast.producer.create_track(moq::Track {
-            name: AUDIO_TRACK_NAME.to_string(),
-            priority: 0,
-        });
+    let mut broadcast = moq::Broadcast::produce();
+    let track_producers: Vec<_> = capture_tracks
+        .iter()
+        .map(|(name, _)| {
+            broadcast
+                .producer
+                .create_track(options.priorities.track(name, TrackKind::Audio))
+        })
+        .collect();
+    let report_producer = broadcast.producer.create_track(
+        options
+            .priorities
+            .track(REPORT_TRACK_NAME, TrackKind::Control),
+    );
+    let heartbeat_producer = broadcast.producer.create_track(
+        options
+            .priorities
+            .track(HEARTBEAT_TRACK_NAME, TrackKind::Control),
+    );
+    // kept alive until we stop publishing.
+    let mut catalog_producer = broadcast.producer.create_track(
+        options
+            .priorities
+            .track(CATALOG_TRACK_NAME, TrackKind::Control),
+    );
+    publish_catalog(&mut catalog_producer, &catalog)?;
 
-        let path = role.publish_path();
-        let published = origin.publish_broadcast(path, broadcast.consumer.clone());
-        if !published {
-            warn!(%path, "broadcast already existed; replacing");
-        }
+    // there is always our presence to tell.
+    let control_producer = broadcast.producer.create_track(
+        options
+            .priorities
+            .track(CONTROL_TRACK_NAME, TrackKind::Control),
+    );
 
-        forward_media_to_moq(capture_track, track_producer).await?;
+    let path = options.broadcast_path();
+    let published = transport.publish_track(&path, broadcast.consumer.clone());
+    if !published {
+        warn!(%path, "broadcast already existed; replacing");
+    }
+    fanout.send_replace(Some(Published {
+        path: path.clone(),
+        broadcast: broadcast.consumer.clone(),
+    }));
 
-        Ok(())
+    let group_strategy = match options.delivery {
+        Delivery::Reliable => options.group_strategy,
+        // a frame must never wait behind another one on the same stream.
+        Delivery::Datagram => {
+            if options.group_strategy != GroupStrategy::PerFrame {
+                warn!("datagram delivery sends one frame per group; ignoring --group");
+            }
+            GroupStrategy::PerFrame
+        }
+    };
+    let reports = tokio::spawn(publish_reports(report_producer, audio.stats().clone()));
+    let heartbeats = tokio::spawn(publish_heartbeats(heartbeat_producer));
+    let mic_status = audio
+        .mic_status()
+        .map(|status| tokio::spawn(send_mic_status(status, control.clone())));
+    let presence = tokio::spawn(send_presence(
+        audio.pause_state().subscribe(),
+        control.clone(),
+    ));
+    let marks = tokio::spawn(send_marks(audio.marks(), control.clone()));
+    let control = tokio::spawn(publish_control(
+        control_producer,
+        control,
+        options.moderator.clone(),
+    ));
+    let forwards: Vec<_> = capture_tracks
+        .into_iter()
+        .zip(track_producers)
+        .map(|((name, capture_track), track_producer)| {
+            let mut extensions = options.extensions.clone();
+            // the level is the microphone's, which extra inputs and RTP aren't.
+            let voice = !name.starts_with(INPUT_TRACK_PREFIX) && options.input_rtp.is_none();
+            if options.send_audio_level && voice {
+                extensions = extensions.with_encoder(AudioLevelEncoder::new(
+                    audio.stats().capture_level.clone(),
+                    options.peer.clone(),
+                ));
+            }
+            let redundancy =
+                RedundancyEncoder::new(redundancy, audio.stats().remote_loss_permille.clone())
+                    .with_bitrate(audio.bitrate().clone())
+                    .with_extensions(extensions)
+                    .with_peer(options.peer.clone());
+            let mut queue = SendQueue::new(track_producer);
+            if let Some(limit) = options.send_backlog {
+                queue = queue.with_backlog_limit(
+                    limit,
+                    audio.stats().connection.total_sent.clone(),
+                    audio.stats().send_dropped.clone(),
+                );
+            }
+            if options.pace {
+                queue = queue.with_pacing();
+            }
+            tokio::spawn(forward_media_to_moq(
+                capture_track,
+                queue,
+                group_strategy,
+                redundancy,
+                audio.pause_state().clone(),
+                options.shutdown.clone(),
+                epoch,
+            ))
+        })
+        .collect();
+    let mut result = Ok(());
+    for forward in forwards {
+        result = result.and(forward.await?);
+    }
+    reports.abort();
+    heartbeats.abort();
+    control.abort();
+    presence.abort();
+    marks.abort();
+    if let Some(mic_status) = mic_status {
+        mic_status.abort();
     }
+    drop(catalog_producer);
+    result
 }
 
-fn subscribe_audio(
+async fn subscribe_audio(
     audio: AudioContext,
-    role: Role,
-    mut origin: moq::OriginConsumer,
-) -> impl std::future::Future<Output = Result<()>> {
-    async move {
-        let target_path = role.subscribe_path();
-        info!(
-            local = role.local_label(),
-            remote = role.remote_label(),
-            target_path,
-            "waiting for remote broadcast"
-        );
+    options: &MoqOptions,
+    transport: &dyn MediaTransport,
+    mut candidate: Option<Announced>,
+    control: ControlChannel,
+) -> Result<()> {
+    let target_path = options.subscribe_path();
+    info!(
+        local = options.local_label(),
+        remote = options.remote_label(),
+        target_path,
+        "waiting for remote broadcast"
+    );
 
-        loop {
-            if let Some(broadcast) = origin.consume_broadcast(target_path) {
-                info!(target_path, "remote broadcast available; attaching");
-                handle_remote_broadcast(audio.clone(), broadcast).await?;
-                return Ok(());
+    // the remote instance we last played; older ones are stale.
+    let mut newest = None;
+    loop {
+        let announced = match candidate.take() {
+            Some(announced) => announced,
+            None => select! {
+                announced = announcement(transport, options, newest, &audio) => announced?,
+                _ = options.shutdown.cancelled() => return Ok(()),
+            },
+        };
+        newest = announced.instance.or(newest);
+        info!(target_path, instance = ?announced.instance, "remote broadcast available; attaching");
+        // a fresh challenge for every candidate, bound to the paths both
+        // sides actually publish at, as the bridge binds it.
+        let pin = options
+            .pin
+            .as_ref()
+            .map(|pin| {
+                PinCheck::new(
+                    pin,
+                    &options.session_id,
+                    options.broadcast_path(),
+                    announced.path(&target_path),
+                )
+            })
+            .transpose()?;
+        let identity = IdentityCheck::new(
+            options.identity.clone(),
+            options.fingerprint,
+            &options.session_id,
+            options.broadcast_path(),
+            announced.path(&target_path),
+        )?;
+        let broadcast = announced.broadcast;
+        let attached = async {
+            if !authenticate_remote(options, &broadcast, pin.as_ref(), &identity, &control).await? {
+                return Ok(None);
             }
+            let control = control.clone();
+            handle_remote_broadcast(audio.clone(), options, broadcast, control, pin, identity)
+                .await
+                .map(Some)
+        };
+        let end = select! {
+            end = attached => end?,
+            // a remote that restarted is announced again, while its old
+            // broadcast may not have timed out yet.
+            announced = announcement(transport, options, newest, &audio) => {
+                info!(target_path, "remote broadcast announced again; re-attaching");
+                options.peer.set(Capabilities::LEGACY);
+                candidate = Some(announced?);
+                continue;
+            }
+        };
+        // what the remote understood left with it; the next one has yet to
+        // say, and may be older.
+        options.peer.set(Capabilities::LEGACY);
+        if options.shutdown.is_cancelled() {
+            return Ok(());
+        }
+        match end {
+            None => warn!(target_path, "remote failed to authenticate; ignoring it"),
+            Some(RemoteEnd::HungUp) if !options.auto_reconnect => return Ok(()),
+            Some(RemoteEnd::HungUp) => {
+                info!(target_path, "remote hung up; waiting for the next one")
+            }
+            Some(RemoteEnd::Vanished) => warn!(
+                target_path,
+                "remote went away without hanging up; waiting for it to come back"
+            ),
+        }
+    }
+}
 
-            match origin.announced().await {
-                Some((path, Some(broadcast))) => {
-                    let path_str = path.as_str();
-                    debug!(%path_str, "received broadcast announcement");
-                    if path_str == target_path {
-                        handle_remote_broadcast(audio.clone(), broadcast).await?;
-                        return Ok(());
-                    }
-                }
-                Some((_path, None)) => {
-                    // broadcast removed; keep waiting
+/// How a remote broadcast we played came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RemoteEnd {
+    /// It sent an end-of-stream marker, or we hung up.
+    HungUp,
+    /// It stopped without one: the remote crashed or lost its connection.
+    Vanished,
+}
+
+/// A broadcast of the remote, and which run of it published it.
+struct Announced {
+    broadcast: moq::BroadcastConsumer,
+    /// None for a bridge mix, which has no instances.
+    instance: Option<InstanceId>,
+}
+
+impl Announced {
+    /// The path the broadcast was announced at, for `target_path`; the
+    /// remote's side of what [`MoqOptions::broadcast_path`] is for us.
+    fn path(&self, target_path: &str) -> String {
+        match self.instance {
+            Some(instance) => instance.path(target_path),
+            None => target_path.to_string(),
+        }
+    }
+}
+
+/// Collect what is announced for `wait`, so that another instance of our
+/// role is noticed before we publish. Returns the newest remote seen.
+async fn settle(
+    transport: &dyn MediaTransport,
+    options: &MoqOptions,
+    wait: Duration,
+    audio: &AudioContext,
+) -> Result<Option<Announced>> {
+    if options.bridge_name.is_some() {
+        let broadcast = transport.subscribe_track(&options.subscribe_path());
+        return Ok(broadcast.map(|broadcast| Announced {
+            broadcast,
+            instance: None,
+        }));
+    }
+    let mut remote: Option<Announced> = None;
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        let newest = remote.as_ref().and_then(|remote| remote.instance);
+        select! {
+            // whatever is queued already counts, however short the wait.
+            biased;
+            announced = announcement(transport, options, newest, audio) => remote = Some(announced?),
+            _ = &mut deadline => return Ok(remote),
+        }
+    }
+}
+
+/// Wait until the remote is announced, skipping instances older than
+/// `newest`. Fails when another instance of our own role is in the way, and
+/// tells the user when a caller queues for the line.
+async fn announcement(
+    transport: &dyn MediaTransport,
+    options: &MoqOptions,
+    newest: Option<InstanceId>,
+    audio: &AudioContext,
+) -> Result<Announced> {
+    let target_path = options.subscribe_path();
+    loop {
+        let Some(event) = transport.events().await else {
+            return Err(anyhow!("announcement stream closed"));
+        };
+        let (path, broadcast) = match event {
+            MediaEvent::Announced { path, broadcast } => (path, broadcast),
+            // keep waiting.
+            MediaEvent::Withdrawn { path } => {
+                debug!(%path, "broadcast withdrawn");
+                continue;
+            }
+        };
+        let path_str = path.as_str();
+        debug!(%path_str, "received broadcast announcement");
+        if options.bridge_name.is_some() {
+            if path_str == target_path {
+                return Ok(Announced {
+                    broadcast,
+                    instance: None,
+                });
+            }
+            continue;
+        }
+        let Some((role, instance)) = split_path(path_str) else {
+            continue;
+        };
+        if role == options.role.publish_path() {
+            check_rival(options, instance)?;
+        } else if role == QUEUE_PATH {
+            if instance != options.instance {
+                caller_waiting(audio, instance);
+            }
+        } else if role != target_path {
+            continue;
+        } else if newest.is_some_and(|newest| instance < newest) {
+            debug!(%instance, "ignoring an older instance of the remote");
+        } else {
+            return Ok(Announced {
+                broadcast,
+                instance: Some(instance),
+            });
+        }
+    }
+}
+
+/// Decide what to do about `instance`, announced under our own role: the
+/// newest instance wins, and an older one is only displaced with `--force`.
+fn check_rival(options: &MoqOptions, instance: InstanceId) -> Result<()> {
+    let role = options.role.local_label();
+    match instance.cmp(&options.instance) {
+        Ordering::Equal => Ok(()),
+        Ordering::Greater => Err(NeetError::SessionRejected(format!(
+            "another {role} ({instance}) took over this session"
+        ))
+        .into()),
+        Ordering::Less if options.force => {
+            warn!(%instance, "taking over from an older {role}");
+            Ok(())
+        }
+        Ordering::Less => Err(NeetError::SessionRejected(format!(
+            "another {role} ({instance}) is already in this session; \
+             if it is a run that crashed, pass --force to take over"
+        ))
+        .into()),
+    }
+}
+
+/// Settle on what we and the remote, whose catalog is `catalog`, both
+/// understand; a remote that lacks what `--require` asks for is refused.
+fn negotiate(options: &MoqOptions, catalog: Option<&Catalog>) -> Result<()> {
+    let theirs = catalog.map_or(Capabilities::LEGACY, Catalog::capabilities);
+    let missing = theirs.missing(&options.require);
+    if !missing.is_empty() {
+        let missing: Vec<_> = missing.iter().map(ToString::to_string).collect();
+        return Err(NeetError::SessionRejected(format!(
+            "the remote can't do {}, which --require asks for; it may run an older neet",
+            missing.join(", ")
+        ))
+        .into());
+    }
+    if catalog.and_then(|catalog| catalog.version) > Some(CATALOG_VERSION) {
+        debug!("the remote's catalog is newer than ours; reading what we know of it");
+    }
+    let common = Capabilities::OURS.common(theirs);
+    if common != options.peer.get() {
+        info!(%common, "settled on what both sides understand");
+        options.peer.set(common);
+    }
+    Ok(())
+}
+
+/// With `--pin` or `--fingerprint`, hold off until the remote proves it
+/// knows the PIN and who it is, challenging it until it answers and
+/// answering its challenges meanwhile. False if it failed to or left.
+async fn authenticate_remote(
+    options: &MoqOptions,
+    broadcast: &moq::BroadcastConsumer,
+    pin: Option<&PinCheck>,
+    identity: &IdentityCheck,
+    control: &ControlChannel,
+) -> Result<bool> {
+    let (mut pin_pending, mut identity_pending) = (pin.is_some(), identity.expects());
+    if !pin_pending && !identity_pending {
+        return Ok(true);
+    }
+    let track = options
+        .priorities
+        .track(CONTROL_TRACK_NAME, TrackKind::Control);
+    let mut track = broadcast.subscribe_track(&track);
+    info!("waiting for the remote to authenticate");
+    let mut challenge = tokio::time::interval(CHALLENGE_INTERVAL);
+    while pin_pending || identity_pending {
+        let frame = select! {
+            _ = challenge.tick() => {
+                if let Some(pin) = pin.filter(|_| pin_pending) {
+                    control.send(&pin.challenge());
                 }
-                None => {
-                    return Err(anyhow!("announcement stream closed"));
+                if identity_pending {
+                    control.send(&identity.challenge());
                 }
+                continue;
             }
+            frame = next_frame(&mut track) => frame?,
+        };
+        let Some(frame) = frame else {
+            debug!("remote left before authenticating");
+            return Ok(false);
+        };
+        match pin.and_then(|pin| pin.handle(&frame, control)) {
+            Some(Verdict::Passed) => {
+                info!("remote proved the PIN");
+                pin_pending = false;
+            }
+            Some(Verdict::Failed) => return Ok(false),
+            None => {}
+        }
+        match identity.handle(&frame, control) {
+            Some(Verdict::Passed) => {
+                info!("remote proved its identity");
+                identity_pending = false;
+            }
+            Some(Verdict::Failed) => return Ok(false),
+            None => {}
         }
     }
+    Ok(true)
 }
 
 async fn handle_remote_broadcast(
     audio: AudioContext,
+    options: &MoqOptions,
     broadcast: moq::BroadcastConsumer,
-) -> Result<()> {
-    let track = moq::Track {
-        name: AUDIO_TRACK_NAME.to_string(),
-        priority: 0,
+    control: ControlChannel,
+    pin: Option<PinCheck>,
+    identity: IdentityCheck,
+) -> Result<RemoteEnd> {
+    let subscribe = |name, track| {
+        options.tracks.wants(track).then(|| {
+            let track = options.priorities.track(name, TrackKind::Control);
+            broadcast.subscribe_track(&track)
+        })
     };
+    let report_consumer = subscribe(REPORT_TRACK_NAME, RemoteTrack::Report);
+    let heartbeat_consumer = subscribe(HEARTBEAT_TRACK_NAME, RemoteTrack::Heartbeat);
+    let control_consumer = subscribe(CONTROL_TRACK_NAME, RemoteTrack::Control);
 
-    let track_consumer = broadcast.subscribe_track(&track);
-
-    let (sender, receiver) = chan::channel::<MediaFrame>(32);
-    let media_track = MediaTrack::new(
-        receiver,
-        Codec::Opus {
-            channels: OpusChannels::Stereo,
+    let catalog_track = options.tracks.wants(RemoteTrack::Audio).then(|| {
+        let track = options
+            .priorities
+            .track(CATALOG_TRACK_NAME, TrackKind::Control);
+        broadcast.subscribe_track(&track)
+    });
+    // a bridge mix is always stereo; its catalog only says what the bridge
+    // understands, and older bridges publish none. Rather than wait that
+    // out, the call starts as with a legacy bridge and settles up once the
+    // catalog is in, unless --require has to know first.
+    let (catalog, late_catalog) = match catalog_track {
+        Some(track) if options.bridge_name.is_some() && options.require.is_empty() => {
+            (None, Some(track))
+        }
+        Some(track) => {
+            let catalog = read_catalog(track).await?;
+            negotiate(options, catalog.as_ref())?;
+            (catalog, None)
+        }
+        None => (None, None),
+    };
+    let late_catalog = async {
+        if let Some(track) = late_catalog {
+            negotiate(options, read_catalog(track).await?.as_ref())?;
+        }
+        std::future::pending().await
+    };
+    let epoch = catalog.as_ref().and_then(Catalog::epoch);
+    let inputs: Vec<_> = catalog
+        .as_ref()
+        .map(|catalog| catalog.inputs.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|input| match &options.play_inputs {
+            Some(names) => names.contains(&input.name),
+            None => true,
+        })
+        .collect();
+    let (codec, track_name) = match catalog {
+        Some(catalog) => match catalog.lossless() {
+            // RTP consumers expect Opus.
+            Some(codec) if !options.simulcast && options.output_rtp.is_none() => {
+                info!("remote offers lossless audio; playing it");
+                (codec, FLAC_TRACK_NAME)
+            }
+            _ => (catalog.codec()?, AUDIO_TRACK_NAME),
         },
-        TrackKind::Audio,
-    );
-    audio
-        .play_track(media_track)
-        .await
-        .context("failed to add remote track to playback")?;
+        None => (
+            Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            AUDIO_TRACK_NAME,
+        ),
+    };
+    if codec.layout() != ChannelLayout::Stereo {
+        info!(layout = %codec.layout(), "remote sends surround audio; playing a stereo downmix");
+    }
 
-    forward_moq_to_media(track_consumer, sender).await?;
+    // reports about our audio; a remote without them just never sends any.
+    let reports = report_consumer.map(|report_consumer| {
+        let stats = audio.stats().clone();
+        let controller = BitrateController::new(audio.bitrate().clone());
+        let controller = match audio.mode().bitrate() {
+            Some(bitrate) => controller.with_max_bitrate(bitrate),
+            None => controller,
+        };
+        tokio::spawn(async move {
+            if let Err(err) = consume_reports(report_consumer, controller, stats).await {
+                warn!(%err, "receiver reports stopped");
+            }
+        })
+    });
 
-    Ok(())
+    // the moderator's commands, unless we are the moderator.
+    let verifier = options
+        .moderator_key
+        .filter(|_| options.moderator.is_none())
+        .map(|key| ControlVerifier::new(key, &options.session_id));
+    let reader = ControlReader {
+        verifier,
+        pin,
+        identity,
+        reply: control,
+        name: options.local_label().to_string(),
+        audio: audio.clone(),
+        recording_notice: options.recording_notice,
+    };
+    let control = async {
+        if let Some(control_consumer) = control_consumer {
+            reader.run(control_consumer).await?;
+        }
+        std::future::pending().await
+    };
+    let liveness = async {
+        match (options.liveness_timeout, heartbeat_consumer) {
+            (Some(timeout), Some(heartbeats)) => {
+                watch_heartbeats(heartbeats, timeout).await;
+                Ok(())
+            }
+            _ => std::future::pending().await,
+        }
+    };
+
+    let remote = options.remote_label();
+    // kept for the call: a caller who hangs up mid-greeting stops it.
+    let _greeting = match &options.greeting {
+        Some(greeting) if options.auto_answer => {
+            let path = greeting.path().display();
+            info!(%path, duration = ?greeting.duration(), "greeting the caller");
+            Some(audio.play_greeting(greeting)?)
+        }
+        _ => None,
+    };
+    if options.auto_answer {
+        info!("answering");
+        audio.set_paused(false);
+    }
+    if options.chime {
+        audio.chime();
+    }
+    audio.call_event(CallEvent::Joined, remote);
+    // further inputs are played next to the audio, RTP consumers aside.
+    let inputs: Vec<_> = match options.output_rtp {
+        Some(_) => Vec::new(),
+        None => inputs
+            .into_iter()
+            .map(|input| {
+                let track = options.priorities.track(&input.track, TrackKind::Audio);
+                tokio::spawn(play_input(
+                    audio.clone(),
+                    input,
+                    broadcast.subscribe_track(&track),
+                    options.delivery,
+                    epoch,
+                    options.extensions.clone(),
+                ))
+            })
+            .collect(),
+    };
+    let mut ended = false;
+    let receive = async {
+        if !options.tracks.wants(RemoteTrack::Audio) {
+            return std::future::pending().await;
+        }
+        let track = if options.simulcast {
+            None
+        } else {
+            // hold off adding a decoder until the remote sends audio.
+            let track = options.priorities.track(track_name, TrackKind::Audio);
+            let Some(track) = AttachedTrack::attach(broadcast.subscribe_track(&track)).await?
+            else {
+                return Ok(());
+            };
+            info!(remote, "remote audio started; playing it");
+            Some(track)
+        };
+        let sender = match options.output_rtp {
+            Some(addr) => audio.rtp_output(addr, codec).await?,
+            None => audio
+                .play_remote_track(VOICE_TRACK, codec)
+                .await
+                .context("failed to add remote track to playback")?,
+        };
+        let mut incoming = IncomingFrames::new(sender, audio.stats().clone())
+            .with_announcer(audio.announcer().cloned(), remote)
+            .with_epoch(epoch)
+            .with_trace(options.trace.clone(), codec)
+            .with_extensions(
+                options
+                    .extensions
+                    .clone()
+                    .with_decoder(AudioLevelDecoder(audio.stats().remote_level.clone())),
+            );
+        let result = match track {
+            Some(track) => receive(track, &mut incoming, options.delivery).await,
+            // layers are read a group at a time, whatever the delivery mode.
+            None => receive_simulcast(broadcast.clone(), &options.priorities, &mut incoming).await,
+        };
+        ended = incoming.ended;
+        result
+    };
+    let mut lost = false;
+    let result = select! {
+        res = receive => res,
+        res = control => res,
+        res = late_catalog => res,
+        res = liveness => {
+            warn!(remote, timeout = ?options.liveness_timeout, "remote lost: its heartbeats stopped");
+            audio.stats().remote_lost.add(1);
+            lost = true;
+            res
+        }
+        _ = options.shutdown.cancelled() => Ok(()),
+    };
+    if let Some(reports) = reports {
+        reports.abort();
+    }
+    for input in inputs {
+        input.abort();
+    }
+    if options.auto_answer {
+        audio.set_paused(true);
+    }
+    audio.call_event(CallEvent::Left, remote);
+    // whoever comes next has not said anything yet, nor been agreed to.
+    audio.stats().remote_presence.set(Presence::Here.index());
+    audio.reset_recording_consent();
+    let end = if !lost && (ended || options.shutdown.is_cancelled()) {
+        RemoteEnd::HungUp
+    } else {
+        RemoteEnd::Vanished
+    };
+    result.map(|()| end)
+}
+
+/// Play one of the remote's further inputs once it sends audio.
+async fn play_input(
+    audio: AudioContext,
+    input: InputEntry,
+    track: moq::TrackConsumer,
+    delivery: Delivery,
+    epoch: Option<CallEpoch>,
+    extensions: FrameExtensions,
+) {
+    let play = async {
+        let codec = input.codec()?;
+        let Some(track) = AttachedTrack::attach(track).await? else {
+            return Ok(());
+        };
+        info!(input = input.name, "remote input started; playing it");
+        let sender = audio.play_remote_track(&input.name, codec).await?;
+        // the main track's statistics stay about the main track.
+        let mut incoming = IncomingFrames::new(sender, Stats::default())
+            .with_epoch(epoch)
+            .with_extensions(extensions);
+        receive(track, &mut incoming, delivery).await
+    };
+    if let Err(err) = play.await {
+        warn!(input = input.name, "failed to play remote input: {err:#}");
+    }
 }
 
 async fn forward_media_to_moq(
     mut media_track: MediaTrack,
-    mut track_producer: moq::TrackProducer,
+    mut queue: SendQueue,
+    group_strategy: GroupStrategy,
+    mut redundancy: RedundancyEncoder,
+    paused: PauseState,
+    shutdown: CancellationToken,
+    epoch: CallEpoch,
 ) -> Result<()> {
+    let format = media_track.codec().audio_format();
+    let mut clock = MediaClock::new(epoch);
+    let mut batcher = GroupBatcher::new(group_strategy);
+    let mut sequence = 0u32;
+    let mut paused = paused.subscribe();
+    let mut was_paused = paused.borrow_and_update().is_paused();
+    let mut stopping = false;
+    // whatever the remote heard on this track before came from another run.
+    queue.marker(FLAG_RESET, sequence);
     loop {
-        match media_track.recv().await {
+        let frame = if stopping {
+            // publish what was already encoded and the rest of the frame the
+            // encoder was collecting, then end the track.
+            match tokio::time::timeout(FLUSH_TIMEOUT, media_track.recv()).await {
+                Ok(Err(RecvError::Closed)) | Err(_) => break,
+                Ok(frame) => frame,
+            }
+        } else {
+            select! {
+                frame = media_track.recv() => frame,
+                _ = shutdown.cancelled() => {
+                    info!("hanging up; publishing the last frames");
+                    media_track.finish();
+                    stopping = true;
+                    continue;
+                }
+                Ok(()) = paused.changed() => {
+                    // going from muted to away, say, is no news to the audio.
+                    let now_paused = paused.borrow_and_update().is_paused();
+                    if now_paused == was_paused {
+                        continue;
+                    }
+                    was_paused = now_paused;
+                    if now_paused {
+                        info!("publishing paused");
+                        queue.close_group();
+                        // tell the remote the gap is intentional; the track stays open.
+                        queue.marker(FLAG_PAUSED, sequence);
+                        batcher = GroupBatcher::new(group_strategy);
+                    } else {
+                        info!("publishing resumed");
+                        clock.resync();
+                    }
+                    continue;
+                }
+            }
+        };
+        match frame {
             Ok(frame) => {
-                let mut group = track_producer.append_group();
-                let mut frame_writer = group.create_frame(moq::Frame {
-                    size: frame.payload.len() as u64,
-                });
-                frame_writer.write_chunk(frame.payload.clone());
-                frame_writer.close();
-                group.close();
+                let duration = frame
+                    .sample_count
+                    .map(|n| format.duration_from_sample_count(n as usize))
+                    .unwrap_or(DEFAULT_FRAME_DURATION);
+                let new_group = batcher.push(duration);
+                let header = FrameHeader::new(sequence).with_timestamp(clock.stamp(duration));
+                let payload = redundancy.encode(header, frame.payload);
+                sequence = sequence.wrapping_add(1);
+                queue.send(payload, duration, new_group);
             }
-            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+            Err(RecvError::Closed) => {
                 info!("capture media track closed; stopping publisher");
                 break;
             }
-            Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+            Err(RecvError::Lagged(skipped)) => {
                 warn!(skipped, "lost {} capture frames before publish", skipped);
+                clock.resync();
             }
         }
     }
+    queue.close_group();
+    // tell the remote we hung up, rather than went away.
+    queue.marker(FLAG_END, sequence);
+    queue.close().await;
     Ok(())
 }
 
 async fn forward_moq_to_media(
-    mut track: moq::TrackConsumer,
-    sender: chan::Sender<MediaFrame>,
+    track: moq::TrackConsumer,
+    mut incoming: IncomingFrames,
+    delivery: Delivery,
 ) -> Result<()> {
-    loop {
-        match track.next_group().await {
-            Ok(Some(mut group)) => {
-                while let Some(payload) = group
-                    .read_frame()
-                    .await
-                    .context("failed to read frame from MoQ group")?
-                {
-                    let frame = MediaFrame {
-                        payload,
-                        sample_count: None,
-                        skipped_frames: None,
-                        skipped_samples: None,
-                    };
-                    let _ = sender.send(frame);
+    receive(track.into(), &mut incoming, delivery).await
+}
+
+/// Deliver `track` until it ends or the remote hangs up.
+async fn receive(
+    track: AttachedTrack,
+    incoming: &mut IncomingFrames,
+    delivery: Delivery,
+) -> Result<()> {
+    match delivery {
+        Delivery::Reliable => receive_in_order(track, incoming).await,
+        Delivery::Datagram => receive_unordered(track, incoming).await,
+    }
+}
+
+async fn receive_in_order(mut track: AttachedTrack, incoming: &mut IncomingFrames) -> Result<()> {
+    while let Some(mut group) = track.next_group().await? {
+        loop {
+            // a group may carry several frames, and may be cut short if the
+            // relay drops it; keep what arrived and move on to the next one.
+            match group.read_frame().await {
+                Ok(Some(payload)) => {
+                    incoming.deliver(payload).await;
+                    if incoming.ended {
+                        return Ok(());
+                    }
                 }
-            }
-            Ok(None) => {
-                info!("remote track closed");
-                break;
-            }
-            Err(err) => {
-                if matches!(err, moq::Error::Cancel) {
-                    info!("remote track cancelled");
+                Ok(None) => break,
+                Err(err) => {
+                    debug!(%err, "group ended early; skipping to next group");
                     break;
                 }
-                return Err(anyhow!(err).context("failed to read from MoQ track"));
             }
         }
     }
     Ok(())
 }
 
+/// Read every group on its own task and deliver frames as soon as they
+/// complete, whichever group they belong to.
+async fn receive_unordered(mut track: AttachedTrack, incoming: &mut IncomingFrames) -> Result<()> {
+    let (frames_tx, mut frames_rx) = mpsc::unbounded_channel();
+    let reader = tokio::spawn(async move {
+        while let Some(mut group) = track.next_group().await? {
+            let frames_tx = frames_tx.clone();
+            tokio::spawn(async move {
+                while let Ok(Some(payload)) = group.read_frame().await {
+                    if frames_tx.send(payload).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        anyhow::Ok(())
+    });
+    // ends once the track and every group still in flight have finished.
+    while let Some(payload) = frames_rx.recv().await {
+        incoming.deliver(payload).await;
+        if incoming.ended {
+            reader.abort();
+            return Ok(());
+        }
+    }
+    reader.await?
+}
+
+async fn next_group(track: &mut moq::TrackConsumer) -> Result<Option<moq::GroupConsumer>> {
+    match track.next_group().await {
+        Ok(Some(group)) => Ok(Some(group)),
+        Ok(None) => {
+            info!("remote track closed");
+            Ok(None)
+        }
+        Err(moq::Error::Cancel) => {
+            info!("remote track cancelled");
+            Ok(None)
+        }
+        Err(err) => Err(anyhow!(err).context("failed to read from MoQ track")),
+    }
+}
+
+/// Strips frame headers, accounts for loss and reordering, and hands the
+/// payloads on to playback.
+struct IncomingFrames {
+    sender: MediaSender,
+    stats: Stats,
+    sequence: SequenceTracker,
+    jitter: JitterEstimator,
+    remote_paused: bool,
+    /// The remote started a new stream; the next frame tells the decoder.
+    restarted: bool,
+    /// The remote hung up; nothing more will come.
+    ended: bool,
+    /// Speaks the remote's pauses, naming it as the second field.
+    announcer: Option<(Announcer, &'static str)>,
+    /// What the remote's timestamps count from, if it told us.
+    epoch: Option<CallEpoch>,
+    /// Reads the extensions frames carry.
+    extensions: FrameExtensions,
+    /// Records frames as they arrive.
+    trace: Option<FrameTrace>,
+}
+
+impl IncomingFrames {
+    fn new(sender: MediaSender, stats: Stats) -> Self {
+        Self {
+            sender,
+            stats,
+            sequence: SequenceTracker::default(),
+            jitter: JitterEstimator::new(DEFAULT_FRAME_DURATION),
+            remote_paused: false,
+            restarted: false,
+            ended: false,
+            announcer: None,
+            epoch: None,
+            extensions: FrameExtensions::default(),
+            trace: None,
+        }
+    }
+
+    /// Record frames to `trace`, of a track in `codec`. Comes after
+    /// `with_epoch`, so that the trace has the epoch too.
+    fn with_trace(mut self, trace: Option<FrameTrace>, codec: Codec) -> Self {
+        if let Some(trace) = &trace {
+            trace.track(codec, self.epoch);
+        }
+        self.trace = trace;
+        self
+    }
+
+    fn with_epoch(mut self, epoch: Option<CallEpoch>) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    fn with_extensions(mut self, extensions: FrameExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    fn with_announcer(mut self, announcer: Option<Announcer>, remote: &'static str) -> Self {
+        self.announcer = announcer.map(|announcer| (announcer, remote));
+        self
+    }
+
+    fn announce(&self, event: CallEvent) {
+        if let Some((announcer, remote)) = &self.announcer {
+            announcer.event(event, remote);
+        }
+    }
+
+    /// Start over on a track with its own sequence numbers and encoder.
+    fn reset(&mut self) {
+        self.sequence.reset();
+        self.jitter.reset();
+        self.restarted = true;
+    }
+
+    async fn deliver(&mut self, frame: Bytes) {
+        self.deliver_at(frame, Instant::now()).await;
+    }
+
+    /// Handle `frame`, which arrived at `arrival`.
+    async fn deliver_at(&mut self, frame: Bytes, arrival: Instant) {
+        if let Some(trace) = &self.trace {
+            trace.frame(&frame, arrival);
+        }
+        let (header, copies, payload) =
+            match FrameHeader::decode(frame).and_then(|(header, payload)| {
+                let split = redundancy::split(&header, payload)?;
+                Ok((header, split))
+            }) {
+                Ok((header, (copies, payload))) => (header, copies, payload),
+                Err(err) => {
+                    warn!(%err, "dropping malformed frame");
+                    return;
+                }
+            };
+        if header.flags & FLAG_END != 0 {
+            info!("remote hung up");
+            self.ended = true;
+            return;
+        }
+        if header.flags & FLAG_RESET != 0 {
+            debug!(sequence = header.sequence, "remote started a new stream");
+            self.reset();
+            return;
+        }
+        if header.flags & FLAG_PAUSED != 0 {
+            if !self.remote_paused {
+                info!("remote paused publishing");
+                self.remote_paused = true;
+                self.stats.remote_pauses.add(1);
+                self.announce(CallEvent::Paused);
+            }
+            // the silence that follows is not network jitter.
+            self.jitter.reset();
+            return;
+        }
+        if self.remote_paused {
+            info!("remote resumed publishing");
+            self.remote_paused = false;
+            self.announce(CallEvent::Resumed);
+        }
+        // copies of frames we never got stand in for them; the rest are duplicates.
+        for (sequence, copy) in copies {
+            if let Arrival::Next { lost } = self.sequence.observe(sequence) {
+                self.stats.received_lost.add(lost as u64 + 1);
+                self.stats.recovered_frames.add(1);
+                self.forward(copy, lost).await;
+            }
+        }
+        let lost = match self.sequence.observe(header.sequence) {
+            Arrival::Next { lost } => lost,
+            Arrival::Late => {
+                self.stats.received_late.add(1);
+                return;
+            }
+        };
+        self.stats.received_lost.add(lost as u64);
+        // what a frame says about itself is no reason to drop it.
+        if let Err(err) = self.extensions.decode(&header) {
+            debug!(%err, "ignoring frame extensions");
+        }
+        let jitter = self.jitter.observe(header.sequence, arrival);
+        self.stats.jitter_us.set(jitter.as_micros() as u64);
+        if let (Some(epoch), Some(timestamp)) = (self.epoch, header.timestamp) {
+            if let Some(age) = epoch.age(timestamp, arrival) {
+                self.stats.one_way_delay_us.set(age.as_micros() as u64);
+            }
+        }
+        self.stats.received_frames.add(1);
+        self.forward(payload, lost).await;
+    }
+
+    /// Hand `payload` on, after the `lost` frames right before it that the
+    /// decoder should conceal.
+    async fn forward(&mut self, payload: Bytes, lost: u32) {
+        let frame = MediaFrame {
+            payload,
+            sample_count: None,
+            skipped_frames: (lost > 0).then_some(lost),
+            skipped_samples: None,
+            reset: std::mem::take(&mut self.restarted),
+        };
+        let _ = self.sender.send_async(frame).await;
+    }
+}
+
+#[cfg(test)]
+mod harness;
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use bytes::Bytes;
 
+    use crate::{
+        media::{self, OverflowPolicy},
+        stats::{Counter, Gauge},
+    };
+
     #[tokio::test]
     async fn append_session_path_appends_namespace() {
         let mut url = Url::parse("https://example.com/anon").unwrap();
@@ -319,7 +1815,8 @@ mod tests {
 
     #[tokio::test]
     async fn forward_roundtrip_delivers_payload() {
-        let (media_tx, media_rx) = chan::channel::<MediaFrame>(8);
+        let (mut media_tx, media_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
         let media_track = MediaTrack::new(
             media_rx,
             Codec::Opus {
@@ -332,25 +1829,36 @@ mod tests {
         let producer = track_pair.producer;
         let consumer = track_pair.consumer;
 
-        let (sink_tx, mut sink_rx) = chan::channel::<MediaFrame>(8);
+        let (sink_tx, mut sink_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
 
         let publish = tokio::spawn(async move {
-            forward_media_to_moq(media_track, producer).await.unwrap();
+            let redundancy = RedundancyEncoder::new(Redundancy::default(), Gauge::default());
+            forward_media_to_moq(
+                media_track,
+                SendQueue::new(producer),
+                GroupStrategy::PerFrame,
+                redundancy,
+                PauseState::default(),
+                CancellationToken::new(),
+                CallEpoch::now(),
+            )
+            .await
+            .unwrap();
         });
 
         let subscribe = tokio::spawn(async move {
-            forward_moq_to_media(consumer, sink_tx).await.unwrap();
+            forward_moq_to_media(
+                consumer,
+                IncomingFrames::new(sink_tx, Stats::default()),
+                Delivery::Reliable,
+            )
+            .await
+            .unwrap();
         });
 
         let payload = Bytes::from_static(b"hello");
-        media_tx
-            .send(MediaFrame {
-                payload: payload.clone(),
-                sample_count: None,
-                skipped_frames: None,
-                skipped_samples: None,
-            })
-            .unwrap();
+        media_tx.send(frame(payload.clone())).unwrap();
         drop(media_tx);
 
         let received = sink_rx.recv().await.unwrap();
@@ -359,4 +1867,159 @@ mod tests {
         publish.await.unwrap();
         subscribe.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn pause_keeps_track_open_and_marks_the_gap() {
+        let (mut media_tx, media_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let media_track = MediaTrack::new(
+            media_rx,
+            Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            TrackKind::Audio,
+        );
+        let track_pair = moq::Track::new(AUDIO_TRACK_NAME).produce();
+        let (sink_tx, mut sink_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let paused = PauseState::default();
+        let stats = Stats::default();
+
+        let publish = tokio::spawn(forward_media_to_moq(
+            media_track,
+            SendQueue::new(track_pair.producer),
+            GroupStrategy::PerFrame,
+            RedundancyEncoder::new(Redundancy::default(), Gauge::default()),
+            paused.clone(),
+            CancellationToken::new(),
+            CallEpoch::now(),
+        ));
+        let subscribe = tokio::spawn(forward_moq_to_media(
+            track_pair.consumer,
+            IncomingFrames::new(sink_tx, stats.clone()),
+            Delivery::Reliable,
+        ));
+
+        media_tx.send(frame(Bytes::from_static(b"before"))).unwrap();
+        assert_eq!(sink_rx.recv().await.unwrap().payload, "before");
+
+        assert!(paused.set(true));
+        while stats.remote_pauses.get() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        paused.set(false);
+
+        media_tx.send(frame(Bytes::from_static(b"after"))).unwrap();
+        assert_eq!(sink_rx.recv().await.unwrap().payload, "after");
+        drop(media_tx);
+        publish.await.unwrap().unwrap();
+        subscribe.await.unwrap().unwrap();
+        assert_eq!(stats.received_lost.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown_publishes_queued_frames_then_ends() {
+        let (mut media_tx, media_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let media_track = MediaTrack::new(
+            media_rx,
+            Codec::Opus {
+                channels: OpusChannels::Stereo,
+            },
+            TrackKind::Audio,
+        );
+        let track_pair = moq::Track::new(AUDIO_TRACK_NAME).produce();
+        let (sink_tx, mut sink_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let shutdown = CancellationToken::new();
+
+        // queued before the hangup, so they must still go out.
+        media_tx.send(frame(Bytes::from_static(b"one"))).unwrap();
+        media_tx.send(frame(Bytes::from_static(b"two"))).unwrap();
+        shutdown.cancel();
+        // the encoder sends the rest of its frame when asked, and closes.
+        let encoder = tokio::spawn(async move {
+            while !media_tx.is_finishing() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            media_tx.send(frame(Bytes::from_static(b"tail"))).unwrap();
+        });
+        forward_media_to_moq(
+            media_track,
+            SendQueue::new(track_pair.producer),
+            GroupStrategy::PerFrame,
+            RedundancyEncoder::new(Redundancy::default(), Gauge::default()),
+            PauseState::default(),
+            shutdown,
+            CallEpoch::now(),
+        )
+        .await
+        .unwrap();
+        forward_moq_to_media(
+            track_pair.consumer,
+            IncomingFrames::new(sink_tx, Stats::default()),
+            Delivery::Reliable,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sink_rx.recv().await.unwrap().payload, "one");
+        assert_eq!(sink_rx.recv().await.unwrap().payload, "two");
+        assert_eq!(sink_rx.recv().await.unwrap().payload, "tail");
+        assert_eq!(sink_rx.recv().await.unwrap_err(), RecvError::Closed);
+        encoder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reset_marker_restarts_sequence_and_decoder() {
+        let (sink_tx, mut sink_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let stats = Stats::default();
+        let mut incoming = IncomingFrames::new(sink_tx, stats.clone());
+        incoming.deliver(FrameHeader::new(7).encode(b"old")).await;
+        // the publisher restarted, and counts from zero again.
+        let marker = FrameHeader {
+            flags: FLAG_RESET,
+            ..FrameHeader::new(0)
+        };
+        incoming.deliver(marker.encode(&[])).await;
+        incoming.deliver(FrameHeader::new(0).encode(b"new")).await;
+        incoming.deliver(FrameHeader::new(1).encode(b"next")).await;
+
+        let old = sink_rx.recv().await.unwrap();
+        let new = sink_rx.recv().await.unwrap();
+        let next = sink_rx.recv().await.unwrap();
+        assert_eq!((old.payload, new.payload), ("old".into(), "new".into()));
+        assert!(new.reset && !next.reset);
+        assert_eq!(stats.received_late.get(), 0);
+        assert_eq!(stats.received_lost.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn loss_runs_are_passed_on_for_concealment() {
+        let (sink_tx, mut sink_rx) =
+            media::channel(8, OverflowPolicy::default(), Counter::default());
+        let stats = Stats::default();
+        let mut incoming = IncomingFrames::new(sink_tx, stats.clone());
+        for sequence in [0, 1, 4] {
+            incoming
+                .deliver(FrameHeader::new(sequence).encode(b"opus"))
+                .await;
+        }
+        let skipped: Vec<_> = (0..3)
+            .map(|_| sink_rx.try_recv().unwrap().skipped_frames)
+            .collect();
+        assert_eq!(skipped, [None, None, Some(2)]);
+        assert_eq!(stats.received_lost.get(), 2);
+    }
+
+    fn frame(payload: Bytes) -> MediaFrame {
+        MediaFrame {
+            payload,
+            sample_count: None,
+            skipped_frames: None,
+            skipped_samples: None,
+            reset: false,
+        }
+    }
 }
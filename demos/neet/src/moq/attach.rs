@@ -0,0 +1,114 @@
+//! Which of the remote's tracks to subscribe to, and attaching to its audio
+//! only once it sends some.
+//!
+//! `--tracks audio,control` leaves out the others: without `report` our
+//! bitrate no longer follows the remote's reports, without `heartbeat` the
+//! remote is never declared lost, and without `audio` nothing is played,
+//! e.g. for a peer that only moderates. The audio decoder is added to
+//! playback when the first group arrives, so a remote that publishes no
+//! audio, or not yet, costs nothing.
+
+use anyhow::Result;
+use moq_lite as moq;
+
+use super::next_group;
+
+/// A remote track that can be left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RemoteTrack {
+    /// The audio, with its catalog.
+    Audio,
+    /// Moderator commands, PIN proofs and the remote's microphone status.
+    Control,
+    /// Loss reports about our audio, which our bitrate follows.
+    Report,
+    /// Liveness of the remote.
+    Heartbeat,
+}
+
+/// The remote tracks to subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTracks(Vec<RemoteTrack>);
+
+impl RemoteTracks {
+    pub fn new(tracks: Vec<RemoteTrack>) -> Self {
+        Self(tracks)
+    }
+
+    pub fn wants(&self, track: RemoteTrack) -> bool {
+        self.0.contains(&track)
+    }
+}
+
+impl Default for RemoteTracks {
+    fn default() -> Self {
+        Self(vec![
+            RemoteTrack::Audio,
+            RemoteTrack::Control,
+            RemoteTrack::Report,
+            RemoteTrack::Heartbeat,
+        ])
+    }
+}
+
+/// A subscribed track, with the group that showed it has data held back to
+/// be read first.
+pub struct AttachedTrack {
+    first: Option<moq::GroupConsumer>,
+    track: moq::TrackConsumer,
+}
+
+impl AttachedTrack {
+    /// Wait until `track` produces its first group; `None` if it ends
+    /// before that.
+    pub async fn attach(mut track: moq::TrackConsumer) -> Result<Option<Self>> {
+        let first = next_group(&mut track).await?;
+        Ok(first.map(|first| Self {
+            first: Some(first),
+            track,
+        }))
+    }
+
+    pub async fn next_group(&mut self) -> Result<Option<moq::GroupConsumer>> {
+        match self.first.take() {
+            Some(group) => Ok(Some(group)),
+            None => next_group(&mut self.track).await,
+        }
+    }
+}
+
+impl From<moq::TrackConsumer> for AttachedTrack {
+    fn from(track: moq::TrackConsumer) -> Self {
+        Self { first: None, track }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn attaching_keeps_the_first_group() {
+        let tracks = RemoteTracks::new(vec![RemoteTrack::Audio, RemoteTrack::Control]);
+        assert!(tracks.wants(RemoteTrack::Control));
+        assert!(!tracks.wants(RemoteTrack::Heartbeat));
+
+        let track_pair = moq::Track::new("audio").produce();
+        let mut producer = track_pair.producer;
+        for frame in ["first", "second"] {
+            let mut group = producer.append_group();
+            group.write_frame(frame);
+            group.close();
+        }
+        producer.close();
+
+        let mut track = AttachedTrack::attach(track_pair.consumer)
+            .await
+            .unwrap()
+            .unwrap();
+        for expected in ["first", "second"] {
+            let mut group = track.next_group().await.unwrap().unwrap();
+            assert_eq!(group.read_frame().await.unwrap().unwrap(), expected);
+        }
+    }
+}
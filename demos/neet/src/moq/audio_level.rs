@@ -0,0 +1,145 @@
+//! The publisher's microphone level in every frame (`--send-audio-level`),
+//! so that receivers can show who is talking, and the bridge can leave out
+//! who isn't, without decoding anything.
+//!
+//! The extension's value is one byte, as in RFC 6464: the top bit is set
+//! while the publisher speaks, and the rest is the level in decibels below
+//! full scale, from 0 to 127.
+//!
+//! ```text
+//! 0 1 2 3 4 5 6 7
+//! +-+-+-+-+-+-+-+-+
+//! |V|    -dBov    |
+//! +-+-+-+-+-+-+-+-+
+//! ```
+//!
+//! Speech holds over short pauses between words, so that indicators and
+//! gates don't flicker.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{ensure, Result};
+use bytes::Bytes;
+
+use super::{
+    capability::{Capability, PeerCapabilities},
+    extension::{ExtensionDecoder, ExtensionEncoder},
+    frame::FrameHeader,
+};
+use crate::stats::{Level, RemoteLevel};
+
+/// The extension id of the audio level.
+pub const AUDIO_LEVEL_EXTENSION: u8 = 1;
+const VOICE_BIT: u8 = 0x80;
+/// The quietest level there is a value for.
+const MIN_DBOV: f32 = -127.;
+/// Level above which the publisher counts as speaking.
+const VOICE_THRESHOLD_DBFS: f32 = -40.;
+/// Frames that still count as speech after the level drops.
+const HANGOVER_FRAMES: u32 = 15;
+
+/// Adds the microphone's level, from its [`Level`], to outgoing frames.
+#[derive(Debug)]
+pub struct AudioLevelEncoder {
+    level: Level,
+    /// Nothing is sent unless the receiver understands it.
+    peer: PeerCapabilities,
+    /// Frames of hangover left.
+    hangover: AtomicU32,
+}
+
+impl AudioLevelEncoder {
+    pub fn new(level: Level, peer: PeerCapabilities) -> Self {
+        Self {
+            level,
+            peer,
+            hangover: AtomicU32::new(0),
+        }
+    }
+}
+
+impl ExtensionEncoder for AudioLevelEncoder {
+    fn id(&self) -> u8 {
+        AUDIO_LEVEL_EXTENSION
+    }
+
+    fn encode(&self, _header: &FrameHeader, _payload: &[u8]) -> Option<Bytes> {
+        if !self.peer.contains(Capability::AudioLevel) {
+            return None;
+        }
+        let dbfs = self.level.snapshot().rms_dbfs;
+        let speaking = if dbfs > VOICE_THRESHOLD_DBFS {
+            self.hangover.store(HANGOVER_FRAMES, Ordering::Relaxed);
+            true
+        } else {
+            let left = self.hangover.load(Ordering::Relaxed);
+            self.hangover
+                .store(left.saturating_sub(1), Ordering::Relaxed);
+            left > 0
+        };
+        let dbov = -dbfs.clamp(MIN_DBOV, 0.);
+        let mut value = dbov.round() as u8;
+        if speaking {
+            value |= VOICE_BIT;
+        }
+        Some(Bytes::copy_from_slice(&[value]))
+    }
+}
+
+/// Keeps the level incoming frames report in a [`RemoteLevel`].
+#[derive(Debug)]
+pub struct AudioLevelDecoder(pub RemoteLevel);
+
+impl ExtensionDecoder for AudioLevelDecoder {
+    fn id(&self) -> u8 {
+        AUDIO_LEVEL_EXTENSION
+    }
+
+    fn decode(&self, _header: &FrameHeader, value: &[u8]) -> Result<()> {
+        ensure!(value.len() == 1, "audio level of {} bytes", value.len());
+        self.0.set(value[0] & !VOICE_BIT, value[0] & VOICE_BIT != 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::moq::{capability::Capabilities, FrameExtensions};
+
+    #[test]
+    fn levels_reach_the_receiver_with_hangover() {
+        let mic = Level::default();
+        let peer = PeerCapabilities::default();
+        let sender = FrameExtensions::default()
+            .with_encoder(AudioLevelEncoder::new(mic.clone(), peer.clone()));
+        let remote = RemoteLevel::default();
+        let receiver = FrameExtensions::default().with_decoder(AudioLevelDecoder(remote.clone()));
+        let send = || {
+            let header = FrameHeader::new(0);
+            let area = sender.encode(&header, b"opus");
+            let (header, _) =
+                FrameHeader::decode(header.with_extensions(area).encode(b"opus")).unwrap();
+            receiver.decode(&header).unwrap();
+            remote.snapshot().unwrap()
+        };
+
+        mic.measure(&[0.1; 960]);
+        // a peer from before levels gets none.
+        let header = FrameHeader::new(0);
+        assert!(sender.encode(&header, b"opus").is_empty());
+        peer.set(Capabilities::OURS);
+        assert_eq!(remote.snapshot(), None);
+        let level = send();
+        assert_eq!(level.dbfs, -20.);
+        assert!(level.speaking);
+
+        mic.measure(&[0.; 960]);
+        for _ in 0..HANGOVER_FRAMES {
+            assert!(send().speaking);
+        }
+        let level = send();
+        assert_eq!(level.dbfs, -100.);
+        assert!(!level.speaking);
+    }
+}
@@ -0,0 +1,376 @@
+//! Keeping a stalled connection from building up latency.
+//!
+//! moq-lite takes every group we append and queues what the connection cannot
+//! send yet without bound, so after a stall the remote would hear everything
+//! since, seconds late. A [`SendQueue`] with a backlog limit estimates how
+//! much audio is still unsent by matching what it wrote against the bytes the
+//! connection reports sent. Once that exceeds the limit the connection counts
+//! as stalled: frames are held back instead, only the newest limit's worth of
+//! them kept and older ones dropped, and the kept ones go out in a fresh
+//! group once the connection catches up. The connection's bytes are sampled
+//! every [`CONNECTION_STATS_INTERVAL`], so a stall shows about that much
+//! later than the limit.
+//!
+//! With [`SendQueue::with_pacing`], frames, held ones included, are spread
+//! out by a [`Pacer`] rather than written the moment they arrive. The pacer
+//! waits in a task of its own, behind a queue, so that the publisher keeps
+//! reading capture while a burst goes out.
+
+use std::{collections::VecDeque, time::Duration};
+
+use bytes::Bytes;
+use moq_lite as moq;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{info, warn};
+
+use super::{pacing::Pacer, FrameHeader, CONNECTION_STATS_INTERVAL};
+use crate::stats::Counter;
+
+/// Where a publisher's frames go: its track, in groups.
+pub struct SendQueue {
+    output: Output,
+    backlog: Option<Backlog>,
+}
+
+impl SendQueue {
+    pub fn new(producer: moq::TrackProducer) -> Self {
+        Self {
+            output: Output::Direct(TrackWriter::new(producer)),
+            backlog: None,
+        }
+    }
+
+    /// Spread bursts of frames out over their duration. Must be called
+    /// within a Tokio runtime.
+    pub fn with_pacing(mut self) -> Self {
+        if let Output::Direct(writer) = self.output {
+            let (writes, queued) = mpsc::unbounded_channel();
+            let task = tokio::spawn(write_paced(writer, queued));
+            self.output = Output::Paced { writes, task };
+        }
+        self
+    }
+
+    /// Hold frames back while more than `limit` of audio is unsent by the
+    /// connection whose sent bytes are `sent`, counting the frames dropped
+    /// meanwhile in `dropped`.
+    pub fn with_backlog_limit(mut self, limit: Duration, sent: Counter, dropped: Counter) -> Self {
+        self.backlog = Some(Backlog::new(limit, sent, dropped));
+        self
+    }
+
+    /// Send a frame of `duration`, in a new group if `new_group`.
+    pub fn send(&mut self, payload: Bytes, duration: Duration, new_group: bool) {
+        let Some(backlog) = &mut self.backlog else {
+            self.output.write(Write::Frame {
+                payload,
+                duration,
+                new_group,
+            });
+            return;
+        };
+        backlog.observe();
+        if backlog.stalled {
+            // whatever is still open has been waiting long enough.
+            self.output.write(Write::CloseGroup);
+            backlog.hold(payload, duration);
+            return;
+        }
+        let mut new_group = new_group;
+        if !backlog.held.is_empty() {
+            info!(
+                "the connection caught up; sending the newest {:?} of audio",
+                backlog.held_duration
+            );
+            backlog.held_duration = Duration::ZERO;
+            for (held, held_duration) in std::mem::take(&mut backlog.held) {
+                backlog.written(held.len(), held_duration);
+                self.output.write(Write::Frame {
+                    payload: held,
+                    duration: held_duration,
+                    new_group,
+                });
+                new_group = false;
+            }
+        }
+        backlog.written(payload.len(), duration);
+        self.output.write(Write::Frame {
+            payload,
+            duration,
+            new_group,
+        });
+    }
+
+    /// Close the open group, if any.
+    pub fn close_group(&mut self) {
+        self.output.write(Write::CloseGroup);
+    }
+
+    /// Send an empty frame carrying `flags` in a group of its own.
+    pub fn marker(&mut self, flags: u8, sequence: u32) {
+        self.output.write(Write::Marker { flags, sequence });
+    }
+
+    /// Close the track, once whatever is still being paced is out.
+    pub async fn close(self) {
+        match self.output {
+            Output::Direct(writer) => writer.close(),
+            Output::Paced { writes, task } => {
+                drop(writes);
+                let _ = task.await;
+            }
+        }
+    }
+}
+
+/// What to do to the track, in order.
+enum Write {
+    Frame {
+        payload: Bytes,
+        duration: Duration,
+        new_group: bool,
+    },
+    CloseGroup,
+    Marker {
+        flags: u8,
+        sequence: u32,
+    },
+}
+
+enum Output {
+    /// Written as it comes.
+    Direct(TrackWriter),
+    /// Queued for [`write_paced`].
+    Paced {
+        writes: mpsc::UnboundedSender<Write>,
+        task: JoinHandle<()>,
+    },
+}
+
+impl Output {
+    fn write(&mut self, write: Write) {
+        match self {
+            Output::Direct(writer) => writer.write(write),
+            Output::Paced { writes, .. } => {
+                let _ = writes.send(write);
+            }
+        }
+    }
+}
+
+/// Write what is `queued`, letting each frame out when the pacer says, and
+/// close the track once the queue ends.
+async fn write_paced(mut writer: TrackWriter, mut queued: mpsc::UnboundedReceiver<Write>) {
+    let mut pacer = Pacer::default();
+    while let Some(write) = queued.recv().await {
+        if let Write::Frame { duration, .. } = &write {
+            pacer.wait(*duration).await;
+        }
+        writer.write(write);
+    }
+    writer.close();
+}
+
+/// A track and its open group.
+struct TrackWriter {
+    producer: moq::TrackProducer,
+    group: Option<moq::GroupProducer>,
+}
+
+impl TrackWriter {
+    fn new(producer: moq::TrackProducer) -> Self {
+        Self {
+            producer,
+            group: None,
+        }
+    }
+
+    fn write(&mut self, write: Write) {
+        match write {
+            Write::Frame {
+                payload, new_group, ..
+            } => {
+                if new_group {
+                    self.close_group();
+                }
+                let group = self
+                    .group
+                    .get_or_insert_with(|| self.producer.append_group());
+                let mut frame_writer = group.create_frame(moq::Frame {
+                    size: payload.len() as u64,
+                });
+                frame_writer.write_chunk(payload);
+                frame_writer.close();
+            }
+            Write::CloseGroup => self.close_group(),
+            Write::Marker { flags, sequence } => {
+                let marker = FrameHeader {
+                    flags,
+                    ..FrameHeader::new(sequence)
+                }
+                .encode(&[]);
+                let mut group = self.producer.append_group();
+                let mut frame_writer = group.create_frame(moq::Frame {
+                    size: marker.len() as u64,
+                });
+                frame_writer.write_chunk(marker);
+                frame_writer.close();
+                group.close();
+            }
+        }
+    }
+
+    fn close_group(&mut self) {
+        if let Some(group) = self.group.take() {
+            group.close();
+        }
+    }
+
+    fn close(mut self) {
+        self.close_group();
+        self.producer.close();
+    }
+}
+
+/// What the connection has yet to send of what we wrote.
+struct Backlog {
+    limit: Duration,
+    /// Bytes the connection sent, all of its traffic.
+    sent: Counter,
+    last_sent: u64,
+    /// Frames written and not yet covered by sent bytes, as their size and
+    /// duration, oldest first.
+    unsent: VecDeque<(usize, Duration)>,
+    unsent_duration: Duration,
+    stalled: bool,
+    /// The newest frames, held back while stalled.
+    held: VecDeque<(Bytes, Duration)>,
+    held_duration: Duration,
+    dropped: Counter,
+}
+
+impl Backlog {
+    fn new(limit: Duration, sent: Counter, dropped: Counter) -> Self {
+        Self {
+            limit,
+            last_sent: sent.get(),
+            sent,
+            unsent: VecDeque::new(),
+            unsent_duration: Duration::ZERO,
+            stalled: false,
+            held: VecDeque::new(),
+            held_duration: Duration::ZERO,
+            dropped,
+        }
+    }
+
+    /// Take in the latest sent bytes and decide whether the connection is
+    /// stalled.
+    fn observe(&mut self) {
+        let sent = self.sent.get();
+        let stalled = if sent != self.last_sent {
+            let mut covered = (sent - self.last_sent) as usize;
+            self.last_sent = sent;
+            while let Some(&(size, duration)) = self.unsent.front() {
+                if size > covered {
+                    break;
+                }
+                covered -= size;
+                self.unsent_duration -= duration;
+                self.unsent.pop_front();
+            }
+            self.unsent_duration > self.limit
+        } else {
+            // between samples the backlog grows by up to one interval anyway.
+            self.stalled || self.unsent_duration > self.limit + CONNECTION_STATS_INTERVAL
+        };
+        if stalled && !self.stalled {
+            warn!(
+                unsent = ?self.unsent_duration,
+                "the connection is not keeping up; holding back all but the newest {:?} of audio",
+                self.limit
+            );
+        }
+        self.stalled = stalled;
+    }
+
+    fn written(&mut self, size: usize, duration: Duration) {
+        self.unsent.push_back((size, duration));
+        self.unsent_duration += duration;
+    }
+
+    /// Keep a frame for later, dropping the oldest ones beyond the limit.
+    fn hold(&mut self, payload: Bytes, duration: Duration) {
+        self.held.push_back((payload, duration));
+        self.held_duration += duration;
+        while self.held_duration > self.limit {
+            let Some((_, oldest)) = self.held.pop_front() else {
+                break;
+            };
+            self.held_duration -= oldest;
+            self.dropped.add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_only_the_newest_audio_while_the_connection_stalls() {
+        let frame = Duration::from_millis(20);
+        let (sent, dropped) = (Counter::default(), Counter::default());
+        let track = moq::Track::new("audio").produce();
+        let mut queue = SendQueue::new(track.producer).with_backlog_limit(
+            Duration::from_millis(100),
+            sent.clone(),
+            dropped.clone(),
+        );
+        let payload = || Bytes::from_static(&[0; 10]);
+
+        // keeping up: every frame goes out.
+        for _ in 0..5 {
+            queue.send(payload(), frame, true);
+            sent.add(10);
+        }
+        // nothing sent for longer than the limit and a sample interval.
+        for _ in 0..100 {
+            queue.send(payload(), frame, true);
+        }
+        let backlog = queue.backlog.as_ref().unwrap();
+        assert!(backlog.stalled);
+        assert_eq!(backlog.held_duration, Duration::from_millis(100));
+        let dropped_while_stalled = dropped.get();
+        assert!(dropped_while_stalled > 0);
+
+        // the connection catches up.
+        sent.add(10 * 100);
+        queue.send(payload(), frame, true);
+        let backlog = queue.backlog.as_ref().unwrap();
+        assert!(!backlog.stalled);
+        assert!(backlog.held.is_empty());
+        assert_eq!(dropped.get(), dropped_while_stalled);
+    }
+
+    #[tokio::test]
+    async fn paces_a_burst_without_holding_up_the_publisher() {
+        let frame = Duration::from_millis(20);
+        let track = moq::Track::new("audio").produce();
+        let mut consumer = track.consumer;
+        let mut queue = SendQueue::new(track.producer).with_pacing();
+        let start = std::time::Instant::now();
+        for _ in 0..5 {
+            queue.send(Bytes::from_static(&[0; 10]), frame, true);
+        }
+        // handing the burst over waits for none of it.
+        assert!(start.elapsed() < Duration::from_millis(16));
+        queue.close().await;
+        // it went out over four gaps of 16 ms.
+        assert!(start.elapsed() >= Duration::from_millis(64));
+        for _ in 0..5 {
+            let mut group = consumer.next_group().await.unwrap().unwrap();
+            assert_eq!(group.read_frame().await.unwrap().unwrap().len(), 10);
+        }
+    }
+}
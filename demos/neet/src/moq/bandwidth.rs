@@ -0,0 +1,114 @@
+//! A data budget for the call, for metered connections.
+//!
+//! `--max-bandwidth-kbps` caps each direction's data rate on the call's QUIC
+//! connection, overhead included. While we send more than that, our encoder
+//! bitrate is capped and redundancy is dropped; while we receive more, our
+//! receiver reports ask the remote for a lower bitrate. Either cap is lifted
+//! a step at a time once the rate is back under the budget.
+
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use super::report::MAX_BITRATE;
+use crate::{codec::BitrateTarget, stats::Stats};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Share of the cap to aim for, leaving room for bursts.
+const HEADROOM: f64 = 0.9;
+/// Lowest bitrate a cap may ask for; Opus gets no smaller.
+const MIN_CEILING: u32 = 6_000;
+const CEILING_STEP: u32 = 2_000;
+
+/// The bitrate ceiling one direction needs to stay within the cap.
+#[derive(Debug)]
+struct Ceiling {
+    direction: &'static str,
+    bitrate: Option<u32>,
+}
+
+impl Ceiling {
+    fn new(direction: &'static str) -> Self {
+        Self {
+            direction,
+            bitrate: None,
+        }
+    }
+
+    /// Update from the measured `rate` with `cap`, both in bits per second.
+    /// `current` is the bitrate before any ceiling and also where the ceiling
+    /// is lifted again.
+    fn update(&mut self, rate: u64, cap: u64, current: u32) -> Option<u32> {
+        let target = cap as f64 * HEADROOM;
+        let before = self.bitrate;
+        if rate > cap {
+            let from = self.bitrate.unwrap_or(current) as f64;
+            let lowered = (from * target / rate as f64) as u32;
+            self.bitrate = Some(lowered.max(MIN_CEILING));
+        } else if let Some(bitrate) = self.bitrate {
+            if (rate as f64) < target {
+                let raised = bitrate + CEILING_STEP;
+                self.bitrate = (raised < current).then_some(raised);
+            }
+        }
+        match (before, self.bitrate) {
+            (None, Some(bitrate)) => warn!(
+                direction = self.direction,
+                rate_bps = rate,
+                bitrate,
+                "over the bandwidth cap; lowering the bitrate"
+            ),
+            (Some(_), None) => info!(direction = self.direction, "back under the bandwidth cap"),
+            _ => {}
+        }
+        self.bitrate
+    }
+}
+
+/// Hold both directions of the call to `cap` bits per second until aborted.
+/// `full` is the bitrate our encoder uses when nothing limits it.
+pub async fn enforce_bandwidth_cap(cap: u64, stats: Stats, bitrate: BitrateTarget, full: u32) {
+    let mut send = Ceiling::new("send");
+    let mut receive = Ceiling::new("receive");
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let connection = &stats.connection;
+        let ceiling = send.update(connection.send_bps.get(), cap, full);
+        bitrate.set_cap(ceiling.unwrap_or(0));
+        let ceiling = receive.update(connection.receive_bps.get(), cap, MAX_BITRATE);
+        stats.receive_limit_bps.set(ceiling.unwrap_or(0) as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ceiling_backs_off_over_the_cap_and_lifts_under_it() {
+        let mut ceiling = Ceiling::new("send");
+        assert_eq!(ceiling.update(30_000, 32_000, 64_000), None);
+        // 64 kbps of audio plus overhead against a 32 kbps cap.
+        let lowered = ceiling.update(80_000, 32_000, 64_000).unwrap();
+        assert_eq!(lowered, 23_040);
+        assert_eq!(
+            ceiling.update(30_000, 32_000, 64_000),
+            Some(lowered),
+            "held just under the cap"
+        );
+        assert_eq!(
+            ceiling.update(20_000, 32_000, 64_000),
+            Some(lowered + CEILING_STEP)
+        );
+        for _ in 0..30 {
+            ceiling.update(10_000, 32_000, 64_000);
+        }
+        assert_eq!(ceiling.update(10_000, 32_000, 64_000), None, "lifted");
+        // caps past what fits in 32 bits.
+        assert_eq!(
+            ceiling.update(10_000_000_000, 8_000_000_000, 64_000),
+            Some(46_080)
+        );
+    }
+}
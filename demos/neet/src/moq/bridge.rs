@@ -0,0 +1,822 @@
+//! Conference bridge: one server-side participant that mixes everyone in a
+//! session and sends each participant a mix of everyone but themselves.
+//!
+//! Participants `join` under a name and publish their audio at that path; the
+//! bridge subscribes to every such broadcast and publishes the participant's
+//! mix-minus at `bridge/<name>`. Mixing happens on decoded audio (an MCU), so
+//! each client sends one track and receives one, however many are in the call.
+//!
+//! Control messages from any participant are relayed to every mix, so the
+//! moderator's commands reach everyone. Given the moderator's key, the bridge
+//! also enforces them itself: it drops kicked participants and admits nobody
+//! new while the session is locked. Given a PIN, it only mixes participants
+//! who prove they know it, and proves it to them in turn.
+//!
+//! Each mix is a bus of the [`BusMixer`], which the config's `[matrix]` can
+//! route otherwise than everyone-but-themselves, and which can add buses of
+//! nobody's, e.g. a program mix.
+//!
+//! Participants who send their audio level (`--send-audio-level`) are left
+//! out of every mix while it says they are not talking, so that open
+//! microphones don't add up to a noise floor in large calls.
+//!
+//! With `--record`, the bridge also writes what it decodes of each
+//! participant to a Matroska file, on a track named after them, so that
+//! post-production can tell who spoke when, and each bus of nobody's on a
+//! track of its own. It tells every participant it records, and with
+//! `--require-recording-consent` it records nothing while anyone in the mix
+//! has not consented.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use moq_lite as moq;
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::{
+    audio_level::AudioLevelDecoder,
+    backlog::SendQueue,
+    capability::Capabilities,
+    catalog::{publish_catalog, Catalog, CATALOG_TRACK_NAME},
+    connect,
+    control::{
+        next_frame, write_frame, ControlChannel, ControlFrame, ControlVerifier, ModeratorCommand,
+        ModeratorKey, CONTROL_TRACK_NAME,
+    },
+    epoch::CallEpoch,
+    extension::FrameExtensions,
+    forward_media_to_moq, forward_moq_to_media,
+    pin::{Pin, PinCheck, Verdict, CHALLENGE_INTERVAL},
+    probe::is_probe,
+    report::{consume_reports, publish_reports, BitrateController, REPORT_TRACK_NAME},
+    Delivery, GroupStrategy, IncomingFrames, Redundancy, RedundancyEncoder, RelayTransport,
+    TrackPriorities, TransportOptions, AUDIO_TRACK_NAME,
+};
+use crate::{
+    audio::{
+        beeps, AudioMode, AudioSink, AudioSource, BusInput, BusMixer, Clip, RoutingMatrix,
+        ENGINE_FORMAT,
+    },
+    codec::{
+        mkv::{MkvTrack, MkvWriter, TrackCodec},
+        opus::{MediaTrackOpusDecoder, OpusChannels},
+        track::MediaTrackEncoder,
+        BitrateTarget, Codec, Encoder,
+    },
+    history::CallRecord,
+    media::{self, MediaTrack, OverflowPolicy, PauseState, TrackKind},
+    schedule::time_limit,
+    stats::{RemoteLevel, Stats},
+};
+
+const BRIDGE_PREFIX: &str = "bridge/";
+const TICK: Duration = Duration::from_millis(20);
+/// Frames buffered between the network and a participant's decoder.
+const INPUT_QUEUE_FRAMES: usize = 32;
+/// Encoded mix frames buffered before publishing.
+const OUTPUT_QUEUE_FRAMES: usize = 16;
+/// Control messages buffered for each mix.
+const CONTROL_CAPACITY: usize = 64;
+/// How often participants are told that we record, until they consent, so
+/// that late joiners learn it too.
+const RECORDING_NOTICE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the bridge publishes the mix for participant `name`.
+pub fn bridge_mix_path(name: &str) -> String {
+    format!("{BRIDGE_PREFIX}{name}")
+}
+
+#[derive(Clone)]
+pub struct BridgeOptions {
+    pub relay_url: Url,
+    pub session_id: String,
+    pub priorities: TrackPriorities,
+    pub transport: TransportOptions,
+    /// Enforce kicks and locks signed with this key.
+    pub moderator_key: Option<ModeratorKey>,
+    /// Only mix participants who prove they know this PIN.
+    pub pin: Option<Pin>,
+    /// Stop after this long, beeping into every mix beforehand.
+    pub max_duration: Option<Duration>,
+    /// Record every participant to this Matroska file.
+    pub record: Option<PathBuf>,
+    /// Record nobody until everyone in the mix consented.
+    pub require_recording_consent: bool,
+    /// What each participant hears, and the buses to record besides.
+    pub matrix: RoutingMatrix,
+    /// Cancelled to stop the bridge, finishing the recording.
+    pub shutdown: CancellationToken,
+}
+
+impl fmt::Debug for BridgeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BridgeOptions")
+            .field("relay_url", &self.relay_url)
+            .field("session_id", &self.session_id)
+            .field("priorities", &self.priorities)
+            .field("transport", &self.transport)
+            .field("moderator_key", &self.moderator_key)
+            .field("pin", &self.pin)
+            .field("max_duration", &self.max_duration)
+            .field("record", &self.record)
+            .field("require_recording_consent", &self.require_recording_consent)
+            .field("matrix", &self.matrix)
+            .finish()
+    }
+}
+
+/// Run the bridge until the relay session ends.
+pub async fn run_bridge(options: BridgeOptions) -> Result<()> {
+    let stats = Stats::default();
+    let route = RelayTransport::new(
+        &options.relay_url,
+        &options.session_id,
+        options.transport.clone(),
+    )?;
+    let relay = connect(&route, stats.connection.clone()).await?;
+    info!(session = options.session_id, "bridge running");
+
+    let recording = options
+        .record
+        .as_deref()
+        .map(|path| Recording::create(path, options.require_recording_consent))
+        .transpose()?;
+    let mut history =
+        CallRecord::start("bridge", &options.session_id).with_recording(options.record.as_deref());
+    let (joins, joined) = mpsc::channel(16);
+    let (marks, marked) = mpsc::unbounded_channel();
+    let alerts = Clip::default();
+    let buses = BusMixer::new(options.matrix.clone());
+    let mut mixer = tokio::spawn(mix_loop(joined, marked, alerts.clone(), buses, recording));
+    let accept = accept_participants(
+        &options,
+        relay.publish.clone(),
+        relay.subscribe,
+        joins,
+        marks,
+        &mut history,
+    );
+    let hangup = async {
+        match options.max_duration {
+            Some(limit) => time_limit(limit, |warning| alerts.push(&beeps(warning))).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let mut mixed = false;
+    let result = select! {
+        res = accept => res,
+        res = &mut mixer => {
+            mixed = true;
+            res.map_err(anyhow::Error::from)
+        }
+        err = relay.session.closed() => Err(anyhow!("MoQ session closed: {err}")),
+        _ = hangup => Ok(()),
+        _ = options.shutdown.cancelled() => Ok(()),
+    };
+    if !mixed {
+        // nobody can join anymore, so the mixer stops and finishes the recording.
+        let _ = mixer.await;
+    }
+    relay.sampler.abort();
+    info!(connection = ?stats.connection.snapshot(), "bridge stopped");
+    history.finish(&result, &stats);
+    result
+}
+
+/// A participant's decoder and mix encoder, owned by the mixer.
+struct MixInput {
+    name: String,
+    decoder: MediaTrackOpusDecoder,
+    encoder: MediaTrackEncoder,
+    buf: Vec<f32>,
+    /// The level the participant reports, if they do.
+    level: RemoteLevel,
+    /// Whether the participant agreed to be recorded.
+    consented: Arc<AtomicBool>,
+}
+
+impl BusInput for MixInput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn samples(&self) -> &[f32] {
+        &self.buf
+    }
+}
+
+/// Network tasks of a participant, stopped when they leave.
+struct Participant {
+    name: String,
+    stats: Stats,
+    tasks: Vec<JoinHandle<()>>,
+    /// Keeps the mix broadcast published.
+    _broadcast: moq::BroadcastProducer,
+    /// Keeps the catalog of the mix published.
+    _catalog: moq::TrackProducer,
+}
+
+impl Drop for Participant {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        info!(participant = self.name, stats = ?self.stats.snapshot(), "participant left");
+    }
+}
+
+/// Relays control messages between participants, and tells the bridge
+/// about the moderator's commands it can verify.
+#[derive(Clone)]
+struct ControlRelay {
+    messages: broadcast::Sender<Bytes>,
+    verifier: Option<ControlVerifier>,
+    commands: mpsc::UnboundedSender<ModeratorCommand>,
+    /// Moments participants marked, by participant, for the recording.
+    marks: mpsc::UnboundedSender<(String, String)>,
+    /// Set if the session is recorded: whether participants must consent.
+    recording: Option<bool>,
+    session_id: String,
+    pin: Option<Pin>,
+}
+
+/// A participant waiting to prove the PIN before they are mixed.
+struct Admission {
+    check: PinCheck,
+    input: MixInput,
+    joins: mpsc::Sender<MixInput>,
+}
+
+async fn accept_participants(
+    options: &BridgeOptions,
+    publish: moq::OriginProducer,
+    mut origin: moq::OriginConsumer,
+    joins: mpsc::Sender<MixInput>,
+    marks: mpsc::UnboundedSender<(String, String)>,
+    history: &mut CallRecord,
+) -> Result<()> {
+    let (commands, mut moderation) = mpsc::unbounded_channel();
+    let control = ControlRelay {
+        messages: broadcast::channel(CONTROL_CAPACITY).0,
+        verifier: options
+            .moderator_key
+            .map(|key| ControlVerifier::new(key, &options.session_id)),
+        commands,
+        marks,
+        recording: options
+            .record
+            .as_ref()
+            .map(|_| options.require_recording_consent),
+        session_id: options.session_id.clone(),
+        pin: options.pin.clone(),
+    };
+    let mut participants: HashMap<String, Participant> = HashMap::new();
+    let mut locked = false;
+    loop {
+        let announced = select! {
+            announced = origin.announced() => announced,
+            Some(command) = moderation.recv() => {
+                match command {
+                    ModeratorCommand::Kick { target } => {
+                        if participants.remove(&target).is_some() {
+                            info!(participant = target, "kicked by the moderator");
+                        }
+                    }
+                    ModeratorCommand::LockSession { locked: lock, repeat } => {
+                        if !repeat && lock != locked {
+                            info!(locked = lock, "moderator changed the session lock");
+                        }
+                        locked = lock;
+                    }
+                    ModeratorCommand::MuteRequest { .. } => {}
+                }
+                continue;
+            }
+        };
+        match announced {
+            Some((path, Some(broadcast))) => {
+                let name = path.as_str();
+                if name.starts_with(BRIDGE_PREFIX) || is_probe(name) {
+                    continue;
+                }
+                if locked && !participants.contains_key(name) {
+                    warn!(participant = name, "session is locked; not admitting");
+                    continue;
+                }
+                info!(participant = name, "participant joined");
+                history.add_peer(name);
+                let started = start_participant(
+                    name,
+                    broadcast,
+                    &publish,
+                    &options.priorities,
+                    &control,
+                    &joins,
+                );
+                let (participant, input) = match started {
+                    Ok(started) => started,
+                    Err(err) => {
+                        warn!(participant = name, "failed to start participant: {err:?}");
+                        continue;
+                    }
+                };
+                // a participant that rejoins replaces its old session.
+                participants.insert(name.to_string(), participant);
+                if let Some(input) = input {
+                    joins
+                        .send(input)
+                        .await
+                        .map_err(|_| anyhow!("bridge mixer stopped"))?;
+                }
+            }
+            Some((path, None)) => {
+                participants.remove(path.as_str());
+            }
+            None => return Err(anyhow!("announcement stream closed")),
+        }
+    }
+}
+
+/// Subscribe to a participant's audio and reports, and publish their mix.
+/// Returns their mixer input, unless they must prove the PIN first.
+fn start_participant(
+    name: &str,
+    broadcast: moq::BroadcastConsumer,
+    publish: &moq::OriginProducer,
+    priorities: &TrackPriorities,
+    control: &ControlRelay,
+    joins: &mpsc::Sender<MixInput>,
+) -> Result<(Participant, Option<MixInput>)> {
+    let stats = Stats::default();
+    let (sender, receiver) = media::channel(
+        INPUT_QUEUE_FRAMES,
+        OverflowPolicy::default(),
+        stats.playback_dropped.clone(),
+    );
+    let codec = Codec::Opus {
+        channels: OpusChannels::Stereo,
+    };
+    let decoder = MediaTrackOpusDecoder::new(MediaTrack::new(receiver, codec, TrackKind::Audio))?
+        .with_buffer_gauge(stats.playback_buffer_us.clone());
+    let bitrate = BitrateTarget::default();
+    let (encoder, mix_track) = MediaTrackEncoder::new(
+        codec,
+        AudioMode::Voice,
+        OUTPUT_QUEUE_FRAMES,
+        OverflowPolicy::default(),
+        stats.capture_dropped.clone(),
+        PauseState::default(),
+    )?;
+    let encoder = encoder.with_bitrate(bitrate.clone());
+
+    let mut mix = moq::Broadcast::produce();
+    let audio_producer = mix
+        .producer
+        .create_track(priorities.track(AUDIO_TRACK_NAME, TrackKind::Audio));
+    let report_producer = mix
+        .producer
+        .create_track(priorities.track(REPORT_TRACK_NAME, TrackKind::Control));
+    let control_producer = mix
+        .producer
+        .create_track(priorities.track(CONTROL_TRACK_NAME, TrackKind::Control));
+    // tells the participant what the bridge understands of what it sends.
+    let mut catalog_producer = mix
+        .producer
+        .create_track(priorities.track(CATALOG_TRACK_NAME, TrackKind::Control));
+    publish_catalog(
+        &mut catalog_producer,
+        &Catalog::new(codec).with_capabilities(Capabilities::BRIDGE),
+    )?;
+    publish.publish_broadcast(bridge_mix_path(name), mix.consumer.clone());
+
+    let audio_consumer =
+        broadcast.subscribe_track(&priorities.track(AUDIO_TRACK_NAME, TrackKind::Audio));
+    let report_consumer =
+        broadcast.subscribe_track(&priorities.track(REPORT_TRACK_NAME, TrackKind::Control));
+    let control_consumer =
+        broadcast.subscribe_track(&priorities.track(CONTROL_TRACK_NAME, TrackKind::Control));
+    let receive = forward_moq_to_media(
+        audio_consumer,
+        IncomingFrames::new(sender, stats.clone()).with_extensions(
+            FrameExtensions::default().with_decoder(AudioLevelDecoder(stats.remote_level.clone())),
+        ),
+        Delivery::Reliable,
+    );
+    let send = forward_media_to_moq(
+        mix_track,
+        SendQueue::new(audio_producer),
+        GroupStrategy::PerFrame,
+        RedundancyEncoder::new(Redundancy::default(), stats.remote_loss_permille.clone()),
+        PauseState::default(),
+        // the mix track ends when the participant leaves.
+        CancellationToken::new(),
+        CallEpoch::now(),
+    );
+    let reports = publish_reports(report_producer, stats.clone());
+    let remote_reports = consume_reports(
+        report_consumer,
+        BitrateController::new(bitrate),
+        stats.clone(),
+    );
+    let input = MixInput {
+        name: name.to_string(),
+        decoder,
+        encoder,
+        buf: vec![0.; ENGINE_FORMAT.sample_count(TICK)],
+        level: stats.remote_level.clone(),
+        consented: Arc::default(),
+    };
+    let consented = input.consented.clone();
+    let (admission, input) = match &control.pin {
+        Some(pin) => {
+            let admission = Admission {
+                check: PinCheck::new(pin, &control.session_id, bridge_mix_path(name), name)?,
+                input,
+                joins: joins.clone(),
+            };
+            (Some(admission), None)
+        }
+        None => (None, Some(input)),
+    };
+    // frames for this participant only: PIN challenges and answers.
+    let own = ControlChannel::default();
+    let tasks = vec![
+        spawn_logged(name, "receive", receive),
+        spawn_logged(name, "send", send),
+        spawn_logged(name, "reports", reports),
+        spawn_logged(name, "remote reports", remote_reports),
+        spawn_logged(
+            name,
+            "control in",
+            participant_control(
+                name.to_string(),
+                control_consumer,
+                control.clone(),
+                own.clone(),
+                admission,
+                consented,
+            ),
+        ),
+        spawn_logged(
+            name,
+            "control out",
+            relay_control_out(
+                control_producer,
+                control.messages.subscribe(),
+                own.subscribe(),
+            ),
+        ),
+    ];
+
+    let participant = Participant {
+        name: name.to_string(),
+        stats,
+        tasks,
+        _broadcast: mix.producer,
+        _catalog: catalog_producer,
+    };
+    Ok((participant, input))
+}
+
+/// Pass a participant's control messages on to every mix, acting on those the
+/// moderator signed, run the PIN handshake with them, and tell them we
+/// record until they `consented`.
+async fn participant_control(
+    name: String,
+    mut track: moq::TrackConsumer,
+    mut control: ControlRelay,
+    own: ControlChannel,
+    mut admission: Option<Admission>,
+    consented: Arc<AtomicBool>,
+) -> Result<()> {
+    let pin = admission.as_ref().map(|admission| admission.check.clone());
+    let mut challenge = tokio::time::interval(CHALLENGE_INTERVAL);
+    let mut notice = tokio::time::interval(RECORDING_NOTICE_INTERVAL);
+    loop {
+        let frame = select! {
+            frame = next_frame(&mut track) => frame?,
+            _ = challenge.tick(), if admission.is_some() => {
+                if let Some(admission) = &admission {
+                    own.send(&admission.check.challenge());
+                }
+                continue;
+            }
+            _ = notice.tick(), if control.recording.is_some() => {
+                let consent = control.recording == Some(true);
+                if !(consent && consented.load(Ordering::Relaxed)) {
+                    own.send(&ControlFrame::Recording { consent });
+                }
+                continue;
+            }
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+        if let ControlFrame::Mark { label } = frame {
+            // nobody recording is fine.
+            let _ = control.marks.send((name.clone(), label));
+            continue;
+        }
+        if let ControlFrame::RecordingConsent = frame {
+            if control.recording.is_some() && !consented.swap(true, Ordering::Relaxed) {
+                info!(
+                    participant = name,
+                    "participant consented to being recorded"
+                );
+            }
+            continue;
+        }
+        let ControlFrame::Moderator(signed) = &frame else {
+            let verdict = pin.as_ref().and_then(|pin| pin.handle(&frame, &own));
+            match (verdict, admission.take()) {
+                (Some(Verdict::Passed), Some(admission)) => {
+                    info!(participant = name, "participant proved the PIN");
+                    admission
+                        .joins
+                        .send(admission.input)
+                        .await
+                        .map_err(|_| anyhow!("bridge mixer stopped"))?;
+                }
+                (Some(Verdict::Failed), Some(_)) => {
+                    warn!(participant = name, "wrong PIN; not mixing participant");
+                }
+                (_, pending) => admission = pending,
+            }
+            continue;
+        };
+        if let Some(verifier) = &mut control.verifier {
+            match verifier.verify(signed) {
+                Ok(command) => {
+                    let _ = control.commands.send(command);
+                }
+                Err(err) => {
+                    debug!("not relaying control message: {err:#}");
+                    continue;
+                }
+            }
+        }
+        // nobody to relay to is fine.
+        let _ = control.messages.send(frame.encode()?);
+    }
+}
+
+/// Publish relayed messages and this participant's own frames on their mix.
+async fn relay_control_out(
+    mut track: moq::TrackProducer,
+    mut shared: broadcast::Receiver<Bytes>,
+    mut own: broadcast::Receiver<Bytes>,
+) -> Result<()> {
+    loop {
+        let payload = select! {
+            payload = shared.recv() => payload,
+            payload = own.recv() => payload,
+        };
+        match payload {
+            Ok(payload) => write_frame(&mut track, payload),
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!(missed, "control messages dropped")
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+fn spawn_logged(
+    participant: &str,
+    task: &'static str,
+    future: impl Future<Output = Result<()>> + Send + 'static,
+) -> JoinHandle<()> {
+    let participant = participant.to_string();
+    tokio::spawn(async move {
+        if let Err(err) = future.await {
+            warn!(participant, task, "{err:#}");
+        }
+    })
+}
+
+/// `--record`: each participant's decoded audio, encoded again on a track of
+/// their own.
+struct Recording {
+    writer: MkvWriter,
+    /// Track and encoder by participant, kept when they rejoin.
+    tracks: HashMap<String, (u64, Box<dyn Encoder>)>,
+    /// Ticks since the recording started.
+    ticks: u32,
+    /// Record nobody until everyone in the mix consented.
+    require_consent: bool,
+    /// Whether someone's consent is missing.
+    paused: bool,
+}
+
+impl Recording {
+    const CODEC: Codec = Codec::Opus {
+        channels: OpusChannels::Stereo,
+    };
+
+    fn create(path: &Path, require_consent: bool) -> Result<Self> {
+        info!(path = %path.display(), require_consent, "recording the session");
+        Ok(Self {
+            writer: MkvWriter::create(path)?,
+            tracks: HashMap::new(),
+            ticks: 0,
+            require_consent,
+            paused: false,
+        })
+    }
+
+    /// Whether this tick of `inputs` may be recorded: with consent required,
+    /// once each of them consented.
+    fn consented(&mut self, inputs: &[MixInput]) -> bool {
+        if !self.require_consent {
+            return true;
+        }
+        let waiting = || {
+            inputs
+                .iter()
+                .filter(|input| !input.consented.load(Ordering::Relaxed))
+        };
+        let paused = waiting().next().is_some();
+        if paused != self.paused {
+            match paused {
+                // listed only when it changes, not every tick.
+                true => {
+                    let waiting: Vec<_> = waiting().map(|input| input.name.as_str()).collect();
+                    info!(?waiting, "recording paused until everyone consents")
+                }
+                false => info!("everyone consented; recording"),
+            }
+            self.paused = paused;
+        }
+        !paused
+    }
+
+    /// Add a tick of `name`'s audio.
+    fn record(&mut self, name: &str, buf: &[f32]) -> Result<()> {
+        if !self.tracks.contains_key(name) {
+            let track = self.writer.add_track(MkvTrack {
+                codec: TrackCodec::Opus {
+                    channels: Self::CODEC.layout().channels() as u8,
+                },
+                participant: name.to_string(),
+            })?;
+            let encoder = Self::CODEC.encoder(AudioMode::Voice)?;
+            self.tracks.insert(name.to_string(), (track, encoder));
+        }
+        let (track, encoder) = self.tracks.get_mut(name).expect("added above");
+        let frame = encoder.encode(buf)?;
+        self.writer.write(*track, TICK * self.ticks, TICK, &frame)
+    }
+
+    /// Add a chapter for the moment `name` marked as `label`.
+    fn mark(&mut self, name: &str, label: &str) -> Result<()> {
+        let at = TICK * self.ticks;
+        info!(participant = name, label, ?at, "marked in the recording");
+        self.writer.add_chapter(at, format!("{name}: {label}"))
+    }
+
+    fn finish(self) {
+        let path = self.writer.path().display().to_string();
+        match self.writer.finish() {
+            Ok(()) => info!(path, participants = self.tracks.len(), "recording written"),
+            Err(err) => warn!(path, "failed to write the recording: {err:#}"),
+        }
+    }
+}
+
+/// Every tick, decode each participant and encode their bus for them, plus
+/// any `alerts`, and record them, with the moments they `marked`, and the
+/// buses of nobody's, if asked to. Returns once no more participants can
+/// join.
+async fn mix_loop(
+    mut joined: mpsc::Receiver<MixInput>,
+    mut marked: mpsc::UnboundedReceiver<(String, String)>,
+    mut alerts: Clip,
+    mut buses: BusMixer,
+    mut recording: Option<Recording>,
+) {
+    let samples = ENGINE_FORMAT.sample_count(TICK);
+    let mut shared = vec![0.; samples];
+    let mut out = vec![0.; samples];
+    let mut inputs: Vec<MixInput> = Vec::new();
+    // each input's bus, kept from tick to tick.
+    let mut mixes: Vec<Vec<f32>> = Vec::new();
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        loop {
+            match joined.try_recv() {
+                Ok(input) => {
+                    // a rejoin replaces the old input, whose track is closing anyway.
+                    inputs.retain(|existing| existing.name != input.name);
+                    inputs.push(input);
+                    debug!(participants = inputs.len(), "mixer inputs changed");
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    if let Some(recording) = recording {
+                        recording.finish();
+                    }
+                    return;
+                }
+            }
+        }
+
+        inputs.retain_mut(|input| {
+            input.buf.fill(0.);
+            match input.decoder.tick(&mut input.buf) {
+                Ok(ControlFlow::Continue(_)) => {
+                    // decoded all the same, to keep the decoder in step.
+                    let level = input.level.snapshot();
+                    if level.is_some_and(|level| !level.speaking) {
+                        input.buf.fill(0.);
+                    }
+                    true
+                }
+                Ok(ControlFlow::Break(())) => false,
+                Err(err) => {
+                    warn!(participant = input.name, "dropping participant: {err:#}");
+                    false
+                }
+            }
+        });
+
+        while let Ok((name, label)) = marked.try_recv() {
+            if let Some(rec) = &mut recording {
+                if let Err(err) = rec.mark(&name, &label) {
+                    warn!(participant = name, "failed to add a chapter: {err:#}");
+                }
+            }
+        }
+        // everyone hears the alerts. An idle clip plays silence and never
+        // ends.
+        let _ = alerts.tick(&mut shared);
+        buses.start(&shared, &inputs);
+        let consented = recording.as_mut().is_some_and(|rec| rec.consented(&inputs));
+        if let Some(rec) = recording.as_mut().filter(|_| consented) {
+            let mut recorded = inputs
+                .iter()
+                .try_for_each(|input| rec.record(&input.name, &input.buf));
+            let unowned = buses
+                .matrix()
+                .buses()
+                .filter(|bus| inputs.iter().all(|input| input.name != *bus));
+            for bus in unowned {
+                buses.mix(bus, &shared, &inputs, &mut out);
+                recorded = recorded.and_then(|()| rec.record(bus, &out));
+            }
+            rec.ticks += 1;
+            if let Err(err) = recorded {
+                warn!("stopped recording: {err:#}");
+                if let Some(recording) = recording.take() {
+                    recording.finish();
+                }
+            }
+        }
+        mixes.resize_with(inputs.len(), || vec![0.; samples]);
+        for (input, mix) in inputs.iter().zip(&mut mixes) {
+            buses.mix(&input.name, &shared, &inputs, mix);
+        }
+        let mut bus = mixes.iter();
+        inputs.retain_mut(|input| {
+            let mix = bus.next().expect("a mix for every input");
+            match input.encoder.tick(mix) {
+                Ok(ControlFlow::Continue(())) => true,
+                Ok(ControlFlow::Break(())) => false,
+                Err(err) => {
+                    warn!(participant = input.name, "dropping participant: {err:#}");
+                    false
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixes_are_published_per_participant() {
+        assert_eq!(bridge_mix_path("alice"), "bridge/alice");
+    }
+}
@@ -0,0 +1,180 @@
+//! What each side of a call understands, exchanged at call start so that
+//! builds of different ages keep talking as features land.
+//!
+//! A broadcast's catalog carries a bitmap of the [`Capability`]s its
+//! publisher can receive, next to the catalog's own version. Each side then
+//! sends only what both understand: no frame extensions to a peer that
+//! would take them for audio, say. A peer from before the bitmap, or one
+//! without a catalog, counts as having [`Capabilities::LEGACY`], and bits we
+//! don't know yet are ignored. `--require` refuses a remote lacking any of
+//! the given capabilities, rather than falling back.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// A feature of the wire format, by its bit in [`Capabilities`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Capability {
+    /// Copies of earlier frames in later ones.
+    Redundancy = 0,
+    /// Capture timestamps in the frame header.
+    Timestamps = 1,
+    /// A FLAC rendition of the audio.
+    Lossless = 2,
+    /// Multichannel Opus.
+    Surround = 3,
+    /// The frame header's extension area.
+    Extensions = 4,
+    /// The audio level extension.
+    AudioLevel = 5,
+    /// Callers waiting their turn while it is on another call; only
+    /// listeners that wait for the next caller have it.
+    Queue = 8,
+}
+
+impl Capability {
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no capability is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+/// A set of capabilities, a bitmap on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// What peers understood before they said what they understand.
+    pub const LEGACY: Self = Self::of(&[
+        Capability::Redundancy,
+        Capability::Timestamps,
+        Capability::Lossless,
+        Capability::Surround,
+    ]);
+    /// What this build receives.
+    pub const OURS: Self = Self::of(&[
+        Capability::Redundancy,
+        Capability::Timestamps,
+        Capability::Lossless,
+        Capability::Surround,
+        Capability::Extensions,
+        Capability::AudioLevel,
+    ]);
+    /// What the bridge receives from participants: stereo Opus only.
+    pub const BRIDGE: Self = Self::of(&[
+        Capability::Redundancy,
+        Capability::Timestamps,
+        Capability::Extensions,
+        Capability::AudioLevel,
+    ]);
+
+    const fn of(capabilities: &[Capability]) -> Self {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < capabilities.len() {
+            bits |= 1 << capabilities[i] as u32;
+            i += 1;
+        }
+        Self(bits)
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    pub fn with(self, capability: Capability) -> Self {
+        Self(self.0 | capability.bit())
+    }
+
+    /// What both sets have.
+    pub fn common(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Those of `required` that are not in the set.
+    pub fn missing(self, required: &[Capability]) -> Vec<Capability> {
+        required
+            .iter()
+            .copied()
+            .filter(|capability| !self.contains(*capability))
+            .collect()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Capability::value_variants()
+            .iter()
+            .filter(|capability| self.contains(**capability))
+            .map(ToString::to_string)
+            .collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// What we and the remote both understand, once it said; shared between
+/// receiving, which learns it from the remote's catalog, and sending, which
+/// adapts to it.
+#[derive(Debug, Clone)]
+pub struct PeerCapabilities(Arc<AtomicU32>);
+
+impl Default for PeerCapabilities {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(Capabilities::LEGACY.0)))
+    }
+}
+
+impl PeerCapabilities {
+    pub fn set(&self, capabilities: Capabilities) {
+        self.0.store(capabilities.0, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Capabilities {
+        Capabilities(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.get().contains(capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_down_to_what_both_understand() {
+        // a newer peer, with a bit we don't know.
+        let theirs: Capabilities = serde_json::from_str("511").unwrap();
+        let common = Capabilities::OURS.common(theirs);
+        assert_eq!(common, Capabilities::OURS);
+        assert!(!common.contains(Capability::Queue));
+
+        let common = Capabilities::OURS.common(Capabilities::LEGACY);
+        assert!(!common.contains(Capability::Extensions));
+        assert_eq!(
+            common.missing(&[Capability::Redundancy, Capability::AudioLevel]),
+            [Capability::AudioLevel]
+        );
+        assert_eq!(
+            Capabilities::BRIDGE.to_string(),
+            "redundancy,timestamps,extensions,audio-level"
+        );
+        assert_eq!(serde_json::to_string(&Capabilities::LEGACY).unwrap(), "15");
+    }
+}
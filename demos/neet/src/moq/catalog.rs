@@ -0,0 +1,215 @@
+//! The `catalog` track: what a broadcast carries, so a subscriber can set up
+//! its decoder before the first frame arrives.
+//!
+//! It holds a single JSON group, e.g. `{"audio":{"codec":"opus","layout":"5.1"}}`.
+//! Peers that predate it, and bridge mixes, publish none; their audio is
+//! stereo Opus. A peer sending lossless audio lists it as `lossless` next to
+//! `audio`: receivers that can decode it play it instead, and the others,
+//! which ignore the field, keep playing the Opus track. Further input
+//! devices (`--extra-input`) are listed under `inputs`, each with the track
+//! it is published on. `epoch_us` is the broadcast's call epoch, see
+//! [`super::epoch`]. `version` and `capabilities` say what the publisher
+//! understands, see [`super::capability`].
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use moq_lite as moq;
+use serde::{Deserialize, Serialize};
+
+use super::{capability::Capabilities, control::write_frame, epoch::CallEpoch, next_group};
+use crate::codec::{multistream::ChannelLayout, Codec};
+
+pub const CATALOG_TRACK_NAME: &str = "catalog";
+/// The version of the catalog format we publish.
+pub const CATALOG_VERSION: u32 = 2;
+/// How long to wait for the catalog of a remote that may not publish one.
+const CATALOG_WAIT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Catalog {
+    pub audio: AudioEntry,
+    /// A FLAC rendition of the audio on a track of its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lossless: Option<AudioEntry>,
+    /// Further inputs, each on a track of its own.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<InputEntry>,
+    /// What frame timestamps count from, in microseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch_us: Option<u64>,
+    /// The catalog format's version; catalogs without one are version 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// What the publisher can receive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioEntry {
+    pub codec: String,
+    /// A [`ChannelLayout`], kept as text so that a layout we don't know is
+    /// reported as such rather than as a malformed catalog.
+    pub layout: String,
+}
+
+/// An input published next to the microphone, e.g. an instrument.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputEntry {
+    pub name: String,
+    pub track: String,
+    #[serde(flatten)]
+    pub audio: AudioEntry,
+}
+
+impl Catalog {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            audio: AudioEntry::new(codec),
+            lossless: None,
+            inputs: Vec::new(),
+            epoch_us: None,
+            version: None,
+            capabilities: None,
+        }
+    }
+
+    /// Say that the publisher understands `capabilities`.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.version = Some(CATALOG_VERSION);
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// What the publisher understands, as far as it said.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities.unwrap_or(Capabilities::LEGACY)
+    }
+
+    pub fn with_epoch(mut self, epoch: CallEpoch) -> Self {
+        self.epoch_us = Some(epoch.unix_us());
+        self
+    }
+
+    /// The epoch the remote's frame timestamps count from, if it sent one.
+    pub fn epoch(&self) -> Option<CallEpoch> {
+        self.epoch_us.map(CallEpoch::from_unix_us)
+    }
+
+    /// Also list the lossless rendition.
+    pub fn with_lossless(mut self) -> Self {
+        self.lossless = Some(AudioEntry::new(Codec::Flac));
+        self
+    }
+
+    /// Also list the input `name`, published on `track`.
+    pub fn with_input(mut self, name: &str, track: &str, codec: Codec) -> Self {
+        self.inputs.push(InputEntry {
+            name: name.to_string(),
+            track: track.to_string(),
+            audio: AudioEntry::new(codec),
+        });
+        self
+    }
+
+    /// The codec to decode the remote's audio with, if we can.
+    pub fn codec(&self) -> Result<Codec> {
+        self.audio.codec()
+    }
+
+    /// The codec of the lossless rendition, if there is one we can decode.
+    pub fn lossless(&self) -> Option<Codec> {
+        self.lossless.as_ref()?.codec().ok()
+    }
+}
+
+impl InputEntry {
+    /// The codec to decode the input with, if we can.
+    pub fn codec(&self) -> Result<Codec> {
+        self.audio.codec()
+    }
+}
+
+impl AudioEntry {
+    pub(super) fn new(codec: Codec) -> Self {
+        Self {
+            codec: codec.name().to_string(),
+            layout: codec.layout().to_string(),
+        }
+    }
+
+    pub(super) fn codec(&self) -> Result<Codec> {
+        let AudioEntry { codec, layout } = self;
+        let parsed: ChannelLayout = layout
+            .parse()
+            .with_context(|| format!("the remote sends {layout} audio, which we can't play"))?;
+        Codec::from_catalog(codec, parsed).with_context(|| {
+            format!("the remote sends {layout} {codec} audio, which we can't decode")
+        })
+    }
+}
+
+pub fn publish_catalog(track: &mut moq::TrackProducer, catalog: &Catalog) -> Result<()> {
+    write_frame(track, Bytes::from(serde_json::to_vec(catalog)?));
+    Ok(())
+}
+
+/// The remote's catalog, or `None` if it publishes none. A catalog we can't
+/// parse is an error; one we can't subscribe to just isn't there.
+pub async fn read_catalog(mut track: moq::TrackConsumer) -> Result<Option<Catalog>> {
+    let read = async {
+        let Ok(Some(mut group)) = next_group(&mut track).await else {
+            return Ok(None);
+        };
+        let Ok(Some(payload)) = group.read_frame().await else {
+            return Ok(None);
+        };
+        let catalog = serde_json::from_slice(&payload)
+            .map_err(|err| anyhow!(err).context("malformed catalog"))?;
+        Ok(Some(catalog))
+    };
+    tokio::time::timeout(CATALOG_WAIT, read)
+        .await
+        .unwrap_or(Ok(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::opus::OpusChannels;
+
+    #[test]
+    fn layouts_we_cannot_play_are_refused() {
+        let layout = ChannelLayout::Surround71;
+        let catalog = Catalog::new(Codec::OpusMultistream { layout });
+        let json = serde_json::to_string(&catalog).unwrap();
+        assert_eq!(json, r#"{"audio":{"codec":"opus","layout":"7.1"}}"#);
+        let parsed: Catalog = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.codec().unwrap(), Codec::OpusMultistream { layout });
+
+        let mut future = parsed.clone();
+        future.audio.layout = "22.2".to_string();
+        assert!(future.codec().is_err());
+        future.audio.codec = "aac".to_string();
+        assert!(future.codec().is_err());
+
+        let lossless = Catalog::new(Codec::Opus {
+            channels: OpusChannels::Stereo,
+        })
+        .with_lossless();
+        let json = serde_json::to_string(&lossless).unwrap();
+        let parsed: Catalog = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.lossless(), Some(Codec::Flac));
+        assert_eq!(catalog.lossless(), None);
+
+        let guitar = catalog.with_input("guitar", "input-guitar", Codec::Flac);
+        let json = serde_json::to_string(&guitar).unwrap();
+        assert!(
+            json.contains(r#""inputs":[{"name":"guitar","track":"input-guitar","codec":"flac","#)
+        );
+        let parsed: Catalog = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.inputs[0].codec().unwrap(), Codec::Flac);
+    }
+}
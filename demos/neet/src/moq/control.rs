@@ -0,0 +1,628 @@
+//! The `control` track: moderator commands, PIN challenges and microphone
+//! trouble.
+//!
+//! Whoever sets up a session can moderate it: their commands are signed with
+//! their identity key and published on a `control` track next to their audio
+//! (the bridge relays them to every participant). Participants started with
+//! the moderator's public key check the signature, the session and a strictly
+//! increasing sequence number, then enforce the command themselves. Anything
+//! that fails the checks is logged and ignored.
+//!
+//! The same track carries the `--pin` handshake, see [`super::pin`], and the
+//! identity one, see [`super::fingerprint`], tells
+//! the remote when our `--mic-watchdog` fires, whether we are muted, on
+//! hold or away, and the moments we `mark`, which a recording bridge keeps as
+//! chapters. A recording bridge says so on the track, and participants tell
+//! it when they `consent` to being recorded.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bytes::Bytes;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use moq_lite as moq;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::{select, sync::broadcast};
+use tracing::{debug, info, warn};
+
+use super::{fingerprint::IdentityCheck, next_group, pin::PinCheck};
+use crate::{
+    audio::{AudioContext, MicStatus},
+    error::NeetError,
+    media::Presence,
+};
+
+pub const CONTROL_TRACK_NAME: &str = "control";
+/// How often a lock is repeated, so that participants who join late learn
+/// about it.
+const LOCK_REFRESH: Duration = Duration::from_secs(5);
+/// Frames waiting to be published.
+const OUTBOX_CAPACITY: usize = 16;
+/// Targets every participant.
+const EVERYONE: &str = "*";
+/// Beeps that tell the user the call is being recorded, without
+/// `--announce`.
+const RECORDING_BEEPS: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ModeratorCommand {
+    /// Ask `target` (or `*`, everyone) to stop publishing audio.
+    MuteRequest { target: String },
+    /// Remove `target` from the session.
+    Kick { target: String },
+    /// Refuse (or admit again) new participants. `repeat` marks the periodic
+    /// reminders, which is all a participant who joins later gets to see.
+    LockSession {
+        locked: bool,
+        #[serde(default)]
+        repeat: bool,
+    },
+}
+
+impl ModeratorCommand {
+    /// The words that start a command typed during a call.
+    pub const KEYWORDS: [&str; 4] = ["mute", "kick", "lock", "unlock"];
+
+    fn targets(target: &str, name: &str) -> bool {
+        target == EVERYONE || target == name
+    }
+}
+
+impl FromStr for ModeratorCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("mute"), Some(target)) => Self::MuteRequest {
+                target: target.to_string(),
+            },
+            (Some("kick"), Some(target)) if target != EVERYONE => Self::Kick {
+                target: target.to_string(),
+            },
+            (Some("lock"), None) => Self::LockSession {
+                locked: true,
+                repeat: false,
+            },
+            (Some("unlock"), None) => Self::LockSession {
+                locked: false,
+                repeat: false,
+            },
+            _ => bail!("expected `mute <name|*>`, `kick <name>`, `lock` or `unlock`, got `{s}`"),
+        };
+        ensure!(words.next().is_none(), "unexpected words after `{s}`");
+        Ok(command)
+    }
+}
+
+/// What gets signed: a command bound to one session, in order.
+#[derive(Debug, Serialize, Deserialize)]
+struct ControlMessage {
+    session: String,
+    sequence: u64,
+    command: ModeratorCommand,
+}
+
+/// A moderator command as sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedMessage {
+    /// The JSON of a [`ControlMessage`], signed as is.
+    message: String,
+    /// Hex-encoded ed25519 signature of `message`.
+    signature: String,
+}
+
+/// A frame on the control track.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ControlFrame {
+    Moderator(SignedMessage),
+    /// Prove you know the PIN by answering for this nonce.
+    PinChallenge {
+        nonce: String,
+    },
+    /// The answer to someone's challenge.
+    PinResponse {
+        nonce: String,
+        proof: String,
+    },
+    /// Prove who you are by signing for this nonce.
+    IdentityChallenge {
+        nonce: String,
+    },
+    /// The answer: an identity key and its signature.
+    IdentityProof {
+        nonce: String,
+        key: String,
+        signature: String,
+    },
+    /// Our microphone stopped working, or works again.
+    MicStatus {
+        status: MicStatus,
+    },
+    /// We muted, put the call on hold, stepped away, or are back.
+    Presence {
+        presence: Presence,
+    },
+    /// A moment we marked, for whoever records the call.
+    Mark {
+        label: String,
+    },
+    /// We record the call. With `consent`, nobody is recorded until everyone
+    /// agreed.
+    Recording {
+        #[serde(default)]
+        consent: bool,
+    },
+    /// We agree to be recorded.
+    RecordingConsent,
+}
+
+impl ControlFrame {
+    pub fn encode(&self) -> Result<Bytes> {
+        Ok(Bytes::from(serde_json::to_vec(self)?))
+    }
+}
+
+/// Frames to publish on our control track.
+#[derive(Debug, Clone)]
+pub struct ControlChannel(broadcast::Sender<Bytes>);
+
+impl Default for ControlChannel {
+    fn default() -> Self {
+        Self(broadcast::channel(OUTBOX_CAPACITY).0)
+    }
+}
+
+impl ControlChannel {
+    /// Queue `frame`; false if no control track is being published.
+    pub fn send(&self, frame: &ControlFrame) -> bool {
+        match frame.encode() {
+            Ok(payload) => self.0.send(payload).is_ok(),
+            Err(err) => {
+                warn!("failed to encode control frame: {err}");
+                false
+            }
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.0.subscribe()
+    }
+}
+
+/// Tell the remote whenever the microphone watchdog changes its mind.
+pub async fn send_mic_status(mut status: watch::Receiver<MicStatus>, channel: ControlChannel) {
+    // a remote that joins while all is well needs no news.
+    let current = *status.borrow_and_update();
+    if current != MicStatus::Ok {
+        channel.send(&ControlFrame::MicStatus { status: current });
+    }
+    while status.changed().await.is_ok() {
+        let current = *status.borrow_and_update();
+        channel.send(&ControlFrame::MicStatus { status: current });
+    }
+}
+
+/// Tell the remote about every moment we mark.
+pub async fn send_marks(mut marks: broadcast::Receiver<String>, channel: ControlChannel) {
+    loop {
+        match marks.recv().await {
+            Ok(label) => {
+                channel.send(&ControlFrame::Mark { label });
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("{skipped} marks were not sent to the remote")
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Tell the remote whenever we mute, hold, step away or come back.
+pub async fn send_presence(mut presence: watch::Receiver<Presence>, channel: ControlChannel) {
+    let mut current = *presence.borrow_and_update();
+    if current != Presence::Here {
+        channel.send(&ControlFrame::Presence { presence: current });
+    }
+    while presence.changed().await.is_ok() {
+        current = *presence.borrow_and_update();
+        channel.send(&ControlFrame::Presence { presence: current });
+    }
+}
+
+/// A moderator's public key, written as 64 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ModeratorKey(VerifyingKey);
+
+impl FromStr for ModeratorKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 32] = hex::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("expected 64 hex digits, got `{s}`"))?;
+        let key = VerifyingKey::from_bytes(&bytes).context("not an ed25519 public key")?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for ModeratorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0.as_bytes()))
+    }
+}
+
+impl fmt::Debug for ModeratorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ModeratorKey({self})")
+    }
+}
+
+/// Signs moderator commands and hands them to [`publish_control`].
+#[derive(Clone)]
+pub struct Moderator {
+    key: Arc<SigningKey>,
+    session: String,
+    sequence: Arc<AtomicU64>,
+    locked: Arc<AtomicBool>,
+    channel: ControlChannel,
+}
+
+impl fmt::Debug for Moderator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Moderator")
+            .field("key", &self.public_key())
+            .field("session", &self.session)
+            .finish()
+    }
+}
+
+impl Moderator {
+    pub fn new(key: SigningKey, session: impl Into<String>) -> Self {
+        // starting from the clock keeps commands sent after a restart newer
+        // than anything participants have already seen.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            key: Arc::new(key),
+            session: session.into(),
+            sequence: Arc::new(AtomicU64::new(now.as_millis() as u64)),
+            locked: Arc::default(),
+            channel: ControlChannel::default(),
+        }
+    }
+
+    /// Where the moderator's commands are queued for publishing.
+    pub fn channel(&self) -> ControlChannel {
+        self.channel.clone()
+    }
+
+    pub fn public_key(&self) -> ModeratorKey {
+        ModeratorKey(self.key.verifying_key())
+    }
+
+    /// Sign `command` and publish it to the session.
+    pub fn issue(&self, command: ModeratorCommand) -> Result<()> {
+        let frame = self.sign(&command)?;
+        if let ModeratorCommand::LockSession { locked, .. } = command {
+            self.locked.store(locked, Ordering::Relaxed);
+        }
+        ensure!(
+            self.channel.send(&frame),
+            "not connected to the session yet"
+        );
+        info!(?command, "moderator command sent");
+        Ok(())
+    }
+
+    fn sign(&self, command: &ModeratorCommand) -> Result<ControlFrame> {
+        let message = serde_json::to_string(&ControlMessage {
+            session: self.session.clone(),
+            sequence: self.sequence.fetch_add(1, Ordering::Relaxed),
+            command: command.clone(),
+        })?;
+        let signature = hex::encode(self.key.sign(message.as_bytes()).to_bytes());
+        Ok(ControlFrame::Moderator(SignedMessage {
+            message,
+            signature,
+        }))
+    }
+}
+
+/// Checks control messages against the moderator's key and session, and
+/// rejects replays.
+#[derive(Debug, Clone)]
+pub struct ControlVerifier {
+    key: ModeratorKey,
+    session: String,
+    last_sequence: Option<u64>,
+}
+
+impl ControlVerifier {
+    pub fn new(key: ModeratorKey, session: impl Into<String>) -> Self {
+        Self {
+            key,
+            session: session.into(),
+            last_sequence: None,
+        }
+    }
+
+    pub fn verify(&mut self, signed: &SignedMessage) -> Result<ModeratorCommand> {
+        let signature = hex::decode(&signed.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| anyhow!("malformed signature"))?;
+        self.key
+            .0
+            .verify_strict(signed.message.as_bytes(), &signature)
+            .map_err(|_| anyhow!("not signed by the moderator"))?;
+        let message: ControlMessage =
+            serde_json::from_str(&signed.message).context("malformed control message")?;
+        ensure!(
+            message.session == self.session,
+            "signed for another session (`{}`)",
+            message.session
+        );
+        if let Some(last) = self.last_sequence {
+            ensure!(message.sequence > last, "replayed command");
+        }
+        self.last_sequence = Some(message.sequence);
+        Ok(message.command)
+    }
+}
+
+/// Publish queued frames, repeating the moderator's lock for late joiners.
+pub async fn publish_control(
+    mut track: moq::TrackProducer,
+    channel: ControlChannel,
+    moderator: Option<Moderator>,
+) -> Result<()> {
+    let mut outbox = channel.subscribe();
+    let mut refresh = tokio::time::interval(LOCK_REFRESH);
+    loop {
+        let payload = select! {
+            payload = outbox.recv() => match payload {
+                Ok(payload) => payload,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(missed, "control frames dropped before publishing");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = refresh.tick() => {
+                let Some(moderator) = moderator.as_ref().filter(|m| m.locked.load(Ordering::Relaxed)) else {
+                    continue;
+                };
+                moderator.sign(&ModeratorCommand::LockSession { locked: true, repeat: true })?.encode()?
+            }
+        };
+        write_frame(&mut track, payload);
+    }
+}
+
+/// One frame per group, like the other control tracks.
+pub fn write_frame(track: &mut moq::TrackProducer, payload: Bytes) {
+    let mut group = track.append_group();
+    let mut frame = group.create_frame(moq::Frame {
+        size: payload.len() as u64,
+    });
+    frame.write_chunk(payload);
+    frame.close();
+    group.close();
+}
+
+/// The next well-formed frame on a remote control track, or `None` once it
+/// ends.
+pub async fn next_frame(track: &mut moq::TrackConsumer) -> Result<Option<ControlFrame>> {
+    while let Some(mut group) = next_group(track).await? {
+        let Ok(Some(payload)) = group.read_frame().await else {
+            continue;
+        };
+        match serde_json::from_slice(&payload) {
+            Ok(frame) => return Ok(Some(frame)),
+            Err(err) => warn!("ignoring malformed control frame: {err}"),
+        }
+    }
+    Ok(None)
+}
+
+/// Handles the remote's control track during a call.
+pub struct ControlReader {
+    /// Set to obey the moderator.
+    pub verifier: Option<ControlVerifier>,
+    /// Set to keep answering PIN challenges.
+    pub pin: Option<PinCheck>,
+    /// Answers identity challenges.
+    pub identity: IdentityCheck,
+    pub reply: ControlChannel,
+    /// Our name, as moderator commands address us.
+    pub name: String,
+    pub audio: AudioContext,
+    /// Play a notice when the remote starts recording.
+    pub recording_notice: bool,
+}
+
+impl ControlReader {
+    /// Act on verified commands addressed to us, answer PIN challenges and
+    /// report the remote's microphone trouble, presence and recording.
+    /// Returns an error when the moderator removes us from the session, or
+    /// we joined after it was locked.
+    pub async fn run(mut self, mut track: moq::TrackConsumer) -> Result<()> {
+        let mut seen_lock = false;
+        let mut recorded = false;
+        loop {
+            let frame = match next_frame(&mut track).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                // a remote with nothing to ask of us may not publish the track.
+                Err(err) if self.verifier.is_none() && self.pin.is_none() => {
+                    debug!("no control track from the remote: {err:#}");
+                    break;
+                }
+                Err(err) => return Err(err),
+            };
+            let signed = match frame {
+                ControlFrame::Moderator(signed) => signed,
+                ControlFrame::MicStatus { status } => {
+                    match status {
+                        MicStatus::Ok => info!("the remote's microphone works again"),
+                        status => warn!("the remote's microphone is {status}; they may not know"),
+                    }
+                    continue;
+                }
+                ControlFrame::Presence { presence } => {
+                    match presence {
+                        Presence::Here => info!("the remote is back"),
+                        presence => info!("the remote is {presence}"),
+                    }
+                    self.audio.stats().remote_presence.set(presence.index());
+                    continue;
+                }
+                ControlFrame::Mark { label } => {
+                    info!("the remote marked this moment: {label}");
+                    continue;
+                }
+                ControlFrame::Recording { consent } => {
+                    if !recorded {
+                        recorded = true;
+                        self.recording_started(consent);
+                    }
+                    // repeated until everyone agreed, so a late `consent`
+                    // still gets there.
+                    if consent && self.audio.consents_to_recording() {
+                        self.reply.send(&ControlFrame::RecordingConsent);
+                    }
+                    continue;
+                }
+                frame => {
+                    if let Some(pin) = &self.pin {
+                        pin.handle(&frame, &self.reply);
+                    }
+                    self.identity.handle(&frame, &self.reply);
+                    continue;
+                }
+            };
+            let Some(verifier) = &mut self.verifier else {
+                continue;
+            };
+            let command = match verifier.verify(&signed) {
+                Ok(command) => command,
+                Err(err) => {
+                    warn!("ignoring control message: {err:#}");
+                    continue;
+                }
+            };
+            match command {
+                ModeratorCommand::MuteRequest { target }
+                    if ModeratorCommand::targets(&target, &self.name) =>
+                {
+                    self.audio.set_paused(true);
+                    warn!("the moderator muted you; type `resume` to unmute");
+                }
+                ModeratorCommand::Kick { target } if target == self.name => {
+                    return Err(NeetError::SessionRejected(
+                        "removed from the session by the moderator".to_string(),
+                    )
+                    .into());
+                }
+                ModeratorCommand::LockSession { locked, repeat } => {
+                    if locked && repeat && !seen_lock {
+                        return Err(NeetError::SessionRejected(
+                            "the session is locked by the moderator".to_string(),
+                        )
+                        .into());
+                    }
+                    if !repeat {
+                        info!(locked, "moderator changed the session lock");
+                    }
+                    seen_lock = true;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn recording_started(&self, consent: bool) {
+        warn!("the call is being recorded");
+        if consent && !self.audio.consents_to_recording() {
+            warn!("nobody is recorded until everyone agrees; type `consent` (or press c) to agree");
+        }
+        if self.recording_notice {
+            match self.audio.announcer() {
+                Some(announcer) => announcer.say("this call is being recorded"),
+                None => self.audio.beep(RECORDING_BEEPS),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_fresh_commands_from_the_moderator_pass() {
+        let moderator = Moderator::new(SigningKey::from_bytes(&[7; 32]), "demo");
+        let mut verifier = ControlVerifier::new(moderator.public_key(), "demo");
+        let kick: ModeratorCommand = "kick bob".parse().unwrap();
+        let signed = |moderator: &Moderator| match moderator.sign(&kick).unwrap() {
+            ControlFrame::Moderator(signed) => signed,
+            frame => panic!("unexpected {frame:?}"),
+        };
+        let first = signed(&moderator);
+        assert_eq!(verifier.verify(&first).unwrap(), kick);
+        assert!(verifier.verify(&first).is_err(), "replay");
+
+        let mallory = Moderator::new(SigningKey::from_bytes(&[8; 32]), "demo");
+        assert!(verifier.verify(&signed(&mallory)).is_err());
+        let elsewhere = Moderator::new(SigningKey::from_bytes(&[7; 32]), "other");
+        assert!(verifier.verify(&signed(&elsewhere)).is_err());
+
+        let key = moderator.public_key().to_string();
+        assert_eq!(key.parse::<ModeratorKey>().unwrap(), moderator.public_key());
+        assert!("kick *".parse::<ModeratorCommand>().is_err());
+        assert!("lock now".parse::<ModeratorCommand>().is_err());
+    }
+
+    #[test]
+    fn presence_frames_name_the_state() {
+        let frame = ControlFrame::Presence {
+            presence: Presence::Held,
+        };
+        let encoded = frame.encode().unwrap();
+        assert_eq!(&encoded[..], br#"{"type":"presence","presence":"held"}"#);
+        match serde_json::from_slice(&encoded).unwrap() {
+            ControlFrame::Presence { presence } => {
+                assert_eq!(Presence::from_index(presence.index()), Presence::Held)
+            }
+            frame => panic!("unexpected {frame:?}"),
+        }
+    }
+
+    #[test]
+    fn recording_frames_name_the_consent() {
+        let frame = ControlFrame::Recording { consent: true };
+        let encoded = frame.encode().unwrap();
+        assert_eq!(&encoded[..], br#"{"type":"recording","consent":true}"#);
+        assert!(matches!(
+            serde_json::from_slice(br#"{"type":"recording"}"#).unwrap(),
+            ControlFrame::Recording { consent: false }
+        ));
+        let encoded = ControlFrame::RecordingConsent.encode().unwrap();
+        assert_eq!(&encoded[..], br#"{"type":"recording-consent"}"#);
+    }
+}
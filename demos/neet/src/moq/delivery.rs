@@ -0,0 +1,16 @@
+//! Reliable vs. lowest-latency frame delivery.
+//!
+//! moq-lite 0.7 only carries data on QUIC streams, so there is no true datagram
+//! path yet. `Datagram` gets as close as the transport allows: one group (and so
+//! one stream) per frame, groups read concurrently so a stalled stream never
+//! holds back newer audio, and frames that arrive after a newer one are dropped
+//! by sequence number instead of being played late.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Delivery {
+    /// Read groups in order; every frame that arrives is played.
+    #[default]
+    Reliable,
+    /// Favor latency: never wait for an older frame, drop it if it is late.
+    Datagram,
+}
@@ -0,0 +1,111 @@
+//! Calls straight between two peers, without a relay: one side accepts QUIC
+//! connections on an embedded MoQ endpoint, the other dials its address and
+//! the moq-lite session runs over that connection.
+//!
+//! The endpoint has a self-signed certificate and the dialing side does not
+//! verify it, so a direct call is only as private as the network it runs on;
+//! `--pin` still checks who is on the other end.
+
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::Mutex;
+use tracing::info;
+use url::Url;
+
+use super::transport::{Opening, SharedClient, Side, Transport, TransportOptions};
+
+/// Dial a peer's endpoint.
+#[derive(Debug, Clone)]
+pub struct DialTransport {
+    addr: SocketAddr,
+    options: TransportOptions,
+    client: SharedClient,
+}
+
+impl DialTransport {
+    pub fn new(addr: SocketAddr, options: TransportOptions) -> Self {
+        Self {
+            addr,
+            options,
+            client: SharedClient::default(),
+        }
+    }
+}
+
+impl Transport for DialTransport {
+    fn open(&self) -> Opening<'_> {
+        Box::pin(async move {
+            let addr = self.addr;
+            info!(%addr, "dialing peer");
+            let client = self.client.get(|| {
+                let mut config = self.options.client_config()?;
+                // the peer's certificate is self-signed.
+                config.tls.disable_verify = Some(true);
+                Ok(config)
+            })?;
+            let url = Url::parse(&format!("https://{addr}/"))?;
+            let connection = client
+                .connect(url)
+                .await
+                .with_context(|| format!("failed to connect to peer at {addr}"))?;
+            Ok((connection, Side::Client))
+        })
+    }
+
+    fn target(&self) -> String {
+        format!("peer at {}", self.addr)
+    }
+}
+
+/// A QUIC endpoint that waits for the peer to dial it.
+#[derive(derive_more::Debug)]
+pub struct Endpoint {
+    #[debug(skip)]
+    server: Mutex<moq_native::Server>,
+    addr: SocketAddr,
+}
+
+impl Endpoint {
+    /// Listen on `bind` with a certificate generated for `host`.
+    pub fn bind(bind: SocketAddr, host: &str) -> Result<Self> {
+        let config = moq_native::ServerConfig {
+            bind: Some(bind),
+            tls: moq_native::ServerTlsConfig {
+                generate: vec![host.to_string()],
+                ..Default::default()
+            },
+        };
+        let server = moq_native::Server::new(config).context("failed to start a QUIC endpoint")?;
+        let addr = server.local_addr()?;
+        Ok(Self {
+            server: Mutex::new(server),
+            addr,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Transport for Endpoint {
+    fn open(&self) -> Opening<'_> {
+        Box::pin(async move {
+            info!(addr = %self.addr, "waiting for the peer to connect");
+            let request = self
+                .server
+                .lock()
+                .await
+                .accept()
+                .await
+                .ok_or_else(|| anyhow!("QUIC endpoint closed"))?;
+            let connection = request.ok().await.context("failed to accept peer")?;
+            Ok((connection, Side::Server))
+        })
+    }
+
+    fn target(&self) -> String {
+        format!("peers dialing {}", self.addr)
+    }
+}
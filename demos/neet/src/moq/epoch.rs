@@ -0,0 +1,148 @@
+//! Call epochs: the wall-clock instant a publisher's frame timestamps count
+//! from.
+//!
+//! A publisher picks its epoch when it starts publishing and lists it in its
+//! catalog, in microseconds since the Unix epoch. Every media frame is then
+//! stamped with the capture time of its first sample, counted in samples
+//! from there, so all tracks of a broadcast share one timeline and the
+//! remote can place every frame on its own wall clock. With both clocks
+//! synchronized, e.g. by NTP, that lines up participants for mixing and A/V
+//! sync, and shows how long audio took from one microphone to the other
+//! side.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::frame::TIMESTAMP_RATE;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CallEpoch {
+    wall: SystemTime,
+    /// `wall` on the monotonic clock, for stamping.
+    start: Instant,
+}
+
+impl CallEpoch {
+    /// An epoch starting now.
+    pub fn now() -> Self {
+        Self {
+            wall: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+
+    /// The epoch a remote announced, in microseconds since the Unix epoch.
+    pub fn from_unix_us(unix_us: u64) -> Self {
+        let wall = UNIX_EPOCH + Duration::from_micros(unix_us);
+        let ago = SystemTime::now().duration_since(wall).unwrap_or_default();
+        let now = Instant::now();
+        Self {
+            wall,
+            start: now.checked_sub(ago).unwrap_or(now),
+        }
+    }
+
+    /// An epoch that began at `start` on the monotonic clock, such as one a
+    /// trace recorded, placed on a replay's clock.
+    pub fn from_instant(start: Instant) -> Self {
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let wall = match now.checked_duration_since(start) {
+            Some(ago) => wall - ago,
+            None => wall + start.duration_since(now),
+        };
+        Self { wall, start }
+    }
+
+    /// `wall` on the monotonic clock.
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    pub fn unix_us(&self) -> u64 {
+        self.wall
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64
+    }
+
+    /// The timestamp of `at`.
+    pub fn timestamp(&self, at: Instant) -> u32 {
+        to_ticks(at.saturating_duration_since(self.start)) as u32
+    }
+
+    /// How long before `at` a frame stamped `timestamp` against this epoch
+    /// was captured; `None` if after it, i.e. the clocks disagree by more
+    /// than the delay.
+    pub fn age(&self, timestamp: u32, at: Instant) -> Option<Duration> {
+        let captured = self.start + from_ticks(timestamp as u64);
+        at.checked_duration_since(captured)
+    }
+}
+
+/// Stamps consecutive frames of one track by counting their samples, which
+/// unlike the time they reach us does not jitter.
+#[derive(Debug)]
+pub struct MediaClock {
+    epoch: CallEpoch,
+    /// The timestamp of the next frame, unless it has to be taken from the
+    /// clock again.
+    next: Option<u32>,
+}
+
+impl MediaClock {
+    pub fn new(epoch: CallEpoch) -> Self {
+        Self { epoch, next: None }
+    }
+
+    /// The timestamp of a frame of `duration` that was just captured.
+    pub fn stamp(&mut self, duration: Duration) -> u32 {
+        let timestamp = self.next.unwrap_or_else(|| {
+            let captured = Instant::now()
+                .checked_sub(duration)
+                .unwrap_or(self.epoch.start);
+            self.epoch.timestamp(captured)
+        });
+        self.next = Some(timestamp.wrapping_add(to_ticks(duration) as u32));
+        timestamp
+    }
+
+    /// Take the next timestamp from the clock again, after a gap in the
+    /// frames such as a pause.
+    pub fn resync(&mut self) {
+        self.next = None;
+    }
+}
+
+fn to_ticks(duration: Duration) -> u64 {
+    (duration.as_micros() * TIMESTAMP_RATE as u128 / 1_000_000) as u64
+}
+
+fn from_ticks(ticks: u64) -> Duration {
+    Duration::from_micros(ticks * 1_000_000 / TIMESTAMP_RATE as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_stamped_by_their_samples_until_a_gap() {
+        let epoch = CallEpoch::now();
+        let mut clock = MediaClock::new(epoch);
+        let frame = Duration::from_millis(20);
+        std::thread::sleep(Duration::from_millis(50));
+        let first = clock.stamp(frame);
+        // captured 30 ms after the epoch, give or take oversleeping.
+        assert!((1_400..=2_400).contains(&first), "{first}");
+        assert_eq!(clock.stamp(frame), first + 960);
+
+        clock.resync();
+        std::thread::sleep(Duration::from_millis(50));
+        let after_gap = clock.stamp(frame);
+        assert!(after_gap > first + 2 * 960, "{after_gap}");
+
+        let remote = CallEpoch::from_unix_us(epoch.unix_us());
+        let age = remote.age(first, Instant::now()).unwrap();
+        assert!(age >= Duration::from_millis(60), "{age:?}");
+        assert!(remote.age(after_gap + 48_000, Instant::now()).is_none());
+    }
+}
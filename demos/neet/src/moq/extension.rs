@@ -0,0 +1,176 @@
+//! Per-frame metadata in the frame header's extension area, see
+//! [`super::frame`]: encryption nonces, audio levels (in the spirit of RFC
+//! 6464), redundancy descriptors and the like.
+//!
+//! Each extension has an id of its own. A publisher's [`ExtensionEncoder`]s
+//! add an entry to any frame they have something to say about, and a
+//! receiver hands each entry to the [`ExtensionDecoder`] registered for its
+//! id, skipping entries it knows nothing about; that is what keeps peers
+//! without a new extension working. Peers from before the extension area
+//! would take it for payload, so nothing is sent while no encoder is
+//! registered.
+
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use tracing::{trace, warn};
+
+use super::frame::{FrameHeader, MAX_EXTENSIONS_LEN};
+
+/// Adds an extension to outgoing frames. Shared by every track a call
+/// publishes.
+pub trait ExtensionEncoder: Send + Sync + 'static {
+    fn id(&self) -> u8;
+    /// The value for the frame with `header` and `payload`, if any; at most
+    /// 255 bytes.
+    fn encode(&self, header: &FrameHeader, payload: &[u8]) -> Option<Bytes>;
+}
+
+/// Reads an extension of incoming frames.
+pub trait ExtensionDecoder: Send + Sync + 'static {
+    fn id(&self) -> u8;
+    /// Take the value the frame with `header` carries.
+    fn decode(&self, header: &FrameHeader, value: &[u8]) -> Result<()>;
+}
+
+/// The extensions a call sends and understands.
+#[derive(derive_more::Debug, Clone, Default)]
+pub struct FrameExtensions {
+    #[debug(skip)]
+    encoders: Vec<Arc<dyn ExtensionEncoder>>,
+    #[debug(skip)]
+    decoders: Vec<Arc<dyn ExtensionDecoder>>,
+}
+
+impl FrameExtensions {
+    pub fn with_encoder(mut self, encoder: impl ExtensionEncoder) -> Self {
+        self.encoders.push(Arc::new(encoder));
+        self
+    }
+
+    pub fn with_decoder(mut self, decoder: impl ExtensionDecoder) -> Self {
+        self.decoders.push(Arc::new(decoder));
+        self
+    }
+
+    /// The extension area for the frame with `header` and `payload`; empty if
+    /// no encoder has anything to add. Entries that do not fit are left out.
+    pub fn encode(&self, header: &FrameHeader, payload: &[u8]) -> Bytes {
+        if self.encoders.is_empty() {
+            return Bytes::new();
+        }
+        let mut area = BytesMut::new();
+        for encoder in &self.encoders {
+            let Some(value) = encoder.encode(header, payload) else {
+                continue;
+            };
+            if value.len() > u8::MAX as usize || area.len() + 2 + value.len() > MAX_EXTENSIONS_LEN {
+                warn!(
+                    id = encoder.id(),
+                    len = value.len(),
+                    "frame extension does not fit"
+                );
+                continue;
+            }
+            area.put_u8(encoder.id());
+            area.put_u8(value.len() as u8);
+            area.put_slice(&value);
+        }
+        area.freeze()
+    }
+
+    /// Hand each entry of the extension area of `header` to its decoder.
+    pub fn decode(&self, header: &FrameHeader) -> Result<()> {
+        for (id, value) in entries(&header.extensions)? {
+            match self.decoders.iter().find(|decoder| decoder.id() == id) {
+                Some(decoder) => decoder.decode(header, value)?,
+                None => trace!(id, "skipping unknown frame extension"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The id and value of each entry in an extension area.
+fn entries(mut area: &[u8]) -> Result<Vec<(u8, &[u8])>> {
+    let mut entries = Vec::new();
+    while let [id, len, rest @ ..] = area {
+        let len = *len as usize;
+        ensure!(rest.len() >= len, "truncated frame extension {id}");
+        entries.push((*id, &rest[..len]));
+        area = &rest[len..];
+    }
+    ensure!(area.is_empty(), "truncated frame extension area");
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct Level;
+
+    impl ExtensionEncoder for Level {
+        fn id(&self) -> u8 {
+            1
+        }
+
+        fn encode(&self, header: &FrameHeader, _payload: &[u8]) -> Option<Bytes> {
+            (header.sequence != 1).then(|| Bytes::from_static(&[0x85]))
+        }
+    }
+
+    struct Nonce;
+
+    impl ExtensionEncoder for Nonce {
+        fn id(&self) -> u8 {
+            7
+        }
+
+        fn encode(&self, _header: &FrameHeader, _payload: &[u8]) -> Option<Bytes> {
+            Some(Bytes::from_static(&[1, 2, 3, 4]))
+        }
+    }
+
+    #[derive(Default)]
+    struct Levels(Mutex<Vec<u8>>);
+
+    impl ExtensionDecoder for Arc<Levels> {
+        fn id(&self) -> u8 {
+            1
+        }
+
+        fn decode(&self, _header: &FrameHeader, value: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().extend_from_slice(value);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn decodes_known_extensions_and_skips_the_rest() {
+        let sender = FrameExtensions::default()
+            .with_encoder(Level)
+            .with_encoder(Nonce);
+        let levels = Arc::new(Levels::default());
+        let receiver = FrameExtensions::default().with_decoder(levels.clone());
+
+        for sequence in 0..3 {
+            let header = FrameHeader::new(sequence);
+            let area = sender.encode(&header, b"opus");
+            let frame = header.with_extensions(area).encode(b"opus");
+            let (header, payload) = FrameHeader::decode(frame).unwrap();
+            assert_eq!(&payload[..], b"opus");
+            receiver.decode(&header).unwrap();
+        }
+        assert_eq!(*levels.0.lock().unwrap(), [0x85, 0x85]);
+
+        assert!(FrameExtensions::default()
+            .encode(&FrameHeader::new(0), b"")
+            .is_empty());
+        assert!(entries(&[1, 2, 0]).is_err());
+        assert!(entries(&[1]).is_err());
+    }
+}
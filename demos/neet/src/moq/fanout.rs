@@ -0,0 +1,113 @@
+//! Publishing to more relays than the one the call runs on (`--fanout`), so
+//! that listeners on other relay deployments can subscribe there too.
+//!
+//! Each fan-out relay has its own connection, reconnected with backoff when it
+//! drops, independently of the call and of the other relays. Whatever we
+//! currently publish on the call's relay is republished on each of them.
+
+use std::sync::Arc;
+
+use moq_lite as moq;
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{info, warn};
+
+use super::{
+    connect, retry::Backoff, transport::Transport, MAX_RECONNECT_BACKOFF, RECONNECT_BACKOFF,
+};
+use crate::stats::{ConnectionSnapshot, ConnectionStats, Counter, Gauge};
+
+/// Our broadcast and where it is published.
+#[derive(Clone)]
+pub struct Published {
+    pub path: String,
+    pub broadcast: moq_lite::BroadcastConsumer,
+}
+
+/// How one fan-out relay is doing.
+#[derive(Debug, Clone)]
+pub struct RelayHealth {
+    pub target: String,
+    /// 1 while connected.
+    connected: Gauge,
+    /// Connections that failed or dropped.
+    failures: Counter,
+    connection: ConnectionStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayHealthSnapshot {
+    pub connected: bool,
+    pub failures: u64,
+    pub connection: ConnectionSnapshot,
+}
+
+impl RelayHealth {
+    pub fn snapshot(&self) -> RelayHealthSnapshot {
+        RelayHealthSnapshot {
+            connected: self.connected.get() == 1,
+            failures: self.failures.get(),
+            connection: self.connection.snapshot(),
+        }
+    }
+}
+
+/// Keep republishing whatever `published` holds on `route` until aborted.
+pub fn spawn_fanout(
+    route: Arc<dyn Transport>,
+    published: watch::Receiver<Option<Published>>,
+) -> (JoinHandle<()>, RelayHealth) {
+    let health = RelayHealth {
+        target: route.target(),
+        connected: Gauge::default(),
+        failures: Counter::default(),
+        connection: ConnectionStats::default(),
+    };
+    let task = tokio::spawn(fan_out(route, published, health.clone()));
+    (task, health)
+}
+
+async fn fan_out(
+    route: Arc<dyn Transport>,
+    mut published: watch::Receiver<Option<Published>>,
+    health: RelayHealth,
+) {
+    let relay = &health.target;
+    let mut backoff = Backoff::new(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF);
+    loop {
+        match connect(&*route, health.connection.clone()).await {
+            Ok(session) => {
+                info!(relay, "fan-out relay connected");
+                health.connected.set(1);
+                backoff.reset();
+                let err = loop {
+                    if let Some(current) = &*published.borrow_and_update() {
+                        session
+                            .publish
+                            .publish_broadcast(&current.path, current.broadcast.clone());
+                    }
+                    tokio::select! {
+                        err = session.session.closed() => break err,
+                        changed = published.changed() => {
+                            if changed.is_err() {
+                                // the call is over.
+                                session.sampler.abort();
+                                session.session.close(moq::Error::Cancel);
+                                return;
+                            }
+                        }
+                    }
+                };
+                session.sampler.abort();
+                health.connected.set(0);
+                warn!(relay, "fan-out relay dropped: {err}");
+            }
+            Err(err) => warn!(relay, "fan-out relay unreachable: {err:#}"),
+        }
+        health.failures.add(1);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff.next_delay()) => {}
+            Err(_) = published.changed() => return,
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Checking who the remote is (`--fingerprint`, or a contact's).
+//!
+//! Like the PIN handshake in [`super::pin`], each side that wants to know
+//! repeats a random challenge on its control track. The remote answers with
+//! its identity key and a signature over the challenge, the session and the
+//! path it publishes at, so that a proof cannot be replayed elsewhere. The key
+//! must sign correctly and hash to the expected fingerprint. Every side with
+//! an identity key answers, whether or not it checks the remote in turn.
+
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use tracing::warn;
+
+use super::{
+    control::{ControlChannel, ControlFrame},
+    pin::Verdict,
+};
+use crate::identity::Fingerprint;
+
+const NONCE_BYTES: usize = 16;
+
+/// One side of the identity handshake with one peer.
+#[derive(Debug, Clone)]
+pub struct IdentityCheck {
+    /// Ours, to answer the peer's challenges with.
+    key: Option<SigningKey>,
+    /// Whom the peer must be, if we check.
+    expected: Option<Fingerprint>,
+    session: String,
+    /// Where we publish, i.e. who we prove to be.
+    local_path: String,
+    /// Where the peer publishes.
+    remote_path: String,
+    /// Our challenge to this peer.
+    nonce: String,
+}
+
+impl IdentityCheck {
+    pub fn new(
+        key: Option<SigningKey>,
+        expected: Option<Fingerprint>,
+        session: impl Into<String>,
+        local_path: impl Into<String>,
+        remote_path: impl Into<String>,
+    ) -> Result<Self> {
+        let mut nonce = [0; NONCE_BYTES];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| anyhow!("no randomness for a nonce: {err}"))?;
+        Ok(Self {
+            key,
+            expected,
+            session: session.into(),
+            local_path: local_path.into(),
+            remote_path: remote_path.into(),
+            nonce: hex::encode(nonce),
+        })
+    }
+
+    /// Whether the peer must prove who it is.
+    pub fn expects(&self) -> bool {
+        self.expected.is_some()
+    }
+
+    pub fn challenge(&self) -> ControlFrame {
+        ControlFrame::IdentityChallenge {
+            nonce: self.nonce.clone(),
+        }
+    }
+
+    /// What `prover` signs to answer `nonce`.
+    fn message(&self, nonce: &str, prover: &str) -> Vec<u8> {
+        let mut message = b"neet identity\0".to_vec();
+        for part in [&self.session, prover, nonce] {
+            message.extend_from_slice(part.as_bytes());
+            message.push(0);
+        }
+        message
+    }
+
+    /// Answer the peer's challenges and check its answers to ours.
+    pub fn handle(&self, frame: &ControlFrame, reply: &ControlChannel) -> Option<Verdict> {
+        match frame {
+            ControlFrame::IdentityChallenge { nonce } => {
+                if let Some(key) = &self.key {
+                    let signature = key.sign(&self.message(nonce, &self.local_path));
+                    reply.send(&ControlFrame::IdentityProof {
+                        nonce: nonce.clone(),
+                        key: hex::encode(key.verifying_key().as_bytes()),
+                        signature: hex::encode(signature.to_bytes()),
+                    });
+                }
+                None
+            }
+            ControlFrame::IdentityProof { nonce, .. } if *nonce != self.nonce => None,
+            ControlFrame::IdentityProof { key, signature, .. } => {
+                let expected = self.expected?;
+                let Some((key, signature)) = decode(key, signature) else {
+                    warn!("the remote sent a malformed identity proof");
+                    return Some(Verdict::Failed);
+                };
+                let message = self.message(&self.nonce, &self.remote_path);
+                if key.verify(&message, &signature).is_err() {
+                    warn!("the remote's identity proof does not verify");
+                    return Some(Verdict::Failed);
+                }
+                let fingerprint = Fingerprint::of(&key);
+                if fingerprint != expected {
+                    warn!(%fingerprint, %expected, "the remote is not who we expected");
+                    return Some(Verdict::Failed);
+                }
+                Some(Verdict::Passed)
+            }
+            ControlFrame::Moderator(_)
+            | ControlFrame::PinChallenge { .. }
+            | ControlFrame::PinResponse { .. }
+            | ControlFrame::MicStatus { .. }
+            | ControlFrame::Presence { .. }
+            | ControlFrame::Mark { .. }
+            | ControlFrame::Recording { .. }
+            | ControlFrame::RecordingConsent => None,
+        }
+    }
+}
+
+fn decode(key: &str, signature: &str) -> Option<(VerifyingKey, Signature)> {
+    let key: [u8; 32] = hex::decode(key).ok()?.try_into().ok()?;
+    let signature: [u8; 64] = hex::decode(signature).ok()?.try_into().ok()?;
+    Some((
+        VerifyingKey::from_bytes(&key).ok()?,
+        Signature::from_bytes(&signature),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_expected_identity_passes() {
+        let alice = SigningKey::from_bytes(&[1; 32]);
+        let mallory = SigningKey::from_bytes(&[2; 32]);
+        let expected = Some(Fingerprint::of(&alice.verifying_key()));
+        let caller = IdentityCheck::new(None, expected, "demo", "caller", "listener").unwrap();
+        let to_caller = ControlChannel::default();
+        let mut answers = to_caller.subscribe();
+
+        let mut answer = |key: &SigningKey, path: &str| {
+            let listener =
+                IdentityCheck::new(Some(key.clone()), None, "demo", path, "caller").unwrap();
+            listener.handle(&caller.challenge(), &to_caller);
+            serde_json::from_slice(&answers.try_recv().unwrap()).unwrap()
+        };
+        let proof = answer(&alice, "listener");
+        assert_eq!(caller.handle(&proof, &to_caller), Some(Verdict::Passed));
+        let impostor = answer(&mallory, "listener");
+        assert_eq!(caller.handle(&impostor, &to_caller), Some(Verdict::Failed));
+        // alice's proof for another path is no proof for this one.
+        let elsewhere = answer(&alice, "bridge");
+        assert_eq!(caller.handle(&elsewhere, &to_caller), Some(Verdict::Failed));
+
+        // without a key of our own there is nothing to answer with.
+        caller.handle(&caller.challenge(), &to_caller);
+        assert!(answers.try_recv().is_err());
+    }
+}
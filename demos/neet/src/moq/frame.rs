@@ -0,0 +1,351 @@
+//! Wire header prepended to every media frame on a MoQ track.
+//!
+//! ```text
+//! 0       1       2               6
+//! +-------+-------+---------------+---------
+//! |version| flags |  sequence BE  | payload…
+//! +-------+-------+---------------+---------
+//! ```
+//!
+//! The sequence number increments by one per frame (wrapping) so receivers can
+//! detect loss and reordering independently of how frames are grouped.
+//!
+//! Media frames with [`FLAG_TIMESTAMP`] carry a capture timestamp between the
+//! sequence number and the payload:
+//!
+//! ```text
+//! 6               10
+//! +---------------+---------
+//! | timestamp BE  | payload…
+//! +---------------+---------
+//! ```
+//!
+//! It counts [`TIMESTAMP_RATE`] ticks since the publisher's call epoch, see
+//! [`super::epoch`], and wraps after about a day.
+//!
+//! Frames with [`FLAG_EXTENSIONS`] carry an extension area next, before the
+//! payload: its length, then entries of an id, a length and a value each.
+//!
+//! ```text
+//! +-------+----+-----+--------+----+-----+--------+---------
+//! |  len  | id | len | value… | id | len | value… | payload…
+//! +-------+----+-----+--------+----+-----+--------+---------
+//! ```
+//!
+//! Receivers skip the entries they have no decoder for, so new extensions,
+//! see [`super::extension`], need no new header version.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+pub const HEADER_VERSION: u8 = 1;
+/// Length of a header without a timestamp.
+pub const HEADER_LEN: usize = 6;
+const TIMESTAMP_LEN: usize = 4;
+/// Bytes of wire frames cut from one allocation, see [`FrameArena`]. Kept
+/// below 64 KiB so that `BytesMut` remembers it when it reallocates.
+const ARENA_LEN: usize = 16 * 1024;
+/// Timestamp ticks per second, one per sample at 48 kHz.
+pub const TIMESTAMP_RATE: u32 = 48_000;
+
+/// The payload starts with copies of earlier frames, see [`super::redundancy`].
+pub const FLAG_REDUNDANT: u8 = 0x01;
+/// An empty frame marking that the publisher paused; nothing follows until it
+/// resumes. Carries the sequence number the next frame will have.
+pub const FLAG_PAUSED: u8 = 0x02;
+/// An empty frame marking that the publisher hung up; the track ends after it.
+pub const FLAG_END: u8 = 0x04;
+/// An empty frame marking that the publisher started a new stream, e.g. after
+/// a restart: sequence numbers start over from the one it carries, and nothing
+/// from before it, decoder state included, carries over.
+pub const FLAG_RESET: u8 = 0x08;
+/// A capture timestamp follows the sequence number. Set from
+/// [`FrameHeader::timestamp`] when encoding, and cleared when decoding.
+pub const FLAG_TIMESTAMP: u8 = 0x10;
+/// An extension area follows the timestamp. Set from
+/// [`FrameHeader::extensions`] when encoding, and cleared when decoding.
+pub const FLAG_EXTENSIONS: u8 = 0x20;
+/// The longest extension area, entries included.
+pub const MAX_EXTENSIONS_LEN: usize = u8::MAX as usize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FrameHeader {
+    pub flags: u8,
+    pub sequence: u32,
+    pub timestamp: Option<u32>,
+    /// The entries of the extension area, empty for none.
+    pub extensions: Bytes,
+}
+
+impl FrameHeader {
+    pub fn new(sequence: u32) -> Self {
+        Self {
+            flags: 0,
+            sequence,
+            timestamp: None,
+            extensions: Bytes::new(),
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u32) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Carry the extension area `extensions`, at most
+    /// [`MAX_EXTENSIONS_LEN`] long.
+    pub fn with_extensions(mut self, extensions: Bytes) -> Self {
+        debug_assert!(extensions.len() <= MAX_EXTENSIONS_LEN);
+        self.extensions = extensions;
+        self
+    }
+
+    /// Encoded length of the header.
+    pub fn encoded_len(&self) -> usize {
+        let timestamp = match self.timestamp {
+            Some(_) => TIMESTAMP_LEN,
+            None => 0,
+        };
+        let extensions = match self.extensions.len() {
+            0 => 0,
+            len => 1 + len,
+        };
+        HEADER_LEN + timestamp + extensions
+    }
+
+    /// Prepend the header to `payload`.
+    pub fn encode(&self, payload: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len() + payload.len());
+        self.put(&mut buf);
+        buf.put_slice(payload);
+        buf.freeze()
+    }
+
+    /// Write just the header, for callers assembling the payload themselves.
+    pub fn put(&self, buf: &mut impl BufMut) {
+        buf.put_u8(HEADER_VERSION);
+        let mut flags = self.flags & !(FLAG_TIMESTAMP | FLAG_EXTENSIONS);
+        if self.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        if !self.extensions.is_empty() {
+            flags |= FLAG_EXTENSIONS;
+        }
+        buf.put_u8(flags);
+        buf.put_u32(self.sequence);
+        if let Some(timestamp) = self.timestamp {
+            buf.put_u32(timestamp);
+        }
+        if !self.extensions.is_empty() {
+            buf.put_u8(self.extensions.len() as u8);
+            buf.put_slice(&self.extensions);
+        }
+    }
+
+    /// Split a received frame into its header and payload without copying.
+    pub fn decode(frame: Bytes) -> Result<(Self, Bytes)> {
+        if frame.len() < HEADER_LEN {
+            bail!("frame too short for header: {} bytes", frame.len());
+        }
+        if frame[0] != HEADER_VERSION {
+            bail!("unsupported frame header version {}", frame[0]);
+        }
+        let flags = frame[1];
+        let mut header = Self {
+            flags: flags & !(FLAG_TIMESTAMP | FLAG_EXTENSIONS),
+            sequence: u32::from_be_bytes([frame[2], frame[3], frame[4], frame[5]]),
+            timestamp: None,
+            extensions: Bytes::new(),
+        };
+        if flags & FLAG_TIMESTAMP != 0 {
+            if frame.len() < HEADER_LEN + TIMESTAMP_LEN {
+                bail!("frame too short for timestamp: {} bytes", frame.len());
+            }
+            header.timestamp = Some(u32::from_be_bytes([frame[6], frame[7], frame[8], frame[9]]));
+        }
+        if flags & FLAG_EXTENSIONS != 0 {
+            let start = header.encoded_len();
+            ensure!(
+                frame.len() > start,
+                "frame too short for extensions: {} bytes",
+                frame.len()
+            );
+            let end = start + 1 + frame[start] as usize;
+            ensure!(
+                frame.len() >= end,
+                "frame too short for extensions: {} bytes",
+                frame.len()
+            );
+            header.extensions = frame.slice(start + 1..end);
+        }
+        let len = header.encoded_len();
+        Ok((header, frame.slice(len..)))
+    }
+}
+
+/// Builds wire frames as views into one shared allocation instead of a
+/// buffer each. Once every frame cut from it has been dropped downstream,
+/// `BytesMut::reserve` takes the allocation back rather than allocating anew.
+#[derive(Debug)]
+pub struct FrameArena {
+    buf: BytesMut,
+}
+
+impl Default for FrameArena {
+    fn default() -> Self {
+        Self {
+            buf: BytesMut::with_capacity(ARENA_LEN),
+        }
+    }
+}
+
+impl FrameArena {
+    /// Prepend `header` to `payload`, as [`FrameHeader::encode`] does.
+    pub fn encode(&mut self, header: &FrameHeader, payload: &[u8]) -> Bytes {
+        self.build(header.encoded_len() + payload.len(), |buf| {
+            header.put(buf);
+            buf.put_slice(payload);
+        })
+    }
+
+    /// A frame of `len` bytes, which `write` writes.
+    pub fn build(&mut self, len: usize, write: impl FnOnce(&mut BytesMut)) -> Bytes {
+        self.buf.reserve(len);
+        write(&mut self.buf);
+        debug_assert_eq!(self.buf.len(), len);
+        self.buf.split().freeze()
+    }
+}
+
+/// How a received sequence number relates to what came before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// Newer than anything seen so far; `lost` frames were skipped in between.
+    Next { lost: u32 },
+    /// Older than (or equal to) a frame already delivered.
+    Late,
+}
+
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last: Option<u32>,
+}
+
+impl SequenceTracker {
+    pub fn observe(&mut self, sequence: u32) -> Arrival {
+        let Some(last) = self.last else {
+            self.last = Some(sequence);
+            return Arrival::Next { lost: 0 };
+        };
+        // wrapping distance, interpreted as signed so that wraparound works.
+        let delta = sequence.wrapping_sub(last) as i32;
+        if delta <= 0 {
+            return Arrival::Late;
+        }
+        self.last = Some(sequence);
+        Arrival::Next {
+            lost: delta as u32 - 1,
+        }
+    }
+
+    /// Forget the previous sequence, e.g. when switching to another track.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// RFC 3550 style interarrival jitter, assuming frames are sent at a fixed
+/// cadence of `frame_duration`.
+#[derive(Debug)]
+pub struct JitterEstimator {
+    frame_duration: Duration,
+    last: Option<(Instant, u32)>,
+    jitter: f64,
+}
+
+impl JitterEstimator {
+    pub fn new(frame_duration: Duration) -> Self {
+        Self {
+            frame_duration,
+            last: None,
+            jitter: 0.,
+        }
+    }
+
+    /// Account for a frame that arrived at `arrival` and return the smoothed
+    /// jitter so far.
+    pub fn observe(&mut self, sequence: u32, arrival: Instant) -> Duration {
+        if let Some((last_arrival, last_sequence)) = self.last {
+            let frames = sequence.wrapping_sub(last_sequence) as i32 as f64;
+            let sent = frames * self.frame_duration.as_secs_f64();
+            let arrived = arrival.duration_since(last_arrival).as_secs_f64();
+            let deviation = (arrived - sent).abs();
+            self.jitter += (deviation - self.jitter) / 16.;
+        }
+        self.last = Some((arrival, sequence));
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    /// Start a new measurement, keeping the smoothed jitter.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arena_frames_share_an_allocation() {
+        let mut arena = FrameArena::default();
+        let header = FrameHeader::new(7).with_timestamp(480);
+        let first = arena.encode(&header, &[1; 100]);
+        assert_eq!(first, header.encode(&[1; 100]));
+        let base = first.as_ptr() as usize;
+        drop(first);
+        // frames dropped as they go leave the arena to be taken back.
+        for _ in 0..2 * ARENA_LEN / 110 {
+            let frame = arena.encode(&header, &[2; 100]);
+            let at = frame.as_ptr() as usize;
+            assert!((base..base + ARENA_LEN).contains(&at));
+        }
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let header = FrameHeader::new(0xdead_beef);
+        let (decoded, payload) = FrameHeader::decode(header.encode(b"opus")).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(&payload[..], b"opus");
+        assert!(FrameHeader::decode(Bytes::from_static(b"\x01")).is_err());
+
+        let header = header.with_timestamp(48_000);
+        let frame = header.encode(b"opus");
+        assert_eq!(frame.len(), 14);
+        let (decoded, payload) = FrameHeader::decode(frame.clone()).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(&payload[..], b"opus");
+        assert!(FrameHeader::decode(frame.slice(..8)).is_err());
+
+        let header = header.with_extensions(Bytes::from_static(b"\x01\x01\x7f"));
+        let frame = header.encode(b"opus");
+        assert_eq!(frame.len(), 18);
+        let (decoded, payload) = FrameHeader::decode(frame.clone()).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(&payload[..], b"opus");
+        assert!(FrameHeader::decode(frame.slice(..12)).is_err());
+    }
+
+    #[test]
+    fn tracker_reports_loss_and_late_frames_across_wraparound() {
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.observe(u32::MAX - 1), Arrival::Next { lost: 0 });
+        assert_eq!(tracker.observe(1), Arrival::Next { lost: 2 });
+        assert_eq!(tracker.observe(u32::MAX), Arrival::Late);
+        assert_eq!(tracker.observe(1), Arrival::Late);
+        assert_eq!(tracker.observe(2), Arrival::Next { lost: 0 });
+    }
+}
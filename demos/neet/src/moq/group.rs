@@ -0,0 +1,81 @@
+//! How published frames are batched into MoQ groups.
+//!
+//! A group is the unit the relay can drop or deliver independently, so one group
+//! per frame gives the finest loss granularity at the cost of per-group overhead
+//! (a new QUIC stream each time). Batching trades some of that granularity for
+//! fewer streams.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Context};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupStrategy {
+    /// Every frame starts a new group.
+    #[default]
+    PerFrame,
+    /// Start a new group every N frames.
+    Frames(u32),
+    /// Start a new group once the current one covers at least this much audio.
+    Duration(Duration),
+}
+
+impl FromStr for GroupStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "frame" {
+            return Ok(Self::PerFrame);
+        }
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expected frame, frames:<n> or ms:<n>, got `{s}`"))?;
+        let value: u32 = value
+            .parse()
+            .with_context(|| format!("invalid group size `{value}`"))?;
+        if value == 0 {
+            return Err(anyhow!("group size must be non-zero"));
+        }
+        match kind {
+            "frames" => Ok(Self::Frames(value)),
+            "ms" => Ok(Self::Duration(Duration::from_millis(value as u64))),
+            _ => Err(anyhow!("unknown group strategy `{kind}`")),
+        }
+    }
+}
+
+/// Tracks the open group and decides when the next frame needs a new one.
+#[derive(Debug)]
+pub struct GroupBatcher {
+    strategy: GroupStrategy,
+    frames: u32,
+    duration: Duration,
+}
+
+impl GroupBatcher {
+    pub fn new(strategy: GroupStrategy) -> Self {
+        Self {
+            strategy,
+            frames: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    /// Account for a frame of `duration` and return whether it must start a new
+    /// group. The first frame always does.
+    pub fn push(&mut self, duration: Duration) -> bool {
+        let new_group = self.frames == 0
+            || match self.strategy {
+                GroupStrategy::PerFrame => true,
+                GroupStrategy::Frames(n) => self.frames >= n,
+                GroupStrategy::Duration(max) => self.duration >= max,
+            };
+        if new_group {
+            self.frames = 0;
+            self.duration = Duration::ZERO;
+        }
+        self.frames += 1;
+        self.duration += duration;
+        new_group
+    }
+}
@@ -0,0 +1,74 @@
+//! Liveness heartbeats.
+//!
+//! A relay keeps a broadcast announced until the publisher's connection times
+//! out, which can take a while after its process died. Each peer therefore
+//! publishes a `heartbeat` track next to its audio with a tiny group every
+//! [`HEARTBEAT_INTERVAL`], and declares the remote lost when they stop.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use moq_lite as moq;
+use tracing::debug;
+
+use super::{control::write_frame, next_group};
+
+pub const HEARTBEAT_TRACK_NAME: &str = "heartbeat";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Publish a heartbeat every [`HEARTBEAT_INTERVAL`] until aborted.
+pub async fn publish_heartbeats(mut track: moq::TrackProducer) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        write_frame(&mut track, Bytes::new());
+    }
+}
+
+/// Returns once the remote, having sent at least one heartbeat, sends none
+/// for `timeout`. A remote that never sends any, e.g. an older version without
+/// the track, is never declared lost.
+pub async fn watch_heartbeats(mut track: moq::TrackConsumer, timeout: Duration) {
+    let mut armed = false;
+    loop {
+        let group = if armed {
+            match tokio::time::timeout(timeout, next_group(&mut track)).await {
+                Ok(group) => group,
+                Err(_) => return,
+            }
+        } else {
+            next_group(&mut track).await
+        };
+        match group {
+            Ok(Some(_)) => armed = true,
+            // the remote closed the track; its broadcast ends with it.
+            Ok(None) => return std::future::pending().await,
+            Err(err) => {
+                debug!("not watching heartbeats: {err:#}");
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lost_only_after_a_heartbeat_went_missing() {
+        let timeout = Duration::from_millis(50);
+        let track = moq::Track::new(HEARTBEAT_TRACK_NAME).produce();
+        let mut producer = track.producer;
+        let watch = tokio::spawn(watch_heartbeats(track.consumer, timeout));
+
+        tokio::time::sleep(timeout * 3).await;
+        assert!(!watch.is_finished(), "not armed before the first heartbeat");
+
+        write_frame(&mut producer, Bytes::new());
+        tokio::time::timeout(timeout * 10, watch)
+            .await
+            .expect("lost after the heartbeats stopped")
+            .unwrap();
+    }
+}
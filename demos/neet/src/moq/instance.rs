@@ -0,0 +1,105 @@
+//! Instance ids: every run publishes its role's broadcast under a path of its
+//! own, `<role>/<instance>`, so a run that crashed and is still announced can
+//! be told apart from the one that replaced it.
+//!
+//! Ids sort by start time, so subscribers follow the newest instance of the
+//! remote and ignore older ones. A publisher that finds an older instance of
+//! its own role refuses to start unless forced, and one that sees a newer
+//! instance appear knows it was taken over.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, ensure, Result};
+
+/// Hex digits of the start time and of the random part.
+const TIME_DIGITS: usize = 12;
+const NONCE_DIGITS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct InstanceId {
+    /// Milliseconds since the Unix epoch when the run started.
+    started_ms: u64,
+    /// Tells apart runs started in the same millisecond.
+    nonce: u32,
+}
+
+impl InstanceId {
+    pub fn new() -> Result<Self> {
+        let started_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let mut nonce = [0; 4];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| anyhow!("no randomness for an instance id: {err}"))?;
+        Ok(Self {
+            started_ms,
+            nonce: u32::from_be_bytes(nonce),
+        })
+    }
+
+    /// The path this instance publishes at for `role_path`.
+    pub fn path(&self, role_path: &str) -> String {
+        format!("{role_path}/{self}")
+    }
+}
+
+impl fmt::Display for InstanceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:0tw$x}{:0nw$x}",
+            self.started_ms,
+            self.nonce,
+            tw = TIME_DIGITS,
+            nw = NONCE_DIGITS
+        )
+    }
+}
+
+impl FromStr for InstanceId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ensure!(
+            s.len() == TIME_DIGITS + NONCE_DIGITS && s.is_ascii(),
+            "malformed instance id `{s}`"
+        );
+        let (started, nonce) = s.split_at(TIME_DIGITS);
+        Ok(Self {
+            started_ms: u64::from_str_radix(started, 16)?,
+            nonce: u32::from_str_radix(nonce, 16)?,
+        })
+    }
+}
+
+/// Split an announced `<role>/<instance>` path.
+pub fn split_path(path: &str) -> Option<(&str, InstanceId)> {
+    let (role, instance) = path.rsplit_once('/')?;
+    Some((role, instance.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_round_trip_and_sort_by_start() {
+        let older = InstanceId {
+            started_ms: 1_700_000_000_000,
+            nonce: u32::MAX,
+        };
+        let newer = InstanceId {
+            started_ms: 1_700_000_000_001,
+            nonce: 0,
+        };
+        assert!(older < newer);
+
+        let path = newer.path("caller");
+        assert_eq!(path, "caller/018bcfe5680100000000");
+        assert_eq!(split_path(&path), Some(("caller", newer)));
+        assert_eq!(split_path("caller"), None);
+        assert_eq!(split_path("caller/not-an-id"), None);
+    }
+}
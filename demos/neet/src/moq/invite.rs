@@ -0,0 +1,250 @@
+//! Invitations: `call --invite` rings a `daemon --inbox` without a session
+//! agreed beforehand.
+//!
+//! Every identity has an inbox on the relay, a session named after its
+//! fingerprint. The caller makes up a fresh session, signs an [`Invite`] to
+//! it with its identity key and publishes that in the callee's inbox for as
+//! long as it waits. The daemon follows its inbox and rings for every invite
+//! that is addressed to it, signed and recent; answering it listens on the
+//! invited session and checks that the caller is whoever signed the invite.
+//! The relay sees who invites whom, but cannot forge or redirect an invite.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::Bytes;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use moq_lite as moq;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::{debug, warn};
+use url::Url;
+
+use super::{
+    control::write_frame,
+    media_transport::{MediaEvent, MediaTransport, MoqRelay},
+    next_group, RelayTransport, TransportOptions,
+};
+use crate::{identity::Fingerprint, stats::ConnectionStats};
+
+const INVITE_TRACK_NAME: &str = "invite";
+/// Invites signed longer ago than this are stale, e.g. replayed.
+const INVITE_TTL: Duration = Duration::from_secs(5 * 60);
+const INVITE_WAIT: Duration = Duration::from_secs(5);
+const SESSION_BYTES: usize = 12;
+
+/// The session an identity's inbox is.
+pub fn inbox_session(owner: Fingerprint) -> String {
+    format!("inbox-{}", owner.to_string().replace(':', ""))
+}
+
+/// A new session nobody else will pick.
+pub fn fresh_session() -> Result<String> {
+    let mut bytes = [0; SESSION_BYTES];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| anyhow!("no randomness for a session: {err}"))?;
+    Ok(format!("invite-{}", hex::encode(bytes)))
+}
+
+/// "Call me in `session`", signed by the caller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub session: String,
+    /// The caller's identity key.
+    key: String,
+    /// Unix seconds.
+    sent_at: u64,
+    signature: String,
+}
+
+impl Invite {
+    pub fn new(key: &SigningKey, to: Fingerprint, session: impl Into<String>) -> Self {
+        let session = session.into();
+        let sent_at = unix_now();
+        let signature = key.sign(&message(to, &session, sent_at));
+        Self {
+            session,
+            key: hex::encode(key.verifying_key().as_bytes()),
+            sent_at,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+
+    /// Who sent the invite, if it is for `to`, signed and recent.
+    pub fn verify(&self, to: Fingerprint) -> Result<Fingerprint> {
+        let key: [u8; 32] = hex::decode(&self.key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow!("malformed key"))?;
+        let signature: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or_else(|| anyhow!("malformed signature"))?;
+        let key = VerifyingKey::from_bytes(&key)?;
+        key.verify(
+            &message(to, &self.session, self.sent_at),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| anyhow!("not signed by its sender, or not for us"))?;
+        let age = unix_now().abs_diff(self.sent_at);
+        ensure!(age <= INVITE_TTL.as_secs(), "sent {age}s ago");
+        Ok(Fingerprint::of(&key))
+    }
+}
+
+fn message(to: Fingerprint, session: &str, sent_at: u64) -> Vec<u8> {
+    let mut message = b"neet invite\0".to_vec();
+    for part in [&to.to_string(), session, &sent_at.to_string()] {
+        message.extend_from_slice(part.as_bytes());
+        message.push(0);
+    }
+    message
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Put `invite` in the inbox of `to` on `relay` and keep it there until
+/// dropped.
+pub async fn send_invite(
+    relay: Url,
+    transport: TransportOptions,
+    to: Fingerprint,
+    invite: Invite,
+) -> Result<()> {
+    let route = RelayTransport::new(&relay, &inbox_session(to), transport)?;
+    let inbox = MoqRelay::connect(&route, ConnectionStats::default()).await?;
+    let mut broadcast = moq::Broadcast::produce();
+    // kept alive for as long as we wait.
+    let mut track = broadcast
+        .producer
+        .create_track(moq::Track::new(INVITE_TRACK_NAME));
+    write_frame(&mut track, Bytes::from(serde_json::to_vec(&invite)?));
+    inbox.publish_track(&invite.session, broadcast.consumer.clone());
+    debug!(%to, session = invite.session, "invite sent");
+    Err(inbox.closed().await.context("the inbox connection closed"))
+}
+
+/// Something arriving in, or leaving, an inbox.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InboxEvent {
+    /// A caller waits for us in `invite.session`.
+    Invited { from: Fingerprint, invite: Invite },
+    /// The caller invited to `session` gave up.
+    Withdrawn { session: String },
+}
+
+/// Follow the inbox of `owner` on `relay`, passing on every valid invite and
+/// the withdrawal of every invite.
+pub async fn watch_inbox(
+    relay: Url,
+    transport: TransportOptions,
+    owner: Fingerprint,
+    events: mpsc::UnboundedSender<InboxEvent>,
+) -> Result<()> {
+    let route = RelayTransport::new(&relay, &inbox_session(owner), transport)?;
+    let inbox = MoqRelay::connect(&route, ConnectionStats::default()).await?;
+    // invites being read, so that a slow caller doesn't hold up the others.
+    let mut readers = JoinSet::new();
+    let mut reading = HashMap::new();
+    loop {
+        let event = tokio::select! {
+            event = inbox.events() => event,
+            Some(_) = readers.join_next() => continue,
+            err = inbox.closed() => return Err(err.context("the inbox connection closed")),
+        };
+        match event {
+            Some(MediaEvent::Announced { path, broadcast }) => {
+                let reader =
+                    readers.spawn(read_invite(owner, path.clone(), broadcast, events.clone()));
+                if let Some(old) = reading.insert(path, reader) {
+                    old.abort();
+                }
+            }
+            Some(MediaEvent::Withdrawn { path }) => {
+                // an invite withdrawn before it was read never rings.
+                if let Some(reader) = reading.remove(&path) {
+                    reader.abort();
+                }
+                if events
+                    .send(InboxEvent::Withdrawn { session: path })
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            None => return Err(anyhow!("the inbox announcements ended")),
+        }
+        if events.is_closed() {
+            return Ok(());
+        }
+    }
+}
+
+/// Read the invite published at `path`, and pass it on if it is valid.
+async fn read_invite(
+    owner: Fingerprint,
+    path: String,
+    broadcast: moq::BroadcastConsumer,
+    events: mpsc::UnboundedSender<InboxEvent>,
+) {
+    let Some(invite) = fetch_invite(broadcast).await else {
+        debug!(path, "no invite in the inbox broadcast");
+        return;
+    };
+    match invite.verify(owner) {
+        Ok(from) if invite.session == path => {
+            let _ = events.send(InboxEvent::Invited { from, invite });
+        }
+        Ok(_) => warn!(path, "ignoring an invite published for another session"),
+        Err(err) => warn!(path, "ignoring an invite: {err:#}"),
+    }
+}
+
+async fn fetch_invite(broadcast: moq::BroadcastConsumer) -> Option<Invite> {
+    let mut track = broadcast.subscribe_track(&moq::Track::new(INVITE_TRACK_NAME));
+    let read = async {
+        let mut group = next_group(&mut track).await.ok()??;
+        let payload = group.read_frame().await.ok()??;
+        serde_json::from_slice(&payload).ok()
+    };
+    tokio::time::timeout(INVITE_WAIT, read).await.ok()?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invites_only_verify_for_their_addressee() {
+        let caller = SigningKey::from_bytes(&[1; 32]);
+        let callee = Fingerprint::of(&SigningKey::from_bytes(&[2; 32]).verifying_key());
+        let someone_else = Fingerprint::of(&SigningKey::from_bytes(&[3; 32]).verifying_key());
+        let invite = Invite::new(&caller, callee, fresh_session().unwrap());
+        assert_eq!(
+            invite.verify(callee).unwrap(),
+            Fingerprint::of(&caller.verifying_key())
+        );
+        assert!(invite.verify(someone_else).is_err());
+
+        let redirected = Invite {
+            session: fresh_session().unwrap(),
+            ..invite.clone()
+        };
+        assert!(redirected.verify(callee).is_err());
+        let stale = Invite {
+            sent_at: invite.sent_at - INVITE_TTL.as_secs() - 1,
+            ..invite
+        };
+        assert!(stale.verify(callee).is_err());
+        assert!(inbox_session(callee).starts_with("inbox-"));
+        assert!(!inbox_session(callee).contains(':'));
+    }
+}
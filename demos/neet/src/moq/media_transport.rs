@@ -0,0 +1,178 @@
+//! What a call's media travels over, apart from how the connection is made.
+//!
+//! A [`MediaTransport`] publishes our tracks, subscribes to the remote's and
+//! reports what is announced, in moq-lite's terms: tracks are bundled into
+//! broadcasts under a path. The call only goes through it, so transports
+//! other than a MoQ session, e.g. in-memory ones for tests, can carry a call
+//! without touching [`super::run_audio_session`]. [`MoqRelay`] is the MoQ
+//! session over a [`Transport`], to a relay or a peer.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use moq_lite as moq;
+use tokio::{
+    sync::{mpsc, Mutex as AsyncMutex},
+    task::JoinHandle,
+};
+
+use super::{
+    connect, sample_connection,
+    transport::{Connection, Transport},
+};
+use crate::stats::ConnectionStats;
+
+/// A broadcast coming or going.
+pub enum MediaEvent {
+    Announced {
+        path: String,
+        broadcast: moq::BroadcastConsumer,
+    },
+    Withdrawn {
+        path: String,
+    },
+}
+
+/// The next event, `None` once no more come.
+pub type NextEvent<'a> = Pin<Box<dyn Future<Output = Option<MediaEvent>> + Send + 'a>>;
+/// Resolves with the reason the transport failed.
+pub type Closed<'a> = Pin<Box<dyn Future<Output = anyhow::Error> + Send + 'a>>;
+
+/// Where a call publishes its tracks and finds the remote's.
+pub trait MediaTransport: Send + Sync {
+    /// Publish the tracks of `broadcast` under `path`. False if that replaced
+    /// a broadcast already there.
+    fn publish_track(&self, path: &str, broadcast: moq::BroadcastConsumer) -> bool;
+
+    /// The tracks announced under `path`, if any are.
+    fn subscribe_track(&self, path: &str) -> Option<moq::BroadcastConsumer>;
+
+    /// Wait for the next broadcast to be announced or withdrawn. Only one
+    /// caller may wait at a time.
+    fn events(&self) -> NextEvent<'_>;
+
+    fn closed(&self) -> Closed<'_>;
+
+    /// Hang up, withdrawing what we published.
+    fn close(self: Box<Self>);
+}
+
+/// A MoQ session, its announcements followed in the background so that
+/// looking up a broadcast never waits on them.
+pub struct MoqRelay {
+    /// Taken when hanging up.
+    session: Option<moq::Session>,
+    publish: moq::OriginProducer,
+    announced: Arc<Mutex<HashMap<String, moq::BroadcastConsumer>>>,
+    events: AsyncMutex<mpsc::UnboundedReceiver<MediaEvent>>,
+    /// Queues events next to the follower's.
+    replay: mpsc::UnboundedSender<MediaEvent>,
+    follower: JoinHandle<()>,
+    connection: Connection,
+    sampler: JoinHandle<()>,
+}
+
+impl MoqRelay {
+    /// Open a connection over `route` and start a MoQ session on it.
+    pub async fn connect(route: &dyn Transport, stats: ConnectionStats) -> Result<Self> {
+        let relay = connect(route, stats).await?;
+        let announced = Arc::new(Mutex::new(HashMap::new()));
+        let (sender, events) = mpsc::unbounded_channel();
+        let follower = tokio::spawn(follow(relay.subscribe, announced.clone(), sender.clone()));
+        Ok(Self {
+            session: Some(relay.session),
+            publish: relay.publish,
+            announced,
+            events: AsyncMutex::new(events),
+            replay: sender,
+            follower,
+            connection: relay.connection,
+            sampler: relay.sampler,
+        })
+    }
+
+    /// Ready a connection kept warm for a call: sample it into the call's
+    /// `stats`, and replace the announcements queued while it waited with
+    /// those current now.
+    pub fn hand_over(&mut self, stats: ConnectionStats) {
+        self.sampler.abort();
+        self.sampler = sample_connection(self.connection.clone(), stats);
+        // the follower sends under this lock, so nothing slips in between.
+        let announced = self.announced.lock().unwrap();
+        let events = self.events.get_mut();
+        while events.try_recv().is_ok() {}
+        for (path, broadcast) in announced.iter() {
+            let _ = self.replay.send(MediaEvent::Announced {
+                path: path.clone(),
+                broadcast: broadcast.clone(),
+            });
+        }
+    }
+}
+
+/// Keep `announced` current and pass every announcement on.
+async fn follow(
+    mut origin: moq::OriginConsumer,
+    announced: Arc<Mutex<HashMap<String, moq::BroadcastConsumer>>>,
+    events: mpsc::UnboundedSender<MediaEvent>,
+) {
+    while let Some((path, broadcast)) = origin.announced().await {
+        let path = path.as_str().to_string();
+        let mut current = announced.lock().unwrap();
+        let event = match broadcast {
+            Some(broadcast) => {
+                current.insert(path.clone(), broadcast.clone());
+                MediaEvent::Announced { path, broadcast }
+            }
+            None => {
+                current.remove(&path);
+                MediaEvent::Withdrawn { path }
+            }
+        };
+        // sent under the lock, for `hand_over` to replace the queue whole.
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+impl MediaTransport for MoqRelay {
+    fn publish_track(&self, path: &str, broadcast: moq::BroadcastConsumer) -> bool {
+        self.publish.publish_broadcast(path, broadcast)
+    }
+
+    fn subscribe_track(&self, path: &str) -> Option<moq::BroadcastConsumer> {
+        self.announced.lock().unwrap().get(path).cloned()
+    }
+
+    fn events(&self) -> NextEvent<'_> {
+        Box::pin(async move { self.events.lock().await.recv().await })
+    }
+
+    fn closed(&self) -> Closed<'_> {
+        Box::pin(async move {
+            match &self.session {
+                Some(session) => session.closed().await.into(),
+                None => std::future::pending().await,
+            }
+        })
+    }
+
+    fn close(mut self: Box<Self>) {
+        if let Some(session) = self.session.take() {
+            session.close(moq::Error::Cancel);
+        }
+    }
+}
+
+impl Drop for MoqRelay {
+    fn drop(&mut self) {
+        self.follower.abort();
+        self.sampler.abort();
+    }
+}
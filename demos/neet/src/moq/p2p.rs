@@ -0,0 +1,301 @@
+//! Calls straight between two peers behind NATs, without a relay
+//! (`--transport p2p`, in builds with the `p2p` feature).
+//!
+//! Each side asks a STUN server which public address its NAT gives its UDP
+//! port, and the two swap those addresses out of band: `--peer` if known,
+//! else typed in when asked. Both then send a few packets to the other's
+//! address from that port, so that each NAT has seen traffic towards the
+//! other and lets its packets in, and the QUIC connection follows on the
+//! same port: the listener accepts it on an [`Endpoint`], the caller dials
+//! it as with `--direct`.
+//!
+//! That gets through NATs that give a port the same public address whatever
+//! it sends to, as most home routers do, but not symmetric ones, such as
+//! some carrier-grade NATs, where only a relay helps. Nothing but the STUN
+//! query leaves the two peers. As with `--direct`, the certificate is not
+//! verified; `--pin` checks who is on the other end.
+
+use std::{
+    io::{BufRead as _, IsTerminal as _},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+use super::{
+    direct::{DialTransport, Endpoint},
+    transport::{IpVersion, Opening, Transport, TransportOptions},
+    Role,
+};
+
+/// Where to learn our public address, unless `--stun` says otherwise.
+pub const STUN_SERVER: &str = "stun.l.google.com:19302";
+/// How long to wait for the STUN server each time we ask.
+const STUN_TIMEOUT: Duration = Duration::from_secs(1);
+const STUN_ATTEMPTS: u32 = 3;
+/// Packets sent to the peer to open our NAT to it, and the gap between them.
+const PUNCHES: u32 = 5;
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the caller keeps dialing while the listener gets ready.
+const DIAL_WINDOW: Duration = Duration::from_secs(120);
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// How a call reaches the other side, chosen with `--transport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TransportKind {
+    /// Through the relay, or straight to `--direct`.
+    Relay,
+    /// Through both NATs, to `--peer`.
+    P2p,
+}
+
+/// A connection through both NATs, on the port they were opened from.
+#[derive(Debug)]
+pub struct P2pTransport {
+    public: SocketAddr,
+    peer: SocketAddr,
+    route: Route,
+}
+
+#[derive(Debug)]
+enum Route {
+    Accept(Endpoint),
+    Dial(DialTransport),
+}
+
+impl P2pTransport {
+    /// Learn our public address from `stun`, take the peer's (`peer`, else
+    /// from stdin) and open our NAT to it, ready to accept the peer's
+    /// connection as the listener or dial it as the caller.
+    pub async fn punch(
+        role: Role,
+        peer: Option<SocketAddr>,
+        stun: &str,
+        host: &str,
+        options: TransportOptions,
+    ) -> Result<Self> {
+        let bind = options.bind.unwrap_or(match options.ip_version {
+            Some(IpVersion::Ipv6) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            _ => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        });
+        let socket = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("failed to bind {bind}"))?;
+        let public = public_addr(&socket, stun).await?;
+        info!(%public, "our public address; the peer passes it as --peer");
+        let peer = match peer {
+            Some(peer) => peer,
+            None => ask_peer().await?,
+        };
+        for _ in 0..PUNCHES {
+            socket.send_to(b"neet", peer).await?;
+            tokio::time::sleep(PUNCH_INTERVAL).await;
+        }
+        debug!(%peer, "opened our NAT to the peer");
+        // the QUIC endpoint takes over the port the NAT has just opened.
+        let local = socket.local_addr()?;
+        drop(socket);
+        let route = match role {
+            Role::Listener => Route::Accept(Endpoint::bind(local, host)?),
+            Role::Caller => Route::Dial(DialTransport::new(
+                peer,
+                TransportOptions {
+                    bind: Some(local),
+                    ..options
+                },
+            )),
+        };
+        Ok(Self {
+            public,
+            peer,
+            route,
+        })
+    }
+}
+
+impl Transport for P2pTransport {
+    fn open(&self) -> Opening<'_> {
+        match &self.route {
+            Route::Accept(endpoint) => endpoint.open(),
+            // until the listener has opened its NAT, our packets are dropped.
+            Route::Dial(dial) => Box::pin(async move {
+                let started = Instant::now();
+                loop {
+                    match dial.open().await {
+                        Ok(opened) => return Ok(opened),
+                        Err(err) if started.elapsed() < DIAL_WINDOW => {
+                            warn!("{err:#}; dialing again")
+                        }
+                        Err(err) => return Err(err),
+                    }
+                }
+            }),
+        }
+    }
+
+    fn target(&self) -> String {
+        format!("peer at {} from {}", self.peer, self.public)
+    }
+}
+
+/// Read the peer's public address from stdin, before anything else reads it.
+async fn ask_peer() -> Result<SocketAddr> {
+    if !std::io::stdin().is_terminal() {
+        bail!("pass the peer's public address with --peer");
+    }
+    eprintln!("the peer's public address (ADDR:PORT): ");
+    tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line.trim()
+            .parse()
+            .with_context(|| format!("not an ADDR:PORT: {:?}", line.trim()))
+    })
+    .await?
+}
+
+/// The address `server` sees `socket` send from, through any NAT.
+async fn public_addr(socket: &UdpSocket, server: &str) -> Result<SocketAddr> {
+    let ipv4 = socket.local_addr()?.is_ipv4();
+    let server = tokio::net::lookup_host(server)
+        .await
+        .with_context(|| format!("failed to resolve STUN server {server}"))?
+        .find(|addr| addr.is_ipv4() == ipv4)
+        .ok_or_else(|| anyhow!("STUN server {server} has no address of our family"))?;
+    let mut transaction = [0; 12];
+    getrandom::getrandom(&mut transaction)?;
+    let request = binding_request(transaction);
+    let mut buf = [0; 512];
+    for _ in 0..STUN_ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = tokio::time::Instant::now() + STUN_TIMEOUT;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            let (len, from) = received?;
+            if from != server {
+                continue;
+            }
+            if let Some(public) = parse_binding_response(&buf[..len], transaction) {
+                return Ok(public);
+            }
+        }
+    }
+    bail!("no answer from STUN server {server}")
+}
+
+/// A STUN (RFC 5389) binding request without attributes.
+fn binding_request(transaction: [u8; 12]) -> [u8; 20] {
+    let mut request = [0; 20];
+    request[..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request[8..].copy_from_slice(&transaction);
+    request
+}
+
+/// The mapped address in a binding response to `transaction`, if it is one.
+fn parse_binding_response(packet: &[u8], transaction: [u8; 12]) -> Option<SocketAddr> {
+    let header = packet.get(..20)?;
+    if header[..2] != BINDING_SUCCESS.to_be_bytes()
+        || header[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || header[8..] != transaction
+    {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let mut attributes = packet.get(20..20 + len)?;
+    let mut mapped = None;
+    while let [kind_hi, kind_lo, len_hi, len_lo, rest @ ..] = attributes {
+        let len = usize::from(u16::from_be_bytes([*len_hi, *len_lo]));
+        let value = rest.get(..len)?;
+        match u16::from_be_bytes([*kind_hi, *kind_lo]) {
+            XOR_MAPPED_ADDRESS => return xor_address(value, transaction),
+            MAPPED_ADDRESS => mapped = address(value),
+            _ => {}
+        }
+        // attributes are padded to a multiple of four bytes.
+        attributes = rest.get(len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped
+}
+
+fn address(value: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let ip = match (value.get(1)?, value.get(4..)?) {
+        (1, &[a, b, c, d]) => IpAddr::from([a, b, c, d]),
+        (2, ip) => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+    Some((ip, port).into())
+}
+
+/// XOR-MAPPED-ADDRESS: the port and address masked with the magic cookie
+/// and transaction id, so that NATs rewriting addresses leave it alone.
+fn xor_address(value: &[u8], transaction: [u8; 12]) -> Option<SocketAddr> {
+    let mut mask = [0; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(&transaction);
+    let mut value = value.to_vec();
+    let (port, ip) = value.get_mut(2..)?.split_at_mut_checked(2)?;
+    for (byte, mask) in port.iter_mut().chain(ip).zip(mask[..2].iter().chain(&mask)) {
+        *byte ^= mask;
+    }
+    address(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A binding response carrying one attribute.
+    fn response(transaction: [u8; 12], kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut packet = BINDING_SUCCESS.to_be_bytes().to_vec();
+        let padded = value.len().next_multiple_of(4);
+        packet.extend_from_slice(&(4 + padded as u16).to_be_bytes());
+        packet.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        packet.extend_from_slice(&transaction);
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        packet.extend_from_slice(value);
+        packet.resize(20 + 4 + padded, 0);
+        packet
+    }
+
+    #[test]
+    fn reads_the_mapped_address() {
+        let transaction = [7; 12];
+        let request = binding_request(transaction);
+        assert_eq!(request[..4], [0, 1, 0, 0]);
+
+        // 203.0.113.5:40000, masked as in RFC 5769.
+        let port = 40000 ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip = u32::from(Ipv4Addr::new(203, 0, 113, 5)) ^ MAGIC_COOKIE;
+        let mut value = vec![0, 1];
+        value.extend_from_slice(&port.to_be_bytes());
+        value.extend_from_slice(&ip.to_be_bytes());
+        let packet = response(transaction, XOR_MAPPED_ADDRESS, &value);
+        assert_eq!(
+            parse_binding_response(&packet, transaction),
+            Some("203.0.113.5:40000".parse().unwrap())
+        );
+        // an answer to someone else's request, or cut short.
+        assert_eq!(parse_binding_response(&packet, [8; 12]), None);
+        assert_eq!(parse_binding_response(&packet[..26], transaction), None);
+
+        let ip: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut value = vec![0, 2, 0x13, 0x88];
+        value.extend_from_slice(&ip.octets());
+        let packet = response(transaction, MAPPED_ADDRESS, &value);
+        assert_eq!(
+            parse_binding_response(&packet, transaction),
+            Some("[2001:db8::1]:5000".parse().unwrap())
+        );
+    }
+}
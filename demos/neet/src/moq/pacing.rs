@@ -0,0 +1,61 @@
+//! Spreading a track's writes out in time (`--pace`).
+//!
+//! Frames reach the publisher in bursts: several at once after capture fell
+//! behind, or all the audio held back while the connection stalled once it
+//! catches up. Written as they come, a burst leaves in one go and queues at
+//! the first hop slower than we are, usually the user's own uplink, where it
+//! delays everything behind it or overflows a shallow buffer. A [`Pacer`]
+//! lets each frame out no sooner than [`PACE_GAIN`] times faster than the
+//! one before it plays: audio that arrives in real time passes untouched,
+//! and a burst goes out over the frames' own interval, a little faster, so
+//! that a backlog still drains.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How much faster than real time a burst is sent.
+const PACE_GAIN: f64 = 1.25;
+
+#[derive(Debug, Default)]
+pub struct Pacer {
+    /// When the next frame may go out.
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    /// Wait until a frame of `duration` may go out.
+    pub async fn wait(&mut self, duration: Duration) {
+        let at = self.schedule(Instant::now(), duration);
+        tokio::time::sleep_until(at).await;
+    }
+
+    /// When a frame of `duration` that is ready at `now` may go out.
+    fn schedule(&mut self, now: Instant, duration: Duration) -> Instant {
+        let at = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(at + duration.div_f64(PACE_GAIN));
+        at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_bursts_and_passes_real_time_audio() {
+        let frame = Duration::from_millis(20);
+        let start = Instant::now();
+        let mut pacer = Pacer::default();
+        let offsets: Vec<_> = (0..4)
+            .map(|_| pacer.schedule(start, frame) - start)
+            .collect();
+        assert_eq!(offsets, [0, 16, 32, 48].map(Duration::from_millis).to_vec());
+
+        // once the burst is out, frames in real time wait for nothing.
+        for i in 4..10 {
+            let now = start + frame * i;
+            assert_eq!(pacer.schedule(now, frame), now);
+        }
+    }
+}
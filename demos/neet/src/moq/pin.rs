@@ -0,0 +1,165 @@
+//! Shared-PIN access control (`--pin`).
+//!
+//! Before a peer's audio is decoded (or, on the bridge, mixed), each side
+//! repeats a random challenge on its control track until the peer answers with
+//! an HMAC-SHA256 of it keyed with the PIN. The answer also covers the session
+//! and the path the prover publishes at, so nobody can get a proof for their
+//! own path out of someone else, e.g. by reflecting a challenge back or by
+//! forwarding it to the bridge from a second connection. Both sides check each
+//! other, and a peer that answers wrongly is ignored.
+
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, ensure, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::control::{ControlChannel, ControlFrame};
+
+/// How often our challenge is repeated until the peer answers it.
+pub const CHALLENGE_INTERVAL: Duration = Duration::from_secs(1);
+const NONCE_BYTES: usize = 16;
+
+/// The shared secret. Never printed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Pin(Arc<str>);
+
+impl FromStr for Pin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ensure!(!s.is_empty(), "the PIN must not be empty");
+        Ok(Self(s.into()))
+    }
+}
+
+impl fmt::Debug for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Pin(..)")
+    }
+}
+
+/// One side of the handshake with one peer.
+#[derive(Debug, Clone)]
+pub struct PinCheck {
+    pin: Pin,
+    session: String,
+    /// Where we publish, i.e. who we prove to be.
+    local_path: String,
+    /// Where the peer publishes.
+    remote_path: String,
+    /// Our challenge to this peer.
+    nonce: String,
+}
+
+/// The outcome of a frame from the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Passed,
+    Failed,
+}
+
+impl PinCheck {
+    pub fn new(
+        pin: &Pin,
+        session: impl Into<String>,
+        local_path: impl Into<String>,
+        remote_path: impl Into<String>,
+    ) -> Result<Self> {
+        let mut nonce = [0; NONCE_BYTES];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|err| anyhow!("no randomness for a nonce: {err}"))?;
+        Ok(Self {
+            pin: pin.clone(),
+            session: session.into(),
+            local_path: local_path.into(),
+            remote_path: remote_path.into(),
+            nonce: hex::encode(nonce),
+        })
+    }
+
+    pub fn challenge(&self) -> ControlFrame {
+        ControlFrame::PinChallenge {
+            nonce: self.nonce.clone(),
+        }
+    }
+
+    /// The proof that `prover` knows the PIN, for `nonce`.
+    fn mac(&self, nonce: &str, prover: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.pin.0.as_bytes())
+            .expect("HMAC takes keys of any length");
+        for part in [&self.session, prover, nonce] {
+            mac.update(part.as_bytes());
+            mac.update(b"\0");
+        }
+        mac
+    }
+
+    /// Answer the peer's challenges and check its answers to ours.
+    pub fn handle(&self, frame: &ControlFrame, reply: &ControlChannel) -> Option<Verdict> {
+        match frame {
+            ControlFrame::PinChallenge { nonce } => {
+                let proof = self.mac(nonce, &self.local_path).finalize().into_bytes();
+                let proof = hex::encode(proof);
+                reply.send(&ControlFrame::PinResponse {
+                    nonce: nonce.clone(),
+                    proof,
+                });
+                None
+            }
+            // answers to someone else's challenge, or an earlier one of ours.
+            ControlFrame::PinResponse { nonce, .. } if *nonce != self.nonce => None,
+            ControlFrame::PinResponse { proof, .. } => {
+                let expected = self.mac(&self.nonce, &self.remote_path);
+                let passed =
+                    hex::decode(proof).is_ok_and(|proof| expected.verify_slice(&proof).is_ok());
+                Some(if passed {
+                    Verdict::Passed
+                } else {
+                    Verdict::Failed
+                })
+            }
+            ControlFrame::Moderator(_)
+            | ControlFrame::IdentityChallenge { .. }
+            | ControlFrame::IdentityProof { .. }
+            | ControlFrame::MicStatus { .. }
+            | ControlFrame::Presence { .. }
+            | ControlFrame::Mark { .. }
+            | ControlFrame::Recording { .. }
+            | ControlFrame::RecordingConsent => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_peers_with_the_pin_pass() {
+        let pin: Pin = "4711".parse().unwrap();
+        let listener = PinCheck::new(&pin, "demo", "listener", "caller").unwrap();
+        let caller = PinCheck::new(&pin, "demo", "caller", "listener").unwrap();
+        let intruder =
+            PinCheck::new(&"0000".parse().unwrap(), "demo", "caller", "listener").unwrap();
+        let to_listener = ControlChannel::default();
+        let mut answers = to_listener.subscribe();
+
+        let mut answer = |check: &PinCheck| {
+            check.handle(&listener.challenge(), &to_listener);
+            serde_json::from_slice(&answers.try_recv().unwrap()).unwrap()
+        };
+        let good = answer(&caller);
+        assert_eq!(listener.handle(&good, &to_listener), Some(Verdict::Passed));
+        let bad = answer(&intruder);
+        assert_eq!(listener.handle(&bad, &to_listener), Some(Verdict::Failed));
+
+        // our own answer, reflected back, proves nothing.
+        let reflected = answer(&listener);
+        assert_eq!(
+            listener.handle(&reflected, &to_listener),
+            Some(Verdict::Failed)
+        );
+        assert!("".parse::<Pin>().is_err());
+    }
+}
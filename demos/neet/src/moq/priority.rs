@@ -0,0 +1,134 @@
+//! Per-track MoQ priorities.
+//!
+//! moq-lite serves higher-priority tracks first when the connection is congested,
+//! so the scheme decides which media degrades first.
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use moq_lite as moq;
+
+use crate::media::TrackKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PriorityScheme {
+    /// Audio is served before every other track.
+    #[default]
+    AudioFirst,
+    /// All tracks get the same priority.
+    Equal,
+}
+
+impl PriorityScheme {
+    pub fn priority(self, kind: TrackKind) -> u8 {
+        match (self, kind) {
+            (PriorityScheme::AudioFirst, TrackKind::Audio) => 200,
+            (PriorityScheme::AudioFirst, TrackKind::Control) => 150,
+            (PriorityScheme::Equal, _) => 100,
+        }
+    }
+}
+
+/// A `<track>=<priority>` override from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityOverride {
+    pub track: String,
+    pub priority: u8,
+}
+
+impl FromStr for PriorityOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (track, priority) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <track>=<priority>, got `{s}`"))?;
+        let priority = priority
+            .parse()
+            .with_context(|| format!("invalid priority `{priority}` (expected 0-255)"))?;
+        Ok(Self {
+            track: track.to_string(),
+            priority,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackPriorities {
+    scheme: PriorityScheme,
+    overrides: HashMap<String, u8>,
+}
+
+impl TrackPriorities {
+    pub fn new(
+        scheme: PriorityScheme,
+        overrides: impl IntoIterator<Item = PriorityOverride>,
+    ) -> Self {
+        Self {
+            scheme,
+            overrides: overrides
+                .into_iter()
+                .map(|o| (o.track, o.priority))
+                .collect(),
+        }
+    }
+
+    /// Describe a track with the priority configured for it.
+    pub fn track(&self, name: &str, kind: TrackKind) -> moq::Track {
+        let priority = self
+            .overrides
+            .get(name)
+            .copied()
+            .unwrap_or_else(|| self.scheme.priority(kind));
+        moq::Track {
+            name: name.to_string(),
+            priority,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_overrides() {
+        assert_eq!(
+            "audio=255".parse::<PriorityOverride>().unwrap(),
+            PriorityOverride {
+                track: "audio".into(),
+                priority: 255,
+            }
+        );
+        // the track name is whatever comes before the first `=`.
+        assert_eq!(
+            "in/program=0".parse::<PriorityOverride>().unwrap().track,
+            "in/program"
+        );
+        for malformed in ["audio", "audio=256", "audio=-1", "audio=high", "audio="] {
+            assert!(
+                malformed.parse::<PriorityOverride>().is_err(),
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn overrides_take_precedence_over_the_scheme() {
+        let overrides = ["report=250", "audio=10", "report=5"].map(|s| s.parse().unwrap());
+        let priorities = TrackPriorities::new(PriorityScheme::AudioFirst, overrides);
+        assert_eq!(priorities.track("audio", TrackKind::Audio).priority, 10);
+        // the last override of a track wins.
+        assert_eq!(priorities.track("report", TrackKind::Control).priority, 5);
+        // tracks without one fall back to the scheme.
+        assert_eq!(priorities.track("flac", TrackKind::Audio).priority, 200);
+        assert_eq!(
+            priorities.track("control", TrackKind::Control).priority,
+            150
+        );
+
+        let equal = TrackPriorities::new(PriorityScheme::Equal, []);
+        let track = equal.track("audio", TrackKind::Audio);
+        assert_eq!((track.name.as_str(), track.priority), ("audio", 100));
+    }
+}
@@ -0,0 +1,131 @@
+//! `--probe-bandwidth`: measure the path through the relay before the call
+//! starts.
+//!
+//! moq-lite only sends what someone subscribed to, so the probe publishes a
+//! broadcast of padding under a path of its own and subscribes to it back
+//! through the relay for [`PROBE_DURATION`]. The call then starts at a bitrate
+//! that fits a share of what arrived, with no more redundancy than fits too,
+//! instead of starting at the highest bitrate and backing off on loss. A
+//! relay that does not return the probe within [`ECHO_TIMEOUT`] leaves the
+//! call to start as usual.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use moq_lite as moq;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::{media_transport::MediaTransport, next_group, report::MIN_BITRATE, Redundancy};
+
+/// Probe broadcasts are announced under this path, like a role.
+pub const PROBE_PATH: &str = "probe";
+const PROBE_TRACK_NAME: &str = "probe";
+const PROBE_DURATION: Duration = Duration::from_secs(2);
+const ECHO_TIMEOUT: Duration = Duration::from_secs(1);
+const ECHO_POLL: Duration = Duration::from_millis(50);
+/// Padding is sent in one group this often...
+const PADDING_INTERVAL: Duration = Duration::from_millis(10);
+/// ...at this rate, well above what a call needs.
+const PADDING_RATE: u32 = 2_000_000;
+/// Share of the measured rate the call may use.
+const SHARE: f64 = 0.5;
+
+/// Whether `path` is a probe broadcast, which is nobody's audio.
+pub fn is_probe(path: &str) -> bool {
+    path.split_once('/')
+        .is_some_and(|(prefix, _)| prefix == PROBE_PATH)
+}
+
+/// Send padding to the relay as `path` and return the rate it came back at,
+/// in bits per second.
+pub async fn probe_bandwidth(transport: &dyn MediaTransport, path: &str) -> Option<u32> {
+    let moq::Produce {
+        producer: mut broadcast,
+        consumer,
+    } = moq::Broadcast::produce();
+    let track = broadcast.create_track(moq::Track::new(PROBE_TRACK_NAME));
+    transport.publish_track(path, consumer);
+    let padding = send_padding(track);
+    let rate = measure(transport, path).await;
+    padding.abort();
+    rate
+}
+
+fn send_padding(mut track: moq::TrackProducer) -> JoinHandle<()> {
+    let size = (PADDING_RATE as u128 * PADDING_INTERVAL.as_micros() / 8_000_000) as usize;
+    let padding = Bytes::from(vec![0; size]);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PADDING_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut group = track.append_group();
+            group.write_frame(padding.clone());
+            group.close();
+        }
+    })
+}
+
+async fn measure(transport: &dyn MediaTransport, path: &str) -> Option<u32> {
+    let deadline = Instant::now() + ECHO_TIMEOUT;
+    let echo = loop {
+        if let Some(echo) = transport.subscribe_track(path) {
+            break echo;
+        }
+        if Instant::now() >= deadline {
+            warn!("the relay did not return the bandwidth probe; starting without it");
+            return None;
+        }
+        tokio::time::sleep(ECHO_POLL).await;
+    };
+    let mut track = echo.subscribe_track(&moq::Track::new(PROBE_TRACK_NAME));
+    let mut bytes = 0;
+    let mut first = None;
+    let count = async {
+        while let Ok(Some(mut group)) = next_group(&mut track).await {
+            first.get_or_insert_with(Instant::now);
+            while let Ok(Some(frame)) = group.read_frame().await {
+                bytes += frame.len();
+            }
+        }
+    };
+    let _ = tokio::time::timeout(PROBE_DURATION, count).await;
+    let elapsed = first?.elapsed().as_secs_f64();
+    debug!(bytes, elapsed, "bandwidth probe done");
+    (elapsed > 0.).then(|| (bytes as f64 * 8. / elapsed) as u32)
+}
+
+/// The bitrate, at most `max_bitrate`, and the redundancy to start a call
+/// with over a path that carries `rate`.
+pub fn fit(rate: u32, max_bitrate: u32, mut redundancy: Redundancy) -> (u32, Redundancy) {
+    let budget = (rate as f64 * SHARE) as u32;
+    let bitrate = budget.clamp(MIN_BITRATE, max_bitrate);
+    // every frame repeated costs about as much again.
+    let copies = (budget / bitrate).saturating_sub(1);
+    redundancy.frames = redundancy.frames.min(copies.min(u8::MAX as u32) as u8);
+    (bitrate, redundancy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_bitrate_and_redundancy_into_the_probed_rate() {
+        let redundancy = Redundancy {
+            frames: 2,
+            loss_threshold_pct: 5.,
+        };
+        // plenty: the highest bitrate, with all the redundancy.
+        let (bitrate, fitted) = fit(1_000_000, 64_000, redundancy);
+        assert_eq!((bitrate, fitted.frames), (64_000, 2));
+        // room for one copy.
+        let (bitrate, fitted) = fit(260_000, 64_000, redundancy);
+        assert_eq!((bitrate, fitted.frames), (64_000, 1));
+        // a bad path starts low and without redundancy.
+        let (bitrate, fitted) = fit(40_000, 64_000, redundancy);
+        assert_eq!((bitrate, fitted.frames), (20_000, 0));
+        let (bitrate, _) = fit(1_000, 64_000, redundancy);
+        assert_eq!(bitrate, MIN_BITRATE);
+    }
+}
@@ -0,0 +1,236 @@
+//! Callers waiting their turn at a busy listener.
+//!
+//! A listener that waits for the next caller after each call
+//! (`--persistent`) says so in its catalog with [`Capability::Queue`]. A
+//! caller who finds an earlier caller still in the session then queues
+//! instead of giving up: it announces an empty broadcast under
+//! [`QUEUE_PATH`], which tells the listener and the caller on the line that
+//! someone is waiting, rings while it waits, and calls once every earlier
+//! caller, on the line or in the queue, has gone. Instance ids, which sort by
+//! start time, keep the order. The broadcast stays up for the queued
+//! caller's whole call, so that those behind it keep waiting. At most
+//! [`MAX_QUEUED`] callers wait; more are turned away as before.
+
+use std::{collections::BTreeSet, time::Duration};
+
+use anyhow::{anyhow, Result};
+use moq_lite as moq;
+use tokio::select;
+use tracing::info;
+
+use super::{
+    catalog::{read_catalog, CATALOG_TRACK_NAME},
+    check_rival,
+    instance::{split_path, InstanceId},
+    media_transport::{MediaEvent, MediaTransport},
+    Announced, Capability, MoqOptions, Role,
+};
+use crate::{audio::AudioContext, error::NeetError, media::TrackKind};
+
+/// Queued callers are announced under this path, like a role.
+pub const QUEUE_PATH: &str = "queue";
+/// Callers that may wait at once.
+const MAX_QUEUED: usize = 3;
+/// A queued caller hears a ring this often.
+const RING_INTERVAL: Duration = Duration::from_secs(4);
+/// Beeps telling those on the line that a caller is waiting.
+const WAITING_BEEPS: usize = 2;
+
+/// Who was in the session once it was our turn.
+pub struct Turn {
+    /// The newest listener seen.
+    pub listener: Option<Announced>,
+    /// Our place in the queue, if we queued; dropping it leaves the queue.
+    pub place: Option<moq::BroadcastProducer>,
+}
+
+/// See who is announced for `wait`, as [`super::settle`] does for a caller,
+/// and if an earlier caller is still in the session, queue behind it where
+/// the listener keeps a queue. Returns early, without a listener, on hangup.
+pub async fn wait_turn(
+    transport: &dyn MediaTransport,
+    options: &MoqOptions,
+    audio: &AudioContext,
+    wait: Duration,
+) -> Result<Turn> {
+    let mut ahead = Ahead::default();
+    let mut listener = None;
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        select! {
+            // whatever is queued already counts, however short the wait.
+            biased;
+            event = transport.events() => observe(options, event, &mut ahead, &mut listener)?,
+            _ = &mut deadline => break,
+        }
+    }
+    if ahead.is_empty() {
+        return Ok(Turn {
+            listener,
+            place: None,
+        });
+    }
+    if !keeps_queue(options, listener.as_ref()).await? {
+        // turned away, as without a queue.
+        for instance in ahead.on_line.iter().chain(&ahead.queued) {
+            check_rival(options, *instance)?;
+        }
+    }
+    if ahead.queued.len() >= MAX_QUEUED {
+        return Err(NeetError::SessionRejected(format!(
+            "the listener is on a call and {MAX_QUEUED} callers are already waiting"
+        ))
+        .into());
+    }
+
+    let broadcast = moq::Broadcast::produce();
+    transport.publish_track(&options.instance.path(QUEUE_PATH), broadcast.consumer);
+    let mut ring = tokio::time::interval(RING_INTERVAL);
+    let mut position = 0;
+    while !ahead.is_empty() {
+        select! {
+            event = transport.events() => observe(options, event, &mut ahead, &mut listener)?,
+            _ = ring.tick() => {
+                if ahead.position() != position {
+                    position = ahead.position();
+                    info!(position, "the listener is on another call; waiting in the queue");
+                    if let Some(announcer) = audio.announcer() {
+                        announcer.say(format!("the line is busy; you are number {position} in the queue"));
+                        continue;
+                    }
+                }
+                audio.beep(1);
+            }
+            _ = options.shutdown.cancelled() => break,
+        }
+    }
+    if !options.shutdown.is_cancelled() {
+        info!("the line is free; calling");
+    }
+    Ok(Turn {
+        listener,
+        place: Some(broadcast.producer),
+    })
+}
+
+/// Whether `listener` lets callers wait for it.
+async fn keeps_queue(options: &MoqOptions, listener: Option<&Announced>) -> Result<bool> {
+    let Some(listener) = listener else {
+        return Ok(false);
+    };
+    let track = options
+        .priorities
+        .track(CATALOG_TRACK_NAME, TrackKind::Control);
+    let catalog = read_catalog(listener.broadcast.subscribe_track(&track)).await?;
+    Ok(catalog.is_some_and(|catalog| catalog.capabilities().contains(Capability::Queue)))
+}
+
+/// Tell those on the line that the caller `instance` is waiting.
+pub fn caller_waiting(audio: &AudioContext, instance: InstanceId) {
+    info!(%instance, "another caller is waiting for the line");
+    match audio.announcer() {
+        Some(announcer) => announcer.say("another caller is waiting"),
+        None => audio.beep(WAITING_BEEPS),
+    }
+}
+
+/// The callers who came before us and are still here.
+#[derive(Debug, Default)]
+struct Ahead {
+    on_line: BTreeSet<InstanceId>,
+    queued: BTreeSet<InstanceId>,
+}
+
+impl Ahead {
+    fn is_empty(&self) -> bool {
+        self.on_line.is_empty() && self.queued.is_empty()
+    }
+
+    /// Our place in the queue, counting from 1. A caller on the line has
+    /// been in the queue too, unless it got the line at once.
+    fn position(&self) -> usize {
+        self.on_line.union(&self.queued).count() + 1
+    }
+
+    /// Take in that the caller with `instance`, on the line or queued as
+    /// `role`, came or went. Only those earlier than `me` count.
+    fn update(&mut self, me: InstanceId, role: &str, instance: InstanceId, came: bool) {
+        let callers = if role == Role::Caller.publish_path() {
+            &mut self.on_line
+        } else if role == QUEUE_PATH {
+            &mut self.queued
+        } else {
+            return;
+        };
+        if !came {
+            callers.remove(&instance);
+        } else if instance < me {
+            callers.insert(instance);
+        }
+    }
+}
+
+/// Take in `event`, keeping who is ahead in `ahead` and the newest listener
+/// in `listener`. Fails if a later caller took the line, as [`check_rival`]
+/// does.
+fn observe(
+    options: &MoqOptions,
+    event: Option<MediaEvent>,
+    ahead: &mut Ahead,
+    listener: &mut Option<Announced>,
+) -> Result<()> {
+    let (path, broadcast) = match event.ok_or_else(|| anyhow!("announcement stream closed"))? {
+        MediaEvent::Announced { path, broadcast } => (path, Some(broadcast)),
+        MediaEvent::Withdrawn { path } => (path, None),
+    };
+    let Some((role, instance)) = split_path(&path) else {
+        return Ok(());
+    };
+    let Some(broadcast) = broadcast else {
+        ahead.update(options.instance, role, instance, false);
+        return Ok(());
+    };
+    if role == Role::Caller.publish_path() && instance >= options.instance {
+        check_rival(options, instance)?;
+    } else if role == Role::Listener.publish_path() {
+        let newest = listener.as_ref().and_then(|listener| listener.instance);
+        if newest.is_none_or(|newest| instance >= newest) {
+            *listener = Some(Announced {
+                broadcast,
+                instance: Some(instance),
+            });
+        }
+    } else {
+        ahead.update(options.instance, role, instance, true);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_for_earlier_callers_in_order() {
+        let id = |started: u64| format!("{started:012x}00000000").parse().unwrap();
+        let me = id(5);
+        let mut ahead = Ahead::default();
+        ahead.update(me, "caller", id(1), true);
+        ahead.update(me, QUEUE_PATH, id(3), true);
+        // ourselves, and those who came after us, are no one to wait for.
+        ahead.update(me, QUEUE_PATH, me, true);
+        ahead.update(me, QUEUE_PATH, id(7), true);
+        ahead.update(me, "listener", id(2), true);
+        assert_eq!(ahead.position(), 3);
+
+        // the one on the line hangs up, and the first in the queue takes it.
+        ahead.update(me, "caller", id(1), false);
+        ahead.update(me, "caller", id(3), true);
+        assert_eq!(ahead.position(), 2);
+        ahead.update(me, QUEUE_PATH, id(3), false);
+        assert!(!ahead.is_empty());
+        ahead.update(me, "caller", id(3), false);
+        assert!(ahead.is_empty());
+    }
+}
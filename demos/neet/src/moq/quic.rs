@@ -0,0 +1,175 @@
+//! The QUIC client that reaches relays, built here rather than by moq-native
+//! so that its transport can be tuned: the congestion controller, initial
+//! RTT, idle timeout and keep-alive interval. Unset ones keep moq-native's
+//! values (BBR, 10 s idle timeout, 4 s keep-alive).
+//!
+//! The relay's certificate is checked against the system's roots, and the
+//! WebTransport session is set up over the connection as moq-native does.
+//! `http://` relays, local development ones whose certificate moq-native
+//! fetches by its fingerprint, still go through moq-native's client and its
+//! settings.
+//!
+//! Every relay client in the process keeps its TLS session tickets in one
+//! store, so a reconnect, or the next call to the same relay, resumes the
+//! session and sends the WebTransport request as 0-RTT early data, a round
+//! trip sooner. The tickets are not kept across runs: rustls has no way to
+//! write one out and read it back.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::debug;
+use url::{Host, Url};
+
+use super::transport::{CongestionController, Connection, IpVersion, TransportOptions};
+
+/// moq-native's idle timeout and keep-alive interval, unless configured.
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE: Duration = Duration::from_secs(4);
+/// Relays to keep session tickets for.
+const TICKET_CACHE: usize = 32;
+
+/// A QUIC endpoint and the settings it dials relays with.
+#[derive(derive_more::Debug)]
+#[debug("RelayClient")]
+pub struct RelayClient {
+    endpoint: quinn::Endpoint,
+    config: quinn::ClientConfig,
+}
+
+impl RelayClient {
+    pub fn new(options: &TransportOptions) -> Result<Self> {
+        let native = rustls_native_certs::load_native_certs();
+        for err in &native.errors {
+            debug!("skipping a system certificate: {err}");
+        }
+        let mut roots = rustls::RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(native.certs);
+        if added == 0 {
+            bail!("no usable root certificates on this system");
+        }
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![web_transport_quinn::ALPN.as_bytes().to_vec()];
+        tls.resumption = rustls::client::Resumption::store(tickets());
+        tls.enable_early_data = true;
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport_config(options)?));
+        let runtime = quinn::default_runtime().context("no async runtime")?;
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            bind_socket(options)?,
+            runtime,
+        )?;
+        Ok(Self { endpoint, config })
+    }
+
+    /// Open a WebTransport session with the relay at `url`, dialing `addr`.
+    pub async fn connect(&self, url: &Url, addr: SocketAddr) -> Result<Connection> {
+        let server_name = match url.host() {
+            Some(Host::Domain(domain)) => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            None => bail!("relay URL has no host: {url}"),
+        };
+        debug!(%addr, "dialing relay");
+        let connecting = self
+            .endpoint
+            .connect_with(self.config.clone(), addr, &server_name)?;
+        let connection = match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                let session = web_transport_quinn::Session::connect(connection, url.clone()).await;
+                if accepted.await {
+                    debug!("resumed the TLS session with 0-RTT");
+                    return session.context("WebTransport handshake failed");
+                }
+                // what went out early was dropped; start over the slow way.
+                debug!("the relay refused 0-RTT");
+                self.endpoint
+                    .connect_with(self.config.clone(), addr, &server_name)?
+                    .await?
+            }
+            Err(connecting) => connecting.await?,
+        };
+        let session = web_transport_quinn::Session::connect(connection, url.clone())
+            .await
+            .context("WebTransport handshake failed")?;
+        Ok(session)
+    }
+}
+
+/// The TLS session tickets of every relay client.
+fn tickets() -> Arc<dyn rustls::client::ClientSessionStore> {
+    static TICKETS: OnceLock<Arc<rustls::client::ClientSessionMemoryCache>> = OnceLock::new();
+    TICKETS
+        .get_or_init(|| Arc::new(rustls::client::ClientSessionMemoryCache::new(TICKET_CACHE)))
+        .clone()
+}
+
+/// The UDP socket to dial from. With `ip_version = "ipv6"` it refuses IPv4,
+/// which a wildcard IPv6 socket would otherwise reach through mapped
+/// addresses.
+fn bind_socket(options: &TransportOptions) -> Result<std::net::UdpSocket> {
+    let bind = options.bind_addr()?;
+    let socket = Socket::new(Domain::for_address(bind), Type::DGRAM, Some(Protocol::UDP))?;
+    if bind.is_ipv6() {
+        socket.set_only_v6(options.ip_version == Some(IpVersion::Ipv6))?;
+    }
+    socket
+        .bind(&bind.into())
+        .with_context(|| format!("failed to bind {bind}"))?;
+    Ok(socket.into())
+}
+
+/// The quinn transport for `options`.
+fn transport_config(options: &TransportOptions) -> Result<quinn::TransportConfig> {
+    let idle = options.max_idle_timeout.unwrap_or(MAX_IDLE_TIMEOUT);
+    let keep_alive = options.keep_alive.unwrap_or(KEEP_ALIVE);
+    if keep_alive >= idle {
+        bail!(
+            "keep-alive interval ({keep_alive:?}) must be shorter than the idle timeout ({idle:?})"
+        );
+    }
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(idle.try_into().context("idle timeout too long")?))
+        .keep_alive_interval(Some(keep_alive));
+    if let Some(rtt) = options.initial_rtt {
+        transport.initial_rtt(rtt);
+    }
+    let controller: Arc<dyn quinn::congestion::ControllerFactory + Send + Sync> =
+        match options.congestion.unwrap_or(CongestionController::Bbr) {
+            CongestionController::Bbr => Arc::new(quinn::congestion::BbrConfig::default()),
+            CongestionController::Cubic => Arc::new(quinn::congestion::CubicConfig::default()),
+            CongestionController::NewReno => Arc::new(quinn::congestion::NewRenoConfig::default()),
+        };
+    transport.congestion_controller_factory(controller);
+    Ok(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_must_come_before_the_idle_timeout() {
+        let options = |keep_alive, idle: Option<u64>| TransportOptions {
+            keep_alive: Some(Duration::from_secs(keep_alive)),
+            max_idle_timeout: idle.map(Duration::from_secs),
+            ..TransportOptions::default()
+        };
+        assert!(transport_config(&options(5, Some(5))).is_err());
+        // against moq-native's 10 s idle timeout.
+        assert!(transport_config(&options(12, None)).is_err());
+    }
+}
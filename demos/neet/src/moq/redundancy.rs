@@ -0,0 +1,202 @@
+//! Application-level redundancy in the spirit of RFC 2198 / Opus RED.
+//!
+//! While the remote reports loss above a threshold, every frame also carries
+//! copies of the previous N encoded frames, so a lost group can be recovered
+//! from a later one. Frames carrying copies set [`FLAG_REDUNDANT`] and prefix
+//! their payload with:
+//!
+//! ```text
+//! count u8 | (len u16 BE | bytes) × count, oldest first | primary payload
+//! ```
+//!
+//! The copies have sequence numbers `sequence - count ..= sequence - 1`;
+//! receivers de-duplicate them against frames already seen.
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes};
+
+use super::{
+    capability::{Capability, PeerCapabilities},
+    extension::FrameExtensions,
+    frame::{FrameArena, FrameHeader, FLAG_REDUNDANT},
+};
+use crate::{codec::BitrateTarget, stats::Gauge};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Redundancy {
+    /// Earlier frames to repeat in every frame; 0 disables redundancy.
+    pub frames: u8,
+    /// Only add redundancy while the remote reports more loss than this, in
+    /// percent. 0 means always.
+    pub loss_threshold_pct: f32,
+}
+
+/// Builds outgoing frames, adding redundancy while it is warranted.
+#[derive(Debug)]
+pub struct RedundancyEncoder {
+    options: Redundancy,
+    /// Loss the remote reports for our audio, in permille.
+    remote_loss: Gauge,
+    /// No redundancy is added while this bitrate is capped.
+    bitrate: Option<BitrateTarget>,
+    history: VecDeque<Bytes>,
+    /// Fills in the extension area of each frame.
+    extensions: FrameExtensions,
+    /// What the receiver understands, if that is known.
+    peer: Option<PeerCapabilities>,
+    /// Where the frames are built.
+    arena: FrameArena,
+}
+
+impl RedundancyEncoder {
+    pub fn new(options: Redundancy, remote_loss: Gauge) -> Self {
+        Self {
+            options,
+            remote_loss,
+            bitrate: None,
+            history: VecDeque::with_capacity(options.frames as usize),
+            extensions: FrameExtensions::default(),
+            peer: None,
+            arena: FrameArena::default(),
+        }
+    }
+
+    /// Drop redundancy while `bitrate` is capped: a data budget has no room
+    /// for repeats.
+    pub fn with_bitrate(mut self, bitrate: BitrateTarget) -> Self {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    /// Add the extensions `extensions` encode to every frame.
+    pub fn with_extensions(mut self, extensions: FrameExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Leave out what `peer` says the receiver does not understand.
+    pub fn with_peer(mut self, peer: PeerCapabilities) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    fn peer_has(&self, capability: Capability) -> bool {
+        self.peer
+            .as_ref()
+            .is_none_or(|peer| peer.contains(capability))
+    }
+
+    fn active(&self) -> bool {
+        let loss_pct = self.remote_loss.get() as f32 / 10.;
+        let capped = self.bitrate.as_ref().is_some_and(BitrateTarget::is_capped);
+        self.options.frames > 0
+            && !capped
+            && self.peer_has(Capability::Redundancy)
+            && (self.options.loss_threshold_pct == 0. || loss_pct > self.options.loss_threshold_pct)
+    }
+
+    /// Build the wire frame for `payload` and remember it for later frames.
+    pub fn encode(&mut self, header: FrameHeader, payload: Bytes) -> Bytes {
+        let extensions = match self.peer_has(Capability::Extensions) {
+            true => self.extensions.encode(&header, &payload),
+            false => Bytes::new(),
+        };
+        let mut header = header.with_extensions(extensions);
+        let frame = if self.active() && !self.history.is_empty() {
+            header.flags |= FLAG_REDUNDANT;
+            let copies: usize = self.history.iter().map(|copy| 2 + copy.len()).sum();
+            let len = header.encoded_len() + 1 + copies + payload.len();
+            let history = &self.history;
+            self.arena.build(len, |buf| {
+                header.put(buf);
+                buf.put_u8(history.len() as u8);
+                for copy in history {
+                    buf.put_u16(copy.len() as u16);
+                    buf.put_slice(copy);
+                }
+                buf.put_slice(&payload);
+            })
+        } else {
+            self.arena.encode(&header, &payload)
+        };
+        if self.options.frames > 0 {
+            if self.history.len() == self.options.frames as usize {
+                self.history.pop_front();
+            }
+            self.history.push_back(payload);
+        }
+        frame
+    }
+}
+
+/// Separate the redundant copies (with their sequence numbers, oldest first)
+/// from the primary payload of a received frame.
+pub fn split(header: &FrameHeader, mut payload: Bytes) -> Result<(Vec<(u32, Bytes)>, Bytes)> {
+    if header.flags & FLAG_REDUNDANT == 0 {
+        return Ok((Vec::new(), payload));
+    }
+    if payload.is_empty() {
+        bail!("redundant frame without copy count");
+    }
+    let count = payload.get_u8() as u32;
+    let mut copies = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        if payload.len() < 2 {
+            bail!("truncated redundant copy length");
+        }
+        let len = payload.get_u16() as usize;
+        if payload.len() < len {
+            bail!("truncated redundant copy");
+        }
+        let sequence = header.sequence.wrapping_sub(count - i);
+        copies.push((sequence, payload.split_to(len)));
+    }
+    Ok((copies, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_carry_previous_payloads() {
+        let options = Redundancy {
+            frames: 2,
+            loss_threshold_pct: 0.,
+        };
+        let mut encoder = RedundancyEncoder::new(options, Gauge::default());
+        let frames: Vec<Bytes> = (0..4u8)
+            .map(|i| encoder.encode(FrameHeader::new(i as u32), Bytes::from(vec![i; 3])))
+            .collect();
+
+        let (header, payload) = FrameHeader::decode(frames[0].clone()).unwrap();
+        assert_eq!(header.flags, 0);
+        assert_eq!(split(&header, payload).unwrap().0, vec![]);
+
+        let (header, payload) = FrameHeader::decode(frames[3].clone()).unwrap();
+        let (copies, primary) = split(&header, payload).unwrap();
+        assert_eq!(
+            copies,
+            vec![(1, Bytes::from(vec![1; 3])), (2, Bytes::from(vec![2; 3]))]
+        );
+        assert_eq!(primary, Bytes::from(vec![3; 3]));
+    }
+
+    #[test]
+    fn redundancy_follows_reported_loss() {
+        let options = Redundancy {
+            frames: 1,
+            loss_threshold_pct: 5.,
+        };
+        let loss = Gauge::default();
+        let mut encoder = RedundancyEncoder::new(options, loss.clone());
+        let flags = |frame: Bytes| FrameHeader::decode(frame).unwrap().0.flags;
+        let mut encode = |sequence, payload| encoder.encode(FrameHeader::new(sequence), payload);
+        assert_eq!(flags(encode(0, Bytes::from_static(b"a"))), 0);
+        assert_eq!(flags(encode(1, Bytes::from_static(b"b"))), 0);
+        loss.set(100);
+        assert_eq!(flags(encode(2, Bytes::from_static(b"c"))), FLAG_REDUNDANT);
+    }
+}
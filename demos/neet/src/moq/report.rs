@@ -0,0 +1,237 @@
+//! Periodic receiver reports, the RTCP analogue.
+//!
+//! Each peer publishes a `report` track next to its audio describing how it
+//! receives the remote's audio. The remote uses the reports to adapt its
+//! encoder bitrate and to warn its user when they are being received poorly.
+//! A receiver on a data budget also asks for a bitrate the remote must not
+//! exceed.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use moq_lite as moq;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::next_group;
+use crate::{
+    codec::BitrateTarget,
+    stats::{LossWindow, Stats},
+};
+
+pub const REPORT_TRACK_NAME: &str = "report";
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Above either of these the remote is considered to receive us poorly.
+const POOR_LOSS_PCT: f32 = 5.;
+const POOR_JITTER_MS: f32 = 40.;
+/// Below this loss the bitrate is allowed to recover.
+const GOOD_LOSS_PCT: f32 = 1.;
+
+pub(super) const MIN_BITRATE: u32 = 12_000;
+pub(super) const MAX_BITRATE: u32 = 64_000;
+const BITRATE_STEP: u32 = 4_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReceiverReport {
+    /// Frames lost in the last interval, in percent of those sent.
+    pub loss_pct: f32,
+    pub jitter_ms: f32,
+    /// Decoded audio waiting to be played.
+    pub buffer_ms: f32,
+    /// The most the receiver wants us to send, in bits per second.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bitrate: Option<u32>,
+}
+
+impl ReceiverReport {
+    fn is_poor(&self) -> bool {
+        self.loss_pct > POOR_LOSS_PCT || self.jitter_ms > POOR_JITTER_MS
+    }
+}
+
+/// Turns the cumulative receive counters into per-interval reports.
+#[derive(Debug, Default)]
+struct ReportBuilder {
+    loss: LossWindow,
+}
+
+impl ReportBuilder {
+    fn build(&mut self, stats: &Stats) -> ReceiverReport {
+        ReceiverReport {
+            loss_pct: self.loss.loss_pct(stats),
+            jitter_ms: stats.jitter_us.get() as f32 / 1000.,
+            buffer_ms: stats.playback_buffer_us.get() as f32 / 1000.,
+            max_bitrate: Some(stats.receive_limit_bps.get() as u32).filter(|&limit| limit != 0),
+        }
+    }
+}
+
+/// Additive-increase/multiplicative-decrease bitrate control driven by the
+/// remote's reports.
+#[derive(Debug)]
+pub struct BitrateController {
+    target: BitrateTarget,
+    bitrate: u32,
+    max_bitrate: u32,
+}
+
+impl BitrateController {
+    /// Starts from the bitrate already requested of `target`, if any, up to
+    /// the maximum.
+    pub fn new(target: BitrateTarget) -> Self {
+        Self {
+            target,
+            bitrate: MAX_BITRATE,
+            max_bitrate: MAX_BITRATE,
+        }
+        .with_max_bitrate(MAX_BITRATE)
+    }
+
+    /// Never raise the bitrate above `bits_per_second`, and start there at
+    /// most.
+    pub fn with_max_bitrate(mut self, bits_per_second: u32) -> Self {
+        self.max_bitrate = bits_per_second;
+        self.bitrate = match self.target.requested() {
+            0 => bits_per_second,
+            requested => requested.min(bits_per_second),
+        };
+        self
+    }
+
+    fn update(&mut self, report: &ReceiverReport) {
+        let bitrate = if report.is_poor() {
+            (self.bitrate * 3 / 4).max(MIN_BITRATE)
+        } else if report.loss_pct < GOOD_LOSS_PCT {
+            (self.bitrate + BITRATE_STEP).min(self.max_bitrate)
+        } else {
+            self.bitrate
+        };
+        let bitrate = match report.max_bitrate {
+            Some(limit) => bitrate.min(limit.max(MIN_BITRATE)),
+            None => bitrate,
+        };
+        if bitrate != self.bitrate {
+            debug!(
+                from = self.bitrate,
+                to = bitrate,
+                "adapting encoder bitrate"
+            );
+            self.bitrate = bitrate;
+            self.target.set(bitrate);
+        }
+    }
+}
+
+/// Publish a report about the audio we receive every [`REPORT_INTERVAL`].
+pub async fn publish_reports(mut track: moq::TrackProducer, stats: Stats) -> Result<()> {
+    let mut builder = ReportBuilder::default();
+    let mut interval = tokio::time::interval(REPORT_INTERVAL);
+    // the first tick completes immediately, before anything was received.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let report = builder.build(&stats);
+        let payload = Bytes::from(serde_json::to_vec(&report)?);
+        let mut group = track.append_group();
+        let mut frame = group.create_frame(moq::Frame {
+            size: payload.len() as u64,
+        });
+        frame.write_chunk(payload);
+        frame.close();
+        group.close();
+    }
+}
+
+/// Read the remote's reports about our audio, adapt the bitrate and warn when
+/// it is receiving us poorly.
+pub async fn consume_reports(
+    mut track: moq::TrackConsumer,
+    mut controller: BitrateController,
+    stats: Stats,
+) -> Result<()> {
+    let mut poor = false;
+    while let Some(mut group) = next_group(&mut track).await? {
+        let Ok(Some(payload)) = group.read_frame().await else {
+            continue;
+        };
+        let report: ReceiverReport = serde_json::from_slice(&payload)
+            .map_err(|err| anyhow!(err).context("malformed receiver report"))?;
+        debug!(?report, "receiver report from remote");
+        stats
+            .remote_loss_permille
+            .set((report.loss_pct * 10.).round() as u64);
+        stats
+            .remote_buffer_us
+            .set((report.buffer_ms * 1000.).round() as u64);
+        stats.remote_reports.add(1);
+        controller.update(&report);
+        match (poor, report.is_poor()) {
+            (false, true) => warn!(
+                loss_pct = report.loss_pct,
+                jitter_ms = report.jitter_ms,
+                "remote is receiving you poorly"
+            ),
+            (true, false) => info!("remote reception recovered"),
+            _ => {}
+        }
+        poor = report.is_poor();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(loss_pct: f32) -> ReceiverReport {
+        ReceiverReport {
+            loss_pct,
+            jitter_ms: 5.,
+            buffer_ms: 40.,
+            max_bitrate: None,
+        }
+    }
+
+    #[test]
+    fn bitrate_backs_off_on_loss_and_recovers() {
+        let target = BitrateTarget::default();
+        let mut controller = BitrateController::new(target.clone());
+        controller.update(&report(0.));
+        assert_eq!(target.get(), 0, "no change at full bitrate");
+        controller.update(&report(10.));
+        assert_eq!(target.get(), 48_000);
+        for _ in 0..20 {
+            controller.update(&report(20.));
+        }
+        assert_eq!(target.get(), MIN_BITRATE);
+        controller.update(&report(0.));
+        assert_eq!(target.get(), MIN_BITRATE + BITRATE_STEP);
+
+        let capped = ReceiverReport {
+            max_bitrate: Some(14_000),
+            ..report(0.)
+        };
+        controller.update(&capped);
+        assert_eq!(target.get(), 14_000, "the receiver's limit holds");
+
+        // music asks for more than voice may have.
+        let target = BitrateTarget::default();
+        target.set(128_000);
+        let mut controller = BitrateController::new(target.clone()).with_max_bitrate(160_000);
+        controller.update(&report(10.));
+        assert_eq!(target.get(), 96_000, "backs off from where music started");
+    }
+
+    #[test]
+    fn report_loss_is_per_interval() {
+        let stats = Stats::default();
+        let mut builder = ReportBuilder::default();
+        stats.received_frames.add(90);
+        stats.received_lost.add(10);
+        assert_eq!(builder.build(&stats).loss_pct, 10.);
+        stats.received_frames.add(50);
+        assert_eq!(builder.build(&stats).loss_pct, 0.);
+    }
+}
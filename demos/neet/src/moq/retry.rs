@@ -0,0 +1,61 @@
+//! Backing off between attempts to reach a relay.
+//!
+//! The delay doubles after every failure up to a cap, and each wait is drawn
+//! at random from its upper half, so that clients the same outage dropped do
+//! not all come back in the same instant.
+
+use std::time::Duration;
+
+/// Exponentially growing, jittered delays.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    next: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            next: initial.min(max),
+            max,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.next;
+        self.next = (self.next * 2).min(self.max);
+        let mut random = [0; 4];
+        // without randomness, waiting the full delay is still a backoff.
+        let fraction = match getrandom::getrandom(&mut random) {
+            Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+            Err(_) => 1.,
+        };
+        ceiling.mul_f64(0.5 + fraction / 2.)
+    }
+
+    /// Start over from the initial delay, e.g. after a connection held.
+    pub fn reset(&mut self) {
+        self.next = self.initial.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_with_jitter_up_to_the_cap() {
+        let second = Duration::from_secs(1);
+        let mut backoff = Backoff::new(second, 8 * second);
+        for ceiling in [1, 2, 4, 8, 8, 8] {
+            let delay = backoff.next_delay();
+            let ceiling = ceiling * second;
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= second);
+    }
+}
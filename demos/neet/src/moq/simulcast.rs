@@ -0,0 +1,177 @@
+//! Simulcast: the same audio published at several bitrates.
+//!
+//! The publisher encodes capture once per [`LAYERS`] entry and publishes each
+//! as its own track. The subscriber only subscribes to one layer at a time and
+//! moves between them based on the loss and jitter it measures: a switch
+//! subscribes to the new layer and takes over at its first complete group, then
+//! drops the old subscription so the relay stops sending it.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use moq_lite as moq;
+use tokio::{select, sync::mpsc, task::JoinHandle};
+use tracing::{debug, info};
+
+use super::{next_group, IncomingFrames, TrackPriorities};
+use crate::{media::TrackKind, stats::LossWindow};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layer {
+    pub name: &'static str,
+    pub bitrate: u32,
+}
+
+/// Highest quality first.
+pub const LAYERS: [Layer; 2] = [
+    Layer {
+        name: "audio-hi",
+        bitrate: 64_000,
+    },
+    Layer {
+        name: "audio-lo",
+        bitrate: 16_000,
+    },
+];
+
+const SELECT_INTERVAL: Duration = Duration::from_secs(1);
+const DOWN_LOSS_PCT: f32 = 5.;
+const DOWN_JITTER_MS: f32 = 40.;
+const UP_LOSS_PCT: f32 = 1.;
+/// Consecutive bad intervals before switching down.
+const DOWN_AFTER: u32 = 2;
+/// Consecutive good intervals before probing the next layer up.
+const UP_AFTER: u32 = 10;
+
+/// Decides which layer to receive from per-interval measurements.
+#[derive(Debug, Default)]
+pub struct LayerSelector {
+    /// Index into [`LAYERS`].
+    current: usize,
+    bad: u32,
+    good: u32,
+}
+
+impl LayerSelector {
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Feed one interval's measurements; returns the layer to switch to, if any.
+    pub fn update(&mut self, loss_pct: f32, jitter_ms: f32) -> Option<usize> {
+        if loss_pct > DOWN_LOSS_PCT || jitter_ms > DOWN_JITTER_MS {
+            self.good = 0;
+            self.bad += 1;
+            if self.bad >= DOWN_AFTER && self.current + 1 < LAYERS.len() {
+                self.bad = 0;
+                self.current += 1;
+                return Some(self.current);
+            }
+        } else if loss_pct < UP_LOSS_PCT {
+            self.bad = 0;
+            self.good += 1;
+            if self.good >= UP_AFTER && self.current > 0 {
+                self.good = 0;
+                self.current -= 1;
+                return Some(self.current);
+            }
+        } else {
+            self.bad = 0;
+            self.good = 0;
+        }
+        None
+    }
+}
+
+/// Receive a simulcast broadcast, following the layer the selector picks.
+pub async fn receive_simulcast(
+    broadcast: moq::BroadcastConsumer,
+    priorities: &TrackPriorities,
+    incoming: &mut IncomingFrames,
+) -> Result<()> {
+    let (groups_tx, mut groups_rx) = mpsc::channel(8);
+    let subscribe = |index: usize| -> JoinHandle<Result<()>> {
+        let track = priorities.track(LAYERS[index].name, TrackKind::Audio);
+        let mut track = broadcast.subscribe_track(&track);
+        let groups_tx = groups_tx.clone();
+        tokio::spawn(async move {
+            while let Some(group) = next_group(&mut track).await? {
+                if groups_tx.send((index, group)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let mut selector = LayerSelector::default();
+    let mut active = (selector.current(), subscribe(selector.current()));
+    let mut pending: Option<(usize, JoinHandle<Result<()>>)> = None;
+    let mut window = LossWindow::default();
+    let mut interval = tokio::time::interval(SELECT_INTERVAL);
+    info!(layer = LAYERS[active.0].name, "receiving simulcast layer");
+
+    loop {
+        select! {
+            _ = interval.tick() => {
+                let loss_pct = window.loss_pct(&incoming.stats);
+                let jitter_ms = incoming.stats.jitter_us.get() as f32 / 1000.;
+                if let Some(next) = selector.update(loss_pct, jitter_ms) {
+                    debug!(loss_pct, jitter_ms, to = LAYERS[next].name, "switching simulcast layer");
+                    if let Some((_, task)) = pending.take() {
+                        task.abort();
+                    }
+                    pending = (next != active.0).then(|| (next, subscribe(next)));
+                }
+            }
+            group = groups_rx.recv() => {
+                let Some((index, mut group)) = group else { break };
+                if pending.as_ref().is_some_and(|(next, _)| *next == index) {
+                    // the new layer has a group ready: switch at this boundary.
+                    let (next, task) = pending.take().unwrap();
+                    let (_, old) = std::mem::replace(&mut active, (next, task));
+                    old.abort();
+                    incoming.reset();
+                    info!(layer = LAYERS[next].name, "receiving simulcast layer");
+                } else if index != active.0 {
+                    continue;
+                }
+                while let Ok(Some(payload)) = group.read_frame().await {
+                    incoming.deliver(payload).await;
+                }
+            }
+        }
+        if incoming.ended || active.1.is_finished() {
+            break;
+        }
+    }
+    if let Some((_, task)) = pending {
+        task.abort();
+    }
+    if incoming.ended {
+        active.1.abort();
+        return Ok(());
+    }
+    active.1.await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selector_steps_down_quickly_and_up_slowly() {
+        let mut selector = LayerSelector::default();
+        assert_eq!(selector.update(10., 0.), None);
+        assert_eq!(selector.update(10., 0.), Some(1));
+        assert_eq!(
+            selector.update(10., 0.),
+            None,
+            "already at the lowest layer"
+        );
+        for _ in 0..UP_AFTER - 1 {
+            assert_eq!(selector.update(0., 5.), None);
+        }
+        assert_eq!(selector.update(0., 5.), Some(0));
+    }
+}
@@ -0,0 +1,151 @@
+//! Warm standby connections (`--preconnect`).
+//!
+//! A call normally opens its QUIC connection and MoQ session when it starts
+//! and then listens a moment for who is already in the session, which adds up
+//! to a second or more before the first audio. A [`Standby`] keeps a session
+//! to one target open ahead of time, QUIC keep-alives holding it up, and
+//! follows the announcements on it. A call that starts there takes it over,
+//! already knowing who is announced, and the standby opens the next one in
+//! the background for the call after.
+
+use std::{future::Future, sync::Arc};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use super::{
+    media_transport::{MediaTransport, MoqRelay},
+    retry::Backoff,
+    transport::Transport,
+    MAX_RECONNECT_BACKOFF, RECONNECT_BACKOFF,
+};
+use crate::stats::ConnectionStats;
+
+type Request = oneshot::Sender<MoqRelay>;
+
+/// A connection kept open for the next call to one target. Clones share it;
+/// it closes once the last is dropped.
+#[derive(Debug, Clone)]
+pub struct Standby {
+    target: String,
+    requests: mpsc::UnboundedSender<Request>,
+}
+
+impl Standby {
+    /// Connect over `route` now, and again whenever the connection drops or
+    /// a call takes it.
+    pub fn spawn(route: Arc<dyn Transport>) -> Self {
+        let target = route.target();
+        let (requests, receiver) = mpsc::unbounded_channel();
+        info!(target, "keeping a standby connection");
+        tokio::spawn(keep_warm(route, receiver));
+        Self { target, requests }
+    }
+
+    /// Where it leads, to tell which calls it can serve.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The warm connection, if one is up right now.
+    pub async fn take(&self) -> Option<MoqRelay> {
+        let (reply, relay) = oneshot::channel();
+        self.requests.send(reply).ok()?;
+        relay.await.ok()
+    }
+}
+
+async fn keep_warm(route: Arc<dyn Transport>, mut requests: mpsc::UnboundedReceiver<Request>) {
+    let target = route.target();
+    let mut backoff = Backoff::new(RECONNECT_BACKOFF, MAX_RECONNECT_BACKOFF);
+    loop {
+        let connect = MoqRelay::connect(&*route, ConnectionStats::default());
+        let relay = match unwarmed(connect, &mut requests).await {
+            Some(Ok(relay)) => relay,
+            Some(Err(err)) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    target,
+                    "standby connection failed: {err:#}; retrying in {:.1}s",
+                    delay.as_secs_f32()
+                );
+                match unwarmed(tokio::time::sleep(delay), &mut requests).await {
+                    Some(()) => continue,
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        backoff.reset();
+        debug!(target, "standby connection ready");
+        let request = tokio::select! {
+            request = requests.recv() => request,
+            err = relay.closed() => {
+                warn!(target, "standby connection dropped: {err:#}");
+                continue;
+            }
+        };
+        match request {
+            Some(reply) => {
+                debug!(target, "handing over the standby connection");
+                // a caller that gave up leaves it to be dropped.
+                let _ = reply.send(relay);
+            }
+            None => {
+                Box::new(relay).close();
+                return;
+            }
+        }
+    }
+}
+
+/// Drive `future` while there is no connection to hand out, turning every
+/// request away. `None` once nobody can ask any more.
+async fn unwarmed<F: Future>(
+    future: F,
+    requests: &mut mpsc::UnboundedReceiver<Request>,
+) -> Option<F::Output> {
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            output = &mut future => return Some(output),
+            // dropping the reply tells the call to connect itself.
+            request = requests.recv() => {
+                request?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::moq::transport::Opening;
+
+    #[derive(Debug)]
+    struct Unreachable;
+
+    impl Transport for Unreachable {
+        fn open(&self) -> Opening<'_> {
+            Box::pin(async { Err(anyhow!("unreachable")) })
+        }
+
+        fn target(&self) -> String {
+            "https://relay.invalid/neet/demo".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_do_not_wait_for_a_standby_that_is_down() {
+        let standby = Standby::spawn(Arc::new(Unreachable));
+        assert_eq!(standby.target(), "https://relay.invalid/neet/demo");
+        for _ in 0..3 {
+            let taken = tokio::time::timeout(Duration::from_secs(1), standby.take()).await;
+            assert!(taken.unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Traces of what a call received (`--dump-trace`), and their replay
+//! (`neet replay`), so that a decoding or jitter buffer bug a user ran into
+//! can be reproduced from their trace alone.
+//!
+//! A trace is a file of JSON lines. Each time receiving the remote's voice
+//! track starts, a `track` line names its codec and, if the remote announced
+//! one, the epoch its frame timestamps count from; then each frame is written
+//! as it came off the network, header and all, with its arrival. Times are in
+//! microseconds since the trace began, the epoch's negative if it was before:
+//!
+//! ```text
+//! {"type":"track","at_us":0,"epoch_us":-5012,"codec":"opus","layout":"stereo"}
+//! {"type":"frame","at_us":20113,"frame":"00000001..."}
+//! ```
+//!
+//! Replay hands the frames to the same frame handling, jitter buffer and
+//! decoder a call does, ticking them on a clock of its own instead of the
+//! wall clock, so that the audio comes out the same on every run and however
+//! fast the machine is. The epoch is placed on that clock too, so the one-way
+//! delay, and with it `--latency-budget`, plays out as it did on the call.
+//! The audio is written to a WAV file.
+
+use std::{
+    fs::{self, File},
+    io::{LineWriter, Write},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{catalog::AudioEntry, epoch::CallEpoch, IncomingFrames};
+use crate::{
+    codec::{
+        opus::{MediaTrackOpusDecoder, OPUS_STREAM_PARAMS},
+        Codec,
+    },
+    media::{self, MediaTrack, OverflowPolicy, TrackKind},
+    stats::Stats,
+};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TraceLine {
+    /// Receiving started, with audio in this codec.
+    Track {
+        at_us: u64,
+        /// When the remote's epoch was, if it announced one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        epoch_us: Option<i64>,
+        #[serde(flatten)]
+        audio: AudioEntry,
+    },
+    /// A frame as it arrived, in hex.
+    Frame { at_us: u64, frame: String },
+}
+
+impl TraceLine {
+    fn at(&self) -> Duration {
+        match self {
+            TraceLine::Track { at_us, .. } | TraceLine::Frame { at_us, .. } => {
+                Duration::from_micros(*at_us)
+            }
+        }
+    }
+}
+
+/// Writes what a call receives to a trace file. Cloned into each receive
+/// loop of the call, which all write to the same file.
+#[derive(derive_more::Debug, Clone)]
+pub struct FrameTrace {
+    path: PathBuf,
+    start: Instant,
+    /// `None` once writing failed.
+    #[debug(skip)]
+    file: Arc<Mutex<Option<LineWriter<File>>>>,
+}
+
+impl FrameTrace {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create trace {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            start: Instant::now(),
+            file: Arc::new(Mutex::new(Some(LineWriter::new(file)))),
+        })
+    }
+
+    fn since_start(&self, instant: Instant) -> u64 {
+        instant.saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    /// Receiving starts over, with audio in `codec` stamped against `epoch`.
+    pub(super) fn track(&self, codec: Codec, epoch: Option<CallEpoch>) {
+        let epoch_us = epoch.map(
+            |epoch| match epoch.start().checked_duration_since(self.start) {
+                Some(after) => after.as_micros() as i64,
+                None => -(self.start.duration_since(epoch.start()).as_micros() as i64),
+            },
+        );
+        self.write(&TraceLine::Track {
+            at_us: self.since_start(Instant::now()),
+            epoch_us,
+            audio: AudioEntry::new(codec),
+        });
+    }
+
+    /// `frame` arrived at `arrival`.
+    pub(super) fn frame(&self, frame: &[u8], arrival: Instant) {
+        self.write(&TraceLine::Frame {
+            at_us: self.since_start(arrival),
+            frame: hex::encode(frame),
+        });
+    }
+
+    /// Append `line`. A trace that can't be written is given up on, never
+    /// the call.
+    fn write(&self, line: &TraceLine) {
+        let mut file = self.file.lock().unwrap();
+        let Some(writer) = file.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_string(line)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(writeln!(writer, "{json}")?));
+        if let Err(err) = written {
+            warn!(path = %self.path.display(), "stopped writing the trace: {err:#}");
+            *file = None;
+        }
+    }
+}
+
+/// The lines of the trace at `path`. Lines that do not parse, such as the
+/// last one of a trace whose call crashed, are skipped.
+fn read_trace(path: &Path) -> Result<Vec<TraceLine>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read trace {}", path.display()))?;
+    Ok(text
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| match serde_json::from_str(line) {
+            Ok(line) => Some(line),
+            Err(err) => {
+                warn!(line = index + 1, %err, "skipping trace line");
+                None
+            }
+        })
+        .collect())
+}
+
+/// How to play a trace back, as the receiving end of the call was set up.
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub trace: PathBuf,
+    /// The WAV file to write the audio to.
+    pub output: PathBuf,
+    /// The playback loop's interval.
+    pub tick: Duration,
+    pub playout_delay: Duration,
+    pub latency_budget: Option<Duration>,
+    /// Frames between the network and the decoder.
+    pub queue_frames: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// Play the trace in `options` back into a WAV file, and return the
+/// statistics receiving it kept.
+pub async fn replay_trace(options: &ReplayOptions) -> Result<Stats> {
+    let mut lines = read_trace(&options.trace)?.into_iter().peekable();
+    let Some(TraceLine::Track {
+        epoch_us, audio, ..
+    }) = lines.next()
+    else {
+        bail!("{} does not start with a track", options.trace.display());
+    };
+    let codec = audio.codec()?;
+    let stats = Stats::default();
+    let (sender, receiver) = media::channel(
+        options.queue_frames,
+        options.overflow,
+        stats.playback_dropped.clone(),
+    );
+    let decoder = MediaTrackOpusDecoder::new(MediaTrack::new(receiver, codec, TrackKind::Audio))?
+        .with_buffer_gauge(stats.playback_buffer_us.clone())
+        .with_playout_delay(options.playout_delay)
+        .with_stall_counter(stats.playback_xruns.resets.clone());
+    let mut decoder = match options.latency_budget {
+        Some(limit) => decoder.with_latency_budget(
+            limit,
+            stats.one_way_delay_us.clone(),
+            stats.resyncs.clone(),
+            stats.resync_skipped_us.clone(),
+        ),
+        None => decoder.without_latency_budget(),
+    };
+    let start = Instant::now();
+    let mut incoming = Some(
+        IncomingFrames::new(sender, stats.clone())
+            .with_epoch(epoch_us.map(|epoch_us| replay_epoch(start, epoch_us))),
+    );
+
+    let spec = WavSpec {
+        channels: OPUS_STREAM_PARAMS.channel_count,
+        sample_rate: OPUS_STREAM_PARAMS.sample_rate.0,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut wav = WavWriter::create(&options.output, spec)
+        .with_context(|| format!("failed to create {}", options.output.display()))?;
+    let mut buf = vec![0.; OPUS_STREAM_PARAMS.sample_count(options.tick)];
+    let mut now = Duration::ZERO;
+    loop {
+        if let Some(receiving) = &mut incoming {
+            while let Some(line) = lines.next_if(|line| line.at() <= now) {
+                match line {
+                    TraceLine::Track {
+                        epoch_us, audio, ..
+                    } => {
+                        ensure!(audio.codec()? == codec, "the trace changes codec");
+                        receiving.reset();
+                        receiving.epoch = epoch_us.map(|epoch_us| replay_epoch(start, epoch_us));
+                    }
+                    TraceLine::Frame { at_us, frame } => {
+                        let frame = hex::decode(frame).context("frame is not hex")?;
+                        let arrival = start + Duration::from_micros(at_us);
+                        receiving.deliver_at(Bytes::from(frame), arrival).await;
+                    }
+                }
+            }
+            // the decoder plays out what it has once the sender is gone.
+            if receiving.ended || lines.peek().is_none() {
+                incoming = None;
+            }
+        }
+        match decoder.tick_at(&mut buf, start + now)? {
+            ControlFlow::Continue(count) => {
+                // what the decoder did not fill is silence to the speaker.
+                buf[count..].fill(0.);
+                for sample in &buf {
+                    wav.write_sample(*sample)?;
+                }
+            }
+            ControlFlow::Break(()) => break,
+        }
+        now += options.tick;
+    }
+    wav.finalize()?;
+    Ok(stats)
+}
+
+/// The epoch a trace recorded at `epoch_us`, on a replay clock that started
+/// at `start`.
+fn replay_epoch(start: Instant, epoch_us: i64) -> CallEpoch {
+    let offset = Duration::from_micros(epoch_us.unsigned_abs());
+    CallEpoch::from_instant(if epoch_us < 0 {
+        start.checked_sub(offset).unwrap_or(start)
+    } else {
+        start + offset
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio::AudioMode, codec::opus::OpusChannels, moq::FrameHeader};
+
+    #[tokio::test]
+    async fn replays_a_trace_the_same_every_time() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let path = dir.join(format!("neet-trace-{id}.jsonl"));
+        let codec = Codec::Opus {
+            channels: OpusChannels::Stereo,
+        };
+        let trace = FrameTrace::create(&path).unwrap();
+        // the remote started its epoch 50 ms before we started receiving.
+        let epoch = CallEpoch::from_instant(trace.start - Duration::from_millis(50));
+        trace.track(codec, Some(epoch));
+        let mut encoder = codec.encoder(AudioMode::Voice).unwrap();
+        let tone: Vec<f32> = (0..encoder.frame_samples())
+            .map(|i| (i as f32 / 20.).sin() * 0.3)
+            .collect();
+        // frame 3 arrives after frame 4, late for the jitter buffer.
+        for (sequence, at_ms) in [(0, 0), (1, 20), (2, 40), (4, 80), (3, 85), (5, 100)] {
+            let payload = encoder.encode(&tone).unwrap();
+            let frame = FrameHeader::new(sequence)
+                .with_timestamp(sequence * 960)
+                .encode(&payload);
+            trace.frame(&frame, trace.start + Duration::from_millis(at_ms));
+        }
+        drop(trace);
+
+        let replay = |output: &str| ReplayOptions {
+            trace: path.clone(),
+            output: dir.join(format!("neet-replay-{id}-{output}.wav")),
+            tick: Duration::from_millis(20),
+            playout_delay: Duration::from_millis(40),
+            latency_budget: None,
+            queue_frames: 32,
+            overflow: OverflowPolicy::default(),
+        };
+        let (first, second) = (replay("a"), replay("b"));
+        let stats = replay_trace(&first).await.unwrap();
+        replay_trace(&second).await.unwrap();
+        let audio = fs::read(&first.output).unwrap();
+        assert_eq!(audio, fs::read(&second.output).unwrap());
+        for file in [&path, &first.output, &second.output] {
+            fs::remove_file(file).unwrap();
+        }
+
+        assert_eq!(stats.received_frames.get(), 5);
+        assert_eq!(stats.received_lost.get(), 1);
+        assert_eq!(stats.received_late.get(), 1);
+        // frame 5 was captured 100 ms into the epoch and arrived 100 ms into
+        // the trace.
+        assert_eq!(stats.one_way_delay_us.get(), 50_000);
+    }
+}
@@ -0,0 +1,344 @@
+//! How a call reaches the other side, and QUIC transport tuning.
+//!
+//! A [`Transport`] opens the QUIC connection a call's MoQ session runs on:
+//! [`RelayTransport`] dials a relay, and the transports in [`super::direct`]
+//! connect two peers without one.
+//!
+//! Relays are dialed with the client in [`super::quic`], which takes the
+//! QUIC transport knobs. moq-native 0.8 builds its quinn `TransportConfig`
+//! internally (BBR, 10 s idle timeout, 4 s keep-alive), so direct calls and
+//! `http://` relays, which go through its client, keep those and say so.
+//!
+//! A transport builds its QUIC client on the first connection and keeps it
+//! for the next ones, so that a reconnect resumes the TLS session from the
+//! ticket rustls cached instead of a full handshake. Relays also get 0-RTT
+//! (see [`super::quic`]); moq-native 0.8, which direct calls go through,
+//! doesn't enable early data.
+//!
+//! Reaching the relay is retried `connect_retries` times, or until it answers
+//! with `wait_for_relay`, with a jittered exponential [`Backoff`] in between.
+//!
+//! Proxies are not supported: QUIC runs over UDP, which HTTP CONNECT cannot
+//! carry, and going through SOCKS5 would take a UDP-associate socket of our
+//! own under quinn, which is left out.
+
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use url::{Host, Url};
+
+use super::{append_session_path, quic::RelayClient, retry::Backoff, MAX_RECONNECT_BACKOFF};
+
+/// The first wait between attempts to reach the relay, unless configured.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// An established QUIC connection, to a relay or a peer.
+pub type Connection = moq_native::web_transport_quinn::Session;
+
+/// Which end of the MoQ handshake we play on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// A connection being opened.
+pub type Opening<'a> = Pin<Box<dyn Future<Output = Result<(Connection, Side)>> + Send + 'a>>;
+
+/// How a call reaches the other side.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Open the connection for one call (again, after it dropped, with
+    /// `--auto-reconnect`).
+    fn open(&self) -> Opening<'_>;
+
+    /// Where it leads, for the log.
+    fn target(&self) -> String;
+}
+
+/// A QUIC client, shared by every connection of one transport.
+#[derive(derive_more::Debug, Clone, Default)]
+#[debug("SharedClient")]
+pub struct SharedClient(Arc<OnceLock<moq_native::Client>>);
+
+impl SharedClient {
+    /// The client, built with `config` unless an earlier connection did.
+    pub fn get(
+        &self,
+        config: impl FnOnce() -> Result<moq_native::ClientConfig>,
+    ) -> Result<&moq_native::Client> {
+        if let Some(client) = self.0.get() {
+            debug!("reusing the QUIC client, to resume its TLS session");
+            return Ok(client);
+        }
+        let client = moq_native::Client::new(config()?).context("failed to build MoQ client")?;
+        Ok(self.0.get_or_init(|| client))
+    }
+}
+
+/// Through a MoQ relay, under the session's path.
+#[derive(Debug, Clone)]
+pub struct RelayTransport {
+    url: Url,
+    options: TransportOptions,
+    client: Arc<OnceLock<RelayClient>>,
+    /// moq-native's client, for `http://` relays.
+    native: SharedClient,
+}
+
+impl RelayTransport {
+    pub fn new(relay_url: &Url, session_id: &str, options: TransportOptions) -> Result<Self> {
+        let mut url = relay_url.clone();
+        append_session_path(&mut url, session_id).with_context(|| {
+            format!("failed to extend relay url with session '{session_id}': {url}")
+        })?;
+        Ok(Self {
+            url,
+            options,
+            client: Arc::default(),
+            native: SharedClient::default(),
+        })
+    }
+}
+
+impl RelayTransport {
+    async fn connect(&self) -> Result<Connection> {
+        let addr = self.options.resolve_relay(&self.url).await?;
+        let connection = if self.url.scheme() == "http" {
+            // moq-native resolves the host again, but its socket is of our
+            // family.
+            let client = self.native.get(|| self.options.client_config())?;
+            client.connect(self.url.clone()).await
+        } else {
+            self.client()?.connect(&self.url, addr).await
+        };
+        connection.context("failed to connect to relay")
+    }
+
+    /// The QUIC client, built on the first connection and kept for the next
+    /// ones, like a [`SharedClient`].
+    fn client(&self) -> Result<&RelayClient> {
+        if let Some(client) = self.client.get() {
+            return Ok(client);
+        }
+        let client = RelayClient::new(&self.options).context("failed to build QUIC client")?;
+        Ok(self.client.get_or_init(|| client))
+    }
+}
+
+impl Transport for RelayTransport {
+    fn open(&self) -> Opening<'_> {
+        Box::pin(async move {
+            info!(url = %self.url, "connecting to relay");
+            let mut backoff = Backoff::new(
+                self.options.retry_backoff.unwrap_or(RETRY_BACKOFF),
+                MAX_RECONNECT_BACKOFF,
+            );
+            let mut retries = 0;
+            loop {
+                let err = match self.connect().await {
+                    Ok(connection) => return Ok((connection, Side::Client)),
+                    Err(err) => err,
+                };
+                if !self.options.wait_for_relay
+                    && retries >= self.options.connect_retries.unwrap_or(0)
+                {
+                    return Err(err);
+                }
+                retries += 1;
+                let delay = backoff.next_delay();
+                warn!(
+                    url = %self.url,
+                    "{err:#}; retrying in {:.1}s",
+                    delay.as_secs_f32()
+                );
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+
+    fn target(&self) -> String {
+        self.url.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CongestionController {
+    Bbr,
+    Cubic,
+    NewReno,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    Ipv4,
+    Ipv6,
+}
+
+impl IpVersion {
+    fn matches(self, ip: IpAddr) -> bool {
+        match self {
+            IpVersion::Ipv4 => ip.is_ipv4(),
+            IpVersion::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransportOptions {
+    /// Local UDP address to send from; picks the interface on multi-homed hosts.
+    pub bind: Option<SocketAddr>,
+    /// Only use this address family to reach the relay.
+    pub ip_version: Option<IpVersion>,
+    pub congestion: Option<CongestionController>,
+    pub initial_rtt: Option<Duration>,
+    pub max_idle_timeout: Option<Duration>,
+    pub keep_alive: Option<Duration>,
+    /// Attempts to reach the relay after the first one fails.
+    pub connect_retries: Option<u32>,
+    /// The first wait between them, doubling each time.
+    pub retry_backoff: Option<Duration>,
+    /// Keep trying until the relay answers.
+    pub wait_for_relay: bool,
+}
+
+impl TransportOptions {
+    /// Fill every knob that is unset here from `fallback`.
+    pub fn or(self, fallback: TransportOptions) -> Self {
+        Self {
+            bind: self.bind.or(fallback.bind),
+            ip_version: self.ip_version.or(fallback.ip_version),
+            congestion: self.congestion.or(fallback.congestion),
+            initial_rtt: self.initial_rtt.or(fallback.initial_rtt),
+            max_idle_timeout: self.max_idle_timeout.or(fallback.max_idle_timeout),
+            keep_alive: self.keep_alive.or(fallback.keep_alive),
+            connect_retries: self.connect_retries.or(fallback.connect_retries),
+            retry_backoff: self.retry_backoff.or(fallback.retry_backoff),
+            wait_for_relay: self.wait_for_relay || fallback.wait_for_relay,
+        }
+    }
+
+    /// Settings for moq-native's client, which keeps its own QUIC transport.
+    pub fn client_config(&self) -> Result<moq_native::ClientConfig> {
+        let unsupported = [
+            ("congestion", self.congestion.map(|c| format!("{c:?}"))),
+            ("initial_rtt", self.initial_rtt.map(|d| format!("{d:?}"))),
+            (
+                "max_idle_timeout",
+                self.max_idle_timeout.map(|d| format!("{d:?}")),
+            ),
+            ("keep_alive", self.keep_alive.map(|d| format!("{d:?}"))),
+        ];
+        for (knob, value) in unsupported {
+            if let Some(value) = value {
+                warn!(knob, %value, "only https relays take this transport setting; using moq-native's");
+            }
+        }
+
+        Ok(moq_native::ClientConfig {
+            bind: self.bind_addr()?,
+            ..Default::default()
+        })
+    }
+
+    /// The local address to send from: `bind`, else any of the chosen family,
+    /// else any address on a dual-stack socket.
+    pub fn bind_addr(&self) -> Result<SocketAddr> {
+        match (self.bind, self.ip_version) {
+            (Some(bind), Some(version)) if !version.matches(bind.ip()) => {
+                bail!("bind address {bind} is not an {version:?} address")
+            }
+            (Some(bind), _) => Ok(bind),
+            (None, Some(IpVersion::Ipv4)) => Ok((Ipv4Addr::UNSPECIFIED, 0).into()),
+            (None, _) => Ok((Ipv6Addr::UNSPECIFIED, 0).into()),
+        }
+    }
+
+    /// The address family `ip_version`, or a specific `bind` address,
+    /// restricts the connection to.
+    pub fn family(&self) -> Option<IpVersion> {
+        match (self.ip_version, self.bind) {
+            (Some(version), _) => Some(version),
+            (None, Some(bind)) => match bind.ip() {
+                IpAddr::V4(_) => Some(IpVersion::Ipv4),
+                // an IPv6 wildcard socket is dual-stack.
+                IpAddr::V6(ip) if ip.is_unspecified() => None,
+                IpAddr::V6(_) => Some(IpVersion::Ipv6),
+            },
+            (None, None) => None,
+        }
+    }
+
+    /// The address to reach the relay at: the first it resolves to in our
+    /// [`family`](Self::family), with a clear message if there is none.
+    pub async fn resolve_relay(&self, url: &Url) -> Result<SocketAddr> {
+        let host = url
+            .host()
+            .ok_or_else(|| anyhow!("relay URL has no host: {url}"))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addrs: Vec<SocketAddr> = match host {
+            // without the brackets an IPv6 URL host has.
+            Host::Ipv6(ip) => vec![(ip, port).into()],
+            Host::Ipv4(ip) => vec![(ip, port).into()],
+            Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+                .await
+                .with_context(|| format!("failed to resolve relay host {host}"))?
+                .collect(),
+        };
+        debug!(?addrs, "resolved relay");
+        let family = self.family();
+        let addr = addrs
+            .iter()
+            .find(|addr| family.is_none_or(|version| version.matches(addr.ip())));
+        match (addr, family) {
+            (Some(addr), _) => Ok(*addr),
+            (None, Some(version)) => {
+                bail!("relay {host} has no {version:?} address (resolved {addrs:?})")
+            }
+            (None, None) => bail!("relay {host} has no address"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn checks_bracketed_ipv6_relays() {
+        let url = Url::parse("https://[::1]:4443/demo").unwrap();
+        let only = |ip_version| TransportOptions {
+            ip_version: Some(ip_version),
+            ..TransportOptions::default()
+        };
+        let addr = only(IpVersion::Ipv6).resolve_relay(&url).await.unwrap();
+        assert_eq!(addr, "[::1]:4443".parse().unwrap());
+        assert!(only(IpVersion::Ipv4).resolve_relay(&url).await.is_err());
+    }
+
+    #[test]
+    fn takes_the_family_from_the_bind_address() {
+        let bind = |addr: &str| TransportOptions {
+            bind: Some(addr.parse().unwrap()),
+            ..TransportOptions::default()
+        };
+        assert_eq!(bind("192.168.1.20:0").family(), Some(IpVersion::Ipv4));
+        assert_eq!(bind("[2001:db8::20]:0").family(), Some(IpVersion::Ipv6));
+        assert_eq!(bind("[::]:0").family(), None);
+        let pinned = TransportOptions {
+            ip_version: Some(IpVersion::Ipv6),
+            ..bind("[::]:0")
+        };
+        assert_eq!(pinned.family(), Some(IpVersion::Ipv6));
+    }
+}
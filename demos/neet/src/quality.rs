@@ -0,0 +1,185 @@
+//! Call quality as a MOS estimate, per direction.
+//!
+//! A simplified ITU-T G.107 E-model: the transmission rating R starts from the
+//! default 93.2 and loses points for one-way delay (Id) and for packet loss
+//! after concealment (Ie-eff); R is then mapped to a 1–4.5 MOS scale. Codec
+//! impairment is taken as zero for Opus at call bitrates.
+//!
+//! The receive direction is rated from what we measure ourselves, the send
+//! direction from the remote's receiver reports.
+
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::stats::{Gauge, Stats};
+
+const INTERVAL: Duration = Duration::from_secs(1);
+/// R for a perfect narrowband-equivalent connection (G.107 defaults).
+const R_DEFAULT: f32 = 93.2;
+/// Robustness of Opus' concealment to random loss.
+const LOSS_ROBUSTNESS: f32 = 20.;
+/// Capture, encode and device buffering on top of the network and jitter
+/// buffer delay.
+const PIPELINE_DELAY_MS: f32 = 40.;
+/// Below this the call is noticeably degraded ("many users dissatisfied").
+const POOR_MOS: f32 = 3.5;
+
+/// One-way mouth-to-ear delay and the loss left after concealment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conditions {
+    pub delay_ms: f32,
+    pub loss_pct: f32,
+}
+
+/// Transmission rating R (0–100) for the given conditions.
+pub fn r_factor(conditions: Conditions) -> f32 {
+    let d = conditions.delay_ms.max(0.);
+    let delay_impairment = 0.024 * d + if d > 177.3 { 0.11 * (d - 177.3) } else { 0. };
+    let loss = conditions.loss_pct.clamp(0., 100.);
+    let loss_impairment = 95. * loss / (loss + LOSS_ROBUSTNESS);
+    (R_DEFAULT - delay_impairment - loss_impairment).clamp(0., 100.)
+}
+
+/// Map R onto the MOS scale (G.107 Annex B).
+pub fn mos(conditions: Conditions) -> f32 {
+    let r = r_factor(conditions);
+    if r <= 0. {
+        return 1.;
+    }
+    (1. + 0.035 * r + 7e-6 * r * (r - 60.) * (100. - r)).clamp(1., 4.5)
+}
+
+/// Running MOS of one direction, published as `MOS × 100` gauges.
+struct Direction {
+    label: &'static str,
+    current: Gauge,
+    average: Gauge,
+    samples: u32,
+    sum: f32,
+    poor: bool,
+}
+
+impl Direction {
+    fn new(label: &'static str, current: &Gauge, average: &Gauge) -> Self {
+        Self {
+            label,
+            current: current.clone(),
+            average: average.clone(),
+            samples: 0,
+            sum: 0.,
+            poor: false,
+        }
+    }
+
+    fn update(&mut self, conditions: Conditions) {
+        let mos = mos(conditions);
+        self.samples += 1;
+        self.sum += mos;
+        self.current.set((mos * 100.).round() as u64);
+        self.average
+            .set((self.sum / self.samples as f32 * 100.).round() as u64);
+        debug!(direction = self.label, mos, ?conditions, "call quality");
+        match (self.poor, mos < POOR_MOS) {
+            (false, true) => warn!(
+                direction = self.label,
+                mos,
+                delay_ms = conditions.delay_ms,
+                loss_pct = conditions.loss_pct,
+                "call quality degraded"
+            ),
+            (true, false) => info!(direction = self.label, mos, "call quality recovered"),
+            _ => {}
+        }
+        self.poor = mos < POOR_MOS;
+    }
+}
+
+/// Cumulative receive counters, to turn them into per-interval loss.
+#[derive(Debug, Default)]
+struct ReceiveWindow {
+    received: u64,
+    unplayable: u64,
+}
+
+impl ReceiveWindow {
+    /// Loss after concealment in the last interval, in percent; late frames
+    /// count as lost. `None` if nothing was expected (paused, not connected).
+    fn loss_pct(&mut self, stats: &Stats) -> Option<f32> {
+        let received = stats.received_frames.get();
+        let unplayable = stats
+            .received_lost
+            .get()
+            .saturating_sub(stats.recovered_frames.get())
+            + stats.received_late.get();
+        let interval_received = received - self.received;
+        let interval_unplayable = unplayable.saturating_sub(self.unplayable);
+        self.received = received;
+        self.unplayable = unplayable;
+        match interval_received + interval_unplayable {
+            0 => None,
+            expected => Some(interval_unplayable as f32 * 100. / expected as f32),
+        }
+    }
+}
+
+/// Rate both directions every second until aborted.
+pub async fn monitor_quality(stats: Stats) {
+    let quality = &stats.quality;
+    let mut receive = Direction::new("receive", &quality.receive_mos, &quality.receive_mos_avg);
+    let mut send = Direction::new("send", &quality.send_mos, &quality.send_mos_avg);
+    let mut window = ReceiveWindow::default();
+    let mut last_report = stats.remote_reports.get();
+    let mut interval = tokio::time::interval(INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let network_ms = stats.connection.rtt_us.get() as f32 / 2000.;
+        if let Some(loss_pct) = window.loss_pct(&stats) {
+            let buffer_ms = stats.playback_buffer_us.get() as f32 / 1000.;
+            receive.update(Conditions {
+                delay_ms: network_ms + buffer_ms + PIPELINE_DELAY_MS,
+                loss_pct,
+            });
+        }
+        let reports = stats.remote_reports.get();
+        if reports != last_report {
+            last_report = reports;
+            send.update(Conditions {
+                delay_ms: network_ms
+                    + stats.remote_buffer_us.get() as f32 / 1000.
+                    + PIPELINE_DELAY_MS,
+                loss_pct: stats.remote_loss_permille.get() as f32 / 10.,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mos_drops_with_loss_and_delay() {
+        let clean = mos(Conditions {
+            delay_ms: 60.,
+            loss_pct: 0.,
+        });
+        assert!(clean > 4.3, "{clean}");
+        let lossy = mos(Conditions {
+            delay_ms: 60.,
+            loss_pct: 5.,
+        });
+        assert!((3.3..3.8).contains(&lossy), "{lossy}");
+        let slow = mos(Conditions {
+            delay_ms: 400.,
+            loss_pct: 0.,
+        });
+        assert!((3.0..3.8).contains(&slow), "{slow}");
+        let broken = mos(Conditions {
+            delay_ms: 1000.,
+            loss_pct: 50.,
+        });
+        assert_eq!(broken, 1.);
+    }
+}
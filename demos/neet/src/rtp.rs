@@ -0,0 +1,367 @@
+//! Opus over RTP (`--input-rtp`, `--output-rtp`).
+//!
+//! A hardware encoder or e.g. GStreamer's `rtpopuspay` sends Opus packets
+//! over UDP, one per RTP payload (RFC 7587). We publish those packets as
+//! they are instead of encoding captured audio, so neet can bridge such a
+//! source into a session without an audio device. Gaps in the RTP sequence
+//! numbers become skipped frames for the receivers' loss concealment, and a
+//! new SSRC, i.e. a restarted sender, resets their decoders. Packets of
+//! another payload type than the stream started with are dropped.
+//!
+//! The other way round, received Opus frames go out in RTP to a local
+//! consumer instead of to the decoder and the speakers, frames lost on the
+//! way leaving gaps in the sequence numbers for its concealment.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    audio::ENGINE_FORMAT,
+    media::{MediaFrame, MediaSender, MediaTrack, PauseState, RecvError},
+};
+
+const RTP_VERSION: u8 = 2;
+/// The dynamic payload type Opus is customarily sent as.
+const OPUS_PAYLOAD_TYPE: u8 = 111;
+/// Opus has no static payload type; anything else, e.g. RTCP on the same
+/// port, is not Opus.
+const DYNAMIC_PAYLOAD_TYPES: std::ops::RangeInclusive<u8> = 96..=127;
+/// What an Opus frame we cannot size is taken for: 20 ms.
+const DEFAULT_FRAME_SAMPLES: u32 = 960;
+const HEADER_LEN: usize = 12;
+/// Larger than any Opus packet fits in a UDP datagram.
+const MAX_PACKET: usize = 1500;
+/// Sequence numbers further ahead than this are taken for a stale or
+/// reordered packet rather than for loss.
+const MAX_GAP: u16 = 1000;
+/// Packets in a row that far off which mean the sender jumped, e.g. after
+/// restarting with the same SSRC, rather than that they are stale.
+const RESYNC_PACKETS: u8 = 3;
+
+/// The parts of an RTP packet we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpPacket<'a> {
+    /// Set on the first packet after a discontinuity.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    /// In samples at 48 kHz.
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    pub fn parse(packet: &'a [u8]) -> Result<Self> {
+        ensure!(packet.len() >= HEADER_LEN, "shorter than an RTP header");
+        ensure!(packet[0] >> 6 == RTP_VERSION, "not RTP version 2");
+        let padding = packet[0] & 0x20 != 0;
+        let extension = packet[0] & 0x10 != 0;
+        let csrc_count = (packet[0] & 0x0f) as usize;
+        let marker = packet[1] & 0x80 != 0;
+        let payload_type = packet[1] & 0x7f;
+        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+        let mut start = HEADER_LEN + 4 * csrc_count;
+        if extension {
+            let words = packet
+                .get(start + 2..start + 4)
+                .context("truncated header extension")?;
+            start += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+        }
+        let mut end = packet.len();
+        if padding {
+            end = end.saturating_sub(packet[end - 1] as usize);
+        }
+        ensure!(start <= end, "no room for a payload");
+        Ok(Self {
+            marker,
+            payload_type,
+            sequence,
+            timestamp,
+            ssrc,
+            payload: &packet[start..end],
+        })
+    }
+
+    /// The packet on the wire, without CSRCs or extensions.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        packet.push(RTP_VERSION << 6);
+        packet.push((self.marker as u8) << 7 | self.payload_type);
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(self.payload);
+        packet
+    }
+}
+
+/// Samples per channel at 48 kHz in an Opus packet, from its TOC byte
+/// (RFC 6716, section 3.1).
+fn opus_samples(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+    let frame = match config {
+        // SILK: 10, 20, 40 or 60 ms
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // hybrid: 10 or 20 ms
+        12..=15 => [480, 960][config as usize % 2],
+        // CELT: 2.5, 5, 10 or 20 ms
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3f) as u32,
+    };
+    Some(frame * frames)
+}
+
+/// Follows the sequence numbers of one RTP stream.
+#[derive(Debug, Default)]
+struct Sequence {
+    /// The SSRC, payload type and sequence number of the last packet passed
+    /// on.
+    last: Option<(u32, u8, u16)>,
+    /// Packets in a row too far off the last one to pass on.
+    strays: u8,
+}
+
+impl Sequence {
+    /// Whether to pass `packet` on, and if so, whether the stream starts
+    /// over with it and how many packets were lost right before it.
+    fn next(&mut self, packet: &RtpPacket) -> Option<(bool, u16)> {
+        if !DYNAMIC_PAYLOAD_TYPES.contains(&packet.payload_type) {
+            trace!(
+                payload_type = packet.payload_type,
+                "dropping a packet that is not Opus"
+            );
+            return None;
+        }
+        let next = match self.last {
+            None => {
+                info!(ssrc = packet.ssrc, "receiving RTP");
+                (false, 0)
+            }
+            Some((ssrc, ..)) if ssrc != packet.ssrc => {
+                info!(ssrc = packet.ssrc, "the RTP source restarted");
+                (true, 0)
+            }
+            // e.g. telephone events next to the Opus stream.
+            Some((_, payload_type, _)) if payload_type != packet.payload_type => {
+                trace!(
+                    payload_type = packet.payload_type,
+                    "dropping a packet of another payload type"
+                );
+                return None;
+            }
+            Some((.., sequence)) => match packet.sequence.wrapping_sub(sequence) {
+                gap @ 1..=MAX_GAP => (false, gap - 1),
+                _ if self.strays + 1 < RESYNC_PACKETS => {
+                    self.strays += 1;
+                    trace!(sequence = packet.sequence, "dropping a late RTP packet");
+                    return None;
+                }
+                _ => {
+                    info!(
+                        sequence = packet.sequence,
+                        "the RTP sequence jumped; resyncing"
+                    );
+                    (true, 0)
+                }
+            },
+        };
+        self.strays = 0;
+        self.last = Some((packet.ssrc, packet.payload_type, packet.sequence));
+        Some(next)
+    }
+}
+
+/// Listens for one RTP stream and passes its Opus packets on.
+#[derive(Debug)]
+pub struct RtpInput {
+    socket: UdpSocket,
+}
+
+impl RtpInput {
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("failed to listen for RTP on {addr}"))?;
+        info!(addr = %socket.local_addr()?, "listening for Opus over RTP");
+        Ok(Self { socket })
+    }
+
+    /// Send every packet on as a frame until the receiver goes away,
+    /// dropping them while `paused`.
+    pub async fn run(self, mut sender: MediaSender, paused: PauseState) -> Result<()> {
+        let mut buf = vec![0; MAX_PACKET];
+        let mut sequence = Sequence::default();
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await?;
+            let packet = match RtpPacket::parse(&buf[..len]) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    debug!(%from, "ignoring a datagram: {err:#}");
+                    continue;
+                }
+            };
+            let Some((reset, skipped)) = sequence.next(&packet) else {
+                continue;
+            };
+            if skipped > 0 {
+                warn!(skipped, "lost RTP packets");
+            }
+            if paused.is_paused() {
+                continue;
+            }
+            let Some(samples) = opus_samples(packet.payload) else {
+                continue;
+            };
+            let frame = MediaFrame {
+                payload: Bytes::copy_from_slice(packet.payload),
+                sample_count: Some(samples * ENGINE_FORMAT.channel_count as u32),
+                skipped_frames: (skipped > 0).then_some(skipped as u32),
+                skipped_samples: None,
+                reset,
+            };
+            if sender.send_async(frame).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Sends Opus frames to one address as an RTP stream.
+#[derive(Debug)]
+pub struct RtpOutput {
+    socket: UdpSocket,
+}
+
+impl RtpOutput {
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("failed to send RTP to {addr}"))?;
+        info!(%addr, "sending received audio as Opus over RTP");
+        Ok(Self { socket })
+    }
+
+    /// Send every frame of `track` until it closes.
+    pub async fn run(self, mut track: MediaTrack) -> Result<()> {
+        // a random start, as RFC 3550 asks, and the SSRC to tell us apart.
+        let mut random = [0; 10];
+        getrandom::getrandom(&mut random)
+            .map_err(|err| anyhow!("no randomness for an RTP stream: {err}"))?;
+        let mut sequence = u16::from_be_bytes([random[0], random[1]]);
+        let mut timestamp = u32::from_be_bytes([random[2], random[3], random[4], random[5]]);
+        let ssrc = u32::from_be_bytes([random[6], random[7], random[8], random[9]]);
+        let mut marker = true;
+        loop {
+            let frame = match track.recv().await {
+                Ok(frame) => frame,
+                Err(RecvError::Closed) => return Ok(()),
+                Err(RecvError::Lagged(dropped)) => {
+                    sequence = sequence.wrapping_add(dropped as u16);
+                    timestamp = timestamp.wrapping_add(dropped as u32 * DEFAULT_FRAME_SAMPLES);
+                    continue;
+                }
+            };
+            let samples = opus_samples(&frame.payload).unwrap_or(DEFAULT_FRAME_SAMPLES);
+            if let Some(lost) = frame.skipped_frames {
+                // the receiver conceals the gap we leave.
+                sequence = sequence.wrapping_add(lost as u16);
+                timestamp = timestamp.wrapping_add(lost * samples);
+            }
+            marker |= frame.reset;
+            let packet = RtpPacket {
+                marker,
+                payload_type: OPUS_PAYLOAD_TYPE,
+                sequence,
+                timestamp,
+                ssrc,
+                payload: &frame.payload,
+            };
+            if let Err(err) = self.socket.send(&packet.encode()).await {
+                // e.g. nobody listening yet; the stream goes on.
+                trace!("failed to send RTP: {err}");
+            }
+            marker = false;
+            sequence = sequence.wrapping_add(1);
+            timestamp = timestamp.wrapping_add(samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rtp_around_an_opus_payload() {
+        // a 20 ms CELT frame after one CSRC, a one-word extension and 2
+        // bytes of padding.
+        let mut packet = vec![0xb1, 111, 0x12, 0x34, 0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef];
+        packet.extend([0; 4]);
+        packet.extend([0xbe, 0xde, 0, 1, 0, 0, 0, 0]);
+        packet.extend([0xfc, 0xff, 0xfe]);
+        packet.extend([0, 2]);
+        let parsed = RtpPacket::parse(&packet).unwrap();
+        assert_eq!(parsed.sequence, 0x1234);
+        assert_eq!(parsed.ssrc, 0xdead_beef);
+        assert_eq!(parsed.payload, [0xfc, 0xff, 0xfe]);
+        let sent = RtpPacket {
+            marker: true,
+            timestamp: 48_000,
+            ..parsed
+        };
+        assert_eq!(RtpPacket::parse(&sent.encode()).unwrap(), sent);
+        assert_eq!(opus_samples(parsed.payload), Some(960));
+        // two 10 ms SILK frames
+        assert_eq!(opus_samples(&[0x01]), Some(960));
+        assert_eq!(opus_samples(&[0x03, 3]), Some(1440));
+
+        assert!(RtpPacket::parse(&packet[..8]).is_err());
+        packet[0] = 0x40;
+        assert!(RtpPacket::parse(&packet).is_err());
+    }
+
+    #[test]
+    fn follows_the_opus_stream_and_resyncs_after_a_jump() {
+        let packet = |payload_type, sequence| RtpPacket {
+            marker: false,
+            payload_type,
+            sequence,
+            timestamp: 0,
+            ssrc: 1,
+            payload: &[],
+        };
+        let mut stream = Sequence::default();
+        // RTCP on the same port, then the Opus stream.
+        assert_eq!(stream.next(&packet(72, 10)), None);
+        assert_eq!(stream.next(&packet(111, 10)), Some((false, 0)));
+        assert_eq!(stream.next(&packet(111, 13)), Some((false, 2)));
+        // telephone events, and a stale packet.
+        assert_eq!(stream.next(&packet(101, 14)), None);
+        assert_eq!(stream.next(&packet(111, 12)), None);
+        assert_eq!(stream.next(&packet(111, 14)), Some((false, 0)));
+        // the sender jumped ahead: dropped until it clearly did.
+        assert_eq!(stream.next(&packet(111, 5000)), None);
+        assert_eq!(stream.next(&packet(111, 5001)), None);
+        assert_eq!(stream.next(&packet(111, 5002)), Some((true, 0)));
+        assert_eq!(stream.next(&packet(111, 5003)), Some((false, 0)));
+    }
+}
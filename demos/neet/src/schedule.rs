@@ -0,0 +1,126 @@
+//! Unattended calls: `--start-at` waits for a wall-clock time before joining,
+//! and `--max-duration` hangs up after a while, warning shortly before.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, TimeZone};
+use tokio::time::{sleep_until, Instant};
+use tracing::{info, warn};
+
+/// How long before the end each warning is given.
+pub const WARNINGS: [Duration; 2] = [Duration::from_secs(5 * 60), Duration::from_secs(60)];
+
+/// A `--max-duration` such as `30m` or `1h 30m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxDuration(pub Duration);
+
+impl FromStr for MaxDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let duration = humantime::parse_duration(s)
+            .with_context(|| format!("expected a duration like 30m or 1h30m, got `{s}`"))?;
+        ensure!(!duration.is_zero(), "the maximum duration must be positive");
+        Ok(Self(duration))
+    }
+}
+
+/// When to start: a local time of day (the next one to come), or a moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartAt {
+    /// `HH:MM` or `HH:MM:SS`.
+    TimeOfDay(NaiveTime),
+    /// An RFC 3339 timestamp, e.g. `2025-06-01T09:00:00+02:00`.
+    Moment(DateTime<FixedOffset>),
+}
+
+impl FromStr for StartAt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ["%H:%M", "%H:%M:%S"]
+            .iter()
+            .find_map(|format| NaiveTime::parse_from_str(s, format).ok())
+            .map(Self::TimeOfDay)
+            .or_else(|| DateTime::parse_from_rfc3339(s).ok().map(Self::Moment))
+            .ok_or_else(|| anyhow!("expected HH:MM, HH:MM:SS or an RFC 3339 timestamp, got `{s}`"))
+    }
+}
+
+impl StartAt {
+    /// How long from `now` until the start; zero if it has passed.
+    fn delay<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Duration {
+        let start = match self {
+            Self::TimeOfDay(time) => {
+                let zone = now.timezone();
+                let mut day = now.date_naive();
+                loop {
+                    // a time skipped by a DST change starts a little later.
+                    let start = zone
+                        .from_local_datetime(&day.and_time(*time))
+                        .earliest()
+                        .unwrap_or_else(|| zone.from_utc_datetime(&day.and_time(*time)));
+                    if start > *now {
+                        break start.fixed_offset();
+                    }
+                    day = day.succ_opt().expect("dates run out after the year 262142");
+                }
+            }
+            Self::Moment(moment) => *moment,
+        };
+        (start - now.fixed_offset()).to_std().unwrap_or_default()
+    }
+
+    /// Sleep until the start.
+    pub async fn wait(&self) {
+        let now = Local::now();
+        let delay = self.delay(&now);
+        if delay.is_zero() {
+            warn!("--start-at is in the past; starting now");
+            return;
+        }
+        let at = now + delay;
+        info!(at = %at.format("%Y-%m-%d %H:%M:%S"), "waiting to start");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Complete after `limit`, logging at each of the [`WARNINGS`] that fits into
+/// it and calling `alert` with the number of the warning (1, then 2).
+pub async fn time_limit(limit: Duration, mut alert: impl FnMut(usize)) {
+    let end = Instant::now() + limit;
+    for (index, remaining) in WARNINGS.into_iter().enumerate() {
+        if remaining < limit {
+            sleep_until(end - remaining).await;
+            let remaining = humantime::format_duration(remaining);
+            warn!(%remaining, "maximum duration almost reached");
+            alert(index + 1);
+        }
+    }
+    sleep_until(end).await;
+    info!("maximum duration reached; hanging up");
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    #[test]
+    fn start_times_resolve_to_the_next_occurrence() {
+        let now = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+        let later: StartAt = "13:30".parse().unwrap();
+        assert_eq!(later.delay(&now), Duration::from_secs(90 * 60));
+        let earlier: StartAt = "11:00:00".parse().unwrap();
+        assert_eq!(earlier.delay(&now), Duration::from_secs(23 * 3600));
+        let moment: StartAt = "2025-06-01T14:00:00+02:00".parse().unwrap();
+        assert_eq!(moment.delay(&now), Duration::ZERO, "already past");
+        assert!("noon".parse::<StartAt>().is_err());
+
+        let limit: MaxDuration = "1h 30m".parse().unwrap();
+        assert_eq!(limit.0, Duration::from_secs(90 * 60));
+        assert!("0s".parse::<MaxDuration>().is_err());
+    }
+}
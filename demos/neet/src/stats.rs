@@ -0,0 +1,401 @@
+//! Call statistics shared between the audio threads and the session tasks.
+//!
+//! Counters are plain atomics so they can be bumped from real-time threads.
+
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+
+use crate::media::Presence;
+
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Last sampled value of something measured elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The level the remote reports in its frames, by the audio level
+/// extension; nothing until the first such frame.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteLevel(Arc<AtomicU32>);
+
+/// Set in [`RemoteLevel`] once a level was reported.
+const REMOTE_LEVEL_SET: u32 = 0x100;
+const REMOTE_LEVEL_SPEAKING: u32 = 0x200;
+
+impl RemoteLevel {
+    /// The remote's level is `dbov` decibels below full scale.
+    pub fn set(&self, dbov: u8, speaking: bool) {
+        let mut bits = REMOTE_LEVEL_SET | dbov as u32;
+        if speaking {
+            bits |= REMOTE_LEVEL_SPEAKING;
+        }
+        self.0.store(bits, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Option<RemoteLevelSnapshot> {
+        let bits = self.0.load(Ordering::Relaxed);
+        (bits & REMOTE_LEVEL_SET != 0).then(|| RemoteLevelSnapshot {
+            dbfs: -((bits & 0xff) as f32),
+            speaking: bits & REMOTE_LEVEL_SPEAKING != 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RemoteLevelSnapshot {
+    pub dbfs: f32,
+    pub speaking: bool,
+}
+
+/// Floor reported for digital silence.
+pub const SILENCE_DBFS: f32 = -100.;
+/// Samples at or above this magnitude count as clipped.
+const CLIP_LEVEL: f32 = 0.999;
+
+/// Short-term signal level of one audio stream, updated every tick by the
+/// audio thread that produces or consumes it.
+#[derive(Debug, Clone)]
+pub struct Level {
+    /// `f32` bits, dBFS.
+    peak: Arc<AtomicU32>,
+    /// `f32` bits, dBFS.
+    rms: Arc<AtomicU32>,
+    /// Ticks in which at least one sample clipped.
+    pub clipped: Counter,
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Self {
+            peak: Arc::new(AtomicU32::new(SILENCE_DBFS.to_bits())),
+            rms: Arc::new(AtomicU32::new(SILENCE_DBFS.to_bits())),
+            clipped: Counter::default(),
+        }
+    }
+}
+
+impl Level {
+    pub fn measure(&self, buf: &[f32]) {
+        let (peak, sum) = buf.iter().fold((0f32, 0f32), |(peak, sum), s| {
+            (peak.max(s.abs()), sum + s * s)
+        });
+        let rms = match buf.len() {
+            0 => 0.,
+            n => (sum / n as f32).sqrt(),
+        };
+        if peak >= CLIP_LEVEL {
+            self.clipped.add(1);
+        }
+        self.peak.store(to_dbfs(peak).to_bits(), Ordering::Relaxed);
+        self.rms.store(to_dbfs(rms).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LevelSnapshot {
+        LevelSnapshot {
+            peak_dbfs: f32::from_bits(self.peak.load(Ordering::Relaxed)),
+            rms_dbfs: f32::from_bits(self.rms.load(Ordering::Relaxed)),
+            clipped: self.clipped.get(),
+        }
+    }
+}
+
+fn to_dbfs(amplitude: f32) -> f32 {
+    if amplitude <= 0. {
+        return SILENCE_DBFS;
+    }
+    (20. * amplitude.log10()).max(SILENCE_DBFS)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Encoded frames dropped between capture and publish.
+    pub capture_dropped: Counter,
+    /// Encoded frames dropped while the connection did not keep up.
+    pub send_dropped: Counter,
+    /// Received frames dropped before reaching the decoder.
+    pub playback_dropped: Counter,
+    /// Frames that failed to encode or decode, and were skipped while the
+    /// error budget lasted.
+    pub codec_errors: Counter,
+    /// Frames received from the remote and passed on to playback.
+    pub received_frames: Counter,
+    /// Frames the remote sent whose own group never arrived, by sequence
+    /// number. Includes frames later recovered from redundant copies.
+    pub received_lost: Counter,
+    /// Lost frames recovered from redundant copies in later frames.
+    pub recovered_frames: Counter,
+    /// Frames discarded because a newer one had already been played.
+    pub received_late: Counter,
+    /// Interarrival jitter of received frames.
+    pub jitter_us: Gauge,
+    /// How long before it arrived the last received frame was captured, by
+    /// the wall clocks of both sides, so only meaningful if they are in sync.
+    /// 0 if the remote sends no timestamps.
+    pub one_way_delay_us: Gauge,
+    /// Decoded audio waiting to be played.
+    pub playback_buffer_us: Gauge,
+    /// Times playback skipped to live after going over the latency budget.
+    pub resyncs: Counter,
+    /// Audio skipped by those resyncs.
+    pub resync_skipped_us: Counter,
+    /// Times the remote paused publishing.
+    pub remote_pauses: Counter,
+    /// Times the remote's heartbeats stopped while its broadcast was still up.
+    pub remote_lost: Counter,
+    /// What the remote last said of its [`Presence`], by index.
+    pub remote_presence: Gauge,
+    /// Loss the remote reports for the audio we send, in permille.
+    pub remote_loss_permille: Gauge,
+    /// Playout buffer the remote reports for the audio we send.
+    pub remote_buffer_us: Gauge,
+    /// Receiver reports received from the remote.
+    pub remote_reports: Counter,
+    /// The most we ask the remote to send, in bits per second; 0 if no limit.
+    pub receive_limit_bps: Gauge,
+    /// Estimated call quality per direction.
+    pub quality: QualityStats,
+    /// QUIC connection to the relay, sampled periodically.
+    pub connection: ConnectionStats,
+    /// Microphone level after processing.
+    pub capture_level: Level,
+    /// Level of the remote track as played.
+    pub playback_level: Level,
+    /// Level of the remote's microphone, as it reports it.
+    pub remote_level: RemoteLevel,
+    /// Output device buffer trouble, shared by every session on the device.
+    pub playback_xruns: XrunStats,
+    /// Time the audio threads and device callbacks spent working, shared by
+    /// every session on the devices.
+    pub pipeline_busy_us: Counter,
+}
+
+/// Receive loss over the interval since the previous call, from the
+/// cumulative counters.
+#[derive(Debug, Default)]
+pub struct LossWindow {
+    received: u64,
+    lost: u64,
+}
+
+impl LossWindow {
+    pub fn loss_pct(&mut self, stats: &Stats) -> f32 {
+        let received = stats.received_frames.get();
+        let lost = stats.received_lost.get();
+        let interval_lost = lost - self.lost;
+        let expected = (received - self.received) + interval_lost;
+        self.received = received;
+        self.lost = lost;
+        match expected {
+            0 => 0.,
+            _ => interval_lost as f32 * 100. / expected as f32,
+        }
+    }
+}
+
+/// MOS estimates (× 100), see [`crate::quality`].
+#[derive(Debug, Clone, Default)]
+pub struct QualityStats {
+    pub receive_mos: Gauge,
+    pub receive_mos_avg: Gauge,
+    pub send_mos: Gauge,
+    pub send_mos_avg: Gauge,
+}
+
+impl QualityStats {
+    pub fn snapshot(&self) -> QualitySnapshot {
+        let mos = |gauge: &Gauge| match gauge.get() {
+            0 => None,
+            centi => Some(centi as f32 / 100.),
+        };
+        QualitySnapshot {
+            receive_mos: mos(&self.receive_mos),
+            receive_mos_avg: mos(&self.receive_mos_avg),
+            send_mos: mos(&self.send_mos),
+            send_mos_avg: mos(&self.send_mos_avg),
+        }
+    }
+}
+
+/// `None` until a direction has been rated.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct QualitySnapshot {
+    pub receive_mos: Option<f32>,
+    pub receive_mos_avg: Option<f32>,
+    pub send_mos: Option<f32>,
+    pub send_mos_avg: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStats {
+    pub rtt_us: Gauge,
+    pub cwnd: Gauge,
+    pub lost_packets: Gauge,
+    pub bytes_sent: Gauge,
+    pub bytes_received: Gauge,
+    /// Bytes over every connection of the call, across reconnects.
+    pub total_sent: Counter,
+    pub total_received: Counter,
+    /// Data rates over the last second, in bits per second.
+    pub send_bps: Gauge,
+    pub receive_bps: Gauge,
+    /// How long opening the last connection and its MoQ session took.
+    pub connect_us: Gauge,
+    /// Connections opened after the first one dropped.
+    pub reconnects: Counter,
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            capture_dropped: self.capture_dropped.get(),
+            send_dropped: self.send_dropped.get(),
+            playback_dropped: self.playback_dropped.get(),
+            codec_errors: self.codec_errors.get(),
+            received_frames: self.received_frames.get(),
+            received_lost: self.received_lost.get(),
+            recovered_frames: self.recovered_frames.get(),
+            received_late: self.received_late.get(),
+            jitter_us: self.jitter_us.get(),
+            one_way_delay_us: self.one_way_delay_us.get(),
+            playback_buffer_us: self.playback_buffer_us.get(),
+            resyncs: self.resyncs.get(),
+            resync_skipped_us: self.resync_skipped_us.get(),
+            remote_pauses: self.remote_pauses.get(),
+            remote_lost: self.remote_lost.get(),
+            remote_presence: Presence::from_index(self.remote_presence.get()),
+            remote_loss_permille: self.remote_loss_permille.get(),
+            remote_buffer_us: self.remote_buffer_us.get(),
+            receive_limit_bps: self.receive_limit_bps.get(),
+            quality: self.quality.snapshot(),
+            connection: self.connection.snapshot(),
+            capture_level: self.capture_level.snapshot(),
+            playback_level: self.playback_level.snapshot(),
+            remote_level: self.remote_level.snapshot(),
+            playback_xruns: self.playback_xruns.snapshot(),
+            pipeline_busy_us: self.pipeline_busy_us.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub capture_dropped: u64,
+    pub send_dropped: u64,
+    pub playback_dropped: u64,
+    pub codec_errors: u64,
+    pub received_frames: u64,
+    pub received_lost: u64,
+    pub recovered_frames: u64,
+    pub received_late: u64,
+    pub jitter_us: u64,
+    pub one_way_delay_us: u64,
+    pub playback_buffer_us: u64,
+    pub resyncs: u64,
+    pub resync_skipped_us: u64,
+    pub remote_pauses: u64,
+    pub remote_lost: u64,
+    pub remote_presence: Presence,
+    pub remote_loss_permille: u64,
+    pub remote_buffer_us: u64,
+    pub receive_limit_bps: u64,
+    pub quality: QualitySnapshot,
+    pub connection: ConnectionSnapshot,
+    pub capture_level: LevelSnapshot,
+    pub playback_level: LevelSnapshot,
+    pub remote_level: Option<RemoteLevelSnapshot>,
+    pub playback_xruns: XrunSnapshot,
+    pub pipeline_busy_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct LevelSnapshot {
+    pub peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    pub clipped: u64,
+}
+
+impl ConnectionStats {
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            rtt_us: self.rtt_us.get(),
+            cwnd: self.cwnd.get(),
+            lost_packets: self.lost_packets.get(),
+            bytes_sent: self.bytes_sent.get(),
+            bytes_received: self.bytes_received.get(),
+            total_sent: self.total_sent.get(),
+            total_received: self.total_received.get(),
+            send_bps: self.send_bps.get(),
+            receive_bps: self.receive_bps.get(),
+            connect_us: self.connect_us.get(),
+            reconnects: self.reconnects.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ConnectionSnapshot {
+    pub rtt_us: u64,
+    pub cwnd: u64,
+    pub lost_packets: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub total_sent: u64,
+    pub total_received: u64,
+    pub send_bps: u64,
+    pub receive_bps: u64,
+    pub connect_us: u64,
+    pub reconnects: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct XrunStats {
+    /// Output callbacks that ran out of audio.
+    pub underruns: Counter,
+    /// Mixer ticks that found the output buffer full.
+    pub overruns: Counter,
+    /// Times the output buffer, or a remote track's jitter buffer, was cut
+    /// back after a stall.
+    pub resets: Counter,
+}
+
+impl XrunStats {
+    pub fn snapshot(&self) -> XrunSnapshot {
+        XrunSnapshot {
+            underruns: self.underruns.get(),
+            overruns: self.overruns.get(),
+            resets: self.resets.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct XrunSnapshot {
+    pub underruns: u64,
+    pub overruns: u64,
+    pub resets: u64,
+}
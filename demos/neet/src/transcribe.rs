@@ -0,0 +1,351 @@
+//! Live transcription of call audio with whisper.cpp.
+//!
+//! Taps on the capture path and on decoded remote audio copy 48 kHz stereo
+//! ticks into ring buffers, and a worker thread drains them, downmixes them
+//! to 16 kHz mono, cuts them into windows per tap and runs whisper on every
+//! window that isn't silence. Lines are printed as `[mm:ss] speaker: text`,
+//! timed from the start of the call, and appended to the transcript file if
+//! one was given, along with the moments marked with `mark` as
+//! `[mm:ss] -- label`.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    ops::ControlFlow,
+    path::PathBuf,
+    str::FromStr,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use ringbuf::{
+    traits::{Consumer as _, Observer as _, Producer as _, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use tracing::{debug, info, warn};
+use whisper_rs::{
+    FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState,
+};
+
+use crate::audio::{AudioSink, ENGINE_FORMAT};
+
+pub const WHISPER_SAMPLE_RATE: u32 = 16_000;
+/// Audio per whisper run; longer windows transcribe better but show up later.
+const WINDOW: Duration = Duration::from_secs(5);
+/// Windows quieter than this are skipped; whisper invents text for silence.
+const SILENCE_DBFS: f32 = -50.;
+/// Audio queued for the worker before taps start dropping it.
+const QUEUE: Duration = Duration::from_secs(10);
+/// Marks and new taps queued for the worker.
+const MESSAGE_QUEUE: usize = 16;
+/// How often the worker drains the taps.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TranscribeSources {
+    Local,
+    Remote,
+    #[default]
+    Both,
+}
+
+impl TranscribeSources {
+    pub fn local(self) -> bool {
+        matches!(self, Self::Local | Self::Both)
+    }
+
+    pub fn remote(self) -> bool {
+        matches!(self, Self::Remote | Self::Both)
+    }
+}
+
+impl FromStr for TranscribeSources {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "remote" => Ok(Self::Remote),
+            "both" => Ok(Self::Both),
+            _ => Err(anyhow!("expected local, remote or both, got `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TranscribeOptions {
+    /// A ggml whisper model, e.g. `ggml-base.en.bin`.
+    pub model: PathBuf,
+    pub sources: TranscribeSources,
+    /// Also write the transcript here.
+    pub output: Option<PathBuf>,
+    /// Spoken language; `None` lets whisper detect it.
+    pub language: Option<String>,
+}
+
+enum Message {
+    /// The audio of a new tap.
+    Tap(TapAudio),
+    /// A moment the user marked, and when.
+    Mark(String, Instant),
+}
+
+/// Handle to the transcription worker; clone it to create more taps.
+#[derive(Debug, Clone)]
+pub struct Transcriber {
+    sender: mpsc::SyncSender<Message>,
+}
+
+impl Transcriber {
+    /// Load the model and start the worker thread.
+    pub fn start(options: TranscribeOptions) -> Result<Self> {
+        let model = options
+            .model
+            .to_str()
+            .context("whisper model path is not valid UTF-8")?;
+        let context = WhisperContext::new_with_params(model, WhisperContextParameters::default())
+            .with_context(|| format!("failed to load whisper model {model}"))?;
+        let output = options
+            .output
+            .as_ref()
+            .map(|path| {
+                File::create(path)
+                    .map(BufWriter::new)
+                    .with_context(|| format!("failed to create transcript {}", path.display()))
+            })
+            .transpose()?;
+        let (sender, receiver) = mpsc::sync_channel(MESSAGE_QUEUE);
+        let language = options.language.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = transcribe_loop(context, language, receiver, output) {
+                warn!("transcription stopped: {err:?}");
+            }
+        });
+        info!(model, sources = ?options.sources, "transcription enabled");
+        Ok(Self { sender })
+    }
+
+    /// Note `label` in the transcript, at the current time.
+    pub fn mark(&self, label: &str) {
+        if self
+            .sender
+            .try_send(Message::Mark(label.to_string(), Instant::now()))
+            .is_err()
+        {
+            warn!("transcription is falling behind; the mark is not in the transcript");
+        }
+    }
+
+    /// An audio sink transcribing what it is fed as `speaker`.
+    pub fn tap(&self, speaker: &'static str) -> TranscriptTap {
+        let (producer, audio) = HeapRb::new(ENGINE_FORMAT.sample_count(QUEUE)).split();
+        let tap = TapAudio {
+            speaker,
+            audio,
+            window: Window::default(),
+        };
+        // if the worker is gone, nobody reads the ring and the tap ends.
+        let _ = self.sender.send(Message::Tap(tap));
+        TranscriptTap {
+            speaker,
+            audio: producer,
+            dropped: 0,
+        }
+    }
+}
+
+/// Copies engine-format audio into the worker's ring buffer, without
+/// blocking or allocating on the audio thread.
+pub struct TranscriptTap {
+    speaker: &'static str,
+    audio: HeapProd<f32>,
+    dropped: u64,
+}
+
+impl AudioSink for TranscriptTap {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        if !self.audio.read_is_held() {
+            return Ok(ControlFlow::Break(()));
+        }
+        // whole ticks only, so that stereo frames stay aligned.
+        if self.audio.vacant_len() < buf.len() {
+            self.dropped += 1;
+            if self.dropped.is_power_of_two() {
+                debug!(
+                    speaker = self.speaker,
+                    dropped = self.dropped,
+                    "transcription falling behind"
+                );
+            }
+        } else {
+            self.audio.push_slice(buf);
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// One tap as the worker sees it.
+struct TapAudio {
+    speaker: &'static str,
+    audio: HeapCons<f32>,
+    window: Window,
+}
+
+/// 16 kHz mono audio of one tap waiting to be transcribed.
+#[derive(Default)]
+struct Window {
+    /// Offset of the first sample from the start of the call.
+    start: Option<Duration>,
+    samples: Vec<f32>,
+}
+
+/// Where transcript lines go.
+struct Transcript {
+    call_start: Instant,
+    output: Option<BufWriter<File>>,
+}
+
+impl Transcript {
+    fn write(&mut self, line: &str) -> Result<()> {
+        // as the log does, which raw mode would otherwise staircase.
+        writeln!(crate::keys::LogWriter, "{line}")?;
+        if let Some(output) = &mut self.output {
+            writeln!(output, "{line}")?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn transcribe_loop(
+    context: WhisperContext,
+    language: Option<String>,
+    receiver: mpsc::Receiver<Message>,
+    output: Option<BufWriter<File>>,
+) -> Result<()> {
+    let mut state = context.create_state()?;
+    let mut transcript = Transcript {
+        call_start: Instant::now(),
+        output,
+    };
+    let window_len = (WINDOW.as_secs_f32() * WHISPER_SAMPLE_RATE as f32) as usize;
+    let mut taps: Vec<TapAudio> = Vec::new();
+    // whole 48 kHz stereo frames, so that downmixing loses nothing.
+    let mut scratch = vec![0.; ENGINE_FORMAT.sample_count(POLL_INTERVAL)];
+    loop {
+        let connected = match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(Message::Tap(tap)) => {
+                taps.push(tap);
+                true
+            }
+            Ok(Message::Mark(label, at)) => {
+                let elapsed = at.saturating_duration_since(transcript.call_start);
+                transcript.write(&format!("[{}] -- {label}", timestamp(elapsed)))?;
+                true
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => true,
+            Err(mpsc::RecvTimeoutError::Disconnected) => false,
+        };
+        for tap in &mut taps {
+            loop {
+                let count = tap.audio.pop_slice(&mut scratch);
+                if count == 0 {
+                    break;
+                }
+                let window = &mut tap.window;
+                window.start.get_or_insert_with(|| {
+                    transcript
+                        .call_start
+                        .elapsed()
+                        .saturating_sub(ENGINE_FORMAT.duration_from_sample_count(count))
+                });
+                downmix_16k(&scratch[..count], &mut window.samples);
+                if window.samples.len() < window_len {
+                    continue;
+                }
+                let start = window.start.take().unwrap_or_default();
+                let samples = std::mem::take(&mut window.samples);
+                if dbfs(&samples) < SILENCE_DBFS {
+                    continue;
+                }
+                let speaker = tap.speaker;
+                transcribe(&mut state, language.as_deref(), &samples, |offset, text| {
+                    transcript.write(&format!(
+                        "[{}] {speaker}: {text}",
+                        timestamp(start + offset)
+                    ))
+                })?;
+            }
+        }
+        // a tap that is gone has nothing more to say.
+        taps.retain(|tap| tap.audio.write_is_held() || !tap.audio.is_empty());
+        if !connected {
+            return Ok(());
+        }
+    }
+}
+
+/// Run whisper over `samples` and hand each piece of text it finds to `line`,
+/// with its offset into them.
+fn transcribe(
+    state: &mut WhisperState,
+    language: Option<&str>,
+    samples: &[f32],
+    mut line: impl FnMut(Duration, &str) -> Result<()>,
+) -> Result<()> {
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(language);
+    params.set_no_context(true);
+    params.set_print_special(false);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+    state.full(params, samples)?;
+    for segment in 0..state.full_n_segments()? {
+        let text = state.full_get_segment_text(segment)?;
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        // segment times are in centiseconds from the window start.
+        let offset = Duration::from_millis(state.full_get_segment_t0(segment)? as u64 * 10);
+        line(offset, text)?;
+    }
+    Ok(())
+}
+
+/// Append 48 kHz interleaved stereo to `out` as 16 kHz mono, averaging each
+/// group of three frames as a crude anti-aliasing filter.
+pub fn downmix_16k(stereo: &[f32], out: &mut Vec<f32>) {
+    let ratio = (ENGINE_FORMAT.sample_rate.0 / WHISPER_SAMPLE_RATE) as usize;
+    for frames in stereo.chunks_exact(2 * ratio) {
+        out.push(frames.iter().sum::<f32>() / frames.len() as f32);
+    }
+}
+
+pub fn dbfs(samples: &[f32]) -> f32 {
+    let power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32;
+    10. * power.max(1e-10).log10()
+}
+
+fn timestamp(offset: Duration) -> String {
+    let secs = offset.as_secs();
+    format!("{:02}:{:02}", secs / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downmix_averages_three_stereo_frames() {
+        let stereo = [0.3, 0.3, 0.6, 0.6, 0.9, 0.9, 1., -1., 1., -1., 1., -1.];
+        let mut mono = Vec::new();
+        downmix_16k(&stereo, &mut mono);
+        assert_eq!(mono.len(), 2);
+        assert!((mono[0] - 0.6).abs() < 1e-6);
+        assert_eq!(mono[1], 0.);
+        assert_eq!(timestamp(Duration::from_secs(75)), "01:15");
+    }
+}
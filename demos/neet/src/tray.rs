@@ -0,0 +1,356 @@
+//! A tray icon and media controls for the call (`--tray`, feature `tray`,
+//! Linux).
+//!
+//! The icon, a StatusNotifierItem, shows whether the microphone is muted;
+//! clicking it mutes or unmutes, and its menu can hang up. The call also
+//! registers as an MPRIS media player, so media keys and headset buttons,
+//! which the desktop sends to the playing player, control it: play/pause
+//! toggles mute, and stop hangs up. Muting is the same pause as the `pause`
+//! command, so the remote is told about it.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use ksni::TrayMethods as _;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use zbus::{
+    interface,
+    object_server::SignalEmitter,
+    zvariant::{ObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use crate::media::PauseState;
+
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const TRACK_ID: &str = "/org/neet/call";
+
+/// The tray icon and media player of a call, removed when dropped.
+pub struct CallControls {
+    tray: ksni::Handle<CallTray>,
+    _mpris: Connection,
+    sync: JoinHandle<()>,
+}
+
+impl CallControls {
+    /// Show the call with `peer`, muting through `paused` and hanging up
+    /// through `shutdown`.
+    pub async fn start(
+        peer: &str,
+        paused: PauseState,
+        shutdown: CancellationToken,
+    ) -> Result<Self> {
+        let controls = Controls { paused, shutdown };
+        let tray = CallTray {
+            peer: peer.to_string(),
+            muted: controls.paused.is_paused(),
+            controls: controls.clone(),
+        }
+        .spawn()
+        .await
+        .context("failed to show the tray icon")?;
+        let mpris = zbus::connection::Builder::session()?
+            .name(format!(
+                "org.mpris.MediaPlayer2.neet.instance{}",
+                std::process::id()
+            ))?
+            .serve_at(MPRIS_PATH, Root(controls.clone()))?
+            .serve_at(
+                MPRIS_PATH,
+                Player {
+                    peer: peer.to_string(),
+                    controls: controls.clone(),
+                },
+            )?
+            .build()
+            .await
+            .context("failed to register media controls")?;
+        let sync = tokio::spawn(follow_mute(controls.paused, tray.clone(), mpris.clone()));
+        info!("added a tray icon and media controls for the call");
+        Ok(Self {
+            tray,
+            _mpris: mpris,
+            sync,
+        })
+    }
+}
+
+impl Drop for CallControls {
+    fn drop(&mut self) {
+        self.sync.abort();
+        // the service stops on its own; nothing to wait for.
+        drop(self.tray.shutdown());
+    }
+}
+
+/// Show mute changes, from wherever they come, in the icon and the player.
+async fn follow_mute(paused: PauseState, tray: ksni::Handle<CallTray>, mpris: Connection) {
+    let mut presence = paused.subscribe();
+    while presence.changed().await.is_ok() {
+        let muted = presence.borrow_and_update().is_paused();
+        tray.update(|tray| tray.muted = muted).await;
+        let changed = async {
+            let player = mpris
+                .object_server()
+                .interface::<_, Player>(MPRIS_PATH)
+                .await?;
+            let emitter = SignalEmitter::new(&mpris, MPRIS_PATH)?;
+            player.get().await.playback_status_changed(&emitter).await?;
+            zbus::Result::Ok(())
+        };
+        if let Err(err) = changed.await {
+            warn!("failed to update the media controls: {err}");
+        }
+    }
+}
+
+/// What the icon and the player act on.
+#[derive(Debug, Clone)]
+struct Controls {
+    paused: PauseState,
+    shutdown: CancellationToken,
+}
+
+impl Controls {
+    fn set_muted(&self, muted: bool) {
+        if self.paused.set(muted) {
+            info!("{}", if muted { "muted" } else { "unmuted" });
+        }
+    }
+
+    fn toggle_mute(&self) {
+        self.set_muted(!self.paused.is_paused());
+    }
+
+    fn hang_up(&self) {
+        info!("hanging up");
+        self.shutdown.cancel();
+    }
+}
+
+#[derive(Debug)]
+struct CallTray {
+    peer: String,
+    muted: bool,
+    controls: Controls,
+}
+
+impl ksni::Tray for CallTray {
+    fn id(&self) -> String {
+        "neet".to_string()
+    }
+
+    fn title(&self) -> String {
+        match self.muted {
+            true => format!("Call with {} (muted)", self.peer),
+            false => format!("Call with {}", self.peer),
+        }
+    }
+
+    fn icon_name(&self) -> String {
+        match self.muted {
+            true => "microphone-sensitivity-muted",
+            false => "audio-input-microphone",
+        }
+        .to_string()
+    }
+
+    fn activate(&mut self, _x: i32, _y: i32) {
+        self.controls.toggle_mute();
+    }
+
+    fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
+        use ksni::menu::{CheckmarkItem, StandardItem};
+        vec![
+            CheckmarkItem {
+                label: "Mute".to_string(),
+                checked: self.muted,
+                activate: Box::new(|tray: &mut Self| tray.controls.toggle_mute()),
+                ..Default::default()
+            }
+            .into(),
+            StandardItem {
+                label: "Hang up".to_string(),
+                icon_name: "call-stop".to_string(),
+                activate: Box::new(|tray: &mut Self| tray.controls.hang_up()),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+}
+
+/// `org.mpris.MediaPlayer2`: who we are; quitting hangs up.
+struct Root(Controls);
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn quit(&self) {
+        self.0.hang_up();
+    }
+
+    fn raise(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        "neet".to_string()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`: the call is playing while unmuted.
+struct Player {
+    peer: String,
+    controls: Controls,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn play_pause(&self) {
+        self.controls.toggle_mute();
+    }
+
+    fn play(&self) {
+        self.controls.set_muted(false);
+    }
+
+    fn pause(&self) {
+        self.controls.set_muted(true);
+    }
+
+    fn stop(&self) {
+        self.controls.hang_up();
+    }
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        match self.controls.paused.is_paused() {
+            true => "Paused",
+            false => "Playing",
+        }
+        .to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let mut metadata = HashMap::new();
+        let entries = [
+            (
+                "mpris:trackid",
+                Value::from(ObjectPath::from_static_str_unchecked(TRACK_ID)),
+            ),
+            (
+                "xesam:title",
+                Value::from(format!("Call with {}", self.peer)),
+            ),
+        ];
+        for (key, value) in entries {
+            if let Ok(value) = OwnedValue::try_from(value) {
+                metadata.insert(key.to_string(), value);
+            }
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn play_pause_toggles_mute() {
+        let controls = Controls {
+            paused: PauseState::default(),
+            shutdown: CancellationToken::new(),
+        };
+        let player = Player {
+            peer: "alice".to_string(),
+            controls: controls.clone(),
+        };
+        assert_eq!(player.playback_status(), "Playing");
+        player.play_pause();
+        assert!(controls.paused.is_paused());
+        assert_eq!(player.playback_status(), "Paused");
+        player.play();
+        assert!(!controls.paused.is_paused());
+        assert_eq!(player.metadata().len(), 2);
+        player.stop();
+        assert!(controls.shutdown.is_cancelled());
+    }
+}
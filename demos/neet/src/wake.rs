@@ -0,0 +1,266 @@
+//! Spoken requests to the daemon (`daemon --wake-model`), for hands-free
+//! intercoms: "hey neet, call alice" calls the contact alice.
+//!
+//! A tap on the capture path of the daemon's own devices copies the
+//! microphone's audio into a ring buffer. A worker thread drains it, keeps
+//! the last [`WINDOW`] of it as 16 kHz mono and runs whisper over it every
+//! [`HOP`] unless it is silence. When the transcript has the wake phrase in
+//! it, the words after it become a request, as if sent with `neet ctl`:
+//! `call <contact>`, `answer`, `decline` or `hang up`. Nothing leaves the
+//! device until a call starts.
+
+use std::{ops::ControlFlow, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use ringbuf::{
+    traits::{Consumer as _, Observer as _, Producer as _, Split},
+    HeapCons, HeapProd, HeapRb,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use whisper_rs::{
+    FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState,
+};
+
+use crate::{
+    audio::{AudioSink, ENGINE_FORMAT},
+    contacts::Contacts,
+    transcribe::{dbfs, downmix_16k, WHISPER_SAMPLE_RATE},
+};
+
+/// Audio per whisper run; enough for the wake phrase and a request.
+const WINDOW: Duration = Duration::from_secs(3);
+/// How often the window is run again; a phrase cut off at one end of a
+/// window is whole in the next.
+const HOP: Duration = Duration::from_millis(1500);
+/// Windows quieter than this are skipped; whisper invents text for silence.
+const SILENCE_DBFS: f32 = -45.;
+/// Audio queued for the worker before the tap starts dropping it.
+const QUEUE: Duration = Duration::from_secs(5);
+/// How often the worker looks for new audio.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// What whisper writes for "neet", a word it has never heard.
+const WAKE_WORDS: &[&str] = &["neet", "neat", "niet"];
+
+/// A request heard after the wake phrase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Spoken {
+    /// Call a contact, named as transcribed.
+    Call(String),
+    Answer,
+    Decline,
+    Hangup,
+}
+
+impl Spoken {
+    /// The request in `words`, the lowercase words said after the wake phrase.
+    fn parse(words: &[&str]) -> Option<Self> {
+        match words {
+            ["call", name @ ..] if !name.is_empty() => Some(Self::Call(name.join(" "))),
+            ["answer" | "pick", ..] => Some(Self::Answer),
+            ["decline" | "reject", ..] => Some(Self::Decline),
+            ["hang", "up", ..] | ["hangup", ..] => Some(Self::Hangup),
+            _ => None,
+        }
+    }
+
+    /// The daemon request line, with the contact looked up in `names`. A
+    /// name that matches no contact is passed on for the daemon to refuse.
+    fn request<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> String {
+        match self {
+            Self::Call(spoken) => match contact_named(names, spoken) {
+                Some(name) => format!("call {name}"),
+                None => format!("call {}", letters(spoken)),
+            },
+            Self::Answer => "answer".to_string(),
+            Self::Decline => "decline".to_string(),
+            Self::Hangup => "hangup".to_string(),
+        }
+    }
+}
+
+/// The request in `text`, a transcript, if it has the wake phrase in it.
+fn heard(text: &str) -> Option<Spoken> {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let at = words
+        .windows(2)
+        .position(|pair| pair[0] == "hey" && WAKE_WORDS.contains(&pair[1]))?;
+    Spoken::parse(&words[at + 2..])
+}
+
+/// The contact among `names` that `spoken` means: the longest whose letters
+/// and digits start it, ignoring case, so that "Alice." and "alice please"
+/// both mean alice.
+fn contact_named<'a>(names: impl IntoIterator<Item = &'a str>, spoken: &str) -> Option<&'a str> {
+    let spoken = letters(spoken);
+    names
+        .into_iter()
+        .map(|name| (letters(name), name))
+        .filter(|(letters, _)| !letters.is_empty() && spoken.starts_with(letters.as_str()))
+        .max_by_key(|(letters, _)| letters.len())
+        .map(|(_, name)| name)
+}
+
+/// The letters and digits of `text`, in lowercase.
+fn letters(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Load the whisper `model` and start listening for the wake phrase in what
+/// the returned tap is fed. Requests heard go to `requests` as request lines.
+pub fn listen(model: &Path, requests: mpsc::UnboundedSender<String>) -> Result<WakeTap> {
+    let model = model
+        .to_str()
+        .context("whisper model path is not valid UTF-8")?;
+    let context = WhisperContext::new_with_params(model, WhisperContextParameters::default())
+        .with_context(|| format!("failed to load whisper model {model}"))?;
+    let (producer, audio) = HeapRb::new(ENGINE_FORMAT.sample_count(QUEUE)).split();
+    std::thread::spawn(move || {
+        if let Err(err) = listen_loop(context, audio, requests) {
+            warn!("stopped listening for the wake word: {err:?}");
+        }
+    });
+    Ok(WakeTap {
+        audio: producer,
+        dropped: 0,
+    })
+}
+
+/// Copies the microphone's audio into the worker's ring buffer, without
+/// blocking or allocating on the audio thread.
+pub struct WakeTap {
+    audio: HeapProd<f32>,
+    dropped: u64,
+}
+
+impl AudioSink for WakeTap {
+    fn tick(&mut self, buf: &[f32]) -> Result<ControlFlow<(), ()>> {
+        if !self.audio.read_is_held() {
+            return Ok(ControlFlow::Break(()));
+        }
+        // whole ticks only, so that stereo frames stay aligned.
+        if self.audio.vacant_len() < buf.len() {
+            self.dropped += 1;
+            if self.dropped.is_power_of_two() {
+                debug!(dropped = self.dropped, "wake word detection falling behind");
+            }
+        } else {
+            self.audio.push_slice(buf);
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+}
+
+/// Listen until the tap is gone or nobody takes requests any more. A window
+/// whisper fails on is skipped, never the listening.
+fn listen_loop(
+    context: WhisperContext,
+    mut audio: HeapCons<f32>,
+    requests: mpsc::UnboundedSender<String>,
+) -> Result<()> {
+    let mut state = context.create_state()?;
+    let sample_count =
+        |duration: Duration| (duration.as_secs_f32() * WHISPER_SAMPLE_RATE as f32) as usize;
+    let (window_len, hop_len) = (sample_count(WINDOW), sample_count(HOP));
+    let mut window = Vec::with_capacity(window_len);
+    // whole 48 kHz stereo frames, so that downmixing loses nothing.
+    let mut scratch = vec![0.; ENGINE_FORMAT.sample_count(POLL_INTERVAL)];
+    loop {
+        loop {
+            let count = audio.pop_slice(&mut scratch);
+            if count == 0 {
+                break;
+            }
+            downmix_16k(&scratch[..count], &mut window);
+            if window.len() < window_len {
+                continue;
+            }
+            let spoken = if dbfs(&window) < SILENCE_DBFS {
+                None
+            } else {
+                match transcribe(&mut state, &window) {
+                    Ok(text) => heard(&text),
+                    Err(err) => {
+                        warn!("failed to transcribe for the wake word: {err:#}");
+                        None
+                    }
+                }
+            };
+            let Some(spoken) = spoken else {
+                window.drain(..hop_len);
+                continue;
+            };
+            // the phrase is still in the window; don't hear it twice.
+            window.clear();
+            let request = match Contacts::load(None) {
+                Ok(contacts) => spoken.request(contacts.iter().map(|(name, _)| name)),
+                Err(err) => {
+                    warn!("failed to load contacts for a spoken request: {err:#}");
+                    // the daemon refuses a contact it can't find either.
+                    spoken.request([])
+                }
+            };
+            info!(request, "heard a spoken request");
+            if requests.send(request).is_err() {
+                return Ok(());
+            }
+        }
+        if !audio.write_is_held() && audio.is_empty() {
+            return Ok(());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// What whisper makes of `samples`, in one line.
+fn transcribe(state: &mut WhisperState, samples: &[f32]) -> Result<String> {
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    // requests are English whatever the calls are in.
+    params.set_language(Some("en"));
+    params.set_no_context(true);
+    params.set_single_segment(true);
+    params.set_print_special(false);
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_timestamps(false);
+    state.full(params, samples)?;
+    let mut text = String::new();
+    for segment in 0..state.full_n_segments()? {
+        text.push_str(&state.full_get_segment_text(segment)?);
+        text.push(' ');
+    }
+    debug!(text, "wake word window");
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hears_requests_after_the_wake_phrase() {
+        let contacts = ["alice", "Bob-Smith", "al"];
+        let request = |text| heard(text).map(|spoken| spoken.request(contacts));
+        assert_eq!(
+            request(" Hey, Neat! Call Alice, please."),
+            Some("call alice".into())
+        );
+        assert_eq!(
+            request("so hey neet call bob smith"),
+            Some("call Bob-Smith".into())
+        );
+        assert_eq!(request("Hey neet, hang up."), Some("hangup".into()));
+        assert_eq!(request("Hey Neet, call Carol."), Some("call carol".into()));
+        // no wake phrase, or nothing asked after it.
+        assert_eq!(request("call alice"), None);
+        assert_eq!(request("hey neet, how are you"), None);
+        assert_eq!(request("hey neet call"), None);
+    }
+}